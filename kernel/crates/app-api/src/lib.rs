@@ -0,0 +1,29 @@
+//! Kernel services available to a built-in kernel application.
+//!
+//! When the kernel is built with the `app` feature, it runs the `kernel-app` crate's `main` in a
+//! kernel thread after boot, passing it an implementation of `Kernel`. The application only sees
+//! the kernel through this trait, so it can be built and changed without touching kernel code.
+
+#![no_std]
+
+use core::fmt;
+
+/// Entry point of a kernel application, returning an exit value which is logged.
+pub type AppMain = fn(kernel: &dyn Kernel) -> usize;
+
+pub trait Kernel: Sync {
+    /// Writes a message to the kernel log.
+    fn log(&self, args: fmt::Arguments);
+
+    /// Returns the number of nanoseconds since boot.
+    fn now_ns(&self) -> u64;
+
+    /// Blocks the application's thread for at least `num_ms` milliseconds.
+    fn sleep_ms(&self, num_ms: u64);
+
+    /// Lets other kernel threads run.
+    fn yield_now(&self);
+
+    /// Returns the contents of the file at `path` in the initrd.
+    fn initrd_file(&self, path: &str) -> Option<&'static [u8]>;
+}
@@ -0,0 +1,47 @@
+//! Types exported to assembly with `define-asm-symbol`, compiled on the host to check the code it
+//! generates, with tests reading back the values of the symbols it defines.
+
+#![no_std]
+
+#[cfg(test)]
+mod tests;
+
+use define_asm_symbol::export_asm_all;
+
+#[repr(C)]
+#[export_asm_all]
+pub struct Inner {
+    pub a: u32,
+    pub b: u64,
+}
+
+#[repr(C)]
+#[export_asm_all(layout)]
+pub struct Outer {
+    pub flags: u8,
+    #[asm_fields(a, b)]
+    pub inner: Inner,
+    #[asm_name = "items"]
+    pub elements: [u16; 5],
+}
+
+#[repr(C, align(32))]
+#[export_asm_all(layout)]
+#[asm_name = "Renamed"]
+pub struct Aligned {
+    pub value: u64,
+}
+
+const KIND_BASE: u8 = 0x20;
+
+#[repr(u8)]
+#[export_asm_all(count)]
+pub enum Kind {
+    First,
+    Second = 5,
+    Third,
+    Fourth = KIND_BASE + 1,
+    Fifth,
+    #[asm_name = "Last"]
+    Sixth = 1 << 7,
+}
@@ -0,0 +1,57 @@
+//! Host tests, reading each symbol's value as an immediate operand. Only x86_64 hosts are
+//! supported, as the symbols are read with `movabs`.
+
+use super::*;
+use core::mem::{align_of, offset_of, size_of};
+
+/// Returns the value of the absolute symbol `name`.
+macro_rules! symbol {
+    ($name:literal) => {{
+        let value: usize;
+        unsafe {
+            core::arch::asm!(
+                concat!("movabs {}, offset \"", $name, "\""),
+                out(reg) value,
+                options(nomem, nostack, pure),
+            );
+        }
+        value
+    }};
+}
+
+#[test]
+fn exports_field_offsets() {
+    assert_eq!(symbol!("Inner.a"), offset_of!(Inner, a));
+    assert_eq!(symbol!("Inner.b"), offset_of!(Inner, b));
+    assert_eq!(symbol!("Outer.flags"), offset_of!(Outer, flags));
+    assert_eq!(symbol!("Outer.inner"), offset_of!(Outer, inner));
+    assert_eq!(symbol!("Outer.inner.a"), offset_of!(Outer, inner.a));
+    assert_eq!(symbol!("Outer.inner.b"), offset_of!(Outer, inner.b));
+    assert_eq!(symbol!("Outer.items"), offset_of!(Outer, elements));
+    assert_eq!(symbol!("Renamed.value"), offset_of!(Aligned, value));
+}
+
+#[test]
+fn exports_array_strides() {
+    assert_eq!(symbol!("Outer.items.__stride"), size_of::<u16>());
+    assert_eq!(symbol!("Outer.items.__len"), 5);
+}
+
+#[test]
+fn exports_layouts() {
+    assert_eq!(symbol!("Outer.__size"), size_of::<Outer>());
+    assert_eq!(symbol!("Outer.__align"), align_of::<Outer>());
+    assert_eq!(symbol!("Renamed.__size"), 32);
+    assert_eq!(symbol!("Renamed.__align"), 32);
+}
+
+#[test]
+fn exports_enum_values() {
+    assert_eq!(symbol!("Kind.First"), Kind::First as usize);
+    assert_eq!(symbol!("Kind.Second"), Kind::Second as usize);
+    assert_eq!(symbol!("Kind.Third"), Kind::Third as usize);
+    assert_eq!(symbol!("Kind.Fourth"), Kind::Fourth as usize);
+    assert_eq!(symbol!("Kind.Fifth"), 0x22);
+    assert_eq!(symbol!("Kind.Last"), Kind::Sixth as usize);
+    assert_eq!(symbol!("Kind.__count"), 6);
+}
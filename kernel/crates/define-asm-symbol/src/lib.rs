@@ -1,59 +1,159 @@
 use proc_macro::TokenStream;
-use quote::quote;
+use quote::{ToTokens, quote};
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
 
+/// A path through nested struct fields, such as `a.b.c`.
+type FieldPath = Punctuated<syn::Ident, syn::Token![.]>;
+
+/// Exports the layout of a struct, or the values of an enum, to assembly as absolute symbols.
+///
+/// Each struct field gives a `Type.field` symbol holding its offset. Array fields also give
+/// `Type.field.__stride` and `Type.field.__len`, the size and number of their elements. Fields of
+/// a nested struct are exported by listing their paths with `#[asm_fields(a, b.c)]` on the field,
+/// giving `Type.field.a` and `Type.field.b.c`. Each unit enum variant gives a `Type.Variant` symbol
+/// holding its discriminant, which may be any constant expression.
+///
+/// The type, fields and variants can be exported under another name with `#[asm_name = "..."]`.
+///
+/// Options can be given as arguments, such as `#[export_asm_all(layout, count)]`:
+/// - `layout` also exports the size and alignment of the type, as `Type.__size` and `Type.__align`.
+/// - `count` also exports the number of variants of an enum, as `Type.__count`.
 #[proc_macro_attribute]
-pub fn export_asm_all(_attr: TokenStream, input: TokenStream) -> TokenStream {
-    let mut output_stream = input.clone();
-    let ast = syn::parse_macro_input!(input as syn::DeriveInput);
-    let item_ident = ast.ident;
-    let item_name = item_ident.to_string();
-    match &ast.data {
+pub fn export_asm_all(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let options = Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated
+        .parse(attr)
+        .expect("`export_asm_all` options must be a list of names");
+    let mut ast = syn::parse_macro_input!(input as syn::DeriveInput);
+    let item_ident = ast.ident.clone();
+    let item_name = take_asm_name(&mut ast.attrs).unwrap_or_else(|| item_ident.to_string());
+    let mut symbols = TokenStream::new();
+    let mut export_count = false;
+    for option in options.iter() {
+        match option.to_string().as_str() {
+            "count" => export_count = true,
+            "layout" => {
+                symbols.extend(define_symbol(
+                    &format!("{item_name}.__size"),
+                    quote!(::core::mem::size_of::<#item_ident>()),
+                ));
+                symbols.extend(define_symbol(
+                    &format!("{item_name}.__align"),
+                    quote!(::core::mem::align_of::<#item_ident>()),
+                ));
+            }
+            _ => panic!("unknown `export_asm_all` option `{option}`"),
+        }
+    }
+    match &mut ast.data {
         syn::Data::Struct(data) => {
             assert!(matches!(data.fields, syn::Fields::Named(_)));
-            for field in data.fields.iter() {
+            assert!(!export_count, "`count` can only be exported for enums");
+            for field in data.fields.iter_mut() {
                 let field_ident = field.ident.as_ref().unwrap();
-                let field_name = field_ident.to_string();
+                let field_name =
+                    take_asm_name(&mut field.attrs).unwrap_or_else(|| field_ident.to_string());
                 let asm_name = format!("{item_name}.{field_name}");
-                let asm_expanded = quote! {
-                    ::core::arch::global_asm!(
-                        concat!(".global \"", #asm_name, "\"\n\"", #asm_name, "\" = {value}"),
-                        value = const ::core::mem::offset_of!(#item_ident, #field_ident),
-                    );
-                };
-                let asm_token_stream: proc_macro::TokenStream = asm_expanded.into();
-                output_stream.extend(asm_token_stream);
+                symbols.extend(define_symbol(
+                    &asm_name,
+                    quote!(::core::mem::offset_of!(#item_ident, #field_ident)),
+                ));
+                if let syn::Type::Array(array) = &field.ty {
+                    let (element, len) = (&array.elem, &array.len);
+                    symbols.extend(define_symbol(
+                        &format!("{asm_name}.__stride"),
+                        quote!(::core::mem::size_of::<#element>()),
+                    ));
+                    symbols.extend(define_symbol(&format!("{asm_name}.__len"), len));
+                }
+                for path in take_asm_fields(&mut field.attrs) {
+                    let path_name = path
+                        .iter()
+                        .map(|ident| ident.to_string())
+                        .collect::<Vec<_>>()
+                        .join(".");
+                    let path = path.iter();
+                    symbols.extend(define_symbol(
+                        &format!("{asm_name}.{path_name}"),
+                        quote!(::core::mem::offset_of!(#item_ident, #field_ident #(. #path)*)),
+                    ));
+                }
             }
         }
         syn::Data::Enum(data) => {
-            let mut current_value: usize = 0;
-            for variant in data.variants.iter() {
+            for variant in data.variants.iter_mut() {
                 assert!(matches!(variant.fields, syn::Fields::Unit));
-                let value = match &variant.discriminant {
-                    Some((_, expr)) => {
-                        let syn::Expr::Lit(literal) = expr else {
-                            panic!("enum variant discriminants must be integer literals");
-                        };
-                        let syn::Lit::Int(int_lit) = &literal.lit else {
-                            panic!("enum variant discriminants must be integer literals");
-                        };
-                        int_lit.base10_parse().unwrap()
-                    }
-                    None => current_value,
-                };
-                let variant_name = variant.ident.to_string();
-                let asm_name = format!("{item_name}.{variant_name}");
-                let asm_expanded = quote! {
-                    ::core::arch::global_asm!(
-                        concat!(".global \"", #asm_name, "\"\n\"", #asm_name, "\" = {value}"),
-                        value = const #value,
-                    );
-                };
-                let asm_token_stream: proc_macro::TokenStream = asm_expanded.into();
-                output_stream.extend(asm_token_stream);
-                current_value = value + 1;
+                let variant_ident = &variant.ident;
+                let variant_name =
+                    take_asm_name(&mut variant.attrs).unwrap_or_else(|| variant_ident.to_string());
+                // Leaves the compiler to evaluate the discriminant, whether it's given or follows
+                // on from the previous variant's
+                symbols.extend(define_symbol(
+                    &format!("{item_name}.{variant_name}"),
+                    quote!(#item_ident::#variant_ident as usize),
+                ));
+            }
+            if export_count {
+                symbols.extend(define_symbol(
+                    &format!("{item_name}.__count"),
+                    data.variants.len(),
+                ));
             }
         }
         _ => panic!("`export_asm_all` must be called on a struct or an enum"),
     }
+    let mut output_stream: TokenStream = ast.into_token_stream().into();
+    output_stream.extend(symbols);
     output_stream
 }
+
+/// Defines a global absolute symbol `name` with the value of the constant expression `value`.
+fn define_symbol(name: &str, value: impl ToTokens) -> TokenStream {
+    quote! {
+        ::core::arch::global_asm!(
+            concat!(".global \"", #name, "\"\n\"", #name, "\" = {value}"),
+            value = const #value,
+        );
+    }
+    .into()
+}
+
+/// Removes any `#[asm_name = "..."]` attribute from `attrs`, returning the name it gives.
+fn take_asm_name(attrs: &mut Vec<syn::Attribute>) -> Option<String> {
+    let index = attrs
+        .iter()
+        .position(|attr| attr.path().is_ident("asm_name"))?;
+    let attr = attrs.remove(index);
+    let syn::Meta::NameValue(name_value) = &attr.meta else {
+        panic!("`asm_name` must be given as `#[asm_name = \"...\"]`");
+    };
+    let syn::Expr::Lit(syn::ExprLit {
+        lit: syn::Lit::Str(name),
+        ..
+    }) = &name_value.value
+    else {
+        panic!("`asm_name` must be a string literal");
+    };
+    Some(name.value())
+}
+
+/// Removes any `#[asm_fields(...)]` attributes from `attrs`, returning the field paths they list.
+fn take_asm_fields(attrs: &mut Vec<syn::Attribute>) -> Vec<Vec<syn::Ident>> {
+    let mut paths = Vec::new();
+    attrs.retain(|attr| {
+        if !attr.path().is_ident("asm_fields") {
+            return true;
+        }
+        let parsed = attr
+            .parse_args_with(|input: syn::parse::ParseStream| {
+                Punctuated::<FieldPath, syn::Token![,]>::parse_terminated_with(
+                    input,
+                    FieldPath::parse_separated_nonempty,
+                )
+            })
+            .expect("`asm_fields` must list field paths, such as `#[asm_fields(a, b.c)]`");
+        paths.extend(parsed.into_iter().map(|path| path.into_iter().collect()));
+        false
+    });
+    paths
+}
@@ -0,0 +1,18 @@
+//! The application run by the kernel when built with the `app` feature.
+//!
+//! Replace `main` with the appliance's code. This one logs the time it took the kernel to get
+//! here, as a quick check that app mode works.
+
+#![no_std]
+
+use app_api::Kernel;
+
+pub fn main(kernel: &dyn Kernel) -> usize {
+    let now_ns = kernel.now_ns();
+    kernel.log(format_args!(
+        "Kernel application started {}.{:06}s after boot",
+        now_ns / 1_000_000_000,
+        now_ns % 1_000_000_000 / 1000,
+    ));
+    0
+}
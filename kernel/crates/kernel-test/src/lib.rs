@@ -0,0 +1,34 @@
+use proc_macro::TokenStream;
+use quote::quote;
+
+/// Registers a function as an in-kernel test, run at boot with the `runtests` command line option.
+///
+/// The function must take no arguments and return `ktest::TestResult`. It's placed in the
+/// `.kernel_tests` section, which `ktest::run_all` reads, so tests don't need listing anywhere.
+#[proc_macro_attribute]
+pub fn kernel_test(attr: TokenStream, input: TokenStream) -> TokenStream {
+    assert!(attr.is_empty(), "`kernel_test` takes no arguments");
+    let function = syn::parse_macro_input!(input as syn::ItemFn);
+    let ident = &function.sig.ident;
+    assert!(
+        function.sig.inputs.is_empty(),
+        "kernel test `{ident}` must take no arguments",
+    );
+    assert!(
+        function.sig.asyncness.is_none() && function.sig.generics.params.is_empty(),
+        "kernel test `{ident}` must be a plain function",
+    );
+    quote! {
+        #function
+
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".kernel_tests")]
+            static TEST: crate::ktest::KernelTest = crate::ktest::KernelTest {
+                name: concat!(module_path!(), "::", stringify!(#ident)),
+                function: #ident,
+            };
+        };
+    }
+    .into()
+}
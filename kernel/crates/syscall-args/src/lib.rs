@@ -0,0 +1,101 @@
+use proc_macro::TokenStream;
+use quote::quote;
+
+/// Derives `UserArgs` for a struct of system call arguments, checking each pointer field against
+/// the current process's mappings.
+///
+/// Every raw pointer field must be marked with `#[user(...)]`, containing:
+/// - `read` or `write`, the access the kernel needs
+/// - `len = field`, the field holding the length in bytes, otherwise the size of the pointee
+/// - `max_len = expr`, optionally, the longest length accepted
+/// - `optional`, if the pointer can be null
+#[proc_macro_derive(UserArgs, attributes(user))]
+pub fn derive_user_args(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::DeriveInput);
+    let item_ident = ast.ident;
+    let syn::Data::Struct(data) = &ast.data else {
+        panic!("`UserArgs` must be derived on a struct");
+    };
+    assert!(
+        matches!(data.fields, syn::Fields::Named(_)),
+        "`UserArgs` must be derived on a struct with named fields",
+    );
+    let mut checks = Vec::new();
+    for field in data.fields.iter() {
+        let field_ident = field.ident.as_ref().unwrap();
+        let attr = field.attrs.iter().find(|attr| attr.path().is_ident("user"));
+        let syn::Type::Ptr(pointer_type) = &field.ty else {
+            assert!(
+                attr.is_none(),
+                "`{field_ident}` is marked `user` but isn't a pointer"
+            );
+            continue;
+        };
+        let Some(attr) = attr else {
+            panic!("pointer field `{field_ident}` must be marked `user`");
+        };
+        let mut access = None;
+        let mut len = None;
+        let mut max_len = None;
+        let mut optional = false;
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("read") {
+                access = Some(quote!(Read));
+            } else if meta.path.is_ident("write") {
+                access = Some(quote!(Write));
+            } else if meta.path.is_ident("len") {
+                len = Some(meta.value()?.parse::<syn::Ident>()?);
+            } else if meta.path.is_ident("max_len") {
+                max_len = Some(meta.value()?.parse::<syn::Expr>()?);
+            } else if meta.path.is_ident("optional") {
+                optional = true;
+            } else {
+                return Err(meta.error("unknown `user` option"));
+            }
+            Ok(())
+        })
+        .unwrap();
+        let access = access.expect("`user` fields must be marked `read` or `write`");
+        if pointer_type.mutability.is_none() && access.to_string() == "Write" {
+            panic!("`{field_ident}` is marked `write` but is a const pointer");
+        }
+        let pointee = &pointer_type.elem;
+        let len = match len {
+            Some(len_ident) => quote!(self.#len_ident as usize),
+            None => quote!(::core::mem::size_of::<#pointee>()),
+        };
+        let max_len_check = max_len.map(|max_len| {
+            quote! {
+                if len > #max_len {
+                    return Err(crate::arch::syscall::SyscallError::INVALID_ARGUMENT);
+                }
+            }
+        });
+        let check = quote! {
+            let len = #len;
+            #max_len_check
+            crate::user_memory::check_user_range(
+                self.#field_ident as usize,
+                len,
+                crate::user_memory::Access::#access,
+            )?;
+        };
+        checks.push(match optional {
+            true => quote! {
+                if !self.#field_ident.is_null() {
+                    #check
+                }
+            },
+            false => quote!({ #check }),
+        });
+    }
+    let expanded = quote! {
+        impl crate::user_memory::UserArgs for #item_ident {
+            fn validate(&self) -> Result<(), crate::arch::syscall::SyscallError> {
+                #(#checks)*
+                Ok(())
+            }
+        }
+    };
+    expanded.into()
+}
@@ -0,0 +1,46 @@
+//! Converts a kernel trace in a capture of serial output, as written by the kshell's `trace dump`,
+//! to Chrome trace JSON.
+//!
+//! Usage: `trace-to-json <capture> [output]`, writing to stdout without an output path.
+
+use std::process::ExitCode;
+
+fn convert(capture_path: &str) -> Result<String, String> {
+    let capture = std::fs::read(capture_path)
+        .map_err(|err| format!("failed to read {capture_path} - {err}"))?;
+    let capture = String::from_utf8_lossy(&capture);
+    let records = trace_format::decode_capture(&capture)
+        .and_then(|bytes| trace_format::parse(&bytes))
+        .map_err(|err| format!("failed to decode trace - {err}"))?;
+    let mut json = String::new();
+    trace_format::write_chrome_json(&records, &mut json).unwrap();
+    Ok(json)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let (capture_path, output_path) = match args.as_slice() {
+        [_, capture_path] => (capture_path, None),
+        [_, capture_path, output_path] => (capture_path, Some(output_path)),
+        _ => {
+            eprintln!("Usage: trace-to-json <capture> [output]");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = convert(capture_path).and_then(|json| match output_path {
+        Some(path) => {
+            std::fs::write(path, json).map_err(|err| format!("failed to write {path} - {err}"))
+        }
+        None => {
+            print!("{json}");
+            Ok(())
+        }
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}
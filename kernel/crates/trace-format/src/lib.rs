@@ -0,0 +1,364 @@
+//! The format of the kernel's event traces, shared by the kernel's `trace` module, which writes
+//! them, and the `trace-to-json` tool, which converts them to Chrome trace JSON for viewing in
+//! Perfetto or `chrome://tracing`.
+//!
+//! A trace is a header followed by fixed size records, all little endian. The header is `MAGIC`,
+//! the `VERSION` as a `u32` and the number of records as a `u32`. Each record is its timestamp in
+//! nanoseconds since boot as a `u64`, the ID of the CPU it was recorded on as a `u32`, its `Event`
+//! as a `u32` and three `u64` arguments, unused ones being 0.
+//!
+//! The kernel writes traces over serial as base64 lines between `BEGIN_MARKER` and `END_MARKER`,
+//! so they can be picked out of a capture of the serial port, along with any log output.
+
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(test)]
+extern crate std;
+
+#[cfg(test)]
+mod tests;
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const MAGIC: [u8; 8] = *b"9XTRACE\0";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 16;
+pub const RECORD_SIZE: usize = 40;
+pub const BEGIN_MARKER: &str = "-----BEGIN 9X TRACE-----";
+pub const END_MARKER: &str = "-----END 9X TRACE-----";
+
+/// Input bytes per base64 line, making 76 character lines.
+const BASE64_BYTES_PER_LINE: usize = 57;
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("no trace between the markers")]
+    MissingMarkers,
+    #[error("invalid base64")]
+    InvalidBase64,
+    #[error("not a trace")]
+    BadMagic,
+    #[error("unsupported trace version {0}")]
+    UnsupportedVersion(u32),
+    #[error("trace is truncated")]
+    Truncated,
+    #[error("unknown event {0}")]
+    UnknownEvent(u32),
+}
+
+/// How an argument is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgFormat {
+    Decimal,
+    Hex,
+}
+
+/// The events recorded by tracepoints, each named after the tracepoint's macro.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Event {
+    /// The CPU switched from one kernel thread to another.
+    SchedSwitch = 1,
+    /// A blocked kernel thread was made ready to run.
+    SchedWakeup = 2,
+    /// A user thread faulted on a page.
+    PageFault = 3,
+    /// A dynamically registered interrupt handler ran.
+    Irq = 4,
+}
+
+impl Event {
+    pub const ALL: [Self; 4] = [
+        Self::SchedSwitch,
+        Self::SchedWakeup,
+        Self::PageFault,
+        Self::Irq,
+    ];
+
+    pub fn from_u32(value: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| *event as u32 == value)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SchedSwitch => "sched_switch",
+            Self::SchedWakeup => "sched_wakeup",
+            Self::PageFault => "page_fault",
+            Self::Irq => "irq",
+        }
+    }
+
+    /// Returns the names and formats of the event's arguments, in order.
+    pub fn args(self) -> &'static [(&'static str, ArgFormat)] {
+        match self {
+            Self::SchedSwitch => &[("from", ArgFormat::Decimal), ("to", ArgFormat::Decimal)],
+            Self::SchedWakeup => &[("thread", ArgFormat::Decimal)],
+            Self::PageFault => &[
+                ("address", ArgFormat::Hex),
+                ("instruction", ArgFormat::Hex),
+                ("error_code", ArgFormat::Hex),
+            ],
+            Self::Irq => &[("vector", ArgFormat::Decimal)],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp_ns: u64,
+    pub cpu: u32,
+    pub event: Event,
+    pub args: [u64; 3],
+}
+
+impl Record {
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.timestamp_ns.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.cpu.to_le_bytes());
+        bytes[12..16].copy_from_slice(&(self.event as u32).to_le_bytes());
+        for (index, arg) in self.args.iter().enumerate() {
+            bytes[16 + index * 8..24 + index * 8].copy_from_slice(&arg.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Result<Self, ParseError> {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let event = u32_at(12);
+        Ok(Self {
+            timestamp_ns: u64_at(0),
+            cpu: u32_at(8),
+            event: Event::from_u32(event).ok_or(ParseError::UnknownEvent(event))?,
+            args: [u64_at(16), u64_at(24), u64_at(32)],
+        })
+    }
+}
+
+/// Formats a record as a line like the kernel's log output, with the timestamp in seconds.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:09}] cpu{} {}",
+            self.timestamp_ns / 1_000_000_000,
+            self.timestamp_ns % 1_000_000_000,
+            self.cpu,
+            self.event.name(),
+        )?;
+        for ((name, format), value) in self.event.args().iter().zip(self.args) {
+            match format {
+                ArgFormat::Decimal => write!(f, " {name}={value}")?,
+                ArgFormat::Hex => write!(f, " {name}={value:#x}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the header of a trace of `record_count` records.
+pub fn header(record_count: u32) -> [u8; HEADER_SIZE] {
+    let mut bytes = [0; HEADER_SIZE];
+    bytes[0..8].copy_from_slice(&MAGIC);
+    bytes[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    bytes[12..16].copy_from_slice(&record_count.to_le_bytes());
+    bytes
+}
+
+/// Parses a whole trace.
+pub fn parse(bytes: &[u8]) -> Result<Vec<Record>, ParseError> {
+    let header = bytes.get(..HEADER_SIZE).ok_or(ParseError::Truncated)?;
+    if header[0..8] != MAGIC {
+        return Err(ParseError::BadMagic);
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != VERSION {
+        return Err(ParseError::UnsupportedVersion(version));
+    }
+    let record_count = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let records = &bytes[HEADER_SIZE..];
+    if records.len() < record_count * RECORD_SIZE {
+        return Err(ParseError::Truncated);
+    }
+    records
+        .chunks_exact(RECORD_SIZE)
+        .take(record_count)
+        .map(|record| Record::from_bytes(record.try_into().unwrap()))
+        .collect()
+}
+
+/// Writes bytes as base64 lines, buffering a line at a time.
+pub struct Base64Writer<'a, W: fmt::Write> {
+    out: &'a mut W,
+    line: [u8; BASE64_BYTES_PER_LINE],
+    line_len: usize,
+}
+
+impl<'a, W: fmt::Write> Base64Writer<'a, W> {
+    pub fn new(out: &'a mut W) -> Self {
+        Self {
+            out,
+            line: [0; BASE64_BYTES_PER_LINE],
+            line_len: 0,
+        }
+    }
+
+    pub fn write(&mut self, mut bytes: &[u8]) -> fmt::Result {
+        while !bytes.is_empty() {
+            let count = bytes.len().min(BASE64_BYTES_PER_LINE - self.line_len);
+            self.line[self.line_len..self.line_len + count].copy_from_slice(&bytes[..count]);
+            self.line_len += count;
+            bytes = &bytes[count..];
+            if self.line_len == BASE64_BYTES_PER_LINE {
+                self.write_line()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes any partial line left.
+    pub fn finish(mut self) -> fmt::Result {
+        match self.line_len {
+            0 => Ok(()),
+            _ => self.write_line(),
+        }
+    }
+
+    fn write_line(&mut self) -> fmt::Result {
+        for group in self.line[..self.line_len].chunks(3) {
+            let bytes = [
+                group[0],
+                *group.get(1).unwrap_or(&0),
+                *group.get(2).unwrap_or(&0),
+            ];
+            let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            for index in 0..4 {
+                // One character per six bits, padding past the end of the input
+                let character = match index <= group.len() {
+                    true => BASE64_ALPHABET[(bits >> (18 - index * 6)) as usize & 0x3F],
+                    false => b'=',
+                };
+                self.out.write_char(character as char)?;
+            }
+        }
+        self.line_len = 0;
+        writeln!(self.out)
+    }
+}
+
+fn base64_value(character: u8) -> Option<u32> {
+    BASE64_ALPHABET
+        .iter()
+        .position(|&c| c == character)
+        .map(|value| value as u32)
+}
+
+/// Decodes the first trace between the markers in a capture of serial output. Lines between the
+/// markers which aren't base64, such as log output written in the middle of the trace, are
+/// skipped.
+pub fn decode_capture(capture: &str) -> Result<Vec<u8>, ParseError> {
+    let mut lines = capture
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != BEGIN_MARKER)
+        .skip(1);
+    let mut bytes = Vec::new();
+    let mut found_end = false;
+    for line in lines.by_ref() {
+        if line == END_MARKER {
+            found_end = true;
+            break;
+        }
+        if line.is_empty()
+            || line.len() % 4 != 0
+            || !line.bytes().all(|c| c == b'=' || base64_value(c).is_some())
+        {
+            continue;
+        }
+        for group in line.as_bytes().chunks(4) {
+            let padding = group.iter().filter(|&&c| c == b'=').count();
+            let mut bits = 0;
+            for &character in group {
+                let value = match character {
+                    b'=' => 0,
+                    _ => base64_value(character).ok_or(ParseError::InvalidBase64)?,
+                };
+                bits = bits << 6 | value;
+            }
+            bytes.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+        }
+    }
+    match found_end {
+        true => Ok(bytes),
+        false => Err(ParseError::MissingMarkers),
+    }
+}
+
+/// Writes microseconds with nanosecond precision, as Chrome trace timestamps are in microseconds.
+fn write_timestamp(out: &mut impl fmt::Write, timestamp_ns: u64) -> fmt::Result {
+    write!(out, "{}.{:03}", timestamp_ns / 1000, timestamp_ns % 1000)
+}
+
+/// Writes records as Chrome trace JSON, in timestamp order. Each CPU is shown as a thread, with
+/// `sched_switch` events splitting its time into slices named after the kernel thread running,
+/// and other events as instants. Arguments are written as strings, as JSON numbers can't hold
+/// every 64 bit value.
+pub fn write_chrome_json(records: &[Record], out: &mut impl fmt::Write) -> fmt::Result {
+    let mut records = records.to_vec();
+    records.sort_by_key(|record| record.timestamp_ns);
+    // CPUs with a slice begun
+    let mut running = BTreeSet::new();
+    write!(out, "{{\"traceEvents\":[")?;
+    let mut separator = "\n";
+    for record in records.iter() {
+        let cpu = record.cpu;
+        if record.event == Event::SchedSwitch {
+            if running.contains(&cpu) {
+                write!(
+                    out,
+                    "{separator}{{\"ph\":\"E\",\"pid\":0,\"tid\":{cpu},\"ts\":"
+                )?;
+                write_timestamp(out, record.timestamp_ns)?;
+                write!(out, "}}")?;
+                separator = ",\n";
+            }
+            running.insert(cpu);
+            write!(
+                out,
+                "{separator}{{\"name\":\"thread {}\",\"ph\":\"B\",\"pid\":0,\"tid\":{cpu},\"ts\":",
+                record.args[1],
+            )?;
+        } else {
+            write!(
+                out,
+                "{separator}{{\"name\":\"{}\",\"ph\":\"i\",\"s\":\"t\",\"pid\":0,\"tid\":{cpu},\"ts\":",
+                record.event.name(),
+            )?;
+        }
+        separator = ",\n";
+        write_timestamp(out, record.timestamp_ns)?;
+        write!(out, ",\"args\":{{")?;
+        for (index, ((name, format), value)) in
+            record.event.args().iter().zip(record.args).enumerate()
+        {
+            if index > 0 {
+                write!(out, ",")?;
+            }
+            match format {
+                ArgFormat::Decimal => write!(out, "\"{name}\":\"{value}\"")?,
+                ArgFormat::Hex => write!(out, "\"{name}\":\"{value:#x}\"")?,
+            }
+        }
+        write!(out, "}}}}")?;
+    }
+    writeln!(out, "\n]}}")
+}
@@ -0,0 +1,113 @@
+//! Host tests, round tripping traces through the serial framing and checking the JSON and text
+//! output.
+
+use super::*;
+use std::string::{String, ToString};
+use std::vec;
+
+fn record(timestamp_ns: u64, cpu: u32, event: Event, args: [u64; 3]) -> Record {
+    Record {
+        timestamp_ns,
+        cpu,
+        event,
+        args,
+    }
+}
+
+fn sample_records() -> Vec<Record> {
+    vec![
+        record(2_500, 0, Event::SchedSwitch, [1, 2, 0]),
+        record(1_000, 0, Event::SchedSwitch, [0, 1, 0]),
+        record(2_000, 1, Event::Irq, [130, 0, 0]),
+        record(
+            3_001,
+            0,
+            Event::PageFault,
+            [0xFFFF_8000_0000_1000, 0x40_1000, 0x6],
+        ),
+    ]
+}
+
+/// Encodes records as the kernel does, with log output around and inside the trace.
+fn capture(records: &[Record]) -> String {
+    let mut capture = String::from("[INFO] Booting\r\n");
+    capture.push_str(BEGIN_MARKER);
+    capture.push_str("\r\n");
+    let mut base64 = String::new();
+    let mut writer = Base64Writer::new(&mut base64);
+    writer.write(&header(records.len() as u32)).unwrap();
+    for record in records {
+        writer.write(&record.to_bytes()).unwrap();
+    }
+    writer.finish().unwrap();
+    for (index, line) in base64.lines().enumerate() {
+        if index == 1 {
+            capture.push_str("[WARN] Something happened\r\n");
+        }
+        capture.push_str(line);
+        capture.push_str("\r\n");
+    }
+    capture.push_str(END_MARKER);
+    capture.push_str("\r\nkshell> ");
+    capture
+}
+
+#[test]
+fn round_trips_through_capture() {
+    let records = sample_records();
+    let bytes = decode_capture(&capture(&records)).unwrap();
+    assert_eq!(bytes.len(), HEADER_SIZE + records.len() * RECORD_SIZE);
+    assert_eq!(parse(&bytes), Ok(records));
+}
+
+#[test]
+fn rejects_bad_traces() {
+    assert_eq!(
+        decode_capture("no trace here"),
+        Err(ParseError::MissingMarkers)
+    );
+    let mut bytes = header(1).to_vec();
+    assert_eq!(parse(&bytes), Err(ParseError::Truncated));
+    bytes.extend_from_slice(&record(0, 0, Event::Irq, [0; 3]).to_bytes());
+    bytes[12 + HEADER_SIZE] = 99;
+    assert_eq!(parse(&bytes), Err(ParseError::UnknownEvent(99)));
+    bytes[8] = 2;
+    assert_eq!(parse(&bytes), Err(ParseError::UnsupportedVersion(2)));
+    bytes[0] = b'X';
+    assert_eq!(parse(&bytes), Err(ParseError::BadMagic));
+}
+
+#[test]
+fn writes_chrome_json() {
+    let mut json = String::new();
+    write_chrome_json(&sample_records(), &mut json).unwrap();
+    let expected = concat!(
+        "{\"traceEvents\":[\n",
+        "{\"name\":\"thread 1\",\"ph\":\"B\",\"pid\":0,\"tid\":0,\"ts\":1.000,",
+        "\"args\":{\"from\":\"0\",\"to\":\"1\"}},\n",
+        "{\"name\":\"irq\",\"ph\":\"i\",\"s\":\"t\",\"pid\":0,\"tid\":1,\"ts\":2.000,",
+        "\"args\":{\"vector\":\"130\"}},\n",
+        "{\"ph\":\"E\",\"pid\":0,\"tid\":0,\"ts\":2.500},\n",
+        "{\"name\":\"thread 2\",\"ph\":\"B\",\"pid\":0,\"tid\":0,\"ts\":2.500,",
+        "\"args\":{\"from\":\"1\",\"to\":\"2\"}},\n",
+        "{\"name\":\"page_fault\",\"ph\":\"i\",\"s\":\"t\",\"pid\":0,\"tid\":0,\"ts\":3.001,",
+        "\"args\":{\"address\":\"0xffff800000001000\",\"instruction\":\"0x401000\",",
+        "\"error_code\":\"0x6\"}}\n",
+        "]}\n",
+    );
+    assert_eq!(json, expected);
+}
+
+#[test]
+fn displays_records() {
+    let records = sample_records();
+    assert_eq!(
+        records[0].to_string(),
+        "[    0.000002500] cpu0 sched_switch from=1 to=2"
+    );
+    assert_eq!(
+        records[3].to_string(),
+        "[    0.000003001] cpu0 page_fault address=0xffff800000001000 instruction=0x401000 \
+         error_code=0x6"
+    );
+}
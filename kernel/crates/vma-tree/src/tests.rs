@@ -0,0 +1,328 @@
+//! Host tests, running random sequences of operations on a tree and checking it against a simple
+//! model of its segments after each one.
+
+use super::*;
+use std::alloc::{Layout, alloc, dealloc};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::vec::Vec;
+
+const NUM_PAGES: usize = 2048;
+const HIGHEST_ADDRESS: usize = NUM_PAGES * PAGE_SIZE - 1;
+const MAX_SEGMENT_PAGES: usize = 4;
+const PAGE_LAYOUT: Layout = match Layout::from_size_align(PAGE_SIZE, PAGE_SIZE) {
+    Ok(layout) => layout,
+    Err(_) => panic!(),
+};
+
+std::thread_local! {
+    static LIVE_PAGES: Cell<usize> = const { Cell::new(0) };
+    /// Page allocations left before they start failing.
+    static ALLOCATIONS_LEFT: Cell<usize> = const { Cell::new(usize::MAX) };
+}
+
+/// Pages from the global allocator, counted per thread so tests can check for leaks.
+struct TestPages;
+
+unsafe impl PageStorage for TestPages {
+    fn allocate_page() -> Result<NonNull<u8>, AllocError> {
+        let allocations_left = ALLOCATIONS_LEFT.get();
+        if allocations_left == 0 {
+            return Err(AllocError);
+        }
+        ALLOCATIONS_LEFT.set(allocations_left - 1);
+        let page = NonNull::new(unsafe { alloc(PAGE_LAYOUT) }).ok_or(AllocError)?;
+        LIVE_PAGES.set(LIVE_PAGES.get() + 1);
+        Ok(page)
+    }
+
+    unsafe fn free_page(page: NonNull<u8>) {
+        LIVE_PAGES.set(LIVE_PAGES.get() - 1);
+        unsafe { dealloc(page.as_ptr(), PAGE_LAYOUT) };
+    }
+}
+
+type Tree = VMATree<TestPages>;
+
+/// Used segments, as their first address mapped to their last address and flags.
+type Model = BTreeMap<usize, (usize, u32)>;
+
+/// A leaf's first and last address, and flags if it's used.
+type Leaf = (usize, usize, Option<u32>);
+
+/// Xorshift generator, so failures can be reproduced from their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+fn flags(bits: u32) -> NodeFlags {
+    NodeFlags(bits)
+}
+
+/// Returns the leaves a tree holding `model` should have, in address order.
+fn expected_leaves(model: &Model) -> Vec<Leaf> {
+    let mut leaves = Vec::new();
+    let mut next_address = 0;
+    for (&start, &(end, bits)) in model {
+        if start > next_address {
+            leaves.push((next_address, start - 1, None));
+        }
+        leaves.push((start, end, Some(bits)));
+        next_address = end + 1;
+    }
+    if next_address <= HIGHEST_ADDRESS {
+        leaves.push((next_address, HIGHEST_ADDRESS, None));
+    }
+    leaves
+}
+
+/// Returns the number of nodes in the subtree at `node`.
+unsafe fn count_nodes(node: NodePtr) -> usize {
+    unsafe {
+        match node.read() {
+            Node::Leaf(_) => 1,
+            Node::Branch(branch) => count_nodes(branch.left) + count_nodes(branch.right) + 1,
+        }
+    }
+}
+
+/// Checks the invariants of `tree`, that its leaves match `model`, and that its storage holds
+/// exactly its nodes.
+fn check(tree: &Tree, model: &Model) {
+    tree.verify().unwrap();
+    let num_nodes = unsafe { count_nodes(tree.root) };
+    let mut leaves = Vec::new();
+    tree.for_each_leaf(|leaf, start, end| {
+        let bits = match leaf {
+            LeafNode::Empty { .. } => None,
+            LeafNode::Used { flags } => Some(flags.0),
+        };
+        leaves.push((start, end, bits));
+    });
+    assert_eq!(leaves, expected_leaves(model));
+    let (mut num_pages, mut num_reserved) = (0, 0);
+    let mut page = Some(tree.node_storage.head);
+    while let Some(page_ptr) = page {
+        let page_ref = unsafe { page_ptr.as_ref() };
+        num_pages += 1;
+        num_reserved += NodeStoragePage::MAX_NODES - page_ref.free_entries;
+        page = page_ref.next_page;
+    }
+    assert_eq!(
+        num_reserved, num_nodes,
+        "storage holds nodes not in the tree"
+    );
+    assert_eq!(num_pages, LIVE_PAGES.get(), "storage pages leaked");
+}
+
+/// Checks `find_gap` against a search of the expected leaves.
+fn check_find_gap(tree: &Tree, model: &Model, min_start: usize, len: usize) {
+    let expected = expected_leaves(model)
+        .into_iter()
+        .filter(|&(_, end, bits)| bits.is_none() && end >= min_start)
+        .map(|(start, end, _)| (usize::max(start, min_start), end))
+        .find(|&(start, end)| end - start >= len - 1)
+        .map(|(start, _)| start);
+    assert_eq!(
+        tree.find_gap(min_start, len),
+        expected,
+        "find_gap({min_start:#x}, {len:#x})",
+    );
+}
+
+fn random_insert(tree: &mut Tree, model: &mut Model, rng: &mut Rng) {
+    let start = rng.below(NUM_PAGES) * PAGE_SIZE;
+    let len = (1 + rng.below(MAX_SEGMENT_PAGES)) * PAGE_SIZE;
+    let end = start + len - 1;
+    let overlaps = model
+        .range(..=end)
+        .next_back()
+        .is_some_and(|(_, (seg_end, _))| *seg_end >= start);
+    if end > HIGHEST_ADDRESS || overlaps {
+        return;
+    }
+    let bits = rng.below(8) as u32;
+    tree.insert(&mut 0, start, len, flags(bits)).unwrap();
+    model.insert(start, (end, bits));
+}
+
+fn random_delete(tree: &mut Tree, model: &mut Model, rng: &mut Rng) {
+    if model.is_empty() {
+        return;
+    }
+    let (&start, &(end, _)) = model.iter().nth(rng.below(model.len())).unwrap();
+    tree.delete(start + rng.below((end - start) / PAGE_SIZE + 1) * PAGE_SIZE);
+    model.remove(&start);
+}
+
+/// Resizes a segment the way the kernel does, moving the boundary with the gap after it if there
+/// is one, or splitting off a new gap otherwise.
+fn random_resize(tree: &mut Tree, model: &mut Model, rng: &mut Rng) {
+    if model.is_empty() {
+        return;
+    }
+    let (&start, &(end, bits)) = model.iter().nth(rng.below(model.len())).unwrap();
+    let new_end = start + (1 + rng.below(MAX_SEGMENT_PAGES)) * PAGE_SIZE - 1;
+    let next_gap_end = match end < HIGHEST_ADDRESS {
+        true => Some(tree.get_leaf_containing(end + 1))
+            .filter(|info| unsafe { info.leaf.is_empty_leaf() })
+            .map(|info| info.end),
+        false => None,
+    };
+    unsafe {
+        match next_gap_end {
+            Some(gap_end) if new_end < gap_end => tree.move_boundary(start, end, new_end),
+            Some(_) => return,
+            None if new_end < end => {
+                let nodes = tree.reserve_node_pair(&mut 0).unwrap();
+                tree.split_tail(start, end, new_end, nodes);
+            }
+            None => return,
+        }
+    }
+    model.insert(start, (new_end, bits));
+}
+
+#[test]
+fn random_operations_keep_invariants() {
+    for seed in 1..=32u64 {
+        let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let mut model = Model::new();
+        let mut tree = Tree::new(&mut 0, HIGHEST_ADDRESS).unwrap();
+        for _ in 0..1000 {
+            match rng.below(6) {
+                0..=2 => random_insert(&mut tree, &mut model, &mut rng),
+                3 | 4 => random_delete(&mut tree, &mut model, &mut rng),
+                _ => random_resize(&mut tree, &mut model, &mut rng),
+            }
+            check(&tree, &model);
+            let min_start = rng.below(NUM_PAGES) * PAGE_SIZE;
+            let len = (1 + rng.below(MAX_SEGMENT_PAGES * 2)) * PAGE_SIZE;
+            check_find_gap(&tree, &model, min_start, len);
+        }
+        while let Some((&start, _)) = model.first_key_value() {
+            tree.delete(start);
+            model.remove(&start);
+            check(&tree, &model);
+        }
+        assert!(unsafe { tree.root.is_empty_leaf() });
+        drop(tree);
+        assert_eq!(LIVE_PAGES.get(), 0, "seed {seed} leaked storage pages");
+    }
+}
+
+#[test]
+fn dense_segments_span_storage_pages() {
+    let mut model = Model::new();
+    let mut tree = Tree::new(&mut 0, HIGHEST_ADDRESS).unwrap();
+    let mut pages_used = 0;
+    // Every other page, so every segment needs its own leaf and gap
+    for page in (1..NUM_PAGES).step_by(2) {
+        let start = page * PAGE_SIZE;
+        tree.insert(&mut pages_used, start, PAGE_SIZE, flags(0b11))
+            .unwrap();
+        model.insert(start, (start + PAGE_SIZE - 1, 0b11));
+    }
+    check(&tree, &model);
+    assert!(pages_used > 1);
+    assert_eq!(LIVE_PAGES.get(), pages_used + 1);
+    // Deleting in a different order from inserting, so different rotations are needed
+    for page in (1..NUM_PAGES).step_by(2).rev().step_by(3) {
+        tree.delete(page * PAGE_SIZE);
+        model.remove(&(page * PAGE_SIZE));
+        check(&tree, &model);
+    }
+    drop(tree);
+    assert_eq!(LIVE_PAGES.get(), 0);
+}
+
+#[test]
+fn find_gap_in_fragmented_tree() {
+    let mut model = Model::new();
+    let mut tree = Tree::new(&mut 0, HIGHEST_ADDRESS).unwrap();
+    // Single page segments separated by gaps of 1 to 4 pages, then one big gap to the end
+    let mut start = PAGE_SIZE;
+    for i in 0..NUM_PAGES / 8 {
+        tree.insert(&mut 0, start, PAGE_SIZE, flags(0b11)).unwrap();
+        model.insert(start, (start + PAGE_SIZE - 1, 0b11));
+        start += PAGE_SIZE * (2 + i % 4);
+    }
+    check(&tree, &model);
+    for len in (1..=6).map(|num_pages| num_pages * PAGE_SIZE) {
+        for min_start in (0..=HIGHEST_ADDRESS).step_by(PAGE_SIZE * 3) {
+            check_find_gap(&tree, &model, min_start, len);
+        }
+    }
+    // Nothing fits once the end is filled too
+    tree.insert(&mut 0, start, HIGHEST_ADDRESS + 1 - start, flags(0b1))
+        .unwrap();
+    model.insert(start, (HIGHEST_ADDRESS, 0b1));
+    assert_eq!(tree.find_gap(0, PAGE_SIZE * 5), None);
+    for len in (1..=5).map(|num_pages| num_pages * PAGE_SIZE) {
+        check_find_gap(&tree, &model, 0, len);
+    }
+}
+
+#[test]
+fn failed_insert_leaves_tree_unchanged() {
+    let mut model = Model::new();
+    let mut tree = Tree::new(&mut 0, HIGHEST_ADDRESS).unwrap();
+    ALLOCATIONS_LEFT.set(0);
+    let mut failed = false;
+    for page in (1..NUM_PAGES).step_by(4) {
+        let start = page * PAGE_SIZE;
+        match tree.insert(&mut 0, start, PAGE_SIZE * 2, flags(0b1)) {
+            Ok(_) => _ = model.insert(start, (start + PAGE_SIZE * 2 - 1, 0b1)),
+            Err(AllocError) => failed = true,
+        }
+        check(&tree, &model);
+    }
+    assert!(failed);
+    assert!(tree.reserve_node_pair(&mut 0).is_err());
+    check(&tree, &model);
+    ALLOCATIONS_LEFT.set(usize::MAX);
+    drop(tree);
+    assert_eq!(LIVE_PAGES.get(), 0);
+}
+
+#[test]
+fn verify_finds_corruption() {
+    let mut tree = Tree::new(&mut 0, HIGHEST_ADDRESS).unwrap();
+    for page in [1, 3, 5, 7] {
+        tree.insert(&mut 0, page * PAGE_SIZE, PAGE_SIZE, flags(0b1))
+            .unwrap();
+    }
+    assert_eq!(tree.verify(), Ok(()));
+    unsafe {
+        let root = tree.root.unwrap_branch();
+        let pivot = root.pivot();
+        root.set_color(NodeColor::Red);
+        assert_eq!(tree.verify(), Err(VerifyError::RedRoot));
+        root.set_color(NodeColor::Black);
+        let max_empty_area_size = (*root.raw()).max_empty_area_size;
+        root.set_max_empty_area_size(max_empty_area_size + 1);
+        assert_eq!(
+            tree.verify(),
+            Err(VerifyError::WrongMaxEmptyAreaSize(pivot))
+        );
+        root.set_max_empty_area_size(max_empty_area_size);
+        root.set_pivot(HIGHEST_ADDRESS + 1);
+        assert_eq!(
+            tree.verify(),
+            Err(VerifyError::PivotOutOfRange(HIGHEST_ADDRESS + 1))
+        );
+        root.set_pivot(pivot);
+    }
+    assert_eq!(tree.verify(), Ok(()));
+}
@@ -0,0 +1,187 @@
+//! Consistency checks and dumps of a tree, for tracking down corruption.
+//!
+//! In debug builds, every operation changing the tree's shape is followed by `verify`, and the tree
+//! is dumped to the log before panicking if it fails.
+
+use super::*;
+
+/// Deepest a node can be before verifying gives up, which no balanced tree of pages in a 64 bit
+/// address space comes near. Stops loops in a corrupt tree from recursing forever.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VerifyError {
+    #[error("the root is red")]
+    RedRoot,
+    #[error("the red branch at {0:#x} has a red child")]
+    RedChildOfRed(usize),
+    #[error("the branch at {0:#x} has subtrees with different black heights")]
+    UnequalBlackHeights(usize),
+    #[error("the branch at {0:#x} has the wrong parent")]
+    WrongParent(usize),
+    #[error("the branch at {0:#x} is a temporary node")]
+    TemporaryNode(usize),
+    #[error("the pivot {0:#x} is outside the range of its parent")]
+    PivotOutOfRange(usize),
+    #[error("the empty leaf at {0:#x} has the wrong size")]
+    WrongEmptySize(usize),
+    #[error("the empty leaf at {0:#x} follows another empty leaf")]
+    AdjacentEmptyLeaves(usize),
+    #[error("the branch at {0:#x} has the wrong largest gap")]
+    WrongMaxEmptyAreaSize(usize),
+    #[error("the tree is too deep to be balanced")]
+    TooDeep,
+}
+
+impl<S: PageStorage> VMATree<S> {
+    /// Checks the red-black properties, that pivots are ordered so leaves cover the whole address
+    /// space without overlapping, and that the sizes of empty leaves and the largest gap under each
+    /// branch are correct.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        unsafe {
+            if self.root.color() == NodeColor::Red {
+                return Err(VerifyError::RedRoot);
+            }
+            let mut previous_leaf_empty = false;
+            verify_subtree(
+                self.root,
+                None,
+                0,
+                self.highest_address,
+                0,
+                &mut previous_leaf_empty,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Panics with a dump of the tree if `verify` fails, in debug builds only.
+    pub(crate) fn debug_verify(&self) {
+        if cfg!(debug_assertions)
+            && let Err(error) = self.verify()
+        {
+            self.dump();
+            panic!("VMA tree is corrupt: {error}");
+        }
+    }
+
+    /// Logs every node, indented by depth, in address order.
+    pub fn dump(&self) {
+        log::info!("VMA tree up to {:#x}:", self.highest_address);
+        unsafe { dump_subtree(self.root, 0, self.highest_address, 1) };
+    }
+}
+
+/// Returns the black height and largest gap of the subtree at `node`, which covers `start` to
+/// `end`. `previous_leaf_empty` is whether the leaf before the subtree is empty, and is updated to
+/// whether its last leaf is.
+unsafe fn verify_subtree(
+    node: NodePtr,
+    parent: Option<BranchNodePtr>,
+    start: usize,
+    end: usize,
+    depth: usize,
+    previous_leaf_empty: &mut bool,
+) -> Result<(usize, usize), VerifyError> {
+    if depth > MAX_DEPTH {
+        return Err(VerifyError::TooDeep);
+    }
+    unsafe {
+        match node.read() {
+            Node::Leaf(LeafNode::Empty { size }) => {
+                if *previous_leaf_empty {
+                    return Err(VerifyError::AdjacentEmptyLeaves(start));
+                }
+                *previous_leaf_empty = true;
+                if size != end - start + 1 {
+                    return Err(VerifyError::WrongEmptySize(start));
+                }
+                Ok((1, size))
+            }
+            Node::Leaf(LeafNode::Used { .. }) => {
+                *previous_leaf_empty = false;
+                Ok((1, 0))
+            }
+            Node::Branch(branch) => {
+                let branch_ptr = node.unwrap_branch();
+                let pivot = branch.pivot();
+                if branch_ptr.is_temp_null() {
+                    return Err(VerifyError::TemporaryNode(pivot));
+                }
+                if branch.parent != parent {
+                    return Err(VerifyError::WrongParent(pivot));
+                }
+                if pivot <= start || pivot > end {
+                    return Err(VerifyError::PivotOutOfRange(pivot));
+                }
+                if branch.color() == NodeColor::Red
+                    && (branch.left.color() == NodeColor::Red
+                        || branch.right.color() == NodeColor::Red)
+                {
+                    return Err(VerifyError::RedChildOfRed(pivot));
+                }
+                let (left_height, left_max) = verify_subtree(
+                    branch.left,
+                    Some(branch_ptr),
+                    start,
+                    pivot - 1,
+                    depth + 1,
+                    previous_leaf_empty,
+                )?;
+                let (right_height, right_max) = verify_subtree(
+                    branch.right,
+                    Some(branch_ptr),
+                    pivot,
+                    end,
+                    depth + 1,
+                    previous_leaf_empty,
+                )?;
+                if left_height != right_height {
+                    return Err(VerifyError::UnequalBlackHeights(pivot));
+                }
+                let max_empty_area_size = usize::max(left_max, right_max);
+                if branch.max_empty_area_size != max_empty_area_size {
+                    return Err(VerifyError::WrongMaxEmptyAreaSize(pivot));
+                }
+                let height = left_height + (branch.color() == NodeColor::Black) as usize;
+                Ok((height, max_empty_area_size))
+            }
+        }
+    }
+}
+
+/// Logs the subtree at `node`, which should cover `start` to `end`. Ranges are clamped rather than
+/// checked, so corrupt trees can still be dumped.
+unsafe fn dump_subtree(node: NodePtr, start: usize, end: usize, depth: usize) {
+    let indent = depth * 2;
+    if depth > MAX_DEPTH {
+        log::info!("{:indent$}...", "");
+        return;
+    }
+    unsafe {
+        match node.read() {
+            Node::Leaf(LeafNode::Empty { size }) => {
+                log::info!("{:indent$}empty {start:#x}-{end:#x}, size {size:#x}", "");
+            }
+            Node::Leaf(LeafNode::Used { flags }) => {
+                log::info!("{:indent$}used {start:#x}-{end:#x}, {flags:?}", "");
+            }
+            Node::Branch(branch) => {
+                let pivot = branch.pivot();
+                log::info!(
+                    "{:indent$}{:?} branch at {pivot:#x}, largest gap {:#x}{}",
+                    "",
+                    branch.color(),
+                    branch.max_empty_area_size,
+                    match node.unwrap_branch().is_temp_null() {
+                        true => " (temporary)",
+                        false => "",
+                    },
+                );
+                let pivot = pivot.max(start.saturating_add(1)).min(end);
+                dump_subtree(branch.left, start, pivot.saturating_sub(1), depth + 1);
+                dump_subtree(branch.right, pivot, end, depth + 1);
+            }
+        }
+    }
+}
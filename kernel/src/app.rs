@@ -0,0 +1,50 @@
+//! Running a built-in application instead of user code, for fixed-function appliances and
+//! benchmarks. Enabled with the `app` feature, which builds in the `kernel-app` crate.
+
+use crate::arch::clock;
+use crate::cpio;
+use crate::kthread;
+use app_api::{AppMain, Kernel};
+use core::fmt;
+
+const APP_MAIN: AppMain = kernel_app::main;
+
+/// Kernel services given to the application.
+struct AppKernel;
+
+impl Kernel for AppKernel {
+    fn log(&self, args: fmt::Arguments) {
+        log::info!(target: "app", "{args}");
+    }
+
+    fn now_ns(&self) -> u64 {
+        clock::now_ns()
+    }
+
+    fn sleep_ms(&self, num_ms: u64) {
+        kthread::sleep_ms(num_ms);
+    }
+
+    fn yield_now(&self) {
+        kthread::yield_now();
+    }
+
+    fn initrd_file(&self, path: &str) -> Option<&'static [u8]> {
+        let initrd = (*cpio::INITRD.lock())?;
+        cpio::find_file(initrd, path.as_bytes())
+    }
+}
+
+fn app_thread(_: usize) -> usize {
+    let exit_value = APP_MAIN(&AppKernel);
+    log::info!("Kernel application exited with {exit_value}");
+    exit_value
+}
+
+/// Starts the application in its own thread.
+pub fn start() {
+    match kthread::spawn("app", app_thread, 0) {
+        Ok(_) => log::debug!("Started kernel application"),
+        Err(err) => log::error!("Failed to start kernel application - {err}"),
+    }
+}
@@ -0,0 +1,751 @@
+//! Address spaces, each with their own lower half mappings and the kernel's higher half.
+//!
+//! Whether lower half pages are accessible from user mode is decided by the address space's
+//! `Policy`, either `User` for processes or `Kernel` for supervisor-only mappings.
+
+use super::page_allocation::{self, OwnedPhysicalPage, ReservePageError};
+use super::paging::{PAGE_SIZE, PageTable, PageTableData, PageTableEntry, align_to_page};
+use crate::vma::SegmentFlags;
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::marker::PhantomData;
+use core::mem::transmute;
+use core::task::Poll;
+
+const LEVEL_MASKS: [usize; 4] = [
+    0xFF80_0000_0000,
+    0x007F_C000_0000,
+    0x0000_3FE0_0000,
+    0x0000_001F_F000,
+];
+/// PML4 entries for the lower half, owned by each address space. The rest are the kernel's.
+const LOWER_HALF_ENTRIES: usize = 256;
+const PRESENT: u64 = 1 << 0;
+const USER_ACCESSABLE: u64 = 1 << 2;
+
+/// Decides how an address space's lower half is mapped.
+pub trait Policy {
+    /// Whether lower half pages can be accessed from user mode.
+    const USER_ACCESSABLE: bool;
+}
+
+/// Lower half accessible from user mode, for processes.
+pub struct User;
+
+impl Policy for User {
+    const USER_ACCESSABLE: bool = true;
+}
+
+/// Lower half only accessible from the kernel.
+pub struct Kernel;
+
+impl Policy for Kernel {
+    const USER_ACCESSABLE: bool = false;
+}
+
+pub type UserAddressSpace = AddressSpace<User>;
+pub type KernelAddressSpace = AddressSpace<Kernel>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum AddressSpaceError {
+    #[error("page already exists")]
+    PageAlreadyExists,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+impl From<ReservePageError> for AddressSpaceError {
+    fn from(_: ReservePageError) -> Self {
+        AddressSpaceError::OutOfMemory
+    }
+}
+
+/// Switches the current CPU to the address space with the top level page table at `address`.
+pub unsafe fn load_page_table(address: usize) {
+    unsafe { asm!("mov cr3, {}", in(reg) address, options(nostack)) }
+}
+
+pub struct AddressSpace<P: Policy> {
+    pml4: OwnedPhysicalPage,
+    policy: PhantomData<P>,
+}
+
+impl<P: Policy> AddressSpace<P> {
+    /// Flags of generated parent tables, which don't restrict the flags of their children.
+    const PARENT_FLAGS: u64 = match P::USER_ACCESSABLE {
+        true => PageTableEntry::READ_WRITE_EXECUTE.0 | USER_ACCESSABLE,
+        false => PageTableEntry::READ_WRITE_EXECUTE.0,
+    };
+    /// Bits always set on child entries.
+    const CHILD_FLAGS: u64 = match P::USER_ACCESSABLE {
+        true => PRESENT | USER_ACCESSABLE,
+        false => PRESENT,
+    };
+
+    /// Creates an address space with an empty lower half.
+    pub fn new() -> Result<Self, ReservePageError> {
+        // Create new PML4
+        let mut pml4 = page_allocation::find_and_reserve_page()?;
+        let pml4_table = unsafe { transmute::<&mut [u8; 4096], &mut PageTable>(&mut pml4) };
+        // Clear first half
+        pml4_table[..LOWER_HALF_ENTRIES].fill(PageTableEntry::ZERO);
+        // Fill second half with kernel pages
+        let kernel_pml4 = unsafe { &*(page_allocation::page_table_address() as *const PageTable) };
+        pml4_table[LOWER_HALF_ENTRIES..].copy_from_slice(&kernel_pml4[LOWER_HALF_ENTRIES..]);
+        Ok(Self {
+            pml4,
+            policy: PhantomData,
+        })
+    }
+
+    fn pml4_address(&self) -> usize {
+        self.pml4.as_ref() as *const [u8; 4096] as usize
+    }
+
+    /// Returns the physical address of the top level page table, for `load_page_table`.
+    pub fn page_table_address(&self) -> usize {
+        self.pml4_address()
+    }
+
+    /// Switches the current CPU to this address space.
+    pub unsafe fn load(&self) {
+        unsafe { load_page_table(self.pml4_address()) }
+    }
+
+    /// Creates a copy of this address space, with each lower half page copied into a new page.
+    pub fn try_clone(&self) -> Result<Self, ReservePageError> {
+        let clone = Self::new()?;
+        let source = unsafe { &*(self.pml4_address() as *const PageTable) };
+        let destination = unsafe { &mut *(clone.pml4_address() as *mut PageTable) };
+        // Partially copied tables are freed when `clone` is dropped on error
+        unsafe { Self::clone_tables(source, destination, 0)? };
+        Ok(clone)
+    }
+
+    unsafe fn clone_tables(
+        source: &[PageTableEntry],
+        destination: &mut [PageTableEntry],
+        level: usize,
+    ) -> Result<(), ReservePageError> {
+        let entries = match level {
+            0 => &source[..LOWER_HALF_ENTRIES],
+            _ => source,
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            if !entry.present() {
+                continue;
+            }
+            // TODO: Add huge page support.
+            if entry.huge_page() {
+                todo!("huge page support")
+            }
+            let new_page = page_allocation::find_and_reserve_page()?.into_raw();
+            destination[i] = entry.replace_addr_with(new_page as usize);
+            unsafe {
+                if level < 3 {
+                    let source_table = &*(entry.address() as *const PageTable);
+                    Self::clone_tables(
+                        source_table,
+                        &mut *(new_page as *mut PageTable),
+                        level + 1,
+                    )?;
+                } else {
+                    (*new_page).copy_from_slice(&*(entry.address() as *const [u8; 4096]));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees every lower half page and page table, along with the PML4. Returns the number of
+    /// pages freed. The address space must not be loaded.
+    pub fn destroy(mut self) -> usize {
+        // The PML4 is freed when dropped
+        self.free_lower_half() + 1
+    }
+
+    fn free_lower_half(&mut self) -> usize {
+        let pml4 = unsafe { &mut *(self.pml4_address() as *mut PageTable) };
+        let mut pages_freed = 0;
+        for entry in &mut pml4[..LOWER_HALF_ENTRIES] {
+            if entry.present() {
+                pages_freed += unsafe { Self::free_page_tree(*entry, 0) };
+                *entry = PageTableEntry::ZERO;
+            }
+        }
+        pages_freed
+    }
+
+    /// Returns the child entry mapping `virtual_address`, or `None` if a parent table is missing.
+    fn child_entry(&mut self, virtual_address: usize) -> Option<&mut PageTableEntry> {
+        let mut current_address = self.pml4_address();
+        for (i, level_mask) in LEVEL_MASKS.iter().enumerate() {
+            let current_table = current_address as *mut PageTable;
+            let index = ((*level_mask & virtual_address) >> ((3 - i) * 9 + 12)) % 512;
+            let entry = unsafe { &mut (&mut *current_table)[index] };
+            if i == 3 {
+                return Some(entry);
+            }
+            debug_assert!(!entry.huge_page());
+            if !entry.present() {
+                return None;
+            }
+            current_address = entry.address();
+        }
+        unreachable!()
+    }
+
+    /// Returns the child entry mapping `virtual_address`, creating parent tables as needed.
+    /// Returns the addresses of the parent tables created, for cleanup on later errors.
+    fn create_child_entry(
+        &mut self,
+        virtual_address: usize,
+        parent_pages_created: &mut [Option<usize>; 3],
+    ) -> Result<&mut PageTableEntry, ReservePageError> {
+        let mut current_address = self.pml4_address();
+        for (i, level_mask) in LEVEL_MASKS.iter().enumerate() {
+            let current_table = current_address as *mut PageTable;
+            let index = ((*level_mask & virtual_address) >> ((3 - i) * 9 + 12)) % 512;
+            let entry = unsafe { &mut (&mut *current_table)[index] };
+            if i == 3 {
+                return Ok(entry);
+            }
+            // Allocate parent table if required
+            if !entry.present() {
+                let new_page = page_allocation::find_and_reserve_page()?.into_raw();
+                parent_pages_created[i] = Some(new_page as usize);
+                *entry =
+                    PageTableEntry(new_page as u64 & 0x000F_FFFF_FFFF_F000 | Self::PARENT_FLAGS);
+            }
+            current_address = entry.address();
+        }
+        unreachable!()
+    }
+
+    /// Maps a new page to virtual memory at `virtual_address` aligned down to the nearest
+    /// page, including any required parent pages. Page will be zeroed out. Generated parent pages
+    /// are set to read/write/execute. Child page flags will be set to `flags`.
+    /// Does not do any page invalidation, so the address space must not be in use.
+    pub fn map_blank_page(
+        &mut self,
+        virtual_address: usize,
+        flags: PageTableEntry,
+        pages_used: &mut usize,
+    ) -> Result<(), AddressSpaceError> {
+        self.map_child_page(virtual_address, None, flags, pages_used)
+    }
+
+    /// Maps a new page like `map_blank_page`, then copies `buffer`, at most a page long, to the
+    /// start of it.
+    pub fn map_page_from_buffer(
+        &mut self,
+        virtual_address: usize,
+        flags: PageTableEntry,
+        buffer: &[u8],
+        pages_used: &mut usize,
+    ) -> Result<(), AddressSpaceError> {
+        debug_assert!(buffer.len() <= PAGE_SIZE);
+        self.map_blank_page(virtual_address, flags, pages_used)?;
+        let page_address = self.child_entry(virtual_address).unwrap().address();
+        unsafe {
+            core::ptr::copy_nonoverlapping(buffer.as_ptr(), page_address as *mut u8, buffer.len());
+        }
+        Ok(())
+    }
+
+    /// Maps the existing page at `physical_address` to virtual memory at `virtual_address`, like
+    /// `map_blank_page`. The page isn't cleared, and isn't counted in `pages_used`, as it belongs
+    /// to someone else. The mapping holds a reference to the page.
+    pub fn map_page(
+        &mut self,
+        virtual_address: usize,
+        physical_address: usize,
+        flags: PageTableEntry,
+        pages_used: &mut usize,
+    ) -> Result<(), AddressSpaceError> {
+        self.map_child_page(virtual_address, Some(physical_address), flags, pages_used)
+    }
+
+    /// Maps `physical_address` at `virtual_address`, or a new blank page if it's `None`.
+    fn map_child_page(
+        &mut self,
+        virtual_address: usize,
+        physical_address: Option<usize>,
+        flags: PageTableEntry,
+        pages_used: &mut usize,
+    ) -> Result<(), AddressSpaceError> {
+        let flags = flags.without_unsupported_bits();
+        let child_flags = (flags.0 & 0x8000_0000_0000_0002) | Self::CHILD_FLAGS;
+        // Store any parent pages created for cleanup if an error occurs
+        let mut parent_pages_created: [Option<usize>; 3] = [None; 3];
+        let result = 'blk: {
+            let entry = match self.create_child_entry(virtual_address, &mut parent_pages_created) {
+                Ok(entry) => entry,
+                Err(err) => break 'blk Err(err.into()),
+            };
+            if entry.present() {
+                break 'blk Err(AddressSpaceError::PageAlreadyExists);
+            }
+            let new_page = match physical_address {
+                Some(address) => {
+                    page_allocation::get_page(address);
+                    address
+                }
+                None => match page_allocation::find_and_reserve_page() {
+                    Ok(page) => {
+                        *pages_used += 1;
+                        page.into_raw() as usize
+                    }
+                    Err(err) => break 'blk Err(err.into()),
+                },
+            };
+            *entry = PageTableEntry(new_page as u64 & 0x000F_FFFF_FFFF_F000 | child_flags);
+            Ok(())
+        };
+        match result {
+            Ok(()) => *pages_used += parent_pages_created.iter().flatten().count(),
+            // Cleanup created parent pages if an error occurred. Only the highest is linked into a
+            // table which already existed.
+            Err(_) => {
+                if let Some(level) = parent_pages_created.iter().position(Option::is_some) {
+                    self.unlink_table(virtual_address, level);
+                }
+                for page in parent_pages_created.iter().flatten() {
+                    page_allocation::free_page(*page);
+                }
+            }
+        }
+        result
+    }
+
+    /// Clears the entry at `level` pointing to the table below it for `virtual_address`.
+    fn unlink_table(&mut self, virtual_address: usize, level: usize) {
+        let mut current_address = self.pml4_address();
+        for (i, level_mask) in LEVEL_MASKS.iter().enumerate() {
+            let current_table = current_address as *mut PageTable;
+            let index = ((*level_mask & virtual_address) >> ((3 - i) * 9 + 12)) % 512;
+            let entry = unsafe { &mut (&mut *current_table)[index] };
+            if i == level {
+                *entry = PageTableEntry::ZERO;
+                return;
+            }
+            current_address = entry.address();
+        }
+    }
+
+    /// Unmaps and frees a page at `virtual_address` aligned down to the nearest page.
+    /// Also checks `free_table_check_depth` (up to 3) number of parent page tables for if they're
+    /// empty and able to be freed.
+    /// Returns the number of pages freed.
+    #[must_use]
+    pub fn unmap_page(&mut self, virtual_address: usize, free_table_check_depth: usize) -> usize {
+        self.unmap_child_page(virtual_address, free_table_check_depth, true)
+    }
+
+    /// Unmaps a page mapped with `map_page`, like `unmap_page`, but dropping the mapping's
+    /// reference to the page instead of freeing it. Returns the number of page tables freed.
+    #[must_use]
+    pub fn unmap_shared_page(
+        &mut self,
+        virtual_address: usize,
+        free_table_check_depth: usize,
+    ) -> usize {
+        self.unmap_child_page(virtual_address, free_table_check_depth, false)
+    }
+
+    fn unmap_child_page(
+        &mut self,
+        virtual_address: usize,
+        free_table_check_depth: usize,
+        free_child: bool,
+    ) -> usize {
+        let virtual_address = virtual_address & 0x000FFFFFFFFFF000;
+        // Collect table addresses as we go down
+        let mut table_addresses: [(usize, usize); 4] = [(0, 0); 4];
+        let mut current_address = self.pml4_address();
+        for (i, level_mask) in LEVEL_MASKS.iter().enumerate() {
+            let current_table = current_address as *mut PageTable;
+            let index = ((*level_mask & virtual_address) >> ((3 - i) * 9 + 12)) % 512;
+            table_addresses[i] = (index, current_address);
+            let entry = unsafe { &mut (&mut *current_table)[index] };
+            debug_assert!(!entry.huge_page());
+            if !entry.present() {
+                return 0;
+            }
+            current_address = entry.address();
+        }
+        // Work backwards from the PT, freeing the target page, then each table which is left
+        // empty, for up to `free_table_check_depth` tables.
+        let mut pages_freed = 0;
+        for (tables_checked, (index, table_address)) in
+            table_addresses.iter().copied().rev().enumerate()
+        {
+            let table = unsafe { &mut *(table_address as *mut PageTable) };
+            let page_address = table[index].address();
+            table[index] = PageTableEntry::ZERO;
+            // TODO: For multicore, we need to send an IPI to any other cores running threads in
+            // this process to tell them to invalidate the page. This needs to happen after zeroing
+            // out the entry, but before freeing the page.
+            if free_child || tables_checked != 0 {
+                page_allocation::free_page(page_address);
+                pages_freed += 1;
+            } else {
+                page_allocation::put_page(page_address);
+            }
+            // The PML4 is never freed
+            if tables_checked >= free_table_check_depth || tables_checked == 3 {
+                break;
+            }
+            if table.iter().any(|entry| *entry != PageTableEntry::ZERO) {
+                break;
+            }
+        }
+        pages_freed
+    }
+
+    /// Maps `(size / 4096) + 1` free pages to virtual memory at start address. Fills pages with
+    /// data from provided buffer. Memory past buffer length is zeroed. Generated child entries are
+    /// set to be only readable, generated parent entries are set to be read/write/execute. Flags
+    /// for already existing parent pages are preserved.
+    pub fn map_mem_copy_from_buffer(
+        &mut self,
+        virtual_start_address: usize,
+        size: usize,
+        buffer: &[u8],
+    ) -> Result<(), ReservePageError> {
+        let child_flags = PageTableEntry::READ.0 | Self::CHILD_FLAGS;
+        let num_pages = num_pages(virtual_start_address, size);
+        let mut start_offset = virtual_start_address & 0xFFF;
+        let mut data_written = 0;
+        for page_i in 0..num_pages {
+            let virtual_address = virtual_start_address + (page_i << 12);
+            let entry = self.create_child_entry(virtual_address, &mut [None; 3])?;
+            // Allocate new child page if required
+            if !entry.present() {
+                let new_page = page_allocation::find_and_reserve_page()?.into_raw();
+                *entry = PageTableEntry(new_page as u64 & 0x000F_FFFF_FFFF_F000 | child_flags);
+            }
+            // Write buffer data to page
+            let data_to_write = usize::min(buffer.len() - data_written, 4096 - start_offset);
+            let write_page = unsafe { &mut *(entry.address() as *mut [u8; 4096]) };
+            write_page[start_offset..][0..data_to_write]
+                .copy_from_slice(&buffer[data_written..][..data_to_write]);
+            // Zero out rest of page
+            write_page[start_offset + data_to_write..].fill(0);
+            // Record amount of data written, reset offset
+            data_written += data_to_write;
+            start_offset = 0;
+        }
+        Ok(())
+    }
+
+    /// Unmaps and frees `(size / 4096) + 1` pages starting at the given linear address.
+    pub fn unmap_mem(&mut self, start_address: usize, size: usize) {
+        // TODO: Cleanup parent page table pages, keep number of used pages somewhere in page table?
+        let actual_start_address = start_address & 0x000FFFFFFFFFF000;
+        for page_i in 0..num_pages(start_address, size) {
+            let virtual_address = actual_start_address + (page_i << 12);
+            if let Some(entry) = self.child_entry(virtual_address)
+                && entry.present()
+            {
+                page_allocation::free_page(entry.address());
+                *entry = PageTableEntry::ZERO;
+            }
+        }
+    }
+
+    /// Sets the flags of `(size / 4096) + 1` child pages starting at the given linear address.
+    pub fn change_flags(&mut self, start_address: usize, size: usize, flags: PageTableEntry) {
+        // TODO: Optimize by keeping count of number of pages done, stay at deepest level.
+        let actual_start_address = start_address & 0x000FFFFFFFFFF000;
+        let actual_flags =
+            (flags.without_unsupported_bits().0 & 0x80000000000001FA) | Self::CHILD_FLAGS;
+        for page_i in 0..num_pages(start_address, size) {
+            let virtual_address = actual_start_address + (page_i << 12);
+            if let Some(entry) = self.child_entry(virtual_address)
+                && entry.present()
+            {
+                *entry = PageTableEntry(entry.address() as u64 | actual_flags);
+            }
+        }
+    }
+
+    /// Relaxes the flags of `(size / 4096) + 1` child pages starting at the given linear address.
+    pub fn change_flags_relaxing(
+        &mut self,
+        start_address: usize,
+        size: usize,
+        flags: PageTableEntry,
+    ) {
+        // TODO: Optimize by keeping count of number of pages done, stay at deepest level.
+        let actual_start_address = start_address & 0x000FFFFFFFFFF000;
+        let relaxation_flags = (flags.0 & 0x2) | Self::CHILD_FLAGS;
+        let no_execute_mask = match flags.0 & (1 << 63) == 0 {
+            true => !(1 << 63),
+            false => !0,
+        };
+        for page_i in 0..num_pages(start_address, size) {
+            let virtual_address = actual_start_address + (page_i << 12);
+            if let Some(entry) = self.child_entry(virtual_address)
+                && entry.present()
+            {
+                *entry = PageTableEntry((entry.0 | relaxation_flags) & no_execute_mask);
+            }
+        }
+    }
+
+    /// Frees the page or table `node` points to, along with everything below it. Returns the
+    /// number of pages freed.
+    unsafe fn free_page_tree(node: PageTableEntry, level: usize) -> usize {
+        unsafe {
+            if !node.present() {
+                return 0;
+            }
+            // TODO: Add huge page support.
+            if node.huge_page() {
+                todo!("huge page support")
+            }
+            let mut pages_freed = 1;
+            if level < 3 {
+                let page_table = &mut *(node.address() as *mut PageTable);
+                for entry in page_table {
+                    pages_freed += Self::free_page_tree(*entry, level + 1);
+                }
+            }
+            page_allocation::free_page(node.address());
+            pages_freed
+        }
+    }
+}
+
+impl<P: Policy> Drop for AddressSpace<P> {
+    fn drop(&mut self) {
+        self.free_lower_half();
+    }
+}
+
+/// Returns the number of pages touched by `size` bytes at `start_address`.
+fn num_pages(start_address: usize, size: usize) -> usize {
+    let lower_bound = align_to_page(start_address);
+    let upper_bound = align_to_page(start_address + (size - 1));
+    ((upper_bound - lower_bound) / PAGE_SIZE) + 1
+}
+
+#[derive(Debug)]
+pub struct UnmapMemTask {
+    current_address: usize,
+    pages_left: usize,
+    pages_freed: usize,
+    /// Whether the pages themselves are freed, rather than just their page tables.
+    free_pages: bool,
+}
+
+impl UnmapMemTask {
+    pub fn new(start_address: usize, num_pages: usize) -> Self {
+        Self {
+            current_address: start_address,
+            pages_left: num_pages,
+            pages_freed: 0,
+            free_pages: true,
+        }
+    }
+
+    /// Creates a task to unmap pages mapped with `MapMemTask::new_shared`, leaving the pages
+    /// themselves to their owner.
+    pub fn new_shared(start_address: usize, num_pages: usize) -> Self {
+        Self {
+            free_pages: false,
+            ..Self::new(start_address, num_pages)
+        }
+    }
+
+    /// If this completes, returns the total number of pages freed.
+    pub fn run<P: Policy, F>(
+        &mut self,
+        address_space: &mut AddressSpace<P>,
+        mut should_suspend: F,
+    ) -> Poll<usize>
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            if should_suspend() {
+                return Poll::Pending;
+            }
+            // Calculate how many parent page tables to check for freeing.
+            let page_address = self.current_address;
+            let next_page_address = page_address + 4096;
+            let free_table_check_depth = match self.pages_left == 1 {
+                true => 3,
+                false => tables_left_behind(page_address, next_page_address),
+            };
+            // Unmap the page.
+            self.pages_freed += match self.free_pages {
+                true => address_space.unmap_page(page_address, free_table_check_depth),
+                false => address_space.unmap_shared_page(page_address, free_table_check_depth),
+            };
+            // Advance.
+            self.current_address = next_page_address;
+            self.pages_left -= 1;
+            if self.pages_left == 0 {
+                return Poll::Ready(self.pages_freed);
+            }
+        }
+    }
+}
+
+/// Returns how many levels of page tables covering `address` don't cover `next_address`.
+fn tables_left_behind(address: usize, next_address: usize) -> usize {
+    for (i, level_mask) in LEVEL_MASKS.iter().enumerate() {
+        if address & level_mask != next_address & level_mask {
+            return 3 - i;
+        }
+    }
+    0
+}
+
+/// Returns the page flags for a segment's pages.
+pub fn segment_page_flags(flags: SegmentFlags) -> PageTableEntry {
+    PageTableEntry::from_data(PageTableData {
+        user_accessable: flags.read,
+        writable: flags.write,
+        no_execute: !flags.execute,
+        ..Default::default()
+    })
+}
+
+#[derive(Debug)]
+pub struct MapMemTask {
+    start_address: usize,
+    current_address: usize,
+    pages_allocated: usize,
+    /// Physical addresses of the pages to map, or `None` to map new blank pages.
+    frames: Option<Arc<[usize]>>,
+    state: MapMemState,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum MapMemState {
+    Mapping {
+        pages_left: usize,
+        flags: PageTableEntry,
+    },
+    FailRewinding {
+        error: MapMemError,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MapMemError {
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+impl MapMemTask {
+    pub fn new(start_address: usize, num_pages: usize, flags: SegmentFlags) -> Self {
+        Self {
+            start_address,
+            current_address: start_address,
+            pages_allocated: 0,
+            frames: None,
+            state: MapMemState::Mapping {
+                pages_left: num_pages,
+                flags: segment_page_flags(flags),
+            },
+        }
+    }
+
+    /// Creates a task to map the existing pages at `frames`, in order, instead of new pages. Only
+    /// page tables are counted as allocated.
+    pub fn new_shared(start_address: usize, frames: Arc<[usize]>, flags: SegmentFlags) -> Self {
+        Self {
+            frames: Some(frames.clone()),
+            ..Self::new(start_address, frames.len(), flags)
+        }
+    }
+
+    pub fn start_address(&self) -> usize {
+        self.start_address
+    }
+
+    /// If this completes successfully, returns the total number of pages allocated.
+    /// If this fails, it cleans up all intermediate allocated pages.
+    /// Panics if the task encounters a page already mapped within the range.
+    pub fn run<P: Policy, F>(
+        &mut self,
+        address_space: &mut AddressSpace<P>,
+        mut should_suspend: F,
+    ) -> Poll<Result<usize, MapMemError>>
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            if should_suspend() {
+                return Poll::Pending;
+            }
+            match &mut self.state {
+                MapMemState::Mapping { pages_left, flags } => {
+                    let page_address = self.current_address;
+                    let result = match &self.frames {
+                        Some(frames) => address_space.map_page(
+                            page_address,
+                            frames[(page_address - self.start_address) / PAGE_SIZE],
+                            *flags,
+                            &mut self.pages_allocated,
+                        ),
+                        None => address_space.map_blank_page(
+                            page_address,
+                            *flags,
+                            &mut self.pages_allocated,
+                        ),
+                    };
+                    match result {
+                        Ok(()) => {}
+                        Err(AddressSpaceError::OutOfMemory) => {
+                            // Nothing to rewind if no pages were mapped
+                            if page_address == self.start_address {
+                                return Poll::Ready(Err(MapMemError::OutOfMemory));
+                            }
+                            self.current_address = page_address - 4096;
+                            self.state = MapMemState::FailRewinding {
+                                error: MapMemError::OutOfMemory,
+                            };
+                            continue;
+                        }
+                        Err(err @ AddressSpaceError::PageAlreadyExists) => {
+                            panic!("MapMemTask error - {err}");
+                        }
+                    }
+                    // Advance.
+                    self.current_address = page_address + 4096;
+                    *pages_left -= 1;
+                    if *pages_left == 0 {
+                        return Poll::Ready(Ok(self.pages_allocated));
+                    }
+                }
+                MapMemState::FailRewinding { error } => {
+                    // Calculate how many parent page tables to check for freeing.
+                    let page_address = self.current_address;
+                    let next_page_address = page_address.saturating_sub(4096);
+                    let free_table_check_depth = match page_address == self.start_address {
+                        true => 3,
+                        false => tables_left_behind(page_address, next_page_address),
+                    };
+                    // Unmap the page.
+                    self.pages_allocated -= match self.frames {
+                        Some(_) => {
+                            address_space.unmap_shared_page(page_address, free_table_check_depth)
+                        }
+                        None => address_space.unmap_page(page_address, free_table_check_depth),
+                    };
+                    // Advance.
+                    self.current_address = next_page_address;
+                    if page_address == self.start_address {
+                        debug_assert_eq!(self.pages_allocated, 0);
+                        return Poll::Ready(Err(*error));
+                    }
+                }
+            }
+        }
+    }
+}
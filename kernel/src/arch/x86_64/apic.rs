@@ -1,10 +1,33 @@
 use super::page_allocation;
 use super::paging::PageTableEntry;
+use super::port;
 use crate::LOCAL_APIC_BASE;
-use core::arch::asm;
+
+/// Remaps the legacy PICs' vectors away from the exceptions, then masks all of their interrupts.
+unsafe fn disable_pic() {
+    unsafe {
+        // Initialisation sequence
+        port::write_byte(port::PIC_MASTER_COMMAND, 0x11);
+        port::write_byte(port::PIC_SLAVE_COMMAND, 0x11);
+        // Vector offsets
+        port::write_byte(port::PIC_MASTER_DATA, 0x20);
+        port::write_byte(port::PIC_SLAVE_DATA, 0x28);
+        // Inform master PIC of slave PIC at IRQ2
+        port::write_byte(port::PIC_MASTER_DATA, 0x04);
+        // Tell slave PIC cascade identity
+        port::write_byte(port::PIC_SLAVE_DATA, 0x02);
+        // Set 8086 mode
+        port::write_byte(port::PIC_MASTER_DATA, 0x01);
+        port::write_byte(port::PIC_SLAVE_DATA, 0x01);
+        // Mask all interrupts
+        port::write_byte(port::PIC_SLAVE_DATA, 0xFF);
+        port::write_byte(port::PIC_MASTER_DATA, 0xFF);
+    }
+}
 
 pub mod local {
-    use super::{LOCAL_APIC_BASE, PageTableEntry, asm, page_allocation};
+    use super::{LOCAL_APIC_BASE, PageTableEntry, disable_pic, page_allocation};
+    use crate::arch::msr::{ApicBase, Msr};
 
     #[repr(transparent)]
     pub struct LocalApic(usize);
@@ -27,42 +50,8 @@ pub mod local {
 
         pub fn enable_bsp_local_apic(&mut self) {
             unsafe {
-                asm!(
-                    // -- Disable PIC --
-                    // PIC initialisation sequence
-                    "mov al, 0x11",
-                    "out 0x20, al",
-                    "out 0xA0, al",
-                    // Master PIC vector offset
-                    "mov al, 0x20",
-                    "out 0x21, al",
-                    // Slave PIC vector offset
-                    "mov al, 0x28",
-                    "out 0xA1, al",
-                    // Inform Master PIC of Slave PIC at IRQ2
-                    "mov al, 0x04",
-                    "out 0x21, al",
-                    // Tell Slave PIC cascade identity
-                    "mov al, 0x02",
-                    "out 0xA1, al",
-                    // Set 8086 mode
-                    "mov al, 0x01",
-                    "out 0x21, al",
-                    "out 0xA1, al",
-                    // Mask all interrupts
-                    "mov al, 0xFF",
-                    "out 0xA1, al",
-                    "out 0x21, al",
-                    // -- Enable Local APIC --
-                    "mov ecx, 0x1B",
-                    "rdmsr",
-                    "or eax, 0x800",
-                    "wrmsr",
-                    out("eax") _,
-                    out("ecx") _,
-                    out("edx") _,
-                    options(nomem, nostack),
-                );
+                disable_pic();
+                ApicBase::update(|value| value.set_enabled(true));
             }
             // Remap APIC Spurious Interrupt Vector Register to 0xFF and enable
             self.write_register(LocalApicRegister::SpuriousInterruptVector, 0x1FF);
@@ -87,8 +76,33 @@ pub mod local {
         pub fn signal_eoi(&mut self) {
             self.write_register(LocalApicRegister::Eoi, 0);
         }
+
+        pub fn id(&self) -> u8 {
+            (self.read_register(LocalApicRegister::LapicId) >> 24) as u8
+        }
+
+        /// Sends a fixed interrupt on `vector` to the Local APIC with `apic_id`, once the last
+        /// one sent has been accepted. Interrupts must be disabled, so that nothing else can send
+        /// one between writing the two halves of the command.
+        pub fn send_ipi(&mut self, apic_id: u8, vector: u8) {
+            while self.read_register(LocalApicRegister::InterruptCommandLow)
+                & INTERRUPT_COMMAND_SEND_PENDING
+                != 0
+            {
+                core::hint::spin_loop();
+            }
+            self.write_register(
+                LocalApicRegister::InterruptCommandHigh,
+                (apic_id as u32) << 24,
+            );
+            self.write_register(LocalApicRegister::InterruptCommandLow, vector as u32);
+        }
     }
 
+    /// Set in the low half of the interrupt command register until the interrupt it describes
+    /// has been accepted.
+    const INTERRUPT_COMMAND_SEND_PENDING: u32 = 1 << 12;
+
     #[derive(Clone, Copy, Debug)]
     pub enum LocalApicRegister {
         LapicId,
@@ -103,6 +117,8 @@ pub mod local {
         SpuriousInterruptVector,
         ErrorStatus,
         LvtCmci,
+        InterruptCommandLow,
+        InterruptCommandHigh,
         LvtTimer,
         LvtThermalSensor,
         LvtPerfMonitoringCounters,
@@ -129,8 +145,10 @@ pub mod local {
                 Self::LogicalDestination => (0xD0, true, true),
                 Self::DestinationFormat => (0xE0, true, true),
                 Self::SpuriousInterruptVector => (0xF0, true, true),
-                Self::ErrorStatus => (0x280, true, false),
+                Self::ErrorStatus => (0x280, true, true),
                 Self::LvtCmci => (0x2F0, true, true),
+                Self::InterruptCommandLow => (0x300, true, true),
+                Self::InterruptCommandHigh => (0x310, true, true),
                 Self::LvtTimer => (0x320, true, true),
                 Self::LvtThermalSensor => (0x330, true, true),
                 Self::LvtPerfMonitoringCounters => (0x340, true, true),
@@ -0,0 +1,116 @@
+//! Functionality shared by the bootloader entry points, which run before `kernel_main` to turn
+//! the bootloader's information into `kernel_args::Args`.
+
+use crate::arch::kernel_args;
+use crate::arch::paging::PAGE_SIZE;
+use core::fmt::Write;
+
+/// Number of mapped bytes per memory bitmap bit.
+pub const BITMAP_BIT_RATIO: usize = PAGE_SIZE;
+/// Number of mapped bytes per memory bitmap byte.
+pub const BITMAP_BYTE_RATIO: usize = BITMAP_BIT_RATIO * 8;
+
+/// Logs to the architecture debug outputs only, as there's no framebuffer terminal yet.
+pub struct BootLogger;
+
+// TODO: Should we implement a framebuffer logger in this stage? Probably unnecessary unless a
+// computer doesn't work and we can't debug it using serial (for some reason).
+pub static LOGGER: BootLogger = BootLogger;
+
+macro_rules! impl_writers_func_body {
+    ($write_fn: ident, $arg: ident) => {
+        crate::arch::debug_output::ArchWriter.$write_fn($arg)?;
+        return Ok(());
+    };
+}
+
+impl Write for BootLogger {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        impl_writers_func_body!(write_str, s);
+    }
+
+    fn write_char(&mut self, c: char) -> core::fmt::Result {
+        impl_writers_func_body!(write_char, c);
+    }
+
+    fn write_fmt(&mut self, args: core::fmt::Arguments) -> core::fmt::Result {
+        impl_writers_func_body!(write_fmt, args);
+    }
+}
+
+impl log::Log for BootLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            _ = writeln!(
+                Self,
+                "[{}] ({}) {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns the size of the memory bitmap needed to cover `mappable_bytes`, rounded up to a
+/// whole number of pages.
+pub fn memory_bitmap_size(mappable_bytes: usize) -> usize {
+    mappable_bytes
+        .div_ceil(BITMAP_BYTE_RATIO)
+        .next_multiple_of(PAGE_SIZE)
+}
+
+/// Marks every page overlapping `base..base + length` as used or free in the memory bitmap.
+/// Pages past the end of the bitmap are ignored.
+pub fn mark_memory_range(bitmap: &mut [u8], base: usize, length: usize, used: bool) {
+    if length == 0 || bitmap.is_empty() {
+        return;
+    }
+    let start_bit = base / BITMAP_BIT_RATIO;
+    let end_bit = ((base + length - 1) / BITMAP_BIT_RATIO).min(bitmap.len() * 8 - 1);
+    if start_bit > end_bit {
+        return;
+    }
+    let start_byte = start_bit / 8;
+    let end_byte = end_bit / 8;
+    // Bits are ordered from the most significant bit in each byte
+    let start_mask = 0xFF >> (start_bit % 8);
+    let end_mask = 0xFF << (7 - end_bit % 8);
+    let apply = |byte: &mut u8, mask: u8| match used {
+        true => *byte |= mask,
+        false => *byte &= !mask,
+    };
+    if start_byte == end_byte {
+        apply(&mut bitmap[start_byte], start_mask & end_mask);
+    } else {
+        apply(&mut bitmap[start_byte], start_mask);
+        bitmap[start_byte + 1..end_byte].fill(if used { 0xFF } else { 0 });
+        apply(&mut bitmap[end_byte], end_mask);
+    }
+}
+
+/// Returns the mask of a color channel, from its size and shift in bits.
+pub fn channel_mask(size: u8, shift: u8) -> u32 {
+    (((1u64 << size) - 1) << shift) as u32
+}
+
+/// Returns the color format matching the given channel masks, using the named formats where
+/// possible.
+pub fn color_format(red_mask: u32, green_mask: u32, blue_mask: u32) -> kernel_args::ColorFormat {
+    match (red_mask, green_mask, blue_mask) {
+        (0xFF0000, 0xFF00, 0xFF) => kernel_args::ColorFormat::Bgrr8,
+        (0xFF, 0xFF00, 0xFF0000) => kernel_args::ColorFormat::Rgbr8,
+        _ => kernel_args::ColorFormat::Bitmask(kernel_args::ColorBitmask {
+            red_mask,
+            green_mask,
+            blue_mask,
+            reserved_mask: !(red_mask | green_mask | blue_mask),
+        }),
+    }
+}
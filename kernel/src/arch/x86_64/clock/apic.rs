@@ -1,10 +1,11 @@
 use super::super::apic::local::{LocalApicRegister, TimerLvt, TimerMode};
-use super::super::{idt, interrupts, tls};
+use super::super::{idt, interrupts, profiler, tls};
 use super::{InterruptType, MANAGER, TIMERS, Timer};
 use core::arch::asm;
 
-unsafe extern "x86-interrupt" fn sleep_handler(_interrupt_frame: idt::InterruptFrame) {
+unsafe extern "x86-interrupt" fn sleep_handler(interrupt_frame: idt::InterruptFrame) {
     unsafe {
+        profiler::timer_interrupt(&interrupt_frame);
         (*tls::get_mut()).local_apic.interrupt_received = true;
         (*tls::get_mut())
             .local_apic
@@ -25,7 +26,8 @@ pub unsafe fn calibrate() {
         let time_slept = {
             let mut start_timer =
                 || local_apic.write_register(LocalApicRegister::InitialCount, 0xFFFFFFFF);
-            (MANAGER.lock().calibration_timer.calibration_sleep)(&mut start_timer)
+            let calibration_sleep = MANAGER.lock().calibration_timer.calibration_sleep;
+            calibration_sleep(&mut start_timer)
         };
         let end_ticks = local_apic.read_register(LocalApicRegister::CurrentCount);
         let num_ticks = 0xFFFFFFFF - end_ticks;
@@ -112,7 +114,8 @@ unsafe fn start_countdown_ms(time_ms: u32) {
         let local_apic = local_apic_tls.apic.as_mut().unwrap();
         let numerator = local_apic_tls.timer_us_numerator;
         let denominator = local_apic_tls.timer_us_denominator;
-        let time_apic_ticks = ((numerator * time_us) / denominator) as u32;
+        let time_apic_ticks =
+            profiler::limit_countdown(((numerator * time_us) / denominator) as u32);
         // Enable timer interrupts, set one shot mode
         let mut timer_lvt =
             TimerLvt::from_u32(local_apic.read_register(LocalApicRegister::LvtTimer));
@@ -1,6 +1,6 @@
 use super::CalibrationTimer;
+use super::wall_clock::DateTime;
 use crate::arch::port;
-use core::arch::asm;
 use spin::Mutex;
 
 pub mod register {
@@ -16,6 +16,16 @@ pub mod register {
     pub const STATUS_C: u8 = 0xC;
 }
 
+/// Status A bit set while the date and time registers are being updated.
+const STATUS_A_UPDATING: u8 = 0x80;
+/// Status B bit which stops updates, for setting the date and time.
+const STATUS_B_SET: u8 = 0x80;
+/// Status B bit set if hours are stored 0-23, rather than 1-12 with `HOUR_PM`.
+const STATUS_B_24_HOUR: u8 = 0x02;
+/// Status B bit set if the date and time are stored in binary, rather than BCD.
+const STATUS_B_BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
 pub struct Cmos;
 
 pub static CMOS: Mutex<Cmos> = Mutex::new(Cmos);
@@ -36,49 +46,120 @@ impl Cmos {
             port::write_byte(port::CMOS_DATA, byte);
         }
     }
+
+    /// Waits until the RTC's next second has just started.
+    pub unsafe fn wait_for_next_second(&self) {
+        unsafe {
+            while self.read_byte(false, register::STATUS_A) & STATUS_A_UPDATING == 0 {}
+            while self.read_byte(false, register::STATUS_A) & STATUS_A_UPDATING != 0 {}
+        }
+    }
+
+    /// Reads the RTC's date and time. `century_register` is the register holding the century, or 0
+    /// if there isn't one, in which case the year is assumed to be in the 2000s.
+    pub unsafe fn read_date_time(&self, century_register: u8) -> DateTime {
+        unsafe {
+            // Read until two reads agree, as an update can happen in the middle of a read
+            let mut raw = self.read_raw_date_time(century_register);
+            loop {
+                let next = self.read_raw_date_time(century_register);
+                if next == raw {
+                    break;
+                }
+                raw = next;
+            }
+            let [second, minute, hour, day, month, year, century] = raw;
+            let status_b = self.read_byte(false, register::STATUS_B);
+            let decode = |value: u8| match status_b & STATUS_B_BINARY != 0 {
+                true => value,
+                false => (value & 0x0F) + (value >> 4) * 10,
+            };
+            let hour = match status_b & STATUS_B_24_HOUR != 0 {
+                true => decode(hour),
+                false => decode(hour & !HOUR_PM) % 12 + if hour & HOUR_PM != 0 { 12 } else { 0 },
+            };
+            let century = match century_register {
+                0 => 20,
+                _ => decode(century) as u16,
+            };
+            DateTime {
+                year: century * 100 + decode(year) as u16,
+                month: decode(month),
+                day: decode(day),
+                hour,
+                minute: decode(minute),
+                second: decode(second),
+            }
+        }
+    }
+
+    /// Sets the RTC's date and time, in whatever format it's already using.
+    pub unsafe fn write_date_time(&self, century_register: u8, date_time: &DateTime) {
+        unsafe {
+            let status_b = self.read_byte(false, register::STATUS_B);
+            let encode = |value: u8| match status_b & STATUS_B_BINARY != 0 {
+                true => value,
+                false => ((value / 10) << 4) | (value % 10),
+            };
+            let hour = match status_b & STATUS_B_24_HOUR != 0 {
+                true => encode(date_time.hour),
+                false => {
+                    let pm_bit = if date_time.hour >= 12 { HOUR_PM } else { 0 };
+                    match date_time.hour % 12 {
+                        0 => encode(12) | pm_bit,
+                        hour => encode(hour) | pm_bit,
+                    }
+                }
+            };
+            self.write_byte(false, register::STATUS_B, status_b | STATUS_B_SET);
+            self.write_byte(false, register::SECONDS, encode(date_time.second));
+            self.write_byte(false, register::MINUTES, encode(date_time.minute));
+            self.write_byte(false, register::HOURS, hour);
+            self.write_byte(false, register::DAY_OF_MONTH, encode(date_time.day));
+            self.write_byte(false, register::MONTH, encode(date_time.month));
+            self.write_byte(false, register::YEAR, encode((date_time.year % 100) as u8));
+            if century_register != 0 {
+                self.write_byte(
+                    false,
+                    century_register,
+                    encode((date_time.year / 100) as u8),
+                );
+            }
+            self.write_byte(false, register::STATUS_B, status_b & !STATUS_B_SET);
+        }
+    }
+
+    /// Reads the date and time registers once no update is in progress, in register order.
+    unsafe fn read_raw_date_time(&self, century_register: u8) -> [u8; 7] {
+        unsafe {
+            while self.read_byte(false, register::STATUS_A) & STATUS_A_UPDATING != 0 {
+                core::hint::spin_loop();
+            }
+            [
+                self.read_byte(false, register::SECONDS),
+                self.read_byte(false, register::MINUTES),
+                self.read_byte(false, register::HOURS),
+                self.read_byte(false, register::DAY_OF_MONTH),
+                self.read_byte(false, register::MONTH),
+                self.read_byte(false, register::YEAR),
+                match century_register {
+                    0 => 0,
+                    register => self.read_byte(false, register),
+                },
+            ]
+        }
+    }
 }
 
 pub const CALIBRATION_TIMER: CalibrationTimer = CalibrationTimer { calibration_sleep };
 
 unsafe fn calibration_sleep(start_timer: &mut dyn FnMut()) -> u32 {
     unsafe {
-        let _cmos = CMOS.lock();
-        // Wait until next second has just started
-        asm!(
-            "2:",
-            "mov al, 0xA",
-            "out 0x70, al",
-            "in al, 0x71",
-            "test al, 0x80",
-            "jz 2b",
-            "3:",
-            "mov al, 0xA",
-            "out 0x70, al",
-            "in al, 0x71",
-            "test al, 0x80",
-            "jnz 3b",
-            out("al") _,
-            options(nomem, nostack),
-        );
+        let cmos = CMOS.lock();
+        cmos.wait_for_next_second();
         // Run measurement function
         start_timer();
-        // Wait until current second has ended
-        asm!(
-            "2:",
-            "mov al, 0xA",
-            "out 0x70, al",
-            "in al, 0x71",
-            "test al, 0x80",
-            "jz 2b",
-            "3:",
-            "mov al, 0xA",
-            "out 0x70, al",
-            "in al, 0x71",
-            "test al, 0x80",
-            "jnz 3b",
-            out("al") _,
-            options(nomem, nostack),
-        );
+        cmos.wait_for_next_second();
         1_000_000
     }
 }
@@ -1,7 +1,14 @@
 pub mod apic;
 pub mod cmos;
 pub mod rtc;
+pub mod tsc;
+pub mod wall_clock;
 
+pub use wall_clock::{realtime_ns, set_realtime_ns, try_realtime_ns};
+
+use crate::sync::IrqMutex;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use spin::Mutex;
 
 #[derive(Clone, Copy, Debug)]
@@ -82,6 +89,12 @@ pub struct Timer {
     pub acknowledge_countdown_interrupt: unsafe fn(),
 }
 
+#[derive(Clone, Copy)]
+pub struct Counter {
+    /// Returns the number of nanoseconds elapsed since the counter was calibrated.
+    pub now_ns: unsafe fn() -> u64,
+}
+
 #[rustfmt::skip]
 define_clock_list!(CalibrationTimers, [
     hpet,
@@ -132,9 +145,13 @@ pub static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
 pub struct Manager {
     pub calibration_timer: CalibrationTimer,
     pub timer: Timer,
+    pub counter: Counter,
 }
 
-pub static MANAGER: Mutex<Manager> = Mutex::new(Manager::new());
+pub static MANAGER: IrqMutex<Manager> = IrqMutex::new(Manager::new());
+/// The current counter's `now_ns`, or null if there's no counter. Allows reading the time without
+/// locking `MANAGER`, so it can be done from any context.
+static COUNTER_NOW_NS: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
 
 impl Manager {
     #[allow(clippy::new_without_default)]
@@ -142,6 +159,7 @@ impl Manager {
         Self {
             calibration_timer: dummy_clock::CALIBRATION_TIMER,
             timer: dummy_clock::TIMER,
+            counter: dummy_clock::COUNTER,
         }
     }
 
@@ -149,6 +167,7 @@ impl Manager {
         &mut self,
         calibration_timers: &CalibrationTimers,
         timers: &Timers,
+        counters: &Counters,
     ) {
         self.calibration_timer = match calibration_timers.get_preferred_clock() {
             None => dummy_clock::CALIBRATION_TIMER,
@@ -161,11 +180,57 @@ impl Manager {
             Some(Clock::Apic) => apic::TIMER,
             Some(other) => unimplemented!("Timer impl for `Clock::{other:?}`"),
         };
+        self.counter = match counters.get_preferred_clock() {
+            None => dummy_clock::COUNTER,
+            Some(Clock::Tsc) => tsc::COUNTER,
+            Some(other) => unimplemented!("Counter impl for `Clock::{other:?}`"),
+        };
+        let counter_now_ns = match counters.get_preferred_clock() {
+            None => ptr::null_mut(),
+            Some(_) => self.counter.now_ns as *mut (),
+        };
+        COUNTER_NOW_NS.store(counter_now_ns, Ordering::Release);
+    }
+}
+
+/// Returns the number of nanoseconds elapsed since the counter was calibrated.
+pub fn now_ns() -> u64 {
+    try_now_ns().expect("no counter available")
+}
+
+/// Returns the number of nanoseconds elapsed since the counter was calibrated, or `None` if no
+/// counter has been calibrated yet. Never locks.
+pub fn try_now_ns() -> Option<u64> {
+    let now_ns = COUNTER_NOW_NS.load(Ordering::Acquire);
+    if now_ns.is_null() {
+        return None;
+    }
+    unsafe {
+        let now_ns: unsafe fn() -> u64 = core::mem::transmute(now_ns);
+        Some(now_ns())
+    }
+}
+
+/// Busy waits for `num_ns` nanoseconds.
+pub fn stall_ns(num_ns: u64) {
+    let end = now_ns() + num_ns;
+    while now_ns() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Starts a countdown which interrupts after at least `num_ns` nanoseconds, replacing any
+/// countdown already in progress.
+pub fn start_countdown_ns(num_ns: u64) {
+    let start_countdown_ms = MANAGER.lock().timer.start_countdown_ms;
+    let num_ms = num_ns.div_ceil(1_000_000).clamp(1, u32::MAX as u64) as u32;
+    unsafe {
+        start_countdown_ms(num_ms);
     }
 }
 
 mod dummy_clock {
-    use super::{CalibrationTimer, InterruptType, Timer};
+    use super::{CalibrationTimer, Counter, InterruptType, Timer};
 
     pub const CALIBRATION_TIMER: CalibrationTimer = CalibrationTimer { calibration_sleep };
 
@@ -210,4 +275,10 @@ mod dummy_clock {
     unsafe fn acknowledge_countdown_interrupt() {
         unimplemented!();
     }
+
+    pub const COUNTER: Counter = Counter { now_ns };
+
+    unsafe fn now_ns() -> u64 {
+        unimplemented!();
+    }
 }
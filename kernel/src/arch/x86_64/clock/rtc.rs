@@ -1,18 +1,19 @@
 use super::super::{idt, interrupts, tls};
 use super::{CalibrationTimer, cmos};
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
 pub struct Rtc;
 
 pub static RTC: Mutex<Rtc> = Mutex::new(Rtc);
 
-static mut INTERRUPT_RECEIVED: bool = true;
+static INTERRUPT_RECEIVED: AtomicBool = AtomicBool::new(true);
 
 impl Rtc {
     unsafe extern "x86-interrupt" fn sleep_handler_apic(_interrupt_frame: idt::InterruptFrame) {
         unsafe {
-            INTERRUPT_RECEIVED = true;
+            INTERRUPT_RECEIVED.store(true, Ordering::Release);
             (*tls::get_mut())
                 .local_apic
                 .apic
@@ -49,7 +50,7 @@ unsafe fn calibration_sleep(start_timer: &mut dyn FnMut()) -> u32 {
         // Compute B register value for disabling interrupts
         let new_b_no_interrupt = previous_b & 0xBF;
         // Reset interrupt received indicator
-        INTERRUPT_RECEIVED = false;
+        INTERRUPT_RECEIVED.store(false, Ordering::Release);
         // Write out rate to A register
         cmos.write_byte(true, cmos::register::STATUS_A, new_a);
         // Enable periodic interrupts on B register
@@ -57,7 +58,7 @@ unsafe fn calibration_sleep(start_timer: &mut dyn FnMut()) -> u32 {
         // Flush C register
         cmos.read_byte(true, cmos::register::STATUS_C);
         // Wait for next timer IRQ
-        while !INTERRUPT_RECEIVED {
+        while !INTERRUPT_RECEIVED.load(Ordering::Acquire) {
             asm!("sti; hlt; cli");
         }
         // Start timer calibration
@@ -65,9 +66,9 @@ unsafe fn calibration_sleep(start_timer: &mut dyn FnMut()) -> u32 {
         // Flush C register, required every time timer IRQ is received
         cmos.read_byte(true, cmos::register::STATUS_C);
         // Reset interrupt received indicator
-        INTERRUPT_RECEIVED = false;
+        INTERRUPT_RECEIVED.store(false, Ordering::Release);
         // Wait until timer indicates end of sleep period
-        while !INTERRUPT_RECEIVED {
+        while !INTERRUPT_RECEIVED.load(Ordering::Acquire) {
             asm!("sti; hlt; cli");
         }
         // Disable timer IRQs
@@ -75,7 +76,7 @@ unsafe fn calibration_sleep(start_timer: &mut dyn FnMut()) -> u32 {
         // Flush C register again, enable NMI
         cmos.read_byte(false, cmos::register::STATUS_C);
         // Reset interrupt received indicator again
-        INTERRUPT_RECEIVED = false;
+        INTERRUPT_RECEIVED.store(false, Ordering::Release);
         // Return number of microseconds slept for
         const FREQUENCY: u32 = 32_768 >> (RATE as u32 - 1);
         1_000_000 / FREQUENCY
@@ -0,0 +1,49 @@
+use super::super::cpuid::{self, Feature};
+use super::{COUNTERS, Counter, MANAGER};
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Only written before the TSC is marked as available, so reads don't need to be synchronised with
+// each other. Kept lock free so that the counter can be read while logging from any context.
+static START_TICKS: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(1);
+static MICROSECONDS: AtomicU64 = AtomicU64::new(1);
+
+/// Measures the TSC frequency against the calibration timer, and marks the TSC as available.
+pub unsafe fn calibrate() {
+    unsafe {
+        if !cpuid::cpu_has(Feature::InvariantTsc) {
+            log::warn!("TSC is not invariant, time may drift with CPU frequency changes");
+        }
+        let mut start_ticks = 0;
+        let time_slept = {
+            let mut start_timer = || start_ticks = _rdtsc();
+            let calibration_sleep = MANAGER.lock().calibration_timer.calibration_sleep;
+            calibration_sleep(&mut start_timer)
+        };
+        let end_ticks = _rdtsc();
+        START_TICKS.store(start_ticks, Ordering::Relaxed);
+        TICKS.store(end_ticks - start_ticks, Ordering::Relaxed);
+        MICROSECONDS.store(time_slept as u64, Ordering::Relaxed);
+        COUNTERS.lock().tsc = true;
+        // Lets host tooling line up kernel timestamps with other logs
+        log::info!(
+            "Monotonic clock anchor: 0 ns is TSC {start_ticks}, {} ticks per {time_slept} us",
+            end_ticks - start_ticks,
+        );
+    }
+}
+
+pub const COUNTER: Counter = Counter { now_ns };
+
+/// Returns the number of TSC ticks per second. Only meaningful once the TSC is calibrated.
+pub fn frequency_hz() -> u64 {
+    TICKS.load(Ordering::Relaxed) * 1_000_000 / MICROSECONDS.load(Ordering::Relaxed)
+}
+
+unsafe fn now_ns() -> u64 {
+    let elapsed_ticks = unsafe { _rdtsc() } - START_TICKS.load(Ordering::Relaxed);
+    let microseconds = MICROSECONDS.load(Ordering::Relaxed);
+    let ticks = TICKS.load(Ordering::Relaxed);
+    (elapsed_ticks as u128 * microseconds as u128 * 1000 / ticks as u128) as u64
+}
@@ -0,0 +1,161 @@
+//! Wall clock time, as nanoseconds since the Unix epoch.
+//!
+//! The CMOS RTC's date and time is read once at boot, and the wall clock is kept as an offset from
+//! the counter, so it advances with `now_ns` rather than the RTC's one second resolution. Setting
+//! the wall clock moves the offset and writes the new time back to the RTC, so it's kept across
+//! reboots. The RTC is assumed to hold UTC.
+
+use super::cmos;
+use crate::platform::acpi::table::{self, Fadt};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+const NS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Wall clock time when the counter was at 0.
+static REALTIME_AT_ZERO_NS: AtomicU64 = AtomicU64::new(0);
+static INITIALISED: AtomicBool = AtomicBool::new(false);
+/// CMOS register holding the century, from the FADT.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// A UTC date and time, to the second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12.
+    pub month: u8,
+    /// 1-31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the number of seconds between the Unix epoch and this time, or 0 if it's earlier.
+    pub fn to_unix_seconds(&self) -> u64 {
+        // Days from civil, counting years from March so leap days come last
+        let month = self.month as i64;
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = days * SECONDS_PER_DAY as i64
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        seconds.max(0) as u64
+    }
+
+    /// Returns the date and time `seconds` after the Unix epoch.
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        let days = (seconds / SECONDS_PER_DAY) as i64 + 719_468;
+        let second_of_day = seconds % SECONDS_PER_DAY;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8,
+            hour: (second_of_day / 3600) as u8,
+            minute: (second_of_day / 60 % 60) as u8,
+            second: (second_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+/// Reads the wall clock from the RTC. Must be called after the counter is calibrated and the ACPI
+/// tables are initialised.
+pub unsafe fn init() {
+    let century_register = unsafe { table::get::<Fadt>() }
+        .map(|fadt| fadt.century)
+        .unwrap_or(0);
+    CENTURY_REGISTER.store(century_register, Ordering::Relaxed);
+    let date_time = unsafe { cmos::CMOS.lock().read_date_time(century_register) };
+    let now_ns = super::now_ns();
+    let realtime_ns = date_time.to_unix_seconds() * NS_PER_SECOND;
+    REALTIME_AT_ZERO_NS.store(realtime_ns.saturating_sub(now_ns), Ordering::Relaxed);
+    INITIALISED.store(true, Ordering::Release);
+    log::info!("Wall clock is {date_time}");
+}
+
+/// Returns the number of nanoseconds since the Unix epoch.
+pub fn realtime_ns() -> u64 {
+    try_realtime_ns().expect("wall clock not initialised")
+}
+
+/// Returns the number of nanoseconds since the Unix epoch, or `None` if the wall clock hasn't been
+/// read from the RTC yet. Never locks.
+pub fn try_realtime_ns() -> Option<u64> {
+    if !INITIALISED.load(Ordering::Acquire) {
+        return None;
+    }
+    let now_ns = super::try_now_ns()?;
+    Some(REALTIME_AT_ZERO_NS.load(Ordering::Relaxed) + now_ns)
+}
+
+/// Sets the wall clock to `realtime_ns` nanoseconds since the Unix epoch, and writes it to the
+/// RTC.
+pub fn set_realtime_ns(realtime_ns: u64) {
+    let date_time = DateTime::from_unix_seconds(realtime_ns / NS_PER_SECOND);
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    unsafe {
+        cmos::CMOS
+            .lock()
+            .write_date_time(century_register, &date_time);
+    }
+    let now_ns = super::now_ns();
+    REALTIME_AT_ZERO_NS.store(realtime_ns.saturating_sub(now_ns), Ordering::Relaxed);
+    INITIALISED.store(true, Ordering::Release);
+    log::info!("Wall clock set to {date_time}");
+}
+
+/// Returns the current date and time, or `None` if the wall clock hasn't been initialised.
+pub fn now() -> Option<DateTime> {
+    try_realtime_ns().map(|realtime_ns| DateTime::from_unix_seconds(realtime_ns / NS_PER_SECOND))
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn converts_unix_time() -> TestResult {
+        let date_time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 34,
+            second: 56,
+        };
+        ktest_assert_eq!(date_time.to_unix_seconds(), 1_709_210_096);
+        ktest_assert_eq!(DateTime::from_unix_seconds(1_709_210_096), date_time);
+        ktest_assert_eq!(DateTime::from_unix_seconds(0).year, 1970);
+        Ok(())
+    }
+}
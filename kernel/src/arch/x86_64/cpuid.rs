@@ -1,22 +1,214 @@
-use core::arch::x86_64::{__cpuid, CpuidResult};
+//! CPU identification and feature flags, read once with CPUID at boot.
+//!
+//! Features are looked up by name with `cpu_has`, rather than by testing CPUID bits where they're
+//! needed. Each `Feature` knows which leaf, register and bit it's reported in, and the leaves are
+//! only queried if the CPU reports supporting them, so missing leaves read as unsupported.
+
+use crate::sync::OnceLock;
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+use core::fmt;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct CpuidInfo {
     // 0h
     pub cpu_vendor_id: [u8; 12],
-    // 0000_0001h
-    pub local_apic_timer_tsc_deadline: bool,
     // 8000_0002h ... 8000_0004h
     pub brand_string_bytes: Option<[u8; 48]>,
+    /// Bit `Feature as u32` is set for each supported feature.
+    features: u64,
+    // 0000_000Dh
+    pub xsave: Option<XsaveInfo>,
+    // 0000_000Ah
+    pub perfmon: Option<PerfmonInfo>,
+}
+
+impl CpuidInfo {
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u32) != 0
+    }
+
+    /// Returns the newest SIMD extension supported, `None` if there's not even SSE2.
+    pub fn simd_level(&self) -> Option<SimdLevel> {
+        SimdLevel::ALL
+            .into_iter()
+            .take_while(|level| level.features().iter().all(|feature| self.has(*feature)))
+            .last()
+    }
+
+    pub fn feature_list(&self) -> FeatureList<'_> {
+        FeatureList(self)
+    }
+}
+
+/// Sizes of the XSAVE area, for saving extended register state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XsaveInfo {
+    /// State components that can be enabled in XCR0.
+    pub supported_components: u64,
+    /// Bytes needed to save every supported component.
+    pub max_size: u32,
+    /// Bytes needed to save the components currently enabled in XCR0.
+    pub enabled_size: u32,
+}
+
+/// Architectural performance monitoring capabilities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PerfmonInfo {
+    pub version: u8,
+    /// General purpose counters per logical processor.
+    pub num_counters: u8,
+    /// Bits in each general purpose counter.
+    pub counter_width: u8,
+    /// Whether the unhalted core cycles event can be counted.
+    pub core_cycles: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+macro_rules! define_features {
+    ($( $feature:ident = ($leaf:expr, $register:ident, $bit:expr, $name:expr) ),* $(,)?) => {
+        /// A CPU feature reported by CPUID.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Feature {
+            $( $feature, )*
+        }
+
+        impl Feature {
+            pub const ALL: &[Self] = &[$( Self::$feature, )*];
+
+            /// Returns the leaf, register and bit the feature is reported in.
+            const fn location(self) -> (u32, Register, u32) {
+                match self {
+                    $( Self::$feature => ($leaf, Register::$register, $bit), )*
+                }
+            }
+
+            /// Returns the name Linux uses for the feature in `/proc/cpuinfo`.
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $( Self::$feature => $name, )*
+                }
+            }
+        }
+    };
+}
+
+#[rustfmt::skip]
+define_features! {
+    // 0000_0001h
+    Fpu = (0x1, Edx, 0, "fpu"),
+    Tsc = (0x1, Edx, 4, "tsc"),
+    Msr = (0x1, Edx, 5, "msr"),
+    Apic = (0x1, Edx, 9, "apic"),
+    Pge = (0x1, Edx, 13, "pge"),
+    Pat = (0x1, Edx, 16, "pat"),
+    Fxsr = (0x1, Edx, 24, "fxsr"),
+    Sse = (0x1, Edx, 25, "sse"),
+    Sse2 = (0x1, Edx, 26, "sse2"),
+    Sse3 = (0x1, Ecx, 0, "pni"),
+    Monitor = (0x1, Ecx, 3, "monitor"),
+    Ssse3 = (0x1, Ecx, 9, "ssse3"),
+    Fma = (0x1, Ecx, 12, "fma"),
+    Pcid = (0x1, Ecx, 17, "pcid"),
+    Sse41 = (0x1, Ecx, 19, "sse4_1"),
+    Sse42 = (0x1, Ecx, 20, "sse4_2"),
+    X2Apic = (0x1, Ecx, 21, "x2apic"),
+    Popcnt = (0x1, Ecx, 23, "popcnt"),
+    TscDeadline = (0x1, Ecx, 24, "tsc_deadline_timer"),
+    Xsave = (0x1, Ecx, 26, "xsave"),
+    Osxsave = (0x1, Ecx, 27, "osxsave"),
+    Avx = (0x1, Ecx, 28, "avx"),
+    Rdrand = (0x1, Ecx, 30, "rdrand"),
+    // 0000_0006h
+    Arat = (0x6, Eax, 2, "arat"),
+    // 0000_0007h, subleaf 0
+    FsGsBase = (0x7, Ebx, 0, "fsgsbase"),
+    Bmi1 = (0x7, Ebx, 3, "bmi1"),
+    Avx2 = (0x7, Ebx, 5, "avx2"),
+    Smep = (0x7, Ebx, 7, "smep"),
+    Bmi2 = (0x7, Ebx, 8, "bmi2"),
+    Avx512F = (0x7, Ebx, 16, "avx512f"),
+    Rdseed = (0x7, Ebx, 18, "rdseed"),
+    Smap = (0x7, Ebx, 20, "smap"),
+    Umip = (0x7, Ecx, 2, "umip"),
+    // 8000_0001h
+    Syscall = (0x8000_0001, Edx, 11, "syscall"),
+    Nx = (0x8000_0001, Edx, 20, "nx"),
+    Page1Gib = (0x8000_0001, Edx, 26, "pdpe1gb"),
+    Rdtscp = (0x8000_0001, Edx, 27, "rdtscp"),
+    LongMode = (0x8000_0001, Edx, 29, "lm"),
     // 8000_0007h
-    pub invariant_tsc: bool,
+    InvariantTsc = (0x8000_0007, Edx, 8, "constant_tsc"),
 }
 
-static mut CPUID_INFO: Option<CpuidInfo> = None;
+const _: () = assert!(Feature::ALL.len() <= u64::BITS as usize);
 
-/// Populates internal information with calls to CPUID.
-/// Caller guarantees no references exist to CPUID_INFO, and that no other thread is accessing
-/// CPUID_INFO.
+/// SIMD extension levels, oldest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Avx,
+    Avx2,
+    Avx512,
+}
+
+impl SimdLevel {
+    pub const ALL: [Self; 8] = [
+        Self::Sse2,
+        Self::Sse3,
+        Self::Ssse3,
+        Self::Sse41,
+        Self::Sse42,
+        Self::Avx,
+        Self::Avx2,
+        Self::Avx512,
+    ];
+
+    /// Features needed for this level, on top of those of the levels before it.
+    fn features(self) -> &'static [Feature] {
+        match self {
+            Self::Sse2 => &[Feature::Sse, Feature::Sse2],
+            Self::Sse3 => &[Feature::Sse3],
+            Self::Ssse3 => &[Feature::Ssse3],
+            Self::Sse41 => &[Feature::Sse41],
+            Self::Sse42 => &[Feature::Sse42],
+            // Also needs the OS to enable the AVX state with XSETBV
+            Self::Avx => &[Feature::Xsave, Feature::Avx],
+            Self::Avx2 => &[Feature::Avx2],
+            Self::Avx512 => &[Feature::Avx512F],
+        }
+    }
+}
+
+/// Lists the supported features by name, separated by spaces.
+pub struct FeatureList<'a>(&'a CpuidInfo);
+
+impl fmt::Display for FeatureList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut supported = Feature::ALL.iter().filter(|feature| self.0.has(**feature));
+        if let Some(first) = supported.next() {
+            f.write_str(first.name())?;
+        }
+        for feature in supported {
+            write!(f, " {}", feature.name())?;
+        }
+        Ok(())
+    }
+}
+
+static CPUID_INFO: OnceLock<CpuidInfo> = OnceLock::new();
+
+/// Populates internal information with calls to CPUID. Does nothing if already populated.
 pub unsafe fn generate_info() {
     unsafe {
         // Supported levels and CPU Vendor ID
@@ -29,37 +221,122 @@ pub unsafe fn generate_info() {
             (regs.eax, cpu_vendor_id)
         };
         let extended_maximum_level = __cpuid(0x8000_0000).eax;
-        // TSC Deadline Mode Supported
-        let local_apic_timer_tsc_deadline =
-            standard_maximum_level >= 1 && __cpuid(1).ecx & 0x100_0000 != 0;
+        let leaf_supported = |leaf: u32| match leaf >= 0x8000_0000 {
+            true => extended_maximum_level >= leaf,
+            false => standard_maximum_level >= leaf,
+        };
+        // Feature flags
+        let mut features = 0;
+        for feature in Feature::ALL {
+            let (leaf, register, bit) = feature.location();
+            if !leaf_supported(leaf) {
+                continue;
+            }
+            let regs = __cpuid_count(leaf, 0);
+            let value = match register {
+                Register::Eax => regs.eax,
+                Register::Ebx => regs.ebx,
+                Register::Ecx => regs.ecx,
+                Register::Edx => regs.edx,
+            };
+            if value & (1 << bit) != 0 {
+                features |= 1 << *feature as u32;
+            }
+        }
         // Brand String
-        let brand_string_bytes = match extended_maximum_level >= 0x8000_0004 {
+        let brand_string_bytes = match leaf_supported(0x8000_0004) {
             true => {
                 let mut bytes = [0u8; 48];
                 bytes[0..16].copy_from_slice(&cpuid_result_to_le_bytes(__cpuid(0x8000_0002)));
-                bytes[16..32].copy_from_slice(&cpuid_result_to_le_bytes(__cpuid(0x8000_0002)));
-                bytes[32..48].copy_from_slice(&cpuid_result_to_le_bytes(__cpuid(0x8000_0003)));
+                bytes[16..32].copy_from_slice(&cpuid_result_to_le_bytes(__cpuid(0x8000_0003)));
+                bytes[32..48].copy_from_slice(&cpuid_result_to_le_bytes(__cpuid(0x8000_0004)));
                 Some(bytes)
             }
             false => None,
         };
-        // Has Invariant TSC
-        let invariant_tsc =
-            extended_maximum_level >= 0x8000_0007 && __cpuid(0x8000_0007).edx & 0x100 != 0;
+        // XSAVE area sizes
+        let xsave = match features & (1 << Feature::Xsave as u32) != 0 && leaf_supported(0xD) {
+            true => {
+                let regs = __cpuid_count(0xD, 0);
+                Some(XsaveInfo {
+                    supported_components: (regs.edx as u64) << 32 | regs.eax as u64,
+                    max_size: regs.ecx,
+                    enabled_size: regs.ebx,
+                })
+            }
+            false => None,
+        };
+        // Performance monitoring, where version 0 means there's none
+        let perfmon = match leaf_supported(0xA) {
+            true => {
+                let regs = __cpuid(0xA);
+                let [version, num_counters, counter_width, num_events] = regs.eax.to_le_bytes();
+                // Events are flagged as unavailable in EBX, up to the number of flags reported
+                let core_cycles = num_events > 0 && regs.ebx & 1 == 0;
+                (version != 0).then_some(PerfmonInfo {
+                    version,
+                    num_counters,
+                    counter_width,
+                    core_cycles,
+                })
+            }
+            false => None,
+        };
         // Populate
-        CPUID_INFO = Some(CpuidInfo {
+        _ = CPUID_INFO.set(CpuidInfo {
             cpu_vendor_id,
-            local_apic_timer_tsc_deadline,
             brand_string_bytes,
-            invariant_tsc,
+            features,
+            xsave,
+            perfmon,
         });
     }
 }
 
 pub fn get_info() -> &'static CpuidInfo {
-    #[allow(static_mut_refs)]
-    unsafe {
-        CPUID_INFO.as_ref().unwrap()
+    CPUID_INFO.get().unwrap()
+}
+
+/// Returns the CPUID information, or `None` if `generate_info` hasn't been called yet.
+pub fn try_get_info() -> Option<&'static CpuidInfo> {
+    CPUID_INFO.get()
+}
+
+/// Returns whether the CPU supports `feature`.
+pub fn cpu_has(feature: Feature) -> bool {
+    get_info().has(feature)
+}
+
+/// Features the kernel can't run without. The FPU and SSE state is saved with FXSAVE, and the
+/// kernel is compiled to use SSE2.
+const REQUIRED_FEATURES: [Feature; 4] = [Feature::Fpu, Feature::Fxsr, Feature::Sse, Feature::Sse2];
+
+/// Logs the CPU's features, and panics if any required ones are missing.
+pub fn check_required_features() {
+    let info = get_info();
+    if let Some(brand_string_bytes) = &info.brand_string_bytes {
+        let brand_string = brand_string_bytes
+            .split(|byte| *byte == 0)
+            .next()
+            .unwrap_or(&[]);
+        log::info!("CPU: {}", brand_string.trim_ascii().escape_ascii());
+    }
+    log::debug!("CPU features: {}", info.feature_list());
+    log::debug!("SIMD level: {:?}", info.simd_level());
+    if let Some(xsave) = info.xsave {
+        log::debug!(
+            "XSAVE components {:#x}, {} bytes for all, {} bytes for enabled",
+            xsave.supported_components,
+            xsave.max_size,
+            xsave.enabled_size,
+        );
+    }
+    for feature in REQUIRED_FEATURES {
+        assert!(
+            info.has(feature),
+            "CPU doesn't support required feature {}",
+            feature.name()
+        );
     }
 }
 
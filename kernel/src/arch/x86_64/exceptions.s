@@ -1,14 +1,78 @@
-.macro exception name, exception_type, message
+// Hands an exception from user mode to `user_exception`, if `from_user` is set. `cs_offset` is
+// the offset of the code segment in the interrupt frame from rsp.
+.macro user_mode_check exception_type, from_user, cs_offset, has_error_code
+  .if \from_user
+    testb $3, \cs_offset(%rsp)
+    jz 4f
+    .if !\has_error_code
+    pushq $0
+    .endif
+    pushq \exception_type
+    jmp user_exception
+  4:
+  .endif
+.endm
+
+.macro exception name, exception_type, message, from_user=1
   1:
   .ascii "\message"
   2:
   .global \name;
   .type \name, @function;
   \name:
+    user_mode_check \exception_type, \from_user, 8, 0
+    cli
+    movq (%rsp), %rdx
+    andq $-16, %rsp
+    movq $1b, %rdi
+    movq $2b - 1b, %rsi
+    pushq %rdx
+    pushq %rbp
+    movq %rsp, %rbp
+    callq exception_message
+  .size \name, . - \name
+.endm
+
+.macro resolvable_exception name, exception_type, message, resolver
+  1:
+  .ascii "\message"
+  2:
+  .global \name;
+  .type \name, @function;
+  \name:
+    user_mode_check \exception_type, 1, 8, 0
+    // Let the kernel resolve the exception, such as for page watchpoints, before panicking
+    pushq %rbp
+    movq %rsp, %rbp
+    pushq %rax
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    leaq 8(%rbp), %rdi
+    andq $-16, %rsp
+    cld
+    callq \resolver
+    leaq -72(%rbp), %rsp
+    testb %al, %al
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rax
+    popq %rbp
+    jz 3f
+    iretq
+  3:
     cli
-    // Panic if exception happened in kernel code
-    // cmpq $KernelGdt.kernel_code, 8(%rsp)
-    // jne 0f
     movq (%rsp), %rdx
     andq $-16, %rsp
     movq $1b, %rdi
@@ -17,18 +81,6 @@
     pushq %rbp
     movq %rsp, %rbp
     callq exception_message
-  // 0:
-  //   // Swap GS to contain pointer to kernel thread local storage
-  //   swapgs
-  //   // Write yield information
-  //   movq $YieldReason.Exception, %gs:ThreadLocalStorage.yield_info.reason
-  //   movq \exception_type, %gs:ThreadLocalStorage.yield_info.exception_type
-  //   movq $0, %gs:ThreadLocalStorage.yield_info.exception_error_code
-  //   movq $0, %gs:ThreadLocalStorage.yield_info.page_fault_address
-  //   // Save RAX
-  //   movq %rax, %gs:ThreadLocalStorage.current_process.registers.rax
-  //   // Jump to main interrupt context switch system
-  //   jmp interrupt_context_switch_body
   .size \name, . - \name
 .endm
 
@@ -39,10 +91,43 @@
   .global \name;
   .type \name, @function;
   \name:
+    user_mode_check \exception_type, 1, 16, 1
+    // Let the kernel resolve the fault, such as for page watchpoints, before panicking
+    pushq %rbp
+    movq %rsp, %rbp
+    pushq %rax
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    movq 8(%rbp), %rdi
+    movq %cr2, %rsi
+    leaq 16(%rbp), %rdx
+    andq $-16, %rsp
+    cld
+    callq resolve_page_fault
+    leaq -72(%rbp), %rsp
+    testb %al, %al
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rax
+    popq %rbp
+    jz 3f
+    // Pop error code
+    addq $8, %rsp
+    iretq
+  3:
     cli
-    // Panic if exception happened in kernel code
-    // cmpq $KernelGdt.kernel_code, 16(%rsp)
-    // jne 0f
     movq 32(%rsp), %rbp
     movq 8(%rsp), %r8
     movq (%rsp), %rdx
@@ -54,34 +139,18 @@
     pushq %rbp
     movq %rsp, %rbp
     callq page_fault_exception_message
-  // 0:
-  //   // Swap GS to contain pointer to kernel thread local storage
-  //   swapgs
-  //   // Save RAX
-  //   movq %rax, %gs:ThreadLocalStorage.current_process.registers.rax
-  //   // Write yield information
-  //   movq $YieldReason.Exception, %gs:ThreadLocalStorage.yield_info.reason
-  //   movq \exception_type, %gs:ThreadLocalStorage.yield_info.exception_type
-  //   popq %rax
-  //   movq %rax, %gs:ThreadLocalStorage.yield_info.exception_error_code
-  //   movq %cr2, %rax
-  //   movq %rax, %gs:ThreadLocalStorage.yield_info.page_fault_address
-  //   // Jump to main interrupt context switch system
-  //   jmp interrupt_context_switch_body
   .size \name, . - \name
 .endm
 
-.macro exception_err_code name, exception_type, message
+.macro exception_err_code name, exception_type, message, from_user=1
   1:
   .ascii "\message"
   2:
   .global \name;
   .type \name, @function;
   \name:
+    user_mode_check \exception_type, \from_user, 16, 1
     cli
-    // Panic if exception happened in kernel code
-    // cmpq $KernelGdt.kernel_code, 16(%rsp)
-    // jne 0f
     movq 8(%rsp), %rcx
     movq (%rsp), %rdx
     andq $-16, %rsp
@@ -91,30 +160,38 @@
     pushq %rbp
     movq %rsp, %rbp
     callq exception_message_with_err_code
-  // 0:
-  //   // Swap GS to contain pointer to kernel thread local storage
-  //   swapgs
-  //   // Save RAX
-  //   movq %rax, %gs:ThreadLocalStorage.current_process.registers.rax
-  //   // Write yield information
-  //   movq $YieldReason.Exception, %gs:ThreadLocalStorage.yield_info.reason
-  //   movq \exception_type, %gs:ThreadLocalStorage.yield_info.exception_type
-  //   popq %rax
-  //   movq %rax, %gs:ThreadLocalStorage.yield_info.exception_error_code
-  //   movq $0, %gs:ThreadLocalStorage.yield_info.page_fault_address
-  //   // Jump to main interrupt context switch system
-  //   jmp interrupt_context_switch_body
+  .size \name, . - \name
+.endm
+
+// Passes the interrupted stack pointer as well, to detect stack overflows
+.macro double_fault_exception name, message
+  1:
+  .ascii "\message"
+  2:
+  .global \name;
+  .type \name, @function;
+  \name:
+    cli
+    movq 32(%rsp), %r8
+    movq 8(%rsp), %rcx
+    movq (%rsp), %rdx
+    andq $-16, %rsp
+    movq $1b, %rdi
+    movq $2b - 1b, %rsi
+    pushq %rcx
+    pushq %rbp
+    movq %rsp, %rbp
+    callq double_fault_exception_message
   .size \name, . - \name
 .endm
 
 exception divide_by_zero, $ExceptionType.DivideByZero, "EXCEPTION: DIVIDE BY ZERO"
-exception debug, $ExceptionType.Debug, "EXCEPTION: DEBUG"
-exception non_maskable_interrupt, $ExceptionType.NonMaskableInterrupt, "EXCEPTION: NON MASKABLE INTERRUPT"
+resolvable_exception debug, $ExceptionType.Debug, "EXCEPTION: DEBUG", resolve_debug_exception
 exception overflow, $ExceptionType.Overflow, "EXCEPTION: OVERFLOW"
 exception bound_range_exceeded, $ExceptionType.BoundRangeExceeded, "EXCEPTION: BOUND RANGE EXCEEDED"
 exception invalid_opcode, $ExceptionType.InvalidOpcode, "EXCEPTION: INVALID OPCODE"
 exception device_not_available, $ExceptionType.DeviceNotAvailable, "EXCEPTION: DEVICE NOT AVAILABLE"
-exception_err_code double_fault, $ExceptionType.DoubleFault, "EXCEPTION: DOUBLE FAULT"
+double_fault_exception double_fault, "EXCEPTION: DOUBLE FAULT"
 exception_err_code invalid_tss, $ExceptionType.InvalidTss, "EXCEPTION: INVALID TSS"
 exception_err_code segment_not_present, $ExceptionType.SegmentNotPresent, "EXCEPTION: SEGMENT NOT PRESENT"
 exception_err_code stack_segment_fault, $ExceptionType.StackSegmentFault, "EXCEPTION: STACK SEGMENT FAULT"
@@ -122,7 +199,7 @@ exception_err_code general_protection_fault, $ExceptionType.GeneralProtectionFau
 page_fault_exception page_fault, $ExceptionType.PageFault, "EXCEPTION: PAGE FAULT"
 exception x87_floating_point, $ExceptionType.X87FloatingPoint, "EXCEPTION: x87 FLOATING POINT"
 exception_err_code alignment_exception, $ExceptionType.AlignmentCheck, "EXCEPTION: ALIGNMENT EXCEPTION"
-exception machine_check, $ExceptionType.MachineCheck, "EXCEPTION: MACHINE CHECK"
+exception machine_check, $ExceptionType.MachineCheck, "EXCEPTION: MACHINE CHECK", 0
 exception simd_floating_point, $ExceptionType.SimdFloatingPoint, "EXCEPTION: SIMD FLOATING POINT"
 exception virtualization, $ExceptionType.Virtualization, "EXCEPTION: VIRTUALIZATION"
 exception_err_code security, $ExceptionType.Security, "EXCEPTION: SECURITY"
@@ -53,6 +53,8 @@ pub struct KernelGdt {
     pub user_data_64: u64,
 }
 
+// Left as a `static mut`, as the CPU writes to it (the TSS busy bit). Only accessed through raw
+// pointers, never references.
 pub static mut KERNEL_GDT: KernelGdt = KernelGdt {
     null: 0x0001_0000_0000_FFFF,
     kernel_code: SegmentFlags::KERNEL_CODE_64.bits(),
@@ -69,7 +71,7 @@ pub unsafe fn inject_tss_and_load() {
     unsafe {
         // Inject TSS into GDT
         {
-            let tss_address = &tss::KERNEL_TSS as *const tss::KernelTss as u64;
+            let tss_address = &raw const tss::KERNEL_TSS as u64;
             let mut low = SegmentFlags::PRESENT.bits();
             // Base
             low |= (tss_address & 0xFFFFFF) << 16;
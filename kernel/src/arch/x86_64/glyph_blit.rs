@@ -0,0 +1,87 @@
+//! SSE2 expansion of 1 bit per pixel glyphs into 32 bit pixels, for fast text rendering.
+//!
+//! The kernel is built without SSE, so the compiler never touches the XMM registers and they
+//! hold user state. The blitter saves the registers it uses and restores them afterwards. SSE2 is
+//! part of x86_64, so it's always available. AVX2 would need XSAVE enabled first.
+
+use core::arch::asm;
+
+/// Bit of a glyph row byte for each of the 8 pixels in a band, leftmost pixel first.
+static PIXEL_BITS: [u32; 8] = [0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01];
+
+/// Expands an 8 pixel wide band of a glyph into `rows` rows of 8 32 bit pixels, each pixel set to
+/// `foreground` where its bit is set and `background` otherwise. A row byte is read from `glyph`
+/// every `glyph_stride` bytes, and a row of pixels is written to `target` every `pitch` bytes.
+///
+/// # Safety
+/// `glyph` must be readable and `target` writable for every row. Interrupts must be disabled, so
+/// nothing else can use the XMM registers while they're borrowed.
+pub unsafe fn expand_band(
+    glyph: *const u8,
+    glyph_stride: usize,
+    target: *mut u8,
+    pitch: usize,
+    rows: usize,
+    foreground: u32,
+    background: u32,
+) {
+    if rows == 0 {
+        return;
+    }
+    let mut saved_registers = [0u128; 6];
+    unsafe {
+        asm!(
+            "movdqu [{saved}], xmm0",
+            "movdqu [{saved} + 16], xmm1",
+            "movdqu [{saved} + 32], xmm2",
+            "movdqu [{saved} + 48], xmm3",
+            "movdqu [{saved} + 64], xmm4",
+            "movdqu [{saved} + 80], xmm5",
+            // Pixels are picked with `background ^ ((foreground ^ background) & mask)`
+            "xor {foreground:e}, {background:e}",
+            "movd xmm1, {foreground:e}",
+            "pshufd xmm1, xmm1, 0",
+            "movd xmm2, {background:e}",
+            "pshufd xmm2, xmm2, 0",
+            "movdqu xmm3, [{pixel_bits}]",
+            "movdqu xmm4, [{pixel_bits} + 16]",
+            "2:",
+            // Broadcast the row's byte, then turn each pixel's bit into an all ones or zero mask
+            "movzx {byte:e}, byte ptr [{glyph}]",
+            "movd xmm0, {byte:e}",
+            "pshufd xmm0, xmm0, 0",
+            "movdqa xmm5, xmm0",
+            "pand xmm0, xmm3",
+            "pcmpeqd xmm0, xmm3",
+            "pand xmm5, xmm4",
+            "pcmpeqd xmm5, xmm4",
+            "pand xmm0, xmm1",
+            "pxor xmm0, xmm2",
+            "pand xmm5, xmm1",
+            "pxor xmm5, xmm2",
+            "movdqu [{target}], xmm0",
+            "movdqu [{target} + 16], xmm5",
+            "add {glyph}, {glyph_stride}",
+            "add {target}, {pitch}",
+            "dec {rows}",
+            "jnz 2b",
+            "movdqu xmm0, [{saved}]",
+            "movdqu xmm1, [{saved} + 16]",
+            "movdqu xmm2, [{saved} + 32]",
+            "movdqu xmm3, [{saved} + 48]",
+            "movdqu xmm4, [{saved} + 64]",
+            "movdqu xmm5, [{saved} + 80]",
+            saved = in(reg) saved_registers.as_mut_ptr(),
+            pixel_bits = in(reg) PIXEL_BITS.as_ptr(),
+            foreground = inout(reg) foreground => _,
+            background = in(reg) background,
+            glyph = inout(reg) glyph => _,
+            glyph_stride = in(reg) glyph_stride,
+            target = inout(reg) target => _,
+            pitch = in(reg) pitch,
+            rows = inout(reg) rows => _,
+            byte = out(reg) _,
+            options(nostack),
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Instructions for entering C-states, used by `crate::idle`. Each one enables interrupts to be
+//! woken, and returns with them disabled once the next interrupt has been handled.
+
+use super::port;
+use core::arch::asm;
+
+/// A cache line for MONITOR to watch, which is never written, so only interrupts end MWAIT.
+#[repr(align(64))]
+struct MonitorLine {
+    _bytes: [u8; 64],
+}
+
+static MONITOR_LINE: MonitorLine = MonitorLine { _bytes: [0; 64] };
+
+/// Enters C1 by halting.
+pub fn halt() {
+    super::kthread::wait_for_interrupt();
+}
+
+/// Enters the C-state given by the MWAIT `hint` in EAX, as listed by `_CST`. The CPU must support
+/// MONITOR and MWAIT.
+pub unsafe fn mwait(hint: u32) {
+    unsafe {
+        asm!(
+            "monitor",
+            in("rax") &raw const MONITOR_LINE,
+            in("ecx") 0,
+            in("edx") 0,
+            options(nostack),
+        );
+        // STI only takes effect after the next instruction, so an interrupt can't be handled
+        // between it and MWAIT and leave MWAIT waiting for another
+        asm!("sti; mwait; cli", in("eax") hint, in("ecx") 0, options(nostack));
+    }
+}
+
+/// Enters a C-state by reading its chipset level register at `port`. The chipset stops the CPU
+/// during the read until an interrupt is pending, even with interrupts disabled, so they're
+/// briefly enabled afterwards to handle it.
+pub unsafe fn read_level_register(port: u16) {
+    unsafe {
+        port::read_byte(port);
+        asm!("sti; nop; cli", options(nostack));
+    }
+}
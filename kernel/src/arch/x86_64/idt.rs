@@ -1,5 +1,6 @@
 use super::DescriptorTablePointer;
 use super::gdt;
+use super::nmi;
 use super::tss;
 use core::arch::{asm, global_asm};
 
@@ -209,8 +210,10 @@ impl InterruptDescriptorTable {
         Self {
             divide_by_zero: Entry::with_handler_and_generic_stack(handlers::divide_by_zero),
             debug: Entry::with_handler_and_generic_stack(handlers::debug),
-            non_maskable_interrupt: Entry::with_handler_and_generic_stack(
-                handlers::non_maskable_interrupt,
+            // Own stack, as NMIs can arrive while on the generic stack
+            non_maskable_interrupt: Entry::with_handler_and_stack(
+                nmi::non_maskable_interrupt,
+                (core::mem::offset_of!(tss::InterruptStacks, non_maskable_interrupt) / 8) as u8,
             ),
             breakpoint: Entry::with_handler_and_generic_stack(handlers::breakpoint),
             overflow: Entry::with_handler_and_generic_stack(handlers::overflow),
@@ -273,6 +276,35 @@ impl InterruptDescriptorTable {
 /// Handlers for CPU exceptions
 pub mod exception_handlers {
     use super::InterruptFrame;
+    use super::tss;
+    use crate::arch::paging::PAGE_SIZE;
+    use crate::arch::watchpoint;
+    use crate::kthread::{self, ThreadId};
+    use core::fmt;
+
+    /// A kernel stack which has overflowed into the unmapped space below it.
+    enum OverflowedStack {
+        Interrupt(&'static str),
+        Thread(ThreadId, &'static str),
+    }
+
+    impl fmt::Display for OverflowedStack {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Interrupt(name) => write!(f, "{name} stack"),
+                Self::Thread(id, name) => write!(f, "stack of thread {} ({name})", id.as_u64()),
+            }
+        }
+    }
+
+    /// Returns the stack whose guard area contains `address`, if any.
+    fn overflowed_stack(address: usize) -> Option<OverflowedStack> {
+        if let Some(stack) = tss::guarded_stack_overflowing_into(address) {
+            return Some(OverflowedStack::Interrupt(stack.name));
+        }
+        kthread::try_thread_overflowing_into(address)
+            .map(|(id, name)| OverflowedStack::Thread(id, name))
+    }
 
     // Panicking exception helper functions
 
@@ -310,6 +342,45 @@ pub mod exception_handlers {
         }
     }
 
+    #[unsafe(no_mangle)]
+    unsafe extern "C" fn double_fault_exception_message(
+        msg_ptr: *const u8,
+        msg_len: usize,
+        error_code: u32,
+        rip: usize,
+        stack_address: usize,
+    ) -> ! {
+        // Stack overflows double fault if the CPU can't push the frame for another exception, so
+        // the stack pointer is still in the bottom page of the stack
+        if let Some(stack) = overflowed_stack(stack_address.wrapping_sub(PAGE_SIZE)) {
+            panic!(
+                concat!(
+                    "Kernel stack overflow on {stack}, causing a double fault:\n",
+                    "- With stack pointer {stack_address:#x}\n",
+                    "- Caused by instruction at {rip:#x}\n",
+                ),
+                stack = stack,
+                stack_address = stack_address,
+                rip = rip,
+            );
+        }
+        unsafe {
+            let msg = core::str::from_utf8_unchecked(core::slice::from_raw_parts(msg_ptr, msg_len));
+            panic!(
+                concat!(
+                    "{msg}:\n",
+                    "- With error code {error_code:#X}\n",
+                    "- With stack pointer {stack_address:#x}\n",
+                    "- Caused by instruction at {rip:#x}\n",
+                ),
+                msg = msg,
+                error_code = error_code,
+                stack_address = stack_address,
+                rip = rip,
+            );
+        }
+    }
+
     #[unsafe(no_mangle)]
     unsafe extern "C" fn page_fault_exception_message(
         msg_ptr: *const u8,
@@ -318,6 +389,17 @@ pub mod exception_handlers {
         access_address: usize,
         rip: usize,
     ) -> ! {
+        if let Some(stack) = overflowed_stack(access_address) {
+            panic!(
+                concat!(
+                    "Kernel stack overflow on {stack}:\n",
+                    "- Caused by access to address {access_address:#x} by instruction at {rip:#x}\n",
+                ),
+                stack = stack,
+                access_address = access_address,
+                rip = rip,
+            );
+        }
         unsafe {
             let msg = core::str::from_utf8_unchecked(core::slice::from_raw_parts(msg_ptr, msg_len));
             panic!(
@@ -334,6 +416,30 @@ pub mod exception_handlers {
         }
     }
 
+    // Exception resolving functions, which return whether the exception was handled and the
+    // interrupted code can continue
+
+    #[unsafe(no_mangle)]
+    unsafe extern "C" fn resolve_page_fault(
+        error_code: u64,
+        access_address: usize,
+        interrupt_frame: &mut InterruptFrame,
+    ) -> bool {
+        unsafe {
+            watchpoint::handle_page_fault(
+                error_code,
+                access_address,
+                interrupt_frame.intruction_address,
+                &mut interrupt_frame.cpu_flags,
+            )
+        }
+    }
+
+    #[unsafe(no_mangle)]
+    unsafe extern "C" fn resolve_debug_exception(interrupt_frame: &mut InterruptFrame) -> bool {
+        unsafe { watchpoint::handle_debug_exception(&mut interrupt_frame.cpu_flags) }
+    }
+
     // Handlers
 
     #[unsafe(no_mangle)]
@@ -344,7 +450,6 @@ pub mod exception_handlers {
     unsafe extern "x86-interrupt" {
         pub unsafe fn divide_by_zero(interrupt_frame: InterruptFrame);
         pub unsafe fn debug(interrupt_frame: InterruptFrame);
-        pub unsafe fn non_maskable_interrupt(interrupt_frame: InterruptFrame);
         pub unsafe fn overflow(interrupt_frame: InterruptFrame);
         pub unsafe fn bound_range_exceeded(interrupt_frame: InterruptFrame);
         pub unsafe fn invalid_opcode(interrupt_frame: InterruptFrame);
@@ -1,10 +1,13 @@
 // TODO Rename to io_interrupts
 
 use super::apic::io::{DeliveryMode, DestinationMode, IoApic, Polarity, TriggerMode};
-use super::apic::local::{LocalApic, LocalApicRegister};
+use super::apic::local::LocalApic;
 use super::platform::acpi::table::{Madt, MadtEntry};
 use super::{idt, tls};
+use crate::sync::IrqRwLock;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::arch::asm;
 use spin::Mutex;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -18,17 +21,44 @@ pub static ACTIVE_IO_INTERRUPT_SYSTEM: Mutex<Option<Controller>> = Mutex::new(No
 pub fn signal_eoi() {
     unsafe {
         match *ACTIVE_IO_INTERRUPT_SYSTEM.lock() {
-            Some(Controller::Apic) => (*tls::get_mut())
-                .local_apic
-                .apic
-                .as_mut()
-                .unwrap()
-                .signal_eoi(),
+            Some(Controller::Apic) => {
+                super::nmi::touch_watchdog();
+                (*tls::get_mut())
+                    .local_apic
+                    .apic
+                    .as_mut()
+                    .unwrap()
+                    .signal_eoi();
+            }
             None => panic!("signal_eoi called with no active interrupt system"),
         }
     }
 }
 
+/// Whether interrupts were enabled before a call to `disable_and_save`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SavedInterruptState(bool);
+
+/// Disables interrupts, returning whether they were previously enabled.
+#[inline]
+pub fn disable_and_save() -> SavedInterruptState {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+    }
+    SavedInterruptState(rflags & 0x200 != 0)
+}
+
+/// Re-enables interrupts if they were enabled when `state` was saved.
+#[inline]
+pub unsafe fn restore(state: SavedInterruptState) {
+    if state.0 {
+        unsafe {
+            asm!("sti");
+        }
+    }
+}
+
 struct IoHandler {
     pub idt_entry: *mut idt::Entry<idt::HandlerFunc>,
     pub entry_index: u8,
@@ -51,7 +81,8 @@ pub unsafe fn map_legacy_irq(irq: u8, handler: idt::HandlerFunc) {
                     .expect("APIC should have interrupt vectors available");
                 idt.apic_interrupts[index as usize] =
                     idt::Entry::with_handler_and_generic_stack(handler);
-                apic::register_legacy_irq(irq, 128 + index);
+                routing::route_irq(irq, 128 + index, apic::current_local_apic_id())
+                    .expect("failed to route legacy IRQ");
                 assert!(legacy_irqs[irq as usize].is_none());
                 legacy_irqs[irq as usize] = Some(IoHandler {
                     idt_entry: &mut idt.apic_interrupts[index as usize]
@@ -98,11 +129,122 @@ impl Drop for ScopedLegacyIrqMapping {
     }
 }
 
+// Dynamic handlers
+
+pub type DynamicHandler = Box<dyn Fn() + Send + Sync>;
+
+/// Vector of the first entry in the IDT's `apic_interrupts`, which dynamic handlers use.
+pub const FIRST_DYNAMIC_VECTOR: u8 = 128;
+
+/// Handlers for each entry in the IDT's `apic_interrupts`. Handlers are called with this locked,
+/// so mustn't register or unregister handlers themselves.
+static DYNAMIC_HANDLERS: IrqRwLock<[Option<DynamicHandler>; 128]> =
+    IrqRwLock::new([const { None }; 128]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HandlerError {
+    #[error("vector outside of the dynamic range")]
+    InvalidVector,
+    #[error("vector already in use")]
+    VectorInUse,
+    #[error("no handler registered for vector")]
+    NotRegistered,
+    #[error("out of interrupt vectors")]
+    OutOfVectors,
+}
+
+/// Registers `handler` to be called when `vector` is raised, reserving the vector. EOI is
+/// signalled once the handler returns.
+pub fn register_handler(
+    vector: u8,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<(), HandlerError> {
+    let index = vector
+        .checked_sub(FIRST_DYNAMIC_VECTOR)
+        .ok_or(HandlerError::InvalidVector)?;
+    if !apic::try_reserve_entry(index) {
+        return Err(HandlerError::VectorInUse);
+    }
+    install_dynamic_handler(index, Box::new(handler));
+    Ok(())
+}
+
+/// Registers `handler` on any free vector, returning the vector.
+pub fn allocate_handler(handler: impl Fn() + Send + Sync + 'static) -> Result<u8, HandlerError> {
+    let index = apic::try_find_and_reserve_entry().ok_or(HandlerError::OutOfVectors)?;
+    install_dynamic_handler(index, Box::new(handler));
+    Ok(FIRST_DYNAMIC_VECTOR + index)
+}
+
+/// Removes the handler registered for `vector`, and frees the vector.
+pub fn unregister_handler(vector: u8) -> Result<(), HandlerError> {
+    let index = vector
+        .checked_sub(FIRST_DYNAMIC_VECTOR)
+        .ok_or(HandlerError::InvalidVector)?;
+    let handler = DYNAMIC_HANDLERS.write()[index as usize]
+        .take()
+        .ok_or(HandlerError::NotRegistered)?;
+    unsafe {
+        (*tls::get_mut()).idt.apic_interrupts[index as usize] = idt::Entry::missing();
+    }
+    // Dropped outside the lock, in case the handler owns anything that frees memory
+    drop(handler);
+    apic::free_entry(index);
+    Ok(())
+}
+
+fn install_dynamic_handler(index: u8, handler: DynamicHandler) {
+    DYNAMIC_HANDLERS.write()[index as usize] = Some(handler);
+    unsafe {
+        (*tls::get_mut()).idt.apic_interrupts[index as usize] =
+            idt::Entry::with_handler_and_generic_stack(DISPATCH_STUBS[index as usize]);
+    }
+}
+
+unsafe extern "x86-interrupt" fn dispatch<const INDEX: usize>(
+    _interrupt_frame: idt::InterruptFrame,
+) {
+    crate::trace_irq!(FIRST_DYNAMIC_VECTOR as usize + INDEX);
+    affinity::record_interrupt(FIRST_DYNAMIC_VECTOR + INDEX as u8);
+    if let Some(handler) = DYNAMIC_HANDLERS.read()[INDEX].as_ref() {
+        handler();
+    }
+    signal_eoi();
+}
+
+macro_rules! dispatch_stubs {
+    ($($row:literal)*) => {
+        [$(
+            dispatch::<{ $row * 16 }>,
+            dispatch::<{ $row * 16 + 1 }>,
+            dispatch::<{ $row * 16 + 2 }>,
+            dispatch::<{ $row * 16 + 3 }>,
+            dispatch::<{ $row * 16 + 4 }>,
+            dispatch::<{ $row * 16 + 5 }>,
+            dispatch::<{ $row * 16 + 6 }>,
+            dispatch::<{ $row * 16 + 7 }>,
+            dispatch::<{ $row * 16 + 8 }>,
+            dispatch::<{ $row * 16 + 9 }>,
+            dispatch::<{ $row * 16 + 10 }>,
+            dispatch::<{ $row * 16 + 11 }>,
+            dispatch::<{ $row * 16 + 12 }>,
+            dispatch::<{ $row * 16 + 13 }>,
+            dispatch::<{ $row * 16 + 14 }>,
+            dispatch::<{ $row * 16 + 15 }>,
+        )*]
+    };
+}
+
+/// Entry points for each entry in `apic_interrupts`, which call the registered dynamic handler.
+static DISPATCH_STUBS: [idt::HandlerFunc; 128] = dispatch_stubs!(0 1 2 3 4 5 6 7);
+
 pub mod apic {
     use super::{
         ACTIVE_IO_INTERRUPT_SYSTEM, Controller, DeliveryMode, DestinationMode, IoApic, LocalApic,
-        LocalApicRegister, Madt, MadtEntry, Mutex, Polarity, TriggerMode, Vec, tls,
+        Madt, MadtEntry, Mutex, Polarity, TriggerMode, Vec, tls,
     };
+    use crate::arch::cpuid::{self, Feature};
+    use crate::init_state::{self, Subsystem};
 
     struct State {
         pub io_apics: Vec<IoApic>,
@@ -121,10 +263,15 @@ pub mod apic {
     static STATE: Mutex<Option<State>> = Mutex::new(None);
 
     pub unsafe fn init_from_madt(madt: &Madt) {
+        init_state::begin(Subsystem::Apic);
         unsafe {
             let mut io_apics = Vec::new();
             let mut interrupt_source_overrides = Vec::new();
             log::debug!("MADT found at {madt:p}");
+            assert!(cpuid::cpu_has(Feature::Apic), "CPU has no local APIC");
+            if cpuid::cpu_has(Feature::X2Apic) {
+                log::debug!("x2APIC supported, using xAPIC mode");
+            }
             log::debug!("Enabling Local APIC at {:#x}", madt.bsp_local_apic_address);
             let mut bsp_apic = LocalApic::new(madt.bsp_local_apic_address as usize);
             bsp_apic.enable_bsp_local_apic();
@@ -162,89 +309,125 @@ pub mod apic {
                 interrupt_vector_map: [1 << 63, 0],
             });
         }
+        init_state::finish(Subsystem::Apic);
     }
 
-    /// Registers a legacy IRQ to be sent to `interrupt_vector` on the Local APIC
-    pub unsafe fn register_legacy_irq(irq: u8, interrupt_vector: u8) {
-        unsafe {
-            // TODO: Return errors instead of panicking.
-            assert!(irq < 16);
-            let mut state_lock = STATE.lock();
-            let state = state_lock.as_mut().unwrap();
-            let mut polarity = Polarity::High;
-            let mut trigger_mode = TriggerMode::EdgeSensitive;
-            let mut irq = irq as u32;
-            if let Some(source_override) = state
-                .interrupt_source_overrides
-                .iter()
-                .find(|source_override| source_override.irq_source as u32 == irq)
-            {
-                polarity = match source_override.flags & 2 != 0 {
+    /// Returns the GSI, polarity and trigger mode a legacy ISA IRQ is connected to, taking
+    /// interrupt source overrides into account.
+    pub fn legacy_irq_route(irq: u8) -> (u32, Polarity, TriggerMode) {
+        let state_lock = STATE.lock();
+        let state = state_lock.as_ref().unwrap();
+        match state
+            .interrupt_source_overrides
+            .iter()
+            .find(|source_override| source_override.irq_source == irq)
+        {
+            Some(source_override) => {
+                let polarity = match source_override.flags & 2 != 0 {
                     false => Polarity::High,
                     true => Polarity::Low,
                 };
-                trigger_mode = match source_override.flags & 8 != 0 {
+                let trigger_mode = match source_override.flags & 8 != 0 {
                     false => TriggerMode::EdgeSensitive,
                     true => TriggerMode::LevelSensitive,
                 };
-                assert!(source_override.global_system_interrupt <= 255);
-                irq = source_override.global_system_interrupt;
+                (
+                    source_override.global_system_interrupt,
+                    polarity,
+                    trigger_mode,
+                )
             }
-            let local_apic_id = (*tls::get())
-                .local_apic
-                .apic
-                .as_ref()
-                .unwrap()
-                .read_register(LocalApicRegister::LapicId);
-            assert!(local_apic_id < 256);
-            // Set entry in I/O APIC
-            for io_apic in &mut state.io_apics {
-                let start_irq = io_apic.global_system_interrupt_base();
-                let end_irq = start_irq + io_apic.num_redirection_entries() as u32;
-                if start_irq <= irq && irq < end_irq {
-                    assert!(irq - start_irq <= 0x3F);
-                    let index = (irq - start_irq) as u8;
-                    let mut redirect = io_apic.read_redirection_entry(index);
-                    redirect.set_interrupt_vector(interrupt_vector);
-                    redirect.set_delivery_mode(DeliveryMode::Normal);
-                    redirect.set_destination_mode(DestinationMode::Physical);
-                    redirect.set_polarity(polarity);
-                    redirect.set_trigger_mode(trigger_mode);
-                    redirect.set_destination(local_apic_id as u8);
-                    redirect.set_masked(false);
-                    io_apic.write_redirection_entry(index, redirect);
-                    return;
-                }
-            }
-            unreachable!();
+            None => (irq as u32, Polarity::High, TriggerMode::EdgeSensitive),
         }
     }
 
     pub unsafe fn unregister_legacy_irq(irq: u8) {
-        let mut state_lock = STATE.lock();
-        let state = state_lock.as_mut().unwrap();
-        let irq = state
-            .interrupt_source_overrides
-            .iter()
-            .find(|source_override| source_override.irq_source == irq)
-            .map(|source_override| source_override.irq_source as u32)
-            .unwrap_or(irq as u32);
-        // Set entry in I/O APIC
-        for io_apic in &mut state.io_apics {
-            let start_irq = io_apic.global_system_interrupt_base();
-            let end_irq = start_irq + io_apic.num_redirection_entries() as u32;
-            if start_irq <= irq && irq < end_irq {
-                assert!(irq - start_irq <= 0x3F);
-                let index = (irq - start_irq) as u8;
+        unsafe {
+            let (gsi, _, _) = legacy_irq_route(irq);
+            assert!(unregister_gsi(gsi));
+        }
+    }
+
+    /// Returns the ID of the current CPU's Local APIC.
+    pub fn current_local_apic_id() -> u8 {
+        unsafe { (*tls::get()).local_apic.apic.as_ref().unwrap().id() }
+    }
+
+    /// Registers a GSI to be sent to `interrupt_vector` on the Local APIC with ID
+    /// `local_apic_id`, and unmasks it. Returns `false` if no I/O APIC handles the GSI.
+    pub unsafe fn register_gsi(
+        gsi: u32,
+        interrupt_vector: u8,
+        polarity: Polarity,
+        trigger_mode: TriggerMode,
+        local_apic_id: u8,
+    ) -> bool {
+        unsafe {
+            with_redirection_entry(gsi, |io_apic, index| {
+                let mut redirect = io_apic.read_redirection_entry(index);
+                redirect.set_interrupt_vector(interrupt_vector);
+                redirect.set_delivery_mode(DeliveryMode::Normal);
+                redirect.set_destination_mode(DestinationMode::Physical);
+                redirect.set_polarity(polarity);
+                redirect.set_trigger_mode(trigger_mode);
+                redirect.set_destination(local_apic_id);
+                redirect.set_masked(false);
+                io_apic.write_redirection_entry(index, redirect);
+            })
+        }
+    }
+
+    /// Masks a GSI. Returns `false` if no I/O APIC handles the GSI.
+    pub unsafe fn unregister_gsi(gsi: u32) -> bool {
+        unsafe {
+            with_redirection_entry(gsi, |io_apic, index| {
                 let mut redirect = io_apic.read_redirection_entry(index);
                 redirect.set_interrupt_vector(0);
                 redirect.set_destination(0);
                 redirect.set_masked(true);
                 io_apic.write_redirection_entry(index, redirect);
-                return;
+            })
+        }
+    }
+
+    /// Masks or unmasks a GSI, leaving the rest of its redirection entry as it is. Returns
+    /// `false` if no I/O APIC handles the GSI.
+    pub unsafe fn set_gsi_masked(gsi: u32, masked: bool) -> bool {
+        unsafe {
+            with_redirection_entry(gsi, |io_apic, index| {
+                let mut redirect = io_apic.read_redirection_entry(index);
+                redirect.set_masked(masked);
+                io_apic.write_redirection_entry(index, redirect);
+            })
+        }
+    }
+
+    /// Changes which Local APIC a registered GSI is sent to. Returns `false` if no I/O APIC
+    /// handles the GSI.
+    pub unsafe fn set_gsi_destination(gsi: u32, local_apic_id: u8) -> bool {
+        unsafe {
+            with_redirection_entry(gsi, |io_apic, index| {
+                let mut redirect = io_apic.read_redirection_entry(index);
+                redirect.set_destination(local_apic_id);
+                io_apic.write_redirection_entry(index, redirect);
+            })
+        }
+    }
+
+    /// Calls `f` with the I/O APIC handling `gsi` and the index of its redirection entry.
+    unsafe fn with_redirection_entry<F: FnOnce(&mut IoApic, u8)>(gsi: u32, f: F) -> bool {
+        let mut state_lock = STATE.lock();
+        let state = state_lock.as_mut().unwrap();
+        for io_apic in &mut state.io_apics {
+            let start_gsi = io_apic.global_system_interrupt_base();
+            let end_gsi = start_gsi + io_apic.num_redirection_entries() as u32;
+            if start_gsi <= gsi && gsi < end_gsi {
+                assert!(gsi - start_gsi <= 0x3F);
+                f(io_apic, (gsi - start_gsi) as u8);
+                return true;
             }
         }
-        unreachable!();
+        false
     }
 
     pub fn try_find_and_reserve_entry() -> Option<u8> {
@@ -254,13 +437,27 @@ pub mod apic {
             if *group != !0 {
                 let index_in_group = group.leading_ones();
                 *group |= (1 << 63) >> index_in_group;
-                return Some(group_index as u8 + index_in_group as u8);
+                return Some((group_index * 64) as u8 + index_in_group as u8);
             }
         }
         None
     }
 
+    /// Reserves a specific interrupt vector entry, returning `false` if it's already reserved.
+    pub fn try_reserve_entry(i: u8) -> bool {
+        let mut state_lock = STATE.lock();
+        let state = state_lock.as_mut().unwrap();
+        let group = &mut state.interrupt_vector_map[i as usize >> 6];
+        let bit = (1 << 63) >> (i & 0x3F);
+        if *group & bit != 0 {
+            return false;
+        }
+        *group |= bit;
+        true
+    }
+
     pub fn free_entry(i: u8) {
+        super::affinity::unregister(super::FIRST_DYNAMIC_VECTOR + i);
         let mut state_lock = STATE.lock();
         let state = state_lock.as_mut().unwrap();
         let group_index = i as usize >> 6;
@@ -268,3 +465,513 @@ pub mod apic {
         state.interrupt_vector_map[group_index] &= !((1 << 63) >> index_in_group);
     }
 }
+
+/// Routing of device interrupts to interrupt vectors, so that drivers only need to know which
+/// interrupt line their device uses.
+pub mod routing {
+    use super::super::platform::acpi::prt::PciRoute;
+    use super::{ACTIVE_IO_INTERRUPT_SYSTEM, Controller, Mutex, Polarity, TriggerMode, Vec};
+    use super::{affinity, apic, idt, tls};
+
+    static PCI_ROUTES: Mutex<Vec<PciRoute>> = Mutex::new(Vec::new());
+    static MAPPED_GSIS: Mutex<Vec<MappedGsi>> = Mutex::new(Vec::new());
+
+    /// A GSI along with how it's signalled.
+    #[derive(Clone, Copy, Debug)]
+    pub struct GsiRoute {
+        pub gsi: u32,
+        pub polarity: Polarity,
+        pub trigger_mode: TriggerMode,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+    pub enum RoutingError {
+        #[error("no interrupt route for device")]
+        NoRoute,
+        #[error("no I/O APIC handles GSI")]
+        NoIoApic,
+        #[error("out of interrupt vectors")]
+        OutOfVectors,
+        #[error("GSI already mapped")]
+        AlreadyMapped,
+        #[error("not a legacy ISA IRQ")]
+        InvalidIrq,
+    }
+
+    struct MappedGsi {
+        gsi: u32,
+        entry_index: u8,
+    }
+
+    /// Sets the known PCI interrupt routes, as read from the firmware.
+    pub fn set_pci_routes(routes: Vec<PciRoute>) {
+        *PCI_ROUTES.lock() = routes;
+    }
+
+    /// Returns the GSI that interrupt pin `pin` (0 for INTA) of a PCI device is connected to.
+    pub fn pci_interrupt_route(bus: u8, device: u8, pin: u8) -> Option<GsiRoute> {
+        PCI_ROUTES
+            .lock()
+            .iter()
+            .find(|route| route.bus == bus && route.device == device && route.pin == pin)
+            .map(|route| GsiRoute {
+                gsi: route.gsi,
+                polarity: match route.active_low {
+                    false => Polarity::High,
+                    true => Polarity::Low,
+                },
+                trigger_mode: match route.level_triggered {
+                    false => TriggerMode::EdgeSensitive,
+                    true => TriggerMode::LevelSensitive,
+                },
+            })
+    }
+
+    /// Returns the GSI legacy ISA IRQ `irq` is connected to, taking interrupt source overrides
+    /// from the MADT into account.
+    pub fn legacy_irq_route(irq: u8) -> Result<GsiRoute, RoutingError> {
+        if irq >= 16 {
+            return Err(RoutingError::InvalidIrq);
+        }
+        let (gsi, polarity, trigger_mode) = apic::legacy_irq_route(irq);
+        Ok(GsiRoute {
+            gsi,
+            polarity,
+            trigger_mode,
+        })
+    }
+
+    /// Sends a GSI to `vector` on the CPU with Local APIC ID `cpu`, and unmasks it. The vector's
+    /// handler must already be installed, such as with `register_handler`.
+    pub unsafe fn route_gsi(route: GsiRoute, vector: u8, cpu: u8) -> Result<(), RoutingError> {
+        let registered = unsafe {
+            apic::register_gsi(route.gsi, vector, route.polarity, route.trigger_mode, cpu)
+        };
+        match registered {
+            true => {
+                affinity::register(vector, affinity::Source::Gsi(route.gsi));
+                Ok(())
+            }
+            false => Err(RoutingError::NoIoApic),
+        }
+    }
+
+    /// Sends legacy ISA IRQ `irq` to `vector` on the CPU with Local APIC ID `cpu`, and unmasks
+    /// it. The vector's handler must already be installed, such as with `register_handler`.
+    pub unsafe fn route_irq(irq: u8, vector: u8, cpu: u8) -> Result<(), RoutingError> {
+        unsafe { route_gsi(legacy_irq_route(irq)?, vector, cpu) }
+    }
+
+    /// Stops legacy ISA IRQ `irq` from being delivered, until it's unmasked with `unmask_irq`.
+    pub unsafe fn mask_irq(irq: u8) -> Result<(), RoutingError> {
+        unsafe { set_irq_masked(irq, true) }
+    }
+
+    /// Delivers legacy ISA IRQ `irq` again after `mask_irq`.
+    pub unsafe fn unmask_irq(irq: u8) -> Result<(), RoutingError> {
+        unsafe { set_irq_masked(irq, false) }
+    }
+
+    unsafe fn set_irq_masked(irq: u8, masked: bool) -> Result<(), RoutingError> {
+        let route = legacy_irq_route(irq)?;
+        match unsafe { apic::set_gsi_masked(route.gsi, masked) } {
+            true => Ok(()),
+            false => Err(RoutingError::NoIoApic),
+        }
+    }
+
+    /// Routes a GSI to `handler`, returning the interrupt vector used.
+    pub unsafe fn map_gsi(route: GsiRoute, handler: idt::HandlerFunc) -> Result<u8, RoutingError> {
+        unsafe {
+            let mut mapped_gsis = MAPPED_GSIS.lock();
+            if mapped_gsis.iter().any(|mapped| mapped.gsi == route.gsi) {
+                return Err(RoutingError::AlreadyMapped);
+            }
+            match *ACTIVE_IO_INTERRUPT_SYSTEM.lock() {
+                Some(Controller::Apic) => {
+                    let index =
+                        apic::try_find_and_reserve_entry().ok_or(RoutingError::OutOfVectors)?;
+                    let idt = &mut (*tls::get_mut()).idt;
+                    idt.apic_interrupts[index as usize] =
+                        idt::Entry::with_handler_and_generic_stack(handler);
+                    let vector = 128 + index;
+                    if route_gsi(route, vector, apic::current_local_apic_id()).is_err() {
+                        idt.apic_interrupts[index as usize] = idt::Entry::missing();
+                        apic::free_entry(index);
+                        return Err(RoutingError::NoIoApic);
+                    }
+                    mapped_gsis.push(MappedGsi {
+                        gsi: route.gsi,
+                        entry_index: index,
+                    });
+                    Ok(vector)
+                }
+                None => panic!("map_gsi called with no active interrupt system"),
+            }
+        }
+    }
+
+    /// Masks a GSI mapped by `map_gsi` and frees its interrupt vector.
+    pub unsafe fn unmap_gsi(gsi: u32) {
+        unsafe {
+            let mut mapped_gsis = MAPPED_GSIS.lock();
+            let position = mapped_gsis
+                .iter()
+                .position(|mapped| mapped.gsi == gsi)
+                .expect("unmap_gsi called on unmapped GSI");
+            let mapped = mapped_gsis.swap_remove(position);
+            match *ACTIVE_IO_INTERRUPT_SYSTEM.lock() {
+                Some(Controller::Apic) => {
+                    apic::unregister_gsi(gsi);
+                    apic::free_entry(mapped.entry_index);
+                    (*tls::get_mut()).idt.apic_interrupts[mapped.entry_index as usize] =
+                        idt::Entry::missing();
+                }
+                None => panic!("unmap_gsi called with no active interrupt system"),
+            }
+        }
+    }
+
+    /// Routes interrupt pin `pin` (0 for INTA) of a PCI device to `handler`, returning the GSI
+    /// and interrupt vector used. The GSI is needed to unmap the interrupt.
+    pub unsafe fn map_pci_interrupt(
+        bus: u8,
+        device: u8,
+        pin: u8,
+        handler: idt::HandlerFunc,
+    ) -> Result<(u32, u8), RoutingError> {
+        let route = pci_interrupt_route(bus, device, pin).ok_or(RoutingError::NoRoute)?;
+        let vector = unsafe { map_gsi(route, handler)? };
+        Ok((route.gsi, vector))
+    }
+}
+
+/// Distribution of device interrupts across CPUs, based on how often each vector fires. Covers
+/// GSIs routed through `routing` as well as MSI and MSI-X messages, which register themselves
+/// once they're set up.
+pub mod affinity {
+    use super::super::{msi, pci::PciAddress, topology};
+    use super::{Mutex, Vec, apic, tls};
+    use crate::{kthread, tunables};
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+    use core::cmp::Reverse;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static VECTOR_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+    /// Vector counts at the last rebalance.
+    static LAST_COUNTS: Mutex<[u64; 256]> = Mutex::new([0; 256]);
+    /// Where each device interrupt vector is raised from.
+    static SOURCES: Mutex<BTreeMap<u8, Source>> = Mutex::new(BTreeMap::new());
+
+    /// Value of the `irq_affinity` tunable which balances interrupts automatically.
+    pub const AUTOMATIC: u64 = 256;
+
+    /// What raises a device interrupt vector, which decides the CPU it's sent to.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Source {
+        /// A GSI routed through an I/O APIC.
+        Gsi(u32),
+        /// A PCI device's single MSI message.
+        Msi(PciAddress),
+        /// An MSI-X table entry, by its address.
+        MsiX(usize),
+    }
+
+    impl Source {
+        unsafe fn set_destination(self, local_apic_id: u8) {
+            unsafe {
+                match self {
+                    Self::Gsi(gsi) => _ = apic::set_gsi_destination(gsi, local_apic_id),
+                    Self::Msi(device) => msi::set_msi_destination(device, local_apic_id),
+                    Self::MsiX(entry_address) => {
+                        msi::set_msix_destination(entry_address, local_apic_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts an interrupt on `vector`. Called by the dynamic handler dispatch stubs, so vectors
+    /// with their own IDT entries, like the ACPI SCI's, are never counted.
+    #[inline]
+    pub fn record_interrupt(vector: u8) {
+        VECTOR_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of interrupts received on `vector` since boot.
+    pub fn interrupt_count(vector: u8) -> u64 {
+        VECTOR_COUNTS[vector as usize].load(Ordering::Relaxed)
+    }
+
+    /// Adds `vector` to the interrupts balanced across CPUs, sending it to the CPU set by the
+    /// `irq_affinity` tunable straight away if there is one. Called once a device or I/O APIC is
+    /// set up to raise it.
+    pub fn register(vector: u8, source: Source) {
+        SOURCES.lock().insert(vector, source);
+        if let Some(local_apic_id) = forced_cpu() {
+            unsafe { source.set_destination(local_apic_id) };
+        }
+    }
+
+    /// Stops balancing `vector`. Called when it's freed.
+    pub fn unregister(vector: u8) {
+        SOURCES.lock().remove(&vector);
+    }
+
+    /// Returns the Local APIC IDs of the CPUs which can receive device interrupts.
+    pub fn online_cpus() -> Vec<u8> {
+        match topology::current() {
+            Some(topology) => topology.online_cpus().map(|cpu| cpu.apic_id).collect(),
+            // Before the topology is known, only the BSP is running
+            None => unsafe { vec![(*tls::get()).local_apic.apic.as_ref().unwrap().id()] },
+        }
+    }
+
+    /// Returns the CPU set by the `irq_affinity` tunable, or `None` if it's automatic or the CPU
+    /// isn't online.
+    fn forced_cpu() -> Option<u8> {
+        let forced = tunables::IRQ_AFFINITY.get();
+        if forced == AUTOMATIC {
+            return None;
+        }
+        match online_cpus().contains(&(forced as u8)) {
+            true => Some(forced as u8),
+            false => {
+                log::warn!("IRQ affinity set to CPU {forced}, which isn't online");
+                None
+            }
+        }
+    }
+
+    /// Sends every registered vector to the CPU set by the `irq_affinity` tunable. If that's
+    /// automatic, vectors are instead assigned busiest first to the least loaded CPU, using the
+    /// interrupts received since the last rebalance. Called whenever the tunable is set.
+    pub fn rebalance() {
+        let sources = SOURCES.lock();
+        // Also skips tunables set on the command line, before interrupts are set up
+        if sources.is_empty() {
+            return;
+        }
+        let mut last_counts = LAST_COUNTS.lock();
+        let mut vectors: Vec<(Source, u64)> = sources
+            .iter()
+            .map(|(&vector, &source)| {
+                let count = interrupt_count(vector);
+                let previous = core::mem::replace(&mut last_counts[vector as usize], count);
+                (source, count - previous)
+            })
+            .collect();
+        if tunables::IRQ_AFFINITY.get() != AUTOMATIC {
+            if let Some(local_apic_id) = forced_cpu() {
+                for (source, _) in vectors {
+                    unsafe { source.set_destination(local_apic_id) };
+                }
+            }
+            return;
+        }
+        let cpus = online_cpus();
+        vectors.sort_unstable_by_key(|&(_, recent)| Reverse(recent));
+        let mut loads = vec![0u64; cpus.len()];
+        for (source, recent) in vectors {
+            let (index, load) = loads
+                .iter_mut()
+                .enumerate()
+                .min_by_key(|(_, load)| **load)
+                .unwrap();
+            // Idle vectors still count for something, so they're spread out too
+            *load += recent.max(1);
+            unsafe { source.set_destination(cpus[index]) };
+        }
+    }
+
+    /// Starts periodically rebalancing interrupts, if there's more than one CPU to balance them
+    /// across. The `irq_affinity` tunable applies either way.
+    pub fn init() {
+        if online_cpus().len() < 2 {
+            log::debug!("Only one CPU online, not balancing interrupts");
+            return;
+        }
+        if let Err(err) = kthread::spawn("irq_balance", balance_thread, 0) {
+            log::error!("Failed to start interrupt balancing thread - {err}");
+        }
+    }
+
+    fn balance_thread(_: usize) -> usize {
+        loop {
+            kthread::sleep_ms(tunables::IRQ_BALANCE_INTERVAL_MS.get());
+            rebalance();
+        }
+    }
+}
+
+/// Handling of spurious interrupts and Local APIC errors. Both are counted per CPU, and logged at
+/// most once a second each, so interrupt storms and misconfigured interrupts show up in the log
+/// without flooding it.
+pub mod apic_errors {
+    use super::super::apic::local::LocalApicRegister;
+    use super::super::clock;
+    use super::{FIRST_DYNAMIC_VECTOR, apic, idt, signal_eoi, tls};
+
+    /// Vector the Local APIC raises spurious interrupts on, set in the spurious interrupt vector
+    /// register when it's enabled.
+    pub const SPURIOUS_VECTOR: u8 = 0xFF;
+    pub const ERROR_VECTOR: u8 = 0xFE;
+
+    const LOG_INTERVAL_NS: u64 = 1_000_000_000;
+
+    bitflags::bitflags! {
+        /// Local APIC error status register flags.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub struct ErrorStatus: u32 {
+            const SEND_CHECKSUM = 1 << 0;
+            const RECEIVE_CHECKSUM = 1 << 1;
+            const SEND_ACCEPT = 1 << 2;
+            const RECEIVE_ACCEPT = 1 << 3;
+            const REDIRECTABLE_IPI = 1 << 4;
+            const SEND_ILLEGAL_VECTOR = 1 << 5;
+            const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+            const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+        }
+    }
+
+    /// Number of times an event has happened on a CPU, and when it was last logged.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct EventCounter {
+        pub count: u64,
+        logged_count: u64,
+        last_log_ns: Option<u64>,
+    }
+
+    impl EventCounter {
+        /// Counts the event, returning how many times it's happened since it was last logged if
+        /// it should be logged now. Before the clock is initialised, only the first is logged.
+        fn record(&mut self) -> Option<u64> {
+            self.count += 1;
+            let now_ns = clock::try_now_ns();
+            let due = match (self.last_log_ns, now_ns) {
+                (None, _) => true,
+                (Some(last_log_ns), Some(now_ns)) => now_ns - last_log_ns >= LOG_INTERVAL_NS,
+                (Some(_), None) => false,
+            };
+            if !due {
+                return None;
+            }
+            self.last_log_ns = Some(now_ns.unwrap_or(0));
+            let since_logged = self.count - self.logged_count;
+            self.logged_count = self.count;
+            Some(since_logged)
+        }
+    }
+
+    /// Installs the spurious interrupt and error handlers, and unmasks error interrupts on the
+    /// current CPU's Local APIC. Must be called after it's enabled.
+    pub unsafe fn init() {
+        for vector in [SPURIOUS_VECTOR, ERROR_VECTOR] {
+            assert!(
+                apic::try_reserve_entry(vector - FIRST_DYNAMIC_VECTOR),
+                "vector {vector:#x} already in use",
+            );
+        }
+        unsafe {
+            let tls = tls::get_mut();
+            let idt = &mut (*tls).idt.apic_interrupts;
+            idt[(SPURIOUS_VECTOR - FIRST_DYNAMIC_VECTOR) as usize] =
+                idt::Entry::with_handler_and_generic_stack(spurious_interrupt);
+            idt[(ERROR_VECTOR - FIRST_DYNAMIC_VECTOR) as usize] =
+                idt::Entry::with_handler_and_generic_stack(error_interrupt);
+            let local_apic = (*tls).local_apic.apic.as_mut().unwrap();
+            // Clears any errors from before the handler was installed
+            local_apic.write_register(LocalApicRegister::ErrorStatus, 0);
+            local_apic.write_register(LocalApicRegister::LvtError, ERROR_VECTOR as u32);
+        }
+    }
+
+    /// Returns the number of spurious interrupts received by the current CPU.
+    pub fn spurious_count() -> u64 {
+        unsafe { (*tls::get()).local_apic.spurious_interrupts.count }
+    }
+
+    /// Returns the number of error interrupts raised by the current CPU's Local APIC.
+    pub fn error_count() -> u64 {
+        unsafe { (*tls::get()).local_apic.errors.count }
+    }
+
+    /// Spurious interrupts aren't marked as in service, so no EOI is sent.
+    extern "x86-interrupt" fn spurious_interrupt(_interrupt_frame: idt::InterruptFrame) {
+        let local_apic = unsafe { &mut (*tls::get_mut()).local_apic };
+        if let Some(since_logged) = local_apic.spurious_interrupts.record() {
+            log::warn!(
+                "{since_logged} spurious interrupts since last reported, {} in total",
+                local_apic.spurious_interrupts.count,
+            );
+        }
+    }
+
+    extern "x86-interrupt" fn error_interrupt(_interrupt_frame: idt::InterruptFrame) {
+        let local_apic = unsafe { &mut (*tls::get_mut()).local_apic };
+        let status = match local_apic.apic.as_mut() {
+            Some(apic) => {
+                // The register only latches the current errors when written
+                apic.write_register(LocalApicRegister::ErrorStatus, 0);
+                ErrorStatus::from_bits_retain(apic.read_register(LocalApicRegister::ErrorStatus))
+            }
+            None => ErrorStatus::empty(),
+        };
+        if let Some(since_logged) = local_apic.errors.record() {
+            log::error!(
+                "Local APIC error {status:?}, {since_logged} errors since last reported, {} in \
+                total",
+                local_apic.errors.count,
+            );
+        }
+        signal_eoi();
+    }
+}
+
+/// Interrupts sent between CPUs. Targets are CPU indices, translated to APIC IDs through the
+/// topology snapshot, so sending never takes a lock.
+pub mod ipi {
+    use super::super::topology;
+    use super::{disable_and_save, register_handler, restore, tls};
+
+    /// Vector sent to wake a CPU idling until a thread is ready. Its handler does nothing, as the
+    /// interrupt ending the idle period is enough.
+    pub const WAKE_VECTOR: u8 = 0xFD;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+    pub enum IpiError {
+        #[error("CPU topology not known yet")]
+        NoTopology,
+        #[error("no online CPU with that index")]
+        NoSuchCpu,
+        #[error("Local APIC not enabled")]
+        NoLocalApic,
+    }
+
+    /// Sends an interrupt on `vector` to the CPU with index `cpu_index`, which may be the current
+    /// one.
+    pub fn send(cpu_index: usize, vector: u8) -> Result<(), IpiError> {
+        let topology = topology::current().ok_or(IpiError::NoTopology)?;
+        let cpu = topology
+            .cpus()
+            .get(cpu_index)
+            .filter(|cpu| cpu.online)
+            .ok_or(IpiError::NoSuchCpu)?;
+        let saved_state = disable_and_save();
+        let result = match unsafe { (*tls::get_mut()).local_apic.apic.as_mut() } {
+            Some(local_apic) => {
+                local_apic.send_ipi(cpu.apic_id, vector);
+                Ok(())
+            }
+            None => Err(IpiError::NoLocalApic),
+        };
+        unsafe { restore(saved_state) };
+        result
+    }
+
+    /// Installs the handler for `WAKE_VECTOR`.
+    pub fn init() {
+        register_handler(WAKE_VECTOR, || {}).expect("IPI wake vector already in use");
+    }
+}
@@ -1,10 +1,16 @@
-use super::define_asm_symbol;
+use define_asm_symbol::export_asm_all;
 
 /// Arguments passed to the kernel at load time.
 #[repr(C)]
 #[derive(Clone)]
+// The page table address is used in assembly
+#[export_asm_all]
+#[asm_name = "kernel_args::Args"]
 pub struct Args {
     pub kernel_elf: Slice<u8>,
+    /// The kernel's symbol table, for bootloaders which can't provide the kernel ELF file. See
+    /// `debugging::KERNEL_SYMBOLS_FILE`.
+    pub kernel_symbols: Slice<u8>,
     pub page_table_address: usize,
     pub environment: Slice<u8>,
     pub memory_bitmap: MemoryBitmap,
@@ -13,12 +19,6 @@ pub struct Args {
     pub framebuffers: Slice<Framebuffer>,
 }
 
-// The page table address is used in assembly, so export it here
-define_asm_symbol!(
-    "kernel_args::Args.page_table_address",
-    core::mem::offset_of!(Args, page_table_address)
-);
-
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct Slice<T> {
@@ -93,12 +93,16 @@ pub struct ArchPointers {
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct Framebuffer {
-    pub ptr: core::ptr::NonNull<u32>,
+    /// Physical address if `ptr_type` is `Physical`.
+    pub ptr: core::ptr::NonNull<u8>,
     pub ptr_type: PtrType,
+    /// Size in bytes.
     pub size: u32,
     pub width: u32,
     pub height: u32,
-    pub scanline_length: u32,
+    /// Bytes per scanline.
+    pub pitch: u32,
+    pub bits_per_pixel: u32,
     pub color_format: ColorFormat,
 }
 
@@ -112,9 +116,9 @@ pub enum PtrType {
 #[repr(C, u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ColorFormat {
-    /// Red, Green, Blue, Reserved - 8 bits per color
+    /// Red, Green, Blue, Reserved - 8 bits per color, no reserved byte at 24 bits per pixel
     Rgbr8,
-    /// Blue, Green, Red, Reserved - 8 bits per color
+    /// Blue, Green, Red, Reserved - 8 bits per color, no reserved byte at 24 bits per pixel
     Bgrr8,
     /// Custom bitmask - information derived from masks
     Bitmask(ColorBitmask),
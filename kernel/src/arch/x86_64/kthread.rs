@@ -0,0 +1,191 @@
+//! Architecture specific kernel thread support: stacks and context switching.
+
+use super::page_allocation;
+use super::paging::{PAGE_SIZE, PageTableEntry};
+use core::arch::{asm, global_asm};
+use spin::Mutex;
+
+global_asm!(include_str!("kthread.s"), options(raw));
+
+unsafe extern "C" {
+    static KERNEL_THREAD_STACKS_BASE: usize;
+    static KERNEL_THREAD_STACKS_END: usize;
+
+    fn kthread_switch_context(old_stack_pointer: *mut usize, new_stack_pointer: usize);
+    fn kthread_entry_trampoline();
+}
+
+/// Size of the mapped part of a kernel thread stack.
+pub const STACK_SIZE: usize = 64 * 1024;
+/// Size of the virtual area reserved for each stack. Everything below the mapped stack is left
+/// unmapped, so that overflows fault instead of corrupting a neighbouring stack.
+const STACK_SLOT_SIZE: usize = 2 * STACK_SIZE;
+const MAX_STACKS: usize = 2048;
+
+/// Bitmap of used stack slots.
+static STACK_SLOTS: Mutex<[u64; MAX_STACKS / 64]> = Mutex::new([0; MAX_STACKS / 64]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum StackAllocError {
+    #[error("no free kernel thread stack slots")]
+    OutOfSlots,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+/// A kernel thread stack, unmapped and freed on drop.
+#[derive(Debug)]
+pub struct Stack {
+    slot: usize,
+}
+
+impl Stack {
+    pub fn new() -> Result<Self, StackAllocError> {
+        let slot = 'blk: {
+            let mut slots = STACK_SLOTS.lock();
+            let max_slots = usize::min(MAX_STACKS, stacks_area_size() / STACK_SLOT_SIZE);
+            for (group_index, group) in slots.iter_mut().enumerate() {
+                if *group == !0 {
+                    continue;
+                }
+                let bit_index = (!*group).trailing_zeros() as usize;
+                let slot = group_index * 64 + bit_index;
+                if slot >= max_slots {
+                    break;
+                }
+                *group |= 1 << bit_index;
+                break 'blk slot;
+            }
+            return Err(StackAllocError::OutOfSlots);
+        };
+        let stack = Self { slot };
+        // Map stack pages, any partially mapped stack is cleaned up by `drop`
+        for address in (stack.bottom()..stack.top()).step_by(PAGE_SIZE) {
+            unsafe {
+                if page_allocation::map_page(address, PageTableEntry::READ_WRITE).is_err() {
+                    return Err(StackAllocError::OutOfMemory);
+                }
+            }
+        }
+        Ok(stack)
+    }
+
+    /// Lowest mapped address of the stack.
+    pub fn bottom(&self) -> usize {
+        self.top() - STACK_SIZE
+    }
+
+    /// Address one past the highest mapped address of the stack.
+    pub fn top(&self) -> usize {
+        stacks_area_base() + (self.slot + 1) * STACK_SLOT_SIZE
+    }
+
+    /// Returns whether `address` is in the unmapped space below the stack, where accesses from an
+    /// overflowing stack end up.
+    pub fn guard_contains(&self, address: usize) -> bool {
+        (self.top() - STACK_SLOT_SIZE..self.bottom()).contains(&address)
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        for address in (self.bottom()..self.top()).step_by(PAGE_SIZE) {
+            unsafe {
+                page_allocation::unmap_and_free_page(address);
+                asm!("invlpg [{}]", in(reg) address, options(nostack, preserves_flags));
+            }
+        }
+        STACK_SLOTS.lock()[self.slot / 64] &= !(1 << (self.slot % 64));
+    }
+}
+
+fn stacks_area_base() -> usize {
+    unsafe { &KERNEL_THREAD_STACKS_BASE as *const usize as usize }
+}
+
+fn stacks_area_size() -> usize {
+    unsafe { (&KERNEL_THREAD_STACKS_END as *const usize as usize) - stacks_area_base() + 1 }
+}
+
+/// Saved execution state of a kernel thread which is not currently running.
+#[derive(Debug)]
+pub struct Context {
+    stack_pointer: usize,
+}
+
+impl Context {
+    /// Context for the currently executing thread, filled in on the first switch away from it.
+    pub const fn current() -> Self {
+        Self { stack_pointer: 0 }
+    }
+
+    /// Creates a context which begins executing `entry` at the top of `stack` with interrupts
+    /// disabled.
+    pub fn new(stack: &Stack, entry: extern "C" fn() -> !) -> Self {
+        // Initial frame popped by `kthread_switch_context`, lowest address first
+        let frame: [u64; 8] = [
+            0,                                            // r15
+            0,                                            // r14
+            0,                                            // r13
+            entry as *const () as u64,                    // r12
+            0,                                            // rbx
+            0,                                            // rbp
+            0x2,                                          // rflags
+            kthread_entry_trampoline as *const () as u64, // return address
+        ];
+        let stack_pointer = stack.top() - size_of_val(&frame);
+        unsafe {
+            (stack_pointer as *mut [u64; 8]).write(frame);
+        }
+        Self { stack_pointer }
+    }
+}
+
+/// Saves the current thread's state into `old` and resumes the thread saved in `new`.
+/// Returns when another thread switches back to `old`.
+///
+/// # Safety
+///
+/// Both contexts must stay alive and in place until the switch back, and `new` must have come
+/// from `Context::new` or a previous switch.
+pub unsafe fn switch(old: *mut Context, new: *const Context) {
+    unsafe {
+        kthread_switch_context(&raw mut (*old).stack_pointer, (*new).stack_pointer);
+    }
+}
+
+/// Halts until the next interrupt, for when there are no threads ready to run.
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("sti; hlt; cli");
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert;
+
+    #[kernel_test]
+    fn maps_and_unmaps_stacks() -> TestResult {
+        let stack =
+            Stack::new().map_err(|err| alloc::format!("allocating stack failed - {err}"))?;
+        let (bottom, top) = (stack.bottom(), stack.top());
+        // Checks the present and writable bits, `check_flags` doesn't support no execute
+        let present_writable = PageTableEntry::READ_WRITE_EXECUTE;
+        ktest_assert!(page_allocation::check_flags(
+            bottom,
+            STACK_SIZE,
+            present_writable,
+        ));
+        ktest_assert!(stack.guard_contains(bottom - 1));
+        ktest_assert!(!stack.guard_contains(bottom));
+        drop(stack);
+        ktest_assert!(!page_allocation::check_flags(
+            bottom,
+            top - bottom,
+            present_writable,
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+// x86_64 kernel thread context switching.
+
+.section ".text"
+
+// Saves the callee-saved registers and flags of the current thread onto its stack, stores the
+// stack pointer at [rdi], then restores the thread whose stack pointer is in rsi.
+.global kthread_switch_context
+.type kthread_switch_context, @function
+kthread_switch_context:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+
+// First return address of a new thread. r12 holds the entry function, which must not return.
+.global kthread_entry_trampoline
+.type kthread_entry_trampoline, @function
+kthread_entry_trampoline:
+    xor rbp, rbp
+    call r12
+    ud2
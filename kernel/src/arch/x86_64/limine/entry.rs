@@ -1,9 +1,9 @@
+use crate::arch::boot;
 use crate::arch::kernel_args;
 use crate::arch::page_allocation;
 use crate::logging;
 use crate::physical_block_allocator::{MaxCapacity, PageBox, PageVec, PhysicalBlockAllocator};
 use core::arch::asm;
-use core::fmt::Write;
 
 mod requests {
     use crate::arch::limine::requests::*;
@@ -52,53 +52,6 @@ unsafe extern "C" {
     unsafe fn init64(kernel_args_ptr: core::ptr::NonNull<kernel_args::Args>) -> !;
 }
 
-struct LimineDebugLogger;
-
-macro_rules! impl_writers_func_body {
-    ($write_fn: ident, $arg: ident) => {
-        crate::arch::debug_output::ArchWriter.$write_fn($arg)?;
-        return Ok(());
-    };
-}
-
-impl Write for LimineDebugLogger {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        impl_writers_func_body!(write_str, s);
-    }
-
-    fn write_char(&mut self, c: char) -> core::fmt::Result {
-        impl_writers_func_body!(write_char, c);
-    }
-
-    fn write_fmt(&mut self, args: core::fmt::Arguments) -> core::fmt::Result {
-        impl_writers_func_body!(write_fmt, args);
-    }
-}
-
-impl log::Log for LimineDebugLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
-    }
-
-    fn log(&self, record: &log::Record) {
-        if self.enabled(record.metadata()) {
-            _ = writeln!(
-                Self,
-                "[{}] ({}) {}",
-                record.level(),
-                record.target(),
-                record.args()
-            );
-        }
-    }
-
-    fn flush(&self) {}
-}
-
-// TODO: Should we implement a framebuffer logger in this stage? Probably unnecessary unless a
-// computer doesn't work and we can't debug it using serial (for some reason).
-static LOGGER: LimineDebugLogger = LimineDebugLogger;
-
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn limine_entry() -> ! {
     unsafe {
@@ -118,7 +71,7 @@ pub unsafe extern "C" fn limine_entry() -> ! {
         // Setup printing to Limine terminals
         crate::arch::debug_output::init_writers();
         logging::init_wrapper();
-        _ = logging::CURRENT_LOGGER.lock().replace(&LOGGER);
+        _ = logging::CURRENT_LOGGER.write().replace(&boot::LOGGER);
         // Get kernel ELF for debugging symbols
         let kernel_file = read_request_volatile(&requests::KERNEL_FILE)
             .response
@@ -127,7 +80,7 @@ pub unsafe extern "C" fn limine_entry() -> ! {
             })
             .file
             .read();
-        crate::debugging::KERNEL_ELF_FILE = Some(core::slice::from_raw_parts(
+        _ = crate::debugging::KERNEL_ELF_FILE.set(core::slice::from_raw_parts(
             kernel_file.ptr,
             kernel_file.size as usize,
         ));
@@ -144,35 +97,8 @@ pub unsafe extern "C" fn limine_entry() -> ! {
         });
         // Generate kernel memory bitmap, initialise page allocator
         {
-            // Bit indexing masks
-            #[rustfmt::skip]
-        const START_MASKS: [u8; 8] = [
-            0b11111111,
-            0b01111111,
-            0b00111111,
-            0b00011111,
-            0b00001111,
-            0b00000111,
-            0b00000011,
-            0b00000001,
-        ];
-            #[rustfmt::skip]
-        const END_MASKS: [u8; 8] = [
-            0b10000000,
-            0b11000000,
-            0b11100000,
-            0b11110000,
-            0b11111000,
-            0b11111100,
-            0b11111110,
-            0b11111111,
-        ];
-            /// Number of mapped bytes per bitmap bit
-            const BIT_RATIO: usize = 4096;
-            /// Number of mapped bytes per bitmap byte
-            const BYTE_RATIO: usize = BIT_RATIO * 8;
             // Allocate kernel memory map
-            let memory_map_page_size = (mappable_bytes / BYTE_RATIO).next_multiple_of(4096);
+            let memory_map_page_size = boot::memory_bitmap_size(mappable_bytes);
             let kernel_bitmap = memory_map
                 .iter()
                 .find_map(|entry| match entry.entry_type {
@@ -194,45 +120,11 @@ pub unsafe extern "C" fn limine_entry() -> ! {
                 .iter()
                 .filter(|entry| entry.entry_type == super::MemoryMapEntryType::Usable);
             for entry in usable_entries_iter {
-                // Find start and ending bit indices in the bitmap
-                let start_bitmap_i = entry.base / BIT_RATIO;
-                let end_bitmap_i = (entry.base + entry.length - 1) / BIT_RATIO;
-                // Indices of bytes containing the start and end bits
-                let start_byte_i = start_bitmap_i / 8;
-                let end_byte_i = end_bitmap_i / 8;
-                // Indices of bits in bytes containing the start and end bits
-                let start_bit_i = start_bitmap_i % 8;
-                let end_bit_i = end_bitmap_i % 8;
-                // Go through all the bytes modified, clear out bits
-                if start_byte_i == end_byte_i {
-                    kernel_bitmap[start_byte_i] &=
-                        !START_MASKS[start_bit_i] | !END_MASKS[end_bit_i];
-                } else {
-                    kernel_bitmap[start_byte_i] &= !START_MASKS[start_bit_i];
-                    kernel_bitmap[start_byte_i + 1..end_byte_i].fill(0);
-                    kernel_bitmap[end_byte_i] &= !END_MASKS[end_bit_i];
-                }
+                boot::mark_memory_range(kernel_bitmap, entry.base, entry.length, false);
             }
             // Reserve space used for kernel bitmap in kernel bitmap
-            {
-                // Find start and ending bit indices in the bitmap
-                let start_bitmap_i = kernel_bitmap.as_ptr() as usize / BIT_RATIO;
-                let end_bitmap_i = kernel_bitmap.as_ptr() as usize + kernel_bitmap.len() - 1;
-                // Indices of bytes containing the start and end bits
-                let start_byte_i = start_bitmap_i / 8;
-                let end_byte_i = end_bitmap_i / 8;
-                // Indices of bits in bytes containing the start and end bits
-                let start_bit_i = start_bitmap_i % 8;
-                let end_bit_i = end_bitmap_i % 8;
-                // Go through all the bytes modified, clear out bits
-                if start_byte_i == end_byte_i {
-                    kernel_bitmap[start_byte_i] |= START_MASKS[start_bit_i] & END_MASKS[end_bit_i];
-                } else {
-                    kernel_bitmap[start_byte_i] |= START_MASKS[start_bit_i];
-                    kernel_bitmap[start_byte_i + 1..end_byte_i].fill(0xFF);
-                    kernel_bitmap[end_byte_i] |= END_MASKS[end_bit_i];
-                }
-            }
+            let (bitmap_address, bitmap_len) = (kernel_bitmap.as_ptr() as usize, kernel_bitmap.len());
+            boot::mark_memory_range(kernel_bitmap, bitmap_address, bitmap_len, true);
             log::debug!(
                 "Allocated kernel memory bitmap - ptr: {:p}, len: {:#x}",
                 kernel_bitmap,
@@ -258,10 +150,6 @@ pub unsafe extern "C" fn limine_entry() -> ! {
                     );
                 }
                 for (i, fb) in limine_framebuffers.iter().enumerate() {
-                    if fb.bpp != 32 {
-                        log::warn!("Skipping framebuffer {i} because of unknown BPP {}", fb.bpp);
-                        continue;
-                    }
                     if fb.memory_model != super::FramebufferModel::Rgb {
                         log::warn!(
                             "Skipping framebuffer {i} because of unknown memory model {:?}",
@@ -269,15 +157,20 @@ pub unsafe extern "C" fn limine_entry() -> ! {
                         );
                         continue;
                     }
-                    let scanline_length = fb.pitch as u32 / (fb.bpp as u32 / 8);
+                    let color_format = boot::color_format(
+                        boot::channel_mask(fb.red_mask_size, fb.red_mask_shift),
+                        boot::channel_mask(fb.green_mask_size, fb.green_mask_shift),
+                        boot::channel_mask(fb.blue_mask_size, fb.blue_mask_shift),
+                    );
                     framebuffers.push(kernel_args::Framebuffer {
-                        ptr: fb.ptr,
+                        ptr: fb.ptr.cast(),
                         ptr_type: kernel_args::PtrType::Linear,
-                        size: scanline_length * fb.height as u32 * (fb.bpp as u32 / 8),
+                        size: (fb.pitch * fb.height) as u32,
                         width: fb.width as u32,
                         height: fb.height as u32,
-                        scanline_length,
-                        color_format: kernel_args::ColorFormat::Bgrr8,
+                        pitch: fb.pitch as u32,
+                        bits_per_pixel: fb.bpp as u32,
+                        color_format,
                     });
                     log::debug!("Added framebuffer {i}");
                     if framebuffers.len() == framebuffers.capacity() {
@@ -303,6 +196,11 @@ pub unsafe extern "C" fn limine_entry() -> ! {
             panic!("no initrd module was provided to the kernel");
         }
         let initrd_file = *module_response.modules;
+        // Kernel command line, passed on as the environment
+        let cmdline = match kernel_file.cmdline_cstr.is_null() {
+            true => c"",
+            false => core::ffi::CStr::from_ptr(kernel_file.cmdline_cstr),
+        };
         // Get architecture pointers
         let efi_ptr = match read_request_volatile(&requests::EFI_SYSTEM_TABLE).response {
             Some(response) => response.ptr,
@@ -319,10 +217,11 @@ pub unsafe extern "C" fn limine_entry() -> ! {
                     ptr: kernel_file.ptr,
                     len: kernel_file.size as usize,
                 },
+                kernel_symbols: kernel_args::Slice::null(),
                 page_table_address: page_allocation::page_table_address(),
                 environment: kernel_args::Slice {
-                    ptr: core::ptr::null(),
-                    len: 0,
+                    ptr: cmdline.as_ptr() as *const u8,
+                    len: cmdline.count_bytes(),
                 },
                 memory_bitmap: kernel_args::MemoryBitmap {
                     slice: page_allocation::memory_bitmap(),
@@ -1,22 +1,39 @@
 //! Architecture specific code for the x86_64 architecture.
 
+pub mod address_space;
 pub mod apic;
 pub mod bochs_debug;
+pub mod boot;
 pub mod clock;
 pub mod cpuid;
 pub mod gdt;
+pub mod glyph_blit;
 pub mod idt;
+pub mod idle;
 pub mod init;
 pub mod interrupts;
 pub mod kernel_args;
+pub mod kthread;
 pub mod limine;
+pub mod msi;
+pub mod msr;
+pub mod multiboot2;
+pub mod nmi;
+pub mod numa;
 pub mod page_allocation;
 pub mod paging;
+pub mod pci;
+pub mod perf_counter;
+pub mod profiler;
+pub mod protection;
+pub mod serial;
 pub mod syscall;
 pub mod tls;
+pub mod topology;
 pub mod tss;
-pub mod user_page_mapping;
-pub mod virtual_page_mapping;
+pub mod uefi;
+pub mod user;
+pub mod watchpoint;
 
 // Platform re-exports
 
@@ -26,17 +43,6 @@ pub mod platform {
 
 // Common functionality
 
-macro_rules! define_asm_symbol {
-    ($name:expr, $value:expr $(,)?) => {
-        core::arch::global_asm!(
-            concat!(".global \"", $name, "\"\n\"", $name, "\" = {value}"),
-            value = const $value,
-        );
-    };
-}
-
-pub use define_asm_symbol;
-
 #[repr(C, align(8))]
 struct DescriptorTablePointer([u8; 10]);
 
@@ -75,15 +81,16 @@ impl DescriptorTablePointer {
 
 pub mod debug_output {
     use super::bochs_debug;
+    use crate::sync::OnceFlag;
 
-    static mut BOCHS_WRITER_ENABLED: bool = false;
+    static BOCHS_WRITER_ENABLED: OnceFlag = OnceFlag::new();
 
     /// Attempts to initialise and enable each writer in turn. Writers failing to initalise do not
     /// impact initialisation of other writers.
     pub unsafe fn init_writers() {
         unsafe {
             if bochs_debug::BochsWriter::test_port_exists() {
-                BOCHS_WRITER_ENABLED = true;
+                BOCHS_WRITER_ENABLED.set();
             }
         }
     }
@@ -93,7 +100,7 @@ pub mod debug_output {
 
     macro_rules! impl_writers_func_body {
         ($write_fn: ident, $arg: ident) => {
-            if unsafe { BOCHS_WRITER_ENABLED } {
+            if BOCHS_WRITER_ENABLED.is_set() {
                 bochs_debug::BochsWriter.$write_fn($arg)?;
             }
             return Ok(());
@@ -115,90 +122,169 @@ pub mod debug_output {
     }
 }
 
-pub mod msr {
-    /// Reads a value from the given MSR.
-    #[inline]
-    pub unsafe fn read(index: u32) -> u64 {
+pub mod port {
+    /// Reads a byte from the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn read_byte(port: u16) -> u8 {
         unsafe {
-            let value;
+            let mut byte: u8;
             core::arch::asm!(
-                "rdmsr",
-                "shl rdx, 32",
-                "or rdx, rax",
-                in("ecx") index,
-                out("rdx") value,
-                out("eax") _,
+                "in al, dx",
+                in("dx") port,
+                lateout("al") byte,
                 options(nomem, preserves_flags),
             );
-            value
+            byte
         }
     }
 
-    /// Writes a value to the given MSR.
-    #[inline]
-    pub unsafe fn write(index: u32, value: u64) {
+    /// Writes a byte to the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn write_byte(port: u16, byte: u8) {
         unsafe {
             core::arch::asm!(
-                "rdmsr",
-                "shl rdx, 32",
-                "or rdx, rax",
-                in("ecx") index,
-                in("eax") value as u32,
-                in("edx") (value >> 32) as u32,
+                "out dx, al",
+                in("dx") port,
+                in("al") byte,
                 options(nomem, preserves_flags),
             );
         }
     }
 
-    // Standard MSRs
-    pub const FS_BASE: u32 = 0xC000_0100;
-    pub const GS_BASE: u32 = 0xC000_0101;
-    pub const KERNEL_GS_BASE: u32 = 0xC000_0102;
-    pub const EFER: u32 = 0xC000_0080;
-    pub const IA32_STAR: u32 = 0xC000_0081;
-    pub const IA32_LSTAR: u32 = 0xC000_0082;
-    pub const IA32_CSTAR: u32 = 0xC000_0083;
-    pub const IA32_FMASK: u32 = 0xC000_0084;
-}
+    /// Reads a word from the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn read_word(port: u16) -> u16 {
+        unsafe {
+            let mut word: u16;
+            core::arch::asm!(
+                "in ax, dx",
+                in("dx") port,
+                lateout("ax") word,
+                options(nomem, preserves_flags),
+            );
+            word
+        }
+    }
 
-pub mod port {
-    /// Reads a byte from the given x86 port number.
+    /// Writes a word to the given x86 port number.
     #[inline(always)]
-    pub unsafe fn read_byte(port: u16) -> u8 {
+    pub unsafe fn write_word(port: u16, word: u16) {
         unsafe {
-            let mut byte: u8;
             core::arch::asm!(
-                "in al, dx",
+                "out dx, ax",
                 in("dx") port,
-                lateout("al") byte,
+                in("ax") word,
                 options(nomem, preserves_flags),
             );
-            byte
         }
     }
 
-    /// Writes a byte to the given x86 port number.
+    /// Reads a double word from the given x86 port number.
     #[inline(always)]
-    pub unsafe fn write_byte(port: u16, byte: u8) {
+    pub unsafe fn read_dword(port: u16) -> u32 {
         unsafe {
+            let mut dword: u32;
             core::arch::asm!(
-                "out dx, al",
+                "in eax, dx",
                 in("dx") port,
-                in("al") byte,
+                lateout("eax") dword,
+                options(nomem, preserves_flags),
+            );
+            dword
+        }
+    }
+
+    /// Writes a double word to the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn write_dword(port: u16, dword: u32) {
+        unsafe {
+            core::arch::asm!(
+                "out dx, eax",
+                in("dx") port,
+                in("eax") dword,
                 options(nomem, preserves_flags),
             );
         }
     }
 
+    /// Fills `buffer` with words read from the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn read_words(port: u16, buffer: &mut [u16]) {
+        unsafe {
+            core::arch::asm!(
+                "rep insw",
+                in("dx") port,
+                inout("rdi") buffer.as_mut_ptr() => _,
+                inout("rcx") buffer.len() => _,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Writes the words in `buffer` to the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn write_words(port: u16, buffer: &[u16]) {
+        unsafe {
+            core::arch::asm!(
+                "rep outsw",
+                in("dx") port,
+                inout("rsi") buffer.as_ptr() => _,
+                inout("rcx") buffer.len() => _,
+                options(readonly, nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Fills `buffer` with double words read from the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn read_dwords(port: u16, buffer: &mut [u32]) {
+        unsafe {
+            core::arch::asm!(
+                "rep insd",
+                in("dx") port,
+                inout("rdi") buffer.as_mut_ptr() => _,
+                inout("rcx") buffer.len() => _,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Writes the double words in `buffer` to the given x86 port number.
+    #[inline(always)]
+    pub unsafe fn write_dwords(port: u16, buffer: &[u32]) {
+        unsafe {
+            core::arch::asm!(
+                "rep outsd",
+                in("dx") port,
+                inout("rsi") buffer.as_ptr() => _,
+                inout("rcx") buffer.len() => _,
+                options(readonly, nostack, preserves_flags),
+            );
+        }
+    }
+
     // Standard ports
+    pub const PIC_MASTER_COMMAND: u16 = 0x20;
+    pub const PIC_MASTER_DATA: u16 = 0x21;
+    pub const PIC_SLAVE_COMMAND: u16 = 0xA0;
+    pub const PIC_SLAVE_DATA: u16 = 0xA1;
     pub const BOCHS_DEBUG: u16 = 0xE9;
+    pub const COM1: u16 = 0x3F8;
     pub const CMOS_NMI_AND_REGISTER: u16 = 0x70;
     pub const CMOS_DATA: u16 = 0x71;
+    pub const QEMU_DEBUG_EXIT: u16 = 0xF4;
+    pub const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+    pub const PCI_CONFIG_DATA: u16 = 0xCFC;
 }
 
 pub mod process {
+    use super::syscall::SyscallError;
+    use define_asm_symbol::export_asm_all;
+
+    /// User mode register state, saved when user code enters the kernel.
     #[derive(Clone, Copy)]
-    #[repr(C)]
+    #[repr(C, align(16))]
+    #[export_asm_all]
     pub struct RegisterStore {
         rax: u64,
         rbx: u64,
@@ -223,6 +309,69 @@ pub mod process {
         fxsave_area: [u128; 32],
     }
 
+    impl RegisterStore {
+        /// Flags user code may change, everything else is fixed.
+        const USER_FLAGS: u64 = 0x0000_0000_0004_0CD5;
+        const INTERRUPT_FLAG: u64 = 1 << 9;
+        const RESERVED_FLAG: u64 = 1 << 1;
+
+        /// Creates the initial registers of a user thread, starting at `entry` with the stack
+        /// pointer at `stack_pointer`.
+        pub fn new_user(entry: usize, stack_pointer: usize) -> Self {
+            let mut fxsave_area = [0u128; 32];
+            // Default x87 control word and MXCSR, with all exceptions masked
+            fxsave_area[0] = 0x037F;
+            fxsave_area[1] = 0x1F80 << 64;
+            Self {
+                rax: 0,
+                rbx: 0,
+                rcx: 0,
+                rdx: 0,
+                rsi: 0,
+                rdi: 0,
+                rbp: 0,
+                rsp: stack_pointer as u64,
+                r8: 0,
+                r9: 0,
+                r10: 0,
+                r11: 0,
+                r12: 0,
+                r13: 0,
+                r14: 0,
+                r15: 0,
+                rip: entry as u64,
+                rflags: Self::INTERRUPT_FLAG | Self::RESERVED_FLAG,
+                fs: 0,
+                gs: 0,
+                fxsave_area,
+            }
+        }
+
+        /// Clears flags user code mustn't set, and makes sure interrupts are enabled.
+        pub fn sanitise_flags(&mut self) {
+            self.rflags = (self.rflags & Self::USER_FLAGS) | Self::INTERRUPT_FLAG | Self::RESERVED_FLAG;
+        }
+
+        pub fn instruction_address(&self) -> usize {
+            self.rip as usize
+        }
+
+        /// System call number, from `rax`.
+        pub fn syscall_number(&self) -> usize {
+            self.rax as usize
+        }
+
+        /// System call arguments, from `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`.
+        pub fn syscall_args(&self) -> [usize; 6] {
+            [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9].map(|arg| arg as usize)
+        }
+
+        /// Returns a system call's result in `rax`, with errors encoded as `-(error + 1)`.
+        pub fn set_syscall_result(&mut self, result: Result<usize, SyscallError>) {
+            self.rax = SyscallError::encode(result) as u64;
+        }
+    }
+
     #[derive(Clone, Copy)]
     #[repr(C)]
     pub struct KernelRegisterStore {
@@ -264,21 +413,36 @@ pub mod process {
 
 pub fn init_stage_1(_args: &kernel_args::Args) {
     unsafe {
+        // First, as page mappings depend on the features available
+        cpuid::generate_info();
+        cpuid::check_required_features();
+        protection::init();
+        tss::init_guarded_stacks().expect("allocating interrupt stacks failed");
         gdt::inject_tss_and_load();
+        syscall::init();
         tls::init();
         (*tls::get()).idt.load();
-        cpuid::generate_info();
     }
 }
 
 pub unsafe fn init_stage_2(args: &kernel_args::Args) {
     unsafe {
+        use crate::init_state::{self, Subsystem};
         use platform::acpi;
         // Initialise ACPI subsystem (ACPICA currently)
         acpi::init_subsystem(args.arch_ptrs.acpi_ptr).unwrap();
         log::debug!("Initialised ACPI subsystem");
         acpi::table::init_manager().expect("initialising ACPI tables failed");
         log::debug!("Initialised ACPI tables");
+        let acpi_dump = crate::cmdline::get().acpi_dump;
+        if acpi_dump != crate::cmdline::AcpiDump::Off {
+            acpi::dump::log_tables();
+            match acpi::dump::export_tables(acpi_dump) {
+                Ok(0) => {}
+                Ok(count) => log::info!("Wrote {count} ACPI tables to serial"),
+                Err(_) => log::warn!("Failed to write ACPI tables to serial"),
+            }
+        }
         // Initialise interrupts
         let madt = acpi::table::get::<acpi::table::Madt>().unwrap();
         log::debug!(
@@ -290,19 +454,50 @@ pub unsafe fn init_stage_2(args: &kernel_args::Args) {
             log::debug!("MADT entry - {entry:#X?}");
         }
         interrupts::apic::init_from_madt(madt);
+        interrupts::apic_errors::init();
+        interrupts::ipi::init();
         log::debug!("Initialised APIC from MADT");
+        let bsp_apic_id = (*tls::get()).local_apic.apic.as_ref().unwrap().id();
+        let mut cpu_topology = topology::Topology::from_madt(madt, bsp_apic_id);
+        // Drop secondary CPUs entirely, so nothing tries to bring them up or route to them
+        if !crate::cmdline::get().smp {
+            cpu_topology = topology::Topology::new(cpu_topology.online_cpus().copied().collect());
+        }
+        topology::publish(cpu_topology);
+        log::debug!(
+            "Found {} CPUs",
+            topology::current().map_or(0, |topology| topology.cpus().len()),
+        );
+        numa::init_from_acpi();
         // Setup APIC Timer
         {
-            use clock::{CALIBRATION_TIMERS, TIMERS};
-            clock::MANAGER
-                .lock()
-                .update_clock_functions(&CALIBRATION_TIMERS.lock(), &TIMERS.lock());
+            use clock::{CALIBRATION_TIMERS, COUNTERS, TIMERS};
+            init_state::begin(Subsystem::Clock);
+            clock::MANAGER.lock().update_clock_functions(
+                &CALIBRATION_TIMERS.lock(),
+                &TIMERS.lock(),
+                &COUNTERS.lock(),
+            );
             clock::apic::calibrate();
             clock::apic::setup();
-            clock::MANAGER
-                .lock()
-                .update_clock_functions(&CALIBRATION_TIMERS.lock(), &TIMERS.lock());
-            log::debug!("Initialised Local APIC Timer");
+            clock::tsc::calibrate();
+            clock::MANAGER.lock().update_clock_functions(
+                &CALIBRATION_TIMERS.lock(),
+                &TIMERS.lock(),
+                &COUNTERS.lock(),
+            );
+            let set_interrupt_type = clock::MANAGER.lock().timer.set_interrupt_type;
+            set_interrupt_type(&clock::InterruptType::Sleep);
+            clock::wall_clock::init();
+            init_state::finish(Subsystem::Clock);
+            log::debug!("Initialised Local APIC Timer and TSC");
         }
+        nmi::init(madt);
+        acpi::init_namespace().expect("initialising ACPI namespace failed");
+        log::debug!("Initialised ACPI namespace");
+        let pci_routes = acpi::prt::read_pci_routes();
+        log::debug!("Found {} PCI interrupt routes", pci_routes.len());
+        interrupts::routing::set_pci_routes(pci_routes);
+        interrupts::affinity::init();
     }
 }
@@ -0,0 +1,243 @@
+//! Message signalled interrupts (MSI and MSI-X) for PCI devices.
+//!
+//! Drivers allocate an interrupt vector with a handler closure, then point one of their device's
+//! MSI or MSI-X messages at it. Messages start out sent to the current CPU, and are moved between
+//! CPUs by `interrupts::affinity`.
+
+use super::interrupts::{self, affinity};
+use super::page_allocation;
+use super::paging::{PAGE_SIZE, PageTableEntry};
+use super::pci::{self, PciAddress};
+use super::tls;
+
+const MESSAGE_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+mod msi_register {
+    pub const CONTROL: u8 = 0x2;
+    pub const ADDRESS_LOW: u8 = 0x4;
+    pub const ADDRESS_HIGH: u8 = 0x8;
+    pub const DATA_32: u8 = 0x8;
+    pub const DATA_64: u8 = 0xC;
+
+    pub const CONTROL_ENABLE: u16 = 1 << 0;
+    pub const CONTROL_MULTIPLE_MESSAGE_ENABLE: u16 = 0x7 << 4;
+    pub const CONTROL_64_BIT: u16 = 1 << 7;
+}
+
+mod msix_register {
+    pub const CONTROL: u8 = 0x2;
+    pub const TABLE: u8 = 0x4;
+
+    pub const CONTROL_TABLE_SIZE: u16 = 0x7FF;
+    pub const CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+    pub const CONTROL_ENABLE: u16 = 1 << 15;
+
+    pub const TABLE_ENTRY_SIZE: u64 = 16;
+    pub const ENTRY_VECTOR_CONTROL_MASKED: u32 = 1 << 0;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MsiError {
+    #[error("device doesn't support MSI")]
+    NoMsiCapability,
+    #[error("device doesn't support MSI-X")]
+    NoMsiXCapability,
+    #[error("out of interrupt vectors")]
+    OutOfVectors,
+    #[error("MSI-X table index out of range")]
+    InvalidTableIndex,
+    #[error("MSI-X table is in an I/O BAR")]
+    UnsupportedBar,
+    #[error("out of memory when mapping MSI-X table")]
+    OutOfMemory,
+}
+
+/// An interrupt vector with a handler installed. The handler is unregistered on drop, so any
+/// device messages pointing at it must be disabled first.
+#[derive(Debug)]
+pub struct AllocatedVector {
+    vector: u8,
+}
+
+impl AllocatedVector {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// Returns the message address and data which raise this vector on the current CPU.
+    fn message(&self) -> (u64, u32) {
+        let local_apic_id = unsafe { (*tls::get()).local_apic.apic.as_ref().unwrap().id() };
+        // Fixed delivery, edge triggered
+        (message_address(local_apic_id), self.vector as u32)
+    }
+}
+
+/// Returns the message address which sends interrupts to the Local APIC with ID `local_apic_id`.
+fn message_address(local_apic_id: u8) -> u64 {
+    MESSAGE_ADDRESS_BASE | (local_apic_id as u64) << 12
+}
+
+impl Drop for AllocatedVector {
+    fn drop(&mut self) {
+        _ = interrupts::unregister_handler(self.vector);
+    }
+}
+
+/// Allocates a free interrupt vector which calls `handler` when raised.
+pub fn allocate_vector(
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<AllocatedVector, MsiError> {
+    let vector = interrupts::allocate_handler(handler).map_err(|_| MsiError::OutOfVectors)?;
+    Ok(AllocatedVector { vector })
+}
+
+fn disable_intx(device: PciAddress) {
+    let command = pci::read_word(device, pci::REGISTER_COMMAND);
+    unsafe {
+        pci::write_word(
+            device,
+            pci::REGISTER_COMMAND,
+            command | pci::COMMAND_INTX_DISABLE,
+        );
+    }
+}
+
+/// Points the device's single MSI message at `vector` and enables MSI, disabling INTx.
+pub unsafe fn enable_msi(device: PciAddress, vector: &AllocatedVector) -> Result<(), MsiError> {
+    use msi_register::*;
+    let capability =
+        pci::find_capability(device, pci::capability::MSI).ok_or(MsiError::NoMsiCapability)?;
+    let (address, data) = vector.message();
+    let control = pci::read_word(device, capability + CONTROL);
+    unsafe {
+        pci::write_dword(device, capability + ADDRESS_LOW, address as u32);
+        if control & CONTROL_64_BIT != 0 {
+            pci::write_dword(device, capability + ADDRESS_HIGH, (address >> 32) as u32);
+            pci::write_word(device, capability + DATA_64, data as u16);
+        } else {
+            pci::write_word(device, capability + DATA_32, data as u16);
+        }
+        let control = control & !CONTROL_MULTIPLE_MESSAGE_ENABLE | CONTROL_ENABLE;
+        pci::write_word(device, capability + CONTROL, control);
+    }
+    disable_intx(device);
+    affinity::register(vector.vector(), affinity::Source::Msi(device));
+    Ok(())
+}
+
+/// Sends the device's MSI message to the Local APIC with ID `local_apic_id`.
+pub unsafe fn set_msi_destination(device: PciAddress, local_apic_id: u8) {
+    use msi_register::*;
+    let Some(capability) = pci::find_capability(device, pci::capability::MSI) else {
+        return;
+    };
+    // The high half of the address stays 0
+    unsafe {
+        pci::write_dword(
+            device,
+            capability + ADDRESS_LOW,
+            message_address(local_apic_id) as u32,
+        );
+    }
+}
+
+pub unsafe fn disable_msi(device: PciAddress) -> Result<(), MsiError> {
+    use msi_register::*;
+    let capability =
+        pci::find_capability(device, pci::capability::MSI).ok_or(MsiError::NoMsiCapability)?;
+    let control = pci::read_word(device, capability + CONTROL);
+    unsafe {
+        pci::write_word(device, capability + CONTROL, control & !CONTROL_ENABLE);
+    }
+    Ok(())
+}
+
+/// Returns the number of entries in the device's MSI-X table.
+pub fn msix_table_size(device: PciAddress) -> Result<u16, MsiError> {
+    use msix_register::*;
+    let capability =
+        pci::find_capability(device, pci::capability::MSI_X).ok_or(MsiError::NoMsiXCapability)?;
+    Ok((pci::read_word(device, capability + CONTROL) & CONTROL_TABLE_SIZE) + 1)
+}
+
+/// Points entry `table_index` of the device's MSI-X table at `vector`, unmasks it and enables
+/// MSI-X, disabling INTx.
+pub unsafe fn enable_msix(
+    device: PciAddress,
+    table_index: u16,
+    vector: &AllocatedVector,
+) -> Result<(), MsiError> {
+    use msix_register::*;
+    let capability =
+        pci::find_capability(device, pci::capability::MSI_X).ok_or(MsiError::NoMsiXCapability)?;
+    let control = pci::read_word(device, capability + CONTROL);
+    if table_index > control & CONTROL_TABLE_SIZE {
+        return Err(MsiError::InvalidTableIndex);
+    }
+    let table = pci::read_dword(device, capability + TABLE);
+    let bar_address =
+        pci::memory_bar_address(device, (table & 0x7) as u8).ok_or(MsiError::UnsupportedBar)?;
+    let entry_address = bar_address + (table & !0x7) as u64 + table_index as u64 * TABLE_ENTRY_SIZE;
+    unsafe {
+        let page_address = entry_address as usize & !(PAGE_SIZE - 1);
+        if !page_allocation::is_address_identity_mapped(page_address) {
+            page_allocation::map_page_translation(
+                page_address,
+                page_address,
+                PageTableEntry::READ_WRITE,
+            )
+            .map_err(|_| MsiError::OutOfMemory)?;
+        }
+        // Mask the whole function while the entry is half written
+        pci::write_word(
+            device,
+            capability + CONTROL,
+            control | CONTROL_ENABLE | CONTROL_FUNCTION_MASK,
+        );
+        let (address, data) = vector.message();
+        let entry = entry_address as *mut u32;
+        entry.write_volatile(address as u32);
+        entry.add(1).write_volatile((address >> 32) as u32);
+        entry.add(2).write_volatile(data);
+        // Vector control, clearing the mask bit
+        entry.add(3).write_volatile(0);
+        pci::write_word(
+            device,
+            capability + CONTROL,
+            (control | CONTROL_ENABLE) & !CONTROL_FUNCTION_MASK,
+        );
+    }
+    disable_intx(device);
+    affinity::register(
+        vector.vector(),
+        affinity::Source::MsiX(entry_address as usize),
+    );
+    Ok(())
+}
+
+/// Sends the MSI-X message in the table entry at `entry_address`, as set up by `enable_msix`, to
+/// the Local APIC with ID `local_apic_id`.
+pub unsafe fn set_msix_destination(entry_address: usize, local_apic_id: u8) {
+    use msix_register::*;
+    let entry = entry_address as *mut u32;
+    unsafe {
+        // Masked while the address is changed, so no message is sent half written
+        let vector_control = entry.add(3).read_volatile();
+        entry
+            .add(3)
+            .write_volatile(vector_control | ENTRY_VECTOR_CONTROL_MASKED);
+        entry.write_volatile(message_address(local_apic_id) as u32);
+        entry.add(3).write_volatile(vector_control);
+    }
+}
+
+pub unsafe fn disable_msix(device: PciAddress) -> Result<(), MsiError> {
+    use msix_register::*;
+    let capability =
+        pci::find_capability(device, pci::capability::MSI_X).ok_or(MsiError::NoMsiXCapability)?;
+    let control = pci::read_word(device, capability + CONTROL);
+    unsafe {
+        pci::write_word(device, capability + CONTROL, control & !CONTROL_ENABLE);
+    }
+    Ok(())
+}
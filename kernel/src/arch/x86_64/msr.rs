@@ -0,0 +1,304 @@
+//! Model specific registers.
+//!
+//! Registers the kernel uses are unit structs implementing `Msr`, which gives them `read`, `write`
+//! and read-modify-write `update` functions in terms of a typed value. Registers only present with
+//! a CPU feature check for it once CPUID has been read, so a missing MSR panics with its name
+//! rather than raising a general protection fault. Other registers can be accessed by index with
+//! `read` and `write`.
+
+use super::cpuid::{self, Feature};
+use core::arch::asm;
+
+/// Reads a value from the given MSR.
+#[inline]
+pub unsafe fn read(index: u32) -> u64 {
+    unsafe {
+        let value;
+        asm!(
+            "rdmsr",
+            "shl rdx, 32",
+            "or rdx, rax",
+            in("ecx") index,
+            out("rdx") value,
+            out("eax") _,
+            options(nomem, nostack),
+        );
+        value
+    }
+}
+
+/// Writes a value to the given MSR.
+#[inline]
+pub unsafe fn write(index: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") index,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+/// A model specific register, holding a `Value`.
+pub trait Msr {
+    const INDEX: u32;
+    const NAME: &'static str;
+    /// Feature the CPU reports if it has the register, `None` if every x86_64 CPU does.
+    const FEATURE: Option<Feature>;
+
+    type Value: Copy;
+
+    fn from_raw(raw: u64) -> Self::Value;
+    fn to_raw(value: Self::Value) -> u64;
+
+    /// Returns whether the CPU has the register. Assumed to be true until CPUID has been read.
+    fn is_supported() -> bool {
+        match (Self::FEATURE, cpuid::try_get_info()) {
+            (Some(feature), Some(info)) => info.has(feature),
+            _ => true,
+        }
+    }
+
+    /// Reads the register, panicking if the CPU doesn't have it.
+    fn read() -> Self::Value {
+        assert!(Self::is_supported(), "CPU has no {} MSR", Self::NAME);
+        Self::from_raw(unsafe { read(Self::INDEX) })
+    }
+
+    /// Writes the register, panicking if the CPU doesn't have it.
+    ///
+    /// # Safety
+    ///
+    /// The value must be valid for the register, and mustn't break anything relying on its current
+    /// value.
+    unsafe fn write(value: Self::Value) {
+        assert!(Self::is_supported(), "CPU has no {} MSR", Self::NAME);
+        unsafe { write(Self::INDEX, Self::to_raw(value)) };
+    }
+
+    /// Reads the register, lets `f` modify its value, then writes it back.
+    ///
+    /// # Safety
+    ///
+    /// Same as `write`.
+    unsafe fn update(f: impl FnOnce(&mut Self::Value)) {
+        let mut value = Self::read();
+        f(&mut value);
+        unsafe { Self::write(value) };
+    }
+}
+
+/// Defines MSRs holding a plain `u64`.
+macro_rules! define_raw_msrs {
+    ($($(#[$attr:meta])* $name:ident = ($index:expr, $feature:expr);)*) => {
+        $(
+            $(#[$attr])*
+            pub struct $name;
+
+            impl Msr for $name {
+                const INDEX: u32 = $index;
+                const NAME: &'static str = stringify!($name);
+                const FEATURE: Option<Feature> = $feature;
+
+                type Value = u64;
+
+                fn from_raw(raw: u64) -> u64 {
+                    raw
+                }
+
+                fn to_raw(value: u64) -> u64 {
+                    value
+                }
+            }
+        )*
+    };
+}
+
+define_raw_msrs! {
+    /// Segment selectors loaded by `syscall` and `sysret`, in bits 32-47 and 48-63.
+    Star = (0xC000_0081, Some(Feature::Syscall));
+    /// Address jumped to by `syscall` from 64 bit code.
+    Lstar = (0xC000_0082, Some(Feature::Syscall));
+    /// Address jumped to by `syscall` from compatibility mode.
+    Cstar = (0xC000_0083, Some(Feature::Syscall));
+    /// RFLAGS bits cleared by `syscall`.
+    Fmask = (0xC000_0084, Some(Feature::Syscall));
+    FsBase = (0xC000_0100, None);
+    GsBase = (0xC000_0101, None);
+    /// Swapped with `GsBase` by `swapgs`.
+    KernelGsBase = (0xC000_0102, None);
+    /// Event selected by the first general purpose performance counter. Only present with
+    /// architectural performance monitoring, see `cpuid::PerfmonInfo`.
+    PerfEvtSel0 = (0x186, None);
+    /// First general purpose performance counter. Writes only set the low 32 bits, sign
+    /// extending them to the counter's width.
+    Pmc0 = (0xC1, None);
+    /// Event selected by the second general purpose performance counter.
+    PerfEvtSel1 = (0x187, None);
+    /// Second general purpose performance counter, written like `Pmc0`.
+    Pmc1 = (0xC2, None);
+    /// Clears performance counter overflow flags. Only present from performance monitoring
+    /// version 2.
+    PerfGlobalOvfCtrl = (0x390, None);
+}
+
+bitflags::bitflags! {
+    /// Extended feature enable register flags.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct EferFlags: u64 {
+        const SYSCALL_ENABLE = 1 << 0;
+        const LONG_MODE_ENABLE = 1 << 8;
+        /// Read-only, set by the CPU once paging is enabled in long mode.
+        const LONG_MODE_ACTIVE = 1 << 10;
+        const NO_EXECUTE_ENABLE = 1 << 11;
+        const SECURE_VIRTUAL_MACHINE_ENABLE = 1 << 12;
+        const LONG_MODE_SEGMENT_LIMIT_ENABLE = 1 << 13;
+        const FAST_FXSAVE_FXRSTOR = 1 << 14;
+        const TRANSLATION_CACHE_EXTENSION = 1 << 15;
+    }
+}
+
+pub struct Efer;
+
+impl Msr for Efer {
+    const INDEX: u32 = 0xC000_0080;
+    const NAME: &'static str = "EFER";
+    const FEATURE: Option<Feature> = None;
+
+    type Value = EferFlags;
+
+    fn from_raw(raw: u64) -> EferFlags {
+        EferFlags::from_bits_retain(raw)
+    }
+
+    fn to_raw(value: EferFlags) -> u64 {
+        value.bits()
+    }
+}
+
+/// Local APIC base address and enable flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApicBaseValue(u64);
+
+impl ApicBaseValue {
+    const BOOTSTRAP_PROCESSOR: u64 = 1 << 8;
+    const X2APIC_ENABLE: u64 = 1 << 10;
+    const GLOBAL_ENABLE: u64 = 1 << 11;
+    const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+    /// Returns the physical address of the local APIC's registers.
+    pub fn address(&self) -> u64 {
+        self.0 & Self::ADDRESS_MASK
+    }
+
+    /// Returns whether this is the bootstrap processor.
+    pub fn is_bootstrap_processor(&self) -> bool {
+        self.0 & Self::BOOTSTRAP_PROCESSOR != 0
+    }
+
+    pub fn is_x2apic_enabled(&self) -> bool {
+        self.0 & Self::X2APIC_ENABLE != 0
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0 & Self::GLOBAL_ENABLE != 0
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.0 = match enabled {
+            true => self.0 | Self::GLOBAL_ENABLE,
+            false => self.0 & !Self::GLOBAL_ENABLE,
+        };
+    }
+}
+
+pub struct ApicBase;
+
+impl Msr for ApicBase {
+    const INDEX: u32 = 0x1B;
+    const NAME: &'static str = "IA32_APIC_BASE";
+    const FEATURE: Option<Feature> = Some(Feature::Apic);
+
+    type Value = ApicBaseValue;
+
+    fn from_raw(raw: u64) -> ApicBaseValue {
+        ApicBaseValue(raw)
+    }
+
+    fn to_raw(value: ApicBaseValue) -> u64 {
+        value.0
+    }
+}
+
+/// Memory types which can be selected by PAT entries.
+pub mod memory_type {
+    pub const UNCACHEABLE: u8 = 0x0;
+    pub const WRITE_COMBINING: u8 = 0x1;
+    pub const WRITE_THROUGH: u8 = 0x4;
+    pub const WRITE_PROTECTED: u8 = 0x5;
+    pub const WRITE_BACK: u8 = 0x6;
+    /// Uncacheable, but can be overridden by write combining MTRRs.
+    pub const UNCACHED: u8 = 0x7;
+}
+
+/// Page attribute table, with the memory type of each of its 8 entries. Entries are selected by
+/// the PAT, PCD and PWT bits of a page table entry, in that order.
+pub struct Pat;
+
+impl Msr for Pat {
+    const INDEX: u32 = 0x277;
+    const NAME: &'static str = "IA32_PAT";
+    const FEATURE: Option<Feature> = Some(Feature::Pat);
+
+    type Value = [u8; 8];
+
+    fn from_raw(raw: u64) -> [u8; 8] {
+        raw.to_le_bytes()
+    }
+
+    fn to_raw(value: [u8; 8]) -> u64 {
+        u64::from_le_bytes(value)
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    #[kernel_test]
+    fn writes_reach_the_register() -> TestResult {
+        // Only swapped in by `swapgs`, which the kernel doesn't use
+        let original = KernelGsBase::read();
+        let value = 0xFFFF_8000_1234_5000;
+        let read_back = unsafe {
+            KernelGsBase::write(value);
+            let read_back = KernelGsBase::read();
+            KernelGsBase::write(original);
+            read_back
+        };
+        ktest_assert_eq!(read_back, value);
+        ktest_assert_eq!(KernelGsBase::read(), original);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn updates_efer_bits() -> TestResult {
+        let original = Efer::read();
+        ktest_assert!(original.contains(EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE));
+        ktest_assert!(original.contains(EferFlags::SYSCALL_ENABLE));
+        // Nothing makes system calls while the test runs, so `syscall` can be briefly disabled
+        let cleared = unsafe {
+            Efer::update(|flags| flags.remove(EferFlags::SYSCALL_ENABLE));
+            let cleared = Efer::read();
+            Efer::update(|flags| flags.insert(EferFlags::SYSCALL_ENABLE));
+            cleared
+        };
+        ktest_assert!(!cleared.contains(EferFlags::SYSCALL_ENABLE));
+        ktest_assert_eq!(cleared | EferFlags::SYSCALL_ENABLE, original);
+        ktest_assert_eq!(Efer::read(), original);
+        Ok(())
+    }
+}
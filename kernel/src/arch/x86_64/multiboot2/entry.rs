@@ -0,0 +1,259 @@
+use super::{Info, tag_type};
+use crate::arch::boot;
+use crate::arch::kernel_args;
+use crate::arch::page_allocation;
+use crate::arch::paging::PAGE_SIZE;
+use crate::logging;
+use crate::physical_block_allocator::{MaxCapacity, PageBox, PageVec, PhysicalBlockAllocator};
+use core::arch::{asm, global_asm};
+
+global_asm!(include_str!("entry.s"), options(raw));
+
+/// Where `entry.s` maps the lower 4 GiB of physical memory in the higher half, used for pointers
+/// to bootloader data so they stay valid once the lower half belongs to processes.
+const PHYSICAL_MAPPING_OFFSET: usize = 0xFFFF_8000_0000_0000;
+/// Amount of physical memory mapped by `entry.s`. Memory above this can't be used, as the page
+/// allocator accesses pages through the identity mapping.
+const MAPPED_PHYSICAL_MEMORY: usize = 4 << 30;
+/// Memory below this is left alone, as firmware structures live there.
+const LOW_MEMORY_END: usize = 0x100000;
+/// String given to the module holding the kernel's symbol table in `grub.cfg`. Any other module
+/// is taken as the initrd.
+const SYMBOLS_MODULE_STRING: &[u8] = b"symbols";
+
+unsafe extern "C" {
+    unsafe fn init64(kernel_args_ptr: core::ptr::NonNull<kernel_args::Args>) -> !;
+    static KERNEL_BASE: u8;
+    static KERNEL_VMA_OFFSET: u8;
+    static KERNEL_IMAGE_END: u8;
+}
+
+/// Returns the physical range of memory which must not be given to the page allocator.
+fn reserved_ranges(info: Info) -> impl Iterator<Item = (usize, usize)> + Clone {
+    let (kernel_start, kernel_end) = unsafe {
+        let vma_offset = &KERNEL_VMA_OFFSET as *const u8 as usize;
+        (
+            (&KERNEL_BASE as *const u8 as usize).wrapping_sub(vma_offset),
+            (&KERNEL_IMAGE_END as *const u8 as usize).wrapping_sub(vma_offset),
+        )
+    };
+    let modules = info
+        .tags()
+        .filter(|tag| tag.tag_type == tag_type::MODULE)
+        .filter_map(|tag| tag.module());
+    [
+        (0, LOW_MEMORY_END),
+        (kernel_start, kernel_end),
+        (info.address(), info.address() + info.len()),
+    ]
+    .into_iter()
+    .chain(modules)
+}
+
+/// Finds `size` bytes of page aligned usable memory which doesn't overlap any reserved ranges.
+fn find_free_range(
+    usable: impl Iterator<Item = (usize, usize)>,
+    reserved: impl Iterator<Item = (usize, usize)> + Clone,
+    size: usize,
+) -> Option<usize> {
+    usable.into_iter().find_map(|(start, end)| {
+        let mut candidate = start.next_multiple_of(PAGE_SIZE);
+        // Move past reserved ranges until nothing overlaps
+        while let Some((_, reserved_end)) =
+            reserved.clone().find(|&(reserved_start, reserved_end)| {
+                candidate < reserved_end && reserved_start < candidate + size
+            })
+        {
+            candidate = reserved_end.next_multiple_of(PAGE_SIZE);
+        }
+        (candidate + size <= end).then_some(candidate)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn multiboot2_entry(info_address: usize) -> ! {
+    unsafe {
+        // Enable SSE
+        asm!(
+            "mov rax, cr0",
+            "and ax, 0xFFFB",
+            "or ax, 0x2",
+            "mov cr0, rax",
+            "mov rax, cr4",
+            "or ax, (3 << 9)",
+            "mov cr4, rax",
+            out("rax") _,
+        );
+        crate::arch::debug_output::init_writers();
+        logging::init_wrapper();
+        _ = logging::CURRENT_LOGGER.write().replace(&boot::LOGGER);
+        let info = Info::from_address(info_address);
+        // Get memory map from bootloader, only keeping whole pages of usable memory
+        let memory_map = info
+            .find(tag_type::MEMORY_MAP)
+            .unwrap_or_else(|| {
+                panic!("bootloader didn't provide a memory map");
+            })
+            .memory_map_entries();
+        let usable_ranges = memory_map
+            .clone()
+            .filter(|entry| entry.entry_type == super::MEMORY_AVAILABLE)
+            .map(|entry| {
+                let start = entry.base.next_multiple_of(PAGE_SIZE);
+                let end =
+                    ((entry.base + entry.length) & !(PAGE_SIZE - 1)).min(MAPPED_PHYSICAL_MEMORY);
+                (start, end.max(start))
+            });
+        let mappable_bytes = memory_map
+            .clone()
+            .fold(0, |acc, entry| {
+                core::cmp::max(acc, entry.base + entry.length)
+            })
+            .min(MAPPED_PHYSICAL_MEMORY);
+        // Generate kernel memory bitmap, initialise page allocator
+        {
+            let memory_map_page_size = boot::memory_bitmap_size(mappable_bytes);
+            let bitmap_address = find_free_range(
+                usable_ranges.clone(),
+                reserved_ranges(info),
+                memory_map_page_size,
+            )
+            .unwrap_or_else(|| {
+                panic!("not enough contiguous memory for memory bitmap");
+            });
+            let kernel_bitmap =
+                core::slice::from_raw_parts_mut(bitmap_address as *mut u8, memory_map_page_size);
+            // Set memory to all used, then free usable memory that isn't reserved
+            kernel_bitmap.fill(0xFF);
+            for (start, end) in usable_ranges {
+                boot::mark_memory_range(kernel_bitmap, start, end - start, false);
+            }
+            for (start, end) in reserved_ranges(info) {
+                boot::mark_memory_range(kernel_bitmap, start, end.saturating_sub(start), true);
+            }
+            boot::mark_memory_range(kernel_bitmap, bitmap_address, memory_map_page_size, true);
+            log::debug!(
+                "Allocated kernel memory bitmap - ptr: {:p}, len: {:#x}",
+                kernel_bitmap,
+                kernel_bitmap.len(),
+            );
+            let page_table_address = {
+                let mut address: usize;
+                asm!("mov {}, cr3", out(reg) address, options(nomem, nostack));
+                address
+            };
+            page_allocation::init(page_table_address, kernel_bitmap, mappable_bytes / 4096);
+        }
+        // Allocate framebuffers
+        let framebuffers = match info
+            .find(tag_type::FRAMEBUFFER)
+            .and_then(|tag| tag.framebuffer())
+        {
+            Some(fb) if fb.framebuffer_type != super::FRAMEBUFFER_TYPE_RGB => {
+                log::info!("Bootloader provided a text mode framebuffer, ignoring");
+                kernel_args::Slice::null()
+            }
+            Some(fb) if fb.address + (fb.pitch * fb.height) as usize > MAPPED_PHYSICAL_MEMORY => {
+                log::warn!("Skipping framebuffer above 4 GiB at {:#x}", fb.address);
+                kernel_args::Slice::null()
+            }
+            Some(fb) => {
+                let color_format = boot::color_format(
+                    boot::channel_mask(fb.red_size, fb.red_position),
+                    boot::channel_mask(fb.green_size, fb.green_position),
+                    boot::channel_mask(fb.blue_size, fb.blue_position),
+                );
+                let mut framebuffers = PageVec::new_with_max_capacity();
+                framebuffers.push(kernel_args::Framebuffer {
+                    ptr: core::ptr::NonNull::new((PHYSICAL_MAPPING_OFFSET + fb.address) as *mut u8)
+                        .unwrap(),
+                    ptr_type: kernel_args::PtrType::Linear,
+                    size: fb.pitch * fb.height,
+                    width: fb.width,
+                    height: fb.height,
+                    pitch: fb.pitch,
+                    bits_per_pixel: fb.bpp as u32,
+                    color_format,
+                });
+                let framebuffers_slice = framebuffers.leak();
+                kernel_args::Slice {
+                    ptr: framebuffers_slice.as_ptr(),
+                    len: framebuffers_slice.len(),
+                }
+            }
+            None => {
+                log::info!("Bootloader provided no framebuffers");
+                kernel_args::Slice::null()
+            }
+        };
+        // Get initrd and symbol table files
+        let mut modules = info.tags().filter(|tag| tag.tag_type == tag_type::MODULE);
+        let Some((initrd_start, initrd_end)) = modules
+            .clone()
+            .find(|tag| tag.module_string() != SYMBOLS_MODULE_STRING)
+            .and_then(|tag| tag.module())
+        else {
+            panic!("no initrd module was provided to the kernel");
+        };
+        let kernel_symbols = match modules
+            .find(|tag| tag.module_string() == SYMBOLS_MODULE_STRING)
+            .and_then(|tag| tag.module())
+        {
+            Some((start, end)) => kernel_args::Slice {
+                ptr: (PHYSICAL_MAPPING_OFFSET + start) as *const u8,
+                len: end - start,
+            },
+            None => {
+                log::info!("Bootloader provided no symbol table module");
+                kernel_args::Slice::null()
+            }
+        };
+        // Kernel command line, passed on as the environment
+        let environment = match info.find(tag_type::COMMAND_LINE) {
+            Some(tag) => kernel_args::Slice {
+                ptr: (PHYSICAL_MAPPING_OFFSET + tag.command_line().as_ptr() as usize) as *const u8,
+                len: tag.command_line().len(),
+            },
+            None => kernel_args::Slice::null(),
+        };
+        // Get architecture pointers
+        let efi_ptr = info
+            .find(tag_type::EFI64_SYSTEM_TABLE)
+            .and_then(|tag| tag.efi64_system_table())
+            .unwrap_or(0);
+        // Both tags contain a copy of the RSDP, preferring the newer one
+        let acpi_ptr = info
+            .find(tag_type::ACPI_NEW_RSDP)
+            .or_else(|| info.find(tag_type::ACPI_OLD_RSDP))
+            .and_then(|tag| core::ptr::NonNull::new(tag.data.as_ptr() as *mut ()));
+        // Write kernel arguments
+        let kernel_args_ptr = PageBox::new_in(
+            kernel_args::Args {
+                // Only the section headers are available, not the whole file
+                kernel_elf: kernel_args::Slice::null(),
+                kernel_symbols,
+                page_table_address: page_allocation::page_table_address(),
+                environment,
+                memory_bitmap: kernel_args::MemoryBitmap {
+                    slice: page_allocation::memory_bitmap(),
+                    mapped_size: mappable_bytes,
+                },
+                initrd: kernel_args::Slice {
+                    ptr: (PHYSICAL_MAPPING_OFFSET + initrd_start) as *const u8,
+                    len: initrd_end - initrd_start,
+                },
+                arch_ptrs: kernel_args::ArchPointers {
+                    efi_ptr,
+                    acpi_ptr,
+                    smbi_ptr: 0,
+                    mp_ptr: 0,
+                },
+                framebuffers,
+            },
+            PhysicalBlockAllocator,
+        );
+        page_allocation::deinit_and_remove().unwrap();
+        // Call kernel init64 entry point
+        init64(PageBox::leak(kernel_args_ptr).into());
+    }
+}
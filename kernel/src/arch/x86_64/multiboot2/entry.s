@@ -0,0 +1,183 @@
+// Multiboot2 header and 32 bit entry point. The bootloader enters in 32 bit protected mode with
+// paging disabled, so this builds bootstrap page tables, switches to long mode and jumps to the
+// higher half before calling `multiboot2_entry`.
+//
+// Until paging is enabled, code runs at the physical load address, so every absolute address is
+// adjusted by the difference between the kernel's virtual and physical addresses.
+
+// Must match `KERNEL_VMA_OFFSET` in the linker script
+.set KERNEL_VMA_OFFSET, 0xFFFFFFFF7FE00000
+.set KERNEL_LOAD_ADDRESS, 0x200000
+
+.set MULTIBOOT2_HEADER_MAGIC, 0xE85250D6
+.set MULTIBOOT2_BOOTLOADER_MAGIC, 0x36D76289
+.set MULTIBOOT2_ARCHITECTURE_I386, 0
+
+.set PAGE_PRESENT_WRITABLE, 0x3
+.set PAGE_PRESENT_WRITABLE_HUGE, 0x83
+.set HUGE_PAGE_SIZE, 0x200000
+
+// -- Multiboot2 Header --
+.section ".multiboot2_header", "a"
+.align 8
+multiboot2_header:
+    .long MULTIBOOT2_HEADER_MAGIC
+    .long MULTIBOOT2_ARCHITECTURE_I386
+    .long multiboot2_header_end - multiboot2_header
+    .long 0x100000000 - (MULTIBOOT2_HEADER_MAGIC + MULTIBOOT2_ARCHITECTURE_I386 + (multiboot2_header_end - multiboot2_header))
+    // Entry address, as the ELF entry point is in the higher half
+    .align 8
+    .word 3
+    .word 0
+    .long 12
+    .long multiboot2_entry32 - KERNEL_VMA_OFFSET
+    // Framebuffer, optional, preferring 32 bits per pixel
+    .align 8
+    .word 5
+    .word 1
+    .long 20
+    .long 0
+    .long 0
+    .long 32
+    // Page align modules, so the initrd can be handed to the page allocator later
+    .align 8
+    .word 6
+    .word 0
+    .long 8
+    // End
+    .align 8
+    .word 0
+    .word 0
+    .long 8
+multiboot2_header_end:
+
+// -- Bootstrap Page Tables and Stack --
+// Zeroed by the bootloader. Lower 4 GiB are identity mapped, and also mapped at
+// 0xFFFF800000000000 for pointers to bootloader data. The top 1 GiB maps the kernel image.
+.section ".bss.multiboot2", "aw", @nobits
+.align 4096
+MULTIBOOT2_PML4:
+    .skip 4096
+MULTIBOOT2_PDPT_LOW:
+    .skip 4096
+MULTIBOOT2_PDPT_HIGH:
+    .skip 4096
+MULTIBOOT2_PD_LOW:
+    .skip 4096 * 4
+MULTIBOOT2_PD_HIGH:
+    .skip 4096
+MULTIBOOT2_STACK:
+    .skip 0x10000
+MULTIBOOT2_STACK_TOP:
+
+// -- 32 Bit Entry --
+.section ".init.multiboot2", "ax"
+.align 16
+MULTIBOOT2_GDT:
+    .quad 0
+    // 64 bit code
+    .quad 0x00AF9A000000FFFF
+    // Data
+    .quad 0x00CF92000000FFFF
+MULTIBOOT2_GDT_POINTER:
+    .word MULTIBOOT2_GDT_POINTER - MULTIBOOT2_GDT - 1
+    .long MULTIBOOT2_GDT - KERNEL_VMA_OFFSET
+
+.code32
+.global multiboot2_entry32
+.type multiboot2_entry32, @function
+multiboot2_entry32:
+    cli
+    cld
+    cmp eax, MULTIBOOT2_BOOTLOADER_MAGIC
+    jne .Lhalt32
+    // Boot information address, passed through to `multiboot2_entry`
+    mov esi, ebx
+
+    // -- Page table setup --
+    mov eax, offset MULTIBOOT2_PDPT_LOW - KERNEL_VMA_OFFSET + PAGE_PRESENT_WRITABLE
+    mov [MULTIBOOT2_PML4 - KERNEL_VMA_OFFSET], eax
+    mov [MULTIBOOT2_PML4 - KERNEL_VMA_OFFSET + 256 * 8], eax
+    mov eax, offset MULTIBOOT2_PDPT_HIGH - KERNEL_VMA_OFFSET + PAGE_PRESENT_WRITABLE
+    mov [MULTIBOOT2_PML4 - KERNEL_VMA_OFFSET + 511 * 8], eax
+    // Point the low PDPT at the 4 low page directories
+    mov eax, offset MULTIBOOT2_PD_LOW - KERNEL_VMA_OFFSET + PAGE_PRESENT_WRITABLE
+    xor ecx, ecx
+.Lfill_pdpt_low:
+    mov [MULTIBOOT2_PDPT_LOW - KERNEL_VMA_OFFSET + ecx * 8], eax
+    add eax, 4096
+    inc ecx
+    cmp ecx, 4
+    jne .Lfill_pdpt_low
+    // Identity map the lower 4 GiB with huge pages
+    mov eax, PAGE_PRESENT_WRITABLE_HUGE
+    xor ecx, ecx
+.Lfill_pd_low:
+    mov [MULTIBOOT2_PD_LOW - KERNEL_VMA_OFFSET + ecx * 8], eax
+    add eax, HUGE_PAGE_SIZE
+    inc ecx
+    cmp ecx, 512 * 4
+    jne .Lfill_pd_low
+    // Map the kernel image at the start of the top 1 GiB
+    mov eax, offset MULTIBOOT2_PD_HIGH - KERNEL_VMA_OFFSET + PAGE_PRESENT_WRITABLE
+    mov [MULTIBOOT2_PDPT_HIGH - KERNEL_VMA_OFFSET + 510 * 8], eax
+    mov eax, KERNEL_LOAD_ADDRESS + PAGE_PRESENT_WRITABLE_HUGE
+    xor ecx, ecx
+.Lfill_pd_high:
+    mov [MULTIBOOT2_PD_HIGH - KERNEL_VMA_OFFSET + ecx * 8], eax
+    add eax, HUGE_PAGE_SIZE
+    inc ecx
+    cmp ecx, 512
+    jne .Lfill_pd_high
+
+    // -- Long mode switch --
+    // Enable PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, offset MULTIBOOT2_PML4 - KERNEL_VMA_OFFSET
+    mov cr3, eax
+    // Enable long mode
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 1 << 8
+    wrmsr
+    // Enable paging and protected mode
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+    lgdt [MULTIBOOT2_GDT_POINTER - KERNEL_VMA_OFFSET]
+    push 8
+    mov eax, offset multiboot2_entry64_low - KERNEL_VMA_OFFSET
+    push eax
+    retf
+.Lhalt32:
+    hlt
+    jmp .Lhalt32
+
+.code64
+multiboot2_entry64_low:
+    // Still running at the physical address, jump to the higher half
+    mov rax, offset multiboot2_entry64
+    jmp rax
+multiboot2_entry64:
+    mov ax, 16
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, offset MULTIBOOT2_STACK_TOP
+    xor rbp, rbp
+    // Upper halves of registers are undefined after the switch
+    mov edi, esi
+    mov rax, offset multiboot2_entry
+    call rax
+.Lhalt64:
+    hlt
+    jmp .Lhalt64
+
+.size multiboot2_entry32, . - multiboot2_entry32
+
+// Revert back to default section, as this get inlined in Rust code
+.text
@@ -0,0 +1,194 @@
+//! Multiboot2 boot information parsing, for loading 9x with GRUB and other Multiboot2
+//! bootloaders.
+
+pub mod entry;
+
+pub const BOOTLOADER_MAGIC: u32 = 0x36D76289;
+
+pub mod tag_type {
+    pub const END: u32 = 0;
+    pub const COMMAND_LINE: u32 = 1;
+    pub const MODULE: u32 = 3;
+    pub const MEMORY_MAP: u32 = 6;
+    pub const FRAMEBUFFER: u32 = 8;
+    pub const EFI64_SYSTEM_TABLE: u32 = 12;
+    pub const ACPI_OLD_RSDP: u32 = 14;
+    pub const ACPI_NEW_RSDP: u32 = 15;
+}
+
+/// Memory map entry type for RAM which is free to use.
+pub const MEMORY_AVAILABLE: u32 = 1;
+/// Framebuffer type for direct RGB color.
+pub const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a NULL terminated string, without the NULL byte.
+fn read_cstr(bytes: &[u8]) -> &[u8] {
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// The boot information structure passed by the bootloader.
+#[derive(Clone, Copy)]
+pub struct Info {
+    bytes: &'static [u8],
+}
+
+impl Info {
+    /// Creates the boot information from its address, which must be mapped.
+    pub unsafe fn from_address(address: usize) -> Self {
+        unsafe {
+            let total_size = (address as *const u32).read();
+            Self {
+                bytes: core::slice::from_raw_parts(address as *const u8, total_size as usize),
+            }
+        }
+    }
+
+    pub fn address(&self) -> usize {
+        self.bytes.as_ptr() as usize
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn tags(&self) -> TagIter {
+        TagIter {
+            bytes: self.bytes,
+            offset: 8,
+        }
+    }
+
+    pub fn find(&self, tag_type: u32) -> Option<Tag> {
+        self.tags().find(|tag| tag.tag_type == tag_type)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Tag {
+    pub tag_type: u32,
+    /// Tag contents, after the type and size.
+    pub data: &'static [u8],
+}
+
+impl Tag {
+    /// Returns the string of a command line tag.
+    pub fn command_line(&self) -> &'static [u8] {
+        read_cstr(self.data)
+    }
+
+    /// Returns the physical start and end addresses of a module tag's contents.
+    pub fn module(&self) -> Option<(usize, usize)> {
+        Some((
+            read_u32(self.data, 0)? as usize,
+            read_u32(self.data, 4)? as usize,
+        ))
+    }
+
+    /// Returns the string of a module tag, given after the module's path in the bootloader's
+    /// configuration.
+    pub fn module_string(&self) -> &'static [u8] {
+        read_cstr(self.data.get(8..).unwrap_or(&[]))
+    }
+
+    pub fn memory_map_entries(&self) -> impl Iterator<Item = MemoryMapEntry> + Clone + use<> {
+        let entry_size = read_u32(self.data, 0).unwrap_or(0) as usize;
+        let entries = self.data.get(8..).unwrap_or(&[]);
+        entries
+            .chunks_exact(entry_size.max(24))
+            .filter_map(|entry| {
+                Some(MemoryMapEntry {
+                    base: read_u64(entry, 0)? as usize,
+                    length: read_u64(entry, 8)? as usize,
+                    entry_type: read_u32(entry, 16)?,
+                })
+            })
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        let data = self.data;
+        Some(Framebuffer {
+            address: read_u64(data, 0)? as usize,
+            pitch: read_u32(data, 8)?,
+            width: read_u32(data, 12)?,
+            height: read_u32(data, 16)?,
+            bpp: *data.get(20)?,
+            framebuffer_type: *data.get(21)?,
+            red_position: *data.get(24)?,
+            red_size: *data.get(25)?,
+            green_position: *data.get(26)?,
+            green_size: *data.get(27)?,
+            blue_position: *data.get(28)?,
+            blue_size: *data.get(29)?,
+        })
+    }
+
+    /// Returns the address of the pointer in an EFI system table tag.
+    pub fn efi64_system_table(&self) -> Option<usize> {
+        Some(read_u64(self.data, 0)? as usize)
+    }
+}
+
+#[derive(Clone)]
+pub struct TagIter {
+    bytes: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for TagIter {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        let tag_type = read_u32(self.bytes, self.offset)?;
+        let size = read_u32(self.bytes, self.offset + 4)? as usize;
+        if tag_type == tag_type::END || size < 8 {
+            return None;
+        }
+        let data = self.bytes.get(self.offset + 8..self.offset + size)?;
+        // Tags are 8 byte aligned
+        self.offset += size.next_multiple_of(8);
+        Some(Tag { tag_type, data })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMapEntry {
+    pub base: usize,
+    pub length: usize,
+    pub entry_type: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    pub address: usize,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub framebuffer_type: u8,
+    pub red_position: u8,
+    pub red_size: u8,
+    pub green_position: u8,
+    pub green_size: u8,
+    pub blue_position: u8,
+    pub blue_size: u8,
+}
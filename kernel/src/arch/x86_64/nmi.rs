@@ -0,0 +1,212 @@
+//! Non-maskable interrupts, and a watchdog using them to catch hard lockups.
+//!
+//! The Local APIC's LINT0 and LINT1 pins are set up as NMI inputs where the MADT says they're
+//! wired to one. Such NMIs usually report hardware errors, so the interrupted context and the tail
+//! of the log are dumped, then the interrupted code carries on.
+//!
+//! Booting with `nmi_watchdog` makes the first performance counter count unhalted core cycles and
+//! raise an NMI each time it overflows. If one arrives with interrupts disabled, and no interrupt
+//! has been acknowledged for the `watchdog_timeout_secs` tunable, the CPU is taken to be locked up
+//! and the kernel panics. Code which knowingly runs that long with interrupts disabled should call
+//! `touch_watchdog`. The second performance counter can raise NMIs too, for `super::profiler`.
+//!
+//! NMIs can interrupt code holding any lock, so the handler runs on its own stack and dumps
+//! through the debug output directly rather than logging. Hard lockups are also sent through
+//! `crate::net::netdump` if it's enabled, along with the CPU's most recent trace events.
+
+use super::apic::local::LocalApicRegister;
+use super::debug_output::ArchWriter;
+use super::idt::InterruptFrame;
+use super::perf_counter::{self, Counter, LVT_DELIVERY_MODE_NMI};
+use super::platform::acpi::table::{Madt, MadtEntry};
+use super::{clock, profiler, tls, topology};
+use crate::debugging::symbols;
+use crate::net::{NetError, netdump};
+use crate::{cmdline, logging, trace, tunables};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// ACPI processor ID of MADT NMI entries applying to every processor.
+const ALL_PROCESSORS: u8 = 0xFF;
+/// MPS INTI flags polarity for active low inputs.
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+
+const RFLAGS_INTERRUPTS_ENABLED: usize = 1 << 9;
+
+/// Cycles between watchdog NMIs.
+const WATCHDOG_PERIOD_CYCLES: u64 = 1 << 30;
+/// Trace events sent in network dumps.
+const NETDUMP_TRACE_EVENTS: usize = 64;
+
+static WATCHDOG_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Incremented by `touch_watchdog`.
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+/// `HEARTBEAT` at the last watchdog NMI.
+static LAST_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+/// When the CPU last showed it wasn't locked up, as far as the watchdog has seen.
+static LAST_PROGRESS_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets up the current CPU's LINT pins as the MADT describes, and starts the watchdog if it's
+/// enabled on the command line. Must be called after the topology is published and the clock is
+/// calibrated.
+pub unsafe fn init(madt: &Madt) {
+    let local_apic = unsafe { (*tls::get_mut()).local_apic.apic.as_mut().unwrap() };
+    let apic_id = local_apic.id();
+    let acpi_processor_id = topology::current()
+        .and_then(|topology| topology.cpus().iter().find(|cpu| cpu.apic_id == apic_id))
+        .map(|cpu| cpu.acpi_processor_id);
+    for entry in unsafe { madt.entry_iter() } {
+        let MadtEntry::Nmi {
+            acpi_processor_id: processor_id,
+            flags,
+            lint,
+        } = entry
+        else {
+            continue;
+        };
+        if processor_id != ALL_PROCESSORS && Some(processor_id) != acpi_processor_id {
+            continue;
+        }
+        let register = match lint {
+            0 => LocalApicRegister::LvtLint0,
+            1 => LocalApicRegister::LvtLint1,
+            _ => {
+                log::warn!("MADT NMI entry for nonexistent LINT{lint}, ignoring");
+                continue;
+            }
+        };
+        // NMIs are always edge triggered, so only the polarity is used
+        let mut lvt = LVT_DELIVERY_MODE_NMI;
+        if flags & POLARITY_ACTIVE_LOW == POLARITY_ACTIVE_LOW {
+            lvt |= LVT_ACTIVE_LOW;
+        }
+        local_apic.write_register(register, lvt);
+        log::debug!("LINT{lint} set up as an NMI input");
+    }
+    if cmdline::get().nmi_watchdog {
+        start_watchdog();
+    }
+}
+
+fn start_watchdog() {
+    if !perf_counter::available(Counter::Watchdog) {
+        log::warn!("No performance counter for the NMI watchdog to use");
+        return;
+    }
+    LAST_PROGRESS_NS.store(clock::try_now_ns().unwrap_or(0), Ordering::Relaxed);
+    WATCHDOG_ENABLED.store(true, Ordering::Release);
+    unsafe { perf_counter::start(Counter::Watchdog, WATCHDOG_PERIOD_CYCLES) };
+    log::info!(
+        "NMI watchdog started, with a {}s timeout",
+        tunables::WATCHDOG_TIMEOUT_SECS.get(),
+    );
+}
+
+/// Tells the watchdog the CPU is making progress. Called on every EOI.
+#[inline]
+pub fn touch_watchdog() {
+    HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns whether the CPU has been stuck with interrupts disabled for the watchdog's timeout.
+fn locked_up(interrupt_frame: &InterruptFrame) -> bool {
+    let Some(now_ns) = clock::try_now_ns() else {
+        return false;
+    };
+    let heartbeat = HEARTBEAT.load(Ordering::Relaxed);
+    let interrupts_enabled = interrupt_frame.cpu_flags & RFLAGS_INTERRUPTS_ENABLED != 0;
+    if interrupts_enabled || LAST_HEARTBEAT.swap(heartbeat, Ordering::Relaxed) != heartbeat {
+        LAST_PROGRESS_NS.store(now_ns, Ordering::Relaxed);
+        return false;
+    }
+    let timeout_ns = tunables::WATCHDOG_TIMEOUT_SECS.get() * 1_000_000_000;
+    now_ns - LAST_PROGRESS_NS.load(Ordering::Relaxed) >= timeout_ns
+}
+
+/// Writes the interrupted context to `out`.
+fn write_context(
+    out: &mut dyn Write,
+    interrupt_frame: &InterruptFrame,
+    reason: &str,
+) -> fmt::Result {
+    let address = interrupt_frame.intruction_address;
+    writeln!(out, "NMI received, {reason}:")?;
+    let symbol = match interrupt_frame.code_segment & 3 {
+        0 => symbols::resolve(address),
+        _ => None,
+    };
+    match symbol {
+        Some((symbol, offset)) => writeln!(
+            out,
+            "- Interrupted instruction at {address:#x} ({}+{offset:#x})",
+            symbol.demangled(),
+        )?,
+        None => writeln!(out, "- Interrupted instruction at {address:#x}")?,
+    }
+    writeln!(
+        out,
+        "- CS {:#x}, RFLAGS {:#x}, RSP {:#x}, SS {:#x}",
+        interrupt_frame.code_segment,
+        interrupt_frame.cpu_flags,
+        interrupt_frame.stack_address,
+        interrupt_frame.stack_segment,
+    )
+}
+
+/// Writes the tail of the log to `out`, unless the interrupted code has it locked.
+fn write_log_tail(mut out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "Recent log output:")?;
+    match logging::try_write_log_tail(&mut out) {
+        Some(result) => result,
+        None => writeln!(out, "<log locked by interrupted code>"),
+    }
+}
+
+/// Writes the interrupted context and the tail of the log to the debug output.
+fn dump(interrupt_frame: &InterruptFrame, reason: &str) {
+    let mut out = ArchWriter;
+    _ = write_context(&mut out, interrupt_frame, reason);
+    _ = write_log_tail(&mut out);
+    _ = writeln!(out);
+}
+
+/// Sends the interrupted context, the CPU's most recent trace events and the tail of the log over
+/// the network, if network dumps are enabled.
+fn send_dump(interrupt_frame: &InterruptFrame, reason: &str) {
+    let result = netdump::send(|out| {
+        write_context(out, interrupt_frame, reason)?;
+        writeln!(out, "Recent trace events:")?;
+        trace::write_recent(out, NETDUMP_TRACE_EVENTS)?;
+        write_log_tail(out)
+    });
+    _ = match result {
+        Ok(()) => writeln!(ArchWriter, "Dump sent over the network"),
+        Err(NetError::NotConfigured) => Ok(()),
+        Err(err) => writeln!(ArchWriter, "Failed to send dump over the network - {err}"),
+    };
+}
+
+pub extern "x86-interrupt" fn non_maskable_interrupt(interrupt_frame: InterruptFrame) {
+    // Both counters may have overflowed, so each is checked
+    let sampled = profiler::counter_overflow(&interrupt_frame);
+    if WATCHDOG_ENABLED.load(Ordering::Acquire) && perf_counter::overflowed(Counter::Watchdog) {
+        if locked_up(&interrupt_frame) {
+            WATCHDOG_ENABLED.store(false, Ordering::Relaxed);
+            perf_counter::stop(Counter::Watchdog);
+            dump(&interrupt_frame, "hard lockup");
+            send_dump(&interrupt_frame, "hard lockup");
+            panic!(
+                "Hard lockup detected, interrupts disabled for {}s at {:#x}",
+                tunables::WATCHDOG_TIMEOUT_SECS.get(),
+                interrupt_frame.intruction_address,
+            );
+        }
+        unsafe { perf_counter::rearm(Counter::Watchdog, WATCHDOG_PERIOD_CYCLES) };
+        return;
+    }
+    if !sampled {
+        dump(&interrupt_frame, "unknown reason");
+    }
+}
@@ -0,0 +1,170 @@
+//! NUMA nodes, from the ACPI SRAT and SLIT.
+//!
+//! Each proximity domain with processors or memory in the SRAT becomes a node, numbered in the
+//! order they're first seen. Memory ranges are given to the page allocator as per node zones, so
+//! `page_allocation::prefer_node` and `page_allocation::interleave` can choose where pages come
+//! from. Without an SRAT, all memory is on node 0.
+
+use super::page_allocation::{self, MAX_NODES, ZoneRange};
+use super::paging::PAGE_SIZE;
+use super::platform::acpi::table::{self, Slit, Srat, SratEntry};
+use super::tls;
+use crate::sync::OnceLock;
+use alloc::vec::Vec;
+
+static NUMA: OnceLock<Numa> = OnceLock::new();
+
+/// Distance assumed between different nodes when there's no SLIT.
+const REMOTE_DISTANCE: u8 = 20;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    pub proximity_domain: u32,
+    /// Usable pages local to the node.
+    pub num_pages: usize,
+    pub num_cpus: usize,
+}
+
+#[derive(Debug)]
+pub struct Numa {
+    nodes: Vec<Node>,
+    /// Node of each processor by APIC ID.
+    cpu_nodes: Vec<(u32, usize)>,
+    /// Distance from each node to every node, from the SLIT, indexed by `from * nodes + to`.
+    distances: Vec<u8>,
+}
+
+impl Numa {
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn node_of_apic_id(&self, apic_id: u32) -> Option<usize> {
+        self.cpu_nodes
+            .iter()
+            .find(|(cpu_apic_id, _)| *cpu_apic_id == apic_id)
+            .map(|(_, node)| *node)
+    }
+
+    /// Returns the relative distance between nodes, where `Slit::LOCAL_DISTANCE` is the distance
+    /// from a node to itself.
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        self.distances
+            .get(from * self.nodes.len() + to)
+            .copied()
+            .unwrap_or(REMOTE_DISTANCE)
+    }
+
+    /// Returns the node index of `proximity_domain`, adding a node if it's new.
+    fn node_index(&mut self, proximity_domain: u32) -> usize {
+        match self
+            .nodes
+            .iter()
+            .position(|node| node.proximity_domain == proximity_domain)
+        {
+            Some(index) => index,
+            None => {
+                self.nodes.push(Node {
+                    proximity_domain,
+                    num_pages: 0,
+                    num_cpus: 0,
+                });
+                self.nodes.len() - 1
+            }
+        }
+    }
+}
+
+/// Returns the NUMA layout, or `None` if there's no SRAT or it hasn't been read yet.
+pub fn get() -> Option<&'static Numa> {
+    NUMA.get()
+}
+
+/// Returns the node of the CPU this is running on, or 0 if it isn't known.
+pub fn current_node() -> usize {
+    let Some(numa) = get() else {
+        return 0;
+    };
+    let apic_id = unsafe { (*tls::get()).local_apic.apic.as_ref().map(|apic| apic.id()) };
+    apic_id
+        .and_then(|apic_id| numa.node_of_apic_id(apic_id as u32))
+        .unwrap_or(0)
+}
+
+/// Reads the NUMA layout from the SRAT and SLIT, and splits the page allocator into per node
+/// zones. Must be called after ACPI tables are initialised.
+pub unsafe fn init_from_acpi() {
+    let Ok(srat) = (unsafe { table::get::<Srat>() }) else {
+        log::debug!("No SRAT, treating memory as one NUMA node");
+        return;
+    };
+    let total_pages = page_allocation::total_pages();
+    let mut numa = Numa {
+        nodes: Vec::new(),
+        cpu_nodes: Vec::new(),
+        distances: Vec::new(),
+    };
+    let mut ranges = Vec::new();
+    for entry in srat.entry_iter() {
+        match entry {
+            SratEntry::ProcessorAffinity {
+                proximity_domain,
+                apic_id,
+                enabled: true,
+            } => {
+                let node = numa.node_index(proximity_domain);
+                numa.nodes[node].num_cpus += 1;
+                numa.cpu_nodes.push((apic_id, node));
+            }
+            SratEntry::MemoryAffinity {
+                proximity_domain,
+                base_address,
+                length,
+                enabled: true,
+                ..
+            } => {
+                let node = numa.node_index(proximity_domain);
+                // Only whole pages the allocator manages
+                let start_page = (base_address as usize).div_ceil(PAGE_SIZE);
+                let end_page =
+                    (base_address.saturating_add(length) as usize / PAGE_SIZE).min(total_pages);
+                if start_page < end_page {
+                    numa.nodes[node].num_pages += end_page - start_page;
+                    ranges.push(ZoneRange {
+                        node,
+                        start_page,
+                        end_page,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    let num_nodes = numa.nodes.len();
+    let slit = unsafe { table::get::<Slit>() }.ok();
+    for from in 0..num_nodes {
+        for to in 0..num_nodes {
+            let from_domain = numa.nodes[from].proximity_domain as usize;
+            let to_domain = numa.nodes[to].proximity_domain as usize;
+            let distance = slit.and_then(|slit| slit.distance(from_domain, to_domain));
+            numa.distances.push(match (distance, from == to) {
+                (Some(distance), _) => distance,
+                (None, true) => Slit::LOCAL_DISTANCE,
+                (None, false) => REMOTE_DISTANCE,
+            });
+        }
+    }
+    for (index, node) in numa.nodes.iter().enumerate() {
+        log::info!(
+            "NUMA node {index}: proximity domain {}, {} pages, {} CPUs",
+            node.proximity_domain,
+            node.num_pages,
+            node.num_cpus,
+        );
+    }
+    if num_nodes > MAX_NODES {
+        log::warn!("Only the first {MAX_NODES} of {num_nodes} NUMA nodes have their own zones");
+    }
+    page_allocation::set_zones(&ranges);
+    _ = NUMA.set(numa);
+}
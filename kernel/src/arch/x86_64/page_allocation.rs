@@ -1,16 +1,250 @@
 //! Provides facilities for allocating physical memory.
 
 use crate::arch::kernel_args::MutSlice;
-use crate::arch::paging::{PAGE_SIZE, PageTable, PageTableEntry, align_to_page};
+use crate::arch::paging::{self, PAGE_SIZE, PageTable, PageTableEntry, align_to_page};
+use crate::event::{self, Event};
+use crate::init_state::{self, Subsystem};
+use crate::memory_tag;
+use crate::sync::IrqMutex;
+use alloc::boxed::Box;
 use core::arch::asm;
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
 use core::ptr::NonNull;
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 pub type RawPage = [u8; PAGE_SIZE];
 
-static PAGE_ALLOCATOR: Mutex<Option<PageAllocatorInternal>> = Mutex::new(None);
+static PAGE_ALLOCATOR: IrqMutex<Option<PageAllocatorInternal>> = IrqMutex::new(None);
+/// Metadata of each physical page, indexed by page number. Set up once the heap is available.
+static PAGE_INFO: IrqMutex<Option<&'static [PageInfo]>> = IrqMutex::new(None);
+/// Whether free memory is below the low watermark, so `Event::MemoryPressure` is only published
+/// once each time it drops below.
+static UNDER_PRESSURE: AtomicBool = AtomicBool::new(false);
+
+/// Memory pressure is reported below 1/16 of pages free, and cleared again above 1/8.
+const LOW_WATERMARK_DIVISOR: usize = 16;
+const HIGH_WATERMARK_DIVISOR: usize = 8;
+
+/// Publishes `Event::MemoryPressure` if free memory has just dropped below the low watermark.
+/// Called with the page allocator unlocked, as subscribers may look at memory usage. Only checked
+/// by `find_and_reserve_page`, as the heap maps pages with the scheduler locked, where waking the
+/// event worker would deadlock.
+fn check_memory_pressure(free_pages: usize, total_pages: usize) {
+    if free_pages < total_pages / LOW_WATERMARK_DIVISOR
+        && !UNDER_PRESSURE.swap(true, Ordering::Relaxed)
+    {
+        event::publish(Event::MemoryPressure { free_pages });
+    }
+}
+
+/// Clears the memory pressure state once free memory rises above the high watermark.
+fn clear_memory_pressure(free_pages: usize, total_pages: usize) {
+    if free_pages > total_pages / HIGH_WATERMARK_DIVISOR {
+        UNDER_PRESSURE.store(false, Ordering::Relaxed);
+    }
+}
+
+bitflags::bitflags! {
+    /// What a physical page is being used for.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct PageFlags: u32 {
+        /// Part of a shared memory object, which may be mapped in several address spaces.
+        const SHARED = 1 << 0;
+        /// Mapped read only in several places, to be copied on the first write.
+        const COPY_ON_WRITE = 1 << 1;
+        /// Caching the contents of a file.
+        const FILE_CACHE = 1 << 2;
+    }
+}
+
+/// Metadata of a physical page.
+#[derive(Debug)]
+struct PageInfo {
+    /// Number of owners of a reserved page, zero while the page is free. The page is freed when
+    /// the last owner calls `put_page`.
+    ref_count: AtomicU32,
+    flags: AtomicU32,
+}
+
+/// Returns the metadata of the page at `address`, or `None` if page metadata isn't set up yet.
+fn page_info(address: usize) -> Option<&'static PageInfo> {
+    let page_info = (*PAGE_INFO.lock())?;
+    page_info.get(address / PAGE_SIZE)
+}
+
+/// Sets up the metadata of every physical page, for reference counting. Pages already reserved
+/// start with one reference. Must be called after the heap is initialised, as the metadata array
+/// is allocated on it.
+pub fn init_page_info() {
+    let page_info: Box<[PageInfo]> = (0..total_pages())
+        .map(|_| PageInfo {
+            ref_count: AtomicU32::new(0),
+            flags: AtomicU32::new(0),
+        })
+        .collect();
+    let page_info = Box::leak(page_info);
+    // Counted with the allocator locked, so no pages are reserved or freed in between
+    let lock = PAGE_ALLOCATOR.lock();
+    let page_allocator = lock.as_ref().unwrap();
+    for (page_index, info) in page_info.iter().enumerate() {
+        if page_allocator.memory_bitmap[page_index / 8] & (0x80 >> (page_index % 8)) != 0 {
+            info.ref_count.store(1, Ordering::Relaxed);
+        }
+    }
+    *PAGE_INFO.lock() = Some(page_info);
+}
+
+/// Adds a reference to the reserved page at `address`, which must then be released with
+/// `put_page` rather than freed.
+pub fn get_page(address: usize) {
+    let info = page_info(address).expect("page metadata not initialised");
+    let old_count = info.ref_count.fetch_add(1, Ordering::Relaxed);
+    assert_ne!(old_count, 0, "reference taken to free page {address:#x}");
+}
+
+/// Drops a reference to the page at `address`, freeing it if it was the last one. Returns whether
+/// the page was freed.
+pub fn put_page(address: usize) -> bool {
+    let Some(info) = page_info(address) else {
+        free_page(address);
+        return true;
+    };
+    let old_count = info.ref_count.fetch_sub(1, Ordering::AcqRel);
+    assert_ne!(old_count, 0, "reference dropped to free page {address:#x}");
+    if old_count == 1 {
+        free_page(address);
+    }
+    old_count == 1
+}
+
+/// Returns the number of references to the page at `address`, or `None` if page metadata isn't
+/// set up yet.
+pub fn page_ref_count(address: usize) -> Option<usize> {
+    page_info(address).map(|info| info.ref_count.load(Ordering::Relaxed) as usize)
+}
+
+/// Returns the flags of the page at `address`.
+pub fn page_flags(address: usize) -> PageFlags {
+    page_info(address).map_or(PageFlags::empty(), |info| {
+        PageFlags::from_bits_retain(info.flags.load(Ordering::Relaxed))
+    })
+}
+
+/// Sets `flags` on the reserved page at `address`. They're cleared when the page is freed.
+pub fn set_page_flags(address: usize, flags: PageFlags) {
+    if let Some(info) = page_info(address) {
+        info.flags.fetch_or(flags.bits(), Ordering::Relaxed);
+    }
+}
+
+/// Records that the page at `address` has been reserved, with a single reference.
+fn page_info_reserved(address: usize) {
+    if let Some(info) = page_info(address) {
+        info.ref_count.store(1, Ordering::Relaxed);
+    }
+}
+
+/// Records that the page at `address` has been freed. It shouldn't have any other references, or
+/// if freed by `put_page`, any references left at all.
+fn page_info_freed(address: usize) {
+    if let Some(info) = page_info(address) {
+        let old_count = info.ref_count.swap(0, Ordering::Relaxed);
+        debug_assert!(
+            old_count <= 1,
+            "page {address:#x} freed with {old_count} references"
+        );
+        info.flags.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Most NUMA nodes pages can be allocated from by node.
+pub const MAX_NODES: usize = 8;
+/// Most physical memory ranges which can be assigned to nodes.
+const MAX_ZONE_RANGES: usize = 32;
+
+/// A range of physical pages local to a NUMA node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZoneRange {
+    pub node: usize,
+    pub start_page: usize,
+    pub end_page: usize,
+}
+
+/// Which node pages are reserved from. Pages come from any node once the chosen node is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocationPolicy {
+    /// The lowest free page, whichever node it's on.
+    Any,
+    PreferNode(usize),
+    /// Each node in turn, spreading memory evenly between nodes.
+    Interleave,
+}
+
+/// Restores the previous allocation policy when dropped.
+pub struct PolicyGuard {
+    previous: AllocationPolicy,
+}
+
+impl Drop for PolicyGuard {
+    fn drop(&mut self) {
+        if let Some(page_allocator) = PAGE_ALLOCATOR.lock().as_mut() {
+            page_allocator.policy = self.previous;
+        }
+    }
+}
+
+fn set_policy(policy: AllocationPolicy) -> PolicyGuard {
+    let mut lock = PAGE_ALLOCATOR.lock();
+    let page_allocator = lock.as_mut().unwrap();
+    PolicyGuard {
+        previous: core::mem::replace(&mut page_allocator.policy, policy),
+    }
+}
+
+/// Reserves pages from `node` while the returned guard is alive. Like memory tags, the policy
+/// isn't per thread, so guards shouldn't be held across anything which can switch threads.
+#[must_use]
+pub fn prefer_node(node: usize) -> PolicyGuard {
+    set_policy(AllocationPolicy::PreferNode(node))
+}
+
+/// Reserves pages from each node in turn while the returned guard is alive, as for `prefer_node`.
+#[must_use]
+pub fn interleave() -> PolicyGuard {
+    set_policy(AllocationPolicy::Interleave)
+}
+
+/// Assigns physical memory ranges to NUMA nodes, replacing any previous assignment. Ranges past
+/// `MAX_ZONE_RANGES`, or of nodes past `MAX_NODES`, are left unassigned.
+pub fn set_zones(ranges: &[ZoneRange]) {
+    let mut lock = PAGE_ALLOCATOR.lock();
+    let page_allocator = lock.as_mut().unwrap();
+    let mut zones = Zones::EMPTY;
+    for range in ranges.iter().filter(|range| range.node < MAX_NODES) {
+        let Some(slot) = zones.ranges.get_mut(zones.len) else {
+            break;
+        };
+        *slot = *range;
+        zones.len += 1;
+    }
+    page_allocator.zones = zones;
+}
+
+/// Returns the number of free pages on `node`.
+pub fn node_free_pages(node: usize) -> usize {
+    let lock = PAGE_ALLOCATOR.lock();
+    let page_allocator = lock.as_ref().unwrap();
+    page_allocator
+        .zones
+        .node_ranges(node)
+        .map(|range| {
+            (range.start_page..range.end_page.min(page_allocator.total_pages))
+                .filter(|page_index| !page_allocator.is_reserved(*page_index))
+                .count()
+        })
+        .sum()
+}
 
 /// Initialises the page allocation system. Does nothing if the page allocation system is already
 /// initialised.
@@ -18,17 +252,23 @@ pub unsafe fn init(page_table_address: usize, memory_bitmap: &'static mut [u8],
     unsafe {
         let mut lock = PAGE_ALLOCATOR.lock();
         if lock.as_mut().is_none() {
+            init_state::begin(Subsystem::PageAllocation);
             lock.replace(PageAllocatorInternal::new(
                 page_table_address,
                 memory_bitmap,
                 num_pages,
             ));
+            init_state::finish(Subsystem::PageAllocation);
         }
     }
 }
 
 pub fn deinit_and_remove() -> Option<PageAllocatorInternal> {
-    PAGE_ALLOCATOR.lock().take()
+    let page_allocator = PAGE_ALLOCATOR.lock().take();
+    if page_allocator.is_some() {
+        init_state::reset(Subsystem::PageAllocation);
+    }
+    page_allocator
 }
 
 #[inline]
@@ -70,8 +310,35 @@ pub fn used_pages() -> usize {
 pub fn find_and_reserve_page() -> Result<OwnedPhysicalPage, ReservePageError> {
     let mut lock = PAGE_ALLOCATOR.lock();
     let page_allocator = lock.as_mut().unwrap();
-    page_allocator
+    let result = page_allocator
         .find_and_reserve_page()
+        .map(OwnedPhysicalPage::from_non_null);
+    let (free_pages, total_pages) = (page_allocator.free_pages, page_allocator.total_pages);
+    drop(lock);
+    check_memory_pressure(free_pages, total_pages);
+    result
+}
+
+/// Attempts to reserve `num_pages` physically contiguous free pages, for devices which access
+/// memory directly. Returns the physical address of the first page. Each page is freed on its own
+/// with `free_page`.
+pub fn find_and_reserve_contiguous(num_pages: usize) -> Result<usize, ReservePageError> {
+    let mut lock = PAGE_ALLOCATOR.lock();
+    let page_allocator = lock.as_mut().unwrap();
+    let result = page_allocator.find_and_reserve_contiguous(num_pages);
+    let (free_pages, total_pages) = (page_allocator.free_pages, page_allocator.total_pages);
+    drop(lock);
+    check_memory_pressure(free_pages, total_pages);
+    result
+}
+
+/// Reserves the page at physical address `address` if it's free. Unlike `find_and_reserve_page`,
+/// the page isn't cleared.
+pub fn reserve_page_at(address: usize) -> Option<OwnedPhysicalPage> {
+    let mut lock = PAGE_ALLOCATOR.lock();
+    let page_allocator = lock.as_mut().unwrap();
+    page_allocator
+        .reserve_page_at(address)
         .map(OwnedPhysicalPage::from_non_null)
 }
 
@@ -81,6 +348,7 @@ pub fn free_page(address: usize) {
     let mut lock = PAGE_ALLOCATOR.lock();
     let page_allocator = lock.as_mut().unwrap();
     page_allocator.free_page(address);
+    clear_memory_pressure(page_allocator.free_pages, page_allocator.total_pages);
 }
 
 /// Returns whether whether memory at the given virtual address is identity mapped.
@@ -122,6 +390,17 @@ pub unsafe fn unmap_and_free_page(virtual_address: usize) {
         let mut lock = PAGE_ALLOCATOR.lock();
         let page_allocator = lock.as_mut().unwrap();
         page_allocator.unmap_and_free_page(virtual_address);
+        clear_memory_pressure(page_allocator.free_pages, page_allocator.total_pages);
+    }
+}
+
+/// Calls `f` with every present mapping in the kernel's page table, as described by
+/// `paging::walk_mappings`. The page allocator is locked throughout.
+pub unsafe fn walk_mappings<F: FnMut(usize, &mut PageTableEntry, usize)>(f: F) {
+    unsafe {
+        let lock = PAGE_ALLOCATOR.lock();
+        let page_allocator = lock.as_ref().unwrap();
+        paging::walk_mappings(page_allocator.page_table.address(), f);
     }
 }
 
@@ -148,6 +427,9 @@ pub struct OwnedPhysicalPage {
     _marker: PhantomData<RawPage>,
 }
 
+// The page is only reachable through this, so it can be moved between threads like a `Box`
+unsafe impl Send for OwnedPhysicalPage {}
+
 impl OwnedPhysicalPage {
     #[must_use]
     pub unsafe fn from_raw(raw: *mut RawPage) -> Self {
@@ -233,6 +515,41 @@ pub struct PageAllocatorInternal {
     pub total_pages: usize,
     pub free_pages: usize,
     pub page_table: PageTableEntry,
+    zones: Zones,
+    policy: AllocationPolicy,
+    /// Node the next page is reserved from under `AllocationPolicy::Interleave`.
+    next_interleave_node: usize,
+}
+
+/// Physical memory ranges of each NUMA node.
+#[derive(Clone, Copy, Debug)]
+struct Zones {
+    ranges: [ZoneRange; MAX_ZONE_RANGES],
+    len: usize,
+}
+
+impl Zones {
+    const EMPTY: Self = Self {
+        ranges: [ZoneRange {
+            node: 0,
+            start_page: 0,
+            end_page: 0,
+        }; MAX_ZONE_RANGES],
+        len: 0,
+    };
+
+    fn node_ranges(&self, node: usize) -> impl Iterator<Item = &ZoneRange> {
+        self.ranges[..self.len]
+            .iter()
+            .filter(move |range| range.node == node)
+    }
+
+    /// Returns the first node after `node` which has memory, wrapping around.
+    fn next_node_with_memory(&self, node: usize) -> Option<usize> {
+        (1..=MAX_NODES)
+            .map(|offset| (node + offset) % MAX_NODES)
+            .find(|node| self.node_ranges(*node).next().is_some())
+    }
 }
 
 impl PageAllocatorInternal {
@@ -257,6 +574,9 @@ impl PageAllocatorInternal {
             total_pages: num_pages,
             free_pages,
             page_table: PageTableEntry::ZERO.replace_addr_with(page_table_address),
+            zones: Zones::EMPTY,
+            policy: AllocationPolicy::Any,
+            next_interleave_node: 0,
         }
     }
 
@@ -265,26 +585,111 @@ impl PageAllocatorInternal {
         self.total_pages - self.free_pages
     }
 
-    /// Attempts to reserve a free page.
+    /// Attempts to reserve a free page, from the node chosen by the allocation policy if it has
+    /// any free.
     /// Returns the physical address if a page is found.
     pub fn find_and_reserve_page(&mut self) -> Result<NonNull<RawPage>, ReservePageError> {
-        for (byte_index, byte) in self.memory_bitmap.iter_mut().enumerate() {
+        let node = match self.policy {
+            AllocationPolicy::Any => None,
+            AllocationPolicy::PreferNode(node) => Some(node),
+            AllocationPolicy::Interleave => {
+                let node = self.zones.next_node_with_memory(self.next_interleave_node);
+                if let Some(node) = node {
+                    self.next_interleave_node = node;
+                }
+                node
+            }
+        };
+        if let Some(node) = node {
+            let page_index = self.zones.node_ranges(node).find_map(|range| {
+                self.first_free_page(range.start_page, range.end_page.min(self.total_pages))
+            });
+            if let Some(page_index) = page_index {
+                return Ok(self.reserve_free_page(page_index));
+            }
+        }
+        for (byte_index, byte) in self.memory_bitmap.iter().enumerate() {
             if *byte != 0xFF {
                 let bit_index = (!*byte).leading_zeros() as usize;
-                *byte |= 0x80 >> bit_index;
-                self.free_pages -= 1;
-                let addr = (byte_index * Self::BYTE_RATIO) + (bit_index * PAGE_SIZE);
-                let page_ptr = addr as *mut RawPage;
-                // Clear page
-                unsafe {
-                    page_ptr.as_mut().unwrap().fill(0);
+                return Ok(self.reserve_free_page(byte_index * 8 + bit_index));
+            }
+        }
+        Err(ReservePageError)
+    }
+
+    /// Reserves and clears the first run of `num_pages` free pages, ignoring the allocation
+    /// policy. Returns the physical address of the first page.
+    pub fn find_and_reserve_contiguous(&mut self, num_pages: usize) -> Result<usize, ReservePageError> {
+        // The page at address 0 is never handed out
+        let mut start_page = 1;
+        while start_page + num_pages <= self.total_pages {
+            match (start_page..start_page + num_pages).find(|page| self.is_reserved(*page)) {
+                Some(reserved_page) => start_page = reserved_page + 1,
+                None => {
+                    for page_index in start_page..start_page + num_pages {
+                        self.reserve_free_page(page_index);
+                    }
+                    return Ok(start_page * PAGE_SIZE);
                 }
-                return Ok(NonNull::new(page_ptr).unwrap());
             }
         }
         Err(ReservePageError)
     }
 
+    fn is_reserved(&self, page_index: usize) -> bool {
+        self.memory_bitmap[page_index / 8] & (0x80 >> (page_index % 8)) != 0
+    }
+
+    /// Returns the index of the first free page from `start_page` up to `end_page`.
+    fn first_free_page(&self, start_page: usize, end_page: usize) -> Option<usize> {
+        let mut page_index = start_page;
+        while page_index < end_page {
+            if self.memory_bitmap[page_index / 8] == 0xFF {
+                // Skip to the next byte
+                page_index = (page_index / 8 + 1) * 8;
+            } else if self.is_reserved(page_index) {
+                page_index += 1;
+            } else {
+                return Some(page_index);
+            }
+        }
+        None
+    }
+
+    /// Reserves and clears the free page at `page_index`.
+    fn reserve_free_page(&mut self, page_index: usize) -> NonNull<RawPage> {
+        self.memory_bitmap[page_index / 8] |= 0x80 >> (page_index % 8);
+        self.free_pages -= 1;
+        let addr = page_index * PAGE_SIZE;
+        let page_ptr = addr as *mut RawPage;
+        memory_tag::page_reserved(addr);
+        page_info_reserved(addr);
+        // Clear page
+        unsafe {
+            page_ptr.as_mut().unwrap().fill(0);
+        }
+        NonNull::new(page_ptr).unwrap()
+    }
+
+    /// Reserves the page at `address` if it's free, without clearing it. The page at address 0 is
+    /// never reserved.
+    pub fn reserve_page_at(&mut self, address: usize) -> Option<NonNull<RawPage>> {
+        let page_index = address / PAGE_SIZE;
+        if address == 0 || page_index >= self.total_pages {
+            return None;
+        }
+        let byte = &mut self.memory_bitmap[page_index / 8];
+        let bit = 0x80 >> (page_index % 8);
+        if *byte & bit != 0 {
+            return None;
+        }
+        *byte |= bit;
+        self.free_pages -= 1;
+        memory_tag::page_reserved(address);
+        page_info_reserved(address);
+        NonNull::new(address as *mut RawPage)
+    }
+
     /// Marks a page as no longer reserved.
     /// The caller is expected to no longer use references to this page.
     pub fn free_page(&mut self, address: usize) {
@@ -295,6 +700,8 @@ impl PageAllocatorInternal {
         }
         self.memory_bitmap[byte_index] &= !(0x80 >> bit_offset);
         self.free_pages += 1;
+        memory_tag::page_freed(address);
+        page_info_freed(address);
     }
 
     pub unsafe fn is_address_identity_mapped(&self, address: usize) -> bool {
@@ -341,7 +748,9 @@ impl PageAllocatorInternal {
                     (3, true) => return Err(MapPageError::PageAlreadyExists),
                     // Child page doesn't exist, map page with flags
                     (3, false) => {
-                        *entry = flags.replace_addr_with(physical_address);
+                        *entry = flags
+                            .without_unsupported_bits()
+                            .replace_addr_with(physical_address);
                         // asm!("invlpg [{}]", in(reg) new_page_address, options(nostack));
                         return Ok(());
                     }
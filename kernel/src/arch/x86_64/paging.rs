@@ -1,6 +1,10 @@
 //! Implementation of x86_64 page tables.
 
+use super::cpuid::{self, Feature};
+
 pub const PAGE_SIZE: usize = 4096;
+/// Entry bit disallowing instruction fetches.
+const NO_EXECUTE: u64 = 1 << 63;
 
 /// Aligns `address` down to the nearest page boundary.
 #[inline]
@@ -10,13 +14,71 @@ pub fn align_to_page(address: usize) -> usize {
 
 pub type PageTable = [PageTableEntry; 512];
 
+/// Calls `f` with every present mapping in the page table at `page_table_address`, as its
+/// virtual address, entry, and size in bytes. Huge page entries are passed along with 4KiB ones.
+/// Page tables must be identity mapped.
+pub unsafe fn walk_mappings<F: FnMut(usize, &mut PageTableEntry, usize)>(
+    page_table_address: usize,
+    mut f: F,
+) {
+    unsafe { walk_table(page_table_address, 0, 0, &mut f) }
+}
+
+unsafe fn walk_table<F: FnMut(usize, &mut PageTableEntry, usize)>(
+    table_address: usize,
+    level: usize,
+    base_address: usize,
+    f: &mut F,
+) {
+    let table = unsafe { &mut *(table_address as *mut PageTable) };
+    let shift = (3 - level) * 9 + 12;
+    for (index, entry) in table.iter_mut().enumerate() {
+        if !entry.present() {
+            continue;
+        }
+        let mut virtual_address = base_address | (index << shift);
+        // Sign extend higher half addresses
+        if level == 0 && index >= 256 {
+            virtual_address |= 0xFFFF_0000_0000_0000;
+        }
+        if level == 3 || entry.huge_page() {
+            f(virtual_address, entry, 1 << shift);
+        } else {
+            unsafe { walk_table(entry.address(), level + 1, virtual_address, f) };
+        }
+    }
+}
+
+/// Returns the entry mapping `virtual_address` in the page table at `page_table_address`, if
+/// it's mapped by a 4KiB page. Page tables must be identity mapped. Doesn't take any locks, so
+/// can be used from exception handlers.
+pub unsafe fn find_entry(
+    page_table_address: usize,
+    virtual_address: usize,
+) -> Option<&'static mut PageTableEntry> {
+    let mut table_address = page_table_address;
+    for level in 0..4 {
+        let table = unsafe { &mut *(table_address as *mut PageTable) };
+        let index = (virtual_address >> ((3 - level) * 9 + 12)) % 512;
+        let entry = &mut table[index];
+        if !entry.present() || (level != 3 && entry.huge_page()) {
+            return None;
+        }
+        if level == 3 {
+            return Some(entry);
+        }
+        table_address = entry.address();
+    }
+    unreachable!()
+}
+
 bitfield::bitfield! {
     #[derive(Clone, Copy, PartialEq, Eq)]
     #[repr(transparent)]
     pub struct PageTableEntry(u64);
     impl Debug;
     pub present, _: 0;
-    pub writable, _: 1;
+    pub writable, set_writable: 1;
     pub user_accessable, _: 2;
     pub write_through_caching_enabled, _: 3;
     pub cache_disabled, _: 4;
@@ -24,9 +86,11 @@ bitfield::bitfield! {
     pub dirty, _: 6;
     pub huge_page, _: 7;
     pub global, _: 8;
-    pub no_execute, _: 63;
+    pub no_execute, set_no_execute: 63;
     address_unextended, _: 51, 12;
     kernel_data_1, _: 11, 9;
+    /// Marks pages made read-only by a page watchpoint.
+    pub watched, set_watched: 9;
     kernel_data_2, _: 58, 52;
 }
 
@@ -117,6 +181,16 @@ impl PageTableEntry {
         Self(raw_address | raw_flags)
     }
 
+    /// Returns the entry without any bits the CPU doesn't support, which would cause a page fault
+    /// on access. No execute is only kept once the CPU is known to support it.
+    #[must_use]
+    pub fn without_unsupported_bits(self) -> Self {
+        match cpuid::try_get_info() {
+            Some(info) if !info.has(Feature::Nx) => Self(self.0 & !NO_EXECUTE),
+            _ => self,
+        }
+    }
+
     #[must_use]
     pub const fn replace_addr_with(&self, addr: usize) -> Self {
         let stripped_address = addr as u64 & 0x000FFFFFFFFFF000;
@@ -0,0 +1,179 @@
+//! Access to PCI configuration space through the legacy I/O port mechanism. Only segment 0 is
+//! reachable this way.
+
+use super::port;
+use crate::sync::IrqMutex;
+
+/// Selecting a register and accessing it takes two port accesses, which mustn't be interleaved.
+static CONFIG_LOCK: IrqMutex<()> = IrqMutex::new(());
+
+pub const REGISTER_VENDOR_ID: u8 = 0x00;
+pub const REGISTER_DEVICE_ID: u8 = 0x02;
+pub const REGISTER_COMMAND: u8 = 0x04;
+pub const REGISTER_STATUS: u8 = 0x06;
+/// Revision ID in the low byte, then programming interface, subclass and class.
+pub const REGISTER_CLASS: u8 = 0x08;
+pub const REGISTER_HEADER_TYPE: u8 = 0x0E;
+pub const REGISTER_BAR0: u8 = 0x10;
+pub const REGISTER_CAPABILITIES: u8 = 0x34;
+/// 1 for INTA through to 4 for INTD, or 0 if the function doesn't use INTx.
+pub const REGISTER_INTERRUPT_PIN: u8 = 0x3D;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+pub const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+
+pub mod capability {
+    pub const MSI: u8 = 0x05;
+    pub const MSI_X: u8 = 0x11;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        assert!(device < 32 && function < 8);
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Calls `f` with the data port for `register`, which must be aligned to its width.
+fn with_register<T>(address: PciAddress, register: u8, f: impl FnOnce(u16) -> T) -> T {
+    let _lock = CONFIG_LOCK.lock();
+    let config_address = 1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (register as u32 & 0xFC);
+    unsafe {
+        port::write_dword(port::PCI_CONFIG_ADDRESS, config_address);
+    }
+    f(port::PCI_CONFIG_DATA + (register & 0x3) as u16)
+}
+
+pub fn read_byte(address: PciAddress, register: u8) -> u8 {
+    with_register(address, register, |data_port| unsafe {
+        port::read_byte(data_port)
+    })
+}
+
+pub fn read_word(address: PciAddress, register: u8) -> u16 {
+    assert!(register.is_multiple_of(2));
+    with_register(address, register, |data_port| unsafe {
+        port::read_word(data_port)
+    })
+}
+
+pub fn read_dword(address: PciAddress, register: u8) -> u32 {
+    assert!(register.is_multiple_of(4));
+    with_register(address, register, |data_port| unsafe {
+        port::read_dword(data_port)
+    })
+}
+
+pub unsafe fn write_byte(address: PciAddress, register: u8, value: u8) {
+    with_register(address, register, |data_port| unsafe {
+        port::write_byte(data_port, value)
+    })
+}
+
+pub unsafe fn write_word(address: PciAddress, register: u8, value: u16) {
+    assert!(register.is_multiple_of(2));
+    with_register(address, register, |data_port| unsafe {
+        port::write_word(data_port, value)
+    })
+}
+
+pub unsafe fn write_dword(address: PciAddress, register: u8, value: u32) {
+    assert!(register.is_multiple_of(4));
+    with_register(address, register, |data_port| unsafe {
+        port::write_dword(data_port, value)
+    })
+}
+
+/// Calls `f` with the address of every function present on segment 0.
+pub fn for_each_function(mut f: impl FnMut(PciAddress)) {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = PciAddress::new(bus, device, function);
+                if read_word(address, REGISTER_VENDOR_ID) == 0xFFFF {
+                    // Devices must implement function 0
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                f(address);
+                if function == 0
+                    && read_byte(address, REGISTER_HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION == 0
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the offset of the first capability with ID `id` in the device's capability list.
+pub fn find_capability(address: PciAddress, id: u8) -> Option<u8> {
+    if read_word(address, REGISTER_STATUS) & STATUS_CAPABILITIES == 0 {
+        return None;
+    }
+    let mut offset = read_byte(address, REGISTER_CAPABILITIES) & 0xFC;
+    // Bounded, in case of a malformed list that loops
+    for _ in 0..48 {
+        if offset == 0 {
+            return None;
+        }
+        if read_byte(address, offset) == id {
+            return Some(offset);
+        }
+        offset = read_byte(address, offset + 1) & 0xFC;
+    }
+    None
+}
+
+/// Returns the physical address of a memory BAR, or `None` if it's an I/O BAR.
+pub fn memory_bar_address(address: PciAddress, bar: u8) -> Option<u64> {
+    assert!(bar < 6);
+    let register = REGISTER_BAR0 + bar * 4;
+    let low = read_dword(address, register);
+    if low & 1 != 0 {
+        return None;
+    }
+    let base = (low & !0xF) as u64;
+    match (low >> 1) & 0x3 {
+        // 64 bit BAR, high half is in the next BAR
+        2 if bar < 5 => Some(base | (read_dword(address, register + 4) as u64) << 32),
+        _ => Some(base),
+    }
+}
+
+/// Returns the base port of an I/O BAR, or `None` if it's a memory BAR.
+pub fn io_bar_port(address: PciAddress, bar: u8) -> Option<u16> {
+    assert!(bar < 6);
+    let value = read_dword(address, REGISTER_BAR0 + bar * 4);
+    match value & 1 {
+        1 => Some((value & !0x3) as u16),
+        _ => None,
+    }
+}
@@ -0,0 +1,119 @@
+//! General purpose performance counters counting unhalted core cycles, which raise an NMI each
+//! time they overflow.
+//!
+//! The first counter is the NMI watchdog's, see `super::nmi`, and the second the profiler's, see
+//! `super::profiler`. Each period is started by writing the counter with its negated length, so
+//! the counter's top bit is clear once it has overflowed. Overflows mask the Local APIC's
+//! performance counter interrupt, so the NMI handler has to `rearm` the counter to get the next
+//! one.
+
+use super::apic::local::LocalApicRegister;
+use super::cpuid;
+use super::msr::{Msr, PerfEvtSel0, PerfEvtSel1, PerfGlobalOvfCtrl, Pmc0, Pmc1};
+use super::tls;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub const LVT_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const EVENT_SELECT_USER: u64 = 1 << 16;
+const EVENT_SELECT_OS: u64 = 1 << 17;
+const EVENT_SELECT_INTERRUPT: u64 = 1 << 20;
+const EVENT_SELECT_ENABLE: u64 = 1 << 22;
+
+/// Longest period a counter can be set to, as writes are sign extended from 32 bits.
+pub const MAX_PERIOD_CYCLES: u64 = (1 << 31) - 1;
+
+static PERFMON_VERSION: AtomicU8 = AtomicU8::new(0);
+static COUNTER_WIDTH: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    /// The first counter.
+    Watchdog = 0,
+    /// The second counter.
+    Profiler = 1,
+}
+
+impl Counter {
+    fn write_event_select(self, value: u64) {
+        unsafe {
+            match self {
+                Self::Watchdog => PerfEvtSel0::write(value),
+                Self::Profiler => PerfEvtSel1::write(value),
+            }
+        }
+    }
+
+    fn read(self) -> u64 {
+        match self {
+            Self::Watchdog => Pmc0::read(),
+            Self::Profiler => Pmc1::read(),
+        }
+    }
+
+    fn write(self, value: u64) {
+        unsafe {
+            match self {
+                Self::Watchdog => Pmc0::write(value),
+                Self::Profiler => Pmc1::write(value),
+            }
+        }
+    }
+}
+
+/// Returns whether `counter` exists and can count core cycles, which isn't the case under
+/// emulation.
+pub fn available(counter: Counter) -> bool {
+    let perfmon = cpuid::get_info()
+        .perfmon
+        .filter(|perfmon| perfmon.core_cycles && perfmon.num_counters > counter as u8);
+    let Some(perfmon) = perfmon else {
+        return false;
+    };
+    PERFMON_VERSION.store(perfmon.version, Ordering::Relaxed);
+    COUNTER_WIDTH.store(perfmon.counter_width, Ordering::Relaxed);
+    true
+}
+
+/// Starts `counter` counting, raising an NMI after `period_cycles`, which must be at most
+/// `MAX_PERIOD_CYCLES`. `available` must have returned true for it.
+pub unsafe fn start(counter: Counter, period_cycles: u64) {
+    counter.write_event_select(0);
+    unsafe { rearm(counter, period_cycles) };
+    counter.write_event_select(
+        EVENT_UNHALTED_CORE_CYCLES
+            | EVENT_SELECT_USER
+            | EVENT_SELECT_OS
+            | EVENT_SELECT_INTERRUPT
+            | EVENT_SELECT_ENABLE,
+    );
+}
+
+/// Stops `counter` counting.
+pub fn stop(counter: Counter) {
+    counter.write_event_select(0);
+}
+
+/// Restarts `counter` with a period of `period_cycles` and unmasks its interrupt, which is masked
+/// on each overflow.
+pub unsafe fn rearm(counter: Counter, period_cycles: u64) {
+    counter.write(period_cycles.wrapping_neg());
+    unsafe {
+        if PERFMON_VERSION.load(Ordering::Relaxed) >= 2 {
+            PerfGlobalOvfCtrl::write(1 << counter as u8);
+        }
+        if let Some(local_apic) = (*tls::get_mut()).local_apic.apic.as_mut() {
+            local_apic.write_register(
+                LocalApicRegister::LvtPerfMonitoringCounters,
+                LVT_DELIVERY_MODE_NMI,
+            );
+        }
+    }
+}
+
+/// Returns whether `counter` has overflowed since it was last armed.
+pub fn overflowed(counter: Counter) -> bool {
+    let top_bit = COUNTER_WIDTH.load(Ordering::Relaxed).saturating_sub(1);
+    counter.read() & (1 << top_bit) == 0
+}
@@ -0,0 +1,132 @@
+//! Sampling interrupts for `crate::profiler`.
+//!
+//! The second performance counter is preferred, as the first is left to the NMI watchdog. It
+//! counts unhalted core cycles and raises an NMI each time it overflows, so code running with
+//! interrupts disabled is sampled too, and idle CPUs aren't. The period is set from the TSC
+//! frequency, so it's only approximate if the core clock differs.
+//!
+//! Without a spare counter, such as under emulation, the local APIC timer is used. While
+//! profiling, countdowns are cut short to the sampling period and each timer interrupt starts the
+//! next period. An interrupt cut short wakes the scheduler early if it's idle, which just starts
+//! the countdown again. Samples of code running with interrupts disabled are taken once they're
+//! enabled again, so they're attributed to whatever enabled them.
+
+use super::apic::local::LocalApicRegister;
+use super::clock::{self, tsc};
+use super::idt::InterruptFrame;
+use super::perf_counter::{self, Counter};
+use super::tls;
+use crate::profiler::{SampleRing, USER_SAMPLE};
+use alloc::boxed::Box;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// How samples are being taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    PerformanceCounter,
+    ApicTimer,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PerformanceCounter => write!(f, "the cycle counter"),
+            Self::ApicTimer => write!(f, "the APIC timer"),
+        }
+    }
+}
+
+static COUNTER_ENABLED: AtomicBool = AtomicBool::new(false);
+static COUNTER_PERIOD_CYCLES: AtomicU64 = AtomicU64::new(0);
+/// APIC timer ticks per sample while sampling with the timer, otherwise 0.
+static TIMER_PERIOD_TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the current CPU's samples, if it's been profiled.
+pub fn samples() -> Option<&'static SampleRing> {
+    unsafe { (*tls::get()).profile_samples.get().map(|ring| &**ring) }
+}
+
+fn record(interrupt_frame: &InterruptFrame) {
+    let address = match interrupt_frame.code_segment & 3 {
+        0 => interrupt_frame.intruction_address,
+        _ => USER_SAMPLE,
+    };
+    if let Some(ring) = samples() {
+        ring.record(address);
+    }
+}
+
+/// Starts sampling the current CPU `rate_hz` times a second, discarding its previous samples.
+pub fn start(rate_hz: u32) -> Source {
+    stop();
+    let profile_samples = unsafe { &(*tls::get()).profile_samples };
+    match profile_samples.get() {
+        Some(ring) => ring.clear(),
+        None => _ = profile_samples.set(Box::new(SampleRing::new())),
+    }
+    match perf_counter::available(Counter::Profiler) {
+        true => {
+            let period_cycles =
+                (tsc::frequency_hz() / rate_hz as u64).clamp(1, perf_counter::MAX_PERIOD_CYCLES);
+            COUNTER_PERIOD_CYCLES.store(period_cycles, Ordering::Relaxed);
+            COUNTER_ENABLED.store(true, Ordering::Release);
+            unsafe { perf_counter::start(Counter::Profiler, period_cycles) };
+            Source::PerformanceCounter
+        }
+        false => {
+            let local_apic_tls = unsafe { &(*tls::get()).local_apic };
+            let period_us = 1_000_000 / rate_hz as usize;
+            let period_ticks =
+                local_apic_tls.timer_us_numerator * period_us / local_apic_tls.timer_us_denominator;
+            TIMER_PERIOD_TICKS.store(
+                period_ticks.clamp(1, u32::MAX as usize) as u32,
+                Ordering::Relaxed,
+            );
+            // Replaces any countdown in progress with the first period
+            clock::start_countdown_ns(0);
+            Source::ApicTimer
+        }
+    }
+}
+
+/// Stops sampling the current CPU, keeping its samples.
+pub fn stop() {
+    if COUNTER_ENABLED.swap(false, Ordering::Relaxed) {
+        perf_counter::stop(Counter::Profiler);
+    }
+    TIMER_PERIOD_TICKS.store(0, Ordering::Relaxed);
+}
+
+/// Takes a sample if the NMI was raised by the profiler's counter overflowing. Returns whether it
+/// was.
+pub fn counter_overflow(interrupt_frame: &InterruptFrame) -> bool {
+    if !COUNTER_ENABLED.load(Ordering::Acquire) || !perf_counter::overflowed(Counter::Profiler) {
+        return false;
+    }
+    record(interrupt_frame);
+    let period_cycles = COUNTER_PERIOD_CYCLES.load(Ordering::Relaxed);
+    unsafe { perf_counter::rearm(Counter::Profiler, period_cycles) };
+    true
+}
+
+/// Shortens an APIC timer countdown to the sampling period while sampling with the timer.
+pub fn limit_countdown(ticks: u32) -> u32 {
+    match TIMER_PERIOD_TICKS.load(Ordering::Relaxed) {
+        0 => ticks,
+        period_ticks => ticks.min(period_ticks),
+    }
+}
+
+/// Takes a sample and starts the next period, if sampling with the timer. Called by the APIC
+/// timer interrupt handler.
+pub unsafe fn timer_interrupt(interrupt_frame: &InterruptFrame) {
+    let period_ticks = TIMER_PERIOD_TICKS.load(Ordering::Relaxed);
+    if period_ticks == 0 {
+        return;
+    }
+    record(interrupt_frame);
+    if let Some(local_apic) = unsafe { (*tls::get_mut()).local_apic.apic.as_mut() } {
+        local_apic.write_register(LocalApicRegister::InitialCount, period_ticks);
+    }
+}
@@ -0,0 +1,231 @@
+//! Hardware protection of the kernel from user memory.
+//!
+//! SMEP stops the kernel executing user pages, SMAP stops it reading or writing them, and UMIP
+//! stops user code reading descriptor table addresses. Each is enabled in CR4 if the CPU supports
+//! it. With SMAP on, the kernel can only touch user memory while the AC flag is set, which is
+//! done by holding a `UserAccess` guard. Only `user_memory`'s copy functions should need one.
+//!
+//! Booting with `nosmep` or `nosmap` leaves the respective protection off, for firmware or
+//! bootloaders which map kernel memory as user accessible.
+//!
+//! Once booted, `protect_kernel_image` makes the kernel's own mappings W^X, using the flags of the
+//! program headers in the kernel ELF file: code becomes read-only, read-only data becomes
+//! read-only and no execute, and everything else becomes no execute. The init segment, holding
+//! the boot entry code and its data, is left writable and executable. The lower half identity
+//! mapping, which physical memory is still accessed through, becomes no execute apart from the
+//! null page, which is unmapped. Bootloaders which don't pass the kernel ELF file leave the
+//! mappings as they are.
+
+use super::cpuid::{self, Feature};
+use super::page_allocation;
+use super::paging::PAGE_SIZE;
+use crate::cmdline;
+use crate::debugging;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CR0_WRITE_PROTECT: u64 = 1 << 16;
+const CR4_UMIP: u64 = 1 << 11;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+/// Start of the higher half. Everything mapped below it in the kernel's page table is identity
+/// mapped.
+const HIGHER_HALF_START: usize = 0xFFFF_8000_0000_0000;
+
+const PROGRAM_HEADER_LOAD: u32 = 1;
+const SEGMENT_EXECUTE: u32 = 1 << 0;
+const SEGMENT_WRITE: u32 = 1 << 1;
+
+/// Whether SMAP is on, so STAC and CLAC are needed (and available) around user accesses.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables whichever of SMEP, SMAP and UMIP are supported and allowed by the command line.
+pub unsafe fn init() {
+    let config = cmdline::get();
+    let mut enable = 0;
+    if cpuid::cpu_has(Feature::Smep) && config.smep {
+        enable |= CR4_SMEP;
+    }
+    if cpuid::cpu_has(Feature::Smap) && config.smap {
+        enable |= CR4_SMAP;
+    }
+    if cpuid::cpu_has(Feature::Umip) {
+        enable |= CR4_UMIP;
+    }
+    unsafe {
+        asm!(
+            "mov {0}, cr4",
+            "or {0}, {1}",
+            "mov cr4, {0}",
+            out(reg) _,
+            in(reg) enable,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    SMAP_ENABLED.store(enable & CR4_SMAP != 0, Ordering::Relaxed);
+    log::debug!(
+        "SMEP {}, SMAP {}, UMIP {}",
+        on_off(enable & CR4_SMEP != 0),
+        on_off(enable & CR4_SMAP != 0),
+        on_off(enable & CR4_UMIP != 0),
+    );
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    match enabled {
+        true => "on",
+        false => "off",
+    }
+}
+
+/// Allows the kernel to access user memory until dropped. Guards mustn't be nested, and mustn't
+/// be held across anything that could switch threads.
+pub struct UserAccess {
+    /// Not `Send`, as the flag belongs to the CPU.
+    _marker: PhantomData<*const ()>,
+}
+
+impl UserAccess {
+    /// Sets the AC flag, allowing accesses to user pages with SMAP on.
+    ///
+    /// # Safety
+    ///
+    /// User memory accessed while the guard is held must already have been checked.
+    pub unsafe fn enter() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("stac", options(nomem, nostack)) };
+        }
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// A loadable segment of the kernel image.
+#[derive(Clone, Copy, Debug)]
+struct ImageSegment {
+    start: usize,
+    end: usize,
+    writable: bool,
+    executable: bool,
+}
+
+/// Returns the loadable segments in the program headers of `elf`.
+fn image_segments(elf: &[u8]) -> Option<Vec<ImageSegment>> {
+    let read = |offset: usize, len: usize| {
+        let bytes = elf.get(offset..offset + len)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | *byte as usize),
+        )
+    };
+    if elf.get(..4)? != b"\x7FELF" {
+        return None;
+    }
+    let program_headers_offset = read(0x20, 8)?;
+    let program_header_size = read(0x36, 2)?;
+    let num_program_headers = read(0x38, 2)?;
+    let mut segments = Vec::new();
+    for index in 0..num_program_headers {
+        let header = program_headers_offset + index * program_header_size;
+        if read(header, 4)? as u32 != PROGRAM_HEADER_LOAD {
+            continue;
+        }
+        let flags = read(header + 0x4, 4)? as u32;
+        let start = read(header + 0x10, 8)?;
+        segments.push(ImageSegment {
+            start,
+            end: start.checked_add(read(header + 0x28, 8)?)?,
+            writable: flags & SEGMENT_WRITE != 0,
+            executable: flags & SEGMENT_EXECUTE != 0,
+        });
+    }
+    Some(segments)
+}
+
+/// Makes the kernel image's mappings W^X and the identity mapping no execute, and unmaps the null
+/// page so null pointer accesses fault. Must be called once ACPI is initialised, as firmware
+/// structures can be read through the null page until then.
+pub unsafe fn protect_kernel_image() {
+    let segments = match debugging::KERNEL_ELF_FILE.get() {
+        Some(elf) => image_segments(elf).unwrap_or_else(|| {
+            log::warn!("Kernel ELF file has invalid program headers, leaving it unprotected");
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    let no_execute_supported = cpuid::cpu_has(Feature::Nx);
+    // The null page is identity mapped, so it can only be unmapped if frame 0 is never allocated
+    let first_frame_used = unsafe { page_allocation::memory_bitmap().get_slice_mut() }
+        .first()
+        .is_some_and(|byte| byte & 0x80 != 0);
+    let mut num_protected = 0;
+    let mut num_huge = 0;
+    let mut num_identity = 0;
+    unsafe {
+        // Needed for read-only pages to apply to the kernel as well
+        asm!(
+            "mov {0}, cr0",
+            "or {0}, {1}",
+            "mov cr0, {0}",
+            out(reg) _,
+            const CR0_WRITE_PROTECT,
+            options(nostack),
+        );
+        page_allocation::walk_mappings(|virtual_address, entry, size| {
+            if virtual_address == 0 && size == PAGE_SIZE && first_frame_used {
+                *entry = super::paging::PageTableEntry::ZERO;
+                asm!("invlpg [{}]", in(reg) virtual_address, options(nostack, preserves_flags));
+                return;
+            }
+            if virtual_address < HIGHER_HALF_START {
+                // Without the image's segments, the init segment could be anywhere in it
+                let overlaps_image = segments.is_empty()
+                    || segments.iter().any(|segment| {
+                        segment.start < virtual_address + size && virtual_address < segment.end
+                    });
+                if no_execute_supported && !overlaps_image {
+                    entry.set_no_execute(true);
+                    asm!("invlpg [{}]", in(reg) virtual_address, options(nostack, preserves_flags));
+                    num_identity += 1;
+                }
+                return;
+            }
+            let Some(segment) = segments.iter().find(|segment| {
+                segment.start <= virtual_address && virtual_address + size <= segment.end
+            }) else {
+                return;
+            };
+            if segment.writable && segment.executable {
+                return;
+            }
+            if size != PAGE_SIZE {
+                num_huge += 1;
+                return;
+            }
+            entry.set_writable(segment.writable);
+            entry.set_no_execute(!segment.executable && no_execute_supported);
+            asm!("invlpg [{}]", in(reg) virtual_address, options(nostack, preserves_flags));
+            num_protected += 1;
+        });
+    }
+    log::debug!(
+        "Protected {num_protected} kernel image pages from {} segments, \
+        {num_huge} huge page mappings left as they were",
+        segments.len(),
+    );
+    log::debug!("Made {num_identity} identity mappings no execute");
+}
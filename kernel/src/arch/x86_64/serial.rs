@@ -0,0 +1,103 @@
+//! Polled 16550 UART on COM1, used as the kernel shell's serial console.
+
+use super::port;
+use crate::sync::OnceFlag;
+use core::fmt;
+
+// Register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+/// Divisor latch registers overlay `DATA` and `INTERRUPT_ENABLE` while enabled.
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const LINE_CONTROL_8N1: u8 = 0x03;
+const LINE_CONTROL_DIVISOR_LATCH: u8 = 1 << 7;
+/// Enable and clear FIFOs, interrupt at 14 bytes.
+const FIFO_CONTROL_ENABLE: u8 = 0xC7;
+/// DTR and RTS set.
+const MODEM_CONTROL_READY: u8 = 0x03;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+/// Divisor of the 115200 Hz base clock.
+const BAUD_DIVISOR: u16 = 1;
+/// Gives up on a byte if the UART doesn't take it, so a stuck UART can't hang the kernel.
+const MAX_TRANSMIT_POLLS: usize = 100_000;
+
+static PRESENT: OnceFlag = OnceFlag::new();
+
+/// Sets up COM1 for 115200 baud 8N1 without interrupts. Returns whether it exists.
+pub unsafe fn init() -> bool {
+    unsafe {
+        // Nothing's there if the scratch register doesn't hold a value
+        write(SCRATCH, 0x5A);
+        if read(SCRATCH) != 0x5A {
+            return false;
+        }
+        write(INTERRUPT_ENABLE, 0);
+        write(LINE_CONTROL, LINE_CONTROL_DIVISOR_LATCH);
+        write(DIVISOR_LOW, BAUD_DIVISOR as u8);
+        write(DIVISOR_HIGH, (BAUD_DIVISOR >> 8) as u8);
+        write(LINE_CONTROL, LINE_CONTROL_8N1);
+        write(FIFO_CONTROL, FIFO_CONTROL_ENABLE);
+        write(MODEM_CONTROL, MODEM_CONTROL_READY);
+    }
+    PRESENT.set();
+    true
+}
+
+pub fn is_present() -> bool {
+    PRESENT.is_set()
+}
+
+/// Returns a received byte, if there is one.
+pub fn try_read_byte() -> Option<u8> {
+    if !is_present() {
+        return None;
+    }
+    unsafe { (read(LINE_STATUS) & LINE_STATUS_DATA_READY != 0).then(|| read(DATA)) }
+}
+
+/// Sends a byte, waiting for space in the transmit buffer.
+pub fn write_byte(byte: u8) {
+    if !is_present() {
+        return;
+    }
+    unsafe {
+        for _ in 0..MAX_TRANSMIT_POLLS {
+            if read(LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY != 0 {
+                write(DATA, byte);
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Writes text to the serial port, translating line endings.
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                write_byte(b'\r');
+            }
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+unsafe fn read(register: u16) -> u8 {
+    unsafe { port::read_byte(port::COM1 + register) }
+}
+
+unsafe fn write(register: u16, value: u8) {
+    unsafe { port::write_byte(port::COM1 + register, value) }
+}
@@ -1,10 +1,20 @@
+use super::gdt::KernelGdt;
+use super::msr::{Fmask, Lstar, Msr, Star};
+use super::process::RegisterStore;
+use crate::net::tcp;
+use crate::{file, io_ring, ipc, process, shared_memory, terminal, time_namespace, tunables};
+use core::mem::offset_of;
 use define_asm_symbol::export_asm_all;
 
 unsafe extern "C" {
     pub unsafe fn syscall_entrypoint();
 }
 
-#[derive(Clone, Copy)]
+/// Flags cleared on entering the kernel through `syscall`: trap, interrupt, direction and
+/// alignment check.
+const MASKED_FLAGS: u64 = 0x4_0700;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct SyscallError(pub usize);
 
@@ -12,20 +22,267 @@ impl SyscallError {
     pub const UNKNOWN_SYSCALL: SyscallError = SyscallError(0);
     pub const INVALID_ARGUMENT: SyscallError = SyscallError(1);
     pub const OUT_OF_MEMORY: SyscallError = SyscallError(2);
+    pub const NO_CHILDREN: SyscallError = SyscallError(3);
+    pub const WOULD_BLOCK: SyscallError = SyscallError(4);
+    pub const PEER_CLOSED: SyscallError = SyscallError(5);
+    pub const NOT_FOUND: SyscallError = SyscallError(6);
+    pub const BAD_DESCRIPTOR: SyscallError = SyscallError(7);
+    pub const CONNECTION_REFUSED: SyscallError = SyscallError(8);
+    pub const CONNECTION_RESET: SyscallError = SyscallError(9);
+    pub const TIMED_OUT: SyscallError = SyscallError(10);
+    pub const ADDRESS_IN_USE: SyscallError = SyscallError(11);
+    pub const NETWORK_UNREACHABLE: SyscallError = SyscallError(12);
+    pub const PERMISSION_DENIED: SyscallError = SyscallError(13);
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UNKNOWN_SYSCALL => "unknown syscall",
+            Self::INVALID_ARGUMENT => "invalid argument",
+            Self::OUT_OF_MEMORY => "out of memory",
+            Self::NO_CHILDREN => "no child processes",
+            Self::WOULD_BLOCK => "operation would block",
+            Self::PEER_CLOSED => "other endpoint closed",
+            Self::NOT_FOUND => "not found",
+            Self::BAD_DESCRIPTOR => "bad file descriptor",
+            Self::CONNECTION_REFUSED => "connection refused",
+            Self::CONNECTION_RESET => "connection reset",
+            Self::TIMED_OUT => "timed out",
+            Self::ADDRESS_IN_USE => "address in use",
+            Self::NETWORK_UNREACHABLE => "network unreachable",
+            Self::PERMISSION_DENIED => "permission denied",
+            _ => "unknown error",
+        }
+    }
+
+    /// Encodes a system call's result as returned to user code, with errors as `-(error + 1)`.
+    pub fn encode(result: Result<usize, SyscallError>) -> usize {
+        match result {
+            Ok(value) => value,
+            Err(SyscallError(error)) => (error + 1).wrapping_neg(),
+        }
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(usize)]
 #[export_asm_all]
 pub enum SystemCall {
     SetBreak,
     MoveBreak,
-    // TODO Implement map_mem and unmap_mem, probably want a VMA scheme so we
-    // allocate random pages starting from the bottom of the stack growing
-    // down towards the heap.
-    // Library level heap allocators will likely request pages at specific
-    // addresses so we don't need to worry about those.
     MapMem,
     UnmapMem,
     Debug,
+    GetTunable,
+    SetTunable,
+    SetFont,
+    ProtectMem,
+    ResizeMem,
+    CreateShared,
+    OpenShared,
+    CloseShared,
+    MapShared,
+    MapFile,
+    SetupRing,
+    EnterRing,
+    CloseRing,
+    GetClock,
+    SetClockOffset,
+    Exit,
+    Wait,
+    CreateChannel,
+    CloseChannel,
+    SendMessage,
+    ReceiveMessage,
+    Open,
+    Close,
+    Read,
+    Write,
+    Seek,
+    SetTtyMode,
+    CreateSocket,
+    CloseSocket,
+    Bind,
+    Listen,
+    Accept,
+    Connect,
+    Send,
+    Receive,
+}
+
+impl SystemCall {
+    /// Every system call, indexed by number.
+    pub const ALL: [SystemCall; 40] = [
+        SystemCall::SetBreak,
+        SystemCall::MoveBreak,
+        SystemCall::MapMem,
+        SystemCall::UnmapMem,
+        SystemCall::Debug,
+        SystemCall::GetTunable,
+        SystemCall::SetTunable,
+        SystemCall::SetFont,
+        SystemCall::ProtectMem,
+        SystemCall::ResizeMem,
+        SystemCall::CreateShared,
+        SystemCall::OpenShared,
+        SystemCall::CloseShared,
+        SystemCall::MapShared,
+        SystemCall::MapFile,
+        SystemCall::SetupRing,
+        SystemCall::EnterRing,
+        SystemCall::CloseRing,
+        SystemCall::GetClock,
+        SystemCall::SetClockOffset,
+        SystemCall::Exit,
+        SystemCall::Wait,
+        SystemCall::CreateChannel,
+        SystemCall::CloseChannel,
+        SystemCall::SendMessage,
+        SystemCall::ReceiveMessage,
+        SystemCall::Open,
+        SystemCall::Close,
+        SystemCall::Read,
+        SystemCall::Write,
+        SystemCall::Seek,
+        SystemCall::SetTtyMode,
+        SystemCall::CreateSocket,
+        SystemCall::CloseSocket,
+        SystemCall::Bind,
+        SystemCall::Listen,
+        SystemCall::Accept,
+        SystemCall::Connect,
+        SystemCall::Send,
+        SystemCall::Receive,
+    ];
+
+    pub fn from_number(number: usize) -> Option<Self> {
+        Self::ALL.get(number).copied()
+    }
+}
+
+const _: () = {
+    let mut number = 0;
+    while number < SystemCall::ALL.len() {
+        assert!(SystemCall::ALL[number] as usize == number);
+        number += 1;
+    }
+};
+
+/// Points the `syscall` instruction at `syscall_entrypoint`.
+pub unsafe fn init() {
+    // `sysret` would load the user code segment from 16 past this, and the stack segment from 8
+    // past it. User mode is always entered with `iretq` at the moment.
+    let user_base = offset_of!(KernelGdt, user_code_32) as u64 | 3;
+    let kernel_base = offset_of!(KernelGdt, kernel_code) as u64;
+    unsafe {
+        Star::write((user_base << 48) | (kernel_base << 32));
+        Lstar::write(syscall_entrypoint as *const () as u64);
+        Fmask::write(MASKED_FLAGS);
+    }
+}
+
+/// Handles a system call made by user code, with its number and arguments in `registers`.
+/// The result is stored back into `registers`.
+pub fn dispatch(registers: &mut RegisterStore) {
+    let result = call(registers.syscall_number(), registers.syscall_args());
+    registers.set_syscall_result(result);
+}
+
+/// Runs system call `number` with `args` on behalf of the current process. Failures are recorded
+/// against the process.
+pub fn call(number: usize, args: [usize; 6]) -> Result<usize, SyscallError> {
+    let result = call_handler(number, args);
+    if let Err(err) = result {
+        process::record_syscall_error(number, args, err);
+    }
+    result
+}
+
+fn call_handler(number: usize, args: [usize; 6]) -> Result<usize, SyscallError> {
+    let [arg_1, arg_2, arg_3, arg_4, arg_5, arg_6] = args;
+    match number {
+        number if number == SystemCall::MapMem as usize => {
+            process::syscall_map_mem(arg_1, arg_2, arg_3, arg_4)
+        }
+        number if number == SystemCall::UnmapMem as usize => process::syscall_unmap_mem(arg_1),
+        number if number == SystemCall::ProtectMem as usize => {
+            process::syscall_protect_mem(arg_1, arg_2, arg_3)
+        }
+        number if number == SystemCall::ResizeMem as usize => {
+            process::syscall_resize_mem(arg_1, arg_2)
+        }
+        number if number == SystemCall::CreateShared as usize => {
+            shared_memory::syscall_create(arg_1 as *const u8, arg_2, arg_3)
+        }
+        number if number == SystemCall::OpenShared as usize => {
+            shared_memory::syscall_open(arg_1 as *const u8, arg_2)
+        }
+        number if number == SystemCall::CloseShared as usize => shared_memory::syscall_close(arg_1),
+        number if number == SystemCall::MapShared as usize => {
+            process::syscall_map_shared(arg_1, arg_2, arg_3, arg_4)
+        }
+        number if number == SystemCall::MapFile as usize => {
+            process::syscall_map_file(arg_1 as *const u8, arg_2, arg_3, arg_4, arg_5, arg_6)
+        }
+        number if number == SystemCall::GetTunable as usize => {
+            tunables::syscall_get(arg_1 as *const u8, arg_2)
+        }
+        number if number == SystemCall::SetTunable as usize => {
+            tunables::syscall_set(arg_1 as *const u8, arg_2, arg_3)
+        }
+        number if number == SystemCall::SetFont as usize => {
+            terminal::syscall_set_font(arg_1 as *const u8, arg_2)
+        }
+        number if number == SystemCall::SetupRing as usize => io_ring::syscall_setup(arg_1, arg_2),
+        number if number == SystemCall::EnterRing as usize => io_ring::syscall_enter(arg_1, arg_2),
+        number if number == SystemCall::CloseRing as usize => io_ring::syscall_close(arg_1),
+        number if number == SystemCall::GetClock as usize => {
+            time_namespace::syscall_get_clock(arg_1)
+        }
+        number if number == SystemCall::SetClockOffset as usize => {
+            time_namespace::syscall_set_clock_offset(arg_1, arg_2)
+        }
+        number if number == SystemCall::Exit as usize => process::syscall_exit(arg_1),
+        number if number == SystemCall::Wait as usize => process::syscall_wait(arg_1, arg_2),
+        number if number == SystemCall::CreateChannel as usize => {
+            ipc::syscall_create(arg_1 as *mut [usize; 2])
+        }
+        number if number == SystemCall::CloseChannel as usize => ipc::syscall_close(arg_1),
+        number if number == SystemCall::SendMessage as usize => {
+            ipc::syscall_send(arg_1, arg_2 as *const u8, arg_3, arg_4, arg_5)
+        }
+        number if number == SystemCall::ReceiveMessage as usize => {
+            ipc::syscall_receive(arg_1, arg_2 as *mut u8, arg_3, arg_4 as *mut usize, arg_5)
+        }
+        number if number == SystemCall::Open as usize => {
+            file::syscall_open(arg_1 as *const u8, arg_2, arg_3)
+        }
+        number if number == SystemCall::Close as usize => file::syscall_close(arg_1),
+        number if number == SystemCall::Read as usize => {
+            file::syscall_read(arg_1, arg_2 as *mut u8, arg_3)
+        }
+        number if number == SystemCall::Write as usize => {
+            file::syscall_write(arg_1, arg_2 as *const u8, arg_3)
+        }
+        number if number == SystemCall::Seek as usize => {
+            file::syscall_seek(arg_1, arg_2 as isize, arg_3)
+        }
+        number if number == SystemCall::SetTtyMode as usize => {
+            file::syscall_set_tty_mode(arg_1, arg_2)
+        }
+        number if number == SystemCall::CreateSocket as usize => tcp::syscall_create(),
+        number if number == SystemCall::CloseSocket as usize => tcp::syscall_close(arg_1),
+        number if number == SystemCall::Bind as usize => tcp::syscall_bind(arg_1, arg_2),
+        number if number == SystemCall::Listen as usize => tcp::syscall_listen(arg_1, arg_2),
+        number if number == SystemCall::Accept as usize => tcp::syscall_accept(arg_1),
+        number if number == SystemCall::Connect as usize => {
+            tcp::syscall_connect(arg_1, arg_2, arg_3)
+        }
+        number if number == SystemCall::Send as usize => {
+            tcp::syscall_send(arg_1, arg_2 as *const u8, arg_3)
+        }
+        number if number == SystemCall::Receive as usize => {
+            tcp::syscall_receive(arg_1, arg_2 as *mut u8, arg_3)
+        }
+        _ => Err(SyscallError::UNKNOWN_SYSCALL),
+    }
 }
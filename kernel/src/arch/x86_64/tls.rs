@@ -2,17 +2,30 @@
 
 use super::apic::local::LocalApic;
 use super::idt::InterruptDescriptorTable;
-use super::{msr, page_allocation, define_asm_symbol};
+use super::interrupts::apic_errors::EventCounter;
+use super::msr::{GsBase, Msr};
+use super::page_allocation;
 use super::paging::PageTableEntry;
+use crate::init_state::{self, Subsystem};
+use crate::profiler::SampleRing;
+use crate::sync::OnceLock;
+use crate::trace::TraceRing;
+use alloc::boxed::Box;
 use core::mem::MaybeUninit;
 use core::ptr::NonNull;
 use define_asm_symbol::export_asm_all;
 
+#[export_asm_all]
 pub struct ThreadLocalStorage {
     pub self_pointer: NonNull<ThreadLocalStorage>,
     pub local_apic: LocalApicInfo,
     pub idt: InterruptDescriptorTable,
+    #[asm_fields(reason, exception_type, exception_error_code, page_fault_address)]
     pub yield_info: YieldInfo,
+    /// Allocated when the profiler is first started on the CPU.
+    pub profile_samples: OnceLock<Box<SampleRing>>,
+    /// Allocated when tracing is first started on the CPU.
+    pub trace_events: OnceLock<Box<TraceRing>>,
 }
 
 pub struct LocalApicInfo {
@@ -21,6 +34,8 @@ pub struct LocalApicInfo {
     pub timer_us_numerator: usize,
     pub timer_us_denominator: usize,
     pub interrupt_received: bool,
+    pub spurious_interrupts: EventCounter,
+    pub errors: EventCounter,
 }
 
 impl Default for LocalApicInfo {
@@ -31,6 +46,8 @@ impl Default for LocalApicInfo {
             timer_us_numerator: 1,
             timer_us_denominator: 1,
             interrupt_received: false,
+            spurious_interrupts: EventCounter::default(),
+            errors: EventCounter::default(),
         }
     }
 }
@@ -56,27 +73,6 @@ impl Default for YieldInfo {
     }
 }
 
-define_asm_symbol!(
-    "ThreadLocalStorage.yield_info.reason",
-    core::mem::offset_of!(ThreadLocalStorage, yield_info)
-        + core::mem::offset_of!(YieldInfo, reason),
-);
-define_asm_symbol!(
-    "ThreadLocalStorage.yield_info.exception_type",
-    core::mem::offset_of!(ThreadLocalStorage, yield_info)
-        + core::mem::offset_of!(YieldInfo, exception_type),
-);
-define_asm_symbol!(
-    "ThreadLocalStorage.yield_info.exception_error_code",
-    core::mem::offset_of!(ThreadLocalStorage, yield_info)
-        + core::mem::offset_of!(YieldInfo, exception_error_code),
-);
-define_asm_symbol!(
-    "ThreadLocalStorage.yield_info.page_fault_address",
-    core::mem::offset_of!(ThreadLocalStorage, yield_info)
-        + core::mem::offset_of!(YieldInfo, page_fault_address),
-);
-
 #[repr(u64)]
 #[export_asm_all]
 pub enum YieldReason {
@@ -87,6 +83,7 @@ pub enum YieldReason {
     Exception,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u64)]
 #[non_exhaustive]
 #[export_asm_all]
@@ -125,6 +122,7 @@ unsafe extern "C" {
 
 /// Initialises the thread local storage. Must only be called once.
 pub unsafe fn init() {
+    init_state::begin(Subsystem::ThreadLocalStorage);
     unsafe {
         let tls_size = core::mem::size_of::<ThreadLocalStorage>();
         let start_address = &raw const TLS as usize & !0xFFF;
@@ -133,14 +131,17 @@ pub unsafe fn init() {
                 .expect("failed to allocate thread local storage");
             log::debug!("Allocated TLS page at {address:#x}");
         }
-        TLS = ThreadLocalStorage {
+        (&raw mut TLS).write(ThreadLocalStorage {
             self_pointer: NonNull::new(&raw mut TLS).unwrap(),
             local_apic: Default::default(),
             idt: InterruptDescriptorTable::new(),
             yield_info: Default::default(),
-        };
-        msr::write(msr::GS_BASE, &raw const TLS as u64);
+            profile_samples: OnceLock::new(),
+            trace_events: OnceLock::new(),
+        });
+        GsBase::write(&raw const TLS as u64);
     }
+    init_state::finish(Subsystem::ThreadLocalStorage);
 }
 
 /// Returns a pointer to the thread local storage.
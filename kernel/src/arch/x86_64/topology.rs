@@ -0,0 +1,108 @@
+//! CPU topology, published as an immutable snapshot so that interrupt and scheduling paths can
+//! translate between APIC IDs and CPU indices without taking locks.
+//!
+//! Readers load the current snapshot through an atomic pointer. Changes (such as future CPU
+//! hotplug) build a whole new snapshot and swap it in. Replaced snapshots are leaked rather than
+//! freed, as there's no grace period tracking to know when the last reader has finished with one,
+//! and replacement is rare enough that the leak doesn't matter.
+
+use super::platform::acpi::table::{Madt, MadtEntry};
+use crate::init_state::{self, Subsystem};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+static CURRENT: AtomicPtr<Topology> = AtomicPtr::new(ptr::null_mut());
+
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const MADT_LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cpu {
+    pub acpi_processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the CPU is running kernel code, rather than just present.
+    pub online: bool,
+}
+
+#[derive(Debug)]
+pub struct Topology {
+    cpus: Vec<Cpu>,
+    /// CPU index for each APIC ID, or `u8::MAX` if there's no CPU with that ID.
+    apic_id_to_index: [u8; 256],
+}
+
+impl Topology {
+    /// Builds the topology from the MADT's usable processors. Only the CPU with `bsp_apic_id` is
+    /// marked online.
+    pub unsafe fn from_madt(madt: &Madt, bsp_apic_id: u8) -> Self {
+        let cpus = unsafe { madt.entry_iter() }
+            .filter_map(|entry| match entry {
+                MadtEntry::LocalApic {
+                    acpi_processor_id,
+                    apic_id,
+                    flags,
+                } if flags & (MADT_LOCAL_APIC_ENABLED | MADT_LOCAL_APIC_ONLINE_CAPABLE) != 0 => {
+                    Some(Cpu {
+                        acpi_processor_id,
+                        apic_id,
+                        online: apic_id == bsp_apic_id,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        Self::new(cpus)
+    }
+
+    pub fn new(cpus: Vec<Cpu>) -> Self {
+        assert!(cpus.len() < u8::MAX as usize, "too many CPUs");
+        let mut apic_id_to_index = [u8::MAX; 256];
+        for (index, cpu) in cpus.iter().enumerate() {
+            apic_id_to_index[cpu.apic_id as usize] = index as u8;
+        }
+        Self {
+            cpus,
+            apic_id_to_index,
+        }
+    }
+
+    pub fn cpus(&self) -> &[Cpu] {
+        &self.cpus
+    }
+
+    pub fn online_cpus(&self) -> impl Iterator<Item = &Cpu> {
+        self.cpus.iter().filter(|cpu| cpu.online)
+    }
+
+    pub fn cpu_index(&self, apic_id: u8) -> Option<usize> {
+        match self.apic_id_to_index[apic_id as usize] {
+            u8::MAX => None,
+            index => Some(index as usize),
+        }
+    }
+}
+
+/// Returns the current topology snapshot, or `None` before one has been published.
+#[inline]
+pub fn current() -> Option<&'static Topology> {
+    unsafe { CURRENT.load(Ordering::Acquire).as_ref() }
+}
+
+/// Replaces the current topology snapshot. Readers of the old snapshot are unaffected.
+pub fn publish(topology: Topology) {
+    let new = Box::into_raw(Box::new(topology));
+    // Leaked, see module documentation
+    _ = CURRENT.swap(new, Ordering::AcqRel);
+}
+
+/// Returns the index of the CPU this is running on, or `None` before the topology is known.
+#[inline]
+pub fn current_cpu_index() -> Option<usize> {
+    if !init_state::is_initialised(Subsystem::ThreadLocalStorage) {
+        return None;
+    }
+    let apic_id = unsafe { (*super::tls::get()).local_apic.apic.as_ref()?.id() };
+    current()?.cpu_index(apic_id)
+}
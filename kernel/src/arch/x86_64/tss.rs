@@ -1,3 +1,6 @@
+use super::kthread::{self, StackAllocError};
+use crate::sync::OnceLock;
+
 #[repr(C, packed(4))]
 pub struct KernelTss {
     _reserved_1: u32,
@@ -24,7 +27,8 @@ pub struct InterruptStacks {
     pub double_fault: *const u8,
     pub page_fault: *const u8,
     pub general_protection_fault: *const u8,
-    _unused: [u64; 3],
+    pub non_maskable_interrupt: *const u8,
+    _unused: [u64; 2],
 }
 
 unsafe impl Sync for InterruptStacks {}
@@ -44,6 +48,8 @@ impl Stack {
     }
 }
 
+// Left as `static mut`s, as they're written to by the CPU. Only their addresses are taken.
+// Only used until `init_guarded_stacks` replaces them.
 mod stacks {
     use super::Stack;
     // Interrupt stacks
@@ -51,11 +57,15 @@ mod stacks {
     pub static mut DOUBLE_FAULT: Stack = Stack::empty();
     pub static mut PAGE_FAULT: Stack = Stack::empty();
     pub static mut GENERAL_PROTECTION_FAULT: Stack = Stack::empty();
+    pub static mut NON_MASKABLE_INTERRUPT: Stack = Stack::empty();
     // Privileged stacks
     pub static mut SYSTEM_CALL_STACK: Stack = Stack::empty();
 }
 
-pub static KERNEL_TSS: KernelTss = KernelTss {
+// Left as a `static mut`, as the user mode entry code updates the stack in
+// `privilege_stack_table`. Named for the entry code.
+#[unsafe(no_mangle)]
+pub static mut KERNEL_TSS: KernelTss = KernelTss {
     privilege_stack_table: PrivilegeStacks {
         system_call: Stack::get_end_address(&raw const stacks::SYSTEM_CALL_STACK),
         _unused: [0; 2],
@@ -67,7 +77,8 @@ pub static KERNEL_TSS: KernelTss = KernelTss {
         general_protection_fault: Stack::get_end_address(
             &raw const stacks::GENERAL_PROTECTION_FAULT,
         ),
-        _unused: [0; 3],
+        non_maskable_interrupt: Stack::get_end_address(&raw const stacks::NON_MASKABLE_INTERRUPT),
+        _unused: [0; 2],
     },
     iopb_base: core::mem::offset_of!(KernelTss, iopb) as u16,
     iopb: IoPermissionBitmap([0xFF; 8192]),
@@ -79,3 +90,66 @@ pub static KERNEL_TSS: KernelTss = KernelTss {
 
 #[repr(transparent)]
 pub struct IoPermissionBitmap([u8; 8192]);
+
+// The user mode entry code accesses RSP0 at this offset
+const _: () = assert!(core::mem::offset_of!(KernelTss, privilege_stack_table) == 4);
+
+/// An interrupt stack allocated by `init_guarded_stacks`.
+#[derive(Debug)]
+pub struct GuardedStack {
+    pub name: &'static str,
+    pub stack: kthread::Stack,
+}
+
+static GUARDED_STACKS: OnceLock<[GuardedStack; 5]> = OnceLock::new();
+
+/// Replaces the interrupt stacks with ones allocated like kernel thread stacks, with unmapped
+/// space below them, so that overflowing one faults instead of corrupting the kernel image. Does
+/// nothing if already done.
+pub unsafe fn init_guarded_stacks() -> Result<(), StackAllocError> {
+    if GUARDED_STACKS.get().is_some() {
+        return Ok(());
+    }
+    let new_stack = |name| {
+        Ok::<_, StackAllocError>(GuardedStack {
+            name,
+            stack: kthread::Stack::new()?,
+        })
+    };
+    let stacks = [
+        new_stack("generic interrupt")?,
+        new_stack("double fault")?,
+        new_stack("page fault")?,
+        new_stack("general protection fault")?,
+        new_stack("non-maskable interrupt")?,
+    ];
+    let [
+        generic,
+        double_fault,
+        page_fault,
+        general_protection_fault,
+        non_maskable_interrupt,
+    ] = stacks
+        .each_ref()
+        .map(|stack| stack.stack.top() as *const u8);
+    unsafe {
+        let interrupt_stacks = &raw mut KERNEL_TSS.interrupt_stack_table;
+        (&raw mut (*interrupt_stacks).generic).write_unaligned(generic);
+        (&raw mut (*interrupt_stacks).double_fault).write_unaligned(double_fault);
+        (&raw mut (*interrupt_stacks).page_fault).write_unaligned(page_fault);
+        (&raw mut (*interrupt_stacks).general_protection_fault)
+            .write_unaligned(general_protection_fault);
+        (&raw mut (*interrupt_stacks).non_maskable_interrupt)
+            .write_unaligned(non_maskable_interrupt);
+    }
+    _ = GUARDED_STACKS.set(stacks);
+    Ok(())
+}
+
+/// Returns the interrupt stack whose guard area contains `address`.
+pub fn guarded_stack_overflowing_into(address: usize) -> Option<&'static GuardedStack> {
+    GUARDED_STACKS
+        .get()?
+        .iter()
+        .find(|stack| stack.stack.guard_contains(address))
+}
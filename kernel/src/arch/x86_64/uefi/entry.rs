@@ -0,0 +1,402 @@
+use super::{
+    BootServices, File, GraphicsOutput, Handle, LoadedImage, MemoryDescriptor, SimpleFileSystem,
+    Status, SystemTable, allocate_type, guid, memory_type, pixel_format,
+};
+use crate::arch::boot;
+use crate::arch::kernel_args;
+use crate::arch::page_allocation;
+use crate::arch::paging::PAGE_SIZE;
+use crate::logging;
+use crate::physical_block_allocator::{MaxCapacity, PageBox, PageVec, PhysicalBlockAllocator};
+use core::arch::{asm, global_asm};
+use core::ffi::c_void;
+
+global_asm!(include_str!("entry.s"), options(raw));
+
+/// Where `entry.s` maps the first 512 GiB of physical memory in the higher half, used for
+/// pointers to boot data so they stay valid once the lower half belongs to processes.
+const PHYSICAL_MAPPING_OFFSET: usize = 0xFFFF_8000_0000_0000;
+/// Memory below this is left alone, as firmware structures live there.
+const LOW_MEMORY_END: usize = 0x100000;
+/// Location of the initrd on the boot volume, the same as on the Limine and GRUB images.
+const INITRD_PATH: [u16; 18] = super::ucs2(b"\\boot\\initrd.cpio\0");
+/// Location of the kernel's symbol table on the boot volume, as the firmware loads a flat image
+/// with no ELF file to read symbols from.
+const SYMBOLS_PATH: [u16; 21] = super::ucs2(b"\\boot\\kernel.symbols\0");
+/// Extra memory map descriptors to leave room for, as allocating the map can add entries.
+const MEMORY_MAP_SLACK_DESCRIPTORS: usize = 8;
+
+unsafe extern "C" {
+    unsafe fn init64(kernel_args_ptr: core::ptr::NonNull<kernel_args::Args>) -> !;
+}
+
+/// GOP framebuffer information, copied out before exiting boot services.
+struct GopFramebuffer {
+    address: usize,
+    width: u32,
+    height: u32,
+    pixels_per_scan_line: u32,
+    color_format: kernel_args::ColorFormat,
+}
+
+/// Memory map owned by the stub, kept after boot services exit.
+struct MemoryMap {
+    buffer: &'static [u8],
+    descriptor_size: usize,
+}
+
+impl MemoryMap {
+    fn descriptors(&self) -> impl Iterator<Item = MemoryDescriptor> + Clone + '_ {
+        self.buffer
+            .chunks_exact(self.descriptor_size)
+            .map(|descriptor| unsafe {
+                (descriptor.as_ptr() as *const MemoryDescriptor).read_unaligned()
+            })
+    }
+
+    /// Returns the end address of the highest memory in the map.
+    fn mappable_bytes(&self) -> usize {
+        self.descriptors()
+            .map(|descriptor| {
+                descriptor.physical_start as usize + descriptor.number_of_pages as usize * PAGE_SIZE
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+unsafe fn allocate_pages(boot_services: &BootServices, bytes: usize) -> Result<usize, Status> {
+    unsafe {
+        let mut address = 0;
+        (boot_services.allocate_pages)(
+            allocate_type::ANY_PAGES,
+            memory_type::LOADER_DATA,
+            bytes.div_ceil(PAGE_SIZE).max(1),
+            &mut address,
+        )
+        .to_result()?;
+        Ok(address as usize)
+    }
+}
+
+unsafe fn handle_protocol<T>(
+    boot_services: &BootServices,
+    handle: Handle,
+    protocol: &super::Guid,
+) -> Result<*mut T, Status> {
+    unsafe {
+        let mut interface = core::ptr::null_mut();
+        (boot_services.handle_protocol)(handle, protocol, &mut interface).to_result()?;
+        Ok(interface as *mut T)
+    }
+}
+
+/// Reads a whole file from the volume the kernel was loaded from into newly allocated pages,
+/// returning the file's physical address and size.
+unsafe fn load_file(
+    boot_services: &BootServices,
+    device_handle: Handle,
+    path: &[u16],
+) -> Result<(usize, usize), Status> {
+    unsafe {
+        let file_system = handle_protocol::<SimpleFileSystem>(
+            boot_services,
+            device_handle,
+            &guid::SIMPLE_FILE_SYSTEM,
+        )?;
+        let mut root: *mut File = core::ptr::null_mut();
+        ((*file_system).open_volume)(file_system, &mut root).to_result()?;
+        let mut file: *mut File = core::ptr::null_mut();
+        let open_status = ((*root).open)(root, &mut file, path.as_ptr(), super::FILE_MODE_READ, 0);
+        ((*root).close)(root);
+        open_status.to_result()?;
+        let result = (|| {
+            // Seeking to the maximum position moves to the end of the file
+            let mut size = 0;
+            ((*file).set_position)(file, u64::MAX).to_result()?;
+            ((*file).get_position)(file, &mut size).to_result()?;
+            ((*file).set_position)(file, 0).to_result()?;
+            let address = allocate_pages(boot_services, size as usize)?;
+            let mut read_size = size as usize;
+            ((*file).read)(file, &mut read_size, address as *mut c_void).to_result()?;
+            Ok((address, read_size))
+        })();
+        ((*file).close)(file);
+        result
+    }
+}
+
+/// Copies the image's load options into newly allocated memory as ASCII, for use as the kernel
+/// command line. Characters outside of ASCII are replaced with `?`.
+unsafe fn load_options(
+    boot_services: &BootServices,
+    loaded_image: &LoadedImage,
+) -> Result<kernel_args::Slice<u8>, Status> {
+    unsafe {
+        if loaded_image.load_options.is_null() {
+            return Ok(kernel_args::Slice::null());
+        }
+        let options = core::slice::from_raw_parts(
+            loaded_image.load_options,
+            loaded_image.load_options_size as usize / 2,
+        );
+        let len = options
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(options.len());
+        if len == 0 {
+            return Ok(kernel_args::Slice::null());
+        }
+        let address = allocate_pages(boot_services, len)?;
+        let cmdline = core::slice::from_raw_parts_mut(address as *mut u8, len);
+        for (out, &c) in cmdline.iter_mut().zip(options) {
+            *out = match c {
+                0..0x80 => c as u8,
+                _ => b'?',
+            };
+        }
+        Ok(kernel_args::Slice {
+            ptr: (PHYSICAL_MAPPING_OFFSET + address) as *const u8,
+            len,
+        })
+    }
+}
+
+unsafe fn find_framebuffer(boot_services: &BootServices) -> Option<GopFramebuffer> {
+    unsafe {
+        let mut gop: *mut c_void = core::ptr::null_mut();
+        if let Err(status) =
+            (boot_services.locate_protocol)(&guid::GRAPHICS_OUTPUT, core::ptr::null_mut(), &mut gop)
+                .to_result()
+        {
+            log::info!("Firmware provided no graphics output - {status:?}");
+            return None;
+        }
+        let mode = &*(*(gop as *const GraphicsOutput)).mode;
+        let info = &*mode.info;
+        let color_format = match info.pixel_format {
+            pixel_format::RGB_RESERVED_8 => kernel_args::ColorFormat::Rgbr8,
+            pixel_format::BGR_RESERVED_8 => kernel_args::ColorFormat::Bgrr8,
+            pixel_format::BIT_MASK => {
+                let masks = info.pixel_information;
+                kernel_args::ColorFormat::Bitmask(kernel_args::ColorBitmask {
+                    red_mask: masks.red_mask,
+                    green_mask: masks.green_mask,
+                    blue_mask: masks.blue_mask,
+                    reserved_mask: masks.reserved_mask,
+                })
+            }
+            pixel_format::BLT_ONLY => {
+                log::info!("Graphics output has no framebuffer, ignoring");
+                return None;
+            }
+            format => {
+                log::warn!("Skipping framebuffer because of unknown pixel format {format}");
+                return None;
+            }
+        };
+        Some(GopFramebuffer {
+            address: mode.frame_buffer_base as usize,
+            width: info.horizontal_resolution,
+            height: info.vertical_resolution,
+            pixels_per_scan_line: info.pixels_per_scan_line,
+            color_format,
+        })
+    }
+}
+
+fn find_rsdp(system_table: &SystemTable) -> Option<core::ptr::NonNull<()>> {
+    let tables = unsafe { system_table.configuration_table() };
+    // Both tables contain a copy of the RSDP, preferring the newer one
+    [guid::ACPI_20_TABLE, guid::ACPI_10_TABLE]
+        .iter()
+        .find_map(|guid| tables.iter().find(|table| table.vendor_guid == *guid))
+        .and_then(|table| core::ptr::NonNull::new(table.vendor_table as *mut ()))
+}
+
+/// Allocates the memory bitmap, then gets the final memory map and exits boot services.
+unsafe fn exit_boot_services(
+    image_handle: Handle,
+    boot_services: &BootServices,
+) -> Result<(MemoryMap, &'static mut [u8]), Status> {
+    unsafe {
+        let mut map_size = 0;
+        let mut map_key = 0;
+        let mut descriptor_size = 0;
+        let mut descriptor_version = 0;
+        let status = (boot_services.get_memory_map)(
+            &mut map_size,
+            core::ptr::null_mut(),
+            &mut map_key,
+            &mut descriptor_size,
+            &mut descriptor_version,
+        );
+        if status != Status::BUFFER_TOO_SMALL {
+            status.to_result()?;
+        }
+        // Allocations below add entries to the memory map, so leave some room
+        let capacity = map_size + MEMORY_MAP_SLACK_DESCRIPTORS * descriptor_size;
+        let buffer = allocate_pages(boot_services, capacity)? as *mut u8;
+        let get_memory_map = || {
+            let mut map_size = capacity;
+            let mut map_key = 0;
+            let mut descriptor_size = 0;
+            let mut descriptor_version = 0;
+            (boot_services.get_memory_map)(
+                &mut map_size,
+                buffer,
+                &mut map_key,
+                &mut descriptor_size,
+                &mut descriptor_version,
+            )
+            .to_result()?;
+            let memory_map = MemoryMap {
+                buffer: core::slice::from_raw_parts(buffer, map_size),
+                descriptor_size,
+            };
+            Ok((memory_map, map_key))
+        };
+        let mappable_bytes = get_memory_map()?.0.mappable_bytes();
+        let bitmap_size = boot::memory_bitmap_size(mappable_bytes);
+        let bitmap_address = allocate_pages(boot_services, bitmap_size)?;
+        // The map key goes stale if anything allocates in between, so try again if that happens
+        let (mut memory_map, mut map_key) = get_memory_map()?;
+        let mut status = (boot_services.exit_boot_services)(image_handle, map_key);
+        if status == Status::INVALID_PARAMETER {
+            (memory_map, map_key) = get_memory_map()?;
+            status = (boot_services.exit_boot_services)(image_handle, map_key);
+        }
+        status.to_result()?;
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_address as *mut u8, bitmap_size);
+        Ok((memory_map, bitmap))
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn uefi_entry(image_handle: Handle, system_table: *const SystemTable) -> ! {
+    unsafe {
+        // SSE is already enabled by the firmware
+        crate::arch::debug_output::init_writers();
+        logging::init_wrapper();
+        _ = logging::CURRENT_LOGGER.write().replace(&boot::LOGGER);
+        let system_table = &*system_table;
+        let boot_services = &*system_table.boot_services;
+        let loaded_image =
+            &*handle_protocol::<LoadedImage>(boot_services, image_handle, &guid::LOADED_IMAGE)
+                .unwrap_or_else(|status| {
+                    panic!("failed to get loaded image protocol - {status:?}");
+                });
+        // Kernel command line, passed on as the environment
+        let environment = load_options(boot_services, loaded_image).unwrap_or_else(|status| {
+            panic!("failed to copy load options - {status:?}");
+        });
+        // Get initrd file
+        let (initrd_address, initrd_len) =
+            load_file(boot_services, loaded_image.device_handle, &INITRD_PATH).unwrap_or_else(
+                |status| {
+                    panic!("failed to load initrd from \\boot\\initrd.cpio - {status:?}");
+                },
+            );
+        // Get symbol table file, which is only needed for backtraces
+        let kernel_symbols =
+            match load_file(boot_services, loaded_image.device_handle, &SYMBOLS_PATH) {
+                Ok((address, len)) => kernel_args::Slice {
+                    ptr: (PHYSICAL_MAPPING_OFFSET + address) as *const u8,
+                    len,
+                },
+                Err(status) => {
+                    log::warn!("Failed to load \\boot\\kernel.symbols - {status:?}");
+                    kernel_args::Slice::null()
+                }
+            };
+        let gop_framebuffer = find_framebuffer(boot_services);
+        let acpi_ptr = find_rsdp(system_table);
+        let (memory_map, kernel_bitmap) = exit_boot_services(image_handle, boot_services)
+            .unwrap_or_else(|status| {
+                panic!("failed to exit boot services - {status:?}");
+            });
+        // Firmware interrupt handlers can't be relied on from here
+        asm!("cli", options(nomem, nostack));
+        // Initialise page allocator, only freeing conventional memory. Boot services memory is
+        // left reserved, as the lower levels of the page tables still belong to the firmware.
+        let mappable_bytes = memory_map.mappable_bytes();
+        {
+            kernel_bitmap.fill(0xFF);
+            for descriptor in memory_map.descriptors() {
+                if descriptor.memory_type == memory_type::CONVENTIONAL {
+                    boot::mark_memory_range(
+                        kernel_bitmap,
+                        descriptor.physical_start as usize,
+                        descriptor.number_of_pages as usize * PAGE_SIZE,
+                        false,
+                    );
+                }
+            }
+            boot::mark_memory_range(kernel_bitmap, 0, LOW_MEMORY_END, true);
+            log::debug!(
+                "Allocated kernel memory bitmap - ptr: {:p}, len: {:#x}",
+                kernel_bitmap,
+                kernel_bitmap.len(),
+            );
+            let page_table_address = {
+                let mut address: usize;
+                asm!("mov {}, cr3", out(reg) address, options(nomem, nostack));
+                address
+            };
+            page_allocation::init(page_table_address, kernel_bitmap, mappable_bytes / 4096);
+        }
+        // Allocate framebuffers
+        let framebuffers = match gop_framebuffer {
+            Some(fb) => {
+                let mut framebuffers = PageVec::new_with_max_capacity();
+                framebuffers.push(kernel_args::Framebuffer {
+                    ptr: core::ptr::NonNull::new((PHYSICAL_MAPPING_OFFSET + fb.address) as *mut u8)
+                        .unwrap(),
+                    ptr_type: kernel_args::PtrType::Linear,
+                    // GOP framebuffers are always 32 bits per pixel
+                    size: fb.pixels_per_scan_line * fb.height * 4,
+                    width: fb.width,
+                    height: fb.height,
+                    pitch: fb.pixels_per_scan_line * 4,
+                    bits_per_pixel: 32,
+                    color_format: fb.color_format,
+                });
+                let framebuffers_slice = framebuffers.leak();
+                kernel_args::Slice {
+                    ptr: framebuffers_slice.as_ptr(),
+                    len: framebuffers_slice.len(),
+                }
+            }
+            None => kernel_args::Slice::null(),
+        };
+        // Write kernel arguments
+        let kernel_args_ptr = PageBox::new_in(
+            kernel_args::Args {
+                // The firmware loads a flat image, so there's no ELF file
+                kernel_elf: kernel_args::Slice::null(),
+                kernel_symbols,
+                page_table_address: page_allocation::page_table_address(),
+                environment,
+                memory_bitmap: kernel_args::MemoryBitmap {
+                    slice: page_allocation::memory_bitmap(),
+                    mapped_size: mappable_bytes,
+                },
+                initrd: kernel_args::Slice {
+                    ptr: (PHYSICAL_MAPPING_OFFSET + initrd_address) as *const u8,
+                    len: initrd_len,
+                },
+                arch_ptrs: kernel_args::ArchPointers {
+                    efi_ptr: system_table as *const SystemTable as usize,
+                    acpi_ptr,
+                    smbi_ptr: 0,
+                    mp_ptr: 0,
+                },
+                framebuffers,
+            },
+            PhysicalBlockAllocator,
+        );
+        page_allocation::deinit_and_remove().unwrap();
+        // Call kernel init64 entry point
+        init64(PageBox::leak(kernel_args_ptr).into());
+    }
+}
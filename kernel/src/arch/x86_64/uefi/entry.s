@@ -0,0 +1,168 @@
+// PE/COFF header and entry point for booting directly from UEFI firmware.
+//
+// The header sits at the very start of the image, so the flat binary made by
+// `objcopy -O binary` is a valid EFI application. The image has no relocations, so the firmware
+// must load it at its physical load address, where the whole file is mapped 1:1 by RVA.
+//
+// The firmware enters in long mode with everything identity mapped. The firmware's page tables
+// are copied and the kernel is mapped into the higher half, keeping the identity mapping so
+// boot services can still be used from `uefi_entry`.
+
+// Must match `KERNEL_LOAD_ADDRESS` in the linker script
+.set KERNEL_LOAD_ADDRESS, 0x200000
+
+.set PE_HEADER_SIZE, 0x1000
+.set PE_MACHINE_X86_64, 0x8664
+// Relocations stripped, executable image, large address aware
+.set PE_CHARACTERISTICS, 0x0023
+.set PE_OPTIONAL_HEADER_MAGIC, 0x20B
+.set PE_SUBSYSTEM_EFI_APPLICATION, 10
+// Code, initialised data, executable, readable, writable
+.set PE_SECTION_CHARACTERISTICS, 0xE0000060
+
+.set PAGE_PRESENT_WRITABLE, 0x3
+.set PAGE_PRESENT_WRITABLE_HUGE, 0x83
+.set HUGE_PAGE_SIZE, 0x200000
+
+// -- PE Header --
+.section ".uefi_pe_header", "a"
+.align 4096
+uefi_image_start:
+    // DOS header, only the magic and PE header offset are used
+    .ascii "MZ"
+    .org uefi_image_start + 0x3C
+    .long uefi_pe_header - uefi_image_start
+uefi_pe_header:
+    .ascii "PE\0\0"
+    // COFF header
+    .word PE_MACHINE_X86_64
+    .word 1
+    .long 0
+    .long 0
+    .long 0
+    .word uefi_section_table - uefi_optional_header
+    .word PE_CHARACTERISTICS
+uefi_optional_header:
+    .word PE_OPTIONAL_HEADER_MAGIC
+    .byte 0
+    .byte 0
+    .long UEFI_FILE_SIZE - PE_HEADER_SIZE
+    .long 0
+    .long 0
+    .long UEFI_ENTRY_RVA
+    .long PE_HEADER_SIZE
+    .quad KERNEL_LOAD_ADDRESS
+    // Section and file alignment
+    .long 0x1000
+    .long 0x1000
+    // OS, image and subsystem versions
+    .word 0
+    .word 0
+    .word 0
+    .word 0
+    .word 0
+    .word 0
+    .long 0
+    .long UEFI_IMAGE_SIZE
+    .long PE_HEADER_SIZE
+    .long 0
+    .word PE_SUBSYSTEM_EFI_APPLICATION
+    .word 0
+    // Stack and heap reserve and commit sizes
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad 0
+    .long 0
+    // Data directories, all empty
+    .long 16
+    .fill 16, 8, 0
+uefi_section_table:
+    // A single section covering the whole image after the header
+    .ascii ".text\0\0\0"
+    .long UEFI_IMAGE_SIZE - PE_HEADER_SIZE
+    .long PE_HEADER_SIZE
+    .long UEFI_FILE_SIZE - PE_HEADER_SIZE
+    .long PE_HEADER_SIZE
+    .long 0
+    .long 0
+    .word 0
+    .word 0
+    .long PE_SECTION_CHARACTERISTICS
+    .org uefi_image_start + PE_HEADER_SIZE
+
+// -- Bootstrap Page Tables and Stack --
+// Zeroed by the firmware's image loader. The PML4 is a copy of the firmware's, with the first
+// 512 GiB also mapped at 0xFFFF800000000000 and the top 1 GiB mapping the kernel image.
+.section ".bss.uefi", "aw", @nobits
+.align 4096
+UEFI_PML4:
+    .skip 4096
+UEFI_PDPT_HIGH:
+    .skip 4096
+UEFI_PD_HIGH:
+    .skip 4096
+UEFI_STACK:
+    .skip 0x10000
+UEFI_STACK_TOP:
+
+// -- Entry --
+// Called with the Microsoft x64 calling convention, with the image handle in rcx and the system
+// table in rdx. Runs at the physical load address until the higher half is mapped, so only RIP
+// relative addressing is used.
+.section ".init.uefi", "ax"
+.code64
+.global uefi_entry_low
+.type uefi_entry_low, @function
+uefi_entry_low:
+    cld
+    mov r12, rcx
+    mov r13, rdx
+
+    // -- Page table setup --
+    // Copy the firmware's PML4
+    mov rsi, cr3
+    and rsi, ~0xFFF
+    lea rdi, [rip + UEFI_PML4]
+    mov ecx, 512
+    rep movsq
+    lea rdi, [rip + UEFI_PML4]
+    mov rax, [rdi]
+    mov [rdi + 256 * 8], rax
+    lea rax, [rip + UEFI_PDPT_HIGH]
+    or rax, PAGE_PRESENT_WRITABLE
+    mov [rdi + 511 * 8], rax
+    // Map the kernel image at the start of the top 1 GiB
+    lea rax, [rip + UEFI_PD_HIGH]
+    or rax, PAGE_PRESENT_WRITABLE
+    mov [rip + UEFI_PDPT_HIGH + 510 * 8], rax
+    lea rdi, [rip + UEFI_PD_HIGH]
+    mov eax, KERNEL_LOAD_ADDRESS + PAGE_PRESENT_WRITABLE_HUGE
+    xor ecx, ecx
+.Lfill_pd_high:
+    mov [rdi + rcx * 8], rax
+    add rax, HUGE_PAGE_SIZE
+    inc ecx
+    cmp ecx, 512
+    jne .Lfill_pd_high
+    lea rax, [rip + UEFI_PML4]
+    mov cr3, rax
+
+    // -- Jump to higher half --
+    mov rax, offset uefi_entry_high
+    jmp rax
+uefi_entry_high:
+    mov rsp, offset UEFI_STACK_TOP
+    xor ebp, ebp
+    mov rdi, r12
+    mov rsi, r13
+    mov rax, offset uefi_entry
+    call rax
+.Lhalt:
+    hlt
+    jmp .Lhalt
+
+.size uefi_entry_low, . - uefi_entry_low
+
+// Revert back to default section, as this get inlined in Rust code
+.text
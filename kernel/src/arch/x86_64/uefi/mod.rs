@@ -0,0 +1,300 @@
+//! Minimal UEFI definitions, for booting 9x directly from firmware without a bootloader.
+//!
+//! Only the parts of the specification used by `entry` are defined. Unused function pointers
+//! are kept as `usize` so the tables have the right layout.
+
+use core::ffi::c_void;
+
+pub mod entry;
+
+pub type Handle = *mut c_void;
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Status(pub usize);
+
+impl Status {
+    const ERROR_BIT: usize = 1 << (usize::BITS - 1);
+
+    pub const SUCCESS: Self = Self(0);
+    pub const INVALID_PARAMETER: Self = Self(Self::ERROR_BIT | 2);
+    pub const BUFFER_TOO_SMALL: Self = Self(Self::ERROR_BIT | 5);
+
+    pub fn is_error(self) -> bool {
+        self.0 & Self::ERROR_BIT != 0
+    }
+
+    pub fn to_result(self) -> Result<(), Status> {
+        match self.is_error() {
+            true => Err(self),
+            false => Ok(()),
+        }
+    }
+}
+
+impl core::fmt::Debug for Status {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Status({:#x})", self.0)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+pub mod guid {
+    use super::Guid;
+
+    pub const LOADED_IMAGE: Guid = Guid(
+        0x5B1B31A1,
+        0x9562,
+        0x11D2,
+        [0x8E, 0x3F, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+    );
+    pub const SIMPLE_FILE_SYSTEM: Guid = Guid(
+        0x964E5B22,
+        0x6459,
+        0x11D2,
+        [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+    );
+    pub const GRAPHICS_OUTPUT: Guid = Guid(
+        0x9042A9DE,
+        0x23DC,
+        0x4A38,
+        [0x96, 0xFB, 0x7A, 0xDE, 0xD0, 0x80, 0x51, 0x6A],
+    );
+    pub const ACPI_10_TABLE: Guid = Guid(
+        0xEB9D2D30,
+        0x2D88,
+        0x11D3,
+        [0x9A, 0x16, 0x00, 0x90, 0x27, 0x3F, 0xC1, 0x4D],
+    );
+    pub const ACPI_20_TABLE: Guid = Guid(
+        0x8868E871,
+        0xE4F1,
+        0x11D3,
+        [0xBC, 0x22, 0x00, 0x80, 0xC7, 0x3C, 0x88, 0x81],
+    );
+}
+
+pub mod memory_type {
+    pub const LOADER_DATA: u32 = 2;
+    pub const CONVENTIONAL: u32 = 7;
+}
+
+pub mod allocate_type {
+    pub const ANY_PAGES: u32 = 0;
+}
+
+pub mod pixel_format {
+    pub const RGB_RESERVED_8: u32 = 0;
+    pub const BGR_RESERVED_8: u32 = 1;
+    pub const BIT_MASK: u32 = 2;
+    pub const BLT_ONLY: u32 = 3;
+}
+
+pub const FILE_MODE_READ: u64 = 1;
+
+/// Converts an ASCII string to UCS-2 at compile time.
+pub const fn ucs2<const N: usize>(string: &[u8; N]) -> [u16; N] {
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = string[i] as u16;
+        i += 1;
+    }
+    out
+}
+
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    pub header: TableHeader,
+    pub firmware_vendor: *const u16,
+    pub firmware_revision: u32,
+    pub console_in_handle: Handle,
+    pub con_in: usize,
+    pub console_out_handle: Handle,
+    pub con_out: usize,
+    pub standard_error_handle: Handle,
+    pub std_err: usize,
+    pub runtime_services: usize,
+    pub boot_services: *const BootServices,
+    pub number_of_table_entries: usize,
+    pub configuration_table: *const ConfigurationTable,
+}
+
+impl SystemTable {
+    pub unsafe fn configuration_table(&self) -> &[ConfigurationTable] {
+        unsafe {
+            core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
+        }
+    }
+}
+
+#[repr(C)]
+pub struct ConfigurationTable {
+    pub vendor_guid: Guid,
+    pub vendor_table: *mut c_void,
+}
+
+#[repr(C)]
+pub struct BootServices {
+    pub header: TableHeader,
+    pub raise_tpl: usize,
+    pub restore_tpl: usize,
+    pub allocate_pages: unsafe extern "efiapi" fn(
+        allocate_type: u32,
+        memory_type: u32,
+        pages: usize,
+        memory: *mut u64,
+    ) -> Status,
+    pub free_pages: usize,
+    pub get_memory_map: unsafe extern "efiapi" fn(
+        memory_map_size: *mut usize,
+        memory_map: *mut u8,
+        map_key: *mut usize,
+        descriptor_size: *mut usize,
+        descriptor_version: *mut u32,
+    ) -> Status,
+    pub allocate_pool: usize,
+    pub free_pool: usize,
+    pub create_event: usize,
+    pub set_timer: usize,
+    pub wait_for_event: usize,
+    pub signal_event: usize,
+    pub close_event: usize,
+    pub check_event: usize,
+    pub install_protocol_interface: usize,
+    pub reinstall_protocol_interface: usize,
+    pub uninstall_protocol_interface: usize,
+    pub handle_protocol: unsafe extern "efiapi" fn(
+        handle: Handle,
+        protocol: *const Guid,
+        interface: *mut *mut c_void,
+    ) -> Status,
+    pub reserved: usize,
+    pub register_protocol_notify: usize,
+    pub locate_handle: usize,
+    pub locate_device_path: usize,
+    pub install_configuration_table: usize,
+    pub load_image: usize,
+    pub start_image: usize,
+    pub exit: usize,
+    pub unload_image: usize,
+    pub exit_boot_services:
+        unsafe extern "efiapi" fn(image_handle: Handle, map_key: usize) -> Status,
+    pub get_next_monotonic_count: usize,
+    pub stall: usize,
+    pub set_watchdog_timer: usize,
+    pub connect_controller: usize,
+    pub disconnect_controller: usize,
+    pub open_protocol: usize,
+    pub close_protocol: usize,
+    pub open_protocol_information: usize,
+    pub protocols_per_handle: usize,
+    pub locate_handle_buffer: usize,
+    pub locate_protocol: unsafe extern "efiapi" fn(
+        protocol: *const Guid,
+        registration: *mut c_void,
+        interface: *mut *mut c_void,
+    ) -> Status,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryDescriptor {
+    pub memory_type: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+#[repr(C)]
+pub struct LoadedImage {
+    pub revision: u32,
+    pub parent_handle: Handle,
+    pub system_table: *const SystemTable,
+    pub device_handle: Handle,
+    pub file_path: *mut c_void,
+    pub reserved: *mut c_void,
+    pub load_options_size: u32,
+    pub load_options: *const u16,
+    pub image_base: *mut c_void,
+    pub image_size: u64,
+    pub image_code_type: u32,
+    pub image_data_type: u32,
+    pub unload: usize,
+}
+
+#[repr(C)]
+pub struct SimpleFileSystem {
+    pub revision: u64,
+    pub open_volume:
+        unsafe extern "efiapi" fn(this: *mut SimpleFileSystem, root: *mut *mut File) -> Status,
+}
+
+#[repr(C)]
+pub struct File {
+    pub revision: u64,
+    pub open: unsafe extern "efiapi" fn(
+        this: *mut File,
+        new_handle: *mut *mut File,
+        file_name: *const u16,
+        open_mode: u64,
+        attributes: u64,
+    ) -> Status,
+    pub close: unsafe extern "efiapi" fn(this: *mut File) -> Status,
+    pub delete: usize,
+    pub read:
+        unsafe extern "efiapi" fn(this: *mut File, size: *mut usize, buffer: *mut c_void) -> Status,
+    pub write: usize,
+    pub get_position: unsafe extern "efiapi" fn(this: *mut File, position: *mut u64) -> Status,
+    pub set_position: unsafe extern "efiapi" fn(this: *mut File, position: u64) -> Status,
+}
+
+#[repr(C)]
+pub struct GraphicsOutput {
+    pub query_mode: usize,
+    pub set_mode: usize,
+    pub blt: usize,
+    pub mode: *const GraphicsOutputMode,
+}
+
+#[repr(C)]
+pub struct GraphicsOutputMode {
+    pub max_mode: u32,
+    pub mode: u32,
+    pub info: *const GraphicsOutputModeInfo,
+    pub size_of_info: usize,
+    pub frame_buffer_base: u64,
+    pub frame_buffer_size: usize,
+}
+
+#[repr(C)]
+pub struct GraphicsOutputModeInfo {
+    pub version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: u32,
+    pub pixel_information: PixelBitmask,
+    pub pixels_per_scan_line: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PixelBitmask {
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+}
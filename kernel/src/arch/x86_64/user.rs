@@ -0,0 +1,45 @@
+//! Running user mode code on kernel threads.
+
+use super::process::RegisterStore;
+use super::tls::{ExceptionType, YieldInfo, YieldReason};
+use core::arch::global_asm;
+
+global_asm!(include_str!("user.s"), options(raw, att_syntax));
+
+unsafe extern "C" {
+    fn user_enter(registers: *mut RegisterStore, yield_info: *mut YieldInfo);
+}
+
+/// Why user code returned to the kernel.
+#[derive(Clone, Copy, Debug)]
+pub enum Exit {
+    SystemCall,
+    Exception {
+        exception_type: ExceptionType,
+        error_code: u64,
+        /// Address accessed, for page faults.
+        page_fault_address: usize,
+    },
+}
+
+/// Runs user code with `registers` on the current thread, until it makes a system call or
+/// causes an exception. `registers` is then updated with the user code's state.
+///
+/// # Safety
+///
+/// The current address space must be the one the user code belongs to.
+pub unsafe fn run(registers: &mut RegisterStore) -> Exit {
+    registers.sanitise_flags();
+    let mut yield_info = YieldInfo::default();
+    unsafe {
+        user_enter(registers, &mut yield_info);
+    }
+    match yield_info.reason {
+        YieldReason::Exception => Exit::Exception {
+            exception_type: unsafe { yield_info.exception_type.assume_init() },
+            error_code: yield_info.exception_error_code,
+            page_fault_address: yield_info.page_fault_address as usize,
+        },
+        _ => Exit::SystemCall,
+    }
+}
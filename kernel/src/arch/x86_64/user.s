@@ -0,0 +1,137 @@
+// x86_64 user mode entry and exit.
+//
+// While user code runs, RSP0 in the TSS points at the kernel stack of the thread running it,
+// just below where `user_enter` saved the kernel's state. The top two words there point at the
+// thread's `RegisterStore` and the `YieldInfo` to fill in on return. System calls and exceptions
+// from user mode save the user registers, then return from `user_enter`.
+
+// Offset of RSP0 in the TSS
+.set TSS_RSP0, 4
+
+.section ".text"
+
+// Enters user mode with the registers in the `RegisterStore` at rdi. Returns once user code makes
+// a system call or causes an exception, with the registers saved back and the `YieldInfo` at rsi
+// filled in.
+.global user_enter
+.type user_enter, @function
+user_enter:
+    pushfq
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    pushq %rsi
+    pushq %rdi
+    cli
+    movq %rsp, KERNEL_TSS+TSS_RSP0(%rip)
+    fxrstor RegisterStore.fxsave_area(%rdi)
+    // Interrupt frame for iretq
+    pushq $KernelGdt.user_data_64 + 3
+    pushq RegisterStore.rsp(%rdi)
+    pushq RegisterStore.rflags(%rdi)
+    pushq $KernelGdt.user_code_64 + 3
+    pushq RegisterStore.rip(%rdi)
+    movq RegisterStore.rax(%rdi), %rax
+    movq RegisterStore.rbx(%rdi), %rbx
+    movq RegisterStore.rcx(%rdi), %rcx
+    movq RegisterStore.rdx(%rdi), %rdx
+    movq RegisterStore.rsi(%rdi), %rsi
+    movq RegisterStore.rbp(%rdi), %rbp
+    movq RegisterStore.r8(%rdi), %r8
+    movq RegisterStore.r9(%rdi), %r9
+    movq RegisterStore.r10(%rdi), %r10
+    movq RegisterStore.r11(%rdi), %r11
+    movq RegisterStore.r12(%rdi), %r12
+    movq RegisterStore.r13(%rdi), %r13
+    movq RegisterStore.r14(%rdi), %r14
+    movq RegisterStore.r15(%rdi), %r15
+    movq RegisterStore.rdi(%rdi), %rdi
+    iretq
+.size user_enter, . - user_enter
+
+// Saves every general purpose register but rax to the `RegisterStore` at rax.
+.macro save_registers_to_rax
+    movq %rbx, RegisterStore.rbx(%rax)
+    movq %rcx, RegisterStore.rcx(%rax)
+    movq %rdx, RegisterStore.rdx(%rax)
+    movq %rsi, RegisterStore.rsi(%rax)
+    movq %rdi, RegisterStore.rdi(%rax)
+    movq %rbp, RegisterStore.rbp(%rax)
+    movq %r8, RegisterStore.r8(%rax)
+    movq %r9, RegisterStore.r9(%rax)
+    movq %r10, RegisterStore.r10(%rax)
+    movq %r11, RegisterStore.r11(%rax)
+    movq %r12, RegisterStore.r12(%rax)
+    movq %r13, RegisterStore.r13(%rax)
+    movq %r14, RegisterStore.r14(%rax)
+    movq %r15, RegisterStore.r15(%rax)
+    fxsave RegisterStore.fxsave_area(%rax)
+.endm
+
+// Entry point of the `syscall` instruction. rcx holds the user instruction pointer and r11 the
+// user flags, interrupts are masked by IA32_FMASK.
+.global syscall_entrypoint
+.type syscall_entrypoint, @function
+syscall_entrypoint:
+    // Switch to the kernel stack, leaving the user stack pointer in RSP0 for now
+    xchgq %rsp, KERNEL_TSS+TSS_RSP0(%rip)
+    pushq %rax
+    movq 8(%rsp), %rax
+    save_registers_to_rax
+    movq %rcx, RegisterStore.rip(%rax)
+    movq %r11, RegisterStore.rflags(%rax)
+    popq RegisterStore.rax(%rax)
+    movq KERNEL_TSS+TSS_RSP0(%rip), %rbx
+    movq %rbx, RegisterStore.rsp(%rax)
+    movq %rsp, KERNEL_TSS+TSS_RSP0(%rip)
+    movq 8(%rsp), %rcx
+    movq $YieldReason.SystemCallRequest, YieldInfo.reason(%rcx)
+    jmp user_return
+.size syscall_entrypoint, . - syscall_entrypoint
+
+// Entered by exception handlers for exceptions from user mode, on the exception's stack. Above
+// the interrupt frame are the exception's error code, or 0 if it has none, then its type.
+.global user_exception
+.type user_exception, @function
+user_exception:
+    cld
+    pushq %rax
+    movq KERNEL_TSS+TSS_RSP0(%rip), %rax
+    movq (%rax), %rax
+    save_registers_to_rax
+    popq RegisterStore.rax(%rax)
+    // Type, error code, then the interrupt frame
+    movq 16(%rsp), %rbx
+    movq %rbx, RegisterStore.rip(%rax)
+    movq 32(%rsp), %rbx
+    movq %rbx, RegisterStore.rflags(%rax)
+    movq 40(%rsp), %rbx
+    movq %rbx, RegisterStore.rsp(%rax)
+    movq KERNEL_TSS+TSS_RSP0(%rip), %rcx
+    movq 8(%rcx), %rcx
+    movq $YieldReason.Exception, YieldInfo.reason(%rcx)
+    movq (%rsp), %rbx
+    movq %rbx, YieldInfo.exception_type(%rcx)
+    movq 8(%rsp), %rbx
+    movq %rbx, YieldInfo.exception_error_code(%rcx)
+    movq %cr2, %rbx
+    movq %rbx, YieldInfo.page_fault_address(%rcx)
+    // The exception's stack is left behind, it's reset on the next exception
+    movq KERNEL_TSS+TSS_RSP0(%rip), %rsp
+    jmp user_return
+.size user_exception, . - user_exception
+
+// Returns from `user_enter`, with rsp where it was left in RSP0.
+user_return:
+    addq $16, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    popfq
+    ret
@@ -0,0 +1,197 @@
+//! Page watchpoints, a debug aid for finding what's writing to a physical page.
+//!
+//! Watching a page maps it read-only everywhere it's mapped in the kernel's page table. A write
+//! to it then page faults, and the fault handler records the writing instruction, makes the
+//! page writable and single steps the instruction. The debug exception after the step makes it
+//! read-only again, so every write is caught. Hits are logged from the work queue, as the writer
+//! may be holding logging locks.
+//!
+//! Only 4KiB mappings are watched, writes through huge page mappings aren't caught. Write
+//! protection is only enabled on the CPU the watchpoint is set from, and only its TLB is
+//! flushed, so boot with `nosmp` when using this.
+
+use super::page_allocation;
+use super::paging::{self, PAGE_SIZE, align_to_page};
+use crate::work_queue::{self, Work};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const NO_PAGE: usize = usize::MAX;
+/// Page fault error code bits for a write to a present page.
+const PRESENT_WRITE_ERROR: u64 = 0b11;
+const TRAP_FLAG: usize = 1 << 8;
+const INTERRUPT_FLAG: usize = 1 << 9;
+const CR0_WRITE_PROTECT: usize = 1 << 16;
+/// Single step bit of DR6.
+const DR6_SINGLE_STEP: usize = 1 << 14;
+const MAX_PENDING_HITS: usize = 16;
+
+/// Physical address of the watched page, or `NO_PAGE`.
+static WATCHED_PAGE: AtomicUsize = AtomicUsize::new(NO_PAGE);
+/// Virtual address of the page made writable for single stepping a write, or `NO_PAGE`.
+static STEPPING_PAGE: AtomicUsize = AtomicUsize::new(NO_PAGE);
+/// Whether interrupts were enabled before single stepping, as they're disabled for the step.
+static STEP_INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Ring of (instruction address, access address) of writes not yet logged.
+static HITS: [(AtomicUsize, AtomicUsize); MAX_PENDING_HITS] =
+    [const { (AtomicUsize::new(0), AtomicUsize::new(0)) }; _];
+static HITS_RECORDED: AtomicUsize = AtomicUsize::new(0);
+static HITS_LOGGED: AtomicUsize = AtomicUsize::new(0);
+static LOG_WORK: Work = Work::new(log_hits, 0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum WatchError {
+    #[error("a page is already being watched")]
+    AlreadyWatching,
+    #[error("page isn't mapped writable by any 4KiB mapping")]
+    NotMapped,
+}
+
+/// Starts trapping writes to the physical page containing `physical_address`.
+pub fn watch(physical_address: usize) -> Result<(), WatchError> {
+    let physical_page = align_to_page(physical_address);
+    if WATCHED_PAGE
+        .compare_exchange(NO_PAGE, physical_page, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return Err(WatchError::AlreadyWatching);
+    }
+    let mut num_watched = 0;
+    let mut num_huge = 0;
+    unsafe {
+        asm!(
+            "mov {0}, cr0",
+            "or {0}, {1}",
+            "mov cr0, {0}",
+            out(reg) _,
+            const CR0_WRITE_PROTECT,
+            options(nostack),
+        );
+        page_allocation::walk_mappings(|virtual_address, entry, size| {
+            if !(entry.address()..entry.address() + size).contains(&physical_page) {
+                return;
+            }
+            if size != PAGE_SIZE {
+                num_huge += 1;
+            } else if entry.writable() {
+                entry.set_writable(false);
+                entry.set_watched(true);
+                asm!("invlpg [{}]", in(reg) virtual_address, options(nostack, preserves_flags));
+                num_watched += 1;
+            }
+        });
+    }
+    if num_watched == 0 {
+        WATCHED_PAGE.store(NO_PAGE, Ordering::Relaxed);
+        return Err(WatchError::NotMapped);
+    }
+    log::info!(
+        "Watching writes to page {physical_page:#x} through {num_watched} mappings, \
+        {num_huge} huge page mappings can't be watched",
+    );
+    Ok(())
+}
+
+/// Stops trapping writes to the watched page, making it writable again.
+pub fn unwatch() {
+    if WATCHED_PAGE.swap(NO_PAGE, Ordering::Relaxed) == NO_PAGE {
+        return;
+    }
+    unsafe {
+        page_allocation::walk_mappings(|virtual_address, entry, _| {
+            if entry.watched() {
+                entry.set_watched(false);
+                entry.set_writable(true);
+                asm!("invlpg [{}]", in(reg) virtual_address, options(nostack, preserves_flags));
+            }
+        });
+    }
+}
+
+/// Handles a page fault on a watched page, recording the write and single stepping it. Returns
+/// whether the fault was caused by the watchpoint, and so can be resumed from.
+pub unsafe fn handle_page_fault(
+    error_code: u64,
+    access_address: usize,
+    instruction_address: usize,
+    cpu_flags: &mut usize,
+) -> bool {
+    if error_code & PRESENT_WRITE_ERROR != PRESENT_WRITE_ERROR {
+        return false;
+    }
+    let Some(entry) = (unsafe { paging::find_entry(current_page_table(), access_address) }) else {
+        return false;
+    };
+    if !entry.watched() {
+        return false;
+    }
+    let hit = HITS_RECORDED.fetch_add(1, Ordering::Relaxed) % MAX_PENDING_HITS;
+    HITS[hit].0.store(instruction_address, Ordering::Relaxed);
+    HITS[hit].1.store(access_address, Ordering::Relaxed);
+    work_queue::schedule(&LOG_WORK);
+    // Let the write through, then protect the page again from the debug exception
+    entry.set_writable(true);
+    unsafe {
+        asm!("invlpg [{}]", in(reg) access_address, options(nostack, preserves_flags));
+    }
+    STEPPING_PAGE.store(align_to_page(access_address), Ordering::Relaxed);
+    STEP_INTERRUPTS_ENABLED.store(*cpu_flags & INTERRUPT_FLAG != 0, Ordering::Relaxed);
+    *cpu_flags = (*cpu_flags | TRAP_FLAG) & !INTERRUPT_FLAG;
+    true
+}
+
+/// Handles the debug exception after single stepping a write, making the page read-only again.
+/// Returns whether the exception was caused by the watchpoint, and so can be resumed from.
+pub unsafe fn handle_debug_exception(cpu_flags: &mut usize) -> bool {
+    let dr6: usize;
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags));
+    }
+    if dr6 & DR6_SINGLE_STEP == 0 {
+        return false;
+    }
+    let page = STEPPING_PAGE.swap(NO_PAGE, Ordering::Relaxed);
+    if page == NO_PAGE {
+        return false;
+    }
+    unsafe {
+        asm!("mov dr6, {}", in(reg) 0usize, options(nomem, nostack, preserves_flags));
+        // Skipped if the page was unwatched during the step
+        if let Some(entry) = paging::find_entry(current_page_table(), page)
+            && entry.watched()
+        {
+            entry.set_writable(false);
+            asm!("invlpg [{}]", in(reg) page, options(nostack, preserves_flags));
+        }
+    }
+    *cpu_flags &= !TRAP_FLAG;
+    if STEP_INTERRUPTS_ENABLED.load(Ordering::Relaxed) {
+        *cpu_flags |= INTERRUPT_FLAG;
+    }
+    true
+}
+
+fn current_page_table() -> usize {
+    let cr3: usize;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    cr3 & 0x000F_FFFF_FFFF_F000
+}
+
+fn log_hits(_: usize) {
+    let recorded = HITS_RECORDED.load(Ordering::Relaxed);
+    let logged = HITS_LOGGED.swap(recorded, Ordering::Relaxed);
+    let missed = (recorded - logged).saturating_sub(MAX_PENDING_HITS);
+    if missed != 0 {
+        log::warn!("Page watchpoint - {missed} writes not logged");
+    }
+    for hit in logged + missed..recorded {
+        let (instruction_address, access_address) = &HITS[hit % MAX_PENDING_HITS];
+        log::warn!(
+            "Page watchpoint - write to {:#x} by instruction at {:#x}",
+            access_address.load(Ordering::Relaxed),
+            instruction_address.load(Ordering::Relaxed),
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! Kernel micro-benchmarks.
+//!
+//! Benchmarks run at boot, and from the kernel shell's `bench` command. Results are reported as a
+//! single `BENCH key=value ...` line so that runs can be collected and compared between builds.
+//! Times are averages in nanoseconds. Other threads running alongside, as they may be when run
+//! from the shell, make them slower.
+
+use crate::arch::interrupts::{self, ipi};
+use crate::arch::paging::PAGE_SIZE;
+use crate::arch::syscall::SystemCall;
+use crate::arch::{self, clock, page_allocation, topology};
+use crate::kthread;
+use crate::process::{self, PROTECTION_EXECUTE, PROTECTION_READ, PROTECTION_WRITE, Process};
+use crate::time_namespace::CLOCK_MONOTONIC;
+use crate::user_memory;
+use crate::vma;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const PAGE_BATCH_SIZE: usize = 256;
+const PAGE_BATCHES: usize = 16;
+const VMA_SEGMENTS: usize = 1024;
+const CONTEXT_SWITCHES: usize = 10_000;
+const MEMCPY_SIZE: usize = 1024 * 1024;
+const MEMCPY_ITERATIONS: usize = 64;
+const SYSCALLS: u32 = 10_000;
+const IPIS: usize = 1000;
+/// How long to wait for each IPI before giving up.
+const IPI_TIMEOUT_NS: u64 = 100_000_000;
+
+const SYSCALL_CODE_LEN: usize = 30;
+/// Offset of the exit system call in `syscall_code`.
+const SYSCALL_CODE_EXIT_OFFSET: usize = 21;
+
+struct Results {
+    entries: Vec<(&'static str, u64)>,
+}
+
+impl Results {
+    fn add(&mut self, name: &'static str, value: u64) {
+        log::debug!("Benchmark {name}: {value}");
+        self.entries.push((name, value));
+    }
+}
+
+/// Runs every benchmark, returning the results line. Must be called from a kernel thread.
+pub fn run_all() -> String {
+    log::info!("Running benchmarks...");
+    let mut results = Results {
+        entries: Vec::new(),
+    };
+    match page_alloc_free() {
+        Some(ns) => results.add("page_alloc_free_ns", ns),
+        None => log::warn!("Page allocation benchmark ran out of memory"),
+    }
+    match vma::bench_insert_delete(VMA_SEGMENTS) {
+        Ok((insert_ns, delete_ns)) => {
+            results.add("vma_insert_ns", insert_ns);
+            results.add("vma_delete_ns", delete_ns);
+        }
+        Err(_) => log::warn!("VMA benchmark ran out of memory"),
+    }
+    match vma::bench_find_gap(VMA_SEGMENTS) {
+        Ok(ns) => results.add("vma_find_gap_ns", ns),
+        Err(_) => log::warn!("VMA gap search benchmark ran out of memory"),
+    }
+    match context_switch() {
+        Ok(ns) => results.add("context_switch_ns", ns),
+        Err(err) => log::warn!("Context switch benchmark failed - {err}"),
+    }
+    results.add("memcpy_mib_per_s", memcpy_bandwidth());
+    match syscall_round_trip() {
+        Ok(ns) => results.add("syscall_ns", ns),
+        Err(err) => log::warn!("System call benchmark failed - {err}"),
+    }
+    match ipi_round_trip() {
+        Ok(ns) => results.add("ipi_ns", ns),
+        Err(err) => log::warn!("IPI benchmark failed - {err}"),
+    }
+    let mut line = String::from("BENCH");
+    for (name, value) in results.entries.iter() {
+        _ = write!(line, " {name}={value}");
+    }
+    line
+}
+
+fn elapsed_per(start_ns: u64, count: usize) -> u64 {
+    (clock::now_ns() - start_ns) / count as u64
+}
+
+/// Average time to reserve and free a physical page.
+fn page_alloc_free() -> Option<u64> {
+    let mut pages = Vec::with_capacity(PAGE_BATCH_SIZE);
+    let start = clock::now_ns();
+    for _ in 0..PAGE_BATCHES {
+        for _ in 0..PAGE_BATCH_SIZE {
+            pages.push(page_allocation::find_and_reserve_page().ok()?);
+        }
+        // Pages are freed on drop
+        pages.clear();
+    }
+    Some(elapsed_per(start, PAGE_BATCHES * PAGE_BATCH_SIZE))
+}
+
+static PARTNER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+fn context_switch_partner(num_yields: usize) -> usize {
+    PARTNER_RUNNING.store(true, Ordering::Release);
+    for _ in 0..num_yields {
+        kthread::yield_now();
+    }
+    0
+}
+
+/// Average time for a yield to switch to another thread.
+fn context_switch() -> Result<u64, kthread::SpawnError> {
+    PARTNER_RUNNING.store(false, Ordering::Release);
+    let partner = kthread::spawn("bench_partner", context_switch_partner, CONTEXT_SWITCHES)?;
+    while !PARTNER_RUNNING.load(Ordering::Acquire) {
+        kthread::yield_now();
+    }
+    // Both threads yield to each other, so each yield is one switch
+    let start = clock::now_ns();
+    for _ in 0..CONTEXT_SWITCHES {
+        kthread::yield_now();
+    }
+    let result = elapsed_per(start, CONTEXT_SWITCHES * 2);
+    partner.join();
+    Ok(result)
+}
+
+/// Copy bandwidth between two heap buffers, in MiB per second.
+fn memcpy_bandwidth() -> u64 {
+    let source = vec![0xA5u8; MEMCPY_SIZE];
+    let mut destination = vec![0u8; MEMCPY_SIZE];
+    let start = clock::now_ns();
+    for _ in 0..MEMCPY_ITERATIONS {
+        destination.copy_from_slice(core::hint::black_box(&source));
+        core::hint::black_box(&mut destination);
+    }
+    let elapsed_ns = u64::max(clock::now_ns() - start, 1);
+    let total_bytes = (MEMCPY_SIZE * MEMCPY_ITERATIONS) as u64;
+    total_bytes * 1_000_000_000 / elapsed_ns / (1024 * 1024)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+enum BenchError {
+    #[error("failed to create process")]
+    ProcessCreate,
+    #[error("failed to load user code")]
+    LoadCode,
+    #[error("failed to spawn thread - {0}")]
+    Spawn(#[from] kthread::SpawnError),
+    #[error("failed to register interrupt handler - {0}")]
+    Handler(#[from] interrupts::HandlerError),
+    #[error("failed to send IPI - {0}")]
+    Ipi(#[from] ipi::IpiError),
+    #[error("IPI never arrived")]
+    IpiTimedOut,
+}
+
+/// User code making `iterations` get clock system calls, then exiting. Starting from
+/// `SYSCALL_CODE_EXIT_OFFSET` only exits.
+fn syscall_code(iterations: u32) -> [u8; SYSCALL_CODE_LEN] {
+    let [i0, i1, i2, i3] = iterations.to_le_bytes();
+    let [c0, c1, c2, c3] = (SystemCall::GetClock as u32).to_le_bytes();
+    let [e0, e1, e2, e3] = (SystemCall::Exit as u32).to_le_bytes();
+    let clock_id = CLOCK_MONOTONIC as u8;
+    [
+        0xBB, i0, i1, i2, i3, // mov ebx, iterations
+        0xB8, c0, c1, c2, c3, // mov eax, GetClock
+        0xBF, clock_id, 0, 0, 0, // mov edi, CLOCK_MONOTONIC
+        0x0F, 0x05, // syscall
+        0xFF, 0xCB, // dec ebx
+        0x75, 0xF0, // jnz to mov eax, GetClock
+        0xB8, e0, e1, e2, e3, // mov eax, Exit
+        0x0F, 0x05, // syscall
+        0x0F, 0x0B, // ud2, as exit doesn't return
+    ]
+}
+
+/// Maps the code pointed to by `code` into the current process, returning its address, or 0 if
+/// it couldn't be.
+fn load_user_code(code: usize) -> usize {
+    let code = unsafe { &*(code as *const [u8; SYSCALL_CODE_LEN]) };
+    let result = process::syscall_map_mem(0, PAGE_SIZE, PROTECTION_READ | PROTECTION_WRITE, 0)
+        .and_then(|address| {
+            user_memory::copy_to_user(address, code)?;
+            process::syscall_protect_mem(address, PAGE_SIZE, PROTECTION_READ | PROTECTION_EXECUTE)?;
+            Ok(address)
+        });
+    result.unwrap_or(0)
+}
+
+/// Time taken to run `code` from `offset` in a new process, until it exits.
+fn time_user_code(code: &[u8; SYSCALL_CODE_LEN], offset: usize) -> Result<u64, BenchError> {
+    let process = Arc::new(Process::new().map_err(|_| BenchError::ProcessCreate)?);
+    let address = kthread::spawn_for_process(
+        "bench_loader",
+        process.clone(),
+        load_user_code,
+        code as *const _ as usize,
+    )?
+    .join();
+    if address == 0 {
+        return Err(BenchError::LoadCode);
+    }
+    let start = clock::now_ns();
+    // There's no stack, as the code doesn't use one
+    kthread::spawn_user("bench_user", process, address + offset, 0)?.join();
+    Ok(clock::now_ns() - start)
+}
+
+/// Average time for a system call from user code to return. Process startup and exit are timed
+/// on their own and taken off.
+fn syscall_round_trip() -> Result<u64, BenchError> {
+    let code = syscall_code(SYSCALLS);
+    let overhead_ns = time_user_code(&code, SYSCALL_CODE_EXIT_OFFSET)?;
+    let total_ns = time_user_code(&code, 0)?;
+    Ok(total_ns.saturating_sub(overhead_ns) / SYSCALLS as u64)
+}
+
+static IPIS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+/// Average time for an IPI to reach its handler. Only the boot CPU runs, so it sends them to
+/// itself, and halts until each one arrives, as kernel threads run with interrupts disabled.
+fn ipi_round_trip() -> Result<u64, BenchError> {
+    let cpu_index = topology::current_cpu_index().ok_or(ipi::IpiError::NoTopology)?;
+    let vector = interrupts::allocate_handler(|| {
+        IPIS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+    })?;
+    let result = time_ipis(cpu_index, vector);
+    _ = interrupts::unregister_handler(vector);
+    result
+}
+
+fn time_ipis(cpu_index: usize, vector: u8) -> Result<u64, BenchError> {
+    let start = clock::now_ns();
+    for _ in 0..IPIS {
+        let received = IPIS_RECEIVED.load(Ordering::Relaxed);
+        let sent_ns = clock::now_ns();
+        ipi::send(cpu_index, vector)?;
+        while IPIS_RECEIVED.load(Ordering::Relaxed) == received {
+            if clock::now_ns() - sent_ns > IPI_TIMEOUT_NS {
+                return Err(BenchError::IpiTimedOut);
+            }
+            // Other interrupts may arrive first
+            arch::kthread::wait_for_interrupt();
+        }
+    }
+    Ok(elapsed_per(start, IPIS))
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn ipi_round_trip_returns_a_time() -> TestResult {
+        ktest_assert_eq!(ipi_round_trip().map(|_| ()), Ok(()));
+        Ok(())
+    }
+}
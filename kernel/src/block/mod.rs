@@ -0,0 +1,74 @@
+//! Block devices, storage addressed in fixed size blocks.
+//!
+//! Drivers register their devices by name, and filesystems look them up with `get`. Reads and
+//! writes cover whole blocks, so buffers must be a multiple of the block size long.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub mod nvme;
+pub mod ramdisk;
+
+static DEVICES: Mutex<Vec<(&'static str, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BlockError {
+    #[error("block out of range")]
+    OutOfRange,
+    #[error("buffer length not a multiple of the block size")]
+    UnalignedLength,
+    #[error("out of memory")]
+    OutOfMemory,
+    #[error("device error")]
+    DeviceError,
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Size of each block in bytes.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Reads blocks into `buffer`, starting from block `start`.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `data` to blocks starting from block `start`.
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Checks that `len` bytes from block `start` are whole blocks within `device`, returning the
+/// number of blocks.
+pub fn check_range(device: &dyn BlockDevice, start: u64, len: usize) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(BlockError::UnalignedLength);
+    }
+    let num_blocks = (len / device.block_size()) as u64;
+    match start.checked_add(num_blocks) {
+        Some(end) if end <= device.num_blocks() => Ok(num_blocks),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Makes `device` available under `name`. Names must be unique.
+pub fn register(name: &'static str, device: Arc<dyn BlockDevice>) {
+    let mut devices = DEVICES.lock();
+    assert!(
+        devices.iter().all(|(existing, _)| *existing != name),
+        "block device {name} registered twice",
+    );
+    log::info!(
+        "Block device {name}: {} blocks of {} bytes",
+        device.num_blocks(),
+        device.block_size(),
+    );
+    devices.push((name, device));
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(existing, _)| *existing == name)
+        .map(|(_, device)| device.clone())
+}
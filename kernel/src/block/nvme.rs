@@ -0,0 +1,657 @@
+//! Driver for NVMe controllers on PCI.
+//!
+//! Each controller gets an admin queue pair and a single I/O queue pair, with their queues and
+//! a bounce page for each I/O command in one physically contiguous allocation. Admin commands are
+//! only sent while setting the controller up, and are polled for. I/O commands raise an MSI-X
+//! interrupt on completion, which wakes threads waiting for their commands, and whichever thread
+//! takes the queue lock next collects every new completion.
+//!
+//! Transfers go through the bounce pages, a page at a time, so buffers don't have to be
+//! physically contiguous. Each active namespace is registered as a block device named
+//! `nvme<controller>n<namespace>`.
+
+use super::{BlockDevice, BlockError};
+use crate::arch::clock;
+use crate::arch::msi::{self, AllocatedVector, MsiError};
+use crate::arch::page_allocation;
+use crate::arch::paging::{PAGE_SIZE, PageTableEntry};
+use crate::arch::pci::{self, PciAddress};
+use crate::wait_queue::WaitQueue;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Mass storage controller, non-volatile memory subclass, NVMe interface.
+const CLASS_NVME: u32 = 0x01_08_02;
+
+const ADMIN_QUEUE_LEN: u16 = 16;
+const IO_QUEUE_LEN: u16 = 16;
+const IO_QUEUE_ID: u16 = 1;
+const ADMIN_TIMEOUT_NS: u64 = 1_000_000_000;
+/// Units of `CAP.TO`, the longest the controller takes to become ready.
+const READY_TIMEOUT_UNIT_NS: u64 = 500_000_000;
+
+/// Pages of each controller's DMA memory, in order.
+const ADMIN_SUBMISSION_PAGE: usize = 0;
+const ADMIN_COMPLETION_PAGE: usize = 1;
+const IO_SUBMISSION_PAGE: usize = 2;
+const IO_COMPLETION_PAGE: usize = 3;
+/// Receives data from identify commands.
+const IDENTIFY_PAGE: usize = 4;
+/// First of the bounce pages, one for each I/O command ID.
+const BOUNCE_PAGE: usize = 5;
+const NUM_DMA_PAGES: usize = BOUNCE_PAGE + IO_QUEUE_LEN as usize;
+
+mod register {
+    pub const CAPABILITIES: usize = 0x00;
+    pub const VERSION: usize = 0x08;
+    pub const CONFIGURATION: usize = 0x14;
+    pub const STATUS: usize = 0x1C;
+    pub const ADMIN_QUEUE_ATTRIBUTES: usize = 0x24;
+    pub const ADMIN_SUBMISSION_QUEUE: usize = 0x28;
+    pub const ADMIN_COMPLETION_QUEUE: usize = 0x30;
+    pub const DOORBELLS: usize = 0x1000;
+
+    pub const CONFIGURATION_ENABLE: u32 = 1 << 0;
+    /// Log2 sizes of submission and completion queue entries.
+    pub const CONFIGURATION_ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+
+    pub const STATUS_READY: u32 = 1 << 0;
+    pub const STATUS_FATAL: u32 = 1 << 1;
+}
+
+mod opcode {
+    pub const ADMIN_CREATE_SUBMISSION_QUEUE: u8 = 0x01;
+    pub const ADMIN_CREATE_COMPLETION_QUEUE: u8 = 0x05;
+    pub const ADMIN_IDENTIFY: u8 = 0x06;
+    pub const ADMIN_SET_FEATURES: u8 = 0x09;
+
+    pub const WRITE: u8 = 0x01;
+    pub const READ: u8 = 0x02;
+}
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS_ENABLED: u32 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum NvmeError {
+    #[error("registers aren't in a memory BAR")]
+    NoMemoryBar,
+    #[error("out of memory")]
+    OutOfMemory,
+    #[error("controller reported a fatal error")]
+    ControllerFatal,
+    #[error("timed out waiting for controller")]
+    Timeout,
+    #[error("command failed with status {0:#x}")]
+    CommandFailed(u16),
+    #[error("unsupported block size of {0} bytes")]
+    UnsupportedBlockSize(usize),
+    #[error("failed to set up MSI-X - {0}")]
+    Msi(#[from] MsiError),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct Command {
+    opcode: u8,
+    flags: u8,
+    command_id: u16,
+    namespace_id: u32,
+    reserved: u64,
+    metadata: u64,
+    prp1: u64,
+    prp2: u64,
+    dwords: [u32; 6],
+}
+
+const _: () = assert!(size_of::<Command>() == 64);
+
+impl Command {
+    fn admin(opcode: u8, prp1: usize, dwords: [u32; 6]) -> Self {
+        Self {
+            opcode,
+            prp1: prp1 as u64,
+            dwords,
+            ..Self::default()
+        }
+    }
+
+    /// Reads or writes `num_blocks` blocks from `lba`, which must fit in a page.
+    fn read_write(opcode: u8, namespace_id: u32, lba: u64, num_blocks: usize) -> Self {
+        Self {
+            opcode,
+            namespace_id,
+            // Number of blocks is 0 based
+            dwords: [
+                lba as u32,
+                (lba >> 32) as u32,
+                num_blocks as u32 - 1,
+                0,
+                0,
+                0,
+            ],
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Completion {
+    result: u32,
+    reserved: u32,
+    submission_head: u16,
+    submission_id: u16,
+    command_id: u16,
+    /// Phase tag in bit 0, then the status code.
+    status: u16,
+}
+
+const _: () = assert!(size_of::<Completion>() == 16);
+
+impl Completion {
+    fn result(&self) -> Result<u32, NvmeError> {
+        match self.status >> 1 {
+            0 => Ok(self.result),
+            status => Err(NvmeError::CommandFailed(status)),
+        }
+    }
+}
+
+/// Identity mapped controller registers.
+#[derive(Debug)]
+struct Registers {
+    base: usize,
+    doorbell_stride: usize,
+}
+
+impl Registers {
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        unsafe { ((self.base + offset) as *const u64).read_volatile() }
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(value) }
+    }
+
+    fn doorbell(&self, queue_id: u16, completion: bool) -> usize {
+        register::DOORBELLS + (2 * queue_id as usize + completion as usize) * self.doorbell_stride
+    }
+
+    /// Waits until `condition` holds for the status register.
+    fn wait_for_status(
+        &self,
+        timeout_ns: u64,
+        condition: impl Fn(u32) -> bool,
+    ) -> Result<(), NvmeError> {
+        let deadline = clock::now_ns() + timeout_ns;
+        loop {
+            let status = self.read_u32(register::STATUS);
+            if condition(status) {
+                return Ok(());
+            }
+            if status & register::STATUS_FATAL != 0 {
+                return Err(NvmeError::ControllerFatal);
+            }
+            if clock::now_ns() >= deadline {
+                return Err(NvmeError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// A submission queue and the completion queue its commands complete on, sharing an ID.
+#[derive(Debug)]
+struct QueuePair {
+    id: u16,
+    len: u16,
+    submissions: *mut Command,
+    completions: *const Completion,
+    submission_tail: u16,
+    completion_head: u16,
+    /// Phase tag of new completion entries, which flips each time the queue wraps.
+    phase: bool,
+}
+
+// The queues are only reachable through this
+unsafe impl Send for QueuePair {}
+
+impl QueuePair {
+    fn new(id: u16, len: u16, submissions: usize, completions: usize) -> Self {
+        Self {
+            id,
+            len,
+            submissions: submissions as *mut Command,
+            completions: completions as *const Completion,
+            submission_tail: 0,
+            completion_head: 0,
+            phase: true,
+        }
+    }
+
+    /// Queues `command`. At most `len - 1` commands can be outstanding.
+    fn submit(&mut self, registers: &Registers, command: Command) {
+        unsafe {
+            self.submissions
+                .add(self.submission_tail as usize)
+                .write_volatile(command);
+        }
+        self.submission_tail = (self.submission_tail + 1) % self.len;
+        registers.write_u32(
+            registers.doorbell(self.id, false),
+            self.submission_tail as u32,
+        );
+    }
+
+    /// Takes the next completion entry, if the controller has posted one.
+    fn pop_completion(&mut self, registers: &Registers) -> Option<Completion> {
+        let completion = unsafe {
+            self.completions
+                .add(self.completion_head as usize)
+                .read_volatile()
+        };
+        if (completion.status & 1 != 0) != self.phase {
+            return None;
+        }
+        self.completion_head += 1;
+        if self.completion_head == self.len {
+            self.completion_head = 0;
+            self.phase = !self.phase;
+        }
+        registers.write_u32(
+            registers.doorbell(self.id, true),
+            self.completion_head as u32,
+        );
+        Some(completion)
+    }
+}
+
+struct IoQueue {
+    queue: QueuePair,
+    /// Command IDs not in use, each with its own bounce page.
+    free_ids: Vec<u16>,
+    /// Completed commands which haven't been collected yet, by command ID.
+    completions: [Option<Completion>; IO_QUEUE_LEN as usize],
+}
+
+impl IoQueue {
+    /// Collects new completions, then takes command `id`'s completion if it's completed.
+    fn take_completion(&mut self, registers: &Registers, id: u16) -> Option<Completion> {
+        while let Some(completion) = self.queue.pop_completion(registers) {
+            if let Some(slot) = self.completions.get_mut(completion.command_id as usize) {
+                *slot = Some(completion);
+            }
+        }
+        self.completions[id as usize].take()
+    }
+}
+
+struct Controller {
+    registers: Registers,
+    /// Physical address of the controller's DMA memory.
+    dma: usize,
+    io: Mutex<IoQueue>,
+    /// Woken by the completion interrupt, and when a command ID is freed.
+    completed: Arc<WaitQueue>,
+    _vector: AllocatedVector,
+}
+
+impl Controller {
+    fn dma_page(&self, index: usize) -> usize {
+        self.dma + index * PAGE_SIZE
+    }
+
+    /// Sends an I/O command using a bounce page, which `fill` is called with beforehand and
+    /// `drain` afterwards.
+    fn io_command(
+        &self,
+        mut command: Command,
+        fill: impl FnOnce(&mut [u8]),
+        drain: impl FnOnce(&[u8]),
+    ) -> Result<(), NvmeError> {
+        let mut id = 0;
+        self.completed
+            .wait_until(|| match self.io.lock().free_ids.pop() {
+                Some(free_id) => {
+                    id = free_id;
+                    true
+                }
+                None => false,
+            });
+        let bounce_page = self.dma_page(BOUNCE_PAGE + id as usize);
+        // Only this command uses the page until its ID is freed
+        let bounce = unsafe { core::slice::from_raw_parts_mut(bounce_page as *mut u8, PAGE_SIZE) };
+        fill(bounce);
+        command.command_id = id;
+        command.prp1 = bounce_page as u64;
+        self.io.lock().queue.submit(&self.registers, command);
+        let mut completion = None;
+        self.completed.wait_until(|| {
+            completion = self.io.lock().take_completion(&self.registers, id);
+            completion.is_some()
+        });
+        drain(bounce);
+        self.io.lock().free_ids.push(id);
+        self.completed.wake_all();
+        completion.unwrap().result().map(drop)
+    }
+}
+
+struct Namespace {
+    controller: Arc<Controller>,
+    id: u32,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl Namespace {
+    fn blocks_per_page(&self) -> u64 {
+        (PAGE_SIZE / self.block_size) as u64
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        super::check_range(self, start, buffer.len())?;
+        for (index, chunk) in buffer.chunks_mut(PAGE_SIZE).enumerate() {
+            let lba = start + index as u64 * self.blocks_per_page();
+            let num_blocks = chunk.len() / self.block_size;
+            let command = Command::read_write(opcode::READ, self.id, lba, num_blocks);
+            self.controller
+                .io_command(
+                    command,
+                    |_| {},
+                    |bounce| chunk.copy_from_slice(&bounce[..chunk.len()]),
+                )
+                .map_err(|err| {
+                    log::error!("NVMe read of block {lba} failed - {err}");
+                    BlockError::DeviceError
+                })?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        super::check_range(self, start, data.len())?;
+        for (index, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+            let lba = start + index as u64 * self.blocks_per_page();
+            let num_blocks = chunk.len() / self.block_size;
+            let command = Command::read_write(opcode::WRITE, self.id, lba, num_blocks);
+            self.controller
+                .io_command(
+                    command,
+                    |bounce| bounce[..chunk.len()].copy_from_slice(chunk),
+                    |_| {},
+                )
+                .map_err(|err| {
+                    log::error!("NVMe write of block {lba} failed - {err}");
+                    BlockError::DeviceError
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Identity maps `size` bytes of registers from `address`, if they aren't already.
+unsafe fn map_registers(address: usize, size: usize) -> Result<(), NvmeError> {
+    for page in (address..address + size).step_by(PAGE_SIZE) {
+        unsafe {
+            if !page_allocation::is_address_identity_mapped(page) {
+                page_allocation::map_page_translation(page, page, PageTableEntry::READ_WRITE)
+                    .map_err(|_| NvmeError::OutOfMemory)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends an admin command while setting up the controller, waiting for it to complete.
+fn admin_command(
+    registers: &Registers,
+    admin: &mut QueuePair,
+    command: Command,
+) -> Result<u32, NvmeError> {
+    admin.submit(registers, command);
+    let deadline = clock::now_ns() + ADMIN_TIMEOUT_NS;
+    loop {
+        if let Some(completion) = admin.pop_completion(registers) {
+            return completion.result();
+        }
+        if clock::now_ns() >= deadline {
+            return Err(NvmeError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Resets and sets up the controller at `address`, returning its active namespaces.
+unsafe fn init_controller(address: PciAddress) -> Result<Vec<Namespace>, NvmeError> {
+    let bar = pci::memory_bar_address(address, 0).ok_or(NvmeError::NoMemoryBar)? as usize;
+    let command = pci::read_word(address, pci::REGISTER_COMMAND);
+    unsafe {
+        pci::write_word(
+            address,
+            pci::REGISTER_COMMAND,
+            command | pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER,
+        );
+        map_registers(bar, PAGE_SIZE)?;
+    }
+    let mut registers = Registers {
+        base: bar,
+        doorbell_stride: 0,
+    };
+    let capabilities = registers.read_u64(register::CAPABILITIES);
+    let max_queue_len = (capabilities & 0xFFFF) as u16 + 1;
+    let ready_timeout_ns = ((capabilities >> 24) & 0xFF).max(1) * READY_TIMEOUT_UNIT_NS;
+    registers.doorbell_stride = 4 << ((capabilities >> 32) & 0xF);
+    unsafe { map_registers(bar, registers.doorbell(IO_QUEUE_ID, true) + 4)? };
+    let version = registers.read_u32(register::VERSION);
+    log::debug!(
+        "NVMe controller at {address}, version {}.{}",
+        version >> 16,
+        (version >> 8) & 0xFF,
+    );
+    // Reset the controller, as firmware may have left it running
+    let configuration = registers.read_u32(register::CONFIGURATION);
+    registers.write_u32(
+        register::CONFIGURATION,
+        configuration & !register::CONFIGURATION_ENABLE,
+    );
+    registers.wait_for_status(ready_timeout_ns, |status| {
+        status & register::STATUS_READY == 0
+    })?;
+    // The controller is never removed, so neither is its memory
+    let dma = page_allocation::find_and_reserve_contiguous(NUM_DMA_PAGES)
+        .map_err(|_| NvmeError::OutOfMemory)?;
+    let dma_page = |index: usize| dma + index * PAGE_SIZE;
+    let admin_len = ADMIN_QUEUE_LEN.min(max_queue_len);
+    let io_len = IO_QUEUE_LEN.min(max_queue_len);
+    let mut admin = QueuePair::new(
+        0,
+        admin_len,
+        dma_page(ADMIN_SUBMISSION_PAGE),
+        dma_page(ADMIN_COMPLETION_PAGE),
+    );
+    let queue_sizes = (admin_len as u32 - 1) << 16 | (admin_len as u32 - 1);
+    registers.write_u32(register::ADMIN_QUEUE_ATTRIBUTES, queue_sizes);
+    registers.write_u64(
+        register::ADMIN_SUBMISSION_QUEUE,
+        dma_page(ADMIN_SUBMISSION_PAGE) as u64,
+    );
+    registers.write_u64(
+        register::ADMIN_COMPLETION_QUEUE,
+        dma_page(ADMIN_COMPLETION_PAGE) as u64,
+    );
+    // NVM command set, 4KiB pages
+    registers.write_u32(
+        register::CONFIGURATION,
+        register::CONFIGURATION_ENTRY_SIZES | register::CONFIGURATION_ENABLE,
+    );
+    registers.wait_for_status(ready_timeout_ns, |status| {
+        status & register::STATUS_READY != 0
+    })?;
+    // Interrupts for the I/O queue, which only needs the first MSI-X entry
+    let completed = Arc::new(WaitQueue::new());
+    let vector = msi::allocate_vector({
+        let completed = completed.clone();
+        move || {
+            completed.wake_all();
+        }
+    })?;
+    unsafe { msi::enable_msix(address, 0, &vector)? };
+    // Asks for one queue pair, 0 based
+    admin_command(
+        &registers,
+        &mut admin,
+        Command::admin(
+            opcode::ADMIN_SET_FEATURES,
+            0,
+            [FEATURE_NUMBER_OF_QUEUES, 0, 0, 0, 0, 0],
+        ),
+    )?;
+    let io_sizes = (io_len as u32 - 1) << 16 | IO_QUEUE_ID as u32;
+    admin_command(
+        &registers,
+        &mut admin,
+        Command::admin(
+            opcode::ADMIN_CREATE_COMPLETION_QUEUE,
+            dma_page(IO_COMPLETION_PAGE),
+            [
+                io_sizes,
+                QUEUE_PHYSICALLY_CONTIGUOUS | QUEUE_INTERRUPTS_ENABLED,
+                0,
+                0,
+                0,
+                0,
+            ],
+        ),
+    )?;
+    admin_command(
+        &registers,
+        &mut admin,
+        Command::admin(
+            opcode::ADMIN_CREATE_SUBMISSION_QUEUE,
+            dma_page(IO_SUBMISSION_PAGE),
+            [
+                io_sizes,
+                (IO_QUEUE_ID as u32) << 16 | QUEUE_PHYSICALLY_CONTIGUOUS,
+                0,
+                0,
+                0,
+                0,
+            ],
+        ),
+    )?;
+    // Active namespace IDs, ending at the first zero
+    let identify = dma_page(IDENTIFY_PAGE);
+    admin_command(
+        &registers,
+        &mut admin,
+        Command::admin(
+            opcode::ADMIN_IDENTIFY,
+            identify,
+            [IDENTIFY_ACTIVE_NAMESPACES, 0, 0, 0, 0, 0],
+        ),
+    )?;
+    let namespace_ids: Vec<u32> = (0..PAGE_SIZE / 4)
+        .map(|index| unsafe { (identify as *const u32).add(index).read_volatile() })
+        .take_while(|id| *id != 0)
+        .collect();
+    let mut namespace_info = Vec::new();
+    for namespace_id in namespace_ids {
+        let mut command = Command::admin(
+            opcode::ADMIN_IDENTIFY,
+            identify,
+            [IDENTIFY_NAMESPACE, 0, 0, 0, 0, 0],
+        );
+        command.namespace_id = namespace_id;
+        admin_command(&registers, &mut admin, command)?;
+        let info = unsafe { core::slice::from_raw_parts(identify as *const u8, PAGE_SIZE) };
+        let num_blocks = u64::from_le_bytes(info[0..8].try_into().unwrap());
+        // Formatted LBA size picks an LBA format, each of which holds the log2 block size
+        let format = (info[26] & 0xF) as usize;
+        let block_size = 1 << info[128 + format * 4 + 2];
+        if block_size > PAGE_SIZE {
+            log::warn!(
+                "Skipping NVMe namespace {namespace_id} - {}",
+                NvmeError::UnsupportedBlockSize(block_size),
+            );
+            continue;
+        }
+        namespace_info.push((namespace_id, block_size, num_blocks));
+    }
+    let controller = Arc::new(Controller {
+        registers,
+        dma,
+        io: Mutex::new(IoQueue {
+            queue: QueuePair::new(
+                IO_QUEUE_ID,
+                io_len,
+                dma_page(IO_SUBMISSION_PAGE),
+                dma_page(IO_COMPLETION_PAGE),
+            ),
+            free_ids: (0..io_len - 1).collect(),
+            completions: [None; IO_QUEUE_LEN as usize],
+        }),
+        completed,
+        _vector: vector,
+    });
+    Ok(namespace_info
+        .into_iter()
+        .map(|(id, block_size, num_blocks)| Namespace {
+            controller: controller.clone(),
+            id,
+            block_size,
+            num_blocks,
+        })
+        .collect())
+}
+
+/// Sets up every NVMe controller on PCI, and registers their namespaces as block devices. Must
+/// be called after interrupts are set up.
+pub fn init() {
+    let mut controllers = Vec::new();
+    pci::for_each_function(|address| {
+        if pci::read_dword(address, pci::REGISTER_CLASS) >> 8 == CLASS_NVME {
+            controllers.push(address);
+        }
+    });
+    let mut num_controllers = 0;
+    for address in controllers {
+        let namespaces = match unsafe { init_controller(address) } {
+            Ok(namespaces) => namespaces,
+            Err(err) => {
+                log::warn!("Failed to set up NVMe controller at {address} - {err}");
+                continue;
+            }
+        };
+        for namespace in namespaces {
+            // Block devices are never removed
+            let name = format!("nvme{num_controllers}n{}", namespace.id).leak();
+            super::register(name, Arc::new(namespace));
+        }
+        num_controllers += 1;
+    }
+}
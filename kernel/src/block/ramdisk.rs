@@ -0,0 +1,167 @@
+//! Ramdisks, block devices backed by memory.
+//!
+//! A ramdisk is set up at boot by `ramdisk=<size>`, where the size is in bytes with an optional
+//! `K`, `M` or `G` suffix, or by `ramdisk=initrd` to start from a copy of the initrd image. Its
+//! pages are taken from the page allocator and held until the ramdisk is dropped, and it's
+//! registered as `ram0`. Contents are lost on reboot.
+
+use super::{BlockDevice, BlockError};
+use crate::arch::page_allocation::{self, OwnedPhysicalPage};
+use crate::arch::paging::PAGE_SIZE;
+use crate::cmdline::{self, RamdiskConfig};
+use crate::cpio;
+use crate::device::{self, PowerOps};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const BLOCK_SIZE: usize = 512;
+const BLOCKS_PER_PAGE: u64 = (PAGE_SIZE / BLOCK_SIZE) as u64;
+
+pub struct Ramdisk {
+    num_blocks: u64,
+    pages: Mutex<Vec<OwnedPhysicalPage>>,
+}
+
+impl Ramdisk {
+    /// Creates a zeroed ramdisk of `size` bytes, rounded up to whole blocks.
+    pub fn new(size: usize) -> Result<Self, BlockError> {
+        let num_pages = size.div_ceil(PAGE_SIZE);
+        let mut pages = Vec::new();
+        pages
+            .try_reserve_exact(num_pages)
+            .map_err(|_| BlockError::OutOfMemory)?;
+        for _ in 0..num_pages {
+            // Pages are cleared as they're reserved, and already reserved ones are freed on error
+            let page =
+                page_allocation::find_and_reserve_page().map_err(|_| BlockError::OutOfMemory)?;
+            pages.push(page);
+        }
+        Ok(Self {
+            num_blocks: size.div_ceil(BLOCK_SIZE) as u64,
+            pages: Mutex::new(pages),
+        })
+    }
+
+    /// Creates a ramdisk holding a copy of `image`, padded with zeroes to whole blocks.
+    pub fn from_image(image: &[u8]) -> Result<Self, BlockError> {
+        let ramdisk = Self::new(image.len())?;
+        for (page, chunk) in ramdisk.pages.lock().iter_mut().zip(image.chunks(PAGE_SIZE)) {
+            page[..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(ramdisk)
+    }
+
+    /// Calls `f` with each page's part of the `len` bytes from block `start`, along with the
+    /// offset into the transfer it starts at.
+    fn for_each_chunk(
+        &self,
+        start: u64,
+        len: usize,
+        mut f: impl FnMut(&mut [u8], usize),
+    ) -> Result<(), BlockError> {
+        super::check_range(self, start, len)?;
+        let mut pages = self.pages.lock();
+        let mut page_index = (start / BLOCKS_PER_PAGE) as usize;
+        let mut page_offset = (start % BLOCKS_PER_PAGE) as usize * BLOCK_SIZE;
+        let mut done = 0;
+        while done < len {
+            let chunk_len = (PAGE_SIZE - page_offset).min(len - done);
+            f(
+                &mut pages[page_index][page_offset..page_offset + chunk_len],
+                done,
+            );
+            done += chunk_len;
+            page_index += 1;
+            page_offset = 0;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Ramdisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.for_each_chunk(start, buffer.len(), |chunk, offset| {
+            buffer[offset..offset + chunk.len()].copy_from_slice(chunk);
+        })
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.for_each_chunk(start, data.len(), |chunk, offset| {
+            chunk.copy_from_slice(&data[offset..offset + chunk.len()]);
+        })
+    }
+}
+
+/// Creates the ramdisk asked for on the command line, if any. Must be called after the initrd is
+/// set.
+pub fn init() {
+    let result = match cmdline::get().ramdisk {
+        None => return,
+        Some(RamdiskConfig::Size(size)) => Ramdisk::new(size),
+        Some(RamdiskConfig::Initrd) => match *cpio::INITRD.lock() {
+            Some(initrd) => Ramdisk::from_image(initrd),
+            None => {
+                log::warn!("No initrd to create ramdisk from");
+                return;
+            }
+        },
+    };
+    match result {
+        Ok(ramdisk) => {
+            device::register("ram0", &[], PowerOps::NONE);
+            super::register("ram0", Arc::new(ramdisk));
+        }
+        Err(err) => log::warn!("Failed to create ramdisk - {err}"),
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+    use alloc::{format, vec};
+
+    #[kernel_test]
+    fn reads_back_writes_across_pages() -> TestResult {
+        let ramdisk = Ramdisk::new(2 * PAGE_SIZE).map_err(|err| format!("{err}"))?;
+        // Spans the boundary between the two pages
+        let start = BLOCKS_PER_PAGE - 1;
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+        ktest_assert_eq!(ramdisk.write_blocks(start, &data), Ok(()));
+        let mut buffer = vec![0; data.len()];
+        ktest_assert_eq!(ramdisk.read_blocks(start, &mut buffer), Ok(()));
+        ktest_assert_eq!(buffer, data);
+        // Blocks around the write are untouched
+        let mut buffer = vec![0xFF; BLOCK_SIZE];
+        ktest_assert_eq!(ramdisk.read_blocks(start + 2, &mut buffer), Ok(()));
+        ktest_assert!(buffer.iter().all(|byte| *byte == 0));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn rejects_invalid_transfers() -> TestResult {
+        let ramdisk = Ramdisk::from_image(&[1, 2, 3]).map_err(|err| format!("{err}"))?;
+        ktest_assert_eq!(ramdisk.num_blocks(), 1);
+        let mut buffer = [0; BLOCK_SIZE];
+        ktest_assert_eq!(ramdisk.read_blocks(0, &mut buffer), Ok(()));
+        ktest_assert_eq!(buffer[..4], [1, 2, 3, 0]);
+        ktest_assert_eq!(
+            ramdisk.read_blocks(1, &mut buffer),
+            Err(BlockError::OutOfRange)
+        );
+        ktest_assert_eq!(
+            ramdisk.write_blocks(0, &buffer[..100]),
+            Err(BlockError::UnalignedLength)
+        );
+        Ok(())
+    }
+}
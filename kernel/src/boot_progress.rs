@@ -0,0 +1,59 @@
+//! Progress bar drawn on every framebuffer during a quiet boot, in place of log output.
+//!
+//! Progress is the fraction of subsystems which have finished initialising.
+
+use crate::cmdline;
+use crate::core_graphics::{FRAMEBUFFERS, Framebuffer};
+use crate::init_state;
+
+const OUTLINE_COLOR: u32 = 0xAAAAAA;
+const FILL_COLOR: u32 = 0xFFFFFF;
+const BACKGROUND_COLOR: u32 = 0x000000;
+const BAR_HEIGHT: u32 = 12;
+const OUTLINE_WIDTH: u32 = 1;
+
+/// Redraws the progress bar, if booting quietly.
+pub fn update() {
+    let (finished, total) = init_state::progress();
+    draw(finished, total);
+}
+
+/// Draws the progress bar as full, for once boot is complete.
+pub fn complete() {
+    draw(1, 1);
+}
+
+fn draw(finished: usize, total: usize) {
+    if !cmdline::get().quiet {
+        return;
+    }
+    // Skip rather than wait if something else is drawing, the next update will catch up
+    let Some(mut framebuffers) = FRAMEBUFFERS.try_lock() else {
+        return;
+    };
+    for (_, framebuffer) in framebuffers.iter_mut() {
+        draw_bar(framebuffer, finished, total);
+        framebuffer.flush();
+    }
+}
+
+fn draw_bar(framebuffer: &mut Framebuffer, finished: usize, total: usize) {
+    let width = framebuffer.width / 2;
+    if width <= 2 * OUTLINE_WIDTH || framebuffer.height < BAR_HEIGHT * 4 {
+        return;
+    }
+    let x = (framebuffer.width - width) / 2;
+    let y = framebuffer.height * 3 / 4;
+    let inner_width = width - 2 * OUTLINE_WIDTH;
+    let inner_height = BAR_HEIGHT - 2 * OUTLINE_WIDTH;
+    let filled_width = (inner_width as usize * finished / total.max(1)) as u32;
+    let inner_x = x + OUTLINE_WIDTH;
+    let inner_y = y + OUTLINE_WIDTH;
+    framebuffer.fill_box((x, y), (width, BAR_HEIGHT), OUTLINE_COLOR);
+    framebuffer.fill_box((inner_x, inner_y), (filled_width, inner_height), FILL_COLOR);
+    framebuffer.fill_box(
+        (inner_x + filled_width, inner_y),
+        (inner_width - filled_width, inner_height),
+        BACKGROUND_COLOR,
+    );
+}
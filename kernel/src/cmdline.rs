@@ -0,0 +1,342 @@
+//! Parsing of the kernel command line into boot options.
+//!
+//! Options are whitespace separated, either `key=value` or bare flags. Unknown options are
+//! ignored here, as the same command line is also given to the tunables.
+
+use crate::net::ipv4::Ipv4Address;
+use crate::sync::IrqRwLock;
+use crate::terminal;
+use crate::tunables;
+use core::fmt;
+
+static CONFIG: IrqRwLock<Config> = IrqRwLock::new(Config::DEFAULT);
+
+/// Where kernel log output is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Console {
+    /// Architecture debug output only, such as the serial port.
+    Serial,
+    /// Framebuffer terminal only.
+    Framebuffer,
+    Both,
+}
+
+impl Console {
+    pub fn uses_serial(self) -> bool {
+        self != Console::Framebuffer
+    }
+
+    pub fn uses_framebuffer(self) -> bool {
+        self != Console::Serial
+    }
+}
+
+/// How the ACPI tables are dumped at boot, see `platform::acpi::dump`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcpiDump {
+    Off,
+    /// Log each table's header.
+    List,
+    /// Also write the tables to serial in `acpidump`'s hex format.
+    Hex,
+    /// Also write the tables to serial in base64.
+    Base64,
+}
+
+/// What the boot ramdisk is created from, see `block::ramdisk`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamdiskConfig {
+    /// Zeroed, of this many bytes.
+    Size(usize),
+    /// A copy of the initrd image.
+    Initrd,
+}
+
+/// Longest value accepted for a `Secret` option.
+pub const MAX_SECRET_LEN: usize = 64;
+
+/// A value which mustn't be logged, so its `Debug` output leaves it out.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Secret {
+    bytes: [u8; MAX_SECRET_LEN],
+    len: usize,
+}
+
+impl Secret {
+    /// Returns `None` if `value` is empty or longer than `MAX_SECRET_LEN` bytes.
+    fn new(value: &str) -> Option<Self> {
+        if value.is_empty() || value.len() > MAX_SECRET_LEN {
+            return None;
+        }
+        let mut bytes = [0; MAX_SECRET_LEN];
+        bytes[..value.len()].copy_from_slice(value.as_bytes());
+        Some(Self {
+            bytes,
+            len: value.len(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Whether secondary CPUs should be used. Cleared by `nosmp`.
+    pub smp: bool,
+    /// Whether SMEP is enabled if supported, see `arch::protection`. Cleared by `nosmep`.
+    pub smep: bool,
+    /// Whether SMAP is enabled if supported. Cleared by `nosmap`.
+    pub smap: bool,
+    /// Set by `console=serial`, `console=fb` or `console=both`.
+    pub console: Console,
+    /// Set by `console_display=<n>` to show the framebuffer terminal on display `n`, or
+    /// `console_display=mirror` to show it on all displays.
+    pub console_output: terminal::Output,
+    /// Set by `quiet`, cleared by `verbose`. Shows a progress bar on the framebuffer instead of
+    /// log output, apart from warnings and errors. Serial output is unaffected.
+    pub quiet: bool,
+    /// Set by `watch_page=<physical address>` to trap and log writes to that page, see
+    /// `arch::watchpoint`. The address is hexadecimal, with an optional `0x` prefix.
+    pub watch_page: Option<usize>,
+    /// Set by `sched_seed=<n>` to schedule kernel threads deterministically from a PRNG seeded
+    /// with `n`, see `kthread`.
+    pub sched_seed: Option<u64>,
+    /// Set by `memscrub` to test free memory in the background, see `scrubber`.
+    pub memory_scrub: bool,
+    /// Set by `acpidump`, `acpidump=hex` or `acpidump=base64`.
+    pub acpi_dump: AcpiDump,
+    /// Set by `runtests` to run the in-kernel tests after boot, then exit QEMU, see `ktest`.
+    pub run_tests: bool,
+    /// Set by `nmi_watchdog` to panic on hard lockups, see `arch::nmi`.
+    pub nmi_watchdog: bool,
+    /// Set by `trace` to start event tracing at boot, see `trace`.
+    pub trace: bool,
+    /// Set by `ramdisk=<size>` or `ramdisk=initrd` to create a ramdisk at boot.
+    pub ramdisk: Option<RamdiskConfig>,
+    /// Set by `ip=<address>/<prefix length>` to give the first network device an IPv4 address,
+    /// see `net::ipv4`.
+    pub ip_address: Option<(Ipv4Address, u8)>,
+    /// Set by `gateway=<address>` to route packets outside the local subnet.
+    pub gateway: Option<Ipv4Address>,
+    /// Set by `netdump=<address>:<port>` to send hard lockup dumps over UDP, see `net::netdump`.
+    pub netdump: Option<(Ipv4Address, u16)>,
+    /// Set by `netconsole=<port>` to accept debug console connections on that TCP port, see
+    /// `net::console`. Only used along with `netconsole_key`.
+    pub netconsole: Option<u16>,
+    /// Set by `netconsole_key=<key>`, the key debug console connections must send first.
+    pub netconsole_key: Option<Secret>,
+}
+
+impl Config {
+    pub const DEFAULT: Self = Self {
+        smp: true,
+        smep: true,
+        smap: true,
+        console: Console::Both,
+        console_output: terminal::Output::Display(0),
+        quiet: false,
+        watch_page: None,
+        sched_seed: None,
+        memory_scrub: false,
+        acpi_dump: AcpiDump::Off,
+        run_tests: false,
+        nmi_watchdog: false,
+        trace: false,
+        ramdisk: None,
+        ip_address: None,
+        gateway: None,
+        netdump: None,
+        netconsole: None,
+        netconsole_key: None,
+    };
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Parses the command line, logging invalid values. Options which aren't given keep their
+/// default.
+pub fn parse(cmdline: &str) -> Config {
+    let mut config = Config::DEFAULT;
+    for option in cmdline.split_whitespace() {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+        match (key, value) {
+            ("nosmp", None) => config.smp = false,
+            ("nosmep", None) => config.smep = false,
+            ("nosmap", None) => config.smap = false,
+            ("quiet", None) => config.quiet = true,
+            ("memscrub", None) => config.memory_scrub = true,
+            ("runtests", None) => config.run_tests = true,
+            ("nmi_watchdog", None) => config.nmi_watchdog = true,
+            ("trace", None) => config.trace = true,
+            ("acpidump", None) => config.acpi_dump = AcpiDump::List,
+            ("acpidump", Some(value)) => match value {
+                "hex" => config.acpi_dump = AcpiDump::Hex,
+                "base64" => config.acpi_dump = AcpiDump::Base64,
+                _ => log::warn!("Unknown ACPI dump format {value:?}, ignoring"),
+            },
+            ("verbose", None) => config.quiet = false,
+            ("console", Some(value)) => match value {
+                "serial" => config.console = Console::Serial,
+                "fb" | "framebuffer" => config.console = Console::Framebuffer,
+                "both" => config.console = Console::Both,
+                _ => log::warn!("Unknown console {value:?}, ignoring"),
+            },
+            ("console_display", Some("mirror")) => {
+                config.console_output = terminal::Output::Mirror;
+            }
+            ("console_display", Some(value)) => match value.parse() {
+                Ok(display) => config.console_output = terminal::Output::Display(display),
+                Err(_) => log::warn!("Invalid console display {value:?}, ignoring"),
+            },
+            ("watch_page", Some(value)) => {
+                match usize::from_str_radix(value.trim_start_matches("0x"), 16) {
+                    Ok(address) => config.watch_page = Some(address),
+                    Err(_) => log::warn!("Invalid watch page address {value:?}, ignoring"),
+                }
+            }
+            ("ramdisk", Some("initrd")) => config.ramdisk = Some(RamdiskConfig::Initrd),
+            ("ramdisk", Some(value)) => match parse_size(value) {
+                Some(size) if size > 0 => config.ramdisk = Some(RamdiskConfig::Size(size)),
+                _ => log::warn!("Invalid ramdisk size {value:?}, ignoring"),
+            },
+            ("ip", Some(value)) => match parse_ip_address(value) {
+                Some(address) => config.ip_address = Some(address),
+                None => log::warn!("Invalid IP address {value:?}, ignoring"),
+            },
+            ("gateway", Some(value)) => match value.parse() {
+                Ok(address) => config.gateway = Some(address),
+                Err(_) => log::warn!("Invalid gateway address {value:?}, ignoring"),
+            },
+            ("netdump", Some(value)) => match parse_socket_address(value) {
+                Some(target) => config.netdump = Some(target),
+                None => log::warn!("Invalid network dump address {value:?}, ignoring"),
+            },
+            ("netconsole", Some(value)) => match value.parse() {
+                Ok(port) if port != 0 => config.netconsole = Some(port),
+                _ => log::warn!("Invalid network console port {value:?}, ignoring"),
+            },
+            ("netconsole_key", Some(value)) => match Secret::new(value) {
+                Some(key) => config.netconsole_key = Some(key),
+                None => {
+                    log::warn!("Network console key must be 1 to {MAX_SECRET_LEN} bytes, ignoring")
+                }
+            },
+            ("sched_seed", Some(value)) => match value.parse() {
+                Ok(seed) => config.sched_seed = Some(seed),
+                Err(_) => log::warn!("Invalid scheduler seed {value:?}, ignoring"),
+            },
+            // Shorthand for the log level tunable
+            ("loglevel", Some(value)) => {
+                if let Err(err) = tunables::LOG_LEVEL.set_from_str(value) {
+                    log::warn!("Failed to set log level to {value:?} - {err}");
+                }
+            }
+            _ => {}
+        }
+    }
+    config
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix.
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+/// Parses an IPv4 address with a prefix length, like `10.0.2.15/24`.
+fn parse_ip_address(value: &str) -> Option<(Ipv4Address, u8)> {
+    let (address, prefix_len) = value.split_once('/')?;
+    let prefix_len = prefix_len.parse().ok().filter(|len| *len <= 32)?;
+    Some((address.parse().ok()?, prefix_len))
+}
+
+/// Parses an IPv4 address with a port, like `10.0.2.2:6666`.
+fn parse_socket_address(value: &str) -> Option<(Ipv4Address, u16)> {
+    let (address, port) = value.split_once(':')?;
+    Some((address.parse().ok()?, port.parse().ok()?))
+}
+
+/// Parses the command line and makes it the current config.
+pub fn init(cmdline: &str) {
+    let config = parse(cmdline);
+    log::debug!("Command line config: {config:?}");
+    *CONFIG.write() = config;
+}
+
+#[inline]
+pub fn get() -> Config {
+    *CONFIG.read()
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    #[kernel_test]
+    fn parses_options() -> TestResult {
+        let config = parse(
+            "nosmp console=serial watch_page=0x1000 sched_seed=7 runtests nmi_watchdog trace ramdisk=4M",
+        );
+        ktest_assert!(!config.smp);
+        ktest_assert_eq!(config.console, Console::Serial);
+        ktest_assert_eq!(config.watch_page, Some(0x1000));
+        ktest_assert_eq!(config.sched_seed, Some(7));
+        ktest_assert!(config.run_tests);
+        ktest_assert!(config.nmi_watchdog);
+        ktest_assert!(config.trace);
+        ktest_assert_eq!(config.ramdisk, Some(RamdiskConfig::Size(4 << 20)));
+        ktest_assert_eq!(parse("ramdisk=initrd").ramdisk, Some(RamdiskConfig::Initrd));
+        let config = parse("ip=10.0.2.15/24 gateway=10.0.2.2 netdump=10.0.2.2:6666");
+        ktest_assert_eq!(config.ip_address, Some((Ipv4Address([10, 0, 2, 15]), 24)));
+        ktest_assert_eq!(config.gateway, Some(Ipv4Address([10, 0, 2, 2])));
+        ktest_assert_eq!(config.netdump, Some((Ipv4Address([10, 0, 2, 2]), 6666)));
+        let config = parse("netconsole=2323 netconsole_key=hunter2");
+        ktest_assert_eq!(config.netconsole, Some(2323));
+        ktest_assert_eq!(
+            config.netconsole_key.as_ref().map(Secret::as_bytes),
+            Some(&b"hunter2"[..])
+        );
+        ktest_assert_eq!(
+            alloc::format!("{:?}", config.netconsole_key),
+            "Some(Secret(..))"
+        );
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn ignores_invalid_values() -> TestResult {
+        let config =
+            parse("console=nowhere watch_page=xyz ramdisk=12Q unknown_option ip=10.0.2.15");
+        ktest_assert_eq!(config.console, Config::DEFAULT.console);
+        ktest_assert_eq!(config.watch_page, None);
+        ktest_assert_eq!(config.ramdisk, None);
+        ktest_assert_eq!(config.ip_address, None);
+        ktest_assert_eq!(parse("ip=10.0.2.15/33").ip_address, None);
+        ktest_assert_eq!(parse("netdump=10.0.2.2").netdump, None);
+        ktest_assert_eq!(parse("netdump=10.0.2.2:65536").netdump, None);
+        ktest_assert_eq!(parse("netconsole=0").netconsole, None);
+        ktest_assert_eq!(parse("netconsole_key=").netconsole_key, None);
+        Ok(())
+    }
+}
@@ -1,36 +1,550 @@
+use crate::arch::glyph_blit;
 use crate::arch::kernel_args;
-use spin::Mutex;
+use crate::arch::page_allocation;
+use crate::arch::paging::{PAGE_SIZE, PageTableEntry};
+use crate::memory_tag::MemoryTag;
+use crate::sync::IrqMutex;
+use alloc::vec::Vec;
 
-pub static FRAMEBUFFER: Mutex<Option<Framebuffer>> = Mutex::new(None);
+/// Most framebuffers which can be registered at once.
+pub const MAX_FRAMEBUFFERS: usize = 4;
 
+pub static FRAMEBUFFERS: IrqMutex<Framebuffers> = IrqMutex::new(Framebuffers::new());
+/// Offset into the framebuffer area of the next physical framebuffer to be mapped.
+static NEXT_MAP_OFFSET: IrqMutex<usize> = IrqMutex::new(0);
+
+unsafe extern "C" {
+    static FRAMEBUFFER_START: usize;
+    static FRAMEBUFFER_END: usize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FormatError {
+    #[error("unsupported bits per pixel {0}")]
+    UnsupportedBitsPerPixel(u32),
+    #[error("unsupported color mask {0:#x}")]
+    InvalidMask(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum RegisterError {
+    #[error("too many framebuffers, only {MAX_FRAMEBUFFERS} supported")]
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MapFramebufferError {
+    #[error("framebuffer too large to map")]
+    TooLarge,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum InitError {
+    #[error("unsupported framebuffer format - {0}")]
+    Format(#[from] FormatError),
+    #[error("failed to map physical framebuffer - {0}")]
+    Map(#[from] MapFramebufferError),
+    #[error("{0}")]
+    Register(#[from] RegisterError),
+}
+
+/// Position and size of a color channel within a pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Channel {
+    shift: u32,
+    size: u32,
+}
+
+impl Channel {
+    fn from_mask(mask: u32) -> Result<Self, FormatError> {
+        let shift = mask.trailing_zeros();
+        let size = mask.count_ones();
+        // Masks must be contiguous, and no wider than 16 bits
+        if mask == 0 || size > 16 || (mask >> shift).trailing_ones() != size {
+            return Err(FormatError::InvalidMask(mask));
+        }
+        Ok(Self { shift, size })
+    }
+
+    /// Scales an 8 bit channel value to the channel size and moves it into place.
+    #[inline]
+    fn encode(self, value: u32) -> u32 {
+        let scaled = match self.size {
+            ..8 => value >> (8 - self.size),
+            _ => value << (self.size - 8),
+        };
+        scaled << self.shift
+    }
+
+    /// Extracts the channel from a pixel, scaled to 8 bits.
+    #[inline]
+    fn decode(self, pixel: u32) -> u32 {
+        let value = (pixel >> self.shift) & ((1 << self.size) - 1);
+        match self.size {
+            ..8 => value << (8 - self.size),
+            _ => value >> (self.size - 8),
+        }
+    }
+}
+
+/// Layout of a pixel in framebuffer memory. Colors passed to the framebuffer are always
+/// `0xRRGGBB`, and converted to this format when drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    bytes_per_pixel: u32,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl PixelFormat {
+    pub fn new(
+        color_format: kernel_args::ColorFormat,
+        bits_per_pixel: u32,
+    ) -> Result<Self, FormatError> {
+        let bytes_per_pixel = match bits_per_pixel {
+            15 | 16 => 2,
+            24 => 3,
+            32 => 4,
+            bits => return Err(FormatError::UnsupportedBitsPerPixel(bits)),
+        };
+        // Masks are of the pixel as a little endian integer
+        let (red_mask, green_mask, blue_mask) = match color_format {
+            kernel_args::ColorFormat::Rgbr8 => (0x0000FF, 0x00FF00, 0xFF0000),
+            kernel_args::ColorFormat::Bgrr8 => (0xFF0000, 0x00FF00, 0x0000FF),
+            kernel_args::ColorFormat::Bitmask(masks) => {
+                (masks.red_mask, masks.green_mask, masks.blue_mask)
+            }
+        };
+        Ok(Self {
+            bytes_per_pixel,
+            red: Channel::from_mask(red_mask)?,
+            green: Channel::from_mask(green_mask)?,
+            blue: Channel::from_mask(blue_mask)?,
+        })
+    }
+
+    pub fn bytes_per_pixel(&self) -> u32 {
+        self.bytes_per_pixel
+    }
+
+    /// Converts a `0xRRGGBB` color to a pixel value.
+    #[inline]
+    pub fn encode(&self, color: u32) -> u32 {
+        self.red.encode((color >> 16) & 0xFF)
+            | self.green.encode((color >> 8) & 0xFF)
+            | self.blue.encode(color & 0xFF)
+    }
+
+    /// Converts a pixel value to a `0xRRGGBB` color.
+    #[inline]
+    pub fn decode(&self, pixel: u32) -> u32 {
+        self.red.decode(pixel) << 16 | self.green.decode(pixel) << 8 | self.blue.decode(pixel)
+    }
+}
+
+/// Maps a framebuffer at a physical address into the kernel's framebuffer area, after any
+/// framebuffers already mapped, returning a pointer to the mapped framebuffer.
+pub unsafe fn map_physical(
+    physical_address: usize,
+    size: usize,
+) -> Result<*mut u8, MapFramebufferError> {
+    unsafe {
+        let mut next_map_offset = NEXT_MAP_OFFSET.lock();
+        let area_start = &FRAMEBUFFER_START as *const usize as usize + *next_map_offset;
+        let area_end = &FRAMEBUFFER_END as *const usize as usize;
+        let page_offset = physical_address % PAGE_SIZE;
+        let map_size = (page_offset + size).next_multiple_of(PAGE_SIZE);
+        if map_size > area_end - area_start + 1 {
+            return Err(MapFramebufferError::TooLarge);
+        }
+        let physical_start = physical_address - page_offset;
+        for offset in (0..map_size).step_by(PAGE_SIZE) {
+            page_allocation::map_page_translation(
+                physical_start + offset,
+                area_start + offset,
+                PageTableEntry::READ_WRITE,
+            )
+            .map_err(|_| MapFramebufferError::OutOfMemory)?;
+        }
+        *next_map_offset += map_size;
+        Ok((area_start + page_offset) as *mut u8)
+    }
+}
+
+/// Sets up a framebuffer given by the bootloader, clearing it and adding it to `FRAMEBUFFERS`.
+/// Returns the framebuffer's display number.
+pub unsafe fn init_framebuffer(
+    framebuffer_arg: &kernel_args::Framebuffer,
+) -> Result<usize, InitError> {
+    let _tag = MemoryTag::Graphics.enter();
+    let format = PixelFormat::new(framebuffer_arg.color_format, framebuffer_arg.bits_per_pixel)?;
+    let buffer_ptr = match framebuffer_arg.ptr_type {
+        kernel_args::PtrType::Linear => framebuffer_arg.ptr.as_ptr(),
+        kernel_args::PtrType::Physical => unsafe {
+            map_physical(
+                framebuffer_arg.ptr.as_ptr() as usize,
+                framebuffer_arg.size as usize,
+            )?
+        },
+    };
+    assert!(
+        buffer_ptr as usize > 0xF000_0000_0000_0000,
+        "lower half framebuffers currently unsupported",
+    );
+    let mut framebuffer = Framebuffer::new(
+        unsafe { core::slice::from_raw_parts_mut(buffer_ptr, framebuffer_arg.size as usize) },
+        framebuffer_arg.width,
+        framebuffer_arg.height,
+        framebuffer_arg.pitch,
+        format,
+    );
+    framebuffer.clear();
+    framebuffer.flush();
+    Ok(FRAMEBUFFERS.lock().register(framebuffer)?)
+}
+
+/// Registry of framebuffers, one per display. Displays are numbered in the order they're
+/// registered, display 0 being the primary display.
+pub struct Framebuffers {
+    framebuffers: [Option<Framebuffer<'static>>; MAX_FRAMEBUFFERS],
+}
+
+impl Framebuffers {
+    pub const fn new() -> Self {
+        Self {
+            framebuffers: [const { None }; MAX_FRAMEBUFFERS],
+        }
+    }
+
+    /// Adds a framebuffer, returning its display number.
+    pub fn register(&mut self, framebuffer: Framebuffer<'static>) -> Result<usize, RegisterError> {
+        let display = self.len();
+        let slot = self
+            .framebuffers
+            .get_mut(display)
+            .ok_or(RegisterError::Full)?;
+        *slot = Some(framebuffer);
+        Ok(display)
+    }
+
+    pub fn len(&self) -> usize {
+        self.framebuffers
+            .iter()
+            .take_while(|fb| fb.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.framebuffers[0].is_none()
+    }
+
+    pub fn get(&self, display: usize) -> Option<&Framebuffer<'static>> {
+        self.framebuffers.get(display)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, display: usize) -> Option<&mut Framebuffer<'static>> {
+        self.framebuffers.get_mut(display)?.as_mut()
+    }
+
+    /// Iterates over the registered framebuffers, along with their display numbers.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Framebuffer<'static>)> {
+        self.framebuffers
+            .iter()
+            .map_while(Option::as_ref)
+            .enumerate()
+    }
+
+    /// Iterates mutably over the registered framebuffers, along with their display numbers.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut Framebuffer<'static>)> {
+        self.framebuffers
+            .iter_mut()
+            .map_while(Option::as_mut)
+            .enumerate()
+    }
+}
+
+impl Default for Framebuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Most separate dirty rectangles tracked before they're all merged into one.
+const MAX_DIRTY_RECTS: usize = 8;
+
+/// Area of a framebuffer, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    const EMPTY: Self = Self {
+        x: 0,
+        y: 0,
+        width: 0,
+        height: 0,
+    };
+
+    fn right(self) -> u32 {
+        self.x + self.width
+    }
+
+    fn bottom(self) -> u32 {
+        self.y + self.height
+    }
+
+    /// Whether the rectangles overlap or share an edge, so merging them adds little area.
+    fn touches(self, other: Rect) -> bool {
+        self.x <= other.right()
+            && other.x <= self.right()
+            && self.y <= other.bottom()
+            && other.y <= self.bottom()
+    }
+
+    /// Smallest rectangle containing both rectangles.
+    fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
+        }
+    }
+}
+
+/// A display's framebuffer. Drawing goes to a shadow buffer in normal memory where possible, as
+/// framebuffer memory is often uncached and slow to access, and changed areas are copied across
+/// by `flush`.
 pub struct Framebuffer<'a> {
-    pub buffer: &'a mut [u32],
+    pub buffer: &'a mut [u8],
     pub width: u32,
     pub height: u32,
-    pub scanline_length: u32,
-    pub color_format: kernel_args::ColorFormat,
+    /// Bytes per scanline.
+    pub pitch: u32,
+    pub format: PixelFormat,
+    /// Copy of `buffer` that's drawn to, or `None` if there wasn't memory for one, in which case
+    /// drawing goes straight to `buffer`.
+    shadow: Option<Vec<u8>>,
+    /// Areas of `shadow` which have changed since the last flush.
+    dirty: [Rect; MAX_DIRTY_RECTS],
+    dirty_len: usize,
 }
 
 impl<'a> Framebuffer<'a> {
+    pub fn new(
+        buffer: &'a mut [u8],
+        width: u32,
+        height: u32,
+        pitch: u32,
+        format: PixelFormat,
+    ) -> Self {
+        let mut shadow = Vec::new();
+        let shadow = match shadow.try_reserve_exact(buffer.len()) {
+            Ok(()) => {
+                shadow.extend_from_slice(buffer);
+                Some(shadow)
+            }
+            Err(_) => {
+                log::warn!("No memory for framebuffer shadow buffer, drawing will be slower");
+                None
+            }
+        };
+        Self {
+            buffer,
+            width,
+            height,
+            pitch,
+            format,
+            shadow,
+            dirty: [Rect::EMPTY; MAX_DIRTY_RECTS],
+            dirty_len: 0,
+        }
+    }
+
+    /// Returns the memory drawn to.
+    #[inline]
+    fn target(&mut self) -> &mut [u8] {
+        match &mut self.shadow {
+            Some(shadow) => shadow,
+            None => self.buffer,
+        }
+    }
+
+    #[inline]
+    fn target_ref(&self) -> &[u8] {
+        match &self.shadow {
+            Some(shadow) => shadow,
+            None => self.buffer,
+        }
+    }
+
+    /// Records an area as needing to be copied to the framebuffer on the next flush.
+    fn mark_dirty(&mut self, rect: Rect) {
+        if self.shadow.is_none() || rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        let dirty = &mut self.dirty[..self.dirty_len];
+        if let Some(existing) = dirty.iter_mut().find(|existing| existing.touches(rect)) {
+            *existing = existing.union(rect);
+        } else if self.dirty_len < MAX_DIRTY_RECTS {
+            self.dirty[self.dirty_len] = rect;
+            self.dirty_len += 1;
+        } else {
+            self.dirty[0] = dirty.iter().fold(rect, |bounds, rect| bounds.union(*rect));
+            self.dirty_len = 1;
+        }
+    }
+
+    /// Copies everything drawn since the last flush to the framebuffer.
+    pub fn flush(&mut self) {
+        let Some(shadow) = &self.shadow else {
+            return;
+        };
+        let bytes_per_pixel = self.format.bytes_per_pixel as usize;
+        let pitch = self.pitch as usize;
+        for rect in &self.dirty[..self.dirty_len] {
+            // Copies go through `memcpy`, which moves whole words at a time with `rep movs`
+            if rect.x == 0 && rect.width == self.width {
+                // Full width rows are contiguous, so copy them all at once
+                let range = rect.y as usize * pitch..rect.bottom() as usize * pitch;
+                let range = range.start..range.end.min(self.buffer.len());
+                self.buffer[range.clone()].copy_from_slice(&shadow[range]);
+            } else {
+                let row_len = rect.width as usize * bytes_per_pixel;
+                for y in rect.y..rect.bottom() {
+                    let start = y as usize * pitch + rect.x as usize * bytes_per_pixel;
+                    self.buffer[start..start + row_len]
+                        .copy_from_slice(&shadow[start..start + row_len]);
+                }
+            }
+        }
+        self.dirty_len = 0;
+    }
+
     pub fn clear(&mut self) {
-        self.buffer.fill(0);
+        self.target().fill(0);
+        self.mark_dirty(Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        });
     }
 
     pub fn fill_box(&mut self, start_pos: (u32, u32), dims: (u32, u32), color: u32) {
+        let pixel = self.format.encode(color);
         for y in start_pos.1..start_pos.1 + dims.1 {
             for x in start_pos.0..start_pos.0 + dims.0 {
-                self.set((x, y), color);
+                self.write_pixel((x, y), pixel);
+            }
+        }
+        self.mark_dirty(Rect {
+            x: start_pos.0,
+            y: start_pos.1,
+            width: dims.0,
+            height: dims.1,
+        });
+    }
+
+    /// Draws a `dims` sized glyph with its top left corner at `pos`. Rows of the glyph are
+    /// `bytes_per_row` bytes of 1 bit per pixel, most significant bit first, drawn in
+    /// `foreground` where set and `background` otherwise.
+    pub fn draw_glyph(
+        &mut self,
+        pos: (u32, u32),
+        dims: (u32, u32),
+        glyph: &[u8],
+        bytes_per_row: usize,
+        foreground: u32,
+        background: u32,
+    ) {
+        let foreground = self.format.encode(foreground);
+        let background = self.format.encode(background);
+        let rows = dims.1 as usize;
+        let pitch = self.pitch as usize;
+        let start = self.offset(pos);
+        // Whole bands of 8 pixels go through the SIMD blitter, which needs 32 bit pixels
+        let simd_width = match self.format.bytes_per_pixel {
+            4 => dims.0 / 8 * 8,
+            _ => 0,
+        };
+        let simd_end = start + rows.saturating_sub(1) * pitch + simd_width as usize * 4;
+        let use_simd = simd_width != 0
+            && rows != 0
+            && glyph.len() >= rows * bytes_per_row
+            && simd_end <= self.target().len();
+        if use_simd {
+            let target = self.target()[start..].as_mut_ptr();
+            for band in 0..simd_width as usize / 8 {
+                unsafe {
+                    glyph_blit::expand_band(
+                        glyph[band..].as_ptr(),
+                        bytes_per_row,
+                        target.add(band * 32),
+                        pitch,
+                        rows,
+                        foreground,
+                        background,
+                    );
+                }
+            }
+        }
+        // Remaining columns, or everything if the blitter can't be used
+        let scalar_start = if use_simd { simd_width } else { 0 };
+        for (row_i, row) in glyph.chunks_exact(bytes_per_row).take(rows).enumerate() {
+            for column in scalar_start..dims.0 {
+                // Branchless code to calculate whether to use the background or foreground color
+                let mask = ((row[column as usize / 8] >> (7 - column % 8)) & 1) as u32;
+                let pixel = (mask * foreground) | ((1 - mask) * background);
+                self.write_pixel((pos.0 + column, pos.1 + row_i as u32), pixel);
             }
         }
+        self.mark_dirty(Rect {
+            x: pos.0,
+            y: pos.1,
+            width: dims.0,
+            height: dims.1,
+        });
+    }
+
+    #[inline]
+    fn offset(&self, pos: (u32, u32)) -> usize {
+        (pos.1 * self.pitch + pos.0 * self.format.bytes_per_pixel) as usize
+    }
+
+    #[inline]
+    fn write_pixel(&mut self, pos: (u32, u32), pixel: u32) {
+        let offset = self.offset(pos);
+        let len = self.format.bytes_per_pixel as usize;
+        self.target()[offset..offset + len].copy_from_slice(&pixel.to_le_bytes()[..len]);
     }
 
     #[inline]
     pub fn get(&self, pos: (u32, u32)) -> u32 {
-        self.buffer[(pos.1 * self.scanline_length + pos.0) as usize]
+        let offset = self.offset(pos);
+        let len = self.format.bytes_per_pixel as usize;
+        let mut bytes = [0; 4];
+        bytes[..len].copy_from_slice(&self.target_ref()[offset..offset + len]);
+        self.format.decode(u32::from_le_bytes(bytes))
     }
 
     #[inline]
     pub fn set(&mut self, pos: (u32, u32), color: u32) {
-        self.buffer[(pos.1 * self.scanline_length + pos.0) as usize] = color;
+        self.write_pixel(pos, self.format.encode(color));
+        self.mark_dirty(Rect {
+            x: pos.0,
+            y: pos.1,
+            width: 1,
+            height: 1,
+        });
     }
 }
@@ -1,4 +1,8 @@
 use core::mem::size_of;
+use spin::Mutex;
+
+/// The initrd archive provided by the bootloader, used to look up files after boot.
+pub static INITRD: Mutex<Option<&'static [u8]>> = Mutex::new(None);
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -1,8 +1,11 @@
+use crate::sync::OnceLock;
 use core::arch::asm;
 use core::mem::{align_of, size_of};
 use core::panic::PanicInfo;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use log::error;
+
+pub mod symbols;
 // use alloc::boxed::Box;
 // use unwinding::abi::*;
 // use core::ffi::c_void;
@@ -12,7 +15,10 @@ use log::error;
 // the kernel crashes.
 
 pub static DISABLE_TRACE_LOGGING: AtomicBool = AtomicBool::new(false);
-pub static mut KERNEL_ELF_FILE: Option<&'static [u8]> = None;
+pub static KERNEL_ELF_FILE: OnceLock<&'static [u8]> = OnceLock::new();
+/// An ELF file holding only the kernel's symbol and string tables, generated at build time and
+/// loaded alongside the kernel by bootloaders which don't provide the kernel ELF file itself.
+pub static KERNEL_SYMBOLS_FILE: OnceLock<&'static [u8]> = OnceLock::new();
 static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
 
 // #[inline(never)]
@@ -74,7 +80,6 @@ impl Iterator for StackFrameIterator {
     }
 }
 
-// TODO Port over ELF file parsing and function name printing
 #[inline(never)]
 fn print_stack_trace() {
     let stack_frame_iterator = unsafe {
@@ -82,8 +87,17 @@ fn print_stack_trace() {
         asm!("mov {}, rbp", out(reg) first_trace_address);
         StackFrameIterator::new(first_trace_address)
     };
+    error!("Backtrace:");
     for instruction_address in stack_frame_iterator {
-        error!("  [{instruction_address:#x}]")
+        // Return addresses point after the call, which may be past the end of the function
+        match symbols::resolve(instruction_address - 1) {
+            Some((symbol, offset)) => error!(
+                "  [{instruction_address:#x}] {}+{:#x}",
+                symbol.demangled(),
+                offset + 1,
+            ),
+            None => error!("  [{instruction_address:#x}] <unknown>"),
+        }
     }
 }
 
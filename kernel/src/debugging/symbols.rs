@@ -0,0 +1,255 @@
+//! Resolution of kernel addresses to function names, using the symbol table of the kernel ELF
+//! file provided by the bootloader. Bootloaders which only load a flat image or can't pass the
+//! kernel file are given `kernel.symbols` instead, an ELF file generated at build time holding
+//! only the symbol and string tables.
+//!
+//! Parsing is done in place on every lookup without allocating, so that it's usable from the
+//! panic handler even if the heap is broken. Names are only demangled when displayed or compared.
+
+use core::fmt;
+
+const SECTION_TYPE_SYMBOL_TABLE: u32 = 2;
+const SYMBOL_TYPE_OBJECT: u8 = 1;
+const SYMBOL_TYPE_FUNCTION: u8 = 2;
+const SYMBOL_ENTRY_SIZE: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    /// A static or other data.
+    Object,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol {
+    /// Raw, possibly mangled, name.
+    pub name: &'static str,
+    pub address: usize,
+    pub size: usize,
+    pub kind: SymbolKind,
+}
+
+impl Symbol {
+    pub fn demangled(&self) -> Demangled<'static> {
+        Demangled(self.name)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Returns the file to read symbols from, preferring the whole kernel ELF file.
+fn symbols_file() -> Option<&'static [u8]> {
+    super::KERNEL_ELF_FILE
+        .get()
+        .or_else(|| super::KERNEL_SYMBOLS_FILE.get())
+        .copied()
+}
+
+/// Returns the kernel's symbol table and its string table.
+fn symbol_tables(elf: &'static [u8]) -> Option<(&'static [u8], &'static [u8])> {
+    if elf.get(..4)? != b"\x7FELF" {
+        return None;
+    }
+    let section_headers_offset = read_u64(elf, 0x28)? as usize;
+    let section_header_size = read_u16(elf, 0x3A)? as usize;
+    let num_section_headers = read_u16(elf, 0x3C)? as usize;
+    let section = |index: usize| {
+        let header = section_headers_offset + index * section_header_size;
+        let offset = read_u64(elf, header + 0x18)? as usize;
+        let size = read_u64(elf, header + 0x20)? as usize;
+        Some((
+            read_u32(elf, header + 0x4)?,
+            elf.get(offset..offset + size)?,
+            read_u32(elf, header + 0x28)? as usize,
+        ))
+    };
+    (0..num_section_headers).find_map(|index| {
+        let (section_type, symbols, link) = section(index)?;
+        if section_type != SECTION_TYPE_SYMBOL_TABLE {
+            return None;
+        }
+        let (_, strings, _) = section(link)?;
+        Some((symbols, strings))
+    })
+}
+
+fn symbol_name(strings: &'static [u8], offset: usize) -> Option<&'static str> {
+    let bytes = strings.get(offset..)?;
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Returns the first result of `f` over the function and data symbols. Names are only read for
+/// symbols `filter` accepts, given their address and size.
+fn find_symbol<T>(
+    mut filter: impl FnMut(usize, usize) -> bool,
+    mut f: impl FnMut(Symbol) -> Option<T>,
+) -> Option<T> {
+    let (symbols, strings) = symbol_tables(symbols_file()?)?;
+    symbols.chunks_exact(SYMBOL_ENTRY_SIZE).find_map(|entry| {
+        let kind = match entry[4] & 0xF {
+            SYMBOL_TYPE_FUNCTION => SymbolKind::Function,
+            SYMBOL_TYPE_OBJECT => SymbolKind::Object,
+            _ => return None,
+        };
+        let address = read_u64(entry, 8)? as usize;
+        let size = read_u64(entry, 16)? as usize;
+        if !filter(address, size) {
+            return None;
+        }
+        f(Symbol {
+            name: symbol_name(strings, read_u32(entry, 0)? as usize)?,
+            address,
+            size,
+            kind,
+        })
+    })
+}
+
+/// Returns the function or data containing `address`, along with the offset of `address` into
+/// it.
+pub fn resolve(address: usize) -> Option<(Symbol, usize)> {
+    find_symbol(
+        |symbol_address, size| (symbol_address..symbol_address + size).contains(&address),
+        |symbol| Some((symbol, address - symbol.address)),
+    )
+}
+
+/// Returns the symbol called `name`, either its raw name or its demangled path without the hash,
+/// such as `kernel::kthread::spawn`.
+pub fn lookup(name: &str) -> Option<Symbol> {
+    find_symbol(
+        |_, _| true,
+        |symbol| (symbol.name == name || symbol.demangled() == *name).then_some(symbol),
+    )
+}
+
+/// Displays a symbol name, demangling it if it uses the legacy Rust mangling scheme. Other
+/// names are displayed as is.
+#[derive(Clone, Copy, Debug)]
+pub struct Demangled<'a>(pub &'a str);
+
+impl Demangled<'_> {
+    /// Calls `f` on each path segment of a legacy mangled name, along with whether it's the first
+    /// segment. Returns `None` if the name isn't a legacy mangled name. The trailing hash segment
+    /// is skipped.
+    fn for_each_segment(
+        &self,
+        mut f: impl FnMut(&str, bool) -> fmt::Result,
+    ) -> Option<fmt::Result> {
+        let mut rest = self.0.strip_prefix("_ZN")?;
+        let mut first = true;
+        loop {
+            if let Some(remaining) = rest.strip_prefix('E') {
+                return remaining.is_empty().then_some(Ok(()));
+            }
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let len: usize = rest[..digits].parse().ok()?;
+            let segment = rest.get(digits..digits + len)?;
+            rest = &rest[digits + len..];
+            let is_hash = rest == "E"
+                && segment.len() == 17
+                && segment.starts_with('h')
+                && segment[1..].bytes().all(|byte| byte.is_ascii_hexdigit());
+            if is_hash {
+                continue;
+            }
+            if let Err(err) = f(segment, first) {
+                return Some(Err(err));
+            }
+            first = false;
+        }
+    }
+}
+
+const ESCAPES: &[(&str, &str)] = &[
+    ("$SP$", "@"),
+    ("$BP$", "*"),
+    ("$RF$", "&"),
+    ("$LT$", "<"),
+    ("$GT$", ">"),
+    ("$LP$", "("),
+    ("$RP$", ")"),
+    ("$C$", ","),
+    ("$u20$", " "),
+    ("$u27$", "'"),
+    ("$u5b$", "["),
+    ("$u5d$", "]"),
+    ("$u7b$", "{"),
+    ("$u7d$", "}"),
+    ("$u7e$", "~"),
+];
+
+fn write_unescaped(f: &mut fmt::Formatter, segment: &str) -> fmt::Result {
+    // A leading underscore is added to segments starting with an escape
+    let mut rest = match segment.starts_with("_$") {
+        true => &segment[1..],
+        false => segment,
+    };
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some((escape, replacement)) =
+            ESCAPES.iter().find(|(escape, _)| rest.starts_with(escape))
+        {
+            f.write_str(replacement)?;
+            rest = &rest[escape.len()..];
+        } else {
+            let len = rest.chars().next().map_or(1, char::len_utf8);
+            f.write_str(&rest[..len])?;
+            rest = &rest[len..];
+        }
+    }
+    Ok(())
+}
+
+/// Compares a demangled name to a string, without writing it anywhere.
+impl PartialEq<str> for Demangled<'_> {
+    fn eq(&self, other: &str) -> bool {
+        struct Matcher<'a>(&'a str);
+
+        impl fmt::Write for Matcher<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                // Stops formatting at the first difference
+                self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+                Ok(())
+            }
+        }
+
+        let mut matcher = Matcher(other);
+        fmt::write(&mut matcher, format_args!("{self}")).is_ok() && matcher.0.is_empty()
+    }
+}
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Validate first, so nothing is written for names which turn out not to be mangled
+        if self.for_each_segment(|_, _| Ok(())).is_none() {
+            return f.write_str(self.0);
+        }
+        self.for_each_segment(|segment, first| {
+            if !first {
+                f.write_str("::")?;
+            }
+            write_unescaped(f, segment)
+        })
+        .unwrap_or(Ok(()))
+    }
+}
@@ -0,0 +1,165 @@
+//! Device model, tracking registered devices and their power states.
+//!
+//! Devices may only depend on devices registered before them, so registration order is always a
+//! valid dependency order. Devices are suspended in reverse registration order, so nothing is
+//! suspended while a device depending on it is still active, and resumed in registration order.
+
+use crate::event::{self, Event};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceId(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    Active,
+    Suspended,
+    /// The device failed to resume, and is in an unknown state.
+    Failed,
+}
+
+/// Power management callbacks for a device.
+#[derive(Clone, Copy, Debug)]
+pub struct PowerOps {
+    pub suspend: fn() -> Result<(), &'static str>,
+    pub resume: fn() -> Result<(), &'static str>,
+}
+
+impl PowerOps {
+    /// For devices which keep no state that needs saving.
+    pub const NONE: Self = Self {
+        suspend: || Ok(()),
+        resume: || Ok(()),
+    };
+}
+
+#[derive(Debug)]
+struct Device {
+    name: &'static str,
+    dependencies: Vec<DeviceId>,
+    ops: PowerOps,
+    state: PowerState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("device {device} failed to suspend - {reason}")]
+pub struct SuspendError {
+    pub device: &'static str,
+    pub reason: &'static str,
+}
+
+/// Registers an active device, which must only depend on already registered devices.
+pub fn register(name: &'static str, dependencies: &[DeviceId], ops: PowerOps) -> DeviceId {
+    let mut devices = DEVICES.lock();
+    let id = DeviceId(devices.len());
+    assert!(
+        dependencies.iter().all(|dependency| *dependency < id),
+        "device {name} depends on an unregistered device",
+    );
+    devices.push(Device {
+        name,
+        dependencies: dependencies.to_vec(),
+        ops,
+        state: PowerState::Active,
+    });
+    drop(devices);
+    event::publish(Event::DeviceAdded { id, name });
+    id
+}
+
+pub fn power_state(id: DeviceId) -> PowerState {
+    DEVICES.lock()[id.0].state
+}
+
+/// Suspends every active device, dependents first. If a device fails to suspend, the devices
+/// already suspended are resumed again.
+pub fn suspend_all() -> Result<(), SuspendError> {
+    let mut devices = DEVICES.lock();
+    for index in (0..devices.len()).rev() {
+        let device = &mut devices[index];
+        if device.state != PowerState::Active {
+            continue;
+        }
+        if let Err(reason) = (device.ops.suspend)() {
+            let err = SuspendError {
+                device: device.name,
+                reason,
+            };
+            drop(devices);
+            resume_all();
+            return Err(err);
+        }
+        device.state = PowerState::Suspended;
+    }
+    Ok(())
+}
+
+/// Resumes every suspended device, dependencies first. Devices that fail to resume, or which
+/// depend on a device that failed, are marked as failed. Returns the names of failed devices.
+pub fn resume_all() -> Vec<&'static str> {
+    let mut devices = DEVICES.lock();
+    let mut failed = Vec::new();
+    for index in 0..devices.len() {
+        if devices[index].state != PowerState::Suspended {
+            continue;
+        }
+        let dependency_failed = devices[index]
+            .dependencies
+            .iter()
+            .any(|dependency| devices[dependency.0].state != PowerState::Active);
+        let device = &mut devices[index];
+        let result = match dependency_failed {
+            true => Err("dependency not active"),
+            false => (device.ops.resume)(),
+        };
+        match result {
+            Ok(()) => device.state = PowerState::Active,
+            Err(reason) => {
+                log::error!("Device {} failed to resume - {reason}", device.name);
+                device.state = PowerState::Failed;
+                failed.push(device.name);
+            }
+        }
+    }
+    failed
+}
+
+/// Suspends and resumes every device without putting the platform to sleep. Returns the names of
+/// devices which failed to resume.
+pub fn freeze_cycle() -> Result<Vec<&'static str>, SuspendError> {
+    suspend_all()?;
+    Ok(resume_all())
+}
+
+/// Repeatedly freezes and thaws all devices, then exits QEMU with the result through its
+/// `isa-debug-exit` device. Returns if that device isn't present.
+#[cfg(feature = "suspend-test")]
+pub fn run_suspend_test(num_cycles: usize) {
+    use crate::arch::port;
+    let mut passed = true;
+    for cycle in 0..num_cycles {
+        match freeze_cycle() {
+            Ok(failed) if failed.is_empty() => {}
+            Ok(failed) => {
+                log::error!("Suspend test cycle {cycle}: devices failed to resume: {failed:?}");
+                passed = false;
+                break;
+            }
+            Err(err) => {
+                log::error!("Suspend test cycle {cycle}: {err}");
+                passed = false;
+                break;
+            }
+        }
+    }
+    if passed {
+        log::info!("Suspend test passed, {num_cycles} cycles");
+    }
+    unsafe {
+        port::write_byte(port::QEMU_DEBUG_EXIT, if passed { 0 } else { 1 });
+    }
+    log::warn!("QEMU debug exit device not present, continuing boot");
+}
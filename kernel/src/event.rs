@@ -0,0 +1,240 @@
+//! Kernel event bus, for notifying subsystems of things happening elsewhere without them needing
+//! references to each other.
+//!
+//! Subscribers are statics registered with `subscribe`, each receiving the kinds of event it asks
+//! for. Immediate subscribers are called by `publish` itself, in whatever context the event was
+//! published from, possibly an interrupt handler, so they must not block or allocate. Deferred
+//! subscribers are called later by the `kworker` thread, and can do anything a thread can.
+//!
+//! Publishing never allocates and is safe from interrupt handlers. Deferred events are buffered
+//! until the worker gets to them, and are dropped if too many are waiting.
+
+use crate::device::DeviceId;
+use crate::sync::IrqMutex;
+use crate::work_queue::{self, Work};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const MAX_SUBSCRIBERS: usize = 32;
+const MAX_PENDING_EVENTS: usize = 32;
+
+static SUBSCRIBERS: IrqMutex<[Option<&'static Subscriber>; MAX_SUBSCRIBERS]> =
+    IrqMutex::new([None; MAX_SUBSCRIBERS]);
+static PENDING_EVENTS: IrqMutex<EventRing> = IrqMutex::new(EventRing {
+    events: [None; MAX_PENDING_EVENTS],
+    start: 0,
+    len: 0,
+});
+static DELIVER_WORK: Work = Work::new(deliver_pending, 0);
+static DROPPED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Free physical memory has dropped below the low watermark.
+    MemoryPressure {
+        free_pages: usize,
+    },
+    DeviceAdded {
+        id: DeviceId,
+        name: &'static str,
+    },
+    DeviceRemoved {
+        id: DeviceId,
+        name: &'static str,
+    },
+    PowerButton,
+    NetworkLinkUp {
+        interface: usize,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::MemoryPressure { .. } => EventKind::MemoryPressure,
+            Self::DeviceAdded { .. } => EventKind::DeviceAdded,
+            Self::DeviceRemoved { .. } => EventKind::DeviceRemoved,
+            Self::PowerButton => EventKind::PowerButton,
+            Self::NetworkLinkUp { .. } => EventKind::NetworkLinkUp,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MemoryPressure { free_pages } => {
+                write!(f, "memory pressure ({free_pages} pages free)")
+            }
+            Self::DeviceAdded { name, .. } => write!(f, "device {name} added"),
+            Self::DeviceRemoved { name, .. } => write!(f, "device {name} removed"),
+            Self::PowerButton => write!(f, "power button pressed"),
+            Self::NetworkLinkUp { interface } => write!(f, "network interface {interface} up"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum EventKind {
+    MemoryPressure,
+    DeviceAdded,
+    DeviceRemoved,
+    PowerButton,
+    NetworkLinkUp,
+}
+
+impl EventKind {
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Called by `publish`, in the publisher's context.
+    Immediate,
+    /// Called later by the `kworker` thread.
+    Deferred,
+}
+
+/// A handler for some kinds of event.
+#[derive(Debug)]
+pub struct Subscriber {
+    name: &'static str,
+    kinds: u32,
+    delivery: Delivery,
+    handler: fn(&Event),
+}
+
+impl Subscriber {
+    pub const fn new(
+        name: &'static str,
+        kinds: &[EventKind],
+        delivery: Delivery,
+        handler: fn(&Event),
+    ) -> Self {
+        let mut mask = 0;
+        let mut index = 0;
+        while index < kinds.len() {
+            mask |= kinds[index].bit();
+            index += 1;
+        }
+        Self {
+            name,
+            kinds: mask,
+            delivery,
+            handler,
+        }
+    }
+
+    fn wants(&self, event: &Event, delivery: Delivery) -> bool {
+        self.delivery == delivery && self.kinds & event.kind().bit() != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SubscribeError {
+    #[error("already subscribed")]
+    AlreadySubscribed,
+    #[error("too many subscribers")]
+    TooManySubscribers,
+}
+
+struct EventRing {
+    events: [Option<Event>; MAX_PENDING_EVENTS],
+    start: usize,
+    len: usize,
+}
+
+impl EventRing {
+    /// Adds an event, returning `false` if the ring is full.
+    fn push(&mut self, event: Event) -> bool {
+        if self.len == MAX_PENDING_EVENTS {
+            return false;
+        }
+        self.events[(self.start + self.len) % MAX_PENDING_EVENTS] = Some(event);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.start].take();
+        self.start = (self.start + 1) % MAX_PENDING_EVENTS;
+        self.len -= 1;
+        event
+    }
+}
+
+/// Starts delivering events to `subscriber`.
+pub fn subscribe(subscriber: &'static Subscriber) -> Result<(), SubscribeError> {
+    let mut subscribers = SUBSCRIBERS.lock();
+    if subscribers
+        .iter()
+        .flatten()
+        .any(|existing| core::ptr::eq(*existing, subscriber))
+    {
+        return Err(SubscribeError::AlreadySubscribed);
+    }
+    let slot = subscribers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(SubscribeError::TooManySubscribers)?;
+    *slot = Some(subscriber);
+    Ok(())
+}
+
+/// Stops delivering events to `subscriber`. Deferred events already published may still be
+/// delivered to it if the worker is running them.
+pub fn unsubscribe(subscriber: &'static Subscriber) {
+    let mut subscribers = SUBSCRIBERS.lock();
+    for slot in subscribers.iter_mut() {
+        if slot.is_some_and(|existing| core::ptr::eq(existing, subscriber)) {
+            *slot = None;
+        }
+    }
+}
+
+/// Sends `event` to every subscriber interested in it. Safe to call from interrupt handlers.
+pub fn publish(event: Event) {
+    // Copied out, so that handlers can subscribe and publish themselves
+    let subscribers = *SUBSCRIBERS.lock();
+    let mut has_deferred = false;
+    for subscriber in subscribers.iter().flatten() {
+        if subscriber.wants(&event, Delivery::Immediate) {
+            (subscriber.handler)(&event);
+        }
+        has_deferred |= subscriber.wants(&event, Delivery::Deferred);
+    }
+    if !has_deferred {
+        return;
+    }
+    if PENDING_EVENTS.lock().push(event) {
+        work_queue::schedule(&DELIVER_WORK);
+    } else {
+        DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of events dropped because deferred subscribers were too far behind.
+pub fn dropped_events() -> usize {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+fn deliver_pending(_: usize) {
+    loop {
+        let Some(event) = PENDING_EVENTS.lock().pop() else {
+            return;
+        };
+        let subscribers = *SUBSCRIBERS.lock();
+        for subscriber in subscribers.iter().flatten() {
+            if subscriber.wants(&event, Delivery::Deferred) {
+                log::trace!("Delivering {event} to {}", subscriber.name);
+                (subscriber.handler)(&event);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Executor for long running kernel tasks, which do their work in slices.
+//!
+//! Tasks have the same interface as the VMA's map and unmap tasks. Each call to `run` does some
+//! of the task's work, calling `should_suspend` as it goes, and returns `Poll::Pending` if it
+//! stopped early because that returned `true`. The `kexecutor` thread resumes each pending task in
+//! turn for up to `TIME_SLICE_NS`, then yields to other threads before the next round, so a
+//! long task doesn't hold up anything else for more than a slice at a time. Segment map and
+//! unmap tasks are spawned by wrapping them in a closure which locks the process's segments for
+//! each slice.
+//!
+//! Spawning a task gives a `TaskHandle`, which can be waited on for the task's result.
+
+use crate::arch::clock;
+use crate::init_state::{self, Subsystem};
+use crate::kthread;
+use crate::wait_queue::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::task::Poll;
+use spin::Mutex;
+
+/// Longest a task is run for before the executor moves on to the next one.
+const TIME_SLICE_NS: u64 = 1_000_000;
+
+static PENDING_TASKS: Mutex<VecDeque<PendingTask>> = Mutex::new(VecDeque::new());
+/// Woken when a task is spawned.
+static SPAWNED: WaitQueue = WaitQueue::new();
+
+/// Work that can be suspended part way through and resumed later.
+pub trait Task: Send {
+    /// Runs the task until it completes, returning its result, or until `should_suspend`
+    /// returns `true`.
+    fn run(&mut self, should_suspend: &mut dyn FnMut() -> bool) -> Poll<usize>;
+}
+
+impl<F> Task for F
+where
+    F: FnMut(&mut dyn FnMut() -> bool) -> Poll<usize> + Send,
+{
+    fn run(&mut self, should_suspend: &mut dyn FnMut() -> bool) -> Poll<usize> {
+        self(should_suspend)
+    }
+}
+
+struct Completion {
+    result: Mutex<Option<usize>>,
+    waiters: WaitQueue,
+}
+
+struct PendingTask {
+    task: Box<dyn Task>,
+    completion: Arc<Completion>,
+}
+
+/// Handle to a spawned task. Dropping this leaves the task running, and discards its result.
+pub struct TaskHandle {
+    completion: Arc<Completion>,
+}
+
+impl TaskHandle {
+    /// Returns the task's result, or `None` if it hasn't completed yet.
+    pub fn try_result(&self) -> Option<usize> {
+        *self.completion.result.lock()
+    }
+
+    /// Blocks until the task completes, returning its result.
+    pub fn wait(self) -> usize {
+        let mut result = None;
+        self.completion.waiters.wait_until(|| {
+            result = self.try_result();
+            result.is_some()
+        });
+        result.unwrap()
+    }
+}
+
+/// Queues `task` to be run by the executor thread. Tasks spawned before `init` are run once the
+/// thread starts.
+pub fn spawn(task: impl Task + 'static) -> TaskHandle {
+    let completion = Arc::new(Completion {
+        result: Mutex::new(None),
+        waiters: WaitQueue::new(),
+    });
+    PENDING_TASKS.lock().push_back(PendingTask {
+        task: Box::new(task),
+        completion: completion.clone(),
+    });
+    SPAWNED.wake_one();
+    TaskHandle { completion }
+}
+
+/// Starts the executor thread.
+pub fn init() {
+    init_state::begin(Subsystem::Executor);
+    if let Err(err) = kthread::spawn("kexecutor", executor_thread, 0) {
+        panic!("failed to start executor thread - {err}");
+    }
+    init_state::finish(Subsystem::Executor);
+}
+
+fn executor_thread(_: usize) -> usize {
+    loop {
+        SPAWNED.wait_until(|| !PENDING_TASKS.lock().is_empty());
+        // Tasks spawned during the round wait until the next one
+        let num_tasks = PENDING_TASKS.lock().len();
+        for _ in 0..num_tasks {
+            let Some(mut pending) = PENDING_TASKS.lock().pop_front() else {
+                break;
+            };
+            let deadline_ns = clock::now_ns().saturating_add(TIME_SLICE_NS);
+            match pending.task.run(&mut || clock::now_ns() >= deadline_ns) {
+                Poll::Ready(result) => {
+                    *pending.completion.result.lock() = Some(result);
+                    pending.completion.waiters.wake_all();
+                }
+                Poll::Pending => PENDING_TASKS.lock().push_back(pending),
+            }
+        }
+        kthread::yield_now();
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn resumes_suspended_tasks() -> TestResult {
+        let mut num_runs = 0;
+        let handle = spawn(move |_: &mut dyn FnMut() -> bool| {
+            num_runs += 1;
+            // Suspended on its first run as if its slice ran out, so it has to be resumed
+            match num_runs {
+                1 => Poll::Pending,
+                _ => Poll::Ready(num_runs),
+            }
+        });
+        ktest_assert_eq!(handle.wait(), 2);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn keeps_results_after_completion() -> TestResult {
+        let handle = spawn(|_: &mut dyn FnMut() -> bool| Poll::Ready(42));
+        while handle.try_result().is_none() {
+            kthread::yield_now();
+        }
+        ktest_assert_eq!(handle.try_result(), Some(42));
+        ktest_assert_eq!(handle.wait(), 42);
+        Ok(())
+    }
+}
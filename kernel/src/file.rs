@@ -0,0 +1,382 @@
+//! Open files, and the table of file descriptors each process refers to them by.
+//!
+//! There's no VFS yet, so the only files are the initrd's, which are read-only, and the console
+//! TTY at `CONSOLE_PATH`. Descriptors are small integers, with the lowest free one used for each
+//! newly opened file, and each has its own position and access mode. A process's files are closed
+//! when it exits.
+//!
+//! The descriptor table is only locked while looking up a file, so reads from devices can block.
+
+use crate::arch::syscall::SyscallError;
+use crate::cpio;
+use crate::kthread;
+use crate::process::Process;
+use crate::tty::{self, TtyMode};
+use crate::user_memory::{self, UserArgs};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Bits of the open syscall's flags.
+pub const OPEN_READ: usize = 1 << 0;
+pub const OPEN_WRITE: usize = 1 << 1;
+/// Where the seek syscall's offset is relative to.
+pub const SEEK_START: usize = 0;
+pub const SEEK_CURRENT: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Bits of the set TTY mode syscall's flags.
+pub const TTY_CANONICAL: usize = 1 << 0;
+pub const TTY_ECHO: usize = 1 << 1;
+
+pub const CONSOLE_PATH: &str = "/dev/console";
+
+/// Most files a process can have open at once.
+const MAX_OPEN_FILES: usize = 64;
+/// Longest path accepted by the open syscall.
+const MAX_PATH_LEN: usize = 256;
+/// Most bytes read or written by one system call.
+const MAX_TRANSFER_LEN: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FileError {
+    #[error("no such file descriptor")]
+    BadDescriptor,
+    #[error("file not opened for this access")]
+    AccessDenied,
+    #[error("file is read-only")]
+    ReadOnly,
+    #[error("too many open files")]
+    TooManyFiles,
+    #[error("position out of range")]
+    InvalidPosition,
+    #[error("file isn't seekable")]
+    NotSeekable,
+}
+
+impl From<FileError> for SyscallError {
+    fn from(err: FileError) -> Self {
+        match err {
+            FileError::BadDescriptor => SyscallError::BAD_DESCRIPTOR,
+            _ => SyscallError::INVALID_ARGUMENT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileAccess {
+    pub read: bool,
+    pub write: bool,
+}
+
+/// What an open file refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileObject {
+    /// The contents of an initrd file.
+    Initrd(&'static [u8]),
+    /// The console TTY.
+    Console,
+}
+
+impl FileObject {
+    /// Reads into `buffer` from `position`, returning the number of bytes read. Blocks until
+    /// there's input for devices.
+    pub fn read(self, position: usize, buffer: &mut [u8]) -> usize {
+        match self {
+            FileObject::Initrd(data) => {
+                let data = data.get(position..).unwrap_or_default();
+                let len = buffer.len().min(data.len());
+                buffer[..len].copy_from_slice(&data[..len]);
+                len
+            }
+            FileObject::Console => tty::CONSOLE.read(buffer),
+        }
+    }
+
+    /// Writes `data` at `position`, returning the number of bytes written.
+    pub fn write(self, _position: usize, data: &[u8]) -> Result<usize, FileError> {
+        match self {
+            FileObject::Initrd(_) => Err(FileError::ReadOnly),
+            FileObject::Console => {
+                tty::CONSOLE.write(data);
+                Ok(data.len())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OpenFile {
+    object: FileObject,
+    position: usize,
+    access: FileAccess,
+}
+
+impl OpenFile {
+    pub fn object(&self) -> FileObject {
+        self.object
+    }
+
+    /// Returns the object and position to read from, if the file was opened for reading.
+    fn start_read(&self) -> Result<(FileObject, usize), FileError> {
+        match self.access.read {
+            true => Ok((self.object, self.position)),
+            false => Err(FileError::AccessDenied),
+        }
+    }
+
+    /// Returns the object and position to write to, if the file was opened for writing.
+    fn start_write(&self) -> Result<(FileObject, usize), FileError> {
+        match self.access.write {
+            true => Ok((self.object, self.position)),
+            false => Err(FileError::AccessDenied),
+        }
+    }
+
+    /// Moves to `offset` bytes from `whence`, returning the new position. Positions past the end
+    /// are allowed, and read nothing.
+    pub fn seek(&mut self, offset: isize, whence: usize) -> Result<usize, FileError> {
+        let FileObject::Initrd(data) = self.object else {
+            return Err(FileError::NotSeekable);
+        };
+        let base = match whence {
+            SEEK_START => 0,
+            SEEK_CURRENT => self.position,
+            SEEK_END => data.len(),
+            _ => return Err(FileError::InvalidPosition),
+        };
+        self.position = base
+            .checked_add_signed(offset)
+            .ok_or(FileError::InvalidPosition)?;
+        Ok(self.position)
+    }
+}
+
+/// A process's open files, indexed by file descriptor.
+#[derive(Debug, Default)]
+pub struct FileTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl FileTable {
+    pub const fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// Opens `object` with `access`, returning its descriptor.
+    pub fn open(&mut self, object: FileObject, access: FileAccess) -> Result<usize, FileError> {
+        if access.write && matches!(object, FileObject::Initrd(_)) {
+            return Err(FileError::ReadOnly);
+        }
+        let file = OpenFile {
+            object,
+            position: 0,
+            access,
+        };
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Ok(fd)
+            }
+            None if self.files.len() < MAX_OPEN_FILES => {
+                self.files.push(Some(file));
+                Ok(self.files.len() - 1)
+            }
+            None => Err(FileError::TooManyFiles),
+        }
+    }
+
+    pub fn get_mut(&mut self, fd: usize) -> Result<&mut OpenFile, FileError> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::as_mut)
+            .ok_or(FileError::BadDescriptor)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), FileError> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(FileError::BadDescriptor)?;
+        Ok(())
+    }
+
+    /// Closes every file.
+    pub fn close_all(&mut self) {
+        self.files.clear();
+    }
+}
+
+fn current_process() -> Arc<Process> {
+    kthread::current_process().expect("file system call from a kernel thread")
+}
+
+#[derive(UserArgs)]
+struct OpenArgs {
+    #[user(read, len = path_len, max_len = MAX_PATH_LEN)]
+    path_ptr: *const u8,
+    path_len: usize,
+}
+
+#[derive(UserArgs)]
+struct ReadArgs {
+    #[user(write, len = len)]
+    buffer_ptr: *mut u8,
+    len: usize,
+}
+
+#[derive(UserArgs)]
+struct WriteArgs {
+    #[user(read, len = len)]
+    buffer_ptr: *const u8,
+    len: usize,
+}
+
+/// Handler for the open syscall. Opens the initrd file or device at `path` with the access given
+/// by `flags`, returning its descriptor.
+pub fn syscall_open(
+    path_ptr: *const u8,
+    path_len: usize,
+    flags: usize,
+) -> Result<usize, SyscallError> {
+    if flags & !(OPEN_READ | OPEN_WRITE) != 0 {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let args = OpenArgs { path_ptr, path_len };
+    args.validate()?;
+    let path = user_memory::read_string(args.path_ptr, args.path_len, MAX_PATH_LEN)?;
+    let object = match path.as_str() {
+        CONSOLE_PATH => FileObject::Console,
+        path => {
+            let initrd = cpio::INITRD.lock().ok_or(SyscallError::NOT_FOUND)?;
+            let data = cpio::find_file(initrd, path.as_bytes()).ok_or(SyscallError::NOT_FOUND)?;
+            FileObject::Initrd(data)
+        }
+    };
+    let access = FileAccess {
+        read: flags & OPEN_READ != 0,
+        write: flags & OPEN_WRITE != 0,
+    };
+    Ok(current_process().files.lock().open(object, access)?)
+}
+
+/// Handler for the close syscall.
+pub fn syscall_close(fd: usize) -> Result<usize, SyscallError> {
+    current_process().files.lock().close(fd)?;
+    Ok(0)
+}
+
+/// Handler for the read syscall. Reads up to `len` bytes from `fd` into `buffer_ptr`, returning the
+/// number read, which is 0 at the end of the file. At most `MAX_TRANSFER_LEN` bytes are read at a
+/// time.
+pub fn syscall_read(fd: usize, buffer_ptr: *mut u8, len: usize) -> Result<usize, SyscallError> {
+    ReadArgs { buffer_ptr, len }.validate()?;
+    let process = current_process();
+    let (object, position) = process.files.lock().get_mut(fd)?.start_read()?;
+    let mut buffer = vec![0; len.min(MAX_TRANSFER_LEN)];
+    let num_read = object.read(position, &mut buffer);
+    user_memory::copy_to_user(buffer_ptr as usize, &buffer[..num_read])?;
+    // The file may have been closed while blocked
+    if let Ok(file) = process.files.lock().get_mut(fd) {
+        file.position = position + num_read;
+    }
+    Ok(num_read)
+}
+
+/// Handler for the write syscall. Writes up to `len` bytes at `buffer_ptr` to `fd`, returning the
+/// number written. At most `MAX_TRANSFER_LEN` bytes are written at a time.
+pub fn syscall_write(fd: usize, buffer_ptr: *const u8, len: usize) -> Result<usize, SyscallError> {
+    WriteArgs { buffer_ptr, len }.validate()?;
+    let process = current_process();
+    let (object, position) = process.files.lock().get_mut(fd)?.start_write()?;
+    let mut data = vec![0; len.min(MAX_TRANSFER_LEN)];
+    user_memory::copy_from_user(&mut data, buffer_ptr as usize)?;
+    let num_written = object.write(position, &data)?;
+    if let Ok(file) = process.files.lock().get_mut(fd) {
+        file.position = position + num_written;
+    }
+    Ok(num_written)
+}
+
+/// Handler for the seek syscall. Moves `fd` to `offset` bytes from `whence`, returning the new
+/// position.
+pub fn syscall_seek(fd: usize, offset: isize, whence: usize) -> Result<usize, SyscallError> {
+    Ok(current_process()
+        .files
+        .lock()
+        .get_mut(fd)?
+        .seek(offset, whence)?)
+}
+
+/// Handler for the set TTY mode syscall. Sets the line discipline of the TTY `fd` refers to from
+/// `flags`.
+pub fn syscall_set_tty_mode(fd: usize, flags: usize) -> Result<usize, SyscallError> {
+    if flags & !(TTY_CANONICAL | TTY_ECHO) != 0 {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let object = current_process().files.lock().get_mut(fd)?.object();
+    if object != FileObject::Console {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    tty::CONSOLE.set_mode(TtyMode {
+        canonical: flags & TTY_CANONICAL != 0,
+        echo: flags & TTY_ECHO != 0,
+    });
+    Ok(0)
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    const READ_ONLY: FileAccess = FileAccess {
+        read: true,
+        write: false,
+    };
+
+    #[kernel_test]
+    fn reads_and_seeks() -> TestResult {
+        let mut files = FileTable::new();
+        let fd = files
+            .open(FileObject::Initrd(b"hello world"), READ_ONLY)
+            .unwrap();
+        let file = files.get_mut(fd).unwrap();
+        let mut buffer = [0; 5];
+        ktest_assert_eq!(
+            file.start_read(),
+            Ok((FileObject::Initrd(b"hello world"), 0))
+        );
+        ktest_assert_eq!(file.seek(6, SEEK_START), Ok(6));
+        let (object, position) = file.start_read().unwrap();
+        ktest_assert_eq!(object.read(position, &mut buffer), 5);
+        ktest_assert_eq!(&buffer, b"world");
+        ktest_assert_eq!(object.read(position + 5, &mut buffer), 0);
+        ktest_assert_eq!(file.seek(-5, SEEK_END), Ok(6));
+        ktest_assert_eq!(file.seek(-7, SEEK_CURRENT), Err(FileError::InvalidPosition));
+        ktest_assert_eq!(file.start_write(), Err(FileError::AccessDenied));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn reuses_lowest_descriptor() -> TestResult {
+        let mut files = FileTable::new();
+        let empty = FileObject::Initrd(b"");
+        ktest_assert_eq!(files.open(empty, READ_ONLY), Ok(0));
+        ktest_assert_eq!(files.open(FileObject::Console, READ_ONLY), Ok(1));
+        ktest_assert_eq!(files.close(0), Ok(()));
+        ktest_assert_eq!(files.close(0), Err(FileError::BadDescriptor));
+        ktest_assert_eq!(files.open(empty, READ_ONLY), Ok(0));
+        let read_write = FileAccess {
+            read: true,
+            write: true,
+        };
+        ktest_assert_eq!(files.open(empty, read_write), Err(FileError::ReadOnly));
+        ktest_assert_eq!(
+            files.get_mut(1).unwrap().seek(0, SEEK_START),
+            Err(FileError::NotSeekable),
+        );
+        ktest_assert!(files.get_mut(2).is_err());
+        Ok(())
+    }
+}
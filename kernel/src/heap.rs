@@ -1,24 +1,28 @@
 use crate::arch::page_allocation;
 use crate::arch::paging::{PAGE_SIZE, PageTableEntry, align_to_page};
+use crate::init_state::{self, Subsystem};
+use crate::memory_tag::{self, MemoryTag};
+use crate::sync::IrqMutex;
 use bitfield::bitfield;
 use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
 use core::iter::Iterator;
 use core::mem::{align_of, size_of};
 use core::ptr::{self, NonNull};
-use spin::Mutex;
 
 #[cfg(target_pointer_width = "64")]
 bitfield! {
     #[repr(transparent)]
     struct Block(u64);
-    len_internal, set_len_internal: 61, 0;
+    len_internal, set_len_internal: 53, 0;
+    tag_internal, set_tag_internal: 61, 54;
     pub used, set_used: 62;
     pub has_next, set_has_next: 63;
 }
 
 impl Block {
     #[cfg(target_pointer_width = "64")]
-    const LEN_MASK: u64 = 0x3FFF_FFFF_FFFF_FFFF;
+    const LEN_MASK: u64 = 0x003F_FFFF_FFFF_FFFF;
     #[cfg(target_pointer_width = "32")]
     const LEN_MASK: u32 = 0x3FFF_FFFF;
 
@@ -43,6 +47,17 @@ impl Block {
         self.set_len_internal(value as u32);
     }
 
+    /// Returns the memory tag the block was allocated under.
+    #[cfg(target_pointer_width = "64")]
+    pub fn tag(&self) -> MemoryTag {
+        MemoryTag::from_u8(self.tag_internal() as u8)
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    pub fn set_tag(&mut self, tag: MemoryTag) {
+        self.set_tag_internal(tag as u64);
+    }
+
     /// Returns the start address of the inner block.
     pub fn start_address(&self) -> usize {
         self as *const Self as usize + size_of::<Self>()
@@ -82,20 +97,207 @@ impl Iterator for BlockIterator {
 }
 
 const PAGE_FLAGS: PageTableEntry = PageTableEntry::READ_WRITE;
+/// Allocations at least this large, needing at most page alignment, are mapped straight from the
+/// page allocator into the large allocation area rather than taking space in the heap.
+const LARGE_ALLOCATION_THRESHOLD: usize = 2 * PAGE_SIZE;
+/// Most pages the large allocation area can have, for sizing its bitmap.
+const MAX_LARGE_AREA_PAGES: usize = 0x10000;
+const FREE_HISTOGRAM_BUCKETS: usize = 16;
+
+fn is_large(layout: &Layout) -> bool {
+    layout.size() >= LARGE_ALLOCATION_THRESHOLD && layout.align() <= PAGE_SIZE
+}
+
+/// Area of virtual memory for large allocations, which are page granular.
+struct LargeArea {
+    start_address: usize,
+    num_pages: usize,
+    /// One bit per page, set if the page is reserved.
+    bitmap: [u64; MAX_LARGE_AREA_PAGES / 64],
+    /// One bit per page, set if an allocation starts at the page. Allocations are freed by
+    /// address alone, as callers such as ACPICA don't know the size when freeing.
+    starts: [u64; MAX_LARGE_AREA_PAGES / 64],
+    allocations: usize,
+    pages_used: usize,
+}
+
+impl LargeArea {
+    fn contains(&self, address: usize) -> bool {
+        (self.start_address..self.start_address + self.num_pages * PAGE_SIZE).contains(&address)
+    }
+
+    fn is_reserved(&self, page: usize) -> bool {
+        self.bitmap[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn is_start(&self, page: usize) -> bool {
+        self.starts[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_reserved(&mut self, first_page: usize, num_pages: usize, reserved: bool) {
+        for page in first_page..first_page + num_pages {
+            match reserved {
+                true => self.bitmap[page / 64] |= 1 << (page % 64),
+                false => self.bitmap[page / 64] &= !(1 << (page % 64)),
+            }
+        }
+    }
+
+    /// Reserves the first run of `num_pages` free pages for an allocation, returning the address
+    /// of the first page.
+    fn reserve(&mut self, num_pages: usize) -> Option<usize> {
+        let mut run_start = 0;
+        for page in 0..self.num_pages {
+            if self.is_reserved(page) {
+                run_start = page + 1;
+            } else if page + 1 - run_start == num_pages {
+                self.set_reserved(run_start, num_pages, true);
+                self.starts[run_start / 64] |= 1 << (run_start % 64);
+                return Some(self.start_address + run_start * PAGE_SIZE);
+            }
+        }
+        None
+    }
+
+    /// Unreserves the allocation starting at `address`, returning the number of pages it had.
+    fn unreserve(&mut self, address: usize) -> usize {
+        let first_page = (address - self.start_address) / PAGE_SIZE;
+        debug_assert!(self.is_start(first_page));
+        let num_pages = (first_page + 1..self.num_pages)
+            .take_while(|&page| self.is_reserved(page) && !self.is_start(page))
+            .count()
+            + 1;
+        self.starts[first_page / 64] &= !(1 << (first_page % 64));
+        self.set_reserved(first_page, num_pages, false);
+        num_pages
+    }
+
+    fn largest_free_run(&self) -> usize {
+        let mut largest = 0;
+        let mut run_start = 0;
+        for page in 0..self.num_pages {
+            if self.is_reserved(page) {
+                run_start = page + 1;
+            } else {
+                largest = largest.max(page + 1 - run_start);
+            }
+        }
+        largest
+    }
+}
+
+/// Snapshot of heap usage, for diagnosing fragmentation and exhaustion.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    pub used_bytes: usize,
+    pub used_blocks: usize,
+    /// Free space in the heap area, most of which isn't backed by pages yet.
+    pub free_bytes: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+    /// Number of free blocks by size. Bucket `i` counts blocks of at least `2^(i + 4)` bytes and
+    /// less than double that, apart from the first and last buckets which also count blocks
+    /// smaller and larger respectively.
+    pub free_histogram: [usize; FREE_HISTOGRAM_BUCKETS],
+    pub large_allocations: usize,
+    pub large_pages_used: usize,
+    /// Largest large allocation that could currently succeed, in pages.
+    pub large_largest_free_run: usize,
+}
+
+impl HeapStats {
+    fn histogram_bucket(len: usize) -> usize {
+        (len.max(1).ilog2() as usize)
+            .saturating_sub(4)
+            .min(FREE_HISTOGRAM_BUCKETS - 1)
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Heap: {} bytes used in {} blocks, {} bytes free in {} blocks, largest free block {} bytes",
+            self.used_bytes,
+            self.used_blocks,
+            self.free_bytes,
+            self.free_blocks,
+            self.largest_free_block,
+        )?;
+        write!(f, "Free blocks by size:")?;
+        for (i, count) in self.free_histogram.iter().enumerate() {
+            if *count != 0 {
+                let min_size = if i == 0 { 0 } else { 1 << (i + 4) };
+                write!(f, " {min_size}+: {count},")?;
+            }
+        }
+        writeln!(f)?;
+        write!(
+            f,
+            "Large allocations: {} using {} pages, largest free run {} pages",
+            self.large_allocations, self.large_pages_used, self.large_largest_free_run,
+        )
+    }
+}
 
 struct KernelHeapAllocator {
-    pub list_head: Mutex<Option<NonNull<Block>>>,
+    pub list_head: IrqMutex<Option<NonNull<Block>>>,
+    large_area: IrqMutex<Option<LargeArea>>,
 }
 
 unsafe impl Sync for KernelHeapAllocator {}
 
-unsafe impl GlobalAlloc for KernelHeapAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl KernelHeapAllocator {
+    unsafe fn alloc_large(&self, layout: Layout) -> *mut u8 {
+        unsafe {
+            let num_pages = layout.size().div_ceil(PAGE_SIZE);
+            let mut lock = self.large_area.lock();
+            let Some(area) = lock.as_mut() else {
+                return ptr::null_mut();
+            };
+            // An extra page is reserved but left unmapped after each allocation, to catch overruns
+            let Some(start_address) = area.reserve(num_pages + 1) else {
+                return ptr::null_mut();
+            };
+            for i in 0..num_pages {
+                if page_allocation::map_page(start_address + i * PAGE_SIZE, PAGE_FLAGS).is_err() {
+                    for page in (start_address..start_address + i * PAGE_SIZE).step_by(PAGE_SIZE) {
+                        page_allocation::unmap_and_free_page(page);
+                    }
+                    area.unreserve(start_address);
+                    return ptr::null_mut();
+                }
+            }
+            area.allocations += 1;
+            area.pages_used += num_pages;
+            start_address as *mut u8
+        }
+    }
+
+    unsafe fn dealloc_large(&self, ptr: *mut u8) {
+        unsafe {
+            let start_address = ptr as usize;
+            let mut lock = self.large_area.lock();
+            let area = lock.as_mut().unwrap();
+            // Don't count the guard page
+            let num_pages = area.unreserve(start_address) - 1;
+            for page in (start_address..start_address + num_pages * PAGE_SIZE).step_by(PAGE_SIZE) {
+                page_allocation::unmap_and_free_page(page);
+            }
+            area.allocations -= 1;
+            area.pages_used -= num_pages;
+        }
+    }
+
+    unsafe fn alloc_small(&self, layout: Layout) -> *mut u8 {
         unsafe {
             let maybe_list_head_lock = self.list_head.lock();
             let Some(list_head) = maybe_list_head_lock.map(|mut ptr| ptr.as_mut()) else {
                 return ptr::null_mut();
             };
+            // Pages are shared between allocations, so they're counted against the heap itself
+            let tag = MemoryTag::current();
+            let _heap_tag = MemoryTag::Heap.enter();
             // Scan through list to find free space large enough
             for mut current_block_ptr in list_head.iter_mut() {
                 let current_block = current_block_ptr.as_mut();
@@ -141,6 +343,8 @@ unsafe impl GlobalAlloc for KernelHeapAllocator {
                         }
                     }
                 }
+                current_block.set_tag(tag);
+                memory_tag::heap_allocated(tag, current_block.len());
                 return start_addr as *mut u8;
             }
             // Space not found, return failure
@@ -148,7 +352,7 @@ unsafe impl GlobalAlloc for KernelHeapAllocator {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc_small(&self, ptr: *mut u8) {
         unsafe {
             let search_addr = ptr as usize;
             let list_head = self.list_head.lock().unwrap().as_mut();
@@ -162,6 +366,8 @@ unsafe impl GlobalAlloc for KernelHeapAllocator {
                     // Check for double free in debug mode
                     debug_assert!(current_block.used());
                     current_block.set_used(false);
+                    memory_tag::heap_freed(current_block.tag(), current_block.len());
+                    current_block.set_tag(MemoryTag::Untracked);
                     // Free middle pages
                     {
                         let start_page = min_addr.next_multiple_of(PAGE_SIZE);
@@ -223,19 +429,274 @@ unsafe impl GlobalAlloc for KernelHeapAllocator {
             }
         }
     }
+
+    fn is_large_allocation(&self, ptr: *mut u8) -> bool {
+        self.large_area
+            .lock()
+            .as_ref()
+            .is_some_and(|area| area.contains(ptr as usize))
+    }
+}
+
+#[cfg(not(feature = "heap-debug"))]
+unsafe impl GlobalAlloc for KernelHeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe {
+            match is_large(&layout) {
+                true => self.alloc_large(layout),
+                false => self.alloc_small(layout),
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        unsafe {
+            match self.is_large_allocation(ptr) {
+                true => self.dealloc_large(ptr),
+                false => self.dealloc_small(ptr),
+            }
+        }
+    }
+}
+
+/// Checks for heap corruption, enabled with the `heap-debug` feature.
+///
+/// Heap allocations get redzones before and after them, filled with `REDZONE` and checked when
+/// the allocation is freed, along with a header holding the allocation's size. New allocations
+/// are filled with `POISON_ALLOCATED`, and freed ones with `POISON_FREED`, so uses of
+/// uninitialised or freed memory stand out. Allocations too big to fit in the heap with their
+/// redzones go to the large allocation area, where they're followed by an unmapped page instead.
+///
+/// Counts of live allocations are kept for finding leaks. `mark_leak_check` starts counting the
+/// allocations made from then on which haven't been freed.
+#[cfg(feature = "heap-debug")]
+mod debug {
+    use super::{GlobalAlloc, KernelHeapAllocator, is_large};
+    use core::alloc::Layout;
+    use core::fmt;
+    use core::mem::{align_of, size_of};
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    pub const REDZONE: u8 = 0xBB;
+    pub const POISON_ALLOCATED: u8 = 0x5A;
+    pub const POISON_FREED: u8 = 0x6B;
+    const MAGIC_ALLOCATED: u32 = 0xA110_CA7E;
+    const MAGIC_FREED: u32 = 0xF1EE_D0D0;
+    /// Size of the redzone after each allocation, and the least before it.
+    const REDZONE_LEN: usize = 16;
+
+    /// Placed directly before each allocation, so it can be found from the allocation's address
+    /// alone. ACPICA doesn't give the size of allocations when freeing them.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Header {
+        magic: u32,
+        size: usize,
+        /// Bytes from the start of the front redzone to the allocation.
+        front_len: usize,
+        sequence: u64,
+    }
+
+    static LIVE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+    static PEAK_LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+    static TOTAL_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+    static TOTAL_FREES: AtomicU64 = AtomicU64::new(0);
+    static MARK_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    static LIVE_SINCE_MARK: AtomicUsize = AtomicUsize::new(0);
+
+    /// Counts of allocations with redzones, for finding leaks.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct HeapDebugStats {
+        pub live_allocations: usize,
+        pub live_bytes: usize,
+        pub peak_live_bytes: usize,
+        pub total_allocations: u64,
+        pub total_frees: u64,
+        /// Allocations made since `mark_leak_check` which are still live.
+        pub live_since_mark: usize,
+    }
+
+    impl fmt::Display for HeapDebugStats {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(
+                f,
+                "Live: {} allocations using {} bytes, peak {} bytes",
+                self.live_allocations, self.live_bytes, self.peak_live_bytes,
+            )?;
+            write!(
+                f,
+                "Total: {} allocations, {} frees, {} live since mark",
+                self.total_allocations, self.total_frees, self.live_since_mark,
+            )
+        }
+    }
+
+    /// Returns counts of live heap allocations.
+    pub fn stats() -> HeapDebugStats {
+        HeapDebugStats {
+            live_allocations: LIVE_ALLOCATIONS.load(Ordering::Relaxed),
+            live_bytes: LIVE_BYTES.load(Ordering::Relaxed),
+            peak_live_bytes: PEAK_LIVE_BYTES.load(Ordering::Relaxed),
+            total_allocations: TOTAL_ALLOCATIONS.load(Ordering::Relaxed),
+            total_frees: TOTAL_FREES.load(Ordering::Relaxed),
+            live_since_mark: LIVE_SINCE_MARK.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Starts counting the heap allocations made from now on which haven't been freed.
+    pub fn mark_leak_check() {
+        MARK_SEQUENCE.store(TOTAL_ALLOCATIONS.load(Ordering::Relaxed), Ordering::Relaxed);
+        LIVE_SINCE_MARK.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the layout including the redzones and header, and the offset of the allocation
+    /// into it.
+    fn redzoned_layout(layout: Layout) -> Option<(Layout, usize)> {
+        let align = layout.align().max(align_of::<Header>());
+        let front_len = (size_of::<Header>() + REDZONE_LEN).next_multiple_of(align);
+        let size = front_len
+            .checked_add(layout.size())?
+            .checked_add(REDZONE_LEN)?;
+        Some((Layout::from_size_align(size, align).ok()?, front_len))
+    }
+
+    fn check_redzone(bytes: &[u8], ptr: *mut u8, header: &Header, position: &str) {
+        if let Some(offset) = bytes.iter().position(|byte| *byte != REDZONE) {
+            panic!(
+                "heap redzone {position} allocation at {ptr:p} ({} bytes, allocation {}) \
+                corrupted at offset {offset} with {:#04x}",
+                header.size, header.sequence, bytes[offset],
+            );
+        }
+    }
+
+    unsafe impl GlobalAlloc for KernelHeapAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            unsafe {
+                let Some((inner_layout, front_len)) = redzoned_layout(layout) else {
+                    return core::ptr::null_mut();
+                };
+                // Large area allocations are told apart by address when freed
+                if is_large(&inner_layout) {
+                    return self.alloc_large(layout);
+                }
+                let inner = self.alloc_small(inner_layout);
+                if inner.is_null() {
+                    return inner;
+                }
+                let ptr = inner.add(front_len);
+                let sequence = TOTAL_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+                inner.write_bytes(REDZONE, front_len - size_of::<Header>());
+                ptr.cast::<Header>().sub(1).write(Header {
+                    magic: MAGIC_ALLOCATED,
+                    size: layout.size(),
+                    front_len,
+                    sequence,
+                });
+                ptr.write_bytes(POISON_ALLOCATED, layout.size());
+                ptr.add(layout.size()).write_bytes(REDZONE, REDZONE_LEN);
+                LIVE_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+                let live_bytes = LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+                PEAK_LIVE_BYTES.fetch_max(live_bytes + layout.size(), Ordering::Relaxed);
+                LIVE_SINCE_MARK.fetch_add(1, Ordering::Relaxed);
+                ptr
+            }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+            unsafe {
+                if self.is_large_allocation(ptr) {
+                    return self.dealloc_large(ptr);
+                }
+                let header_ptr = ptr.cast::<Header>().sub(1);
+                let header = header_ptr.read();
+                match header.magic {
+                    MAGIC_ALLOCATED => {}
+                    MAGIC_FREED => panic!("heap allocation at {ptr:p} freed twice"),
+                    magic => panic!("heap allocation at {ptr:p} has corrupted header {magic:#x}"),
+                }
+                let inner = ptr.sub(header.front_len);
+                let front_redzone =
+                    core::slice::from_raw_parts(inner, header.front_len - size_of::<Header>());
+                check_redzone(front_redzone, ptr, &header, "before");
+                let back_redzone = core::slice::from_raw_parts(ptr.add(header.size), REDZONE_LEN);
+                check_redzone(back_redzone, ptr, &header, "after");
+                inner.write_bytes(POISON_FREED, header.front_len + header.size + REDZONE_LEN);
+                (*header_ptr).magic = MAGIC_FREED;
+                LIVE_ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+                LIVE_BYTES.fetch_sub(header.size, Ordering::Relaxed);
+                TOTAL_FREES.fetch_add(1, Ordering::Relaxed);
+                if header.sequence >= MARK_SEQUENCE.load(Ordering::Relaxed) {
+                    LIVE_SINCE_MARK.fetch_sub(1, Ordering::Relaxed);
+                }
+                self.dealloc_small(inner);
+            }
+        }
+    }
 }
 
 #[global_allocator]
 static ALLOCATOR: KernelHeapAllocator = KernelHeapAllocator {
-    list_head: Mutex::new(None),
+    list_head: IrqMutex::new(None),
+    large_area: IrqMutex::new(None),
 };
 
-/// Initialises an area of virtual memory for use as heap space. The allocator will automatically
-/// map pages, so the area should be unmapped.
+/// Returns current heap usage statistics.
+pub fn stats() -> HeapStats {
+    let mut stats = HeapStats::default();
+    if let Some(list_head) = *ALLOCATOR.list_head.lock() {
+        let blocks = BlockIterator {
+            current_block: Some(list_head),
+        };
+        for block_ptr in blocks {
+            let block = unsafe { block_ptr.as_ref() };
+            if block.used() {
+                stats.used_bytes += block.len();
+                stats.used_blocks += 1;
+            } else {
+                stats.free_bytes += block.len();
+                stats.free_blocks += 1;
+                stats.largest_free_block = stats.largest_free_block.max(block.len());
+                stats.free_histogram[HeapStats::histogram_bucket(block.len())] += 1;
+            }
+        }
+    }
+    if let Some(area) = ALLOCATOR.large_area.lock().as_ref() {
+        stats.large_allocations = area.allocations;
+        stats.large_pages_used = area.pages_used;
+        stats.large_largest_free_run = area.largest_free_run();
+    }
+    stats
+}
+
+#[cfg(feature = "heap-debug")]
+pub use debug::{HeapDebugStats, mark_leak_check, stats as debug_stats};
+
+/// Initialises an area of virtual memory for use as heap space, and another for large
+/// allocations. The allocator will automatically map pages, so the areas should be unmapped.
 ///
 /// # Safety
 /// The caller guarantees this function is only called once.
-pub unsafe fn init_heap(start_address: usize, length: usize) {
+pub unsafe fn init_heap(
+    start_address: usize,
+    length: usize,
+    large_area_start_address: usize,
+    large_area_length: usize,
+) {
+    init_state::begin(Subsystem::Heap);
+    assert!(
+        large_area_length / PAGE_SIZE <= MAX_LARGE_AREA_PAGES,
+        "large allocation area too large",
+    );
+    *ALLOCATOR.large_area.lock() = Some(LargeArea {
+        start_address: large_area_start_address,
+        num_pages: large_area_length / PAGE_SIZE,
+        bitmap: [0; MAX_LARGE_AREA_PAGES / 64],
+        starts: [0; MAX_LARGE_AREA_PAGES / 64],
+        allocations: 0,
+        pages_used: 0,
+    });
     unsafe {
         let new_block_addr = start_address.next_multiple_of(align_of::<Block>());
         page_allocation::map_page(new_block_addr, PAGE_FLAGS).unwrap();
@@ -247,4 +708,5 @@ pub unsafe fn init_heap(start_address: usize, length: usize) {
         ));
         *ALLOCATOR.list_head.lock() = Some(NonNull::new_unchecked(new_block_ptr));
     }
+    init_state::finish(Subsystem::Heap);
 }
@@ -0,0 +1,124 @@
+//! Idling the CPU while no thread is ready to run.
+//!
+//! The scheduler calls `enter` with the next timer deadline whenever its run queue is empty. C1,
+//! halting, is always available. Deeper C-states are read from ACPI at boot, and only used if the
+//! local APIC timer keeps counting in them, as it's what wakes the CPU for the deadline. Each idle
+//! period uses the deepest state whose exit latency is small next to the time until the deadline.
+//! Other interrupts can end the period sooner, so that's only an upper bound on how long it lasts.
+
+use crate::arch::clock;
+use crate::arch::cpuid::{self, Feature};
+use crate::arch::idle;
+use crate::init_state::{self, Subsystem};
+use crate::platform::acpi::processor;
+use crate::sync::OnceLock;
+use alloc::vec::Vec;
+
+/// How many times its exit latency an idle period must be expected to last to use a state, so
+/// that the time spent entering and leaving it is a small part of the period.
+const LATENCY_FACTOR: u64 = 3;
+
+/// How a C-state is entered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entry {
+    Halt,
+    /// MWAIT with the given hint.
+    Mwait(u32),
+    /// Reading the chipset level register at the given I/O port.
+    LevelRegister(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CState {
+    /// 1 to 3, for C1 to C3.
+    pub acpi_type: u8,
+    /// Worst case time taken to wake up, in microseconds.
+    pub latency_us: u32,
+    pub entry: Entry,
+}
+
+const C1: CState = CState {
+    acpi_type: 1,
+    latency_us: 0,
+    entry: Entry::Halt,
+};
+
+/// States deeper than C1 which can be entered, by increasing latency.
+static DEEP_STATES: OnceLock<Vec<CState>> = OnceLock::new();
+
+/// Reads the C-states deeper than C1 from ACPI. Until this is called, or if there are none, idling
+/// only halts.
+pub fn init() {
+    init_state::begin(Subsystem::Idle);
+    let mut states = processor::c_states();
+    states.retain(|state| {
+        state.acpi_type >= 2
+            && (!matches!(state.entry, Entry::Mwait(_)) || cpuid::cpu_has(Feature::Monitor))
+    });
+    if !states.is_empty() && !cpuid::cpu_has(Feature::Arat) {
+        log::info!("Only using C1, as the APIC timer stops in deeper C-states");
+        states.clear();
+    }
+    states.sort_by_key(|state| state.latency_us);
+    for state in states.iter() {
+        log::info!(
+            "Using C{} ({:?}), exit latency {} us",
+            state.acpi_type,
+            state.entry,
+            state.latency_us,
+        );
+    }
+    let _ = DEEP_STATES.set(states);
+    init_state::finish(Subsystem::Idle);
+}
+
+/// Picks the deepest state worth entering for an idle period expected to last `expected_ns`.
+fn select(states: &[CState], expected_ns: u64) -> CState {
+    states
+        .iter()
+        .rev()
+        .find(|state| state.latency_us as u64 * 1000 * LATENCY_FACTOR <= expected_ns)
+        .copied()
+        .unwrap_or(C1)
+}
+
+/// Idles until the next interrupt has been handled, given the time of the next timer deadline, if
+/// any. Interrupts are left disabled on return.
+pub fn enter(next_deadline_ns: Option<u64>) {
+    let expected_ns = next_deadline_ns.map_or(u64::MAX, |deadline| {
+        deadline.saturating_sub(clock::now_ns())
+    });
+    let states = DEEP_STATES.get().map_or(&[][..], Vec::as_slice);
+    match select(states, expected_ns).entry {
+        Entry::Halt => idle::halt(),
+        Entry::Mwait(hint) => unsafe { idle::mwait(hint) },
+        Entry::LevelRegister(port) => unsafe { idle::read_level_register(port) },
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn selects_deepest_worthwhile_state() -> TestResult {
+        let c2 = CState {
+            acpi_type: 2,
+            latency_us: 20,
+            entry: Entry::LevelRegister(0x414),
+        };
+        let c3 = CState {
+            acpi_type: 3,
+            latency_us: 100,
+            entry: Entry::Mwait(0x20),
+        };
+        let states = [c2, c3];
+        ktest_assert_eq!(select(&states, 10_000), C1);
+        ktest_assert_eq!(select(&states, 60_000), c2);
+        ktest_assert_eq!(select(&states, 300_000), c3);
+        ktest_assert_eq!(select(&states, u64::MAX), c3);
+        ktest_assert_eq!(select(&[], u64::MAX), C1);
+        Ok(())
+    }
+}
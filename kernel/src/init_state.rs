@@ -0,0 +1,207 @@
+//! Tracking of subsystem initialisation, to catch subsystems being initialised twice or before
+//! the subsystems they depend on.
+//!
+//! Each subsystem's init function calls `begin` on entry and `finish` once it has succeeded.
+//! Ordering violations are caught with debug assertions, and the order subsystems finished in is
+//! recorded so it can be logged once boot completes.
+
+use crate::boot_progress;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Subsystem {
+    PageAllocation,
+    ThreadLocalStorage,
+    Heap,
+    KernelThreads,
+    WorkQueue,
+    Executor,
+    AcpiSubsystem,
+    AcpiTables,
+    Apic,
+    Clock,
+    AcpiNamespace,
+    Thermal,
+    Idle,
+}
+
+impl Subsystem {
+    pub const ALL: [Self; 13] = [
+        Self::PageAllocation,
+        Self::ThreadLocalStorage,
+        Self::Heap,
+        Self::KernelThreads,
+        Self::WorkQueue,
+        Self::Executor,
+        Self::AcpiSubsystem,
+        Self::AcpiTables,
+        Self::Apic,
+        Self::Clock,
+        Self::AcpiNamespace,
+        Self::Thermal,
+        Self::Idle,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PageAllocation => "page_allocation",
+            Self::ThreadLocalStorage => "tls",
+            Self::Heap => "heap",
+            Self::KernelThreads => "kthread",
+            Self::WorkQueue => "work_queue",
+            Self::Executor => "executor",
+            Self::AcpiSubsystem => "acpi_subsystem",
+            Self::AcpiTables => "acpi_tables",
+            Self::Apic => "apic",
+            Self::Clock => "clock",
+            Self::AcpiNamespace => "acpi_namespace",
+            Self::Thermal => "thermal",
+            Self::Idle => "idle",
+        }
+    }
+
+    /// Subsystems which must have finished initialising before this one begins.
+    pub fn dependencies(self) -> &'static [Self] {
+        match self {
+            Self::PageAllocation => &[],
+            Self::ThreadLocalStorage => &[Self::PageAllocation],
+            Self::Heap => &[Self::PageAllocation],
+            Self::KernelThreads => &[Self::Heap],
+            Self::WorkQueue => &[Self::KernelThreads],
+            Self::Executor => &[Self::KernelThreads],
+            Self::AcpiSubsystem => &[Self::Heap],
+            Self::AcpiTables => &[Self::AcpiSubsystem],
+            Self::Apic => &[Self::ThreadLocalStorage, Self::AcpiTables],
+            Self::Clock => &[Self::Apic],
+            // The EC driver spawns a thread, and AML can sleep
+            Self::AcpiNamespace => &[Self::AcpiTables, Self::Clock, Self::KernelThreads],
+            Self::Thermal => &[Self::AcpiNamespace, Self::KernelThreads],
+            Self::Idle => &[Self::AcpiNamespace],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Phase {
+    Uninitialised = 0,
+    Initialising = 1,
+    Initialised = 2,
+}
+
+impl Phase {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Uninitialised,
+            1 => Self::Initialising,
+            _ => Self::Initialised,
+        }
+    }
+}
+
+const NUM_SUBSYSTEMS: usize = Subsystem::ALL.len();
+
+static PHASES: [AtomicU8; NUM_SUBSYSTEMS] =
+    [const { AtomicU8::new(Phase::Uninitialised as u8) }; NUM_SUBSYSTEMS];
+static BOOT_ORDER: [AtomicU8; NUM_SUBSYSTEMS] = [const { AtomicU8::new(0) }; NUM_SUBSYSTEMS];
+static BOOT_ORDER_LEN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn phase(subsystem: Subsystem) -> Phase {
+    Phase::from_u8(PHASES[subsystem as usize].load(Ordering::Acquire))
+}
+
+pub fn is_initialised(subsystem: Subsystem) -> bool {
+    phase(subsystem) == Phase::Initialised
+}
+
+/// Marks `subsystem` as initialising. In debug builds, panics if it has already begun
+/// initialising, or if any of its dependencies haven't finished.
+pub fn begin(subsystem: Subsystem) {
+    let previous = Phase::from_u8(
+        PHASES[subsystem as usize].swap(Phase::Initialising as u8, Ordering::AcqRel),
+    );
+    debug_assert_eq!(
+        previous,
+        Phase::Uninitialised,
+        "{} initialised twice",
+        subsystem.name(),
+    );
+    for &dependency in subsystem.dependencies() {
+        debug_assert!(
+            is_initialised(dependency),
+            "{} initialised before its dependency {}",
+            subsystem.name(),
+            dependency.name(),
+        );
+    }
+}
+
+/// Marks `subsystem` as initialised, recording its position in the boot order.
+pub fn finish(subsystem: Subsystem) {
+    let previous =
+        Phase::from_u8(PHASES[subsystem as usize].swap(Phase::Initialised as u8, Ordering::AcqRel));
+    debug_assert_eq!(
+        previous,
+        Phase::Initialising,
+        "{} finished initialising without beginning",
+        subsystem.name(),
+    );
+    let index = BOOT_ORDER_LEN.fetch_add(1, Ordering::AcqRel);
+    if let Some(entry) = BOOT_ORDER.get(index) {
+        entry.store(subsystem as u8, Ordering::Release);
+    }
+    boot_progress::update();
+}
+
+/// Returns the number of subsystems which have finished initialising, and the total number.
+pub fn progress() -> (usize, usize) {
+    let finished = BOOT_ORDER_LEN.load(Ordering::Acquire).min(NUM_SUBSYSTEMS);
+    (finished, NUM_SUBSYSTEMS)
+}
+
+/// Marks `subsystem` as uninitialised again after it's been torn down, so it can be initialised
+/// again later. Only meant for subsystems torn down before any other has finished, such as the
+/// bootloader stage's page allocator, so its boot order entry is removed too.
+pub fn reset(subsystem: Subsystem) {
+    let previous = Phase::from_u8(
+        PHASES[subsystem as usize].swap(Phase::Uninitialised as u8, Ordering::AcqRel),
+    );
+    debug_assert_eq!(
+        previous,
+        Phase::Initialised,
+        "{} reset without being initialised",
+        subsystem.name(),
+    );
+    let len = BOOT_ORDER_LEN.load(Ordering::Acquire);
+    let last = len
+        .checked_sub(1)
+        .and_then(|index| BOOT_ORDER.get(index))
+        .map(|entry| entry.load(Ordering::Acquire));
+    debug_assert_eq!(
+        last,
+        Some(subsystem as u8),
+        "{} reset after other subsystems finished",
+        subsystem.name(),
+    );
+    if last == Some(subsystem as u8) {
+        _ = BOOT_ORDER_LEN.compare_exchange(len, len - 1, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
+
+/// Logs the order subsystems finished initialising in, along with any that never did.
+pub fn log_boot_order() {
+    let len = BOOT_ORDER_LEN.load(Ordering::Acquire).min(NUM_SUBSYSTEMS);
+    log::debug!("Boot order:");
+    for (position, entry) in BOOT_ORDER[..len].iter().enumerate() {
+        let subsystem = Subsystem::ALL[entry.load(Ordering::Acquire) as usize];
+        log::debug!("    {position:>2}: {}", subsystem.name());
+    }
+    for subsystem in Subsystem::ALL {
+        match phase(subsystem) {
+            Phase::Initialised => {}
+            Phase::Initialising => log::warn!("{} never finished initialising", subsystem.name()),
+            Phase::Uninitialised => log::debug!("{} not initialised", subsystem.name()),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Input device activity tracking, and handling of keys with a meaning to the kernel.
+//!
+//! Input drivers, such as the USB keyboard driver, call `report_activity` on every event, so that
+//! idle features such as screen blanking know the user is present, and `report_key` on every key
+//! press, along with `report_char` for keys which type a character.
+
+use crate::arch::clock;
+use crate::kshell;
+use crate::terminal;
+use crate::tty;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Counter time of the last input event in nanoseconds, or 0 if there hasn't been one.
+static LAST_ACTIVITY_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Records that an input event happened now, waking the screen if it's blanked. Safe to call
+/// from interrupt handlers.
+pub fn report_activity() {
+    LAST_ACTIVITY_NS.store(clock::try_now_ns().unwrap_or(0), Ordering::Relaxed);
+    terminal::wake_screen();
+}
+
+/// Returns the counter time of the last input event in nanoseconds, or 0 if there hasn't been
+/// one since boot.
+pub fn last_activity_ns() -> u64 {
+    LAST_ACTIVITY_NS.load(Ordering::Relaxed)
+}
+
+/// Keys with a meaning to the kernel itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    PageUp,
+    PageDown,
+    /// F1 onwards, numbered from 1.
+    Function(u8),
+}
+
+/// Modifier keys held during a key press.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+/// Handles a key press, along with recording activity. Safe to call from interrupt handlers.
+pub fn report_key(key: Key, modifiers: Modifiers) {
+    report_activity();
+    match (key, modifiers.shift) {
+        (Key::PageUp, true) => terminal::scroll_view_pages(1),
+        (Key::PageDown, true) => terminal::scroll_view_pages(-1),
+        (Key::Function(n @ 1..), _) if modifiers.alt && n as usize <= terminal::NUM_VTS => {
+            terminal::switch_vt(n as usize - 1)
+        }
+        _ => {}
+    }
+}
+
+/// Handles a typed character, passing it on to the kernel shell and the console TTY, which only
+/// take it while their VT is shown. Safe to call from interrupt handlers.
+pub fn report_char(character: char) {
+    report_activity();
+    kshell::push_input(character);
+    tty::push_input(character);
+}
@@ -0,0 +1,230 @@
+//! Submission and completion rings, for making system calls in batches.
+//!
+//! A ring is a page of memory shared between a process and the kernel, holding a queue of
+//! submissions written by the process and a queue of completions written by the kernel. Each
+//! ring has a worker thread running in the process, which makes each submitted system call and
+//! posts its result, so a process can queue up many operations and then only make one system call
+//! to wake the worker, or none at all if the worker is still busy.
+//!
+//! Both queues hold `RING_ENTRIES` entries. Head and tail indices count up forever, wrapping at
+//! `u32::MAX`, and are taken modulo `RING_ENTRIES` to find an entry. The producer of a queue
+//! writes the entry before moving its tail with release ordering, and the consumer reads the entry
+//! before moving its head. Submissions aren't taken while the completion queue is full.
+
+use crate::arch::paging::PAGE_SIZE;
+use crate::arch::syscall::{self, SyscallError, SystemCall};
+use crate::kthread;
+use crate::process::{self, PROTECTION_READ, PROTECTION_WRITE};
+use crate::shared_memory::{self, SharedMemory};
+use crate::wait_queue::WaitQueue;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+
+/// Number of entries in each queue of a ring.
+pub const RING_ENTRIES: usize = 32;
+
+/// Rings set up by every process.
+static RINGS: Mutex<Vec<Arc<IoRing>>> = Mutex::new(Vec::new());
+
+/// Queue indices at the start of a ring.
+#[derive(Debug)]
+#[repr(C)]
+pub struct RingHeader {
+    /// Index of the next submission for the kernel to take.
+    pub submission_head: AtomicU32,
+    /// Index one past the last submission, written by the process.
+    pub submission_tail: AtomicU32,
+    /// Index of the next completion for the process to take.
+    pub completion_head: AtomicU32,
+    /// Index one past the last completion, written by the kernel.
+    pub completion_tail: AtomicU32,
+}
+
+/// A system call to make, as passed in registers.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Submission {
+    pub number: usize,
+    pub args: [usize; 6],
+    /// Copied into the completion, for the process to match it up with.
+    pub user_data: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Completion {
+    pub user_data: usize,
+    /// The system call's result, encoded as it would be returned in a register.
+    pub result: usize,
+}
+
+/// Layout of a ring's page.
+#[repr(C)]
+pub struct RingPage {
+    pub header: RingHeader,
+    pub submissions: [Submission; RING_ENTRIES],
+    pub completions: [Completion; RING_ENTRIES],
+}
+
+const _: () = assert!(size_of::<RingPage>() <= PAGE_SIZE);
+
+struct IoRing {
+    /// Page table of the process the ring belongs to, identifying the ring along with `address`.
+    page_table_address: usize,
+    /// Address the ring is mapped at in the process.
+    address: usize,
+    /// Kept for the worker, which may still be running after the process unmaps the ring.
+    memory: Arc<SharedMemory>,
+    /// Woken when there may be submissions to take.
+    doorbell: WaitQueue,
+    /// Woken when completions are posted.
+    completed: WaitQueue,
+    closed: AtomicBool,
+}
+
+impl IoRing {
+    fn page(&self) -> *mut RingPage {
+        self.memory.frames()[0] as *mut RingPage
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &(*self.page()).header }
+    }
+
+    /// Returns the number of completions the process hasn't taken yet.
+    fn completions_pending(&self) -> usize {
+        let header = self.header();
+        let head = header.completion_head.load(Ordering::Acquire);
+        let tail = header.completion_tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head) as usize
+    }
+
+    /// Returns whether there's a submission to take and room to post its completion.
+    fn can_take_submission(&self) -> bool {
+        let header = self.header();
+        let head = header.submission_head.load(Ordering::Relaxed);
+        let tail = header.submission_tail.load(Ordering::Acquire);
+        head != tail && self.completions_pending() < RING_ENTRIES
+    }
+
+    /// Makes submitted system calls until there are none left, or the completion queue is full.
+    fn run_submissions(&self) {
+        let page = self.page();
+        let header = self.header();
+        while self.can_take_submission() {
+            let head = header.submission_head.load(Ordering::Relaxed);
+            let submission = unsafe {
+                (&raw const (*page).submissions[head as usize % RING_ENTRIES]).read_volatile()
+            };
+            header
+                .submission_head
+                .store(head.wrapping_add(1), Ordering::Release);
+            let result = match submission.number {
+                // Rings can't be used from a ring, as the worker would end up waiting on itself
+                number
+                    if number == SystemCall::SetupRing as usize
+                        || number == SystemCall::EnterRing as usize
+                        || number == SystemCall::CloseRing as usize =>
+                {
+                    Err(SyscallError::INVALID_ARGUMENT)
+                }
+                number => syscall::call(number, submission.args),
+            };
+            let completion = Completion {
+                user_data: submission.user_data,
+                result: SyscallError::encode(result),
+            };
+            let tail = header.completion_tail.load(Ordering::Relaxed);
+            unsafe {
+                (&raw mut (*page).completions[tail as usize % RING_ENTRIES])
+                    .write_volatile(completion);
+            }
+            header
+                .completion_tail
+                .store(tail.wrapping_add(1), Ordering::Release);
+            self.completed.wake_all();
+        }
+    }
+}
+
+fn worker_main(ring: usize) -> usize {
+    let ring = unsafe { Arc::from_raw(ring as *const IoRing) };
+    loop {
+        ring.doorbell
+            .wait_until(|| ring.closed.load(Ordering::Acquire) || ring.can_take_submission());
+        if ring.closed.load(Ordering::Acquire) {
+            return 0;
+        }
+        ring.run_submissions();
+    }
+}
+
+fn find_ring(address: usize) -> Result<Arc<IoRing>, SyscallError> {
+    let page_table_address = kthread::current_process()
+        .ok_or(SyscallError::INVALID_ARGUMENT)?
+        .page_table_address();
+    RINGS
+        .lock()
+        .iter()
+        .find(|ring| ring.page_table_address == page_table_address && ring.address == address)
+        .cloned()
+        .ok_or(SyscallError::INVALID_ARGUMENT)
+}
+
+/// Handler for the setup ring syscall. Maps a new ring into the current process and starts its
+/// worker, returning the ring's address. `address` and `map_flags` are as for the map memory
+/// syscall.
+pub fn syscall_setup(address: usize, map_flags: usize) -> Result<usize, SyscallError> {
+    let process = kthread::current_process().ok_or(SyscallError::INVALID_ARGUMENT)?;
+    let memory = shared_memory::allocate(PAGE_SIZE)?;
+    let address = process::map_shared(
+        memory.clone(),
+        address,
+        PROTECTION_READ | PROTECTION_WRITE,
+        map_flags,
+    )?;
+    let ring = Arc::new(IoRing {
+        page_table_address: process.page_table_address(),
+        address,
+        memory,
+        doorbell: WaitQueue::new(),
+        completed: WaitQueue::new(),
+        closed: AtomicBool::new(false),
+    });
+    let worker_ring = Arc::into_raw(ring.clone());
+    if kthread::spawn_for_process("io_ring", process, worker_main, worker_ring as usize).is_err() {
+        drop(unsafe { Arc::from_raw(worker_ring) });
+        // The mapping is left for the process to unmap
+        return Err(SyscallError::OUT_OF_MEMORY);
+    }
+    RINGS.lock().push(ring);
+    Ok(address)
+}
+
+/// Handler for the enter ring syscall. Wakes the worker of the ring at `address`, then waits until
+/// at least `min_complete` completions are waiting to be taken, or the ring is closed. Returns the
+/// number waiting.
+pub fn syscall_enter(address: usize, min_complete: usize) -> Result<usize, SyscallError> {
+    if min_complete > RING_ENTRIES {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let ring = find_ring(address)?;
+    ring.doorbell.wake_one();
+    ring.completed.wait_until(|| {
+        ring.closed.load(Ordering::Acquire) || ring.completions_pending() >= min_complete
+    });
+    Ok(ring.completions_pending())
+}
+
+/// Handler for the close ring syscall. Stops the worker of the ring at `address` once it finishes
+/// its current submission. The ring stays mapped until the process unmaps it.
+pub fn syscall_close(address: usize) -> Result<usize, SyscallError> {
+    let ring = find_ring(address)?;
+    RINGS.lock().retain(|other| !Arc::ptr_eq(other, &ring));
+    ring.closed.store(true, Ordering::Release);
+    ring.doorbell.wake_one();
+    ring.completed.wake_all();
+    Ok(0)
+}
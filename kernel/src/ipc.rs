@@ -0,0 +1,409 @@
+//! Message passing channels, for processes to talk to each other.
+//!
+//! A channel is a pair of endpoints, each referred to by a handle. Messages sent from one endpoint
+//! are queued in order for the other, up to `MAX_QUEUED_MESSAGES` at a time. Each carries up to
+//! `MAX_MESSAGE_LEN` bytes, copied through the kernel. Larger buffers are passed by granting a
+//! shared memory object with a message, which moves the sender's handle to the object over to the
+//! receiver.
+//!
+//! Sending blocks while the other endpoint's queue is full, and receiving blocks until there's a
+//! message, unless `IPC_NONBLOCK` is given. Once an endpoint is closed, sending to it fails, but
+//! messages already queued by it can still be received.
+
+use crate::arch::syscall::SyscallError;
+use crate::shared_memory::{self, SharedMemory, SharedMemoryError, SharedMemoryHandle};
+use crate::user_memory::{self, UserArgs};
+use crate::wait_queue::WaitQueue;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub const MAX_MESSAGE_LEN: usize = 256;
+pub const MAX_QUEUED_MESSAGES: usize = 16;
+
+/// Flag to fail with `SyscallError::WOULD_BLOCK` instead of blocking.
+pub const IPC_NONBLOCK: usize = 1 << 0;
+/// Send flag to grant the shared memory object given with the message.
+pub const IPC_GRANT: usize = 1 << 1;
+/// Written in place of a shared memory handle for received messages without a grant.
+pub const NO_GRANT: usize = usize::MAX;
+
+/// Open handles, indexed by handle.
+static HANDLES: Mutex<Vec<Option<Arc<Endpoint>>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ChannelError {
+    #[error("no such channel endpoint")]
+    NotFound,
+    #[error("the other endpoint is closed")]
+    PeerClosed,
+    #[error("operation would block")]
+    WouldBlock,
+    #[error("message too long")]
+    MessageTooLong,
+    #[error("out of memory")]
+    OutOfMemory,
+    #[error("invalid grant - {0}")]
+    Grant(#[from] SharedMemoryError),
+}
+
+impl From<ChannelError> for SyscallError {
+    fn from(err: ChannelError) -> Self {
+        match err {
+            ChannelError::PeerClosed => SyscallError::PEER_CLOSED,
+            ChannelError::WouldBlock => SyscallError::WOULD_BLOCK,
+            ChannelError::OutOfMemory => SyscallError::OUT_OF_MEMORY,
+            ChannelError::Grant(err) => err.into(),
+            _ => SyscallError::INVALID_ARGUMENT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelHandle(pub usize);
+
+#[derive(Debug)]
+pub struct Message {
+    pub data: Vec<u8>,
+    pub grant: Option<Arc<SharedMemory>>,
+}
+
+/// State shared by both endpoints, indexed by endpoint.
+struct Channel {
+    /// Messages waiting to be received by each endpoint. Space for every message is reserved up
+    /// front, so queueing doesn't allocate.
+    queues: [Mutex<VecDeque<Message>>; 2],
+    closed: [AtomicBool; 2],
+    /// Woken when a message is queued for the endpoint, or the other endpoint is closed.
+    readable: [WaitQueue; 2],
+    /// Woken when a message is taken from the endpoint's queue, or the endpoint is closed.
+    writable: [WaitQueue; 2],
+}
+
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+impl Endpoint {
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    /// Queues `message` for the other endpoint, blocking while its queue is full unless
+    /// `nonblock` is set.
+    pub fn send(&self, message: Message, nonblock: bool) -> Result<(), ChannelError> {
+        if message.data.len() > MAX_MESSAGE_LEN {
+            return Err(ChannelError::MessageTooLong);
+        }
+        let channel = &self.channel;
+        let peer = self.peer();
+        let mut message = Some(message);
+        let mut result = Err(ChannelError::WouldBlock);
+        let mut try_send = || {
+            if channel.closed[peer].load(Ordering::Acquire) {
+                result = Err(ChannelError::PeerClosed);
+                return true;
+            }
+            let mut queue = channel.queues[peer].lock();
+            if queue.len() >= MAX_QUEUED_MESSAGES {
+                return false;
+            }
+            queue.push_back(message.take().unwrap());
+            result = Ok(());
+            true
+        };
+        match nonblock {
+            true => _ = try_send(),
+            false => channel.writable[peer].wait_until(try_send),
+        }
+        if result.is_ok() {
+            channel.readable[peer].wake_one();
+        }
+        result
+    }
+
+    /// Takes the next message queued for this endpoint, blocking until there is one unless
+    /// `nonblock` is set. Messages longer than `max_len` are left queued.
+    pub fn receive(&self, max_len: usize, nonblock: bool) -> Result<Message, ChannelError> {
+        let channel = &self.channel;
+        let (side, peer) = (self.side, self.peer());
+        let mut result = Err(ChannelError::WouldBlock);
+        let mut try_receive = || {
+            let mut queue = channel.queues[side].lock();
+            result = match queue.front() {
+                Some(message) if message.data.len() > max_len => Err(ChannelError::MessageTooLong),
+                Some(_) => Ok(queue.pop_front().unwrap()),
+                None if channel.closed[peer].load(Ordering::Acquire) => {
+                    Err(ChannelError::PeerClosed)
+                }
+                None => return false,
+            };
+            true
+        };
+        match nonblock {
+            true => _ = try_receive(),
+            false => channel.readable[side].wait_until(try_receive),
+        }
+        if result.is_ok() {
+            channel.writable[side].wake_one();
+        }
+        result
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let channel = &self.channel;
+        channel.closed[self.side].store(true, Ordering::Release);
+        // Threads blocked on either side would otherwise never see the endpoint close
+        channel.readable[self.peer()].wake_all();
+        channel.writable[self.side].wake_all();
+    }
+}
+
+fn insert_handle(handles: &mut Vec<Option<Arc<Endpoint>>>, endpoint: Arc<Endpoint>) -> usize {
+    match handles.iter().position(Option::is_none) {
+        Some(index) => {
+            handles[index] = Some(endpoint);
+            index
+        }
+        None => {
+            handles.push(Some(endpoint));
+            handles.len() - 1
+        }
+    }
+}
+
+/// Creates a channel, returning handles to its two endpoints.
+pub fn create() -> Result<(ChannelHandle, ChannelHandle), ChannelError> {
+    let new_queue = || -> Result<_, ChannelError> {
+        let mut queue = VecDeque::new();
+        queue
+            .try_reserve_exact(MAX_QUEUED_MESSAGES)
+            .map_err(|_| ChannelError::OutOfMemory)?;
+        Ok(Mutex::new(queue))
+    };
+    let channel = Arc::new(Channel {
+        queues: [new_queue()?, new_queue()?],
+        closed: [AtomicBool::new(false), AtomicBool::new(false)],
+        readable: [WaitQueue::new(), WaitQueue::new()],
+        writable: [WaitQueue::new(), WaitQueue::new()],
+    });
+    let [first, second] = [0, 1].map(|side| {
+        Arc::new(Endpoint {
+            channel: channel.clone(),
+            side,
+        })
+    });
+    let mut handles = HANDLES.lock();
+    Ok((
+        ChannelHandle(insert_handle(&mut handles, first)),
+        ChannelHandle(insert_handle(&mut handles, second)),
+    ))
+}
+
+/// Returns the endpoint `handle` refers to.
+pub fn get(handle: ChannelHandle) -> Result<Arc<Endpoint>, ChannelError> {
+    HANDLES
+        .lock()
+        .get(handle.0)
+        .cloned()
+        .flatten()
+        .ok_or(ChannelError::NotFound)
+}
+
+/// Closes `handle`. The endpoint is closed once nothing is still using it.
+pub fn close(handle: ChannelHandle) -> Result<(), ChannelError> {
+    let endpoint = HANDLES
+        .lock()
+        .get_mut(handle.0)
+        .and_then(Option::take)
+        .ok_or(ChannelError::NotFound)?;
+    // Dropped outside the lock, as closing wakes any waiting threads
+    drop(endpoint);
+    Ok(())
+}
+
+#[derive(UserArgs)]
+struct CreateArgs {
+    #[user(write)]
+    handles_ptr: *mut [usize; 2],
+}
+
+#[derive(UserArgs)]
+struct SendArgs {
+    #[user(read, len = data_len, max_len = MAX_MESSAGE_LEN, optional)]
+    data_ptr: *const u8,
+    data_len: usize,
+}
+
+#[derive(UserArgs)]
+struct ReceiveArgs {
+    #[user(write, len = buffer_len, optional)]
+    buffer_ptr: *mut u8,
+    buffer_len: usize,
+    #[user(write, optional)]
+    grant_ptr: *mut usize,
+}
+
+/// Handler for the create channel syscall. Writes handles to the two endpoints to `handles_ptr`.
+pub fn syscall_create(handles_ptr: *mut [usize; 2]) -> Result<usize, SyscallError> {
+    CreateArgs { handles_ptr }.validate()?;
+    let (first, second) = create()?;
+    let mut bytes = [0; size_of::<[usize; 2]>()];
+    bytes[..size_of::<usize>()].copy_from_slice(&first.0.to_ne_bytes());
+    bytes[size_of::<usize>()..].copy_from_slice(&second.0.to_ne_bytes());
+    if let Err(err) = user_memory::copy_to_user(handles_ptr as usize, &bytes) {
+        _ = close(first);
+        _ = close(second);
+        return Err(err);
+    }
+    Ok(0)
+}
+
+/// Handler for the close channel syscall.
+pub fn syscall_close(handle: usize) -> Result<usize, SyscallError> {
+    close(ChannelHandle(handle))?;
+    Ok(0)
+}
+
+/// Handler for the send message syscall. Sends `data_len` bytes at `data_ptr` from the endpoint
+/// `handle`. With `IPC_GRANT`, the shared memory object `grant` is sent with the message and its
+/// handle is closed.
+pub fn syscall_send(
+    handle: usize,
+    data_ptr: *const u8,
+    data_len: usize,
+    grant: usize,
+    flags: usize,
+) -> Result<usize, SyscallError> {
+    if flags & !(IPC_NONBLOCK | IPC_GRANT) != 0 {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    SendArgs { data_ptr, data_len }.validate()?;
+    let endpoint = get(ChannelHandle(handle))?;
+    let mut data = vec![0; data_len];
+    if data_len != 0 {
+        user_memory::copy_from_user(&mut data, data_ptr as usize)?;
+    }
+    let grant_handle = (flags & IPC_GRANT != 0).then_some(SharedMemoryHandle(grant));
+    let grant = grant_handle
+        .map(shared_memory::get)
+        .transpose()
+        .map_err(ChannelError::from)?;
+    endpoint.send(Message { data, grant }, flags & IPC_NONBLOCK != 0)?;
+    if let Some(grant_handle) = grant_handle {
+        // The receiver gets its own handle, so the object isn't freed in between
+        _ = shared_memory::close(grant_handle);
+    }
+    Ok(0)
+}
+
+/// Handler for the receive message syscall. Receives a message of up to `buffer_len` bytes into
+/// `buffer_ptr` on the endpoint `handle`, returning its length. A handle to any shared memory
+/// object granted with the message is written to `grant_ptr`, or `NO_GRANT` if there isn't one.
+/// Grants are dropped if `grant_ptr` is null.
+pub fn syscall_receive(
+    handle: usize,
+    buffer_ptr: *mut u8,
+    buffer_len: usize,
+    grant_ptr: *mut usize,
+    flags: usize,
+) -> Result<usize, SyscallError> {
+    if flags & !IPC_NONBLOCK != 0 {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    ReceiveArgs {
+        buffer_ptr,
+        buffer_len,
+        grant_ptr,
+    }
+    .validate()?;
+    let endpoint = get(ChannelHandle(handle))?;
+    let message = endpoint.receive(buffer_len, flags & IPC_NONBLOCK != 0)?;
+    if !message.data.is_empty() {
+        user_memory::copy_to_user(buffer_ptr as usize, &message.data)?;
+    }
+    if !grant_ptr.is_null() {
+        let grant = match message.grant {
+            Some(object) => shared_memory::add_handle(object).0,
+            None => NO_GRANT,
+        };
+        user_memory::copy_to_user(grant_ptr as usize, &grant.to_ne_bytes())?;
+    }
+    Ok(message.data.len())
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    fn message(data: &[u8]) -> Message {
+        Message {
+            data: data.into(),
+            grant: None,
+        }
+    }
+
+    #[kernel_test]
+    fn delivers_messages_in_order() -> TestResult {
+        let (first, second) = create().map_err(|err| alloc::format!("{err}"))?;
+        let (sender, receiver) = (get(first).unwrap(), get(second).unwrap());
+        for data in [b"one", b"two"] {
+            ktest_assert_eq!(sender.send(message(data), true), Ok(()));
+        }
+        ktest_assert_eq!(
+            receiver.receive(2, true).err(),
+            Some(ChannelError::MessageTooLong)
+        );
+        ktest_assert_eq!(receiver.receive(3, true).unwrap().data, b"one");
+        ktest_assert_eq!(receiver.receive(3, true).unwrap().data, b"two");
+        ktest_assert_eq!(
+            receiver.receive(3, true).err(),
+            Some(ChannelError::WouldBlock)
+        );
+        // Messages go both ways
+        ktest_assert_eq!(receiver.send(message(b"back"), true), Ok(()));
+        ktest_assert_eq!(sender.receive(4, false).unwrap().data, b"back");
+        drop((sender, receiver));
+        ktest_assert!(close(first).is_ok() && close(second).is_ok());
+        ktest_assert_eq!(get(first).err(), Some(ChannelError::NotFound));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn full_and_closed_channels() -> TestResult {
+        let (first, second) = create().map_err(|err| alloc::format!("{err}"))?;
+        let sender = get(first).unwrap();
+        for _ in 0..MAX_QUEUED_MESSAGES {
+            ktest_assert_eq!(sender.send(message(b""), true), Ok(()));
+        }
+        ktest_assert_eq!(
+            sender.send(message(b""), true),
+            Err(ChannelError::WouldBlock)
+        );
+        ktest_assert!(close(first).is_ok());
+        drop(sender);
+        // Queued messages outlive the sender's endpoint
+        let receiver = get(second).unwrap();
+        for _ in 0..MAX_QUEUED_MESSAGES {
+            ktest_assert!(receiver.receive(0, true).is_ok());
+        }
+        ktest_assert_eq!(
+            receiver.receive(0, false).err(),
+            Some(ChannelError::PeerClosed)
+        );
+        ktest_assert_eq!(
+            receiver.send(message(b""), false),
+            Err(ChannelError::PeerClosed)
+        );
+        drop(receiver);
+        ktest_assert!(close(second).is_ok());
+        Ok(())
+    }
+}
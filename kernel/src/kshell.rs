@@ -0,0 +1,421 @@
+//! Interactive kernel monitor, for debugging bring-up on real hardware.
+//!
+//! The shell reads lines from the keyboard while its VT is active (Alt+F2), and from the serial
+//! port if there is one, and writes its output to both. Type `help` for the commands. Other
+//! sessions, like `net::console`'s, feed their input to a `Session` of their own.
+
+use crate::arch::{clock, pci, serial};
+#[cfg(feature = "bench")]
+use crate::bench;
+use crate::cmdline::AcpiDump;
+use crate::debugging::symbols;
+#[cfg(feature = "heap-debug")]
+use crate::heap;
+use crate::kthread;
+use crate::logging;
+use crate::memstats;
+use crate::platform::acpi;
+use crate::profiler;
+use crate::sync::IrqMutex;
+use crate::terminal;
+use crate::trace;
+use crate::tunables;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// VT the shell is shown on.
+pub const KSHELL_VT: usize = 1;
+const PROMPT: &str = "kshell> ";
+const MAX_LINE_LEN: usize = 128;
+const MAX_PENDING_INPUT: usize = 64;
+/// How often input is checked for, as the serial port is polled.
+const POLL_MS: u64 = 20;
+/// Most bytes of a symbol shown by `d`.
+const MAX_DUMP_LEN: usize = 256;
+/// Functions shown by `prof`.
+const MAX_PROFILE_ENTRIES: usize = 20;
+const DEFAULT_PROFILE_RATE_HZ: u32 = 1000;
+const MAX_PROFILE_RATE_HZ: u32 = 10_000;
+
+const HELP: &str = "\
+Commands:
+  help          Show this help
+  dmesg         Recent console output
+  mem           Page allocator, process, heap, scrubber and memory tag usage
+  heap [mark]   Live heap allocations, or start counting leaks from now
+  vmas <pid>    Memory areas of a process
+  acpi tables   List ACPI tables
+  acpi dump <hex|base64>
+                Write ACPI tables to serial
+  lspci         List PCI functions
+  sym <addr>    Find the kernel symbol containing a hex address
+  d <symbol>    Show the address and contents of a kernel symbol
+  ticks         Time since boot
+  date          Wall clock date and time
+  syserr        Recent failed system calls of each process
+  sched         Thread count, run queue length and load averages
+  prof start [hz]
+                Start sampling where this CPU is running, 1000 times a second by default
+  prof stop     Stop sampling
+  prof          Functions with the most samples
+  trace start   Start recording scheduler, page fault and interrupt events
+  trace stop    Stop recording events
+  trace dump    Write this CPU's recorded events to serial
+  bench         Run the micro-benchmarks
+  tun           List tunables with their values
+  tun <name> <value>
+                Set a tunable
+  panic         Panic the kernel
+";
+
+/// Characters typed on the keyboard, not yet read by the shell.
+static KEYBOARD_INPUT: IrqMutex<InputBuffer> = IrqMutex::new(InputBuffer {
+    chars: ['\0'; MAX_PENDING_INPUT],
+    start: 0,
+    len: 0,
+});
+
+struct InputBuffer {
+    chars: [char; MAX_PENDING_INPUT],
+    start: usize,
+    len: usize,
+}
+
+impl InputBuffer {
+    /// Adds a character, dropping it if the buffer is full.
+    fn push(&mut self, character: char) {
+        if self.len < MAX_PENDING_INPUT {
+            self.chars[(self.start + self.len) % MAX_PENDING_INPUT] = character;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        if self.len == 0 {
+            return None;
+        }
+        let character = self.chars[self.start];
+        self.start = (self.start + 1) % MAX_PENDING_INPUT;
+        self.len -= 1;
+        Some(character)
+    }
+}
+
+/// Passes a character typed on the keyboard to the shell. Safe to call from interrupt handlers.
+pub fn push_input(character: char) {
+    KEYBOARD_INPUT.lock().push(character);
+}
+
+/// Sets up the serial port and starts the shell's thread.
+pub fn start() {
+    if unsafe { serial::init() } {
+        log::debug!("Kernel shell available on COM1");
+    }
+    if let Err(err) = kthread::spawn("kshell", shell_thread, 0) {
+        log::warn!("Failed to start kernel shell - {err}");
+    }
+}
+
+/// Writes to the shell's VT and the serial port.
+struct Output;
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                serial::write_byte(b'\r');
+            }
+            serial::write_byte(byte);
+        }
+        if let Ok(terminal) = terminal::VTS.lock().get_or_create(KSHELL_VT) {
+            terminal.write(s);
+            // Terminals only render on new lines, but the prompt and echo should show straight away
+            terminal.render();
+        }
+        Ok(())
+    }
+}
+
+/// Returns the next input character, from the keyboard if the shell's VT is active, or serial.
+fn next_input() -> Option<char> {
+    let keyboard = KEYBOARD_INPUT.lock().pop();
+    let keyboard = keyboard.filter(|_| terminal::VTS.lock().active() == KSHELL_VT);
+    keyboard.or_else(|| serial::try_read_byte().map(char::from))
+}
+
+fn shell_thread(_: usize) -> usize {
+    let mut out = Output;
+    let mut session = Session::start(&mut out, true);
+    loop {
+        kthread::sleep_ms(POLL_MS);
+        while let Some(character) = next_input() {
+            session.input(&mut out, character);
+        }
+    }
+}
+
+/// A line being typed into the shell, run as a command once it's entered.
+pub struct Session {
+    line: [u8; MAX_LINE_LEN],
+    line_len: usize,
+    /// Whether typed characters are written back, for terminals which don't show them already.
+    echo: bool,
+}
+
+impl Session {
+    /// Starts a session, writing its first prompt to `out`.
+    pub fn start(out: &mut dyn Write, echo: bool) -> Self {
+        _ = write!(out, "{PROMPT}");
+        Self {
+            line: [0; MAX_LINE_LEN],
+            line_len: 0,
+            echo,
+        }
+    }
+
+    /// Handles a character typed into the session, writing any output to `out`.
+    pub fn input(&mut self, out: &mut dyn Write, character: char) {
+        match character {
+            '\r' | '\n' => {
+                if self.echo {
+                    _ = writeln!(out);
+                }
+                // Only ASCII is accepted into the line
+                let command = core::str::from_utf8(&self.line[..self.line_len]).unwrap();
+                _ = run_command(out, command);
+                self.line_len = 0;
+                _ = write!(out, "{PROMPT}");
+            }
+            '\x08' | '\x7F' if self.line_len > 0 => {
+                self.line_len -= 1;
+                if self.echo {
+                    _ = write!(out, "\x08 \x08");
+                }
+            }
+            ' '..='~' if self.line_len < MAX_LINE_LEN => {
+                self.line[self.line_len] = character as u8;
+                self.line_len += 1;
+                if self.echo {
+                    _ = write!(out, "{character}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn run_command(out: &mut dyn Write, command: &str) -> fmt::Result {
+    let mut args = command.split_whitespace();
+    match (args.next(), args.next()) {
+        (None, _) => Ok(()),
+        (Some("help"), None) => write!(out, "{HELP}"),
+        (Some("dmesg"), None) => logging::write_log_tail(out),
+        (Some("mem"), None) => writeln!(out, "{}", memstats::get()),
+        (Some("heap"), arg) => heap_debug(out, arg),
+        (Some("vmas"), Some(pid)) => match pid.parse::<u64>() {
+            // Processes aren't given IDs yet, so there's nothing to look up
+            Ok(pid) => writeln!(out, "No process with pid {pid}"),
+            Err(_) => writeln!(out, "Invalid pid {pid:?}"),
+        },
+        (Some("acpi"), Some("tables")) => acpi_tables(out),
+        (Some("acpi"), Some("dump")) => {
+            let format = match args.next() {
+                Some("hex") => AcpiDump::Hex,
+                Some("base64") => AcpiDump::Base64,
+                _ => return writeln!(out, "Usage: acpi dump <hex|base64>"),
+            };
+            match acpi::dump::export_tables(format) {
+                Ok(count) => writeln!(out, "Wrote {count} tables to serial"),
+                Err(_) => writeln!(out, "Failed to write tables to serial"),
+            }
+        }
+        (Some("lspci"), None) => lspci(out),
+        (Some("sym"), Some(address)) => {
+            match usize::from_str_radix(address.trim_start_matches("0x"), 16) {
+                Ok(address) => match symbols::resolve(address) {
+                    Some((symbol, offset)) => {
+                        writeln!(out, "{address:#x} is {}+{offset:#x}", symbol.demangled())
+                    }
+                    None => writeln!(out, "No symbol contains {address:#x}"),
+                },
+                Err(_) => writeln!(out, "Invalid address {address:?}"),
+            }
+        }
+        (Some("d"), Some(name)) => match symbols::lookup(name) {
+            Some(symbol) => dump_symbol(out, symbol),
+            None => writeln!(out, "No symbol named {name:?}"),
+        },
+        (Some("ticks"), None) => {
+            let now_ns = clock::now_ns();
+            writeln!(
+                out,
+                "{now_ns} ns since boot ({}.{:03} s)",
+                now_ns / 1_000_000_000,
+                now_ns / 1_000_000 % 1000,
+            )
+        }
+        (Some("date"), None) => match clock::wall_clock::now() {
+            Some(date_time) => writeln!(out, "{date_time}"),
+            None => writeln!(out, "Wall clock not initialised"),
+        },
+        (Some("syserr"), None) => syscall_errors(out),
+        (Some("sched"), None) => match kthread::stats() {
+            Some(stats) => writeln!(out, "{stats}"),
+            None => writeln!(out, "Kernel threads not initialised"),
+        },
+        (Some("prof"), None) => profiler::write_report(out, MAX_PROFILE_ENTRIES),
+        (Some("prof"), Some("start")) => {
+            let rate_hz = match args.next().map(str::parse::<u32>) {
+                None => DEFAULT_PROFILE_RATE_HZ,
+                Some(Ok(rate_hz @ 1..=MAX_PROFILE_RATE_HZ)) => rate_hz,
+                Some(_) => return writeln!(out, "Rate must be 1 to {MAX_PROFILE_RATE_HZ} Hz"),
+            };
+            let source = profiler::start(rate_hz);
+            writeln!(out, "Sampling {rate_hz} times a second using {source}")
+        }
+        (Some("prof"), Some("stop")) => {
+            profiler::stop();
+            writeln!(out, "Sampling stopped")
+        }
+        (Some("trace"), Some("start")) => {
+            trace::start();
+            writeln!(out, "Tracing started")
+        }
+        (Some("trace"), Some("stop")) => {
+            trace::stop();
+            writeln!(out, "Tracing stopped")
+        }
+        (Some("trace"), Some("dump")) => match trace::dump() {
+            Some(Ok(count)) => writeln!(out, "Wrote {count} events to serial"),
+            Some(Err(_)) => writeln!(out, "Failed to write events to serial"),
+            None => writeln!(out, "No serial port"),
+        },
+        (Some("bench"), None) => run_benchmarks(out),
+        (Some("tun"), None) => {
+            for tunable in tunables::ALL {
+                writeln!(
+                    out,
+                    "{} = {} (default {}) - {}",
+                    tunable.name,
+                    tunable.get(),
+                    tunable.default_value(),
+                    tunable.description,
+                )?;
+            }
+            Ok(())
+        }
+        (Some("tun"), Some(name)) => {
+            let Some(value) = args.next() else {
+                return writeln!(out, "Usage: tun <name> <value>");
+            };
+            match tunables::set(name, value) {
+                Ok(()) => writeln!(out, "{name} set to {value}"),
+                Err(err) => writeln!(out, "Failed to set {name} - {err}"),
+            }
+        }
+        (Some("panic"), None) => panic!("Panic requested from kernel shell"),
+        (Some(name), _) => writeln!(out, "Unknown command {name:?}, type `help` for commands"),
+    }
+}
+
+fn syscall_errors(out: &mut dyn Write) -> fmt::Result {
+    // Copied out first, as output shouldn't be written with the scheduler locked
+    let mut logs = Vec::new();
+    if !kthread::try_for_each_process(|process| logs.push(process.syscall_errors.lock().clone())) {
+        return writeln!(out, "Scheduler locked");
+    }
+    for (process_index, log) in logs.iter().enumerate() {
+        writeln!(out, "Process {process_index}:")?;
+        for failure in log.iter() {
+            writeln!(out, "  {failure}")?;
+        }
+    }
+    Ok(())
+}
+
+fn acpi_tables(out: &mut dyn Write) -> fmt::Result {
+    for index in 0.. {
+        let Ok(header) = (unsafe { acpi::table::get_by_index(index) }) else {
+            break;
+        };
+        let length = header.length;
+        writeln!(
+            out,
+            "{} rev {} len {length:#x} oem {} {}",
+            header.signature.escape_ascii(),
+            header.revision,
+            header.oem_id.escape_ascii(),
+            header.oem_table_id.escape_ascii(),
+        )?;
+    }
+    Ok(())
+}
+
+fn dump_symbol(out: &mut dyn Write, symbol: symbols::Symbol) -> fmt::Result {
+    writeln!(
+        out,
+        "{} at {:#x}, {} bytes ({:?})",
+        symbol.demangled(),
+        symbol.address,
+        symbol.size,
+        symbol.kind,
+    )?;
+    // Symbols are in the kernel image, which is always mapped
+    let bytes = unsafe {
+        core::slice::from_raw_parts(symbol.address as *const u8, symbol.size.min(MAX_DUMP_LEN))
+    };
+    for (line_index, line) in bytes.chunks(16).enumerate() {
+        write!(out, "{:#x}:", symbol.address + line_index * 16)?;
+        for byte in line {
+            write!(out, " {byte:02x}")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "heap-debug")]
+fn heap_debug(out: &mut dyn Write, arg: Option<&str>) -> fmt::Result {
+    match arg {
+        None => writeln!(out, "{}", heap::debug_stats()),
+        Some("mark") => {
+            heap::mark_leak_check();
+            writeln!(out, "Counting live allocations from now")
+        }
+        Some(_) => writeln!(out, "Usage: heap [mark]"),
+    }
+}
+
+#[cfg(not(feature = "heap-debug"))]
+fn heap_debug(out: &mut dyn Write, _arg: Option<&str>) -> fmt::Result {
+    writeln!(
+        out,
+        "Heap debugging is off, build with the heap-debug feature"
+    )
+}
+
+#[cfg(feature = "bench")]
+fn run_benchmarks(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{}", bench::run_all())
+}
+
+#[cfg(not(feature = "bench"))]
+fn run_benchmarks(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "Benchmarks are off, build with the bench feature")
+}
+
+fn lspci(out: &mut dyn Write) -> fmt::Result {
+    let mut result = Ok(());
+    pci::for_each_function(|address| {
+        let vendor_id = pci::read_word(address, pci::REGISTER_VENDOR_ID);
+        let device_id = pci::read_word(address, pci::REGISTER_DEVICE_ID);
+        let class = pci::read_dword(address, pci::REGISTER_CLASS) >> 8;
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "{address} {vendor_id:04x}:{device_id:04x} class {class:06x}"
+            );
+        }
+    });
+    result
+}
@@ -0,0 +1,101 @@
+//! In-kernel tests, for code which can only be tested on real page tables, interrupts and
+//! devices.
+//!
+//! Tests are functions marked `#[kernel_test]`, returning `TestResult`, and are usually placed
+//! next to the code they test. The attribute puts each test in the `.kernel_tests` section, so
+//! booting with `runtests` on the command line runs all of them once the kernel is initialised.
+//! The results are logged, ending with a `KTEST passed=<n> failed=<n>` line, and QEMU is exited
+//! through its `isa-debug-exit` device, with status 1 if every test passed and 3 otherwise.
+//!
+//! Panics can't be caught, so tests report failures by returning an error, usually with
+//! `ktest_assert!` and `ktest_assert_eq!`.
+
+use crate::arch::port;
+use alloc::string::String;
+
+pub use kernel_test::kernel_test;
+
+unsafe extern "C" {
+    static KERNEL_TESTS_START: u8;
+    static KERNEL_TESTS_END: u8;
+}
+
+pub type TestResult = Result<(), String>;
+
+/// A test registered with `#[kernel_test]`.
+pub struct KernelTest {
+    /// Path of the test function.
+    pub name: &'static str,
+    pub function: fn() -> TestResult,
+}
+
+/// Returns from the current test with a failure if `condition` is false.
+#[macro_export]
+macro_rules! ktest_assert {
+    ($condition:expr $(,)?) => {
+        if !$condition {
+            return Err(alloc::format!(
+                "{}:{}: assertion failed: {}",
+                file!(),
+                line!(),
+                stringify!($condition),
+            ));
+        }
+    };
+}
+
+/// Returns from the current test with a failure if `left` and `right` aren't equal.
+#[macro_export]
+macro_rules! ktest_assert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if left != right {
+                    return Err(alloc::format!(
+                        "{}:{}: {} == {} failed, left: {:?}, right: {:?}",
+                        file!(),
+                        line!(),
+                        stringify!($left),
+                        stringify!($right),
+                        left,
+                        right,
+                    ));
+                }
+            }
+        }
+    };
+}
+
+/// Returns every registered test.
+pub fn tests() -> &'static [KernelTest] {
+    unsafe {
+        let start = (&raw const KERNEL_TESTS_START).cast::<KernelTest>();
+        let end = (&raw const KERNEL_TESTS_END).cast::<KernelTest>();
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Runs every registered test, logs the results, then exits QEMU with them. Returns if QEMU's
+/// `isa-debug-exit` device isn't present. Must be called from a kernel thread.
+pub fn run_all() {
+    let tests = tests();
+    log::info!("Running {} kernel tests...", tests.len());
+    let mut num_failed = 0;
+    for test in tests {
+        match (test.function)() {
+            Ok(()) => log::info!("test {} ... ok", test.name),
+            Err(message) => {
+                log::error!("test {} ... FAILED - {message}", test.name);
+                num_failed += 1;
+            }
+        }
+    }
+    log::info!(
+        "KTEST passed={} failed={num_failed}",
+        tests.len() - num_failed
+    );
+    unsafe {
+        port::write_byte(port::QEMU_DEBUG_EXIT, if num_failed == 0 { 0 } else { 1 });
+    }
+    log::warn!("QEMU debug exit device not present, continuing boot");
+}
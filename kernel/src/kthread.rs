@@ -0,0 +1,720 @@
+//! Kernel threads.
+//!
+//! Threads are cooperatively scheduled round-robin, and only give up the CPU when they yield,
+//! block, sleep or exit.
+//!
+//! Booting with `sched_seed=<n>` switches to deterministic mode, for replaying intermittent
+//! concurrency failures. The next thread to run is picked from the run queue by a PRNG seeded
+//! with `n` instead of in order, and sleep wakeup times are rounded up to a multiple of the
+//! `sched_quantum_ms` tunable, so that threads woken around the same time are woken together in
+//! the same order. A failing seed then reproduces the same interleaving, as long as interrupts
+//! wake threads at the same points.
+//!
+//! User threads run user code in a process's address space, entering the kernel on system calls
+//! and exceptions. Each has its own kernel stack, which the CPU switches to on entering the
+//! kernel. User threads give up the CPU whenever they make a system call, as there's no
+//! preemption.
+//!
+//! Load averages are sampled every 5 seconds whenever the scheduler runs, as the number of
+//! runnable threads decayed over 1, 5 and 15 minutes the same way as on Unix.
+//!
+//! CPUs with nothing to run idle until an interrupt. Waking a thread sends an IPI to one of the
+//! other idle CPUs, found by index through the topology snapshot without taking any locks beyond
+//! the scheduler's own.
+
+use crate::arch::address_space;
+use crate::arch::interrupts::ipi;
+use crate::arch::kthread::{Context, Stack, StackAllocError};
+use crate::arch::process::RegisterStore;
+use crate::arch::tls::ExceptionType;
+use crate::arch::user::{self, Exit};
+use crate::arch::{clock, page_allocation, syscall, topology};
+use crate::cmdline;
+use crate::idle;
+use crate::init_state::{self, Subsystem};
+use crate::memory_tag::MemoryTag;
+use crate::process::{EXIT_STATUS_KILLED, Process};
+use crate::sync::IrqMutex;
+use crate::timer;
+use crate::tunables;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+static SCHEDULER: IrqMutex<Option<Scheduler>> = IrqMutex::new(None);
+/// Bit set for each CPU index idling in `reschedule`. Bits are set with the scheduler locked, so
+/// `wake` can't miss one, and may be cleared a little late, which only costs a spurious IPI. CPUs
+/// past the first 64 are never woken early.
+static IDLE_CPUS: AtomicU64 = AtomicU64::new(0);
+
+const LOAD_SAMPLE_INTERVAL_NS: u64 = 5_000_000_000;
+/// Number of fractional bits in fixed point load averages.
+pub const LOAD_FRACTION_BITS: u32 = 11;
+const LOAD_ONE: u64 = 1 << LOAD_FRACTION_BITS;
+/// `LOAD_ONE / e^(5s / period)` for the 1, 5 and 15 minute periods.
+const LOAD_DECAY: [u64; 3] = [1884, 2014, 2037];
+
+pub type ThreadFunction = fn(usize) -> usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SpawnError {
+    #[error("kernel threads not initialised")]
+    NotInitialised,
+    #[error("failed to allocate stack - {0}")]
+    Stack(#[from] StackAllocError),
+}
+
+struct Thread {
+    name: &'static str,
+    state: ThreadState,
+    context: Context,
+    /// `None` for the boot thread, which runs on the stack it was given by the bootloader.
+    stack: Option<Stack>,
+    entry: Option<(ThreadFunction, usize)>,
+    exit_value: Option<usize>,
+    joiner: Option<ThreadId>,
+    detached: bool,
+    /// Process whose address space the thread runs in, `None` for kernel threads.
+    process: Option<Arc<Process>>,
+}
+
+impl Thread {
+    fn page_table_address(&self) -> Option<usize> {
+        self.process
+            .as_ref()
+            .map(|process| process.page_table_address())
+    }
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    run_queue: VecDeque<ThreadId>,
+    current: ThreadId,
+    next_id: u64,
+    /// Detached threads which have exited, freed once they're no longer running.
+    /// Boxed so that the context being switched away from doesn't move.
+    #[allow(clippy::vec_box)]
+    dead: Vec<Box<Thread>>,
+    /// Stacks and processes of joinable threads which have exited, released once they're no
+    /// longer running rather than when the thread is joined.
+    released: Vec<(Option<Stack>, Option<Arc<Process>>)>,
+    /// Picks the next thread to run in deterministic mode, `None` for round-robin.
+    rng: Option<Rng>,
+    /// 1, 5 and 15 minute load averages, fixed point with `LOAD_FRACTION_BITS` fractional bits.
+    load_averages: [u64; 3],
+    next_load_sample_ns: u64,
+}
+
+/// SplitMix64 PRNG, for deterministic mode scheduling decisions.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Scheduler {
+    fn current_thread(&mut self) -> &mut Thread {
+        self.threads.get_mut(&self.current).unwrap()
+    }
+
+    fn wake(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id)
+            && thread.state == ThreadState::Blocked
+        {
+            thread.state = ThreadState::Ready;
+            // The current thread is queued when it's switched away from
+            if id != self.current {
+                self.run_queue.push_back(id);
+            }
+        }
+    }
+
+    /// Takes the next thread to run off the run queue.
+    fn pop_next(&mut self) -> Option<ThreadId> {
+        match &mut self.rng {
+            Some(rng) if !self.run_queue.is_empty() => {
+                let index = rng.next() % self.run_queue.len() as u64;
+                self.run_queue.remove(index as usize)
+            }
+            _ => self.run_queue.pop_front(),
+        }
+    }
+
+    /// Returns when a thread sleeping until `wake_time_ns` should actually be woken.
+    fn quantise_wake_time(&self, wake_time_ns: u64) -> u64 {
+        match self.rng {
+            Some(_) => {
+                let quantum_ns = tunables::SCHED_QUANTUM_MS.get() * 1_000_000;
+                wake_time_ns.div_ceil(quantum_ns).saturating_mul(quantum_ns)
+            }
+            None => wake_time_ns,
+        }
+    }
+
+    /// Updates the load averages if a sample is due.
+    fn sample_load(&mut self) {
+        let now = clock::now_ns();
+        if now < self.next_load_sample_ns {
+            return;
+        }
+        let current_runnable = matches!(
+            self.current_thread().state,
+            ThreadState::Ready | ThreadState::Running,
+        );
+        let active = (self.run_queue.len() + current_runnable as usize) as u64 * LOAD_ONE;
+        // Catch up on samples missed while nothing was scheduled
+        while self.next_load_sample_ns <= now {
+            for (average, decay) in self.load_averages.iter_mut().zip(LOAD_DECAY) {
+                *average = (*average * decay + active * (LOAD_ONE - decay)) >> LOAD_FRACTION_BITS;
+            }
+            self.next_load_sample_ns += LOAD_SAMPLE_INTERVAL_NS;
+        }
+    }
+}
+
+/// Snapshot of scheduler activity, for telling whether the system is busy or stuck.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SchedulerStats {
+    pub num_threads: usize,
+    /// Threads waiting to run. There's a single run queue, shared by all CPUs.
+    pub run_queue_len: usize,
+    /// 1, 5 and 15 minute load averages, fixed point with `LOAD_FRACTION_BITS` fractional bits.
+    pub load_averages: [u64; 3],
+}
+
+impl fmt::Display for SchedulerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Threads: {}, run queue length {}, load average",
+            self.num_threads, self.run_queue_len,
+        )?;
+        for average in self.load_averages {
+            // Rounded to 2 decimal places
+            let average = average + LOAD_ONE / 200;
+            let fraction = ((average & (LOAD_ONE - 1)) * 100) >> LOAD_FRACTION_BITS;
+            write!(f, " {}.{fraction:02}", average >> LOAD_FRACTION_BITS)?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle to a spawned thread. Dropping this detaches the thread.
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread exits, returning its exit value.
+    pub fn join(self) -> usize {
+        let id = self.id;
+        core::mem::forget(self);
+        loop {
+            {
+                let mut lock = SCHEDULER.lock();
+                let scheduler = lock.as_mut().unwrap();
+                let current = scheduler.current;
+                let thread = scheduler.threads.get_mut(&id).unwrap();
+                if thread.state == ThreadState::Exited {
+                    let thread = scheduler.threads.remove(&id).unwrap();
+                    return thread.exit_value.unwrap();
+                }
+                thread.joiner = Some(current);
+                scheduler.current_thread().state = ThreadState::Blocked;
+            }
+            reschedule();
+        }
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut lock = SCHEDULER.lock();
+        let scheduler = lock.as_mut().unwrap();
+        let thread = scheduler.threads.get_mut(&self.id).unwrap();
+        if thread.state == ThreadState::Exited {
+            scheduler.threads.remove(&self.id);
+        } else {
+            thread.detached = true;
+        }
+    }
+}
+
+/// Registers the currently executing code as the first kernel thread. Must only be called once,
+/// after the heap has been initialised.
+pub fn init() {
+    init_state::begin(Subsystem::KernelThreads);
+    let sched_seed = cmdline::get().sched_seed;
+    let mut lock = SCHEDULER.lock();
+    assert!(lock.is_none(), "kernel threads already initialised");
+    let boot_id = ThreadId(1);
+    let mut threads = BTreeMap::new();
+    threads.insert(
+        boot_id,
+        Box::new(Thread {
+            name: "boot",
+            state: ThreadState::Running,
+            context: Context::current(),
+            stack: None,
+            entry: None,
+            exit_value: None,
+            joiner: None,
+            detached: true,
+            process: None,
+        }),
+    );
+    *lock = Some(Scheduler {
+        // Room for every thread, see `spawn_in`
+        run_queue: VecDeque::with_capacity(threads.len()),
+        threads,
+        current: boot_id,
+        next_id: 2,
+        dead: Vec::new(),
+        released: Vec::new(),
+        rng: sched_seed.map(Rng),
+        load_averages: [0; 3],
+        next_load_sample_ns: clock::now_ns() + LOAD_SAMPLE_INTERVAL_NS,
+    });
+    drop(lock);
+    if let Some(seed) = sched_seed {
+        log::info!("Deterministic scheduling with seed {seed}");
+    }
+    init_state::finish(Subsystem::KernelThreads);
+}
+
+/// Spawns a new kernel thread running `function(arg)`. The thread exits with the function's
+/// return value once it returns.
+pub fn spawn(
+    name: &'static str,
+    function: ThreadFunction,
+    arg: usize,
+) -> Result<JoinHandle, SpawnError> {
+    spawn_in(name, function, arg, None)
+}
+
+/// Spawns a new user thread in `process`, starting at `entry` with its stack pointer set to
+/// `stack_pointer`. The thread exits when the process exits, or when the user code causes an
+/// exception, which kills the process.
+pub fn spawn_user(
+    name: &'static str,
+    process: Arc<Process>,
+    entry: usize,
+    stack_pointer: usize,
+) -> Result<JoinHandle, SpawnError> {
+    let registers = Box::new(RegisterStore::new_user(entry, stack_pointer));
+    let registers = Box::into_raw(registers);
+    spawn_in(name, user_thread_main, registers as usize, Some(process)).inspect_err(|_| {
+        drop(unsafe { Box::from_raw(registers) });
+    })
+}
+
+/// Spawns a new kernel thread which runs in `process`'s address space, making system calls on its
+/// behalf.
+pub fn spawn_for_process(
+    name: &'static str,
+    process: Arc<Process>,
+    function: ThreadFunction,
+    arg: usize,
+) -> Result<JoinHandle, SpawnError> {
+    spawn_in(name, function, arg, Some(process))
+}
+
+fn spawn_in(
+    name: &'static str,
+    function: ThreadFunction,
+    arg: usize,
+    process: Option<Arc<Process>>,
+) -> Result<JoinHandle, SpawnError> {
+    let _tag = MemoryTag::KernelThreads.enter();
+    let stack = Stack::new()?;
+    let context = Context::new(&stack, thread_entry);
+    let mut lock = SCHEDULER.lock();
+    let scheduler = lock.as_mut().ok_or(SpawnError::NotInitialised)?;
+    let id = ThreadId(scheduler.next_id);
+    scheduler.next_id += 1;
+    scheduler.threads.insert(
+        id,
+        Box::new(Thread {
+            name,
+            state: ThreadState::Ready,
+            context,
+            stack: Some(stack),
+            entry: Some((function, arg)),
+            exit_value: None,
+            joiner: None,
+            detached: false,
+            process,
+        }),
+    );
+    // Each thread is queued at most once, so with room for every thread, waking one never
+    // allocates
+    let additional = scheduler.threads.len() - scheduler.run_queue.len();
+    scheduler.run_queue.reserve(additional);
+    scheduler.run_queue.push_back(id);
+    Ok(JoinHandle { id })
+}
+
+/// Returns the ID of the currently running thread, or `None` if threads aren't initialised yet.
+pub fn current() -> Option<ThreadId> {
+    SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current)
+}
+
+/// Returns the name of the currently running thread.
+pub fn current_name() -> Option<&'static str> {
+    let mut lock = SCHEDULER.lock();
+    lock.as_mut()
+        .map(|scheduler| scheduler.current_thread().name)
+}
+
+/// Returns the process the current thread runs in, or `None` for kernel threads.
+pub fn current_process() -> Option<Arc<Process>> {
+    let mut lock = SCHEDULER.lock();
+    lock.as_mut()?.current_thread().process.clone()
+}
+
+/// Calls `f` once with each process that has threads. Returns `false` without calling `f` if the
+/// scheduler is locked, so this can be used from anywhere, including while handling errors.
+pub fn try_for_each_process<F: FnMut(&Process)>(mut f: F) -> bool {
+    let Some(lock) = SCHEDULER.try_lock() else {
+        return false;
+    };
+    let Some(scheduler) = lock.as_ref() else {
+        return true;
+    };
+    let threads = &scheduler.threads;
+    for (index, thread) in threads.values().enumerate() {
+        let Some(process) = &thread.process else {
+            continue;
+        };
+        // Only visited at its first thread
+        let seen = threads.values().take(index).any(|other| {
+            other
+                .process
+                .as_ref()
+                .is_some_and(|other| Arc::ptr_eq(other, process))
+        });
+        if !seen {
+            f(process);
+        }
+    }
+    true
+}
+
+/// Returns the ID and name of the thread whose stack's guard area contains `address`. Returns
+/// `None` if there isn't one, or if the scheduler is locked, so this can be used while handling
+/// faults.
+pub fn try_thread_overflowing_into(address: usize) -> Option<(ThreadId, &'static str)> {
+    let lock = SCHEDULER.try_lock()?;
+    lock.as_ref()?.threads.iter().find_map(|(id, thread)| {
+        let stack = thread.stack.as_ref()?;
+        stack.guard_contains(address).then_some((*id, thread.name))
+    })
+}
+
+/// Returns current scheduler statistics, or `None` if threads aren't initialised yet.
+pub fn stats() -> Option<SchedulerStats> {
+    SCHEDULER.lock().as_ref().map(|scheduler| SchedulerStats {
+        num_threads: scheduler.threads.len(),
+        run_queue_len: scheduler.run_queue.len(),
+        load_averages: scheduler.load_averages,
+    })
+}
+
+/// Gives up the CPU to the next ready thread, if there is one.
+pub fn yield_now() {
+    timer::run_expired();
+    {
+        let mut lock = SCHEDULER.lock();
+        let Some(scheduler) = lock.as_mut() else {
+            return;
+        };
+        scheduler.sample_load();
+        if scheduler.run_queue.is_empty() {
+            return;
+        }
+        scheduler.current_thread().state = ThreadState::Ready;
+    }
+    reschedule();
+}
+
+/// Exits the current thread with `value`, waking any thread joining on it.
+pub fn exit(value: usize) -> ! {
+    {
+        let mut lock = SCHEDULER.lock();
+        let scheduler = lock.as_mut().unwrap();
+        let thread = scheduler.current_thread();
+        assert!(thread.stack.is_some(), "boot thread cannot exit");
+        thread.state = ThreadState::Exited;
+        thread.exit_value = Some(value);
+        if let Some(joiner) = thread.joiner.take() {
+            scheduler.wake(joiner);
+        }
+    }
+    reschedule();
+    unreachable!("exited thread was rescheduled");
+}
+
+/// Makes a blocked thread ready to run again. Never allocates, so it can be called from interrupt
+/// handlers.
+pub fn wake(id: ThreadId) {
+    crate::trace_sched_wakeup!(id);
+    let mut lock = SCHEDULER.lock();
+    if let Some(scheduler) = lock.as_mut() {
+        scheduler.wake(id);
+        wake_idle_cpu();
+    }
+}
+
+/// Returns the bit for the current CPU in `IDLE_CPUS`, if it has one.
+fn idle_cpu_bit() -> Option<u64> {
+    topology::current_cpu_index()
+        .and_then(|index| 1u64.checked_shl(index as u32))
+        .filter(|&bit| bit != 0)
+}
+
+/// Sends an IPI to an idle CPU other than this one, so it picks up a newly ready thread. This one
+/// checks the run queue itself before it next idles.
+fn wake_idle_cpu() {
+    let others = IDLE_CPUS.load(Ordering::Relaxed) & !idle_cpu_bit().unwrap_or(0);
+    if others != 0 {
+        _ = ipi::send(others.trailing_zeros() as usize, ipi::WAKE_VECTOR);
+    }
+}
+
+/// Marks the current thread as blocked, so that a `wake` between now and the next call to
+/// `block` isn't lost. Must be followed by either `block` or `cancel_block`.
+pub fn prepare_to_block() {
+    let mut lock = SCHEDULER.lock();
+    let scheduler = lock.as_mut().unwrap();
+    scheduler.current_thread().state = ThreadState::Blocked;
+}
+
+/// Marks the current thread as running again after `prepare_to_block`.
+pub fn cancel_block() {
+    let mut lock = SCHEDULER.lock();
+    let scheduler = lock.as_mut().unwrap();
+    scheduler.current_thread().state = ThreadState::Running;
+}
+
+/// Switches away from the current thread until it's woken, after `prepare_to_block`. Returns
+/// immediately if the thread has already been woken.
+pub fn block() {
+    reschedule();
+}
+
+/// Blocks the current thread, as `block` does, but also wakes it once the clock counter reaches
+/// `wake_time_ns`.
+pub fn block_until(wake_time_ns: u64) {
+    let (current, wake_time_ns) = {
+        let lock = SCHEDULER.lock();
+        let scheduler = lock.as_ref().unwrap();
+        (
+            scheduler.current,
+            scheduler.quantise_wake_time(wake_time_ns),
+        )
+    };
+    // Timers only run when rescheduling, so this can't fire before the thread blocks
+    let timer = timer::add_timer(wake_time_ns, wake_sleeper, current.0 as usize);
+    block();
+    // Woken by something else first
+    timer::cancel_timer(timer);
+}
+
+/// Blocks the current thread until the clock counter reaches `wake_time_ns`.
+pub fn sleep_until(wake_time_ns: u64) {
+    while clock::now_ns() < wake_time_ns {
+        prepare_to_block();
+        block_until(wake_time_ns);
+    }
+}
+
+fn wake_sleeper(id: usize) {
+    wake(ThreadId(id as u64));
+}
+
+/// Blocks the current thread for at least `num_ms` milliseconds.
+pub fn sleep_ms(num_ms: u64) {
+    sleep_until(clock::now_ns() + num_ms * 1_000_000);
+}
+
+/// Switches to the next ready thread. The current thread's state must already be set to what it
+/// should be while switched out. Ready threads are put at the back of the run queue.
+fn reschedule() {
+    let (old_context, new_context, page_table_switch) = loop {
+        timer::run_expired();
+        let mut lock = SCHEDULER.lock();
+        let scheduler = lock.as_mut().unwrap();
+        let current = scheduler.current;
+        scheduler.sample_load();
+        let Some(next) = scheduler.pop_next() else {
+            if scheduler.current_thread().state == ThreadState::Ready {
+                scheduler.current_thread().state = ThreadState::Running;
+                return;
+            }
+            // Nothing can run until an interrupt wakes something up
+            let idle_bit = idle_cpu_bit().unwrap_or(0);
+            IDLE_CPUS.fetch_or(idle_bit, Ordering::Relaxed);
+            drop(lock);
+            let next_wake_time = timer::next_deadline_ns();
+            if let Some(next_wake_time) = next_wake_time {
+                clock::start_countdown_ns(next_wake_time.saturating_sub(clock::now_ns()));
+            }
+            idle::enter(next_wake_time);
+            IDLE_CPUS.fetch_and(!idle_bit, Ordering::Relaxed);
+            continue;
+        };
+        // The current thread may have been woken up while waiting for an interrupt
+        if next == current {
+            scheduler.current_thread().state = ThreadState::Running;
+            return;
+        }
+        let old_thread = scheduler.current_thread();
+        let old_page_table = old_thread.page_table_address();
+        match old_thread.state {
+            ThreadState::Ready => scheduler.run_queue.push_back(current),
+            ThreadState::Exited if old_thread.detached => {
+                let thread = scheduler.threads.remove(&current).unwrap();
+                scheduler.dead.push(thread);
+            }
+            ThreadState::Exited => {
+                let resources = (old_thread.stack.take(), old_thread.process.take());
+                scheduler.released.push(resources);
+            }
+            _ => {}
+        }
+        let old_context = match scheduler.threads.get_mut(&current) {
+            Some(thread) => &raw mut thread.context,
+            // Detached exited threads are kept alive in `dead` until the next thread runs
+            None => &raw mut scheduler.dead.last_mut().unwrap().context,
+        };
+        let new_thread = scheduler.threads.get_mut(&next).unwrap();
+        new_thread.state = ThreadState::Running;
+        let new_context = &raw const new_thread.context;
+        let new_page_table = new_thread.page_table_address();
+        let page_table_switch = (new_page_table != old_page_table).then_some(new_page_table);
+        crate::trace_sched_switch!(current, next);
+        scheduler.current = next;
+        break (old_context, new_context, page_table_switch);
+    };
+    // The new thread's process is kept alive by the thread, which can't be freed until it runs
+    match page_table_switch {
+        Some(Some(address)) => unsafe { address_space::load_page_table(address) },
+        Some(None) => unsafe { page_allocation::load_kernel_address_space() },
+        None => {}
+    }
+    unsafe {
+        crate::arch::kthread::switch(old_context, new_context);
+    }
+    reap_dead_threads();
+}
+
+/// Frees detached threads which have exited, and the stacks and processes of joinable ones. Must
+/// not be called from an exited thread.
+fn reap_dead_threads() {
+    let (dead, released) = {
+        let mut lock = SCHEDULER.lock();
+        let scheduler = lock.as_mut().unwrap();
+        (
+            core::mem::take(&mut scheduler.dead),
+            core::mem::take(&mut scheduler.released),
+        )
+    };
+    // Dropped outside of the lock, as freeing stacks takes the page allocator lock, and dropping
+    // a process wakes threads waiting for it to exit
+    drop(dead);
+    drop(released);
+}
+
+extern "C" fn thread_entry() -> ! {
+    reap_dead_threads();
+    let (function, arg) = {
+        let mut lock = SCHEDULER.lock();
+        let scheduler = lock.as_mut().unwrap();
+        scheduler.current_thread().entry.take().unwrap()
+    };
+    exit(function(arg));
+}
+
+/// Page fault error code bit set for write accesses.
+const PAGE_FAULT_WRITE: u64 = 1 << 1;
+
+fn user_thread_main(registers: usize) -> usize {
+    let mut registers = unsafe { Box::from_raw(registers as *mut RegisterStore) };
+    loop {
+        match unsafe { user::run(&mut registers) } {
+            Exit::SystemCall => {
+                syscall::dispatch(&mut registers);
+                // Set once any of the process's threads exits it
+                if let Some(status) = current_process().and_then(|process| process.exit_status()) {
+                    return status;
+                }
+                yield_now();
+            }
+            Exit::Exception {
+                exception_type,
+                error_code,
+                page_fault_address,
+            } => {
+                if exception_type == ExceptionType::PageFault {
+                    crate::trace_page_fault!(
+                        page_fault_address,
+                        registers.instruction_address(),
+                        error_code,
+                    );
+                }
+                // Pages of file backed segments are mapped on first access
+                if exception_type == ExceptionType::PageFault
+                    && let Some(process) = current_process()
+                    && process
+                        .handle_page_fault(page_fault_address, error_code & PAGE_FAULT_WRITE != 0)
+                        .is_ok()
+                {
+                    continue;
+                }
+                let process = current_process().unwrap();
+                // Faults are expected once another thread has unmapped everything by exiting
+                if let Some(status) = process.exit_status() {
+                    return status;
+                }
+                log::warn!(
+                    "User thread {:?} exited on {exception_type:?} at {:#x} (error code {error_code:#x}, address {page_fault_address:#x})",
+                    current_name().unwrap(),
+                    registers.instruction_address(),
+                );
+                process.exit(EXIT_STATUS_KILLED);
+                return EXIT_STATUS_KILLED;
+            }
+        }
+    }
+}
@@ -1,23 +1,24 @@
 use crate::arch;
+use crate::cmdline;
+use crate::sync::{IrqMutex, IrqRwLock, OnceFlag};
 use crate::terminal;
+use alloc::boxed::Box;
 use core::fmt::Write;
-use log::{LevelFilter, Log, Metadata, Record};
-use spin::Mutex;
+use log::{Level, LevelFilter, Log, Metadata, Record};
 
 /// Initialises the global logger wrapper. Can be called multiple times. This is not thread safe,
 /// refer to the safety constraints of `log::set_logger_racy` for more details.
 pub unsafe fn init_wrapper() {
-    unsafe {
-        if !LOG_WRAPPER_INITIALISED {
-            LOG_WRAPPER_INITIALISED = true;
+    if LOG_WRAPPER_INITIALISED.set() {
+        unsafe {
             _ = log::set_logger_racy(&LOG_WRAPPER).map(|()| log::set_max_level(LevelFilter::Trace));
         }
     }
 }
 
-static mut LOG_WRAPPER_INITIALISED: bool = false;
+static LOG_WRAPPER_INITIALISED: OnceFlag = OnceFlag::new();
 static LOG_WRAPPER: LogWrapper = LogWrapper;
-pub static CURRENT_LOGGER: Mutex<Option<&'static dyn Log>> = Mutex::new(None);
+pub static CURRENT_LOGGER: IrqRwLock<Option<&'static dyn Log>> = IrqRwLock::new(None);
 
 struct LogWrapper;
 
@@ -27,44 +28,242 @@ impl log::Log for LogWrapper {
     }
 
     fn log(&self, record: &Record) {
-        if let Some(logger) = CURRENT_LOGGER.lock().as_mut() {
+        if let Some(logger) = CURRENT_LOGGER.read().as_ref() {
             logger.log(record);
         }
     }
 
     fn flush(&self) {
-        if let Some(logger) = CURRENT_LOGGER.lock().as_ref() {
+        if let Some(logger) = CURRENT_LOGGER.read().as_ref() {
             logger.flush();
         }
     }
 }
 
+/// Receives all console output as it's written, such as a remote console connection. Called with
+/// interrupts disabled, and must not log.
+pub type ConsoleSink = Box<dyn Fn(&str) + Send + Sync>;
+
+const MAX_CONSOLE_SINKS: usize = 4;
+
+static CONSOLE_SINKS: IrqRwLock<[Option<ConsoleSink>; MAX_CONSOLE_SINKS]> =
+    IrqRwLock::new([const { None }; MAX_CONSOLE_SINKS]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SinkError {
+    #[error("too many console sinks attached")]
+    TooManySinks,
+    #[error("console sink not attached")]
+    NotAttached,
+}
+
+/// Identifies an attached console sink, for detaching it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SinkId(usize);
+
+/// Attaches `sink` to receive console output until it's detached.
+pub fn attach_sink(sink: impl Fn(&str) + Send + Sync + 'static) -> Result<SinkId, SinkError> {
+    let sink: ConsoleSink = Box::new(sink);
+    let mut sinks = CONSOLE_SINKS.write();
+    let index = sinks
+        .iter()
+        .position(Option::is_none)
+        .ok_or(SinkError::TooManySinks)?;
+    sinks[index] = Some(sink);
+    Ok(SinkId(index))
+}
+
+pub fn detach_sink(id: SinkId) -> Result<(), SinkError> {
+    let sink = CONSOLE_SINKS.write()[id.0]
+        .take()
+        .ok_or(SinkError::NotAttached)?;
+    // Freed after the lock is released, so output isn't blocked while deallocating
+    drop(sink);
+    Ok(())
+}
+
+struct SinkWriter;
+
+impl core::fmt::Write for SinkWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for sink in CONSOLE_SINKS.read().iter().flatten() {
+            sink(s);
+        }
+        Ok(())
+    }
+}
+
+/// Bytes of recent console output kept for `write_log_tail`.
+const LOG_TAIL_LEN: usize = 4096;
+
+static LOG_TAIL: IrqMutex<LogTail> = IrqMutex::new(LogTail {
+    bytes: [0; LOG_TAIL_LEN],
+    next: 0,
+    len: 0,
+});
+
+/// The most recent console output, in a ring buffer, for state dumps and the `dmesg` command.
+struct LogTail {
+    bytes: [u8; LOG_TAIL_LEN],
+    /// Index the next byte is written at.
+    next: usize,
+    len: usize,
+}
+
+struct LogTailWriter;
+
+impl core::fmt::Write for LogTailWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut tail = LOG_TAIL.lock();
+        for byte in s.bytes() {
+            let next = tail.next;
+            tail.bytes[next] = byte;
+            tail.next = (next + 1) % LOG_TAIL_LEN;
+            tail.len = (tail.len + 1).min(LOG_TAIL_LEN);
+        }
+        Ok(())
+    }
+}
+
+/// Writes the most recent `LOG_TAIL_LEN` bytes of console output to `out`, oldest first. Doesn't
+/// allocate or copy the tail, so `out` is written to with the tail locked and must not log.
+pub fn write_log_tail(out: &mut (impl Write + ?Sized)) -> core::fmt::Result {
+    LOG_TAIL.lock().write_to(out)
+}
+
+/// Like `write_log_tail`, but writes nothing and returns `None` if the tail is locked, for callers
+/// which may have interrupted a write to it, such as NMI handlers.
+pub fn try_write_log_tail(out: &mut (impl Write + ?Sized)) -> Option<core::fmt::Result> {
+    Some(LOG_TAIL.try_lock()?.write_to(out))
+}
+
+impl LogTail {
+    /// Writes the tail oldest first, as the part up to the end of the buffer then the part which
+    /// wrapped around to its start.
+    fn write_to(&self, out: &mut (impl Write + ?Sized)) -> core::fmt::Result {
+        let start = (self.next + LOG_TAIL_LEN - self.len) % LOG_TAIL_LEN;
+        let (older, newer) = match start + self.len <= LOG_TAIL_LEN {
+            true => (&self.bytes[start..start + self.len], &[][..]),
+            false => (&self.bytes[start..], &self.bytes[..self.next]),
+        };
+        // A character split by the wrap is put back together
+        let (older, carried) = older.split_at(older.len() - incomplete_suffix_len(older));
+        let completed = newer
+            .iter()
+            .take(4 - carried.len())
+            .take_while(|&&byte| byte & 0xC0 == 0x80)
+            .count();
+        let mut character = [0; 4];
+        character[..carried.len()].copy_from_slice(carried);
+        character[carried.len()..][..completed].copy_from_slice(&newer[..completed]);
+        write_utf8(older, out)?;
+        write_utf8(&character[..carried.len() + completed], out)?;
+        write_utf8(&newer[completed..], out)
+    }
+}
+
+/// Returns the number of bytes at the end of `bytes` which start a UTF-8 character but don't
+/// finish it.
+fn incomplete_suffix_len(bytes: &[u8]) -> usize {
+    for len in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - len];
+        if byte & 0xC0 != 0x80 {
+            let char_len = match byte {
+                0xF0.. => 4,
+                0xE0.. => 3,
+                0xC0.. => 2,
+                _ => 1,
+            };
+            return if char_len > len { len } else { 0 };
+        }
+    }
+    0
+}
+
+fn write_utf8(bytes: &[u8], out: &mut (impl Write + ?Sized)) -> core::fmt::Result {
+    // The oldest character may have been cut in half
+    for chunk in bytes.utf8_chunks() {
+        out.write_str(chunk.valid())?;
+    }
+    Ok(())
+}
+
+/// Formats nanoseconds since boot as seconds, or dashes if the clock isn't running yet.
+struct Timestamp(Option<u64>);
+
+impl core::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(ns) => write!(f, "{:5}.{:09}", ns / 1_000_000_000, ns % 1_000_000_000),
+            None => write!(f, "    -.---------"),
+        }
+    }
+}
+
 pub static KERNEL_LOGGER: KernelLogger = KernelLogger;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct KernelLogger;
 
+/// Writes to the consoles selected on the command line.
+#[derive(Clone, Copy)]
+struct ConsoleWriter {
+    serial: bool,
+    framebuffer: bool,
+}
+
+impl ConsoleWriter {
+    /// During a quiet boot, only `important` output is shown on the framebuffer.
+    fn new(important: bool) -> Self {
+        let config = cmdline::get();
+        Self {
+            serial: config.console.uses_serial(),
+            framebuffer: config.console.uses_framebuffer() && (important || !config.quiet),
+        }
+    }
+}
+
 macro_rules! impl_writers_func_body {
-    ($write_fn: ident, $arg: ident) => {
-        arch::debug_output::ArchWriter.$write_fn($arg)?;
-        if let Some(terminal) = terminal::TERMINAL.lock().as_mut() {
-            terminal.$write_fn($arg)?;
+    ($self: ident, $write_fn: ident, $arg: ident) => {
+        if $self.serial {
+            arch::debug_output::ArchWriter.$write_fn($arg)?;
         }
+        if $self.framebuffer {
+            if let Some(terminal) = terminal::VTS.lock().get_mut(terminal::LOG_VT) {
+                terminal.$write_fn($arg)?;
+            }
+        }
+        SinkWriter.$write_fn($arg)?;
+        LogTailWriter.$write_fn($arg)?;
         return Ok(());
     };
 }
 
+impl core::fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        impl_writers_func_body!(self, write_str, s);
+    }
+
+    fn write_char(&mut self, c: char) -> core::fmt::Result {
+        impl_writers_func_body!(self, write_char, c);
+    }
+
+    fn write_fmt(&mut self, args: core::fmt::Arguments) -> core::fmt::Result {
+        impl_writers_func_body!(self, write_fmt, args);
+    }
+}
+
 impl core::fmt::Write for KernelLogger {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        impl_writers_func_body!(write_str, s);
+        ConsoleWriter::new(false).write_str(s)
     }
 
     fn write_char(&mut self, c: char) -> core::fmt::Result {
-        impl_writers_func_body!(write_char, c);
+        ConsoleWriter::new(false).write_char(c)
     }
 
     fn write_fmt(&mut self, args: core::fmt::Arguments) -> core::fmt::Result {
-        impl_writers_func_body!(write_fmt, args);
+        ConsoleWriter::new(false).write_fmt(args)
     }
 }
 
@@ -75,9 +274,14 @@ impl log::Log for KernelLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
+            // Only the BSP runs before the topology is known
+            let cpu = arch::topology::current_cpu_index().unwrap_or(0);
+            let mut writer = ConsoleWriter::new(record.level() <= Level::Warn);
+            // Written in one go, so each console gets the whole line at once
             _ = writeln!(
-                Self,
-                "[{}] ({}) {}",
+                writer,
+                "[{}] cpu{cpu} [{}] ({}) {}",
+                Timestamp(arch::clock::try_now_ns()),
                 record.level(),
                 record.target(),
                 record.args()
@@ -87,3 +291,31 @@ impl log::Log for KernelLogger {
 
     fn flush(&self) {}
 }
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+    use alloc::string::String;
+
+    #[kernel_test]
+    fn writes_wrapped_log_tail() -> TestResult {
+        let mut tail = LogTail {
+            bytes: [0; LOG_TAIL_LEN],
+            next: 0,
+            len: 0,
+        };
+        let text = "é\nabc";
+        // Wraps in the middle of the first line's character
+        let start = LOG_TAIL_LEN - 1;
+        for (index, byte) in text.bytes().enumerate() {
+            tail.bytes[(start + index) % LOG_TAIL_LEN] = byte;
+        }
+        tail.next = (start + text.len()) % LOG_TAIL_LEN;
+        tail.len = text.len();
+        let mut out = String::new();
+        tail.write_to(&mut out).unwrap();
+        ktest_assert_eq!(out, text);
+        Ok(())
+    }
+}
@@ -15,20 +15,54 @@
 #![feature(cfg_select)]
 // Used in ACPI for defining implementations of `AcpiOsPrintf` and `AcpiOsVprintf`.
 #![feature(c_variadic)]
-// Used in various places, including the process Virtual Memory Allocator.
-#![feature(offset_of_enum)]
 
+#[cfg(feature = "app")]
+pub mod app;
 pub mod arch;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod block;
+pub mod boot_progress;
+pub mod cmdline;
 pub mod core_graphics;
 pub mod cpio;
 pub mod debugging;
+pub mod device;
+pub mod event;
+pub mod executor;
+pub mod file;
 pub mod heap;
+pub mod idle;
+pub mod init_state;
+pub mod input;
+pub mod io_ring;
+pub mod ipc;
+pub mod kshell;
+pub mod ktest;
+pub mod kthread;
 pub mod logging;
+pub mod memory_tag;
+pub mod memstats;
+pub mod net;
 pub mod physical_block_allocator;
 pub mod platform;
 pub mod process;
+pub mod profiler;
+pub mod scrubber;
+pub mod shared_memory;
+pub mod sync;
+pub mod syscall_errors;
 pub mod terminal;
+pub mod time_namespace;
+pub mod timer;
+pub mod trace;
+pub mod tty;
+pub mod tunables;
+pub mod usb;
+pub mod user_memory;
 pub mod vma;
+pub mod wait_queue;
+pub mod work_queue;
 
 extern crate alloc;
 
@@ -37,10 +71,14 @@ use log::{debug, warn};
 unsafe extern "C" {
     static HEAP_BASE: usize;
     static HEAP_END: usize;
+    static LARGE_ALLOCATION_AREA_BASE: usize;
+    static LARGE_ALLOCATION_AREA_END: usize;
     static LOCAL_APIC_BASE: usize;
 }
 
 const FONT_PATH: &str = "etc/kernel/standard_font.psf";
+#[cfg(feature = "suspend-test")]
+const SUSPEND_TEST_CYCLES: usize = 100;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn kernel_main(args: &arch::kernel_args::Args) -> ! {
@@ -49,10 +87,21 @@ pub extern "C" fn kernel_main(args: &arch::kernel_args::Args) -> ! {
         arch::debug_output::init_writers();
         logging::init_wrapper();
         _ = logging::CURRENT_LOGGER
-            .lock()
+            .write()
             .replace(&logging::KERNEL_LOGGER);
     }
     debug!("Early logging initialised");
+    // Apply boot options and tunables from the command line
+    match core::str::from_utf8(unsafe { args.environment.get_slice() }) {
+        Ok(environment) => {
+            cmdline::init(environment);
+            tunables::apply_arguments(environment.split_whitespace());
+        }
+        Err(_) => warn!("Kernel command line is not valid UTF-8, ignoring"),
+    }
+    if !args.kernel_symbols.ptr.is_null() {
+        _ = debugging::KERNEL_SYMBOLS_FILE.set(unsafe { args.kernel_symbols.get_slice() });
+    }
     unsafe {
         arch::page_allocation::init(
             args.page_table_address,
@@ -67,63 +116,67 @@ pub extern "C" fn kernel_main(args: &arch::kernel_args::Args) -> ! {
     unsafe {
         let heap_start_addr = &HEAP_BASE as *const usize as usize;
         let heap_size = (&HEAP_END as *const usize as usize) - heap_start_addr + 1;
-        heap::init_heap(heap_start_addr, heap_size);
+        let large_area_start_addr = &LARGE_ALLOCATION_AREA_BASE as *const usize as usize;
+        let large_area_size =
+            (&LARGE_ALLOCATION_AREA_END as *const usize as usize) - large_area_start_addr + 1;
+        heap::init_heap(
+            heap_start_addr,
+            heap_size,
+            large_area_start_addr,
+            large_area_size,
+        );
     }
+    memory_tag::init();
+    arch::page_allocation::init_page_info();
+    kthread::init();
+    debug!("Kernel threads initialised");
+    work_queue::init();
+    debug!("Work queue initialised");
+    executor::init();
+    debug!("Executor initialised");
     let initrd = unsafe { args.initrd.get_slice() };
     assert!(
         initrd.as_ptr() as usize > 0xF000_0000_0000_0000,
         "lower half initrd currently unsupported"
     );
+    _ = cpio::INITRD.lock().replace(initrd);
+    block::ramdisk::init();
     // Initialise framebuffer logging
     unsafe {
         'fb_log: {
-            // Initialise framebuffer
-            match args.framebuffers.len {
-                0 => {
-                    debug!("No framebuffer found");
-                    break 'fb_log;
-                }
-                1 => debug!("1 framebuffer found, initialising..."),
-                x => warn!(
-                    concat!(
-                        "{} framebuffers found, ",
-                        "but 9x currently only supports using a single framebuffer"
-                    ),
-                    x
-                ),
-            }
-            let framebuffer_arg = args.framebuffers.get_slice()[0];
-            assert!(
-                framebuffer_arg.ptr.as_ptr() as usize > 0xF000_0000_0000_0000,
-                "lower half framebuffers currently unsupported",
-            );
-            if framebuffer_arg.ptr_type != arch::kernel_args::PtrType::Linear {
-                warn!("Physical location framebuffers currently unsupported");
+            if !cmdline::get().console.uses_framebuffer() {
+                debug!("Framebuffer console disabled on command line");
                 break 'fb_log;
             }
-            if framebuffer_arg.color_format != arch::kernel_args::ColorFormat::Bgrr8 {
-                warn!(
-                    "Unsupported framebuffer format: {:?}, ignoring provided framebuffer",
-                    framebuffer_arg.color_format
-                );
+            // Initialise framebuffers
+            if args.framebuffers.len == 0 {
+                debug!("No framebuffer found");
                 break 'fb_log;
             }
-            // Swap in new feamebuffer
-            {
-                let mut global_framebuffer = core_graphics::FRAMEBUFFER.lock();
-                let mut new_framebuffer = core_graphics::Framebuffer {
-                    buffer: core::slice::from_raw_parts_mut(
-                        framebuffer_arg.ptr.as_ptr(),
-                        framebuffer_arg.size as usize,
+            debug!(
+                "{} framebuffer(s) found, initialising...",
+                args.framebuffers.len
+            );
+            for framebuffer_arg in args.framebuffers.get_slice() {
+                match core_graphics::init_framebuffer(framebuffer_arg) {
+                    Ok(display) => debug!(
+                        "Display {display}: {}x{} framebuffer",
+                        framebuffer_arg.width, framebuffer_arg.height,
                     ),
-                    width: framebuffer_arg.width,
-                    height: framebuffer_arg.height,
-                    scanline_length: framebuffer_arg.scanline_length,
-                    color_format: framebuffer_arg.color_format,
-                };
-                new_framebuffer.clear();
-                _ = global_framebuffer.replace(new_framebuffer);
+                    Err(err) => warn!("Ignoring provided framebuffer - {err}"),
+                }
+            }
+            let num_displays = core_graphics::FRAMEBUFFERS.lock().len();
+            if num_displays == 0 {
+                break 'fb_log;
             }
+            let output = match cmdline::get().console_output {
+                terminal::Output::Display(display) if display >= num_displays => {
+                    warn!("Console display {display} not found, using display 0");
+                    terminal::Output::Display(0)
+                }
+                output => output,
+            };
             // Initialise console font for terminal
             let font_result: Result<_, &str> = 'font: {
                 let Some(font_file) = cpio::find_file(initrd, FONT_PATH.as_bytes()) else {
@@ -138,25 +191,64 @@ pub extern "C" fn kernel_main(args: &arch::kernel_args::Args) -> ! {
                     break 'fb_log;
                 }
             };
-            // Swap in new terminal
+            // Set up the first VT, which the log is written to
             {
-                let mut global_terminal = terminal::TERMINAL.lock();
-                let new_terminal = terminal::Terminal::new(font).unwrap();
-                _ = global_terminal.replace(new_terminal);
+                let new_terminal = terminal::Terminal::new(font, output).unwrap();
+                terminal::VTS.lock().init(new_terminal);
             }
             debug!("Framebuffer terminal initialised");
+            terminal::start_blink_thread();
+            boot_progress::update();
         }
     }
     // Architecture stage 2 init
     unsafe {
         arch::init_stage_2(args);
+        arch::protection::protect_kernel_image();
+    }
+    if let Some(address) = cmdline::get().watch_page
+        && let Err(err) = arch::watchpoint::watch(address)
+    {
+        warn!("Failed to watch page {address:#x} - {err}");
+    }
+    if cmdline::get().trace {
+        trace::start();
+    }
+    block::nvme::init();
+    net::virtio_net::init();
+    net::e1000::init();
+    net::ipv4::init();
+    net::netdump::init();
+    usb::xhci::init();
+    platform::acpi::thermal::init();
+    idle::init();
+    #[cfg(feature = "suspend-test")]
+    device::run_suspend_test(SUSPEND_TEST_CYCLES);
+    #[cfg(feature = "bench")]
+    log::info!("{}", bench::run_all());
+    if cmdline::get().run_tests {
+        ktest::run_all();
+    }
+    init_state::log_boot_order();
+    kshell::start();
+    net::console::start();
+    if cmdline::get().memory_scrub {
+        scrubber::start();
+    }
+    #[cfg(feature = "app")]
+    app::start();
+    boot_progress::complete();
+    debug!("Finished, parking the boot thread");
+    // The boot thread can't exit, so it blocks forever, and the scheduler idles the CPU whenever
+    // nothing else is ready to run
+    loop {
+        kthread::prepare_to_block();
+        kthread::block();
     }
-    debug!("Finished, entering infinite loop!");
-    #[allow(clippy::empty_loop)]
-    loop {}
 }
 
 #[alloc_error_handler]
 fn alloc_error(layout: alloc::alloc::Layout) -> ! {
+    log::error!("{}", memstats::get());
     panic!("out of memory when allocating with layout {layout:?}");
 }
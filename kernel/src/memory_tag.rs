@@ -0,0 +1,189 @@
+//! Tagging of memory by the subsystem which allocated it, to find out what's using memory.
+//!
+//! Code marks the subsystem it's allocating for with `MemoryTag::enter`. Heap allocations and
+//! pages reserved from the page allocator while the returned guard is alive are counted against
+//! that tag, and uncounted from the same tag when freed. Pages mapped by the heap to hold small
+//! allocations are counted against `MemoryTag::Heap`, while large allocations are mapped page by
+//! page and so only show up in their tag's page count.
+//!
+//! Tracking is only done in debug builds, in release builds every count reads as zero.
+
+use crate::arch::page_allocation;
+use crate::arch::paging::PAGE_SIZE;
+use crate::sync::IrqMutex;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+const ENABLED: bool = cfg!(debug_assertions);
+
+/// Tag applied to allocations made outside of any `MemoryTag::enter` scope.
+static CURRENT_TAG: AtomicU8 = AtomicU8::new(MemoryTag::Other as u8);
+static HEAP_BYTES: [AtomicUsize; MemoryTag::COUNT] = [const { AtomicUsize::new(0) }; _];
+static PAGES: [AtomicUsize; MemoryTag::COUNT] = [const { AtomicUsize::new(0) }; _];
+/// Tag of each physical page, indexed by page number.
+static PAGE_TAGS: IrqMutex<Option<Vec<MemoryTag>>> = IrqMutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryTag {
+    /// Allocated before tracking started, never counted.
+    Untracked,
+    Other,
+    /// Pages backing the heap.
+    Heap,
+    Acpi,
+    Graphics,
+    Terminal,
+    KernelThreads,
+}
+
+impl MemoryTag {
+    pub const ALL: [MemoryTag; 7] = [
+        MemoryTag::Untracked,
+        MemoryTag::Other,
+        MemoryTag::Heap,
+        MemoryTag::Acpi,
+        MemoryTag::Graphics,
+        MemoryTag::Terminal,
+        MemoryTag::KernelThreads,
+    ];
+    pub const COUNT: usize = Self::ALL.len();
+
+    pub fn from_u8(value: u8) -> Self {
+        Self::ALL
+            .get(value as usize)
+            .copied()
+            .unwrap_or(MemoryTag::Untracked)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryTag::Untracked => "untracked",
+            MemoryTag::Other => "other",
+            MemoryTag::Heap => "heap",
+            MemoryTag::Acpi => "acpi",
+            MemoryTag::Graphics => "graphics",
+            MemoryTag::Terminal => "terminal",
+            MemoryTag::KernelThreads => "kthreads",
+        }
+    }
+
+    /// Returns the tag new allocations are counted against.
+    pub fn current() -> Self {
+        Self::from_u8(CURRENT_TAG.load(Ordering::Relaxed))
+    }
+
+    /// Counts allocations against this tag until the returned guard is dropped. Guards shouldn't
+    /// be held across anything which can switch threads, as the tag isn't per thread.
+    #[must_use]
+    pub fn enter(self) -> TagGuard {
+        TagGuard {
+            previous: CURRENT_TAG.swap(self as u8, Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct TagGuard {
+    previous: u8,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        CURRENT_TAG.store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// Starts tracking pages from the page allocator. Pages reserved before this are untracked.
+pub fn init() {
+    if !ENABLED {
+        return;
+    }
+    let num_pages = page_allocation::total_pages();
+    let mut page_tags = Vec::new();
+    if page_tags.try_reserve_exact(num_pages).is_err() {
+        log::warn!("No memory for page tags, pages won't be tracked by memory tag");
+        return;
+    }
+    page_tags.resize(num_pages, MemoryTag::Untracked);
+    *PAGE_TAGS.lock() = Some(page_tags);
+}
+
+pub fn heap_allocated(tag: MemoryTag, bytes: usize) {
+    if ENABLED && tag != MemoryTag::Untracked {
+        HEAP_BYTES[tag as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+pub fn heap_freed(tag: MemoryTag, bytes: usize) {
+    if ENABLED && tag != MemoryTag::Untracked {
+        HEAP_BYTES[tag as usize].fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Counts the physical page at `address` against the current tag.
+pub fn page_reserved(address: usize) {
+    if !ENABLED {
+        return;
+    }
+    let tag = MemoryTag::current();
+    if let Some(page_tag) = PAGE_TAGS
+        .lock()
+        .as_mut()
+        .and_then(|page_tags| page_tags.get_mut(address / PAGE_SIZE))
+    {
+        *page_tag = tag;
+        PAGES[tag as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Uncounts the physical page at `address` from the tag it was reserved under.
+pub fn page_freed(address: usize) {
+    if !ENABLED {
+        return;
+    }
+    if let Some(page_tag) = PAGE_TAGS
+        .lock()
+        .as_mut()
+        .and_then(|page_tags| page_tags.get_mut(address / PAGE_SIZE))
+    {
+        let tag = core::mem::replace(page_tag, MemoryTag::Untracked);
+        if tag != MemoryTag::Untracked {
+            PAGES[tag as usize].fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Snapshot of memory use by tag.
+#[derive(Clone, Copy, Debug)]
+pub struct Usage {
+    pub heap_bytes: [usize; MemoryTag::COUNT],
+    pub pages: [usize; MemoryTag::COUNT],
+}
+
+impl Usage {
+    pub fn get(&self, tag: MemoryTag) -> (usize, usize) {
+        (self.heap_bytes[tag as usize], self.pages[tag as usize])
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Memory by tag (heap bytes, pages):")?;
+        for tag in MemoryTag::ALL {
+            let (heap_bytes, pages) = self.get(tag);
+            if heap_bytes != 0 || pages != 0 {
+                write!(f, " {} {heap_bytes}/{pages},", tag.name())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns current memory use by tag. Doesn't allocate, so is safe to call when out of memory.
+pub fn usage() -> Usage {
+    Usage {
+        heap_bytes: core::array::from_fn(|i| HEAP_BYTES[i].load(Ordering::Relaxed)),
+        pages: core::array::from_fn(|i| PAGES[i].load(Ordering::Relaxed)),
+    }
+}
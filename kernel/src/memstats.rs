@@ -0,0 +1,91 @@
+//! Memory statistics gathered from every allocator, for the `mem` shell command and out of memory
+//! reports.
+//!
+//! Gathering doesn't allocate, and skips process memory it can't lock straight away, so a report
+//! can be made from the allocation error handler whatever was allocating at the time.
+
+use crate::arch::page_allocation;
+use crate::arch::paging::PAGE_SIZE;
+use crate::cmdline;
+use crate::heap::{self, HeapStats};
+use crate::kthread;
+use crate::memory_tag::{self, Usage};
+use crate::scrubber::{self, ScrubStats};
+use core::fmt;
+
+/// Pages used by processes, for their segments, page tables and segment trees.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessStats {
+    pub num_processes: usize,
+    pub pages_used: usize,
+    /// Processes whose memory was locked, so aren't counted in `pages_used`.
+    pub num_locked: usize,
+}
+
+/// Snapshot of memory use across the kernel.
+#[derive(Clone, Copy, Debug)]
+pub struct MemStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    /// `None` if the scheduler was locked.
+    pub processes: Option<ProcessStats>,
+    pub heap: HeapStats,
+    /// `None` if the scrubber isn't enabled.
+    pub scrubber: Option<ScrubStats>,
+    pub tags: Usage,
+}
+
+impl fmt::Display for MemStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Pages: {} of {} used ({} KiB free)",
+            self.total_pages - self.free_pages,
+            self.total_pages,
+            self.free_pages * PAGE_SIZE / 1024,
+        )?;
+        match self.processes {
+            Some(processes) => {
+                write!(
+                    f,
+                    "Processes: {} using {} pages",
+                    processes.num_processes, processes.pages_used,
+                )?;
+                if processes.num_locked != 0 {
+                    write!(f, " ({} locked, not counted)", processes.num_locked)?;
+                }
+                writeln!(f)?;
+            }
+            None => writeln!(f, "Processes: scheduler locked")?,
+        }
+        writeln!(f, "{}", self.heap)?;
+        if let Some(scrubber) = self.scrubber {
+            writeln!(f, "{scrubber}")?;
+        }
+        write!(f, "{}", self.tags)
+    }
+}
+
+fn process_stats() -> Option<ProcessStats> {
+    let mut stats = ProcessStats::default();
+    let scheduler_unlocked = kthread::try_for_each_process(|process| {
+        stats.num_processes += 1;
+        match process.memory.try_lock() {
+            Some(memory) => stats.pages_used += memory.pages_used,
+            None => stats.num_locked += 1,
+        }
+    });
+    scheduler_unlocked.then_some(stats)
+}
+
+/// Returns current memory usage.
+pub fn get() -> MemStats {
+    MemStats {
+        total_pages: page_allocation::total_pages(),
+        free_pages: page_allocation::free_pages(),
+        processes: process_stats(),
+        heap: heap::stats(),
+        scrubber: cmdline::get().memory_scrub.then(scrubber::stats),
+        tags: memory_tag::usage(),
+    }
+}
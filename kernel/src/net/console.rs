@@ -0,0 +1,195 @@
+//! Debug console over TCP, for machines with nothing but a network cable attached.
+//!
+//! Booting with `netconsole=<port> netconsole_key=<key>` accepts connections on that port, one at
+//! a time, so something like `nc <address> <port>` can connect. A connection must send the key as
+//! its first line within `KEY_TIMEOUT_NS`. It then receives all console output, and everything
+//! else it sends is typed into a kernel shell session of its own, see `kshell`. Nothing is
+//! encrypted, so the key only keeps out whoever can't see the traffic.
+//!
+//! Console output is written with interrupts disabled, so it's queued for the connection's thread
+//! to send, and dropped if more than `MAX_QUEUED_OUTPUT` bytes build up.
+
+use super::tcp::{Socket, SocketError};
+use crate::arch::clock;
+use crate::cmdline::{self, MAX_SECRET_LEN, Secret};
+use crate::kshell;
+use crate::kthread;
+use crate::logging;
+use crate::sync::IrqMutex;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+const MAX_QUEUED_OUTPUT: usize = 16 * 1024;
+const KEY_TIMEOUT_NS: u64 = 10_000_000_000;
+/// How long to wait after a wrong key before accepting another connection, to slow down guessing.
+const WRONG_KEY_DELAY_MS: u64 = 1000;
+/// How often input is checked for and output sent, as for the local shell.
+const POLL_MS: u64 = 20;
+
+/// Collects a session's shell output, to be sent once the command has finished.
+struct Output(Vec<u8>);
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Starts listening for connections, if a port and key were given on the command line. Must be
+/// called after `super::ipv4::init`.
+pub fn start() {
+    let config = cmdline::get();
+    let Some(port) = config.netconsole else {
+        return;
+    };
+    if config.netconsole_key.is_none() {
+        log::warn!("No network console key given, not listening");
+        return;
+    }
+    if let Err(err) = kthread::spawn("netconsole", listen_thread, port as usize) {
+        log::warn!("Failed to start network console - {err}");
+    }
+}
+
+fn listen_thread(port: usize) -> usize {
+    let key = cmdline::get().netconsole_key.unwrap();
+    let listener = Arc::new(Socket::new());
+    if let Err(err) = listener.bind(port as u16).and_then(|_| listener.listen(1)) {
+        log::warn!("Failed to listen for network console connections - {err}");
+        return 0;
+    }
+    log::info!("Network console listening on port {port}");
+    loop {
+        let connection = match listener.accept() {
+            Ok(connection) => connection,
+            Err(err) => {
+                log::warn!("Network console stopped accepting connections - {err}");
+                return 0;
+            }
+        };
+        if let Err(err) = serve(&connection, &key) {
+            log::info!("Network console connection ended - {err}");
+        }
+        connection.close();
+    }
+}
+
+fn serve(connection: &Socket, key: &Secret) -> Result<(), SocketError> {
+    if !authenticate(connection, key)? {
+        log::warn!("Network console connection didn't send the key");
+        send_all(connection, b"Wrong key\n")?;
+        kthread::sleep_ms(WRONG_KEY_DELAY_MS);
+        return Ok(());
+    }
+    log::info!("Network console connected");
+    let queue = Arc::new(IrqMutex::new(VecDeque::with_capacity(MAX_QUEUED_OUTPUT)));
+    let sink_queue = queue.clone();
+    // Never grows the queue, so the sink never allocates, as output can be written from anywhere
+    let sink = logging::attach_sink(move |s: &str| {
+        let mut queue = sink_queue.lock();
+        let len = s.len().min(MAX_QUEUED_OUTPUT - queue.len());
+        queue.extend(&s.as_bytes()[..len]);
+    });
+    if let Err(err) = sink {
+        send_all(
+            connection,
+            alloc::format!("No console output - {err}\n").as_bytes(),
+        )?;
+    }
+    let result = run_session(connection, &queue);
+    if let Ok(sink) = sink {
+        _ = logging::detach_sink(sink);
+    }
+    log::info!("Network console disconnected");
+    result
+}
+
+/// Reads the first line of the connection, returning whether it's the key.
+fn authenticate(connection: &Socket, key: &Secret) -> Result<bool, SocketError> {
+    send_all(connection, b"Key: ")?;
+    let deadline_ns = clock::now_ns() + KEY_TIMEOUT_NS;
+    // One longer than any key, so that longer lines never match
+    let mut line = [0; MAX_SECRET_LEN + 1];
+    let mut line_len = 0;
+    let mut byte = [0];
+    loop {
+        match connection.try_receive(&mut byte)? {
+            Some(0) => return Ok(false),
+            Some(_) => match byte[0] {
+                b'\n' => break,
+                b'\r' => {}
+                byte if line_len < line.len() => {
+                    line[line_len] = byte;
+                    line_len += 1;
+                }
+                _ => {}
+            },
+            None if clock::now_ns() >= deadline_ns => return Ok(false),
+            None => kthread::sleep_ms(POLL_MS),
+        }
+    }
+    Ok(keys_match(&line[..line_len], key.as_bytes()))
+}
+
+/// Compares without stopping at the first difference, so the time taken doesn't give away how
+/// much of a guess was right.
+fn keys_match(guess: &[u8], key: &[u8]) -> bool {
+    let difference = guess
+        .iter()
+        .zip(key)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    guess.len() == key.len() && difference == 0
+}
+
+/// Passes input from the connection to a shell session, and sends back its output and the
+/// console's, until the connection is closed.
+fn run_session(connection: &Socket, queue: &IrqMutex<VecDeque<u8>>) -> Result<(), SocketError> {
+    let mut out = Output(Vec::new());
+    let mut session = kshell::Session::start(&mut out, false);
+    let mut console_output = Vec::with_capacity(MAX_QUEUED_OUTPUT);
+    let mut input = [0; 64];
+    loop {
+        console_output.extend(queue.lock().drain(..));
+        send_all(connection, &console_output)?;
+        console_output.clear();
+        send_all(connection, &out.0)?;
+        out.0.clear();
+        match connection.try_receive(&mut input)? {
+            Some(0) => return Ok(()),
+            Some(len) => {
+                // Lines end with either `\n` or `\r\n`, which would otherwise run two commands
+                for &byte in input[..len].iter().filter(|&&byte| byte != b'\r') {
+                    session.input(&mut out, char::from(byte));
+                }
+            }
+            None => kthread::sleep_ms(POLL_MS),
+        }
+    }
+}
+
+fn send_all(connection: &Socket, mut data: &[u8]) -> Result<(), SocketError> {
+    while !data.is_empty() {
+        let len = connection.send(data)?;
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert;
+
+    #[kernel_test]
+    fn matches_only_the_whole_key() -> TestResult {
+        ktest_assert!(keys_match(b"hunter2", b"hunter2"));
+        ktest_assert!(!keys_match(b"hunter3", b"hunter2"));
+        ktest_assert!(!keys_match(b"hunter", b"hunter2"));
+        ktest_assert!(!keys_match(b"hunter22", b"hunter2"));
+        ktest_assert!(!keys_match(b"", b"hunter2"));
+        Ok(())
+    }
+}
@@ -0,0 +1,556 @@
+//! Driver for Intel 8254x and 82574 (e1000 and e1000e) network controllers on PCI.
+//!
+//! Each controller gets a receive and a transmit descriptor ring, with a fixed buffer per
+//! descriptor, all in one physically contiguous allocation. Every receive descriptor but one is
+//! handed to the controller up front, as the ring is full when the tail reaches the head.
+//! Interrupts use MSI if the controller has it and INTx otherwise. The handler reads and so clears
+//! the interrupt causes, and schedules work to pass received frames to the receive callback and
+//! log link changes. Transmit interrupts are left off, with sent descriptors reclaimed on the next
+//! transmit instead.
+
+use super::{MAX_FRAME_LEN, MacAddress, NetDevice, NetError, ReceiveCallback};
+use crate::arch::clock;
+use crate::arch::interrupts::{apic, routing};
+use crate::arch::msi::{self, AllocatedVector, MsiError};
+use crate::arch::page_allocation;
+use crate::arch::paging::{PAGE_SIZE, PageTableEntry};
+use crate::arch::pci::{self, PciAddress};
+use crate::sync::OnceLock;
+use crate::work_queue::{self, Work};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering, fence};
+use spin::Mutex;
+
+const VENDOR_ID: u16 = 0x8086;
+/// 82540EM (QEMU's `e1000`), 82545EM and 82574L (QEMU's `e1000e`).
+const DEVICE_IDS: [u16; 3] = [0x100E, 0x100F, 0x10D3];
+
+const MAX_DEVICES: usize = 4;
+const REGISTERS_SIZE: usize = 128 * 1024;
+const RESET_TIMEOUT_NS: u64 = 100_000_000;
+/// Descriptors in each ring. Ring sizes must be a multiple of 128 bytes.
+const NUM_DESCRIPTORS: usize = 32;
+/// Matches the default receive buffer size in `RCTL`.
+const BUFFER_SIZE: usize = 2048;
+const RING_SIZE: usize = NUM_DESCRIPTORS * size_of::<ReceiveDescriptor>();
+
+const _: () = assert!(RING_SIZE.is_multiple_of(128));
+const _: () = assert!(2 * RING_SIZE <= PAGE_SIZE);
+const _: () = assert!(MAX_FRAME_LEN <= BUFFER_SIZE);
+
+mod register {
+    pub const CONTROL: usize = 0x0000;
+    pub const STATUS: usize = 0x0008;
+    pub const INTERRUPT_CAUSE: usize = 0x00C0;
+    pub const INTERRUPT_MASK_SET: usize = 0x00D0;
+    pub const INTERRUPT_MASK_CLEAR: usize = 0x00D8;
+    pub const RECEIVE_CONTROL: usize = 0x0100;
+    pub const TRANSMIT_CONTROL: usize = 0x0400;
+    pub const TRANSMIT_IPG: usize = 0x0410;
+    pub const RECEIVE_BASE_LOW: usize = 0x2800;
+    pub const RECEIVE_BASE_HIGH: usize = 0x2804;
+    pub const RECEIVE_LENGTH: usize = 0x2808;
+    pub const RECEIVE_HEAD: usize = 0x2810;
+    pub const RECEIVE_TAIL: usize = 0x2818;
+    pub const TRANSMIT_BASE_LOW: usize = 0x3800;
+    pub const TRANSMIT_BASE_HIGH: usize = 0x3804;
+    pub const TRANSMIT_LENGTH: usize = 0x3808;
+    pub const TRANSMIT_HEAD: usize = 0x3810;
+    pub const TRANSMIT_TAIL: usize = 0x3818;
+    /// 128 entries.
+    pub const MULTICAST_TABLE: usize = 0x5200;
+    pub const RECEIVE_ADDRESS_LOW: usize = 0x5400;
+    pub const RECEIVE_ADDRESS_HIGH: usize = 0x5404;
+
+    pub const CONTROL_AUTO_SPEED: u32 = 1 << 5;
+    pub const CONTROL_SET_LINK_UP: u32 = 1 << 6;
+    pub const CONTROL_RESET: u32 = 1 << 26;
+    pub const CONTROL_PHY_RESET: u32 = 1 << 31;
+
+    pub const STATUS_LINK_UP: u32 = 1 << 1;
+    pub const STATUS_SPEED_SHIFT: u32 = 6;
+
+    pub const RECEIVE_ADDRESS_VALID: u32 = 1 << 31;
+
+    pub const RECEIVE_ENABLE: u32 = 1 << 1;
+    pub const RECEIVE_BROADCAST: u32 = 1 << 15;
+    pub const RECEIVE_STRIP_CRC: u32 = 1 << 26;
+
+    pub const TRANSMIT_ENABLE: u32 = 1 << 1;
+    pub const TRANSMIT_PAD_SHORT: u32 = 1 << 3;
+    pub const TRANSMIT_COLLISION_THRESHOLD: u32 = 0x10 << 4;
+    pub const TRANSMIT_COLLISION_DISTANCE: u32 = 0x40 << 12;
+    /// Recommended inter packet gap for copper.
+    pub const TRANSMIT_IPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+}
+
+mod cause {
+    pub const LINK_STATUS_CHANGE: u32 = 1 << 2;
+    pub const RECEIVE_MIN_THRESHOLD: u32 = 1 << 4;
+    pub const RECEIVE_OVERRUN: u32 = 1 << 6;
+    pub const RECEIVE_TIMER: u32 = 1 << 7;
+
+    pub const RECEIVE: u32 = RECEIVE_MIN_THRESHOLD | RECEIVE_OVERRUN | RECEIVE_TIMER;
+}
+
+const DESCRIPTOR_DONE: u8 = 1 << 0;
+const RECEIVE_END_OF_PACKET: u8 = 1 << 1;
+const TRANSMIT_END_OF_PACKET: u8 = 1 << 0;
+const TRANSMIT_INSERT_CRC: u8 = 1 << 1;
+const TRANSMIT_REPORT_STATUS: u8 = 1 << 3;
+
+static DEVICES: Mutex<Vec<Arc<E1000>>> = Mutex::new(Vec::new());
+/// Interrupt causes not yet handled, for each device by index in `DEVICES`.
+static PENDING_CAUSES: [AtomicU32; MAX_DEVICES] = [const { AtomicU32::new(0) }; MAX_DEVICES];
+/// Interrupt work for each device, by index in `DEVICES`.
+static INTERRUPT_WORKS: [Work; MAX_DEVICES] = {
+    let mut works = [const { Work::new(interrupt_work, 0) }; MAX_DEVICES];
+    let mut i = 0;
+    while i < MAX_DEVICES {
+        works[i] = Work::new(interrupt_work, i);
+        i += 1;
+    }
+    works
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum E1000Error {
+    #[error("registers aren't in a memory BAR")]
+    NoMemoryBar,
+    #[error("no MAC address programmed")]
+    NoMacAddress,
+    #[error("timed out resetting the controller")]
+    ResetTimeout,
+    #[error("no interrupt route")]
+    NoInterruptRoute,
+    #[error("out of memory")]
+    OutOfMemory,
+    #[error("too many devices")]
+    TooManyDevices,
+    #[error("failed to set up interrupts - {0}")]
+    Msi(#[from] MsiError),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct ReceiveDescriptor {
+    address: u64,
+    len: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+/// Legacy transmit descriptor.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct TransmitDescriptor {
+    address: u64,
+    len: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+const _: () = assert!(size_of::<ReceiveDescriptor>() == size_of::<TransmitDescriptor>());
+
+#[derive(Clone, Copy, Debug)]
+struct Registers {
+    base: usize,
+}
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+/// A descriptor ring in identity mapped memory, with a buffer for each descriptor.
+struct Ring<T> {
+    descriptors: *mut T,
+    /// Physical address of the buffers.
+    buffers: usize,
+    /// Next descriptor for the driver to look at.
+    next: usize,
+}
+
+// The ring's memory is only reachable through this
+unsafe impl<T> Send for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn buffer(&self, index: usize) -> usize {
+        self.buffers + index * BUFFER_SIZE
+    }
+
+    fn descriptor(&self, index: usize) -> *mut T {
+        unsafe { self.descriptors.add(index) }
+    }
+}
+
+/// Returns the link speed encoded in the status register, in Mb/s.
+fn link_speed(status: u32) -> u32 {
+    match (status >> register::STATUS_SPEED_SHIFT) & 0x3 {
+        0 => 10,
+        1 => 100,
+        _ => 1000,
+    }
+}
+
+pub struct E1000 {
+    registers: Registers,
+    /// Set once the device is registered.
+    name: OnceLock<&'static str>,
+    mac_address: MacAddress,
+    receive_ring: Mutex<Ring<ReceiveDescriptor>>,
+    transmit_ring: Mutex<Ring<TransmitDescriptor>>,
+    receive_callback: Mutex<Option<ReceiveCallback>>,
+    _vector: AllocatedVector,
+}
+
+impl E1000 {
+    fn transmit_locked(
+        &self,
+        ring: &mut Ring<TransmitDescriptor>,
+        frame: &[u8],
+    ) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(NetError::FrameTooLong);
+        }
+        let index = ring.next;
+        let descriptor = ring.descriptor(index);
+        // Unused descriptors start out done, so this only fails while the ring is full
+        if unsafe { descriptor.read_volatile() }.status & DESCRIPTOR_DONE == 0 {
+            return Err(NetError::QueueFull);
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                ring.buffer(index) as *mut u8,
+                frame.len(),
+            );
+            descriptor.write_volatile(TransmitDescriptor {
+                address: ring.buffer(index) as u64,
+                len: frame.len() as u16,
+                command: TRANSMIT_END_OF_PACKET | TRANSMIT_INSERT_CRC | TRANSMIT_REPORT_STATUS,
+                ..Default::default()
+            });
+        }
+        ring.next = (index + 1) % NUM_DESCRIPTORS;
+        fence(Ordering::SeqCst);
+        self.registers
+            .write(register::TRANSMIT_TAIL, ring.next as u32);
+        Ok(())
+    }
+
+    /// Passes every received frame to the receive callback, then gives the descriptors back.
+    fn receive_pending(&self) {
+        let mut frame = [0; MAX_FRAME_LEN];
+        loop {
+            let frame_len = {
+                let mut ring = self.receive_ring.lock();
+                let index = ring.next;
+                let descriptor = unsafe { ring.descriptor(index).read_volatile() };
+                if descriptor.status & DESCRIPTOR_DONE == 0 {
+                    break;
+                }
+                fence(Ordering::SeqCst);
+                // Frames are never split, as every buffer fits the longest one, so anything else
+                // is a runt or an error and is dropped
+                let frame_len = match descriptor.status & RECEIVE_END_OF_PACKET != 0
+                    && descriptor.errors == 0
+                {
+                    true => (descriptor.len as usize).min(MAX_FRAME_LEN),
+                    false => 0,
+                };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        ring.buffer(index) as *const u8,
+                        frame.as_mut_ptr(),
+                        frame_len,
+                    );
+                    ring.descriptor(index).write_volatile(ReceiveDescriptor {
+                        address: ring.buffer(index) as u64,
+                        ..Default::default()
+                    });
+                }
+                fence(Ordering::SeqCst);
+                self.registers.write(register::RECEIVE_TAIL, index as u32);
+                ring.next = (index + 1) % NUM_DESCRIPTORS;
+                frame_len
+            };
+            if frame_len == 0 {
+                continue;
+            }
+            // Called without locks held, in case it transmits a reply
+            let callback = self.receive_callback.lock().clone();
+            if let Some(callback) = callback {
+                callback(&frame[..frame_len]);
+            }
+        }
+    }
+
+    fn log_link_status(&self) {
+        let name = self.name.get().copied().unwrap_or("e1000");
+        let status = self.registers.read(register::STATUS);
+        match status & register::STATUS_LINK_UP != 0 {
+            true => log::info!("{name}: link up at {} Mb/s", link_speed(status)),
+            false => log::info!("{name}: link down"),
+        }
+    }
+}
+
+impl NetDevice for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac_address
+    }
+
+    fn link_up(&self) -> bool {
+        self.registers.read(register::STATUS) & register::STATUS_LINK_UP != 0
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.transmit_locked(&mut self.transmit_ring.lock(), frame)
+    }
+
+    fn try_transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        let mut ring = self.transmit_ring.try_lock().ok_or(NetError::Busy)?;
+        self.transmit_locked(&mut ring, frame)
+    }
+
+    fn set_receive_callback(&self, callback: ReceiveCallback) {
+        *self.receive_callback.lock() = Some(callback);
+    }
+}
+
+fn interrupt_work(index: usize) {
+    let causes = PENDING_CAUSES[index].swap(0, Ordering::AcqRel);
+    let device = DEVICES.lock().get(index).cloned();
+    let Some(device) = device else {
+        return;
+    };
+    if causes & cause::LINK_STATUS_CHANGE != 0 {
+        device.log_link_status();
+    }
+    if causes & cause::RECEIVE != 0 {
+        device.receive_pending();
+    }
+}
+
+/// Routes the controller's interrupt to a new vector, through MSI if it's supported or INTx.
+unsafe fn setup_interrupt(
+    address: PciAddress,
+    registers: Registers,
+    index: usize,
+) -> Result<AllocatedVector, E1000Error> {
+    let vector = msi::allocate_vector(move || {
+        // Reading clears the causes, which also deasserts INTx
+        let causes = registers.read(register::INTERRUPT_CAUSE);
+        if causes != 0 {
+            PENDING_CAUSES[index].fetch_or(causes, Ordering::AcqRel);
+            work_queue::schedule(&INTERRUPT_WORKS[index]);
+        }
+    })?;
+    match unsafe { msi::enable_msi(address, &vector) } {
+        Ok(()) => return Ok(vector),
+        Err(MsiError::NoMsiCapability) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let pin = pci::read_byte(address, pci::REGISTER_INTERRUPT_PIN);
+    let route = pin
+        .checked_sub(1)
+        .and_then(|pin| routing::pci_interrupt_route(address.bus, address.device, pin))
+        .ok_or(E1000Error::NoInterruptRoute)?;
+    unsafe {
+        routing::route_gsi(route, vector.vector(), apic::current_local_apic_id())
+            .map_err(|_| E1000Error::NoInterruptRoute)?;
+    }
+    Ok(vector)
+}
+
+/// Resets and sets up the controller at `address`, which will be at `index` in `DEVICES`.
+unsafe fn init_device(address: PciAddress, index: usize) -> Result<E1000, E1000Error> {
+    use register::*;
+    if index >= MAX_DEVICES {
+        return Err(E1000Error::TooManyDevices);
+    }
+    let base = pci::memory_bar_address(address, 0).ok_or(E1000Error::NoMemoryBar)? as usize;
+    for page in (base..base + REGISTERS_SIZE).step_by(PAGE_SIZE) {
+        unsafe {
+            if !page_allocation::is_address_identity_mapped(page) {
+                page_allocation::map_page_translation(page, page, PageTableEntry::READ_WRITE)
+                    .map_err(|_| E1000Error::OutOfMemory)?;
+            }
+        }
+    }
+    let command = pci::read_word(address, pci::REGISTER_COMMAND);
+    unsafe {
+        pci::write_word(
+            address,
+            pci::REGISTER_COMMAND,
+            command | pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER,
+        );
+    }
+    let registers = Registers { base };
+
+    registers.write(INTERRUPT_MASK_CLEAR, u32::MAX);
+    registers.write(CONTROL, registers.read(CONTROL) | CONTROL_RESET);
+    let deadline = clock::now_ns() + RESET_TIMEOUT_NS;
+    while registers.read(CONTROL) & CONTROL_RESET != 0 {
+        if clock::now_ns() >= deadline {
+            return Err(E1000Error::ResetTimeout);
+        }
+        core::hint::spin_loop();
+    }
+    registers.write(INTERRUPT_MASK_CLEAR, u32::MAX);
+    registers.read(INTERRUPT_CAUSE);
+    let control = registers.read(CONTROL);
+    registers.write(
+        CONTROL,
+        (control | CONTROL_SET_LINK_UP | CONTROL_AUTO_SPEED) & !CONTROL_PHY_RESET,
+    );
+
+    // Loaded from the EEPROM on reset
+    let address_high = registers.read(RECEIVE_ADDRESS_HIGH);
+    if address_high & RECEIVE_ADDRESS_VALID == 0 {
+        return Err(E1000Error::NoMacAddress);
+    }
+    let [a, b, c, d] = registers.read(RECEIVE_ADDRESS_LOW).to_le_bytes();
+    let [e, f, ..] = address_high.to_le_bytes();
+    let mac_address = MacAddress([a, b, c, d, e, f]);
+    for entry in 0..128 {
+        registers.write(MULTICAST_TABLE + entry * 4, 0);
+    }
+
+    // Both rings on the first page, then every buffer. The device is never removed, so neither
+    // is its memory.
+    let num_pages = 1 + (2 * NUM_DESCRIPTORS * BUFFER_SIZE).div_ceil(PAGE_SIZE);
+    let memory = page_allocation::find_and_reserve_contiguous(num_pages)
+        .map_err(|_| E1000Error::OutOfMemory)?;
+    let receive_ring = Ring {
+        descriptors: memory as *mut ReceiveDescriptor,
+        buffers: memory + PAGE_SIZE,
+        next: 0,
+    };
+    let transmit_ring = Ring {
+        descriptors: (memory + RING_SIZE) as *mut TransmitDescriptor,
+        buffers: memory + PAGE_SIZE + NUM_DESCRIPTORS * BUFFER_SIZE,
+        next: 0,
+    };
+    for index in 0..NUM_DESCRIPTORS {
+        unsafe {
+            receive_ring
+                .descriptor(index)
+                .write_volatile(ReceiveDescriptor {
+                    address: receive_ring.buffer(index) as u64,
+                    ..Default::default()
+                });
+            transmit_ring
+                .descriptor(index)
+                .write_volatile(TransmitDescriptor {
+                    status: DESCRIPTOR_DONE,
+                    ..Default::default()
+                });
+        }
+    }
+    for (base_low, base_high, length, head, ring_address) in [
+        (
+            RECEIVE_BASE_LOW,
+            RECEIVE_BASE_HIGH,
+            RECEIVE_LENGTH,
+            RECEIVE_HEAD,
+            memory,
+        ),
+        (
+            TRANSMIT_BASE_LOW,
+            TRANSMIT_BASE_HIGH,
+            TRANSMIT_LENGTH,
+            TRANSMIT_HEAD,
+            memory + RING_SIZE,
+        ),
+    ] {
+        registers.write(base_low, ring_address as u32);
+        registers.write(base_high, (ring_address as u64 >> 32) as u32);
+        registers.write(length, RING_SIZE as u32);
+        registers.write(head, 0);
+    }
+    registers.write(TRANSMIT_TAIL, 0);
+    registers.write(RECEIVE_TAIL, (NUM_DESCRIPTORS - 1) as u32);
+
+    let vector = unsafe { setup_interrupt(address, registers, index)? };
+    registers.write(
+        RECEIVE_CONTROL,
+        RECEIVE_ENABLE | RECEIVE_BROADCAST | RECEIVE_STRIP_CRC,
+    );
+    registers.write(TRANSMIT_IPG, TRANSMIT_IPG_COPPER);
+    registers.write(
+        TRANSMIT_CONTROL,
+        TRANSMIT_ENABLE
+            | TRANSMIT_PAD_SHORT
+            | TRANSMIT_COLLISION_THRESHOLD
+            | TRANSMIT_COLLISION_DISTANCE,
+    );
+    registers.write(
+        INTERRUPT_MASK_SET,
+        cause::RECEIVE | cause::LINK_STATUS_CHANGE,
+    );
+    Ok(E1000 {
+        registers,
+        name: OnceLock::new(),
+        mac_address,
+        receive_ring: Mutex::new(receive_ring),
+        transmit_ring: Mutex::new(transmit_ring),
+        receive_callback: Mutex::new(None),
+        _vector: vector,
+    })
+}
+
+/// Sets up every supported Intel network controller on PCI, and registers them as network
+/// devices. Must be called after interrupts and the work queue are set up.
+pub fn init() {
+    let mut addresses = Vec::new();
+    pci::for_each_function(|address| {
+        if pci::read_word(address, pci::REGISTER_VENDOR_ID) == VENDOR_ID
+            && DEVICE_IDS.contains(&pci::read_word(address, pci::REGISTER_DEVICE_ID))
+        {
+            addresses.push(address);
+        }
+    });
+    for address in addresses {
+        let mut devices = DEVICES.lock();
+        let device = match unsafe { init_device(address, devices.len()) } {
+            Ok(device) => Arc::new(device),
+            Err(err) => {
+                log::warn!("Failed to set up e1000 network device at {address} - {err}");
+                continue;
+            }
+        };
+        devices.push(device.clone());
+        drop(devices);
+        _ = device.name.set(super::register(device.clone()));
+        device.log_link_status();
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn decodes_link_speed() -> TestResult {
+        ktest_assert_eq!(link_speed(register::STATUS_LINK_UP), 10);
+        ktest_assert_eq!(link_speed(1 << 6 | register::STATUS_LINK_UP), 100);
+        ktest_assert_eq!(link_speed(2 << 6), 1000);
+        // Both high bits mean gigabit too
+        ktest_assert_eq!(link_speed(3 << 6), 1000);
+        Ok(())
+    }
+}
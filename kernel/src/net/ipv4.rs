@@ -0,0 +1,356 @@
+//! IPv4 over Ethernet, on a single interface.
+//!
+//! The interface is set up at boot by `ip=<address>/<prefix length>` on the first network device,
+//! with `gateway=<address>` giving the router for destinations outside its subnet. Link addresses
+//! are found with ARP. Packets to a next hop whose link address isn't known yet are dropped after
+//! sending an ARP request, which leaves it to TCP to send them again. Fragmented packets are
+//! dropped, and IP options are ignored.
+//!
+//! `try_send_in_place` sends without allocating or waiting for locks, for code which may have
+//! interrupted any other, like NMI handlers. It builds headers in front of a payload already in
+//! the frame, and broadcasts rather than sending ARP requests.
+
+use super::{MAX_FRAME_LEN, MacAddress, NetDevice, NetError};
+use crate::cmdline;
+use crate::net::tcp;
+use crate::sync::OnceLock;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use spin::Mutex;
+
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const HEADER_LEN: usize = 20;
+/// Longest payload sent in one packet.
+pub const MAX_PAYLOAD_LEN: usize = MAX_FRAME_LEN - ETHERNET_HEADER_LEN - HEADER_LEN;
+/// Where the payload starts in frames passed to `try_send_in_place`.
+pub const PAYLOAD_OFFSET: usize = ETHERNET_HEADER_LEN + HEADER_LEN;
+const TIME_TO_LIVE: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET: u16 = 0x1FFF;
+
+const ARP_PACKET_LEN: usize = 28;
+const ARP_HARDWARE_ETHERNET: u16 = 1;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+
+static INTERFACE: OnceLock<Interface> = OnceLock::new();
+static ARP_CACHE: Mutex<BTreeMap<Ipv4Address, MacAddress>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    /// Converts from a `u32` with the first byte of the address most significant, as user code
+    /// passes addresses.
+    pub fn from_u32(value: u32) -> Self {
+        Self(value.to_be_bytes())
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+impl FromStr for Ipv4Address {
+    type Err = ();
+
+    /// Parses dotted decimal notation.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut bytes = [0; 4];
+        let mut parts = s.split('.');
+        for byte in &mut bytes {
+            *byte = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+        match parts.next() {
+            Some(_) => Err(()),
+            None => Ok(Self(bytes)),
+        }
+    }
+}
+
+struct Interface {
+    device: Arc<dyn NetDevice>,
+    address: Ipv4Address,
+    prefix_len: u8,
+    gateway: Option<Ipv4Address>,
+}
+
+impl Interface {
+    /// Returns the address of the host packets to `destination` are sent to.
+    fn next_hop(&self, destination: Ipv4Address) -> Result<Ipv4Address, NetError> {
+        let mask = u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0);
+        match destination.to_u32() & mask == self.address.to_u32() & mask {
+            true => Ok(destination),
+            false => self.gateway.ok_or(NetError::NoRoute),
+        }
+    }
+
+    fn send_frame(&self, destination: MacAddress, ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&destination.0);
+        frame.extend_from_slice(&self.device.mac_address().0);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        // Lost frames are no different to ones lost on the wire
+        if let Err(err) = self.device.transmit(&frame) {
+            log::debug!("Dropped outgoing frame - {err}");
+        }
+    }
+
+    fn send_arp(&self, operation: u16, target_mac: MacAddress, target_address: Ipv4Address) {
+        let mut packet = Vec::with_capacity(ARP_PACKET_LEN);
+        packet.extend_from_slice(&ARP_HARDWARE_ETHERNET.to_be_bytes());
+        packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        packet.extend_from_slice(&[6, 4]);
+        packet.extend_from_slice(&operation.to_be_bytes());
+        packet.extend_from_slice(&self.device.mac_address().0);
+        packet.extend_from_slice(&self.address.0);
+        packet.extend_from_slice(&target_mac.0);
+        packet.extend_from_slice(&target_address.0);
+        let destination = match operation {
+            ARP_REQUEST => BROADCAST,
+            _ => target_mac,
+        };
+        self.send_frame(destination, ETHERTYPE_ARP, &packet);
+    }
+
+    fn receive_arp(&self, packet: &[u8]) {
+        if packet.len() < ARP_PACKET_LEN
+            || packet[0..2] != ARP_HARDWARE_ETHERNET.to_be_bytes()
+            || packet[2..4] != ETHERTYPE_IPV4.to_be_bytes()
+        {
+            return;
+        }
+        let operation = u16::from_be_bytes([packet[6], packet[7]]);
+        let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+        let sender_address = Ipv4Address(packet[14..18].try_into().unwrap());
+        let target_address = Ipv4Address(packet[24..28].try_into().unwrap());
+        if target_address != self.address {
+            return;
+        }
+        ARP_CACHE.lock().insert(sender_address, sender_mac);
+        if operation == ARP_REQUEST {
+            self.send_arp(ARP_REPLY, sender_mac, sender_address);
+        }
+    }
+
+    fn receive_ipv4(&self, packet: &[u8]) {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+            return;
+        }
+        let header_len = (packet[0] & 0xF) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_LEN
+            || total_len < header_len
+            || total_len > packet.len()
+            || checksum(&packet[..header_len], 0) != 0
+        {
+            return;
+        }
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+            return;
+        }
+        let source = Ipv4Address(packet[12..16].try_into().unwrap());
+        let destination = Ipv4Address(packet[16..20].try_into().unwrap());
+        if destination != self.address {
+            return;
+        }
+        let payload = &packet[header_len..total_len];
+        if packet[9] == PROTOCOL_TCP {
+            tcp::receive(source, destination, payload);
+        }
+    }
+}
+
+/// Adds `data` to a running one's complement sum of 16 bit words.
+pub fn add_to_checksum(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Returns the internet checksum of `data`, continuing from the running sum `sum`. Checking data
+/// including its checksum gives 0 if it's correct.
+pub fn checksum(data: &[u8], sum: u32) -> u16 {
+    let mut sum = add_to_checksum(data, sum);
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the running sum of the pseudo header covered by TCP and UDP checksums.
+pub fn pseudo_header_sum(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    len: usize,
+) -> u32 {
+    let sum = add_to_checksum(&source.0, 0);
+    let sum = add_to_checksum(&destination.0, sum);
+    sum + protocol as u32 + len as u32
+}
+
+/// Returns the interface's address, or `None` if there's no interface.
+pub fn local_address() -> Option<Ipv4Address> {
+    INTERFACE.get().map(|interface| interface.address)
+}
+
+/// Sends `payload` to `destination` with the protocol number `protocol`.
+pub fn send(destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let interface = INTERFACE.get().ok_or(NetError::NotConfigured)?;
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(NetError::FrameTooLong);
+    }
+    let next_hop = interface.next_hop(destination)?;
+    let Some(destination_mac) = ARP_CACHE.lock().get(&next_hop).copied() else {
+        interface.send_arp(ARP_REQUEST, MacAddress([0; 6]), next_hop);
+        return Ok(());
+    };
+    let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+    packet.push(0x40 | (HEADER_LEN / 4) as u8);
+    packet.push(0);
+    packet.extend_from_slice(&((HEADER_LEN + payload.len()) as u16).to_be_bytes());
+    // Identification only matters for fragments, which are never sent
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[TIME_TO_LIVE, protocol, 0, 0]);
+    packet.extend_from_slice(&interface.address.0);
+    packet.extend_from_slice(&destination.0);
+    let header_checksum = checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    interface.send_frame(destination_mac, ETHERTYPE_IPV4, &packet);
+    Ok(())
+}
+
+/// Sends `frame[PAYLOAD_OFFSET..]` to `destination` with the protocol number `protocol`, filling
+/// in the headers before it. Never allocates or waits for a lock, failing with `NetError::Busy`
+/// if the device is locked. Frames to a next hop whose link address isn't known, or can't be
+/// looked up without waiting, are broadcast.
+pub fn try_send_in_place(
+    destination: Ipv4Address,
+    protocol: u8,
+    frame: &mut [u8],
+) -> Result<(), NetError> {
+    let interface = INTERFACE.get().ok_or(NetError::NotConfigured)?;
+    let payload_len = frame.len() - PAYLOAD_OFFSET;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(NetError::FrameTooLong);
+    }
+    let next_hop = interface.next_hop(destination)?;
+    let destination_mac = ARP_CACHE
+        .try_lock()
+        .and_then(|cache| cache.get(&next_hop).copied())
+        .unwrap_or(BROADCAST);
+    frame[0..6].copy_from_slice(&destination_mac.0);
+    frame[6..12].copy_from_slice(&interface.device.mac_address().0);
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    let header = &mut frame[ETHERNET_HEADER_LEN..PAYLOAD_OFFSET];
+    header[0] = 0x40 | (HEADER_LEN / 4) as u8;
+    header[1] = 0;
+    header[2..4].copy_from_slice(&((HEADER_LEN + payload_len) as u16).to_be_bytes());
+    header[4..6].fill(0);
+    header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    header[8..12].copy_from_slice(&[TIME_TO_LIVE, protocol, 0, 0]);
+    header[12..16].copy_from_slice(&interface.address.0);
+    header[16..20].copy_from_slice(&destination.0);
+    let header_checksum = checksum(header, 0);
+    header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    interface.device.try_transmit(frame)
+}
+
+fn receive_frame(frame: &[u8]) {
+    let Some(interface) = INTERFACE.get() else {
+        return;
+    };
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => interface.receive_arp(payload),
+        ETHERTYPE_IPV4 => interface.receive_ipv4(payload),
+        _ => {}
+    }
+}
+
+/// Sets up the interface given on the command line, if any. Must be called after network
+/// devices are registered.
+pub fn init() {
+    let config = cmdline::get();
+    let Some((address, prefix_len)) = config.ip_address else {
+        return;
+    };
+    let Some(device) = super::get("eth0") else {
+        log::warn!("No network device for IP address {address}");
+        return;
+    };
+    device.set_receive_callback(Arc::new(receive_frame));
+    let interface = Interface {
+        device,
+        address,
+        prefix_len,
+        gateway: config.gateway,
+    };
+    if INTERFACE.set(interface).is_ok() {
+        log::info!("eth0: IP address {address}/{prefix_len}");
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn computes_checksums() -> TestResult {
+        // Example from RFC 1071
+        let data = [0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7];
+        ktest_assert_eq!(checksum(&data, 0), !0xDDF2);
+        let mut with_checksum = data.to_vec();
+        with_checksum.extend_from_slice(&checksum(&data, 0).to_be_bytes());
+        ktest_assert_eq!(checksum(&with_checksum, 0), 0);
+        // Odd lengths are padded with a zero byte
+        ktest_assert_eq!(checksum(&[0x12], 0), checksum(&[0x12, 0x00], 0));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn parses_addresses() -> TestResult {
+        ktest_assert_eq!("10.0.2.15".parse(), Ok(Ipv4Address([10, 0, 2, 15])));
+        ktest_assert_eq!("10.0.2".parse::<Ipv4Address>(), Err(()));
+        ktest_assert_eq!("10.0.2.256".parse::<Ipv4Address>(), Err(()));
+        ktest_assert_eq!("1.2.3.4.5".parse::<Ipv4Address>(), Err(()));
+        ktest_assert_eq!(
+            Ipv4Address::from_u32(0x0A00_020F),
+            Ipv4Address([10, 0, 2, 15])
+        );
+        Ok(())
+    }
+}
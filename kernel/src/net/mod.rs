@@ -0,0 +1,92 @@
+//! Network devices, which send and receive Ethernet frames.
+//!
+//! Drivers register each device they find, which names it `eth<n>` in registration order.
+//! Whatever handles a device's traffic sets its receive callback, which drivers call from the
+//! kernel work queue rather than from their interrupt handlers.
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+pub mod console;
+pub mod e1000;
+pub mod ipv4;
+pub mod netdump;
+pub mod tcp;
+pub mod udp;
+pub mod virtio_net;
+
+/// Longest Ethernet frame sent or received, not counting the frame check sequence.
+pub const MAX_FRAME_LEN: usize = 1514;
+
+static DEVICES: Mutex<Vec<(&'static str, Arc<dyn NetDevice>)>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum NetError {
+    #[error("frame too long")]
+    FrameTooLong,
+    #[error("transmit queue full")]
+    QueueFull,
+    #[error("no network interface configured")]
+    NotConfigured,
+    #[error("no route to host")]
+    NoRoute,
+    #[error("locked by the interrupted code")]
+    Busy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// Called with each frame a device receives.
+pub type ReceiveCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+pub trait NetDevice: Send + Sync {
+    fn mac_address(&self) -> MacAddress;
+
+    /// Whether the link is up. Devices which can't tell always report it as up.
+    fn link_up(&self) -> bool {
+        true
+    }
+
+    /// Queues `frame` to be sent, which must include the Ethernet header.
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Like `transmit`, but fails with `NetError::Busy` rather than waiting if the device is
+    /// locked, for NMI handlers.
+    fn try_transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Sets the function received frames are passed to, replacing any previous one. Frames
+    /// received without a callback are dropped.
+    fn set_receive_callback(&self, callback: ReceiveCallback);
+}
+
+/// Makes `device` available, returning the name it was given.
+pub fn register(device: Arc<dyn NetDevice>) -> &'static str {
+    let mut devices = DEVICES.lock();
+    // Devices are never removed
+    let name = format!("eth{}", devices.len()).leak();
+    log::info!(
+        "Network device {name}: MAC address {}",
+        device.mac_address()
+    );
+    devices.push((name, device));
+    name
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn NetDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(existing, _)| *existing == name)
+        .map(|(_, device)| device.clone())
+}
@@ -0,0 +1,113 @@
+//! Sending dumps of kernel state over UDP, for machines whose debug output nobody can read.
+//!
+//! Booting with `netdump=<address>:<port>` sends the NMI watchdog's dump on a hard lockup to
+//! that host as well as the debug output. Dumps are plain text, split across as many datagrams
+//! as they need, from port `SOURCE_PORT`, so something like `nc -ul <port>` can receive them.
+//!
+//! Dumps are sent from NMI handlers, which may have interrupted code holding any lock, so
+//! nothing here allocates or waits for a lock. A dump is given up on if the network device is
+//! locked by the interrupted code, or its transmit queue stays full for `SEND_TIMEOUT_NS`.
+
+use super::ipv4::Ipv4Address;
+use super::{MAX_FRAME_LEN, NetError, udp};
+use crate::arch::clock;
+use crate::cmdline;
+use crate::sync::OnceLock;
+use core::fmt;
+use spin::Mutex;
+
+const SOURCE_PORT: u16 = 6665;
+/// How long to wait for space in the transmit queue for each datagram.
+const SEND_TIMEOUT_NS: u64 = 100_000_000;
+
+static TARGET: OnceLock<(Ipv4Address, u16)> = OnceLock::new();
+/// Frame datagrams are built in, kept off the NMI stack.
+static FRAME: Mutex<[u8; MAX_FRAME_LEN]> = Mutex::new([0; MAX_FRAME_LEN]);
+
+/// Fills datagrams with what's written to it, sending each one once it's full.
+struct DatagramWriter<'a> {
+    frame: &'a mut [u8; MAX_FRAME_LEN],
+    len: usize,
+    address: Ipv4Address,
+    port: u16,
+    result: Result<(), NetError>,
+}
+
+impl DatagramWriter<'_> {
+    fn flush(&mut self) -> Result<(), NetError> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let frame = &mut self.frame[..udp::PAYLOAD_OFFSET + self.len];
+        let start_ns = clock::try_now_ns();
+        loop {
+            match udp::try_send_in_place(SOURCE_PORT, self.address, self.port, frame) {
+                Err(NetError::QueueFull)
+                    if clock::try_now_ns()
+                        .zip(start_ns)
+                        .is_some_and(|(now_ns, start_ns)| now_ns - start_ns < SEND_TIMEOUT_NS) =>
+                {
+                    core::hint::spin_loop();
+                }
+                result => {
+                    self.len = 0;
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Write for DatagramWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Characters may be split across datagrams, which are put back together in order
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let start = udp::PAYLOAD_OFFSET + self.len;
+            let chunk_len = bytes.len().min(udp::MAX_PAYLOAD_LEN - self.len);
+            self.frame[start..start + chunk_len].copy_from_slice(&bytes[..chunk_len]);
+            self.len += chunk_len;
+            bytes = &bytes[chunk_len..];
+            if self.len == udp::MAX_PAYLOAD_LEN {
+                self.flush().map_err(|err| {
+                    self.result = Err(err);
+                    fmt::Error
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends what `f` writes to the host given on the command line. Never allocates or waits for a
+/// lock.
+pub fn send(f: impl FnOnce(&mut dyn fmt::Write) -> fmt::Result) -> Result<(), NetError> {
+    let &(address, port) = TARGET.get().ok_or(NetError::NotConfigured)?;
+    let mut frame = FRAME.try_lock().ok_or(NetError::Busy)?;
+    let mut writer = DatagramWriter {
+        frame: &mut frame,
+        len: 0,
+        address,
+        port,
+        result: Ok(()),
+    };
+    // Only the writer fails, and it keeps its own error
+    _ = f(&mut writer);
+    writer.result?;
+    writer.flush()
+}
+
+/// Sets up dumps to the host given on the command line, if any. Must be called after
+/// `super::ipv4::init`.
+pub fn init() {
+    let Some((address, port)) = cmdline::get().netdump else {
+        return;
+    };
+    if super::ipv4::local_address().is_none() {
+        log::warn!("No IP address to send network dumps from");
+        return;
+    }
+    if TARGET.set((address, port)).is_ok() {
+        log::info!("Network dumps go to {address}:{port}");
+    }
+}
@@ -0,0 +1,1361 @@
+//! TCP, and the socket system calls user code reaches it through.
+//!
+//! Each socket is referred to by a handle in its process's socket table, which closes the sockets
+//! left open when the process exits. Kernel threads, like `super::console`'s, use `Socket`
+//! directly instead. Sockets are either unbound, bound to a local port,
+//! listening for connections on its port, or connected. Connections follow the RFC 793 state
+//! machine, with only in order segments accepted, so anything arriving early is dropped and
+//! acknowledged again to ask the peer to resend. Unacknowledged data is sent again go-back-N style
+//! when the retransmission timer expires, doubling the timeout each time until the connection is
+//! given up on. The receive window is whatever's left of a fixed size receive buffer, and is
+//! reopened with an acknowledgement as user code reads from it.
+//!
+//! Segments are handled on the kernel work queue, as are expired timers, so connections are only
+//! ever locked by threads. Sockets are always locked before `TCP`, never while holding it.
+
+use super::NetError;
+use super::ipv4::{self, Ipv4Address, PROTOCOL_TCP};
+use crate::arch::clock;
+use crate::arch::syscall::SyscallError;
+use crate::kthread;
+use crate::process::Process;
+use crate::timer::{self, TimerId};
+use crate::user_memory::{self, UserArgs};
+use crate::wait_queue::WaitQueue;
+use crate::work_queue::{self, Work};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const HEADER_LEN: usize = 20;
+/// Largest segment payload received, as advertised to peers.
+const MSS: usize = ipv4::MAX_PAYLOAD_LEN - HEADER_LEN;
+/// Largest segment payload sent to peers which don't give their own.
+const DEFAULT_PEER_MSS: usize = 536;
+const RECEIVE_BUFFER_LEN: usize = 16 * 1024;
+const SEND_BUFFER_LEN: usize = 16 * 1024;
+const INITIAL_RTO_NS: u64 = 1_000_000_000;
+const MAX_RTO_NS: u64 = 60_000_000_000;
+/// Retransmissions of the same data before the connection times out.
+const MAX_RETRANSMITS: u32 = 8;
+/// How long closed connections linger to soak up the peer's retransmissions, twice the maximum
+/// segment lifetime.
+const TIME_WAIT_NS: u64 = 60_000_000_000;
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+const FLAG_FIN: u8 = 1 << 0;
+const FLAG_SYN: u8 = 1 << 1;
+const FLAG_RST: u8 = 1 << 2;
+const FLAG_PSH: u8 = 1 << 3;
+const FLAG_ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+static TCP: Mutex<Tcp> = Mutex::new(Tcp {
+    listeners: BTreeMap::new(),
+    connections: BTreeMap::new(),
+    ports: BTreeSet::new(),
+    next_ephemeral_port: FIRST_EPHEMERAL_PORT,
+});
+static TIMER_WORK: Work = Work::new(run_timers, 0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SocketError {
+    #[error("no such socket")]
+    NotFound,
+    #[error("socket is in the wrong state for the operation")]
+    InvalidState,
+    #[error("address in use")]
+    AddressInUse,
+    #[error("connection refused")]
+    ConnectionRefused,
+    #[error("connection reset")]
+    ConnectionReset,
+    #[error("connection timed out")]
+    TimedOut,
+    #[error("connection closed for sending")]
+    Closed,
+    #[error("network error - {0}")]
+    Net(#[from] NetError),
+}
+
+impl From<SocketError> for SyscallError {
+    fn from(err: SocketError) -> Self {
+        match err {
+            SocketError::AddressInUse => SyscallError::ADDRESS_IN_USE,
+            SocketError::ConnectionRefused => SyscallError::CONNECTION_REFUSED,
+            SocketError::ConnectionReset => SyscallError::CONNECTION_RESET,
+            SocketError::TimedOut => SyscallError::TIMED_OUT,
+            SocketError::Closed => SyscallError::PEER_CLOSED,
+            SocketError::Net(NetError::NotConfigured | NetError::NoRoute) => {
+                SyscallError::NETWORK_UNREACHABLE
+            }
+            _ => SyscallError::INVALID_ARGUMENT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketHandle(pub usize);
+
+/// Returns whether sequence number `a` comes before `b`, allowing for wrapping.
+fn sequence_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Segment<'a> {
+    source_port: u16,
+    destination_port: u16,
+    sequence: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    /// Parses a segment from `source` to `destination`, returning `None` if it's malformed or its
+    /// checksum is wrong.
+    fn parse(source: Ipv4Address, destination: Ipv4Address, data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+        let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_TCP, data.len());
+        if ipv4::checksum(data, sum) != 0 {
+            return None;
+        }
+        let header_len = (data[12] >> 4) as usize * 4;
+        if header_len < HEADER_LEN || header_len > data.len() {
+            return None;
+        }
+        let mut mss = None;
+        let mut options = &data[HEADER_LEN..header_len];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                OPTION_END => break,
+                OPTION_NOP => options = rest,
+                kind => {
+                    let len = *rest.first()? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+        Some(Self {
+            source_port: u16::from_be_bytes([data[0], data[1]]),
+            destination_port: u16::from_be_bytes([data[2], data[3]]),
+            sequence: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            ack: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            mss,
+            payload: &data[header_len..],
+        })
+    }
+
+    /// Builds the segment, with its checksum, to be sent from `source` to `destination`.
+    fn build(&self, source: Ipv4Address, destination: Ipv4Address) -> Vec<u8> {
+        let header_len = HEADER_LEN + if self.mss.is_some() { 4 } else { 0 };
+        let mut data = Vec::with_capacity(header_len + self.payload.len());
+        data.extend_from_slice(&self.source_port.to_be_bytes());
+        data.extend_from_slice(&self.destination_port.to_be_bytes());
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.ack.to_be_bytes());
+        data.extend_from_slice(&[(header_len / 4) as u8 * 16, self.flags]);
+        data.extend_from_slice(&self.window.to_be_bytes());
+        // Checksum, then urgent pointer
+        data.extend_from_slice(&[0; 4]);
+        if let Some(mss) = self.mss {
+            data.extend_from_slice(&[OPTION_MSS, 4]);
+            data.extend_from_slice(&mss.to_be_bytes());
+        }
+        data.extend_from_slice(self.payload);
+        let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_TCP, data.len());
+        let checksum = ipv4::checksum(&data, sum);
+        data[16..18].copy_from_slice(&checksum.to_be_bytes());
+        data
+    }
+
+    /// Returns how much sequence space the segment takes up, with SYN and FIN counting as one
+    /// each.
+    fn sequence_len(&self) -> u32 {
+        let flags = [FLAG_SYN, FLAG_FIN]
+            .into_iter()
+            .filter(|flag| self.flags & flag != 0)
+            .count();
+        (self.payload.len() + flags) as u32
+    }
+}
+
+/// Identifies a connection by its local port and the peer's address and port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ConnectionKey {
+    local_port: u16,
+    remote_address: Ipv4Address,
+    remote_port: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl State {
+    /// Whether data and FIN can be sent, which is until our FIN is acknowledged.
+    fn can_send(self) -> bool {
+        matches!(
+            self,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::Closing
+                | State::LastAck
+        )
+    }
+
+    /// Whether data can be received, which is until the peer's FIN.
+    fn can_receive(self) -> bool {
+        matches!(self, State::Established | State::FinWait1 | State::FinWait2)
+    }
+}
+
+struct Connection {
+    key: ConnectionKey,
+    local_address: Ipv4Address,
+    state: State,
+    /// Oldest sequence number not yet acknowledged.
+    send_unacked: u32,
+    /// Sequence number of the next byte to send.
+    send_next: u32,
+    send_window: usize,
+    peer_mss: usize,
+    /// Data written but not yet acknowledged, from `send_unacked` once established.
+    send_buffer: VecDeque<u8>,
+    /// Set once the socket is closed, to send a FIN after the remaining data.
+    fin_queued: bool,
+    /// Sequence number of the next byte expected.
+    receive_next: u32,
+    /// Data received in order but not yet read.
+    receive_buffer: VecDeque<u8>,
+    /// Set once the peer's FIN is received.
+    receive_closed: bool,
+    /// Why the connection was closed, if it wasn't closed cleanly.
+    error: Option<SocketError>,
+    rto_ns: u64,
+    num_retransmits: u32,
+    /// When to retransmit, or give up waiting in `FinWait2` or `TimeWait`.
+    deadline: Option<TimerId>,
+    /// Listening socket to queue the connection on once it's established.
+    listener: Option<Arc<Socket>>,
+    /// Whether the local port is released when the connection is removed, rather than belonging
+    /// to a listener.
+    owns_port: bool,
+}
+
+impl Connection {
+    fn new(key: ConnectionKey, local_address: Ipv4Address, state: State) -> Self {
+        // Clock driven initial sequence number, ticking every 4 microseconds as in RFC 793
+        let initial_sequence = (clock::now_ns() / 4000) as u32;
+        Self {
+            key,
+            local_address,
+            state,
+            send_unacked: initial_sequence,
+            send_next: initial_sequence.wrapping_add(1),
+            send_window: 0,
+            peer_mss: DEFAULT_PEER_MSS,
+            send_buffer: VecDeque::new(),
+            fin_queued: false,
+            receive_next: 0,
+            receive_buffer: VecDeque::new(),
+            receive_closed: false,
+            error: None,
+            rto_ns: INITIAL_RTO_NS,
+            num_retransmits: 0,
+            deadline: None,
+            listener: None,
+            owns_port: true,
+        }
+    }
+
+    fn receive_window(&self) -> usize {
+        RECEIVE_BUFFER_LEN - self.receive_buffer.len()
+    }
+
+    fn send_segment(&self, sequence: u32, flags: u8, payload: &[u8]) -> Result<(), NetError> {
+        let segment = Segment {
+            source_port: self.key.local_port,
+            destination_port: self.key.remote_port,
+            sequence,
+            ack: if flags & FLAG_ACK != 0 {
+                self.receive_next
+            } else {
+                0
+            },
+            flags,
+            window: self.receive_window() as u16,
+            mss: (flags & FLAG_SYN != 0).then_some(MSS as u16),
+            payload,
+        };
+        let data = segment.build(self.local_address, self.key.remote_address);
+        ipv4::send(self.key.remote_address, PROTOCOL_TCP, &data)
+    }
+
+    /// Sends a SYN, or a SYN-ACK if the peer's SYN has been received.
+    fn send_syn(&self) -> Result<(), NetError> {
+        let flags = match self.state {
+            State::SynSent => FLAG_SYN,
+            _ => FLAG_SYN | FLAG_ACK,
+        };
+        self.send_segment(self.send_unacked, flags, &[])
+    }
+
+    fn send_ack(&self) {
+        _ = self.send_segment(self.send_next, FLAG_ACK, &[]);
+    }
+
+    fn send_reset(&self) {
+        _ = self.send_segment(self.send_next, FLAG_RST | FLAG_ACK, &[]);
+    }
+
+    /// Runs `on_deadline` at `deadline_ns`, replacing any previous deadline.
+    fn set_deadline(&mut self, deadline_ns: Option<u64>) {
+        if let Some(id) = self.deadline.take() {
+            timer::cancel_timer(id);
+        }
+        self.deadline =
+            deadline_ns.map(|deadline_ns| timer::add_timer(deadline_ns, timer_expired, 0));
+    }
+
+    fn start_retransmit_timer(&mut self) {
+        if self.deadline.is_none() {
+            self.set_deadline(Some(clock::now_ns() + self.rto_ns));
+        }
+    }
+
+    fn close(&mut self, error: Option<SocketError>) {
+        self.state = State::Closed;
+        self.error = self.error.or(error);
+        self.set_deadline(None);
+    }
+
+    /// Sends as much buffered data as the peer's window allows, then a FIN if the socket is
+    /// closed and everything else has been sent. A probe sends at least one byte into a closed
+    /// window, to find out when it opens.
+    fn output(&mut self, probe: bool) {
+        if !self.state.can_send() {
+            return;
+        }
+        loop {
+            let sent = self.send_next.wrapping_sub(self.send_unacked) as usize;
+            let mut window = self.send_window.saturating_sub(sent);
+            if probe && sent == 0 {
+                window = window.max(1);
+            }
+            let len = self
+                .send_buffer
+                .len()
+                .saturating_sub(sent)
+                .min(window)
+                .min(self.peer_mss);
+            if len == 0 {
+                break;
+            }
+            self.send_buffer.make_contiguous();
+            let payload = &self.send_buffer.as_slices().0[sent..sent + len];
+            _ = self.send_segment(self.send_next, FLAG_ACK | FLAG_PSH, payload);
+            self.send_next = self.send_next.wrapping_add(len as u32);
+        }
+        let fin_sequence = self
+            .send_unacked
+            .wrapping_add(self.send_buffer.len() as u32);
+        if self.fin_queued && self.send_next == fin_sequence {
+            _ = self.send_segment(self.send_next, FLAG_FIN | FLAG_ACK, &[]);
+            self.send_next = self.send_next.wrapping_add(1);
+            self.state = match self.state {
+                State::Established => State::FinWait1,
+                State::CloseWait => State::LastAck,
+                state => state,
+            };
+        }
+        if self.send_next != self.send_unacked || !self.send_buffer.is_empty() {
+            self.start_retransmit_timer();
+        }
+    }
+
+    /// Handles an expired deadline, retransmitting or giving up on the connection.
+    fn on_deadline(&mut self) {
+        self.deadline = None;
+        if matches!(self.state, State::FinWait2 | State::TimeWait) {
+            self.close(None);
+            return;
+        }
+        if self.num_retransmits == MAX_RETRANSMITS {
+            self.send_reset();
+            self.close(Some(SocketError::TimedOut));
+            return;
+        }
+        self.num_retransmits += 1;
+        self.rto_ns = (self.rto_ns * 2).min(MAX_RTO_NS);
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                _ = self.send_syn();
+                self.start_retransmit_timer();
+            }
+            _ => {
+                self.send_next = self.send_unacked;
+                self.output(true);
+            }
+        }
+    }
+
+    /// Handles a segment for the connection. Returns `true` if it was just established and
+    /// should be queued on its listener.
+    fn receive_segment(&mut self, segment: &Segment) -> bool {
+        if self.state == State::SynSent {
+            self.receive_syn_sent(segment);
+            return false;
+        }
+        if segment.sequence != self.receive_next {
+            // Early or repeated, asking again for what's expected next
+            if segment.flags & FLAG_RST == 0 {
+                self.send_ack();
+            }
+            return false;
+        }
+        if segment.flags & FLAG_RST != 0 {
+            self.close(Some(SocketError::ConnectionReset));
+            return false;
+        }
+        if segment.flags & FLAG_SYN != 0 {
+            self.send_reset();
+            self.close(Some(SocketError::ConnectionReset));
+            return false;
+        }
+        if segment.flags & FLAG_ACK == 0 {
+            return false;
+        }
+        let mut established = false;
+        if self.state == State::SynReceived {
+            if segment.ack != self.send_next {
+                _ = self.send_segment(segment.ack, FLAG_RST, &[]);
+                return false;
+            }
+            self.state = State::Established;
+            self.send_unacked = segment.ack;
+            self.send_window = segment.window as usize;
+            self.rto_ns = INITIAL_RTO_NS;
+            self.num_retransmits = 0;
+            self.set_deadline(None);
+            established = self.listener.is_some();
+        }
+        if !self.receive_ack(segment) {
+            return established;
+        }
+        if self.state == State::Closed {
+            return established;
+        }
+
+        let mut need_ack = false;
+        let mut payload_len = 0;
+        if !segment.payload.is_empty() && self.state.can_receive() {
+            payload_len = segment.payload.len().min(self.receive_window());
+            self.receive_buffer.extend(&segment.payload[..payload_len]);
+            self.receive_next = self.receive_next.wrapping_add(payload_len as u32);
+            need_ack = true;
+        }
+        // A FIN only counts once everything before it has been taken
+        if segment.flags & FLAG_FIN != 0 && payload_len == segment.payload.len() {
+            if !self.receive_closed {
+                self.receive_next = self.receive_next.wrapping_add(1);
+                self.receive_closed = true;
+            }
+            self.state = match self.state {
+                State::SynReceived | State::Established => State::CloseWait,
+                State::FinWait1 => State::Closing,
+                State::FinWait2 | State::TimeWait => {
+                    self.set_deadline(Some(clock::now_ns() + TIME_WAIT_NS));
+                    State::TimeWait
+                }
+                state => state,
+            };
+            need_ack = true;
+        }
+        if need_ack {
+            self.send_ack();
+        }
+        self.output(false);
+        established
+    }
+
+    fn receive_syn_sent(&mut self, segment: &Segment) {
+        let acceptable_ack = segment.ack == self.send_next;
+        if segment.flags & FLAG_ACK != 0 && !acceptable_ack {
+            if segment.flags & FLAG_RST == 0 {
+                _ = self.send_segment(segment.ack, FLAG_RST, &[]);
+            }
+            return;
+        }
+        if segment.flags & FLAG_RST != 0 {
+            if acceptable_ack {
+                self.close(Some(SocketError::ConnectionRefused));
+            }
+            return;
+        }
+        if segment.flags & FLAG_SYN == 0 {
+            return;
+        }
+        self.receive_next = segment.sequence.wrapping_add(1);
+        self.peer_mss = segment.mss.map_or(DEFAULT_PEER_MSS, |mss| mss as usize);
+        self.send_window = segment.window as usize;
+        self.rto_ns = INITIAL_RTO_NS;
+        self.num_retransmits = 0;
+        self.set_deadline(None);
+        if segment.flags & FLAG_ACK != 0 {
+            self.state = State::Established;
+            self.send_unacked = segment.ack;
+            self.send_ack();
+        } else {
+            // Simultaneous open
+            self.state = State::SynReceived;
+            _ = self.send_syn();
+            self.start_retransmit_timer();
+        }
+    }
+
+    /// Handles the acknowledgement and window in an in order segment. Returns `false` if the rest
+    /// of the segment should be ignored.
+    fn receive_ack(&mut self, segment: &Segment) -> bool {
+        let acked = segment.ack.wrapping_sub(self.send_unacked);
+        let in_flight = self.send_next.wrapping_sub(self.send_unacked);
+        if acked > in_flight {
+            if sequence_before(self.send_next, segment.ack) {
+                // Acknowledging something never sent
+                self.send_ack();
+                return false;
+            }
+            // Old, but may still carry data
+            return true;
+        }
+        self.send_window = segment.window as usize;
+        if acked == 0 {
+            return true;
+        }
+        let data_acked = (acked as usize).min(self.send_buffer.len());
+        self.send_buffer.drain(..data_acked);
+        self.send_unacked = segment.ack;
+        self.rto_ns = INITIAL_RTO_NS;
+        self.num_retransmits = 0;
+        self.set_deadline(None);
+        if self.send_next != self.send_unacked {
+            self.start_retransmit_timer();
+        }
+        let fin_acked = acked as usize > data_acked;
+        if fin_acked {
+            match self.state {
+                State::FinWait1 => {
+                    // The socket is already closed, so don't wait forever on the peer's FIN
+                    self.state = State::FinWait2;
+                    self.set_deadline(Some(clock::now_ns() + TIME_WAIT_NS));
+                }
+                State::Closing => {
+                    self.state = State::TimeWait;
+                    self.set_deadline(Some(clock::now_ns() + TIME_WAIT_NS));
+                }
+                State::LastAck => self.close(None),
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+enum SocketState {
+    Unbound,
+    Bound {
+        port: u16,
+    },
+    Listening {
+        port: u16,
+        /// Established connections waiting to be accepted.
+        backlog: VecDeque<Arc<Socket>>,
+        max_backlog: usize,
+    },
+    Connected(Connection),
+}
+
+pub struct Socket {
+    state: Mutex<SocketState>,
+    /// Woken whenever the socket's state changes.
+    changed: WaitQueue,
+}
+
+struct Tcp {
+    listeners: BTreeMap<u16, Arc<Socket>>,
+    connections: BTreeMap<ConnectionKey, Arc<Socket>>,
+    /// Ports bound by sockets, which connections accepted by listeners share.
+    ports: BTreeSet<u16>,
+    next_ephemeral_port: u16,
+}
+
+impl Tcp {
+    fn allocate_port(&mut self, port: u16) -> Result<u16, SocketError> {
+        if port != 0 {
+            return match self.ports.insert(port) {
+                true => Ok(port),
+                false => Err(SocketError::AddressInUse),
+            };
+        }
+        for _ in FIRST_EPHEMERAL_PORT..=u16::MAX {
+            let port = self.next_ephemeral_port;
+            self.next_ephemeral_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            if self.ports.insert(port) {
+                return Ok(port);
+            }
+        }
+        Err(SocketError::AddressInUse)
+    }
+
+    /// Removes a closed connection, freeing its port if it has its own.
+    fn remove(&mut self, connection: &Connection) {
+        self.connections.remove(&connection.key);
+        if connection.owns_port {
+            self.ports.remove(&connection.key.local_port);
+        }
+    }
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Socket {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(SocketState::Unbound),
+            changed: WaitQueue::new(),
+        }
+    }
+
+    fn new_connected(connection: Connection) -> Self {
+        Self {
+            state: Mutex::new(SocketState::Connected(connection)),
+            changed: WaitQueue::new(),
+        }
+    }
+
+    /// Binds the socket to `port`, or a free ephemeral port if it's 0. Returns the port.
+    pub fn bind(&self, port: u16) -> Result<u16, SocketError> {
+        let mut state = self.state.lock();
+        let SocketState::Unbound = *state else {
+            return Err(SocketError::InvalidState);
+        };
+        let port = TCP.lock().allocate_port(port)?;
+        *state = SocketState::Bound { port };
+        Ok(port)
+    }
+
+    /// Starts accepting connections on the socket's port, queueing up to `max_backlog`
+    /// established connections.
+    pub fn listen(self: &Arc<Self>, max_backlog: usize) -> Result<(), SocketError> {
+        let mut state = self.state.lock();
+        let SocketState::Bound { port } = *state else {
+            return Err(SocketError::InvalidState);
+        };
+        TCP.lock().listeners.insert(port, self.clone());
+        *state = SocketState::Listening {
+            port,
+            backlog: VecDeque::new(),
+            max_backlog: max_backlog.max(1),
+        };
+        Ok(())
+    }
+
+    /// Blocks until a connection is established on the listening socket, then returns it.
+    pub fn accept(&self) -> Result<Arc<Socket>, SocketError> {
+        let mut result = Err(SocketError::InvalidState);
+        self.changed.wait_until(|| {
+            let mut state = self.state.lock();
+            let SocketState::Listening { backlog, .. } = &mut *state else {
+                return true;
+            };
+            match backlog.pop_front() {
+                Some(socket) => {
+                    result = Ok(socket);
+                    true
+                }
+                None => false,
+            }
+        });
+        result
+    }
+
+    /// Connects to `port` on `address`, binding the socket to an ephemeral port first if it's
+    /// unbound. Blocks until the connection is established or fails.
+    pub fn connect(self: &Arc<Self>, address: Ipv4Address, port: u16) -> Result<(), SocketError> {
+        {
+            let mut state = self.state.lock();
+            let bound_port = match *state {
+                SocketState::Unbound => None,
+                SocketState::Bound { port } => Some(port),
+                _ => return Err(SocketError::InvalidState),
+            };
+            let local_address = ipv4::local_address().ok_or(NetError::NotConfigured)?;
+            let mut tcp = TCP.lock();
+            let local_port = match bound_port {
+                Some(port) => port,
+                None => tcp.allocate_port(0)?,
+            };
+            let key = ConnectionKey {
+                local_port,
+                remote_address: address,
+                remote_port: port,
+            };
+            // Undoes the reservations here if the connection can't be started
+            let release = |tcp: &mut Tcp| {
+                tcp.connections.remove(&key);
+                if bound_port.is_none() {
+                    tcp.ports.remove(&local_port);
+                }
+            };
+            if tcp.connections.contains_key(&key) {
+                if bound_port.is_none() {
+                    tcp.ports.remove(&local_port);
+                }
+                return Err(SocketError::AddressInUse);
+            }
+            tcp.connections.insert(key, self.clone());
+            drop(tcp);
+            let mut connection = Connection::new(key, local_address, State::SynSent);
+            if let Err(err) = connection.send_syn() {
+                release(&mut TCP.lock());
+                return Err(err.into());
+            }
+            connection.start_retransmit_timer();
+            *state = SocketState::Connected(connection);
+        }
+        let mut result = Ok(());
+        self.changed.wait_until(|| {
+            let state = self.state.lock();
+            let SocketState::Connected(connection) = &*state else {
+                return true;
+            };
+            match connection.state {
+                State::SynSent | State::SynReceived => false,
+                State::Closed => {
+                    result = Err(connection.error.unwrap_or(SocketError::ConnectionReset));
+                    true
+                }
+                _ => true,
+            }
+        });
+        result
+    }
+
+    /// Blocks until there's space in the send buffer, then queues as much of `data` as fits to
+    /// be sent. Returns how much was queued.
+    pub fn send(&self, data: &[u8]) -> Result<usize, SocketError> {
+        let mut result = Ok(0);
+        self.changed.wait_until(|| {
+            let mut state = self.state.lock();
+            let SocketState::Connected(connection) = &mut *state else {
+                result = Err(SocketError::InvalidState);
+                return true;
+            };
+            if let Some(err) = connection.error {
+                result = Err(err);
+                return true;
+            }
+            if connection.fin_queued
+                || !matches!(connection.state, State::Established | State::CloseWait)
+            {
+                result = Err(SocketError::Closed);
+                return true;
+            }
+            let len = data
+                .len()
+                .min(SEND_BUFFER_LEN - connection.send_buffer.len());
+            if len == 0 {
+                return data.is_empty();
+            }
+            connection.send_buffer.extend(&data[..len]);
+            connection.output(false);
+            result = Ok(len);
+            true
+        });
+        result
+    }
+
+    /// Blocks until data has been received, then reads up to `buffer.len()` bytes of it. Returns
+    /// 0 once the peer has closed the connection and everything has been read.
+    pub fn receive(&self, buffer: &mut [u8]) -> Result<usize, SocketError> {
+        let mut result = Ok(0);
+        self.changed.wait_until(|| match self.try_receive(buffer) {
+            Ok(None) => false,
+            done => {
+                result = done.map(Option::unwrap_or_default);
+                true
+            }
+        });
+        result
+    }
+
+    /// Like `receive`, but returns `None` instead of blocking if there's nothing to read yet.
+    pub fn try_receive(&self, buffer: &mut [u8]) -> Result<Option<usize>, SocketError> {
+        let mut state = self.state.lock();
+        let SocketState::Connected(connection) = &mut *state else {
+            return Err(SocketError::InvalidState);
+        };
+        if !connection.receive_buffer.is_empty() {
+            let window_was_small = connection.receive_window() < MSS;
+            let len = buffer.len().min(connection.receive_buffer.len());
+            for (byte, received) in buffer
+                .iter_mut()
+                .zip(connection.receive_buffer.drain(..len))
+            {
+                *byte = received;
+            }
+            // Tell the peer once there's room for a full segment again
+            if window_was_small
+                && connection.receive_window() >= MSS
+                && connection.state.can_receive()
+            {
+                connection.send_ack();
+            }
+            return Ok(Some(len));
+        }
+        if let Some(err) = connection.error {
+            return Err(err);
+        }
+        let closed = connection.receive_closed || connection.state == State::Closed;
+        Ok(closed.then_some(0))
+    }
+
+    /// Closes the socket. Connections are shut down gracefully, sending any remaining data before
+    /// a FIN, and are removed once the peer has closed its side too.
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        match &mut *state {
+            SocketState::Unbound => {}
+            SocketState::Bound { port } => {
+                TCP.lock().ports.remove(port);
+                *state = SocketState::Unbound;
+            }
+            SocketState::Listening { port, backlog, .. } => {
+                let mut tcp = TCP.lock();
+                tcp.listeners.remove(port);
+                tcp.ports.remove(port);
+                drop(tcp);
+                let backlog = core::mem::take(backlog);
+                *state = SocketState::Unbound;
+                drop(state);
+                for socket in backlog {
+                    socket.reset();
+                }
+                self.changed.wake_all();
+                return;
+            }
+            SocketState::Connected(connection) => match connection.state {
+                State::SynSent => {
+                    connection.close(None);
+                    TCP.lock().remove(connection);
+                }
+                State::SynReceived | State::Established | State::CloseWait => {
+                    connection.fin_queued = true;
+                    connection.output(false);
+                }
+                _ => {}
+            },
+        }
+        drop(state);
+        // Threads blocked on the socket would otherwise wait until the connection changes
+        self.changed.wake_all();
+    }
+
+    /// Aborts the socket's connection, telling the peer with a RST.
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        if let SocketState::Connected(connection) = &mut *state
+            && connection.state != State::Closed
+        {
+            connection.send_reset();
+            connection.close(Some(SocketError::ConnectionReset));
+            TCP.lock().remove(connection);
+        }
+        self.changed.wake_all();
+    }
+}
+
+/// Handles a segment for `socket`'s connection.
+fn receive_for_connection(socket: &Arc<Socket>, segment: &Segment) {
+    let established = {
+        let mut state = socket.state.lock();
+        let SocketState::Connected(connection) = &mut *state else {
+            return;
+        };
+        let established = connection.receive_segment(segment);
+        if connection.state == State::Closed {
+            TCP.lock().remove(connection);
+        }
+        established.then(|| connection.listener.take()).flatten()
+    };
+    socket.changed.wake_all();
+    // Queued with the connection unlocked, as sockets are never locked together
+    let Some(listener) = established else {
+        return;
+    };
+    let mut listener_state = listener.state.lock();
+    match &mut *listener_state {
+        SocketState::Listening { backlog, .. } => {
+            backlog.push_back(socket.clone());
+            drop(listener_state);
+            listener.changed.wake_all();
+        }
+        _ => {
+            drop(listener_state);
+            socket.reset();
+        }
+    }
+}
+
+/// Handles a SYN for a listening socket, starting a new connection.
+fn receive_for_listener(
+    listener: &Arc<Socket>,
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    segment: &Segment,
+) {
+    let state = listener.state.lock();
+    let SocketState::Listening {
+        backlog,
+        max_backlog,
+        ..
+    } = &*state
+    else {
+        return;
+    };
+    if backlog.len() >= *max_backlog {
+        // Dropped rather than refused, so the peer tries again later
+        return;
+    }
+    let key = ConnectionKey {
+        local_port: segment.destination_port,
+        remote_address: source,
+        remote_port: segment.source_port,
+    };
+    let mut connection = Connection::new(key, destination, State::SynReceived);
+    connection.receive_next = segment.sequence.wrapping_add(1);
+    connection.peer_mss = segment.mss.map_or(DEFAULT_PEER_MSS, |mss| mss as usize);
+    connection.send_window = segment.window as usize;
+    connection.listener = Some(listener.clone());
+    connection.owns_port = false;
+    drop(state);
+    _ = connection.send_syn();
+    connection.start_retransmit_timer();
+    let socket = Arc::new(Socket::new_connected(connection));
+    TCP.lock().connections.insert(key, socket);
+}
+
+/// Replies to a segment with no connection with a RST, unless it's a RST itself.
+fn reply_with_reset(source: Ipv4Address, destination: Ipv4Address, segment: &Segment) {
+    if segment.flags & FLAG_RST != 0 {
+        return;
+    }
+    let (sequence, ack, flags) = match segment.flags & FLAG_ACK {
+        0 => (
+            0,
+            segment.sequence.wrapping_add(segment.sequence_len()),
+            FLAG_RST | FLAG_ACK,
+        ),
+        _ => (segment.ack, 0, FLAG_RST),
+    };
+    let reset = Segment {
+        source_port: segment.destination_port,
+        destination_port: segment.source_port,
+        sequence,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        payload: &[],
+    };
+    _ = ipv4::send(source, PROTOCOL_TCP, &reset.build(destination, source));
+}
+
+/// Handles a TCP segment received from `source`.
+pub fn receive(source: Ipv4Address, destination: Ipv4Address, data: &[u8]) {
+    let Some(segment) = Segment::parse(source, destination, data) else {
+        return;
+    };
+    let key = ConnectionKey {
+        local_port: segment.destination_port,
+        remote_address: source,
+        remote_port: segment.source_port,
+    };
+    let (connection, listener) = {
+        let tcp = TCP.lock();
+        (
+            tcp.connections.get(&key).cloned(),
+            tcp.listeners.get(&key.local_port).cloned(),
+        )
+    };
+    match (connection, listener) {
+        (Some(socket), _) => receive_for_connection(&socket, &segment),
+        (None, Some(listener)) if segment.flags & (FLAG_SYN | FLAG_ACK | FLAG_RST) == FLAG_SYN => {
+            receive_for_listener(&listener, source, destination, &segment);
+        }
+        _ => reply_with_reset(source, destination, &segment),
+    }
+}
+
+fn timer_expired(_: usize) {
+    work_queue::schedule(&TIMER_WORK);
+}
+
+/// Runs the deadlines of every connection which have passed.
+fn run_timers(_: usize) {
+    let now_ns = clock::now_ns();
+    let sockets: Vec<_> = TCP.lock().connections.values().cloned().collect();
+    for socket in sockets {
+        {
+            let mut state = socket.state.lock();
+            let SocketState::Connected(connection) = &mut *state else {
+                continue;
+            };
+            if connection
+                .deadline
+                .is_none_or(|deadline| deadline.deadline_ns() > now_ns)
+            {
+                continue;
+            }
+            connection.on_deadline();
+            if connection.state == State::Closed {
+                TCP.lock().remove(connection);
+            }
+        }
+        socket.changed.wake_all();
+    }
+}
+
+/// A process's open sockets, indexed by handle.
+#[derive(Default)]
+pub struct SocketTable {
+    sockets: Vec<Option<Arc<Socket>>>,
+}
+
+impl SocketTable {
+    pub const fn new() -> Self {
+        Self {
+            sockets: Vec::new(),
+        }
+    }
+
+    /// Adds `socket` to the table, returning its handle.
+    pub fn insert(&mut self, socket: Arc<Socket>) -> SocketHandle {
+        match self.sockets.iter().position(Option::is_none) {
+            Some(index) => {
+                self.sockets[index] = Some(socket);
+                SocketHandle(index)
+            }
+            None => {
+                self.sockets.push(Some(socket));
+                SocketHandle(self.sockets.len() - 1)
+            }
+        }
+    }
+
+    /// Returns the socket `handle` refers to.
+    pub fn get(&self, handle: SocketHandle) -> Result<Arc<Socket>, SocketError> {
+        self.sockets
+            .get(handle.0)
+            .cloned()
+            .flatten()
+            .ok_or(SocketError::NotFound)
+    }
+
+    /// Removes `handle` from the table, returning its socket to be closed.
+    fn remove(&mut self, handle: SocketHandle) -> Result<Arc<Socket>, SocketError> {
+        self.sockets
+            .get_mut(handle.0)
+            .and_then(Option::take)
+            .ok_or(SocketError::NotFound)
+    }
+
+    /// Closes every socket.
+    pub fn close_all(&mut self) {
+        for socket in self.sockets.drain(..).flatten() {
+            socket.close();
+        }
+    }
+}
+
+fn current_process() -> Arc<Process> {
+    kthread::current_process().expect("socket system call from a kernel thread")
+}
+
+/// Returns the socket `handle` refers to in the current process.
+fn get(handle: usize) -> Result<Arc<Socket>, SocketError> {
+    current_process().sockets.lock().get(SocketHandle(handle))
+}
+
+#[derive(UserArgs)]
+struct SendArgs {
+    #[user(read, len = data_len, optional)]
+    data_ptr: *const u8,
+    data_len: usize,
+}
+
+#[derive(UserArgs)]
+struct ReceiveArgs {
+    #[user(write, len = buffer_len, optional)]
+    buffer_ptr: *mut u8,
+    buffer_len: usize,
+}
+
+/// Handler for the create socket syscall, returning a handle to a new unbound socket.
+pub fn syscall_create() -> Result<usize, SyscallError> {
+    let socket = Arc::new(Socket::new());
+    Ok(current_process().sockets.lock().insert(socket).0)
+}
+
+/// Handler for the close socket syscall.
+pub fn syscall_close(handle: usize) -> Result<usize, SyscallError> {
+    let socket = current_process()
+        .sockets
+        .lock()
+        .remove(SocketHandle(handle))?;
+    socket.close();
+    Ok(0)
+}
+
+/// Handler for the bind syscall. Binds the socket `handle` to `port`, or an ephemeral port if
+/// it's 0, returning the port.
+pub fn syscall_bind(handle: usize, port: usize) -> Result<usize, SyscallError> {
+    let port = u16::try_from(port).map_err(|_| SyscallError::INVALID_ARGUMENT)?;
+    let port = get(handle)?.bind(port)?;
+    Ok(port as usize)
+}
+
+/// Handler for the listen syscall.
+pub fn syscall_listen(handle: usize, max_backlog: usize) -> Result<usize, SyscallError> {
+    get(handle)?.listen(max_backlog)?;
+    Ok(0)
+}
+
+/// Handler for the accept syscall, returning a handle to the next established connection.
+pub fn syscall_accept(handle: usize) -> Result<usize, SyscallError> {
+    let socket = get(handle)?.accept()?;
+    Ok(current_process().sockets.lock().insert(socket).0)
+}
+
+/// Handler for the connect syscall. `address` is an IPv4 address with its first byte most
+/// significant.
+pub fn syscall_connect(handle: usize, address: usize, port: usize) -> Result<usize, SyscallError> {
+    let address = u32::try_from(address).map_err(|_| SyscallError::INVALID_ARGUMENT)?;
+    let port = u16::try_from(port).map_err(|_| SyscallError::INVALID_ARGUMENT)?;
+    get(handle)?.connect(Ipv4Address::from_u32(address), port)?;
+    Ok(0)
+}
+
+/// Handler for the send syscall. Queues up to `data_len` bytes at `data_ptr` to be sent on the
+/// socket `handle`, returning how many were queued.
+pub fn syscall_send(
+    handle: usize,
+    data_ptr: *const u8,
+    data_len: usize,
+) -> Result<usize, SyscallError> {
+    SendArgs { data_ptr, data_len }.validate()?;
+    let socket = get(handle)?;
+    let mut data = vec![0; data_len.min(SEND_BUFFER_LEN)];
+    if !data.is_empty() {
+        user_memory::copy_from_user(&mut data, data_ptr as usize)?;
+    }
+    Ok(socket.send(&data)?)
+}
+
+/// Handler for the receive syscall. Reads up to `buffer_len` bytes received on the socket
+/// `handle` into `buffer_ptr`, returning how many were read, or 0 at the end of the stream.
+pub fn syscall_receive(
+    handle: usize,
+    buffer_ptr: *mut u8,
+    buffer_len: usize,
+) -> Result<usize, SyscallError> {
+    ReceiveArgs {
+        buffer_ptr,
+        buffer_len,
+    }
+    .validate()?;
+    let socket = get(handle)?;
+    let mut buffer = vec![0; buffer_len.min(RECEIVE_BUFFER_LEN)];
+    let len = socket.receive(&mut buffer)?;
+    if len != 0 {
+        user_memory::copy_to_user(buffer_ptr as usize, &buffer[..len])?;
+    }
+    Ok(len)
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    const LOCAL: Ipv4Address = Ipv4Address([10, 0, 2, 15]);
+    const REMOTE: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
+
+    #[kernel_test]
+    fn builds_and_parses_segments() -> TestResult {
+        let segment = Segment {
+            source_port: 1234,
+            destination_port: 80,
+            sequence: 0xDEAD_BEEF,
+            ack: 7,
+            flags: FLAG_SYN | FLAG_ACK,
+            window: 4096,
+            mss: Some(1460),
+            payload: b"hello",
+        };
+        let mut data = segment.build(LOCAL, REMOTE);
+        ktest_assert_eq!(Segment::parse(LOCAL, REMOTE, &data), Some(segment));
+        ktest_assert_eq!(segment.sequence_len(), 6);
+        // Corrupted segments fail their checksum
+        data[HEADER_LEN + 4] ^= 1;
+        ktest_assert_eq!(Segment::parse(LOCAL, REMOTE, &data), None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn compares_wrapping_sequence_numbers() -> TestResult {
+        ktest_assert!(sequence_before(1, 2));
+        ktest_assert!(sequence_before(u32::MAX, 0));
+        ktest_assert!(!sequence_before(0, u32::MAX));
+        ktest_assert!(!sequence_before(5, 5));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn establishes_and_closes_connections() -> TestResult {
+        let key = ConnectionKey {
+            local_port: FIRST_EPHEMERAL_PORT,
+            remote_address: REMOTE,
+            remote_port: 80,
+        };
+        let mut connection = Connection::new(key, LOCAL, State::SynSent);
+        let syn_ack = Segment {
+            source_port: 80,
+            destination_port: FIRST_EPHEMERAL_PORT,
+            sequence: 1000,
+            ack: connection.send_next,
+            flags: FLAG_SYN | FLAG_ACK,
+            window: 8192,
+            mss: Some(1200),
+            payload: &[],
+        };
+        // No interface is configured in tests, so nothing is actually sent
+        connection.receive_segment(&syn_ack);
+        ktest_assert_eq!(connection.state, State::Established);
+        ktest_assert_eq!(connection.receive_next, 1001);
+        ktest_assert_eq!(connection.peer_mss, 1200);
+        ktest_assert_eq!(connection.send_window, 8192);
+
+        let data = Segment {
+            sequence: 1001,
+            flags: FLAG_ACK | FLAG_FIN,
+            mss: None,
+            payload: b"data",
+            ..syn_ack
+        };
+        connection.receive_segment(&data);
+        ktest_assert_eq!(connection.state, State::CloseWait);
+        ktest_assert_eq!(connection.receive_next, 1006);
+        ktest_assert!(connection.receive_closed);
+        ktest_assert_eq!(
+            connection
+                .receive_buffer
+                .iter()
+                .copied()
+                .collect::<Vec<_>>(),
+            b"data"
+        );
+
+        connection.fin_queued = true;
+        connection.output(false);
+        ktest_assert_eq!(connection.state, State::LastAck);
+        let fin_ack = Segment {
+            sequence: 1006,
+            ack: connection.send_next,
+            flags: FLAG_ACK,
+            payload: &[],
+            ..data
+        };
+        connection.receive_segment(&fin_ack);
+        ktest_assert_eq!(connection.state, State::Closed);
+        ktest_assert_eq!(connection.error, None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn refuses_on_reset() -> TestResult {
+        let key = ConnectionKey {
+            local_port: FIRST_EPHEMERAL_PORT,
+            remote_address: REMOTE,
+            remote_port: 81,
+        };
+        let mut connection = Connection::new(key, LOCAL, State::SynSent);
+        let reset = Segment {
+            source_port: 81,
+            destination_port: FIRST_EPHEMERAL_PORT,
+            sequence: 0,
+            ack: connection.send_next,
+            flags: FLAG_RST | FLAG_ACK,
+            window: 0,
+            mss: None,
+            payload: &[],
+        };
+        connection.receive_segment(&reset);
+        ktest_assert_eq!(connection.state, State::Closed);
+        ktest_assert_eq!(connection.error, Some(SocketError::ConnectionRefused));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn closes_sockets_in_table() -> TestResult {
+        // Well below the ephemeral ports, which other tests may have allocated
+        const PORT: u16 = 4321;
+        let mut sockets = SocketTable::new();
+        let socket = Arc::new(Socket::new());
+        ktest_assert_eq!(socket.bind(PORT), Ok(PORT));
+        ktest_assert_eq!(sockets.insert(socket), SocketHandle(0));
+        ktest_assert_eq!(sockets.insert(Arc::new(Socket::new())), SocketHandle(1));
+        ktest_assert!(sockets.remove(SocketHandle(1)).is_ok());
+        ktest_assert!(sockets.get(SocketHandle(1)).is_err());
+        sockets.close_all();
+        ktest_assert!(sockets.get(SocketHandle(0)).is_err());
+        // Closing the bound socket freed its port
+        let socket = Socket::new();
+        ktest_assert_eq!(socket.bind(PORT), Ok(PORT));
+        socket.close();
+        Ok(())
+    }
+}
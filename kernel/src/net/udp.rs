@@ -0,0 +1,76 @@
+//! Sending UDP datagrams, for `super::netdump`.
+//!
+//! Nothing is received yet, so datagrams are only ever built in place in front of a payload, and
+//! sent without allocating or waiting for locks.
+
+use super::NetError;
+use super::ipv4::{self, Ipv4Address, PROTOCOL_UDP};
+
+const HEADER_LEN: usize = 8;
+/// Longest payload sent in one datagram.
+pub const MAX_PAYLOAD_LEN: usize = ipv4::MAX_PAYLOAD_LEN - HEADER_LEN;
+/// Where the payload starts in frames passed to `try_send_in_place`.
+pub const PAYLOAD_OFFSET: usize = ipv4::PAYLOAD_OFFSET + HEADER_LEN;
+
+/// Fills in the header at the start of `datagram`, with its checksum.
+fn write_header(
+    datagram: &mut [u8],
+    source: Ipv4Address,
+    source_port: u16,
+    destination: Ipv4Address,
+    destination_port: u16,
+) {
+    datagram[0..2].copy_from_slice(&source_port.to_be_bytes());
+    datagram[2..4].copy_from_slice(&destination_port.to_be_bytes());
+    let len = datagram.len();
+    datagram[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    datagram[6..8].fill(0);
+    let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_UDP, len);
+    // A checksum of 0 means there isn't one, and 0xFFFF is the same in one's complement
+    let checksum = match ipv4::checksum(datagram, sum) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Sends `frame[PAYLOAD_OFFSET..]` from `source_port` to `destination_port` on `destination`,
+/// filling in the headers before it. Like `ipv4::try_send_in_place`, never allocates or waits
+/// for a lock.
+pub fn try_send_in_place(
+    source_port: u16,
+    destination: Ipv4Address,
+    destination_port: u16,
+    frame: &mut [u8],
+) -> Result<(), NetError> {
+    let source = ipv4::local_address().ok_or(NetError::NotConfigured)?;
+    if frame.len() - PAYLOAD_OFFSET > MAX_PAYLOAD_LEN {
+        return Err(NetError::FrameTooLong);
+    }
+    write_header(
+        &mut frame[ipv4::PAYLOAD_OFFSET..],
+        source,
+        source_port,
+        destination,
+        destination_port,
+    );
+    ipv4::try_send_in_place(destination, PROTOCOL_UDP, frame)
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn writes_headers() -> TestResult {
+        let source = Ipv4Address([10, 0, 2, 15]);
+        let destination = Ipv4Address([10, 0, 2, 2]);
+        let mut datagram = *b"\0\0\0\0\0\0\0\0hello";
+        write_header(&mut datagram, source, 6665, destination, 6666);
+        ktest_assert_eq!(datagram[0..6], [0x1A, 0x09, 0x1A, 0x0A, 0, 13]);
+        let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_UDP, datagram.len());
+        ktest_assert_eq!(ipv4::checksum(&datagram, sum), 0);
+        Ok(())
+    }
+}
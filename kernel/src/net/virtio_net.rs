@@ -0,0 +1,437 @@
+//! Driver for virtio network devices on PCI, through the legacy I/O port interface.
+//!
+//! Each device has a receive and a transmit virtqueue, set up with a fixed buffer for each
+//! descriptor that's used, in physically contiguous memory. Every receive buffer is handed to the
+//! device up front. The receive queue raises an MSI-X interrupt when frames arrive, which
+//! schedules work to pass them to the receive callback and give the buffers back. Transmit
+//! interrupts are suppressed, and sent buffers are reclaimed on the next transmit instead.
+
+use super::{MAX_FRAME_LEN, MacAddress, NetDevice, NetError, ReceiveCallback};
+use crate::arch::msi::{self, AllocatedVector, MsiError};
+use crate::arch::page_allocation;
+use crate::arch::paging::PAGE_SIZE;
+use crate::arch::pci::{self, PciAddress};
+use crate::arch::port;
+use crate::work_queue::{self, Work};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{Ordering, fence};
+use spin::Mutex;
+
+const VENDOR_ID: u16 = 0x1AF4;
+/// Network device ID under the legacy interface.
+const DEVICE_ID: u16 = 0x1000;
+
+const MAX_DEVICES: usize = 4;
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+/// Most buffers used in each queue, less if the device's queues are smaller.
+const MAX_BUFFERS: u16 = 32;
+/// Room for the virtio header and the longest frame.
+const BUFFER_SIZE: usize = 2048;
+/// Legacy header in front of each frame, without mergeable receive buffers.
+const HEADER_LEN: usize = 10;
+/// The legacy interface gives queue addresses as page numbers.
+const QUEUE_ALIGN: usize = 4096;
+
+const _: () = assert!(HEADER_LEN + MAX_FRAME_LEN <= BUFFER_SIZE);
+const _: () = assert!(PAGE_SIZE.is_multiple_of(BUFFER_SIZE));
+
+mod register {
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const DRIVER_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0C;
+    pub const QUEUE_SELECT: u16 = 0x0E;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+    pub const CONFIG_VECTOR: u16 = 0x14;
+    pub const QUEUE_VECTOR: u16 = 0x16;
+    /// Device specific configuration, which starts here once MSI-X is enabled.
+    pub const CONFIG: u16 = 0x18;
+
+    pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+    pub const STATUS_DRIVER: u8 = 1 << 1;
+    pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+    pub const STATUS_FAILED: u8 = 1 << 7;
+
+    pub const NO_VECTOR: u16 = 0xFFFF;
+}
+
+const FEATURE_MAC: u32 = 1 << 5;
+
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+const AVAILABLE_NO_INTERRUPT: u16 = 1 << 0;
+
+static DEVICES: Mutex<Vec<Arc<VirtioNet>>> = Mutex::new(Vec::new());
+/// Receive work for each device, by index in `DEVICES`.
+static RECEIVE_WORKS: [Work; MAX_DEVICES] = {
+    let mut works = [const { Work::new(receive_work, 0) }; MAX_DEVICES];
+    let mut i = 0;
+    while i < MAX_DEVICES {
+        works[i] = Work::new(receive_work, i);
+        i += 1;
+    }
+    works
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VirtioNetError {
+    #[error("registers aren't in an I/O BAR")]
+    NoIoBar,
+    #[error("device doesn't provide a MAC address")]
+    NoMacAddress,
+    #[error("queue {0} not present")]
+    MissingQueue(u16),
+    #[error("device rejected the MSI-X vector")]
+    VectorRejected,
+    #[error("out of memory")]
+    OutOfMemory,
+    #[error("too many devices")]
+    TooManyDevices,
+    #[error("failed to set up MSI-X - {0}")]
+    Msi(#[from] MsiError),
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// Offset of the used ring, and the total size, of a legacy virtqueue with `size` entries.
+fn queue_layout(size: usize) -> (usize, usize) {
+    let available_end = size * size_of::<Descriptor>() + 6 + 2 * size;
+    let used_offset = available_end.next_multiple_of(QUEUE_ALIGN);
+    let used_end = used_offset + 6 + size * size_of::<UsedElement>();
+    (used_offset, used_end.next_multiple_of(QUEUE_ALIGN))
+}
+
+/// A virtqueue, in identity mapped memory, using the first `num_buffers` descriptors, each
+/// always pointing at the same buffer.
+struct Virtqueue {
+    index: u16,
+    size: u16,
+    descriptors: *mut Descriptor,
+    /// Flags, index, then the ring.
+    available: *mut u16,
+    /// Flags and index as one `u16` each, then the ring.
+    used: *const u16,
+    /// Physical address of the buffers.
+    buffers: usize,
+    num_buffers: u16,
+    /// Index of the next used ring entry to look at.
+    last_used: u16,
+}
+
+// The queue's memory is only reachable through this
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// Sets up queue `index`, which is device writable if `writable` is set.
+    fn new(io_base: u16, index: u16, writable: bool) -> Result<Self, VirtioNetError> {
+        let size = unsafe {
+            port::write_word(io_base + register::QUEUE_SELECT, index);
+            port::read_word(io_base + register::QUEUE_SIZE)
+        };
+        if size == 0 {
+            return Err(VirtioNetError::MissingQueue(index));
+        }
+        let (used_offset, queue_size) = queue_layout(size as usize);
+        let num_buffers = size.min(MAX_BUFFERS);
+        // The device is never removed, so neither is its memory
+        let memory = page_allocation::find_and_reserve_contiguous(queue_size / PAGE_SIZE)
+            .map_err(|_| VirtioNetError::OutOfMemory)?;
+        let buffers = page_allocation::find_and_reserve_contiguous(
+            (num_buffers as usize * BUFFER_SIZE).div_ceil(PAGE_SIZE),
+        )
+        .map_err(|_| VirtioNetError::OutOfMemory)?;
+        let queue = Self {
+            index,
+            size,
+            descriptors: memory as *mut Descriptor,
+            available: (memory + size as usize * size_of::<Descriptor>()) as *mut u16,
+            used: (memory + used_offset) as *const u16,
+            buffers,
+            num_buffers,
+            last_used: 0,
+        };
+        for id in 0..num_buffers {
+            let descriptor = Descriptor {
+                address: queue.buffer(id) as u64,
+                len: BUFFER_SIZE as u32,
+                flags: if writable { DESCRIPTOR_WRITE } else { 0 },
+                next: 0,
+            };
+            unsafe {
+                queue
+                    .descriptors
+                    .add(id as usize)
+                    .write_volatile(descriptor)
+            };
+        }
+        unsafe {
+            port::write_dword(
+                io_base + register::QUEUE_ADDRESS,
+                (memory / QUEUE_ALIGN) as u32,
+            );
+        }
+        Ok(queue)
+    }
+
+    fn buffer(&self, id: u16) -> usize {
+        self.buffers + id as usize * BUFFER_SIZE
+    }
+
+    fn set_available_flags(&mut self, flags: u16) {
+        unsafe { self.available.write_volatile(flags) };
+    }
+
+    /// Hands descriptor `id` to the device, with `len` bytes of its buffer used. The device
+    /// isn't notified.
+    fn push_available(&mut self, id: u16, len: usize) {
+        unsafe {
+            let descriptor = self.descriptors.add(id as usize);
+            (&raw mut (*descriptor).len).write_volatile(len as u32);
+            let index = self.available.add(1).read_volatile();
+            self.available
+                .add(2 + (index % self.size) as usize)
+                .write_volatile(id);
+            // The entry must be visible before the index that publishes it
+            fence(Ordering::SeqCst);
+            self.available.add(1).write_volatile(index.wrapping_add(1));
+        }
+    }
+
+    /// Takes the next descriptor the device has finished with, along with the number of bytes
+    /// it wrote.
+    fn pop_used(&mut self) -> Option<(u16, usize)> {
+        let index = unsafe { self.used.add(1).read_volatile() };
+        if index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = unsafe {
+            (self.used.add(2) as *const UsedElement)
+                .add((self.last_used % self.size) as usize)
+                .read_volatile()
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((element.id as u16, element.len as usize))
+    }
+
+    fn notify(&self, io_base: u16) {
+        fence(Ordering::SeqCst);
+        unsafe { port::write_word(io_base + register::QUEUE_NOTIFY, self.index) };
+    }
+}
+
+struct Transmitter {
+    queue: Virtqueue,
+    free_ids: Vec<u16>,
+}
+
+pub struct VirtioNet {
+    io_base: u16,
+    mac_address: MacAddress,
+    receive_queue: Mutex<Virtqueue>,
+    transmitter: Mutex<Transmitter>,
+    receive_callback: Mutex<Option<ReceiveCallback>>,
+    _vector: AllocatedVector,
+}
+
+impl VirtioNet {
+    fn transmit_locked(&self, transmitter: &mut Transmitter, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(NetError::FrameTooLong);
+        }
+        let Transmitter { queue, free_ids } = transmitter;
+        while let Some((id, _)) = queue.pop_used() {
+            free_ids.push(id);
+        }
+        let id = free_ids.pop().ok_or(NetError::QueueFull)?;
+        let buffer = queue.buffer(id) as *mut u8;
+        unsafe {
+            // No checksum offload or segmentation
+            core::ptr::write_bytes(buffer, 0, HEADER_LEN);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer.add(HEADER_LEN), frame.len());
+        }
+        queue.push_available(id, HEADER_LEN + frame.len());
+        queue.notify(self.io_base);
+        Ok(())
+    }
+
+    /// Passes every received frame to the receive callback, then gives their buffers back.
+    fn receive_pending(&self) {
+        let mut frame = [0; MAX_FRAME_LEN];
+        loop {
+            let frame_len = {
+                let mut queue = self.receive_queue.lock();
+                let Some((id, len)) = queue.pop_used() else {
+                    break;
+                };
+                let frame_len = len.saturating_sub(HEADER_LEN).min(MAX_FRAME_LEN);
+                let buffer = (queue.buffer(id) + HEADER_LEN) as *const u8;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(buffer, frame.as_mut_ptr(), frame_len);
+                }
+                queue.push_available(id, BUFFER_SIZE);
+                queue.notify(self.io_base);
+                frame_len
+            };
+            // Called without locks held, in case it transmits a reply
+            let callback = self.receive_callback.lock().clone();
+            if let Some(callback) = callback {
+                callback(&frame[..frame_len]);
+            }
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac_address(&self) -> MacAddress {
+        self.mac_address
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.transmit_locked(&mut self.transmitter.lock(), frame)
+    }
+
+    fn try_transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        let mut transmitter = self.transmitter.try_lock().ok_or(NetError::Busy)?;
+        self.transmit_locked(&mut transmitter, frame)
+    }
+
+    fn set_receive_callback(&self, callback: ReceiveCallback) {
+        *self.receive_callback.lock() = Some(callback);
+    }
+}
+
+fn receive_work(index: usize) {
+    let device = DEVICES.lock().get(index).cloned();
+    if let Some(device) = device {
+        device.receive_pending();
+    }
+}
+
+/// Resets and sets up the device at `address`, which will be at `index` in `DEVICES`.
+unsafe fn init_device(address: PciAddress, index: usize) -> Result<VirtioNet, VirtioNetError> {
+    use register::*;
+    if index >= MAX_DEVICES {
+        return Err(VirtioNetError::TooManyDevices);
+    }
+    let io_base = pci::io_bar_port(address, 0).ok_or(VirtioNetError::NoIoBar)?;
+    let command = pci::read_word(address, pci::REGISTER_COMMAND);
+    unsafe {
+        pci::write_word(
+            address,
+            pci::REGISTER_COMMAND,
+            command | pci::COMMAND_IO_SPACE | pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER,
+        );
+        port::write_byte(io_base + DEVICE_STATUS, 0);
+        port::write_byte(io_base + DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    }
+    let result = (|| {
+        let features = unsafe { port::read_dword(io_base + DEVICE_FEATURES) };
+        if features & FEATURE_MAC == 0 {
+            return Err(VirtioNetError::NoMacAddress);
+        }
+        unsafe { port::write_dword(io_base + DRIVER_FEATURES, FEATURE_MAC) };
+        // Moves the device configuration, so must come before reading the MAC address
+        let vector = msi::allocate_vector(move || {
+            work_queue::schedule(&RECEIVE_WORKS[index]);
+        })?;
+        unsafe { msi::enable_msix(address, 0, &vector)? };
+        let mut mac_address = [0; 6];
+        for (offset, byte) in mac_address.iter_mut().enumerate() {
+            *byte = unsafe { port::read_byte(io_base + CONFIG + offset as u16) };
+        }
+        let mut receive_queue = Virtqueue::new(io_base, RECEIVE_QUEUE, true)?;
+        let accepted_vector = unsafe {
+            port::write_word(io_base + CONFIG_VECTOR, NO_VECTOR);
+            port::write_word(io_base + QUEUE_VECTOR, 0);
+            port::read_word(io_base + QUEUE_VECTOR)
+        };
+        if accepted_vector == NO_VECTOR {
+            return Err(VirtioNetError::VectorRejected);
+        }
+        let mut transmit_queue = Virtqueue::new(io_base, TRANSMIT_QUEUE, false)?;
+        unsafe { port::write_word(io_base + QUEUE_VECTOR, NO_VECTOR) };
+        transmit_queue.set_available_flags(AVAILABLE_NO_INTERRUPT);
+        for id in 0..receive_queue.num_buffers {
+            receive_queue.push_available(id, BUFFER_SIZE);
+        }
+        unsafe {
+            port::write_byte(
+                io_base + DEVICE_STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+            );
+        }
+        receive_queue.notify(io_base);
+        Ok(VirtioNet {
+            io_base,
+            mac_address: MacAddress(mac_address),
+            receive_queue: Mutex::new(receive_queue),
+            transmitter: Mutex::new(Transmitter {
+                free_ids: (0..transmit_queue.num_buffers).collect(),
+                queue: transmit_queue,
+            }),
+            receive_callback: Mutex::new(None),
+            _vector: vector,
+        })
+    })();
+    if result.is_err() {
+        unsafe { port::write_byte(io_base + DEVICE_STATUS, STATUS_FAILED) };
+    }
+    result
+}
+
+/// Sets up every virtio network device on PCI, and registers them as network devices. Must be
+/// called after interrupts and the work queue are set up.
+pub fn init() {
+    let mut addresses = Vec::new();
+    pci::for_each_function(|address| {
+        if pci::read_word(address, pci::REGISTER_VENDOR_ID) == VENDOR_ID
+            && pci::read_word(address, pci::REGISTER_DEVICE_ID) == DEVICE_ID
+        {
+            addresses.push(address);
+        }
+    });
+    for address in addresses {
+        let mut devices = DEVICES.lock();
+        let device = match unsafe { init_device(address, devices.len()) } {
+            Ok(device) => Arc::new(device),
+            Err(err) => {
+                log::warn!("Failed to set up virtio network device at {address} - {err}");
+                continue;
+            }
+        };
+        devices.push(device.clone());
+        drop(devices);
+        super::register(device);
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn lays_out_legacy_queues() -> TestResult {
+        // QEMU's default queue size, with the used ring on the page after the available ring
+        ktest_assert_eq!(queue_layout(256), (8192, 12288));
+        ktest_assert_eq!(queue_layout(16), (4096, 8192));
+        Ok(())
+    }
+}
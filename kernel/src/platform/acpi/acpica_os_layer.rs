@@ -1,14 +1,27 @@
 #![allow(non_snake_case)]
 
-use super::acpica_sys::{Boolean, Status};
+use super::acpica_sys::{Boolean, InterruptHandler, PciId, Status};
+use crate::arch::apic::io::{Polarity, TriggerMode};
+use crate::arch::clock;
+use crate::arch::idt;
+use crate::arch::interrupts;
+use crate::arch::interrupts::routing::{self, GsiRoute};
 use crate::arch::page_allocation;
 use crate::arch::paging::PageTableEntry;
+use crate::arch::pci::{self, PciAddress};
+use crate::arch::port;
+use crate::kthread;
 use crate::logging::KERNEL_LOGGER;
+use crate::memory_tag::MemoryTag;
+use crate::sync::{IrqMutex, RawIrqSpinlock, Semaphore};
+use crate::wait_queue::WaitQueue;
+use crate::work_queue::{Work, WorkQueue};
 use alloc::alloc::{Layout, alloc, dealloc};
 use alloc::boxed::Box;
 use core::ffi::{CStr, VaList, c_char};
 use core::fmt::Write;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use spin::Mutex;
 
 pub static RSDP_ADDRESS: Mutex<usize> = Mutex::new(0);
@@ -84,16 +97,43 @@ extern "C" fn AcpiOsGetPhysicalAddress(
     Status::OK
 }
 
+/// Space before each ACPICA allocation holding its size, as `AcpiOsFree` isn't given it. Keeps
+/// the allocation itself 16 byte aligned.
+const ALLOCATION_HEADER_SIZE: usize = 16;
+const ALLOCATION_ALIGN: usize = 16;
+
+/// Returns the layout of an ACPICA allocation of `size` bytes, including the header.
+fn allocation_layout(size: usize) -> Option<Layout> {
+    let size = size.checked_add(ALLOCATION_HEADER_SIZE)?;
+    Layout::from_size_align(size, ALLOCATION_ALIGN).ok()
+}
+
 #[unsafe(no_mangle)]
 unsafe extern "C" fn AcpiOsAllocate(size: usize) -> *mut u8 {
-    unsafe { alloc(Layout::from_size_align(size, 8).unwrap()) }
+    let _tag = MemoryTag::Acpi.enter();
+    let Some(layout) = allocation_layout(size) else {
+        return core::ptr::null_mut();
+    };
+    unsafe {
+        let header = alloc(layout);
+        if header.is_null() {
+            return header;
+        }
+        (header as *mut usize).write(size);
+        header.add(ALLOCATION_HEADER_SIZE)
+    }
 }
 
 #[unsafe(no_mangle)]
 unsafe extern "C" fn AcpiOsFree(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
     unsafe {
-        // SAFETY: Current heap allocator does not rely on knowing size of allocation
-        dealloc(ptr, Layout::from_size_align(8, 8).unwrap())
+        let header = ptr.sub(ALLOCATION_HEADER_SIZE);
+        let size = (header as *const usize).read();
+        // Allocated with this layout, so it's valid
+        dealloc(header, allocation_layout(size).unwrap_unchecked())
     }
 }
 
@@ -108,64 +148,71 @@ extern "C" fn AcpiOsWritable(virtual_address_start: usize, len: usize) -> Boolea
 }
 
 // Mutual exclusion and synchronization
-// We use dummy synchronization primitives here, as we're only running ACPICA single threaded.
+// Handles are leaked boxes. ACPICA mutexes are semaphores with a single unit, and locks are
+// spinlocks which disable interrupts, as they're also taken in the SCI handler.
+
+/// `ACPI_WAIT_FOREVER`, as a timeout in milliseconds.
+const WAIT_FOREVER: u16 = 0xFFFF;
+
+/// Takes units from a semaphore, with an ACPICA timeout in milliseconds.
+fn acquire_semaphore(semaphore: &Semaphore, units: u32, timeout: u16) -> Status {
+    let acquired = match timeout {
+        0 => semaphore.try_acquire(units),
+        WAIT_FOREVER => {
+            semaphore.acquire(units);
+            true
+        }
+        timeout_ms => {
+            semaphore.acquire_until(units, clock::now_ns() + timeout_ms as u64 * 1_000_000)
+        }
+    };
+    match acquired {
+        true => Status::OK,
+        false => Status::TIME,
+    }
+}
 
 #[unsafe(no_mangle)]
-unsafe extern "C" fn AcpiOsCreateMutex(out_handle: Option<&mut *mut bool>) -> Status {
+unsafe extern "C" fn AcpiOsCreateMutex(out_handle: Option<&mut *mut Semaphore>) -> Status {
     let Some(out_handle) = out_handle else {
         return Status::BAD_PARAMETER;
     };
-    let Ok(dummy_mutex) = Box::try_new(false) else {
+    let Ok(mutex) = Box::try_new(Semaphore::new(1)) else {
         return Status::NO_MEMORY;
     };
-    *out_handle = Box::leak(dummy_mutex) as *mut bool;
+    *out_handle = Box::into_raw(mutex);
     Status::OK
 }
 
 #[unsafe(no_mangle)]
-unsafe extern "C" fn AcpiOsDeleteMutex(handle: Option<NonNull<bool>>) {
-    unsafe {
-        let Some(handle) = handle else {
-            return;
-        };
-        drop(Box::from_raw(handle.as_ptr()));
-    }
+unsafe extern "C" fn AcpiOsDeleteMutex(handle: Option<NonNull<Semaphore>>) {
+    let Some(handle) = handle else {
+        return;
+    };
+    drop(unsafe { Box::from_raw(handle.as_ptr()) });
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsAcquireMutex(handle: Option<&mut bool>, timeout: u16) -> Status {
-    let Some(dummy_mutex) = handle else {
+extern "C" fn AcpiOsAcquireMutex(handle: Option<&Semaphore>, timeout: u16) -> Status {
+    let Some(mutex) = handle else {
         return Status::BAD_PARAMETER;
     };
-    if *dummy_mutex {
-        if timeout == 0xFFFF {
-            panic!("mutex poisoned");
-        } else {
-            Status::TIME
-        }
-    } else {
-        *dummy_mutex = true;
-        Status::OK
-    }
+    acquire_semaphore(mutex, 1, timeout)
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsReleaseMutex(handle: Option<&mut bool>) {
-    let Some(dummy_mutex) = handle else {
+extern "C" fn AcpiOsReleaseMutex(handle: Option<&Semaphore>) {
+    let Some(mutex) = handle else {
         return;
     };
-    if *dummy_mutex {
-        *dummy_mutex = false;
-    } else {
-        panic!("mutex poisoned");
-    }
+    mutex.release(1);
 }
 
 #[unsafe(no_mangle)]
 unsafe extern "C" fn AcpiOsCreateSemaphore(
     max_units: u32,
     initial_units: u32,
-    out_handle: Option<&mut *mut ()>,
+    out_handle: Option<&mut *mut Semaphore>,
 ) -> Status {
     if initial_units > max_units {
         return Status::BAD_PARAMETER;
@@ -173,48 +220,77 @@ unsafe extern "C" fn AcpiOsCreateSemaphore(
     let Some(out_handle) = out_handle else {
         return Status::BAD_PARAMETER;
     };
-    *out_handle = core::ptr::null_mut();
+    // As on other hosts, the maximum isn't enforced on signalling
+    let Ok(semaphore) = Box::try_new(Semaphore::new(initial_units)) else {
+        return Status::NO_MEMORY;
+    };
+    *out_handle = Box::into_raw(semaphore);
     Status::OK
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsDeleteSemaphore(_handle: Option<NonNull<()>>) -> Status {
+unsafe extern "C" fn AcpiOsDeleteSemaphore(handle: Option<NonNull<Semaphore>>) -> Status {
+    let Some(handle) = handle else {
+        return Status::BAD_PARAMETER;
+    };
+    drop(unsafe { Box::from_raw(handle.as_ptr()) });
     Status::OK
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsWaitSemaphore(
-    _handle: Option<NonNull<()>>,
-    _units: u32,
-    _timeout: u16,
-) -> Status {
-    Status::OK
+extern "C" fn AcpiOsWaitSemaphore(handle: Option<&Semaphore>, units: u32, timeout: u16) -> Status {
+    let Some(semaphore) = handle else {
+        return Status::BAD_PARAMETER;
+    };
+    acquire_semaphore(semaphore, units, timeout)
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsSignalSemaphore(_handle: Option<NonNull<bool>>, _units: u32) -> Status {
+extern "C" fn AcpiOsSignalSemaphore(handle: Option<&Semaphore>, units: u32) -> Status {
+    let Some(semaphore) = handle else {
+        return Status::BAD_PARAMETER;
+    };
+    semaphore.release(units);
     Status::OK
 }
 
 #[unsafe(no_mangle)]
-unsafe extern "C" fn AcpiOsCreateLock(out_handle: Option<&mut *mut ()>) -> Status {
+unsafe extern "C" fn AcpiOsCreateLock(out_handle: Option<&mut *mut RawIrqSpinlock>) -> Status {
     let Some(out_handle) = out_handle else {
         return Status::BAD_PARAMETER;
     };
-    *out_handle = core::ptr::null_mut();
+    let Ok(lock) = Box::try_new(RawIrqSpinlock::new()) else {
+        return Status::NO_MEMORY;
+    };
+    *out_handle = Box::into_raw(lock);
     Status::OK
 }
 
 #[unsafe(no_mangle)]
-unsafe extern "C" fn AcpiOsDeleteLock(_handle: Option<NonNull<()>>) {}
+unsafe extern "C" fn AcpiOsDeleteLock(handle: Option<NonNull<RawIrqSpinlock>>) {
+    let Some(handle) = handle else {
+        return;
+    };
+    drop(unsafe { Box::from_raw(handle.as_ptr()) });
+}
 
+/// Returns flags for `AcpiOsReleaseLock`, which are unused as the lock keeps the interrupt state
+/// itself.
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsAcquireLock(_handle: Option<&mut ()>) -> usize {
+extern "C" fn AcpiOsAcquireLock(handle: Option<&RawIrqSpinlock>) -> usize {
+    if let Some(lock) = handle {
+        lock.lock();
+    }
     0
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsReleaseLock(_handle: Option<&mut ()>, _flags: usize) {}
+extern "C" fn AcpiOsReleaseLock(handle: Option<&RawIrqSpinlock>, _flags: usize) {
+    if let Some(lock) = handle {
+        // ACPICA only releases locks it holds
+        unsafe { lock.unlock() };
+    }
+}
 
 // Printing functions
 
@@ -661,85 +737,356 @@ unsafe extern "C" fn AcpiOsVprintf(format_string_ptr: *const c_char, mut args: V
 
 #[unsafe(no_mangle)]
 extern "C" fn AcpiOsGetThreadId() -> u64 {
-    1
+    // ACPICA reserves 0, so this is always valid even before threads are initialised
+    kthread::current().map_or(1, |id| id.as_u64())
+}
+
+/// Most `AcpiOsExecute` callbacks which can be queued at once. Callbacks are queued from GPE
+/// handlers in interrupt context, so slots are preallocated rather than allocated per callback.
+const MAX_EXECUTE_CALLBACKS: usize = 32;
+
+/// Runs `AcpiOsExecute` callbacks, separately from the kernel work queue as AML methods can
+/// sleep for a long time.
+static EXECUTE_WORK_QUEUE: WorkQueue = WorkQueue::new("kacpid");
+static EXECUTE_SLOTS: [ExecuteSlot; MAX_EXECUTE_CALLBACKS] = ExecuteSlot::new_all();
+/// Number of `AcpiOsExecute` callbacks which have not yet finished.
+static PENDING_EXECUTE_CALLBACKS: AtomicUsize = AtomicUsize::new(0);
+/// Woken when `PENDING_EXECUTE_CALLBACKS` reaches zero.
+static EXECUTE_CALLBACKS_COMPLETE: WaitQueue = WaitQueue::new();
+
+struct ExecuteCallback {
+    function: extern "C" fn(*mut ()),
+    context: *mut (),
+}
+
+// The context is owned by ACPICA, which expects the callback to run on another thread
+unsafe impl Send for ExecuteCallback {}
+
+struct ExecuteSlot {
+    in_use: AtomicBool,
+    callback: IrqMutex<Option<ExecuteCallback>>,
+    work: Work,
+}
+
+impl ExecuteSlot {
+    const fn new_all() -> [Self; MAX_EXECUTE_CALLBACKS] {
+        let mut slots = [const {
+            Self {
+                in_use: AtomicBool::new(false),
+                callback: IrqMutex::new(None),
+                work: Work::new(run_execute_callback, 0),
+            }
+        }; MAX_EXECUTE_CALLBACKS];
+        let mut i = 0;
+        while i < MAX_EXECUTE_CALLBACKS {
+            slots[i].work = Work::new(run_execute_callback, i);
+            i += 1;
+        }
+        slots
+    }
+}
+
+/// Starts the thread which runs `AcpiOsExecute` callbacks. Callbacks queued before this are run
+/// once it starts.
+pub fn start_execute_worker() -> Result<(), kthread::SpawnError> {
+    EXECUTE_WORK_QUEUE.start()
+}
+
+fn run_execute_callback(slot_index: usize) {
+    let slot = &EXECUTE_SLOTS[slot_index];
+    let callback = slot.callback.lock().take().unwrap();
+    slot.in_use.store(false, Ordering::Release);
+    (callback.function)(callback.context);
+    if PENDING_EXECUTE_CALLBACKS.fetch_sub(1, Ordering::AcqRel) == 1 {
+        EXECUTE_CALLBACKS_COMPLETE.wake_all();
+    }
 }
 
+/// Queues `function` to be run on the ACPICA worker thread. Safe to call from interrupt
+/// handlers.
 #[unsafe(no_mangle)]
 extern "C" fn AcpiOsExecute(
     _execute_type: usize,
-    _function: *const (),
-    _context: *const (),
+    function: Option<extern "C" fn(*mut ())>,
+    context: *mut (),
 ) -> Status {
-    unimplemented!();
+    let Some(function) = function else {
+        return Status::BAD_PARAMETER;
+    };
+    let Some(slot) = EXECUTE_SLOTS
+        .iter()
+        .find(|slot| !slot.in_use.swap(true, Ordering::Acquire))
+    else {
+        log::error!("AcpiOsExecute failed - too many callbacks queued");
+        return Status::NO_MEMORY;
+    };
+    *slot.callback.lock() = Some(ExecuteCallback { function, context });
+    PENDING_EXECUTE_CALLBACKS.fetch_add(1, Ordering::AcqRel);
+    EXECUTE_WORK_QUEUE.schedule(&slot.work);
+    Status::OK
 }
 
+/// Blocks the calling thread. Only called from AML `Sleep`, which never runs in interrupt context.
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsSleep(_: u64) {
-    unimplemented!();
+extern "C" fn AcpiOsSleep(milliseconds: u64) {
+    kthread::sleep_ms(milliseconds);
 }
 
+/// Busy waits, for AML `Stall` and hardware delays which may be in interrupt context.
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsStall(_: u32) {
-    unimplemented!();
+extern "C" fn AcpiOsStall(microseconds: u32) {
+    clock::stall_ns(microseconds as u64 * 1000);
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn AcpiOsWaitEventsComplete() {
-    unimplemented!();
+    EXECUTE_CALLBACKS_COMPLETE
+        .wait_until(|| PENDING_EXECUTE_CALLBACKS.load(Ordering::Acquire) == 0);
 }
 
+// Global lock, shared with the firmware through the FACS
+
+const GLOBAL_LOCK_PENDING: u32 = 1 << 0;
+const GLOBAL_LOCK_OWNED: u32 = 1 << 1;
+
+/// Returns non-zero if the lock was acquired. Otherwise the lock is marked as pending, and the
+/// firmware signals when it releases it.
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsAcquireGlobalLock(_lock: *const u32) -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsAcquireGlobalLock(lock: *mut u32) -> i32 {
+    let lock = unsafe { AtomicU32::from_ptr(lock) };
+    let mut new = 0;
+    _ = lock.fetch_update(Ordering::AcqRel, Ordering::Acquire, |old| {
+        new = (old & !GLOBAL_LOCK_PENDING) | GLOBAL_LOCK_OWNED;
+        if old & GLOBAL_LOCK_OWNED != 0 {
+            new |= GLOBAL_LOCK_PENDING;
+        }
+        Some(new)
+    });
+    (new & GLOBAL_LOCK_PENDING == 0) as i32
 }
 
+/// Returns non-zero if the firmware is waiting for the lock, and needs to be signalled.
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsReleaseGlobalLock(_lock: *const u32) -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsReleaseGlobalLock(lock: *mut u32) -> i32 {
+    let lock = unsafe { AtomicU32::from_ptr(lock) };
+    let old = lock.fetch_and(!(GLOBAL_LOCK_PENDING | GLOBAL_LOCK_OWNED), Ordering::AcqRel);
+    (old & GLOBAL_LOCK_PENDING != 0) as i32
+}
+
+// Interrupts
+
+struct InstalledInterruptHandler {
+    interrupt_number: u32,
+    gsi: u32,
+    handler: InterruptHandler,
+    context: *mut (),
+}
+
+unsafe impl Send for InstalledInterruptHandler {}
+
+/// ACPICA only installs a handler for the SCI, so only one is supported.
+static INTERRUPT_HANDLER: IrqMutex<Option<InstalledInterruptHandler>> = IrqMutex::new(None);
+
+unsafe extern "x86-interrupt" fn acpi_interrupt(_interrupt_frame: idt::InterruptFrame) {
+    let handler = INTERRUPT_HANDLER
+        .lock()
+        .as_ref()
+        .map(|installed| (installed.handler, installed.context));
+    if let Some((handler, context)) = handler {
+        unsafe { handler(context) };
+    }
+    interrupts::signal_eoi();
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn AcpiOsInstallInterruptHandler(
-    _interrupt_level: u32,
-    _handler: *const (),
-    _context: *const (),
+    interrupt_number: u32,
+    handler: Option<InterruptHandler>,
+    context: *mut (),
 ) -> Status {
-    unimplemented!();
+    let Some(handler) = handler else {
+        return Status::BAD_PARAMETER;
+    };
+    let route = sci_route(interrupt_number);
+    {
+        let mut installed = INTERRUPT_HANDLER.lock();
+        if installed.is_some() {
+            return Status::ALREADY_EXISTS;
+        }
+        *installed = Some(InstalledInterruptHandler {
+            interrupt_number,
+            gsi: route.gsi,
+            handler,
+            context,
+        });
+    }
+    match unsafe { routing::map_gsi(route, acpi_interrupt) } {
+        Ok(vector) => {
+            log::debug!("ACPI SCI on GSI {} at vector {vector}", route.gsi);
+            Status::OK
+        }
+        Err(err) => {
+            log::error!("Failed to route ACPI SCI - {err}");
+            *INTERRUPT_HANDLER.lock() = None;
+            Status::SUPPORT
+        }
+    }
+}
+
+/// Returns the route of the SCI, given as an ISA IRQ which may be overridden in the MADT. The
+/// SCI is shareable, so it's always level triggered and active low.
+fn sci_route(interrupt_number: u32) -> GsiRoute {
+    let gsi = match u8::try_from(interrupt_number).map(routing::legacy_irq_route) {
+        Ok(Ok(route)) => route.gsi,
+        _ => interrupt_number,
+    };
+    GsiRoute {
+        gsi,
+        polarity: Polarity::Low,
+        trigger_mode: TriggerMode::LevelSensitive,
+    }
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsRemoveInterruptHandler(_interrupt_number: u32, _handler: *const ()) -> Status {
-    unimplemented!();
+extern "C" fn AcpiOsRemoveInterruptHandler(
+    interrupt_number: u32,
+    _handler: Option<InterruptHandler>,
+) -> Status {
+    let mut installed = INTERRUPT_HANDLER.lock();
+    match installed.as_ref() {
+        Some(handler) if handler.interrupt_number == interrupt_number => {
+            unsafe {
+                routing::unmap_gsi(handler.gsi);
+            }
+            *installed = None;
+            Status::OK
+        }
+        _ => Status::NOT_EXIST,
+    }
 }
 
+// Memory and port access
+
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsReadMemory(_address: usize, _value: *const u64, _width: u32) -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsReadMemory(address: u64, value: *mut u64, width: u32) -> Status {
+    // All of physical memory is identity mapped
+    unsafe {
+        let address = address as usize;
+        *value = match width {
+            8 => (address as *const u8).read_volatile() as u64,
+            16 => (address as *const u16).read_volatile() as u64,
+            32 => (address as *const u32).read_volatile() as u64,
+            64 => (address as *const u64).read_volatile(),
+            _ => return Status::BAD_PARAMETER,
+        };
+    }
+    Status::OK
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsWriteMemory(_address: usize, _value: u64, _width: u32) -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsWriteMemory(address: u64, value: u64, width: u32) -> Status {
+    unsafe {
+        let address = address as usize;
+        match width {
+            8 => (address as *mut u8).write_volatile(value as u8),
+            16 => (address as *mut u16).write_volatile(value as u16),
+            32 => (address as *mut u32).write_volatile(value as u32),
+            64 => (address as *mut u64).write_volatile(value),
+            _ => return Status::BAD_PARAMETER,
+        }
+    }
+    Status::OK
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsReadPort(_address: usize, _value: *const u32, _width: u32) -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsReadPort(address: u64, value: *mut u32, width: u32) -> Status {
+    let Ok(port) = u16::try_from(address) else {
+        return Status::BAD_PARAMETER;
+    };
+    unsafe {
+        *value = match width {
+            8 => port::read_byte(port) as u32,
+            16 => port::read_word(port) as u32,
+            32 => port::read_dword(port),
+            _ => return Status::BAD_PARAMETER,
+        };
+    }
+    Status::OK
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsWritePort(_address: usize, _value: u32, _width: u32) -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsWritePort(address: u64, value: u32, width: u32) -> Status {
+    let Ok(port) = u16::try_from(address) else {
+        return Status::BAD_PARAMETER;
+    };
+    unsafe {
+        match width {
+            8 => port::write_byte(port, value as u8),
+            16 => port::write_word(port, value as u16),
+            32 => port::write_dword(port, value),
+            _ => return Status::BAD_PARAMETER,
+        }
+    }
+    Status::OK
+}
+
+/// Converts an ACPICA PCI ID and register offset, checking they're reachable and that the
+/// register is aligned to `width`.
+fn pci_register(pci_id: &PciId, register: u32, width: u32) -> Option<(PciAddress, u8)> {
+    let width_bytes = width / 8;
+    if pci_id.segment != 0
+        || pci_id.bus > 0xFF
+        || pci_id.device > 0x1F
+        || pci_id.function > 0x7
+        || register > 0xFF
+        || !matches!(width_bytes, 1 | 2 | 4)
+        || !register.is_multiple_of(width_bytes)
+    {
+        return None;
+    }
+    let address = PciAddress::new(pci_id.bus as u8, pci_id.device as u8, pci_id.function as u8);
+    Some((address, register as u8))
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsReadPciConfiguration() -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsReadPciConfiguration(
+    pci_id: &PciId,
+    register: u32,
+    value: *mut u64,
+    width: u32,
+) -> Status {
+    let Some((address, register)) = pci_register(pci_id, register, width) else {
+        return Status::SUPPORT;
+    };
+    let read_value = match width {
+        8 => pci::read_byte(address, register) as u64,
+        16 => pci::read_word(address, register) as u64,
+        _ => pci::read_dword(address, register) as u64,
+    };
+    unsafe {
+        *value = read_value;
+    }
+    Status::OK
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsWritePciConfiguration() -> Status {
-    unimplemented!();
+unsafe extern "C" fn AcpiOsWritePciConfiguration(
+    pci_id: &PciId,
+    register: u32,
+    value: u64,
+    width: u32,
+) -> Status {
+    let Some((address, register)) = pci_register(pci_id, register, width) else {
+        return Status::SUPPORT;
+    };
+    unsafe {
+        match width {
+            8 => pci::write_byte(address, register, value as u8),
+            16 => pci::write_word(address, register, value as u16),
+            _ => pci::write_dword(address, register, value as u32),
+        }
+    }
+    Status::OK
 }
 
 #[unsafe(no_mangle)]
@@ -747,17 +1094,50 @@ extern "C" fn AcpiOsRedirectOutput(_destination: *const ()) -> Status {
     unimplemented!();
 }
 
+/// Returns the current time in 100ns units. Used by AML `While` loop timeouts.
+///
+/// ACPICA is initialised before the counter is calibrated, so this returns 0 until then rather
+/// than panicking.
 #[unsafe(no_mangle)]
 extern "C" fn AcpiOsGetTimer() -> u64 {
-    unimplemented!();
+    clock::try_now_ns().unwrap_or(0) / 100
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn AcpiOsSignal(_function: u32, _info: *const ()) -> Status {
-    unimplemented!();
+extern "C" fn AcpiOsSignal(function: u32, _info: *const ()) -> Status {
+    const SIGNAL_FATAL: u32 = 0;
+    const SIGNAL_BREAKPOINT: u32 = 1;
+    match function {
+        SIGNAL_FATAL => log::error!("AML signalled a fatal error"),
+        SIGNAL_BREAKPOINT => log::debug!("AML breakpoint reached"),
+        _ => return Status::BAD_PARAMETER,
+    }
+    Status::OK
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn AcpiOsEnterSleep(_sleep_state: u8, _rega_value: u32, _regb_value: u32) -> Status {
-    unimplemented!();
+    // Nothing to do before ACPICA writes the sleep registers
+    Status::OK
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert;
+
+    #[kernel_test]
+    fn allocations_round_trip() -> TestResult {
+        for size in [0, 1, 24, 4096] {
+            let ptr = unsafe { AcpiOsAllocate(size) };
+            ktest_assert!(!ptr.is_null());
+            ktest_assert!((ptr as usize).is_multiple_of(ALLOCATION_ALIGN));
+            unsafe {
+                core::ptr::write_bytes(ptr, 0xAA, size);
+                AcpiOsFree(ptr);
+            }
+        }
+        ktest_assert!(allocation_layout(usize::MAX).is_none());
+        Ok(())
+    }
 }
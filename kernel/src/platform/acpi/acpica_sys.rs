@@ -20,6 +20,9 @@ impl Status {
     pub const OK: Status = Status(0);
     // Environmental exceptions
     pub const NO_MEMORY: Status = Status::new(Code::Environment, 0x4);
+    pub const NOT_EXIST: Status = Status::new(Code::Environment, 0x6);
+    pub const ALREADY_EXISTS: Status = Status::new(Code::Environment, 0x7);
+    pub const SUPPORT: Status = Status::new(Code::Environment, 0xF);
     pub const TIME: Status = Status::new(Code::Environment, 0x11);
     // Programmer exceptions
     pub const BAD_PARAMETER: Status = Status::new(Code::Programmer, 0x1);
@@ -56,9 +59,20 @@ impl From<Boolean> for bool {
 pub mod subsystem {
     use super::Status;
 
+    pub const FULL_INITIALISATION: u32 = 0x00;
+
     unsafe extern "C" {
         #[link_name = "AcpiInitializeSubsystem"]
         pub unsafe fn initialise() -> Status;
+
+        #[link_name = "AcpiLoadTables"]
+        pub unsafe fn load_tables() -> Status;
+
+        #[link_name = "AcpiEnableSubsystem"]
+        pub unsafe fn enable(flags: u32) -> Status;
+
+        #[link_name = "AcpiInitializeObjects"]
+        pub unsafe fn initialise_objects(flags: u32) -> Status;
     }
 }
 
@@ -80,5 +94,285 @@ pub mod table_manager {
             instance: u32,
             out_table: &mut *const (),
         ) -> Status;
+
+        #[link_name = "AcpiGetTableByIndex"]
+        pub unsafe fn get_table_by_index(index: u32, out_table: &mut *const ()) -> Status;
+    }
+}
+
+/// `ACPI_PCI_ID`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PciId {
+    pub segment: u16,
+    pub bus: u16,
+    pub device: u16,
+    pub function: u16,
+}
+
+/// `ACPI_OSD_HANDLER`
+pub type InterruptHandler = unsafe extern "C" fn(context: *mut ()) -> u32;
+
+/// Opaque reference to a namespace node.
+pub type Handle = *mut ();
+
+pub const ROOT_OBJECT: Handle = usize::MAX as Handle;
+
+/// `ACPI_OBJECT_TYPE`
+pub mod object_type {
+    pub const ANY: u32 = 0x00;
+    pub const INTEGER: u32 = 0x01;
+    pub const BUFFER: u32 = 0x03;
+    pub const PACKAGE: u32 = 0x04;
+    pub const DEVICE: u32 = 0x06;
+    pub const PROCESSOR: u32 = 0x0C;
+    pub const THERMAL: u32 = 0x0D;
+}
+
+/// `ACPI_BUFFER`
+#[repr(C)]
+pub struct Buffer {
+    pub length: usize,
+    pub pointer: *mut (),
+}
+
+/// The integer variant of `ACPI_OBJECT`, padded out to the size of the full union.
+#[repr(C)]
+pub struct IntegerObject {
+    pub object_type: u32,
+    pub value: u64,
+    _padding: u64,
+}
+
+impl IntegerObject {
+    pub const fn zeroed() -> Self {
+        Self {
+            object_type: 0,
+            value: 0,
+            _padding: 0,
+        }
+    }
+
+    pub const fn new(value: u64) -> Self {
+        Self {
+            object_type: object_type::INTEGER,
+            value,
+            _padding: 0,
+        }
+    }
+}
+
+/// `ACPI_OBJECT_LIST`, for passing arguments to methods.
+#[repr(C)]
+pub struct ObjectList {
+    pub count: u32,
+    pub pointer: *mut IntegerObject,
+}
+
+/// The buffer variant of `ACPI_OBJECT`. `pointer` points into the same `ACPI_BUFFER` allocation.
+#[repr(C)]
+pub struct BufferObject {
+    pub object_type: u32,
+    pub length: u32,
+    pub pointer: *const u8,
+}
+
+/// The package variant of `ACPI_OBJECT`. `elements` points to `count` full size objects in the
+/// same `ACPI_BUFFER` allocation.
+#[repr(C)]
+pub struct PackageObject {
+    pub object_type: u32,
+    pub count: u32,
+    pub elements: *const IntegerObject,
+}
+
+/// The processor variant of `ACPI_OBJECT`, from the legacy `Processor` declaration.
+#[repr(C)]
+pub struct ProcessorObject {
+    pub object_type: u32,
+    pub processor_id: u32,
+    /// I/O port of the processor control block, or 0 if there isn't one.
+    pub block_address: u64,
+    pub block_length: u32,
+}
+
+pub type WalkCallback = unsafe extern "C" fn(
+    object: Handle,
+    nesting_level: u32,
+    context: *mut (),
+    return_value: *mut *mut (),
+) -> Status;
+
+pub mod namespace {
+    use super::*;
+
+    pub const SINGLE_NAME: u32 = 1;
+
+    unsafe extern "C" {
+        #[link_name = "AcpiWalkNamespace"]
+        pub unsafe fn walk(
+            object_type: u32,
+            start_object: Handle,
+            max_depth: u32,
+            descending_callback: Option<WalkCallback>,
+            ascending_callback: Option<WalkCallback>,
+            context: *mut (),
+            return_value: *mut *mut (),
+        ) -> Status;
+
+        #[link_name = "AcpiEvaluateObject"]
+        pub unsafe fn evaluate_object(
+            object: Handle,
+            pathname: *const core::ffi::c_char,
+            parameter_objects: *const ObjectList,
+            return_object_buffer: *mut Buffer,
+        ) -> Status;
+
+        #[link_name = "AcpiGetName"]
+        pub unsafe fn get_name(object: Handle, name_type: u32, out_name: *mut Buffer) -> Status;
+
+        #[link_name = "AcpiGetHandle"]
+        pub unsafe fn get_handle(
+            parent: Handle,
+            pathname: *const core::ffi::c_char,
+            out_handle: *mut Handle,
+        ) -> Status;
+
+        #[link_name = "AcpiGetIrqRoutingTable"]
+        pub unsafe fn get_irq_routing_table(device: Handle, out_buffer: *mut Buffer) -> Status;
+
+        #[link_name = "AcpiGetDevices"]
+        pub unsafe fn get_devices(
+            hardware_id: *const core::ffi::c_char,
+            callback: Option<WalkCallback>,
+            context: *mut (),
+            return_value: *mut *mut (),
+        ) -> Status;
+    }
+}
+
+pub mod hardware {
+    use super::Status;
+
+    unsafe extern "C" {
+        #[link_name = "AcpiEnterSleepStatePrep"]
+        pub unsafe fn enter_sleep_state_prep(sleep_state: u8) -> Status;
+
+        #[link_name = "AcpiEnterSleepState"]
+        pub unsafe fn enter_sleep_state(sleep_state: u8) -> Status;
+    }
+}
+
+/// `ACPI_ADR_SPACE_*` operation region handlers.
+pub mod address_space {
+    use super::*;
+
+    pub const EMBEDDED_CONTROLLER: u8 = 3;
+
+    pub const READ: u32 = 0;
+    pub const WRITE: u32 = 1;
+
+    pub type Handler = unsafe extern "C" fn(
+        function: u32,
+        address: u64,
+        bit_width: u32,
+        value: *mut u64,
+        handler_context: *mut (),
+        region_context: *mut (),
+    ) -> Status;
+
+    pub type Setup = unsafe extern "C" fn(
+        region_handle: Handle,
+        function: u32,
+        handler_context: *mut (),
+        region_context: *mut *mut (),
+    ) -> Status;
+
+    unsafe extern "C" {
+        #[link_name = "AcpiInstallAddressSpaceHandler"]
+        pub unsafe fn install_handler(
+            device: Handle,
+            space_id: u8,
+            handler: Option<Handler>,
+            setup: Option<Setup>,
+            context: *mut (),
+        ) -> Status;
+    }
+}
+
+/// General purpose events.
+pub mod gpe {
+    use super::*;
+
+    pub const EDGE_TRIGGERED: u32 = 0x00;
+
+    pub const INTERRUPT_HANDLED: u32 = 0x01;
+    pub const REENABLE: u32 = 0x80;
+
+    pub type Handler =
+        unsafe extern "C" fn(gpe_device: Handle, gpe_number: u32, context: *mut ()) -> u32;
+
+    unsafe extern "C" {
+        #[link_name = "AcpiInstallGpeHandler"]
+        pub unsafe fn install_handler(
+            gpe_device: Handle,
+            gpe_number: u32,
+            handler_type: u32,
+            handler: Option<Handler>,
+            context: *mut (),
+        ) -> Status;
+
+        #[link_name = "AcpiEnableGpe"]
+        pub unsafe fn enable(gpe_device: Handle, gpe_number: u32) -> Status;
+
+        #[link_name = "AcpiDisableGpe"]
+        pub unsafe fn disable(gpe_device: Handle, gpe_number: u32) -> Status;
+
+        /// Enables every GPE with a `_Lxx` or `_Exx` method and no handler installed.
+        #[link_name = "AcpiUpdateAllGpes"]
+        pub unsafe fn update_all() -> Status;
+    }
+}
+
+/// Fixed events, signalled through the fixed hardware registers rather than a GPE.
+pub mod event {
+    use super::*;
+
+    pub const POWER_BUTTON: u32 = 2;
+
+    pub const INTERRUPT_HANDLED: u32 = 0x01;
+
+    /// Called from the SCI handler.
+    pub type Handler = unsafe extern "C" fn(context: *mut ()) -> u32;
+
+    unsafe extern "C" {
+        /// Installs a handler for a fixed event, and enables the event.
+        #[link_name = "AcpiInstallFixedEventHandler"]
+        pub unsafe fn install_fixed_handler(
+            event: u32,
+            handler: Option<Handler>,
+            context: *mut (),
+        ) -> Status;
+    }
+}
+
+/// Notify handlers, for `Notify` operations run by AML.
+pub mod notify {
+    use super::*;
+
+    pub const SYSTEM: u32 = 0x1;
+    pub const DEVICE: u32 = 0x2;
+    pub const ALL: u32 = SYSTEM | DEVICE;
+
+    pub type Handler = unsafe extern "C" fn(device: Handle, value: u32, context: *mut ());
+
+    unsafe extern "C" {
+        #[link_name = "AcpiInstallNotifyHandler"]
+        pub unsafe fn install_handler(
+            device: Handle,
+            handler_type: u32,
+            handler: Option<Handler>,
+            context: *mut (),
+        ) -> Status;
     }
 }
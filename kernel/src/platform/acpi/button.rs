@@ -0,0 +1,50 @@
+//! ACPI power button.
+//!
+//! Only the fixed hardware power button is supported, not power buttons implemented in AML as
+//! `PNP0C0C` devices. Its event is raised in the SCI handler, which schedules work to start a
+//! thread for the orderly shutdown, as suspending devices may block.
+
+use super::{AcpiError, acpica_sys, power};
+use crate::kthread;
+use crate::sync::OnceFlag;
+use crate::work_queue::{self, Work};
+
+static SHUTDOWN_WORK: Work = Work::new(start_shutdown, 0);
+/// Set by the first press, so that pressing again while shutting down does nothing.
+static SHUTDOWN_STARTED: OnceFlag = OnceFlag::new();
+
+/// Installs the power button handler. Called by `acpi::init_namespace` once the subsystem is
+/// enabled.
+pub(super) fn init() {
+    let result = unsafe {
+        <Result<(), AcpiError>>::from(acpica_sys::event::install_fixed_handler(
+            acpica_sys::event::POWER_BUTTON,
+            Some(power_button_handler),
+            core::ptr::null_mut(),
+        ))
+    };
+    // Hardware-reduced platforms have no fixed events
+    if let Err(err) = result {
+        log::debug!("No fixed ACPI power button - {err:?}");
+    }
+}
+
+unsafe extern "C" fn power_button_handler(_context: *mut ()) -> u32 {
+    if SHUTDOWN_STARTED.set() {
+        work_queue::schedule(&SHUTDOWN_WORK);
+    }
+    acpica_sys::event::INTERRUPT_HANDLED
+}
+
+fn start_shutdown(_: usize) {
+    log::info!("Power button pressed, shutting down");
+    if let Err(err) = kthread::spawn("shutdown", shutdown_thread, 0) {
+        log::error!("Failed to start shutdown thread - {err}");
+    }
+}
+
+fn shutdown_thread(_: usize) -> usize {
+    let err = power::orderly_shutdown();
+    log::error!("Power button shutdown failed - {err:?}");
+    0
+}
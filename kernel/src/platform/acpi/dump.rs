@@ -0,0 +1,127 @@
+//! Dumping the ACPI tables at boot, enabled with `acpidump`, for reporting firmware bugs.
+//!
+//! Each table's header is logged. With `acpidump=hex`, the raw tables are also written to the
+//! serial port in the same format as Linux's `acpidump`, so a capture can be split up with
+//! `acpixtract -a` and disassembled with `iasl -d`. `acpidump=base64` writes each table between
+//! PEM style markers instead, for when the capture is going to be pasted somewhere.
+//!
+//! Tables are written straight to the serial port rather than logged, as they can run to hundreds
+//! of kilobytes.
+
+use super::table::{self, Header};
+use crate::arch::serial::{self, SerialWriter};
+use crate::cmdline::AcpiDump;
+use core::fmt::{self, Write};
+
+const HEX_BYTES_PER_LINE: usize = 16;
+/// Input bytes per base64 line, making 76 character lines.
+const BASE64_BYTES_PER_LINE: usize = 57;
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Calls `f` with each table in ACPICA's table list and its contents.
+fn for_each_table<F: FnMut(&Header, &[u8])>(mut f: F) {
+    for index in 0.. {
+        let Ok(header) = (unsafe { table::get_by_index(index) }) else {
+            break;
+        };
+        let length = header.length as usize;
+        let data =
+            unsafe { core::slice::from_raw_parts(header as *const Header as *const u8, length) };
+        f(header, data);
+    }
+}
+
+/// Logs the header of every table.
+pub fn log_tables() {
+    for_each_table(|header, data| {
+        let (revision, oem_revision) = (header.revision, header.oem_revision);
+        log::info!(
+            "ACPI table {} at {:p}: len {:#x} rev {revision} oem {} {} rev {oem_revision:#x}",
+            header.signature.escape_ascii(),
+            data.as_ptr(),
+            data.len(),
+            header.oem_id.escape_ascii(),
+            header.oem_table_id.escape_ascii(),
+        );
+    });
+}
+
+/// Writes the contents of every table to the serial port in `format`, returning the number of
+/// tables written.
+pub fn export_tables(format: AcpiDump) -> Result<usize, fmt::Error> {
+    if !serial::is_present() && !unsafe { serial::init() } {
+        log::warn!("No serial port to dump ACPI tables to");
+        return Ok(0);
+    }
+    let mut out = SerialWriter;
+    let mut result = Ok(());
+    let mut tables_written = 0;
+    for_each_table(|header, data| {
+        if result.is_err() {
+            return;
+        }
+        result = match format {
+            AcpiDump::Hex => write_hex(&mut out, header, data),
+            AcpiDump::Base64 => write_base64(&mut out, header, data),
+            AcpiDump::Off | AcpiDump::List => Ok(()),
+        };
+        tables_written += 1;
+    });
+    result.map(|()| tables_written)
+}
+
+/// Writes a table like `acpidump` does, as a hex dump with offsets and ASCII.
+fn write_hex(out: &mut impl Write, header: &Header, data: &[u8]) -> fmt::Result {
+    writeln!(
+        out,
+        "{} @ {:#018x}",
+        header.signature.escape_ascii(),
+        data.as_ptr() as usize
+    )?;
+    for (line_index, line) in data.chunks(HEX_BYTES_PER_LINE).enumerate() {
+        write!(out, "    {:04X}:", line_index * HEX_BYTES_PER_LINE)?;
+        for byte in line {
+            write!(out, " {byte:02X}")?;
+        }
+        // Pad short lines so the ASCII column lines up
+        for _ in line.len()..HEX_BYTES_PER_LINE {
+            write!(out, "   ")?;
+        }
+        write!(out, "  ")?;
+        for byte in line {
+            let character = match byte {
+                b' '..=b'~' => *byte as char,
+                _ => '.',
+            };
+            out.write_char(character)?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out)
+}
+
+fn write_base64(out: &mut impl Write, header: &Header, data: &[u8]) -> fmt::Result {
+    let signature = header.signature.escape_ascii();
+    writeln!(out, "-----BEGIN ACPI TABLE {signature}-----")?;
+    for line in data.chunks(BASE64_BYTES_PER_LINE) {
+        for group in line.chunks(3) {
+            let bytes = [
+                group[0],
+                *group.get(1).unwrap_or(&0),
+                *group.get(2).unwrap_or(&0),
+            ];
+            let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            for index in 0..4 {
+                // One character per six bits, padding past the end of the input
+                let character = match index <= group.len() {
+                    true => BASE64_ALPHABET[(bits >> (18 - index * 6)) as usize & 0x3F],
+                    false => b'=',
+                };
+                out.write_char(character as char)?;
+            }
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "-----END ACPI TABLE {signature}-----")
+}
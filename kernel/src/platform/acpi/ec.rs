@@ -0,0 +1,370 @@
+//! ACPI Embedded Controller driver.
+//!
+//! The EC is found through the ECDT if the firmware provides one, otherwise through the namespace.
+//! Its registers are exposed to AML through the EC operation region handler, and query events
+//! signalled through its GPE are dispatched to the matching `_Qxx` method by a kernel thread.
+
+use super::namespace::{self, Node};
+use super::resource::{self, Resource};
+use super::table::{self, Ecdt, GenericAddress};
+use super::{AcpiError, acpica_sys};
+use crate::arch::clock;
+use crate::arch::port;
+use crate::device::{self, PowerOps};
+use crate::kthread;
+use crate::tunables;
+use crate::wait_queue::WaitQueue;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const HARDWARE_ID: &core::ffi::CStr = c"PNP0C09";
+
+static EC: Mutex<Option<EmbeddedController>> = Mutex::new(None);
+
+static QUERY_PENDING: AtomicBool = AtomicBool::new(false);
+static QUERY_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+mod status {
+    pub const OUTPUT_BUFFER_FULL: u8 = 1 << 0;
+    pub const INPUT_BUFFER_FULL: u8 = 1 << 1;
+    pub const SCI_EVENT: u8 = 1 << 5;
+}
+
+mod command {
+    pub const READ: u8 = 0x80;
+    pub const WRITE: u8 = 0x81;
+    pub const BURST_ENABLE: u8 = 0x82;
+    pub const BURST_DISABLE: u8 = 0x83;
+    pub const QUERY: u8 = 0x84;
+}
+
+const BURST_ACKNOWLEDGE: u8 = 0x90;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, thiserror::Error)]
+pub enum EcError {
+    #[error("timed out waiting for embedded controller")]
+    Timeout,
+}
+
+#[derive(Debug)]
+pub struct EmbeddedController {
+    node: Node,
+    command_port: u16,
+    data_port: u16,
+    gpe: Option<u32>,
+}
+
+impl EmbeddedController {
+    fn status(&self) -> u8 {
+        unsafe { port::read_byte(self.command_port) }
+    }
+
+    fn wait_for(&self, condition: impl Fn(u8) -> bool) -> Result<(), EcError> {
+        // Applies to each step of a transaction
+        let deadline = clock::now_ns() + tunables::EC_TIMEOUT_MS.get() * 1_000_000;
+        while !condition(self.status()) {
+            if clock::now_ns() >= deadline {
+                return Err(EcError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn wait_input_empty(&self) -> Result<(), EcError> {
+        self.wait_for(|status| status & status::INPUT_BUFFER_FULL == 0)
+    }
+
+    fn wait_output_full(&self) -> Result<(), EcError> {
+        self.wait_for(|status| status & status::OUTPUT_BUFFER_FULL != 0)
+    }
+
+    fn write_command(&self, command: u8) -> Result<(), EcError> {
+        self.wait_input_empty()?;
+        unsafe { port::write_byte(self.command_port, command) };
+        Ok(())
+    }
+
+    fn write_data(&self, byte: u8) -> Result<(), EcError> {
+        self.wait_input_empty()?;
+        unsafe { port::write_byte(self.data_port, byte) };
+        Ok(())
+    }
+
+    fn read_data(&self) -> Result<u8, EcError> {
+        self.wait_output_full()?;
+        Ok(unsafe { port::read_byte(self.data_port) })
+    }
+
+    pub fn read(&self, address: u8) -> Result<u8, EcError> {
+        self.write_command(command::READ)?;
+        self.write_data(address)?;
+        self.read_data()
+    }
+
+    pub fn write(&self, address: u8, byte: u8) -> Result<(), EcError> {
+        self.write_command(command::WRITE)?;
+        self.write_data(address)?;
+        self.write_data(byte)
+    }
+
+    /// Asks the EC to dedicate itself to the host for multi-byte accesses. Returns whether the
+    /// EC acknowledged, if it didn't accesses still work, just more slowly.
+    fn enable_burst(&self) -> Result<bool, EcError> {
+        self.write_command(command::BURST_ENABLE)?;
+        Ok(self.read_data()? == BURST_ACKNOWLEDGE)
+    }
+
+    fn disable_burst(&self) -> Result<(), EcError> {
+        self.write_command(command::BURST_DISABLE)?;
+        self.wait_input_empty()
+    }
+
+    /// Returns the pending query event, or 0 if there isn't one.
+    pub fn query(&self) -> Result<u8, EcError> {
+        self.write_command(command::QUERY)?;
+        self.read_data()
+    }
+
+    /// Reads or writes `bytes.len()` consecutive registers, using burst mode where possible.
+    fn transfer(&self, address: u8, bytes: &mut [u8], write: bool) -> Result<(), EcError> {
+        let burst = bytes.len() > 1 && self.enable_burst()?;
+        let result = bytes.iter_mut().enumerate().try_for_each(|(i, byte)| {
+            let address = address.wrapping_add(i as u8);
+            if write {
+                self.write(address, *byte)
+            } else {
+                *byte = self.read(address)?;
+                Ok(())
+            }
+        });
+        if burst {
+            self.disable_burst()?;
+        }
+        result
+    }
+}
+
+/// Finds the EC and installs its operation region and GPE handlers.
+/// Called by `acpi::init_namespace` once the ACPI tables have been loaded.
+pub(super) fn init() {
+    let ec = match find_from_ecdt().or_else(find_from_namespace) {
+        Some(ec) => ec,
+        None => {
+            log::debug!("No embedded controller found");
+            return;
+        }
+    };
+    log::info!(
+        "Embedded controller: command port {:#X}, data port {:#X}, GPE {:?}",
+        ec.command_port,
+        ec.data_port,
+        ec.gpe,
+    );
+    let node = ec.node;
+    let gpe = ec.gpe;
+    *EC.lock() = Some(ec);
+    let install_result: Result<(), AcpiError> = unsafe {
+        acpica_sys::address_space::install_handler(
+            node.handle(),
+            acpica_sys::address_space::EMBEDDED_CONTROLLER,
+            Some(address_space_handler),
+            None,
+            core::ptr::null_mut(),
+        )
+        .into()
+    };
+    if let Err(err) = install_result {
+        log::error!("Failed to install EC address space handler - {err:?}");
+        *EC.lock() = None;
+        return;
+    }
+    let Some(gpe) = gpe else {
+        log::warn!("Embedded controller has no GPE, query events will be ignored");
+        return;
+    };
+    if let Err(err) = kthread::spawn("ec_query", query_thread, 0) {
+        log::error!("Failed to start EC query thread - {err}");
+        return;
+    }
+    let gpe_result = unsafe {
+        <Result<(), AcpiError>>::from(acpica_sys::gpe::install_handler(
+            core::ptr::null_mut(),
+            gpe,
+            acpica_sys::gpe::EDGE_TRIGGERED,
+            Some(gpe_handler),
+            core::ptr::null_mut(),
+        ))
+        .and_then(|()| acpica_sys::gpe::enable(core::ptr::null_mut(), gpe).into())
+    };
+    if let Err(err) = gpe_result {
+        log::error!("Failed to enable EC GPE {gpe:#X} - {err:?}");
+        return;
+    }
+    device::register("ec", &[], PowerOps { suspend, resume });
+}
+
+/// Stops query events from arriving while suspended.
+fn suspend() -> Result<(), &'static str> {
+    let gpe = EC.lock().as_ref().and_then(|ec| ec.gpe).ok_or("no GPE")?;
+    unsafe { <Result<(), AcpiError>>::from(acpica_sys::gpe::disable(core::ptr::null_mut(), gpe)) }
+        .map_err(|_| "failed to disable GPE")
+}
+
+fn resume() -> Result<(), &'static str> {
+    let gpe = EC.lock().as_ref().and_then(|ec| ec.gpe).ok_or("no GPE")?;
+    unsafe { <Result<(), AcpiError>>::from(acpica_sys::gpe::enable(core::ptr::null_mut(), gpe)) }
+        .map_err(|_| "failed to enable GPE")?;
+    // Events may have been raised while the GPE was disabled
+    QUERY_PENDING.store(true, Ordering::Release);
+    QUERY_WAIT_QUEUE.wake_all();
+    Ok(())
+}
+
+fn find_from_ecdt() -> Option<EmbeddedController> {
+    let ecdt = unsafe { table::get::<Ecdt>().ok()? };
+    let (control, data) = (ecdt.control, ecdt.data);
+    if control.space_id != GenericAddress::SPACE_SYSTEM_IO
+        || data.space_id != GenericAddress::SPACE_SYSTEM_IO
+    {
+        log::warn!("ECDT registers aren't in I/O space, ignoring");
+        return None;
+    }
+    // The namespace may not be loaded yet, in which case the handler is installed at the root
+    let node = namespace::get_node(ecdt.id()).unwrap_or(Node::ROOT);
+    Some(EmbeddedController {
+        node,
+        command_port: control.address as u16,
+        data_port: data.address as u16,
+        gpe: Some(ecdt.gpe_bit as u32),
+    })
+}
+
+fn find_from_namespace() -> Option<EmbeddedController> {
+    let mut found = None;
+    let walk_result = namespace::find_devices(HARDWARE_ID, |node| {
+        if found.is_some() {
+            return;
+        }
+        let resources = match node.evaluate_buffer(c"_CRS") {
+            Ok(resources) => resources,
+            Err(err) => {
+                log::warn!("Failed to read EC resources - {err:?}");
+                return;
+            }
+        };
+        // The first I/O resource is the data port, the second the command/status port
+        let mut ports = resource::iter(&resources).filter_map(|resource| match resource {
+            Resource::Io { minimum, .. } => Some(minimum),
+            Resource::FixedIo { base, .. } => Some(base),
+            _ => None,
+        });
+        let (Some(data_port), Some(command_port)) = (ports.next(), ports.next()) else {
+            log::warn!("EC resources are missing I/O ports");
+            return;
+        };
+        let gpe = match node.evaluate_integer(c"_GPE") {
+            Ok(gpe) => Some(gpe as u32),
+            Err(err) => {
+                log::warn!("Failed to read EC GPE - {err:?}");
+                None
+            }
+        };
+        found = Some(EmbeddedController {
+            node,
+            command_port,
+            data_port,
+            gpe,
+        });
+    });
+    if let Err(err) = walk_result {
+        log::warn!("Failed to search for embedded controller - {err:?}");
+    }
+    found
+}
+
+unsafe extern "C" fn address_space_handler(
+    function: u32,
+    address: u64,
+    bit_width: u32,
+    value: *mut u64,
+    _handler_context: *mut (),
+    _region_context: *mut (),
+) -> acpica_sys::Status {
+    let num_bytes = (bit_width as usize).div_ceil(8);
+    if address > u8::MAX as u64 || num_bytes == 0 || num_bytes > 8 || value.is_null() {
+        return acpica_sys::Status::BAD_PARAMETER;
+    }
+    let lock = EC.lock();
+    let Some(ec) = lock.as_ref() else {
+        return acpica_sys::Status::NOT_EXIST;
+    };
+    let write = match function {
+        acpica_sys::address_space::READ => false,
+        acpica_sys::address_space::WRITE => true,
+        _ => return acpica_sys::Status::BAD_PARAMETER,
+    };
+    unsafe {
+        let mut bytes = (*value).to_le_bytes();
+        match ec.transfer(address as u8, &mut bytes[..num_bytes], write) {
+            Ok(()) => {
+                if !write {
+                    *value = u64::from_le_bytes(bytes);
+                }
+                acpica_sys::Status::OK
+            }
+            Err(EcError::Timeout) => {
+                log::warn!("EC access at {address:#X} timed out");
+                acpica_sys::Status::TIME
+            }
+        }
+    }
+}
+
+/// Runs in interrupt context, so just hands off to the query thread.
+unsafe extern "C" fn gpe_handler(_gpe_device: acpica_sys::Handle, _gpe: u32, _: *mut ()) -> u32 {
+    QUERY_PENDING.store(true, Ordering::Release);
+    QUERY_WAIT_QUEUE.wake_all();
+    acpica_sys::gpe::INTERRUPT_HANDLED | acpica_sys::gpe::REENABLE
+}
+
+fn query_thread(_: usize) -> usize {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    loop {
+        QUERY_WAIT_QUEUE.wait_until(|| QUERY_PENDING.swap(false, Ordering::Acquire));
+        // Drain every pending event, the EC may have queued several behind one GPE
+        loop {
+            let (node, query_result) = {
+                let lock = EC.lock();
+                let Some(ec) = lock.as_ref() else {
+                    break;
+                };
+                if ec.status() & status::SCI_EVENT == 0 {
+                    break;
+                }
+                (ec.node, ec.query())
+            };
+            let event = match query_result {
+                Ok(0) => break,
+                Ok(event) => event,
+                Err(err) => {
+                    log::warn!("EC query failed - {err}");
+                    break;
+                }
+            };
+            let method = [
+                b'_',
+                b'Q',
+                HEX_DIGITS[(event >> 4) as usize],
+                HEX_DIGITS[(event & 0xF) as usize],
+                0,
+            ];
+            let method = core::ffi::CStr::from_bytes_with_nul(&method).unwrap();
+            // Lock must not be held here, as the method will likely access the EC region
+            match node.evaluate(method) {
+                Ok(()) | Err(AcpiError::NOT_FOUND) => {}
+                Err(err) => log::warn!("Failed to run EC query method {method:?} - {err:?}"),
+            }
+        }
+    }
+}
@@ -1,12 +1,37 @@
+use crate::init_state::{self, Subsystem};
+
 mod acpica_os_layer;
 mod acpica_sys;
+pub mod button;
+pub mod dump;
+pub mod ec;
+pub mod notify;
+pub mod processor;
+pub mod prt;
+pub mod resource;
+pub mod thermal;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct AcpiError {
     pub code: AcpiErrorCode,
     pub exception: u16,
 }
 
+impl AcpiError {
+    pub const NOT_FOUND: Self = Self {
+        code: AcpiErrorCode::Environment,
+        exception: 0x5,
+    };
+    pub const TYPE: Self = Self {
+        code: AcpiErrorCode::Environment,
+        exception: 0x8,
+    };
+    pub const BUFFER_OVERFLOW: Self = Self {
+        code: AcpiErrorCode::Environment,
+        exception: 0xB,
+    };
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum AcpiErrorCode {
     Environment = 0,
@@ -44,10 +69,331 @@ impl From<acpica_sys::Status> for Result<(), AcpiError> {
 
 /// Must only be called once.
 pub unsafe fn init_subsystem(acpi_ptr: Option<core::ptr::NonNull<()>>) -> Result<(), AcpiError> {
+    init_state::begin(Subsystem::AcpiSubsystem);
     if let Some(acpi_ptr) = acpi_ptr {
         *acpica_os_layer::RSDP_ADDRESS.lock() = acpi_ptr.as_ptr() as usize;
     }
-    unsafe { acpica_sys::subsystem::initialise().into() }
+    unsafe { <Result<(), AcpiError>>::from(acpica_sys::subsystem::initialise())? };
+    if let Err(err) = acpica_os_layer::start_execute_worker() {
+        log::error!("Failed to start ACPICA worker thread - {err}");
+    }
+    init_state::finish(Subsystem::AcpiSubsystem);
+    Ok(())
+}
+
+/// Loads the AML namespace from the DSDT and SSDTs, enables ACPI mode and runs device
+/// initialisation methods. Must only be called once, after `table::init_manager` and once
+/// interrupts and clocks are available.
+pub unsafe fn init_namespace() -> Result<(), AcpiError> {
+    init_state::begin(Subsystem::AcpiNamespace);
+    unsafe {
+        use acpica_sys::subsystem::{self, FULL_INITIALISATION};
+        <Result<(), AcpiError>>::from(subsystem::load_tables())?;
+        // `_REG` and `_INI` methods commonly access the EC's operation region
+        ec::init();
+        if let Err(err) = notify::init() {
+            log::error!("Failed to install ACPI notify handler - {err:?}");
+        }
+        <Result<(), AcpiError>>::from(subsystem::enable(FULL_INITIALISATION))?;
+        // Needs the SCI handler, which is installed when enabling the subsystem
+        button::init();
+        <Result<(), AcpiError>>::from(subsystem::initialise_objects(FULL_INITIALISATION))?;
+        // Enable the GPEs AML has methods for, which is how most notifications are raised
+        if let Err(err) = <Result<(), AcpiError>>::from(acpica_sys::gpe::update_all()) {
+            log::error!("Failed to enable ACPI GPEs - {err:?}");
+        }
+        // Tell the firmware we're using the I/O APIC, so that `_PRT` returns APIC routing
+        const PIC_MODE_APIC: u64 = 1;
+        match namespace::Node::ROOT.evaluate_with_integer(c"_PIC", PIC_MODE_APIC) {
+            Ok(()) | Err(AcpiError::NOT_FOUND) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    init_state::finish(Subsystem::AcpiNamespace);
+    Ok(())
+}
+
+pub mod namespace {
+    use super::*;
+    use acpica_sys::{Buffer, BufferObject, Handle, IntegerObject, PackageObject, ProcessorObject};
+    use alloc::vec::Vec;
+    use core::ffi::CStr;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum ObjectType {
+        Any = acpica_sys::object_type::ANY as isize,
+        Device = acpica_sys::object_type::DEVICE as isize,
+        Processor = acpica_sys::object_type::PROCESSOR as isize,
+        Thermal = acpica_sys::object_type::THERMAL as isize,
+    }
+
+    /// A value produced by evaluating an object, copied out of ACPICA's result buffer.
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub enum Object {
+        Integer(u64),
+        Buffer(Vec<u8>),
+        Package(Vec<Object>),
+        /// A legacy `Processor` declaration, with its processor control block.
+        Processor {
+            block_address: u64,
+            block_length: u32,
+        },
+        Other,
+    }
+
+    impl Object {
+        /// Copies out an object, and any objects it contains.
+        unsafe fn from_raw(object: *const IntegerObject) -> Self {
+            unsafe {
+                match (*object).object_type {
+                    acpica_sys::object_type::INTEGER => Self::Integer((*object).value),
+                    acpica_sys::object_type::BUFFER => {
+                        let buffer = &*(object as *const BufferObject);
+                        let bytes =
+                            core::slice::from_raw_parts(buffer.pointer, buffer.length as usize);
+                        Self::Buffer(bytes.to_vec())
+                    }
+                    acpica_sys::object_type::PACKAGE => {
+                        let package = &*(object as *const PackageObject);
+                        let elements = (0..package.count as usize)
+                            .map(|index| Self::from_raw(package.elements.add(index)))
+                            .collect();
+                        Self::Package(elements)
+                    }
+                    acpica_sys::object_type::PROCESSOR => {
+                        let processor = &*(object as *const ProcessorObject);
+                        Self::Processor {
+                            block_address: processor.block_address,
+                            block_length: processor.block_length,
+                        }
+                    }
+                    _ => Self::Other,
+                }
+            }
+        }
+    }
+
+    /// A node in the ACPI namespace.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Node(Handle);
+
+    unsafe impl Send for Node {}
+    unsafe impl Sync for Node {}
+
+    impl Node {
+        pub const ROOT: Self = Self(acpica_sys::ROOT_OBJECT);
+
+        pub(super) fn handle(self) -> Handle {
+            self.0
+        }
+
+        pub(super) fn from_handle(handle: Handle) -> Self {
+            Self(handle)
+        }
+
+        /// Returns the four character name of the node.
+        pub fn name(self) -> Result<[u8; 4], AcpiError> {
+            unsafe {
+                let mut name = [0u8; 5];
+                let mut buffer = Buffer {
+                    length: name.len(),
+                    pointer: name.as_mut_ptr() as *mut (),
+                };
+                <Result<(), AcpiError>>::from(acpica_sys::namespace::get_name(
+                    self.0,
+                    acpica_sys::namespace::SINGLE_NAME,
+                    &mut buffer,
+                ))?;
+                Ok([name[0], name[1], name[2], name[3]])
+            }
+        }
+
+        /// Evaluates the object at `path` relative to this node, which must produce an integer.
+        pub fn evaluate_integer(self, path: &CStr) -> Result<u64, AcpiError> {
+            unsafe {
+                let mut object = IntegerObject::zeroed();
+                let mut buffer = Buffer {
+                    length: core::mem::size_of::<IntegerObject>(),
+                    pointer: &raw mut object as *mut (),
+                };
+                <Result<(), AcpiError>>::from(acpica_sys::namespace::evaluate_object(
+                    self.0,
+                    path.as_ptr(),
+                    core::ptr::null(),
+                    &mut buffer,
+                ))?;
+                if object.object_type != acpica_sys::object_type::INTEGER {
+                    return Err(AcpiError::TYPE);
+                }
+                Ok(object.value)
+            }
+        }
+
+        /// Evaluates the object at `path` relative to this node, which must produce a buffer.
+        pub fn evaluate_buffer(self, path: &CStr) -> Result<Vec<u8>, AcpiError> {
+            unsafe {
+                // Holds the object followed by its data, u64s for alignment
+                let mut storage = [0u64; 64];
+                let mut buffer = Buffer {
+                    length: core::mem::size_of_val(&storage),
+                    pointer: storage.as_mut_ptr() as *mut (),
+                };
+                <Result<(), AcpiError>>::from(acpica_sys::namespace::evaluate_object(
+                    self.0,
+                    path.as_ptr(),
+                    core::ptr::null(),
+                    &mut buffer,
+                ))?;
+                let object = &*(storage.as_ptr() as *const BufferObject);
+                if object.object_type != acpica_sys::object_type::BUFFER {
+                    return Err(AcpiError::TYPE);
+                }
+                Ok(core::slice::from_raw_parts(object.pointer, object.length as usize).to_vec())
+            }
+        }
+
+        /// Evaluates the object at `path` relative to this node, or this node itself if `path` is
+        /// `None`, returning whatever it produces.
+        pub fn evaluate_object(self, path: Option<&CStr>) -> Result<Object, AcpiError> {
+            unsafe {
+                // Holds the object followed by anything it refers to, u64s for alignment
+                let mut storage = [0u64; 512];
+                let mut buffer = Buffer {
+                    length: core::mem::size_of_val(&storage),
+                    pointer: storage.as_mut_ptr() as *mut (),
+                };
+                <Result<(), AcpiError>>::from(acpica_sys::namespace::evaluate_object(
+                    self.0,
+                    path.map_or(core::ptr::null(), CStr::as_ptr),
+                    core::ptr::null(),
+                    &mut buffer,
+                ))?;
+                Ok(Object::from_raw(storage.as_ptr() as *const IntegerObject))
+            }
+        }
+
+        /// Calls the method at `path` relative to this node with a single integer argument,
+        /// ignoring any result.
+        pub fn evaluate_with_integer(self, path: &CStr, argument: u64) -> Result<(), AcpiError> {
+            unsafe {
+                let mut argument = IntegerObject::new(argument);
+                let arguments = acpica_sys::ObjectList {
+                    count: 1,
+                    pointer: &mut argument,
+                };
+                acpica_sys::namespace::evaluate_object(
+                    self.0,
+                    path.as_ptr(),
+                    &arguments,
+                    core::ptr::null_mut(),
+                )
+                .into()
+            }
+        }
+
+        /// Evaluates the object at `path` relative to this node, ignoring any result.
+        pub fn evaluate(self, path: &CStr) -> Result<(), AcpiError> {
+            unsafe {
+                acpica_sys::namespace::evaluate_object(
+                    self.0,
+                    path.as_ptr(),
+                    core::ptr::null(),
+                    core::ptr::null_mut(),
+                )
+                .into()
+            }
+        }
+    }
+
+    /// Looks up the node at the absolute `path`.
+    pub fn get_node(path: &CStr) -> Result<Node, AcpiError> {
+        unsafe {
+            let mut handle: Handle = core::ptr::null_mut();
+            <Result<(), AcpiError>>::from(acpica_sys::namespace::get_handle(
+                core::ptr::null_mut(),
+                path.as_ptr(),
+                &mut handle,
+            ))?;
+            Ok(Node(handle))
+        }
+    }
+
+    unsafe extern "C" fn walk_callback<F: FnMut(Node)>(
+        object: Handle,
+        _nesting_level: u32,
+        context: *mut (),
+        _return_value: *mut *mut (),
+    ) -> acpica_sys::Status {
+        unsafe {
+            (*(context as *mut F))(Node(object));
+        }
+        acpica_sys::Status::OK
+    }
+
+    /// Calls `f` on every node of type `object_type` in the namespace, parents before children.
+    pub fn walk<F: FnMut(Node)>(object_type: ObjectType, mut f: F) -> Result<(), AcpiError> {
+        unsafe {
+            acpica_sys::namespace::walk(
+                object_type as u32,
+                acpica_sys::ROOT_OBJECT,
+                u32::MAX,
+                Some(walk_callback::<F>),
+                None,
+                &raw mut f as *mut (),
+                core::ptr::null_mut(),
+            )
+            .into()
+        }
+    }
+
+    /// Calls `f` on every present device with the hardware or compatible ID `hardware_id`.
+    pub fn find_devices<F: FnMut(Node)>(hardware_id: &CStr, mut f: F) -> Result<(), AcpiError> {
+        unsafe {
+            acpica_sys::namespace::get_devices(
+                hardware_id.as_ptr(),
+                Some(walk_callback::<F>),
+                &raw mut f as *mut (),
+                core::ptr::null_mut(),
+            )
+            .into()
+        }
+    }
+}
+
+pub mod power {
+    use super::*;
+    use crate::device;
+
+    const SLEEP_STATE_SOFT_OFF: u8 = 5;
+
+    /// Powers off the system by entering the S5 sleep state. Only returns if that failed.
+    pub unsafe fn shutdown() -> AcpiError {
+        unsafe {
+            let result = <Result<(), AcpiError>>::from(
+                acpica_sys::hardware::enter_sleep_state_prep(SLEEP_STATE_SOFT_OFF),
+            )
+            .and_then(|()| acpica_sys::hardware::enter_sleep_state(SLEEP_STATE_SOFT_OFF).into());
+            match result {
+                Err(err) => err,
+                // Firmware reported success, but we're still running
+                Ok(()) => AcpiError {
+                    code: AcpiErrorCode::Unknown,
+                    exception: 0,
+                },
+            }
+        }
+    }
+
+    /// Suspends every device, so that they're quiesced, then powers off. Only returns if powering
+    /// off failed, after resuming the devices again. Must be called from a kernel thread.
+    pub fn orderly_shutdown() -> AcpiError {
+        if let Err(err) = device::suspend_all() {
+            log::warn!("Powering off with devices still active - {err}");
+        }
+        let err = unsafe { shutdown() };
+        device::resume_all();
+        err
+    }
 }
 
 pub mod table {
@@ -55,7 +401,16 @@ pub mod table {
 
     /// Must only be called once, after `acpi::init_subsystem`.
     pub unsafe fn init_manager() -> Result<(), AcpiError> {
-        unsafe { acpica_sys::table_manager::initialise(None, 16, false.into()).into() }
+        init_state::begin(Subsystem::AcpiTables);
+        unsafe {
+            <Result<(), AcpiError>>::from(acpica_sys::table_manager::initialise(
+                None,
+                16,
+                false.into(),
+            ))?;
+        }
+        init_state::finish(Subsystem::AcpiTables);
+        Ok(())
     }
 
     pub unsafe fn get<T: Table>() -> Result<&'static T, AcpiError> {
@@ -70,10 +425,36 @@ pub mod table {
         }
     }
 
+    /// Returns the header of the table at `index` in ACPICA's table list, or an error once past
+    /// the end.
+    pub unsafe fn get_by_index(index: u32) -> Result<&'static Header, AcpiError> {
+        unsafe {
+            let mut table: *const () = core::ptr::null();
+            <Result<(), AcpiError>>::from(acpica_sys::table_manager::get_table_by_index(
+                index, &mut table,
+            ))?;
+            Ok(&*(table as *const Header))
+        }
+    }
+
     pub trait Table {
         const SIGNATURE: [u8; 4];
     }
 
+    /// Header common to every table.
+    #[repr(C, packed)]
+    pub struct Header {
+        pub signature: [u8; 4],
+        pub length: u32,
+        pub revision: u8,
+        pub checksum: u8,
+        pub oem_id: [u8; 6],
+        pub oem_table_id: [u8; 8],
+        pub oem_revision: u32,
+        pub creator_id: u32,
+        pub creator_revision: u32,
+    }
+
     #[repr(C)]
     pub struct Madt {
         _signature: [u8; 4],
@@ -104,6 +485,319 @@ pub mod table {
         }
     }
 
+    /// Embedded Controller Boot Resources Table.
+    #[repr(C, packed)]
+    pub struct Ecdt {
+        _signature: [u8; 4],
+        length: u32,
+        _revision: u8,
+        _checksum: u8,
+        _oem_id: [u8; 6],
+        _oem_table_id: [u8; 8],
+        _oem_revision: u32,
+        _creator_id: u32,
+        _creator_revision: u32,
+        pub control: GenericAddress,
+        pub data: GenericAddress,
+        pub uid: u32,
+        pub gpe_bit: u8,
+        id: [u8; 0],
+    }
+
+    impl Table for Ecdt {
+        const SIGNATURE: [u8; 4] = *b"ECDT";
+    }
+
+    impl Ecdt {
+        /// Absolute namespace path of the EC device.
+        pub fn id(&self) -> &core::ffi::CStr {
+            unsafe {
+                let start = (&raw const self.id) as *const u8;
+                let end = (self as *const Self as *const u8).add(self.length as usize);
+                let bytes = core::slice::from_raw_parts(start, end.offset_from(start) as usize);
+                core::ffi::CStr::from_bytes_until_nul(bytes).unwrap_or(c"")
+            }
+        }
+    }
+
+    /// Fixed ACPI Description Table, up to the ACPI 1.0 fields.
+    #[repr(C, packed)]
+    pub struct Fadt {
+        _signature: [u8; 4],
+        _length: u32,
+        _revision: u8,
+        _checksum: u8,
+        _oem_id: [u8; 6],
+        _oem_table_id: [u8; 8],
+        _oem_revision: u32,
+        _creator_id: u32,
+        _creator_revision: u32,
+        pub firmware_control: u32,
+        pub dsdt: u32,
+        _reserved_0: u8,
+        pub preferred_pm_profile: u8,
+        pub sci_interrupt: u16,
+        pub smi_command: u32,
+        pub acpi_enable: u8,
+        pub acpi_disable: u8,
+        pub s4_bios_request: u8,
+        pub pstate_control: u8,
+        pub pm1a_event_block: u32,
+        pub pm1b_event_block: u32,
+        pub pm1a_control_block: u32,
+        pub pm1b_control_block: u32,
+        pub pm2_control_block: u32,
+        pub pm_timer_block: u32,
+        pub gpe0_block: u32,
+        pub gpe1_block: u32,
+        pub pm1_event_length: u8,
+        pub pm1_control_length: u8,
+        pub pm2_control_length: u8,
+        pub pm_timer_length: u8,
+        pub gpe0_block_length: u8,
+        pub gpe1_block_length: u8,
+        pub gpe1_base: u8,
+        pub cstate_control: u8,
+        pub c2_latency: u16,
+        pub c3_latency: u16,
+        pub flush_size: u16,
+        pub flush_stride: u16,
+        pub duty_offset: u8,
+        pub duty_width: u8,
+        pub day_alarm: u8,
+        pub month_alarm: u8,
+        /// CMOS register holding the century, or 0 if there isn't one.
+        pub century: u8,
+        pub boot_architecture_flags: u16,
+        _reserved_1: u8,
+        pub flags: u32,
+    }
+
+    impl Table for Fadt {
+        const SIGNATURE: [u8; 4] = *b"FACP";
+    }
+
+    const _: () = assert!(core::mem::offset_of!(Fadt, century) == 108);
+
+    /// System Resource Affinity Table, assigning processors and memory ranges to NUMA proximity
+    /// domains.
+    #[repr(C, packed)]
+    pub struct Srat {
+        _signature: [u8; 4],
+        length: u32,
+        _revision: u8,
+        _checksum: u8,
+        _oem_id: [u8; 6],
+        _oem_table_id: [u8; 8],
+        _oem_revision: u32,
+        _creator_id: u32,
+        _creator_revision: u32,
+        _table_revision: u32,
+        _reserved: u64,
+    }
+
+    impl Table for Srat {
+        const SIGNATURE: [u8; 4] = *b"SRAT";
+    }
+
+    impl Srat {
+        pub fn entry_iter(&self) -> SratEntryIterator {
+            let start = self as *const Self as usize;
+            SratEntryIterator {
+                current_address: start + size_of::<Self>(),
+                end_address: start + self.length as usize,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub enum SratEntry {
+        /// Proximity domain of a processor by APIC ID. Also used for x2APIC entries.
+        ProcessorAffinity {
+            proximity_domain: u32,
+            apic_id: u32,
+            enabled: bool,
+        },
+        MemoryAffinity {
+            proximity_domain: u32,
+            base_address: u64,
+            length: u64,
+            enabled: bool,
+            hot_pluggable: bool,
+        },
+    }
+
+    pub struct SratEntryIterator {
+        current_address: usize,
+        end_address: usize,
+    }
+
+    impl Iterator for SratEntryIterator {
+        type Item = SratEntry;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                if self.current_address + size_of::<SratEntryHeader>() > self.end_address {
+                    return None;
+                }
+                let header = unsafe { &*(self.current_address as *const SratEntryHeader) };
+                let entry_address = self.current_address;
+                let entry_length = header.entry_length as usize;
+                // A zero length entry would never end
+                if entry_length < size_of::<SratEntryHeader>()
+                    || entry_address + entry_length > self.end_address
+                {
+                    return None;
+                }
+                self.current_address += entry_length;
+                match header.entry_type {
+                    SRAT_PROCESSOR_AFFINITY if entry_length >= size_of::<SratProcessorEntry>() => {
+                        let entry = unsafe { &*(entry_address as *const SratProcessorEntry) };
+                        let [high_0, high_1, high_2] = entry.proximity_domain_high;
+                        return Some(SratEntry::ProcessorAffinity {
+                            proximity_domain: u32::from_le_bytes([
+                                entry.proximity_domain_low,
+                                high_0,
+                                high_1,
+                                high_2,
+                            ]),
+                            apic_id: entry.apic_id as u32,
+                            enabled: entry.flags & SRAT_ENABLED != 0,
+                        });
+                    }
+                    SRAT_MEMORY_AFFINITY if entry_length >= size_of::<SratMemoryEntry>() => {
+                        let entry = unsafe { &*(entry_address as *const SratMemoryEntry) };
+                        return Some(SratEntry::MemoryAffinity {
+                            proximity_domain: entry.proximity_domain,
+                            base_address: entry.base_address,
+                            length: entry.length,
+                            enabled: entry.flags & SRAT_ENABLED != 0,
+                            hot_pluggable: entry.flags & SRAT_MEMORY_HOT_PLUGGABLE != 0,
+                        });
+                    }
+                    SRAT_X2APIC_AFFINITY if entry_length >= size_of::<SratX2ApicEntry>() => {
+                        let entry = unsafe { &*(entry_address as *const SratX2ApicEntry) };
+                        return Some(SratEntry::ProcessorAffinity {
+                            proximity_domain: entry.proximity_domain,
+                            apic_id: entry.x2apic_id,
+                            enabled: entry.flags & SRAT_ENABLED != 0,
+                        });
+                    }
+                    // Skip over unknown entry types
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    const SRAT_PROCESSOR_AFFINITY: u8 = 0;
+    const SRAT_MEMORY_AFFINITY: u8 = 1;
+    const SRAT_X2APIC_AFFINITY: u8 = 2;
+    const SRAT_ENABLED: u32 = 1 << 0;
+    const SRAT_MEMORY_HOT_PLUGGABLE: u32 = 1 << 1;
+
+    #[repr(C, packed)]
+    struct SratEntryHeader {
+        entry_type: u8,
+        entry_length: u8,
+    }
+
+    #[repr(C, packed)]
+    struct SratProcessorEntry {
+        _header: SratEntryHeader,
+        proximity_domain_low: u8,
+        apic_id: u8,
+        flags: u32,
+        _local_sapic_eid: u8,
+        proximity_domain_high: [u8; 3],
+        _clock_domain: u32,
+    }
+
+    #[repr(C, packed)]
+    struct SratMemoryEntry {
+        _header: SratEntryHeader,
+        proximity_domain: u32,
+        _reserved_1: u16,
+        base_address: u64,
+        length: u64,
+        _reserved_2: u32,
+        flags: u32,
+        _reserved_3: u64,
+    }
+
+    #[repr(C, packed)]
+    struct SratX2ApicEntry {
+        _header: SratEntryHeader,
+        _reserved_1: u16,
+        proximity_domain: u32,
+        x2apic_id: u32,
+        flags: u32,
+        _clock_domain: u32,
+        _reserved_2: u32,
+    }
+
+    /// System Locality Information Table, giving the relative distances between proximity
+    /// domains.
+    #[repr(C, packed)]
+    pub struct Slit {
+        _signature: [u8; 4],
+        length: u32,
+        _revision: u8,
+        _checksum: u8,
+        _oem_id: [u8; 6],
+        _oem_table_id: [u8; 8],
+        _oem_revision: u32,
+        _creator_id: u32,
+        _creator_revision: u32,
+        num_localities: u64,
+        entries: [u8; 0],
+    }
+
+    impl Table for Slit {
+        const SIGNATURE: [u8; 4] = *b"SLIT";
+    }
+
+    impl Slit {
+        /// Distance to a domain from itself.
+        pub const LOCAL_DISTANCE: u8 = 10;
+
+        pub fn num_localities(&self) -> usize {
+            self.num_localities as usize
+        }
+
+        /// Returns the relative distance from domain `from` to `to`, or `None` if either is out of
+        /// range or the distance is unknown.
+        pub fn distance(&self, from: usize, to: usize) -> Option<u8> {
+            let num_localities = self.num_localities();
+            if from >= num_localities || to >= num_localities {
+                return None;
+            }
+            let index = from.checked_mul(num_localities)?.checked_add(to)?;
+            let entries_len = (self.length as usize).checked_sub(size_of::<Self>())?;
+            if index >= entries_len {
+                return None;
+            }
+            let distance = unsafe { *((&raw const self.entries) as *const u8).add(index) };
+            (distance != u8::MAX).then_some(distance)
+        }
+    }
+
+    /// `ACPI_GENERIC_ADDRESS`
+    #[repr(C, packed)]
+    #[derive(Clone, Copy, Debug)]
+    pub struct GenericAddress {
+        pub space_id: u8,
+        pub bit_width: u8,
+        pub bit_offset: u8,
+        pub access_width: u8,
+        pub address: u64,
+    }
+
+    impl GenericAddress {
+        pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+        pub const SPACE_SYSTEM_IO: u8 = 1;
+    }
+
     #[derive(Clone, Copy, Debug)]
     pub enum MadtEntry {
         LocalApic {
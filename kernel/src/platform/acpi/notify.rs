@@ -0,0 +1,87 @@
+//! Dispatch of ACPI `Notify` events to the drivers of the devices they're for.
+//!
+//! A single global handler receives every notification, and calls the handler registered for the
+//! notified node, if any. Handlers run on the ACPICA worker thread, so may sleep and evaluate AML.
+
+use super::AcpiError;
+use super::acpica_sys;
+use super::namespace::Node;
+use crate::device::{self, DeviceId, PowerState};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+static HANDLERS: Mutex<Vec<Handler>> = Mutex::new(Vec::new());
+
+/// The device's children may have been added or removed, and should be re-enumerated.
+pub const BUS_CHECK: u32 = 0x00;
+/// The device may have been added or removed.
+pub const DEVICE_CHECK: u32 = 0x01;
+pub const DEVICE_WAKE: u32 = 0x02;
+pub const EJECT_REQUEST: u32 = 0x03;
+/// Values from this up are device specific, such as lid switch changes for `PNP0C0D` devices.
+pub const DEVICE_SPECIFIC_START: u32 = 0x80;
+
+pub type HandlerFunction = fn(node: Node, value: u32);
+
+#[derive(Clone, Copy)]
+struct Handler {
+    node: Node,
+    device: DeviceId,
+    function: HandlerFunction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum RegisterError {
+    #[error("a handler is already registered for the node")]
+    AlreadyRegistered,
+}
+
+/// Calls `function` with the value of every notification for `node`, while `device` is active.
+pub fn register(
+    node: Node,
+    device: DeviceId,
+    function: HandlerFunction,
+) -> Result<(), RegisterError> {
+    let mut handlers = HANDLERS.lock();
+    if handlers.iter().any(|handler| handler.node == node) {
+        return Err(RegisterError::AlreadyRegistered);
+    }
+    handlers.push(Handler {
+        node,
+        device,
+        function,
+    });
+    Ok(())
+}
+
+/// Installs the global notify handler. Called by `acpi::init_namespace` before the namespace is
+/// initialised, so notifications from `_INI` methods aren't missed.
+pub(super) fn init() -> Result<(), AcpiError> {
+    unsafe {
+        acpica_sys::notify::install_handler(
+            acpica_sys::ROOT_OBJECT,
+            acpica_sys::notify::ALL,
+            Some(global_handler),
+            core::ptr::null_mut(),
+        )
+        .into()
+    }
+}
+
+unsafe extern "C" fn global_handler(device: acpica_sys::Handle, value: u32, _context: *mut ()) {
+    let node = Node::from_handle(device);
+    let handler = HANDLERS
+        .lock()
+        .iter()
+        .find(|handler| handler.node == node)
+        .copied();
+    let name = node.name().unwrap_or(*b"????");
+    let name = core::str::from_utf8(&name).unwrap_or("????");
+    match handler {
+        Some(handler) if device::power_state(handler.device) == PowerState::Active => {
+            (handler.function)(node, value);
+        }
+        Some(_) => log::debug!("Dropped notify {value:#X} for {name}, device not active"),
+        None => log::debug!("Unhandled notify {value:#X} for {name}"),
+    }
+}
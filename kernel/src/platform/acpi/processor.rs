@@ -0,0 +1,188 @@
+//! ACPI processor power states, or C-states, for `crate::idle`.
+//!
+//! States are read from the first processor's `_CST` package, which gives each state's type, exit
+//! latency and the register to access to enter it. Without `_CST`, the FADT's C2 latency and the
+//! processor control block give a single C2 state. C3 states entered through an I/O port need bus
+//! master activity to be stopped and the caches flushed first, which isn't supported, so only C3
+//! states entered with MWAIT are used, as the CPU keeps its caches coherent itself.
+
+use super::AcpiError;
+use super::namespace::{self, Node, Object, ObjectType};
+use super::resource::{self, Resource};
+use super::table::{self, Fadt, GenericAddress};
+use crate::idle::{CState, Entry};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Address space of `_CST` registers for states entered in a CPU specific way.
+const SPACE_FUNCTIONAL_FIXED_HARDWARE: u8 = 0x7F;
+/// Functional fixed hardware class, in the register's bit offset, for states entered with MWAIT
+/// using the register's address as the hint.
+const FFH_CLASS_MWAIT: u8 = 2;
+
+/// FADT C2 latencies above this mean C2 isn't supported.
+const FADT_MAX_C2_LATENCY_US: u16 = 100;
+/// Offset of the C2 level register in the processor control block.
+const BLOCK_C2_LEVEL_OFFSET: u64 = 4;
+
+/// Returns the first processor in the namespace, either a legacy `Processor` declaration or a
+/// processor device.
+fn first_processor() -> Option<Node> {
+    // A failed walk just leaves nothing found
+    let mut first = None;
+    let _ = namespace::walk(ObjectType::Processor, |node| {
+        first.get_or_insert(node);
+    });
+    if first.is_none() {
+        let _ = namespace::find_devices(c"ACPI0007", |node| {
+            first.get_or_insert(node);
+        });
+    }
+    first
+}
+
+/// Parses one state from a `_CST` package, returning `None` for states that can't be entered.
+fn parse_cst_state(state: &Object) -> Option<CState> {
+    let Object::Package(fields) = state else {
+        return None;
+    };
+    let [
+        Object::Buffer(register),
+        Object::Integer(acpi_type),
+        Object::Integer(latency_us),
+        ..,
+    ] = fields.as_slice()
+    else {
+        return None;
+    };
+    let Some(Resource::GenericRegister {
+        space_id,
+        bit_offset,
+        address,
+        ..
+    }) = resource::iter(register).next()
+    else {
+        return None;
+    };
+    let entry = match (*acpi_type, space_id) {
+        // C1 is always entered by halting, or something equivalent
+        (1, _) => Entry::Halt,
+        (_, SPACE_FUNCTIONAL_FIXED_HARDWARE) if bit_offset == FFH_CLASS_MWAIT => {
+            Entry::Mwait(address as u32)
+        }
+        (2, GenericAddress::SPACE_SYSTEM_IO) => Entry::LevelRegister(address as u16),
+        _ => return None,
+    };
+    Some(CState {
+        acpi_type: *acpi_type as u8,
+        latency_us: (*latency_us).min(u32::MAX as u64) as u32,
+        entry,
+    })
+}
+
+/// Parses a `_CST` package, a count followed by a package for each state.
+fn parse_cst(cst: &Object) -> Vec<CState> {
+    match cst {
+        Object::Package(elements) => elements
+            .iter()
+            .skip(1)
+            .filter_map(parse_cst_state)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the C2 state described by the FADT and the processor control block, if any.
+fn fadt_c_states(processor: Node) -> Vec<CState> {
+    let Ok(fadt) = (unsafe { table::get::<Fadt>() }) else {
+        return Vec::new();
+    };
+    let latency_us = fadt.c2_latency;
+    let Ok(Object::Processor {
+        block_address,
+        block_length,
+    }) = processor.evaluate_object(None)
+    else {
+        return Vec::new();
+    };
+    if latency_us > FADT_MAX_C2_LATENCY_US
+        || block_address == 0
+        || (block_length as u64) <= BLOCK_C2_LEVEL_OFFSET
+    {
+        return Vec::new();
+    }
+    vec![CState {
+        acpi_type: 2,
+        latency_us: latency_us as u32,
+        entry: Entry::LevelRegister((block_address + BLOCK_C2_LEVEL_OFFSET) as u16),
+    }]
+}
+
+/// Returns the C-states the firmware describes for the processors, in the order it lists them.
+/// Assumes every processor has the same states as the first. Must be called after the namespace
+/// is loaded.
+pub fn c_states() -> Vec<CState> {
+    let Some(processor) = first_processor() else {
+        return Vec::new();
+    };
+    match processor.evaluate_object(Some(c"_CST")) {
+        Ok(cst) => parse_cst(&cst),
+        Err(AcpiError::NOT_FOUND) => fadt_c_states(processor),
+        Err(err) => {
+            log::warn!("Failed to read _CST - {err:?}");
+            Vec::new()
+        }
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    fn cst_state(space_id: u8, bit_offset: u8, address: u64, acpi_type: u64) -> Object {
+        let mut register = vec![0x82, 12, 0, space_id, 8, bit_offset, 1];
+        register.extend_from_slice(&address.to_le_bytes());
+        register.extend_from_slice(&[0x79, 0]);
+        Object::Package(vec![
+            Object::Buffer(register),
+            Object::Integer(acpi_type),
+            Object::Integer(acpi_type * 50),
+            Object::Integer(1000 / acpi_type),
+        ])
+    }
+
+    #[kernel_test]
+    fn parses_cst() -> TestResult {
+        let cst = Object::Package(vec![
+            Object::Integer(4),
+            cst_state(SPACE_FUNCTIONAL_FIXED_HARDWARE, 1, 0, 1),
+            cst_state(GenericAddress::SPACE_SYSTEM_IO, 0, 0x414, 2),
+            // Needs bus master control, so skipped
+            cst_state(GenericAddress::SPACE_SYSTEM_IO, 0, 0x415, 3),
+            cst_state(SPACE_FUNCTIONAL_FIXED_HARDWARE, FFH_CLASS_MWAIT, 0x20, 3),
+        ]);
+        ktest_assert_eq!(
+            parse_cst(&cst),
+            [
+                CState {
+                    acpi_type: 1,
+                    latency_us: 50,
+                    entry: Entry::Halt,
+                },
+                CState {
+                    acpi_type: 2,
+                    latency_us: 100,
+                    entry: Entry::LevelRegister(0x414),
+                },
+                CState {
+                    acpi_type: 3,
+                    latency_us: 150,
+                    entry: Entry::Mwait(0x20),
+                },
+            ]
+        );
+        ktest_assert_eq!(parse_cst(&Object::Integer(0)), []);
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Reading of PCI interrupt routing from `_PRT` objects.
+
+use super::namespace::{self, Node};
+use super::resource::{self, Resource};
+use super::{AcpiError, acpica_sys};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+/// The GSI a PCI interrupt pin is connected to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciRoute {
+    pub bus: u8,
+    pub device: u8,
+    /// 0 for INTA through to 3 for INTD.
+    pub pin: u8,
+    pub gsi: u32,
+    pub level_triggered: bool,
+    pub active_low: bool,
+}
+
+/// An `ACPI_PCI_ROUTING_TABLE` entry, whose null terminated source path starts at `source` and
+/// runs on for as long as it needs.
+#[repr(C)]
+struct RoutingTableEntry {
+    length: u32,
+    pin: u32,
+    address: u64,
+    source_index: u32,
+    source: [u8; 4],
+}
+
+/// Length of an entry up to its source path, which may be all that's left of the table.
+const ENTRY_HEADER_LEN: usize = core::mem::offset_of!(RoutingTableEntry, source);
+
+/// Reads the interrupt routing of every PCI root bridge. Bridges whose routing can't be read
+/// are skipped. Must only be called after `init_namespace`.
+pub fn read_pci_routes() -> Vec<PciRoute> {
+    let mut routes = Vec::new();
+    let result = namespace::find_devices(c"PNP0A03", |bridge| {
+        let bus = match bridge.evaluate_integer(c"_BBN") {
+            Ok(bus) => bus as u8,
+            Err(AcpiError::NOT_FOUND) => 0,
+            Err(err) => {
+                log::warn!("Failed to read PCI root bridge bus number - {err:?}");
+                return;
+            }
+        };
+        if let Err(err) = read_bridge_routes(bridge, bus, &mut routes) {
+            log::warn!("Failed to read routing table of PCI bus {bus} - {err:?}");
+        }
+    });
+    if let Err(err) = result {
+        log::warn!("Failed to search for PCI root bridges - {err:?}");
+    }
+    routes
+}
+
+fn read_bridge_routes(bridge: Node, bus: u8, routes: &mut Vec<PciRoute>) -> Result<(), AcpiError> {
+    // u64s for alignment of the entries
+    let mut storage = vec![0u64; 64];
+    loop {
+        let mut buffer = acpica_sys::Buffer {
+            length: storage.len() * size_of::<u64>(),
+            pointer: storage.as_mut_ptr() as *mut (),
+        };
+        let result = <Result<(), AcpiError>>::from(unsafe {
+            acpica_sys::namespace::get_irq_routing_table(bridge.handle(), &mut buffer)
+        });
+        match result {
+            // The required length has been written to the buffer
+            Err(AcpiError::BUFFER_OVERFLOW) => {
+                storage = vec![0u64; buffer.length.div_ceil(size_of::<u64>())];
+            }
+            result => break result?,
+        }
+    }
+    parse_routing_table(&storage, bus, link_device_interrupt, routes)
+}
+
+/// Parses a routing table returned by ACPICA, looking up the interrupts of link devices with
+/// `link_interrupt`.
+fn parse_routing_table(
+    storage: &[u64],
+    bus: u8,
+    mut link_interrupt: impl FnMut(&CStr) -> Result<Option<(u32, bool, bool)>, AcpiError>,
+    routes: &mut Vec<PciRoute>,
+) -> Result<(), AcpiError> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(storage.as_ptr() as *const u8, size_of_val(storage)) };
+    let mut offset = 0;
+    while offset + ENTRY_HEADER_LEN <= bytes.len() {
+        // Entries are 8 byte aligned, and only the fields before the source are read through it
+        let entry = unsafe { bytes.as_ptr().add(offset) as *const RoutingTableEntry };
+        let (length, pin, address, source_index) = unsafe {
+            (
+                (*entry).length,
+                (*entry).pin,
+                (*entry).address,
+                (*entry).source_index,
+            )
+        };
+        if length == 0 {
+            break;
+        }
+        let end = offset + length as usize;
+        if (length as usize) < ENTRY_HEADER_LEN || end > bytes.len() {
+            return Err(AcpiError::TYPE);
+        }
+        let source = CStr::from_bytes_until_nul(&bytes[offset + ENTRY_HEADER_LEN..end])
+            .map_err(|_| AcpiError::TYPE)?;
+        offset = end;
+        // The address is the device number in the high word, the function is always 0xFFFF
+        let device = (address >> 16) as u8;
+        let pin = pin as u8;
+        let route = match source.is_empty() {
+            // Hardwired to a GSI, which is always level triggered and active low
+            true => Some((source_index, true, true)),
+            false => link_interrupt(source)?,
+        };
+        match route {
+            Some((gsi, level_triggered, active_low)) => routes.push(PciRoute {
+                bus,
+                device,
+                pin,
+                gsi,
+                level_triggered,
+                active_low,
+            }),
+            None => log::warn!(
+                "PCI interrupt link {source:?} for {bus:02x}:{device:02x} pin {pin} has no interrupt",
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the interrupt currently assigned to an interrupt link device.
+fn link_device_interrupt(path: &CStr) -> Result<Option<(u32, bool, bool)>, AcpiError> {
+    let resources = namespace::get_node(path)?.evaluate_buffer(c"_CRS")?;
+    Ok(
+        resource::iter(&resources).find_map(|resource| match resource {
+            Resource::Irq {
+                mask,
+                level_triggered,
+                active_low,
+            } if mask != 0 => Some((mask.trailing_zeros(), level_triggered, active_low)),
+            Resource::ExtendedInterrupt {
+                gsi,
+                level_triggered,
+                active_low,
+            } => Some((gsi, level_triggered, active_low)),
+            _ => None,
+        }),
+    )
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    fn entry(pin: u32, device: u64, source_index: u32, source: &[u8]) -> Vec<u8> {
+        let length = (ENTRY_HEADER_LEN + source.len() + 1).next_multiple_of(8);
+        let mut entry = Vec::new();
+        entry.extend_from_slice(&(length as u32).to_le_bytes());
+        entry.extend_from_slice(&pin.to_le_bytes());
+        entry.extend_from_slice(&(device << 16 | 0xFFFF).to_le_bytes());
+        entry.extend_from_slice(&source_index.to_le_bytes());
+        entry.extend_from_slice(source);
+        entry.resize(length, 0);
+        entry
+    }
+
+    #[kernel_test]
+    fn parses_routing_table() -> TestResult {
+        let mut bytes = entry(0, 1, 0, b"LNKA");
+        bytes.extend(entry(1, 2, 20, b""));
+        // Zero length terminator
+        bytes.extend_from_slice(&[0; 8]);
+        let storage: Vec<u64> = bytes
+            .chunks(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let mut routes = Vec::new();
+        let result = parse_routing_table(
+            &storage,
+            3,
+            |source| match source.to_bytes() {
+                b"LNKA" => Ok(Some((11, true, false))),
+                _ => Err(AcpiError::NOT_FOUND),
+            },
+            &mut routes,
+        );
+        ktest_assert_eq!(result, Ok(()));
+        ktest_assert_eq!(
+            routes,
+            [
+                PciRoute {
+                    bus: 3,
+                    device: 1,
+                    pin: 0,
+                    gsi: 11,
+                    level_triggered: true,
+                    active_low: false,
+                },
+                PciRoute {
+                    bus: 3,
+                    device: 2,
+                    pin: 1,
+                    gsi: 20,
+                    level_triggered: true,
+                    active_low: true,
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! Parsing of raw ACPI resource descriptors, as returned by `_CRS`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    /// ISA IRQs, one bit per IRQ.
+    Irq {
+        mask: u16,
+        level_triggered: bool,
+        active_low: bool,
+    },
+    Io {
+        minimum: u16,
+        length: u8,
+    },
+    FixedIo {
+        base: u16,
+        length: u8,
+    },
+    /// The first of the interrupts listed by an extended interrupt descriptor, usually the only one.
+    ExtendedInterrupt {
+        gsi: u32,
+        level_triggered: bool,
+        active_low: bool,
+    },
+    /// A register in an address space, laid out as in a `GenericAddress`.
+    GenericRegister {
+        space_id: u8,
+        bit_width: u8,
+        bit_offset: u8,
+        access_size: u8,
+        address: u64,
+    },
+    Other,
+}
+
+mod small {
+    pub const IRQ: u8 = 0x04;
+    pub const IO: u8 = 0x08;
+    pub const FIXED_IO: u8 = 0x09;
+    pub const END_TAG: u8 = 0x0F;
+}
+
+mod large {
+    pub const GENERIC_REGISTER: u8 = 0x02;
+    pub const EXTENDED_INTERRUPT: u8 = 0x09;
+}
+
+/// Iterates over the resources in a raw resource buffer, stopping at the end tag or at the first
+/// malformed descriptor.
+pub fn iter(mut buffer: &[u8]) -> impl Iterator<Item = Resource> + '_ {
+    core::iter::from_fn(move || {
+        let (&tag, rest) = buffer.split_first()?;
+        if tag & 0x80 != 0 {
+            // Large resource, 16 bit length follows the tag
+            let length = u16::from_le_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+            let body = rest.get(2..2 + length)?;
+            buffer = &rest[2 + length..];
+            return Some(match tag & 0x7F {
+                large::GENERIC_REGISTER if length >= 12 => Resource::GenericRegister {
+                    space_id: body[0],
+                    bit_width: body[1],
+                    bit_offset: body[2],
+                    access_size: body[3],
+                    address: u64::from_le_bytes(body[4..12].try_into().unwrap()),
+                },
+                large::EXTENDED_INTERRUPT if length >= 6 => Resource::ExtendedInterrupt {
+                    gsi: u32::from_le_bytes([body[2], body[3], body[4], body[5]]),
+                    level_triggered: body[0] & (1 << 1) == 0,
+                    active_low: body[0] & (1 << 2) != 0,
+                },
+                _ => Resource::Other,
+            });
+        }
+        let (item_type, length) = (tag >> 3 & 0xF, (tag & 0x7) as usize);
+        let body = rest.get(..length)?;
+        buffer = &rest[length..];
+        Some(match item_type {
+            small::IRQ if length >= 2 => {
+                // Without the flags byte, the IRQ is edge triggered and active high
+                let flags = body.get(2).copied().unwrap_or(1 << 0);
+                Resource::Irq {
+                    mask: u16::from_le_bytes([body[0], body[1]]),
+                    level_triggered: flags & (1 << 0) == 0,
+                    active_low: flags & (1 << 3) != 0,
+                }
+            }
+            small::IO if length >= 7 => Resource::Io {
+                minimum: u16::from_le_bytes([body[1], body[2]]),
+                length: body[6],
+            },
+            small::FIXED_IO if length >= 3 => Resource::FixedIo {
+                base: u16::from_le_bytes([body[0], body[1]]),
+                length: body[2],
+            },
+            small::END_TAG => return None,
+            _ => Resource::Other,
+        })
+    })
+}
@@ -0,0 +1,167 @@
+//! ACPI thermal zone monitoring.
+//!
+//! Thermal zones are polled periodically by a kernel thread. Crossing a zone's passive cooling
+//! threshold is logged, and reaching its critical threshold powers the system off.
+
+use super::namespace::{self, Node, ObjectType};
+use super::{AcpiError, power};
+use crate::init_state::{self, Subsystem};
+use crate::kthread;
+use crate::tunables;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+static THERMAL_ZONES: Mutex<Vec<ThermalZone>> = Mutex::new(Vec::new());
+
+/// A temperature in tenths of a Kelvin, as used by ACPI.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DeciKelvin(pub u64);
+
+impl fmt::Display for DeciKelvin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let deci_celsius = self.0 as i64 - 2732;
+        let sign = if deci_celsius < 0 { "-" } else { "" };
+        let abs = deci_celsius.unsigned_abs();
+        write!(f, "{sign}{}.{}C", abs / 10, abs % 10)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ThermalState {
+    Normal,
+    Passive,
+    Critical,
+}
+
+#[derive(Debug)]
+pub struct ThermalZone {
+    node: Node,
+    name: [u8; 4],
+    passive: Option<DeciKelvin>,
+    critical: Option<DeciKelvin>,
+    state: ThermalState,
+}
+
+impl ThermalZone {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name).unwrap_or("????")
+    }
+
+    pub fn temperature(&self) -> Result<DeciKelvin, AcpiError> {
+        self.node.evaluate_integer(c"_TMP").map(DeciKelvin)
+    }
+
+    fn state_at(&self, temperature: DeciKelvin) -> ThermalState {
+        if self
+            .critical
+            .is_some_and(|critical| temperature >= critical)
+        {
+            ThermalState::Critical
+        } else if self.passive.is_some_and(|passive| temperature >= passive) {
+            ThermalState::Passive
+        } else {
+            ThermalState::Normal
+        }
+    }
+}
+
+/// Reads an optional trip point, logging anything other than it not existing.
+fn read_trip_point(node: Node, zone_name: &[u8; 4], path: &core::ffi::CStr) -> Option<DeciKelvin> {
+    match node.evaluate_integer(path) {
+        Ok(value) => Some(DeciKelvin(value)),
+        Err(AcpiError::NOT_FOUND) => None,
+        Err(err) => {
+            log::warn!(
+                "Failed to read {path:?} for thermal zone {} - {err:?}",
+                zone_name.escape_ascii(),
+            );
+            None
+        }
+    }
+}
+
+/// Finds all thermal zones in the ACPI namespace and starts monitoring them.
+/// Must be called after the ACPI namespace has been loaded.
+pub fn init() {
+    init_state::begin(Subsystem::Thermal);
+    let mut zones = Vec::new();
+    let walk_result = namespace::walk(ObjectType::Thermal, |node| {
+        let name = node.name().unwrap_or(*b"????");
+        let passive = read_trip_point(node, &name, c"_PSV");
+        let critical = read_trip_point(node, &name, c"_CRT");
+        zones.push(ThermalZone {
+            node,
+            name,
+            passive,
+            critical,
+            state: ThermalState::Normal,
+        });
+    });
+    if let Err(err) = walk_result {
+        log::warn!("Failed to enumerate thermal zones - {err:?}");
+        return;
+    }
+    for zone in zones.iter() {
+        log::info!(
+            "Thermal zone {}: passive {:?}, critical {:?}",
+            zone.name(),
+            zone.passive.map(|t| t.0),
+            zone.critical.map(|t| t.0),
+        );
+    }
+    init_state::finish(Subsystem::Thermal);
+    if zones.is_empty() {
+        log::debug!("No thermal zones found");
+        return;
+    }
+    *THERMAL_ZONES.lock() = zones;
+    if let Err(err) = kthread::spawn("thermal", poll_thread, 0) {
+        log::error!("Failed to start thermal monitoring thread - {err}");
+    }
+}
+
+/// Reads the temperature of every thermal zone, logging threshold crossings.
+/// Returns the state of the hottest zone.
+pub fn poll() -> ThermalState {
+    let mut zones = THERMAL_ZONES.lock();
+    let mut hottest = ThermalState::Normal;
+    for zone in zones.iter_mut() {
+        let temperature = match zone.temperature() {
+            Ok(temperature) => temperature,
+            Err(err) => {
+                log::warn!(
+                    "Failed to read temperature of thermal zone {} - {err:?}",
+                    zone.name()
+                );
+                continue;
+            }
+        };
+        let new_state = zone.state_at(temperature);
+        if new_state > zone.state {
+            log::warn!(
+                "Thermal zone {} entered {new_state:?} state at {temperature}",
+                zone.name(),
+            );
+        } else if new_state < zone.state {
+            log::info!(
+                "Thermal zone {} returned to {new_state:?} state at {temperature}",
+                zone.name(),
+            );
+        }
+        zone.state = new_state;
+        hottest = ThermalState::max(hottest, new_state);
+    }
+    hottest
+}
+
+fn poll_thread(_: usize) -> usize {
+    loop {
+        if poll() == ThermalState::Critical {
+            log::error!("Critical temperature reached, shutting down");
+            let err = unsafe { power::shutdown() };
+            panic!("emergency thermal shutdown failed - {err:?}");
+        }
+        kthread::sleep_ms(tunables::THERMAL_POLL_INTERVAL_MS.get());
+    }
+}
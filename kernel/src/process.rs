@@ -1,9 +1,28 @@
 //! User and kernel processes, as well as scheduling.
 
 use crate::arch;
+use crate::arch::address_space::{MapMemError, UserAddressSpace};
+use crate::arch::page_allocation::ReservePageError;
+use crate::arch::paging::PAGE_SIZE;
+use crate::arch::syscall::SyscallError;
+use crate::cpio;
+use crate::file::FileTable;
+use crate::kthread;
+use crate::net::tcp::SocketTable;
 use crate::physical_block_allocator::{PageBox, PhysicalBlockAllocator};
+use crate::shared_memory::{self, SharedMemory, SharedMemoryHandle};
+use crate::syscall_errors::SyscallErrorLog;
+use crate::time_namespace::TimeNamespace;
+use crate::user_memory::{self, UserArgs};
+use crate::vma::{
+    Segment, SegmentBacking, SegmentFlags, VMAAllocator, VMAFaultError, VMAMapError, VMAResizeError,
+};
+use crate::wait_queue::WaitQueue;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use core::marker::PhantomData;
 use core::ptr::NonNull;
+use core::task::Poll;
 use spin::Mutex;
 
 pub mod process_list {
@@ -54,7 +73,666 @@ pub mod process_list {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// The init process, which orphaned processes are re-parented to while it's running. Its ID is
+/// kept for it, see `Process::new_init`.
+pub const INIT_PROCESS: ProcessId = ProcessId(1);
+/// Exit status of processes killed by an exception, or whose threads all exited without any of
+/// them calling exit.
+pub const EXIT_STATUS_KILLED: usize = usize::MAX;
+
+/// Parent and exit status of every process which hasn't been waited for yet.
+///
+/// Exited processes are zombies, only kept as their exit status until their parent collects it
+/// with `wait`. When a process exits its children are re-parented to `INIT_PROCESS`, or forgotten
+/// about if that has exited too, in which case they're reaped as soon as they exit.
+pub mod lifecycle {
+    use super::{BTreeMap, INIT_PROCESS, Mutex, ProcessId, WaitQueue};
+
+    static TABLE: Mutex<ProcessTable> = Mutex::new(ProcessTable {
+        // Starting after the ID kept for init
+        next_id: INIT_PROCESS.0 + 1,
+        entries: BTreeMap::new(),
+    });
+    /// Woken whenever a process exits.
+    static EXITED: WaitQueue = WaitQueue::new();
+
+    struct ProcessTable {
+        next_id: u64,
+        entries: BTreeMap<ProcessId, Entry>,
+    }
+
+    struct Entry {
+        /// `None` if nothing will wait for the process.
+        parent: Option<ProcessId>,
+        exit_status: Option<usize>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+    pub enum WaitError {
+        #[error("no matching child process")]
+        NoChildren,
+    }
+
+    /// Assigns an ID to a new process, which is a child of `parent`.
+    pub(super) fn register(parent: Option<ProcessId>) -> ProcessId {
+        let mut table = TABLE.lock();
+        let id = ProcessId(table.next_id);
+        table.next_id += 1;
+        table.entries.insert(
+            id,
+            Entry {
+                parent,
+                exit_status: None,
+            },
+        );
+        id
+    }
+
+    /// Assigns `INIT_PROCESS` to a new process with no parent, returning `None` if another init
+    /// process hasn't been reaped yet.
+    pub(super) fn register_init() -> Option<ProcessId> {
+        let mut table = TABLE.lock();
+        if table.entries.contains_key(&INIT_PROCESS) {
+            return None;
+        }
+        table.entries.insert(
+            INIT_PROCESS,
+            Entry {
+                parent: None,
+                exit_status: None,
+            },
+        );
+        Some(INIT_PROCESS)
+    }
+
+    /// Records that `id` has exited with `status`, making it a zombie until its parent waits for
+    /// it, and re-parents its children.
+    pub(super) fn mark_exited(id: ProcessId, status: usize) {
+        {
+            let mut table = TABLE.lock();
+            let init_running = id != INIT_PROCESS
+                && table
+                    .entries
+                    .get(&INIT_PROCESS)
+                    .is_some_and(|init| init.exit_status.is_none());
+            let new_parent = init_running.then_some(INIT_PROCESS);
+            table.entries.retain(|_, entry| {
+                if entry.parent == Some(id) {
+                    entry.parent = new_parent;
+                }
+                // Zombies nothing will wait for are reaped straight away
+                entry.parent.is_some() || entry.exit_status.is_none()
+            });
+            if let Some(entry) = table.entries.get_mut(&id) {
+                entry.exit_status = Some(status);
+                if entry.parent.is_none() {
+                    table.entries.remove(&id);
+                }
+            }
+        }
+        EXITED.wake_all();
+    }
+
+    /// Blocks until a child of `parent` exits, or `child` if it's given, then reaps it and
+    /// returns its ID and exit status. Children which have already exited are reaped first.
+    pub fn wait(
+        parent: ProcessId,
+        child: Option<ProcessId>,
+    ) -> Result<(ProcessId, usize), WaitError> {
+        let mut result = None;
+        EXITED.wait_until(|| {
+            result = try_reap(parent, child);
+            result.is_some()
+        });
+        result.unwrap()
+    }
+
+    /// Reaps an exited child of `parent` matching `child`, returning `None` if there are matching
+    /// children but none of them have exited yet.
+    fn try_reap(
+        parent: ProcessId,
+        child: Option<ProcessId>,
+    ) -> Option<Result<(ProcessId, usize), WaitError>> {
+        let mut table = TABLE.lock();
+        let mut has_children = false;
+        let mut exited = None;
+        for (id, entry) in &table.entries {
+            if entry.parent != Some(parent) || child.is_some_and(|child| *id != child) {
+                continue;
+            }
+            has_children = true;
+            if let Some(status) = entry.exit_status {
+                exited = Some((*id, status));
+                break;
+            }
+        }
+        if !has_children {
+            return Some(Err(WaitError::NoChildren));
+        }
+        let (id, status) = exited?;
+        table.entries.remove(&id);
+        Some(Ok((id, status)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ProcessCreateError {
+    #[error("failed to allocate page table - {0}")]
+    PageTable(#[from] ReservePageError),
+    #[error("failed to allocate memory area tree")]
+    MemoryAreas,
+    #[error("init process already exists")]
+    InitExists,
+}
+
+/// An address space and the memory areas within it, shared by the process's threads.
 pub struct Process {
     pub next: Option<NonNull<Process>>,
-    pub registers: arch::process::RegisterStore,
+    pub id: ProcessId,
+    /// Set once any of the process's threads calls exit, after which the others exit too.
+    exit_status: Mutex<Option<usize>>,
+    /// Cached, as the scheduler needs it without taking the memory lock.
+    page_table_address: usize,
+    pub memory: Mutex<ProcessMemory>,
+    pub files: Mutex<FileTable>,
+    pub sockets: Mutex<SocketTable>,
+    /// Clock offsets of the process.
+    pub time: TimeNamespace,
+    /// The process's most recent failed system calls.
+    pub syscall_errors: Mutex<SyscallErrorLog>,
+}
+
+pub struct ProcessMemory {
+    pub segments: VMAAllocator,
+    /// Pages allocated for the process's segments, their page tables and the segment tree.
+    pub pages_used: usize,
+}
+
+// `next` is only followed while the process list is locked.
+unsafe impl Send for Process {}
+unsafe impl Sync for Process {}
+
+impl Process {
+    /// Creates a process with no memory mapped in its lower half.
+    pub fn new() -> Result<Self, ProcessCreateError> {
+        let parent = kthread::current_process().map(|process| process.id);
+        Self::create(|| Ok(lifecycle::register(parent)))
+    }
+
+    /// Creates the init process, with no memory mapped in its lower half. Fails if another init
+    /// process hasn't been reaped yet.
+    pub fn new_init() -> Result<Self, ProcessCreateError> {
+        Self::create(|| lifecycle::register_init().ok_or(ProcessCreateError::InitExists))
+    }
+
+    /// Creates a process with the ID returned by `register`, once nothing else can fail.
+    fn create(
+        register: impl FnOnce() -> Result<ProcessId, ProcessCreateError>,
+    ) -> Result<Self, ProcessCreateError> {
+        let address_space = UserAddressSpace::new()?;
+        let page_table_address = address_space.page_table_address();
+        // Counting the top level page table
+        let mut pages_used = 1;
+        let segments = VMAAllocator::new(address_space, &mut pages_used)
+            .map_err(|_| ProcessCreateError::MemoryAreas)?;
+        Ok(Self {
+            next: None,
+            id: register()?,
+            exit_status: Mutex::new(None),
+            page_table_address,
+            memory: Mutex::new(ProcessMemory {
+                segments,
+                pages_used,
+            }),
+            files: Mutex::new(FileTable::new()),
+            sockets: Mutex::new(SocketTable::new()),
+            time: TimeNamespace::new(),
+            syscall_errors: Mutex::new(SyscallErrorLog::new()),
+        })
+    }
+
+    /// Returns the physical address of the process's top level page table.
+    pub fn page_table_address(&self) -> usize {
+        self.page_table_address
+    }
+
+    /// Returns the status the process exited with, or `None` if it's still running.
+    pub fn exit_status(&self) -> Option<usize> {
+        *self.exit_status.lock()
+    }
+
+    /// Exits the process with `status`, closing its files and sockets, unmapping all of its memory
+    /// and waking its parent if it's waiting. Its threads exit the next time they return to user
+    /// code. Does nothing if the process has already exited. Must be called from one of the
+    /// process's threads.
+    pub fn exit(&self, status: usize) {
+        {
+            let mut exit_status = self.exit_status.lock();
+            if exit_status.is_some() {
+                return;
+            }
+            *exit_status = Some(status);
+        }
+        self.files.lock().close_all();
+        self.sockets.lock().close_all();
+        let segments = self.memory.lock().segments.segments();
+        for segment in segments {
+            // Segments locked by another thread's task are left to be freed with the process
+            let Ok(mut task) = self.memory.lock().segments.start_unmap(segment.start) else {
+                continue;
+            };
+            let pages_freed =
+                self.run_task(|segments, should_suspend| task.run(segments, should_suspend));
+            let mut memory = self.memory.lock();
+            memory.pages_used = memory.pages_used.saturating_sub(pages_freed);
+        }
+        // Reloaded to flush translations of the freed pages
+        unsafe { self.memory.lock().segments.address_space().load() };
+        lifecycle::mark_exited(self.id, status);
+    }
+
+    /// Handles a page fault at `address` from one of the process's threads, by mapping the page if
+    /// it's in a file backed segment. Returns an error if the fault can't be resolved.
+    pub fn handle_page_fault(&self, address: usize, write: bool) -> Result<(), VMAFaultError> {
+        let mut memory = self.memory.lock();
+        let ProcessMemory {
+            segments,
+            pages_used,
+        } = &mut *memory;
+        segments.handle_page_fault(pages_used, address, write)
+    }
+
+    /// Runs a segment map or unmap task to completion, letting other threads run every
+    /// `PAGES_PER_YIELD` pages.
+    fn run_task<T, F>(&self, mut run: F) -> T
+    where
+        F: FnMut(&mut VMAAllocator, &mut dyn FnMut() -> bool) -> Poll<T>,
+    {
+        loop {
+            let mut pages_done = 0;
+            let mut should_suspend = || {
+                pages_done += 1;
+                pages_done > PAGES_PER_YIELD
+            };
+            let result = run(&mut self.memory.lock().segments, &mut should_suspend);
+            match result {
+                Poll::Ready(value) => return value,
+                Poll::Pending => kthread::yield_now(),
+            }
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        if self.exit_status.get_mut().is_none() {
+            lifecycle::mark_exited(self.id, EXIT_STATUS_KILLED);
+        }
+    }
+}
+
+/// Bits of the protection argument of memory system calls.
+pub const PROTECTION_READ: usize = 1 << 0;
+pub const PROTECTION_WRITE: usize = 1 << 1;
+pub const PROTECTION_EXECUTE: usize = 1 << 2;
+/// Map flag to map at exactly the given address, instead of using it as a hint.
+pub const MAP_FIXED: usize = 1 << 0;
+
+/// Longest initrd path accepted by the map file syscall.
+const MAX_PATH_LEN: usize = 256;
+/// Pages mapped or unmapped by a system call before other threads are given a chance to run.
+const PAGES_PER_YIELD: usize = 64;
+
+fn segment_flags(protection: usize) -> Result<SegmentFlags, SyscallError> {
+    if protection & !(PROTECTION_READ | PROTECTION_WRITE | PROTECTION_EXECUTE) != 0 {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    Ok(SegmentFlags {
+        read: protection & PROTECTION_READ != 0,
+        write: protection & PROTECTION_WRITE != 0,
+        execute: protection & PROTECTION_EXECUTE != 0,
+    })
+}
+
+/// Returns the page aligned length of `len` bytes at `address`, if they're all valid user
+/// addresses.
+fn user_range_len(address: usize, len: usize) -> Result<usize, SyscallError> {
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE)
+        .filter(|len| *len != 0)
+        .ok_or(SyscallError::INVALID_ARGUMENT)?;
+    let end = address
+        .checked_add(len - 1)
+        .ok_or(SyscallError::INVALID_ARGUMENT)?;
+    if !address.is_multiple_of(PAGE_SIZE) || !arch::process::is_user_address_valid(end) {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    Ok(len)
+}
+
+/// Returns the page aligned address to map at, and whether it's fixed rather than a hint.
+fn map_address(address: usize, map_flags: usize) -> Result<(usize, bool), SyscallError> {
+    if map_flags & !MAP_FIXED != 0 {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let fixed = map_flags & MAP_FIXED != 0;
+    // Nothing is mapped at the null page
+    let address = match fixed {
+        true if address < PAGE_SIZE => return Err(SyscallError::INVALID_ARGUMENT),
+        true => address,
+        false => address.next_multiple_of(PAGE_SIZE).max(PAGE_SIZE),
+    };
+    Ok((address, fixed))
+}
+
+fn map_error(err: VMAMapError) -> SyscallError {
+    match err {
+        VMAMapError::SegmentAlreadyExists => SyscallError::INVALID_ARGUMENT,
+        VMAMapError::OutOfMemory | VMAMapError::OutOfAddressSpace => SyscallError::OUT_OF_MEMORY,
+    }
+}
+
+/// Records a failed system call against the current process, if there is one.
+pub fn record_syscall_error(number: usize, args: [usize; 6], error: SyscallError) {
+    if let Some(process) = kthread::current_process() {
+        process.syscall_errors.lock().record(number, args, error);
+    }
+}
+
+fn current_process() -> Arc<Process> {
+    kthread::current_process().expect("process system call from a kernel thread")
+}
+
+/// Handler for the map memory syscall. Maps `len` bytes of zeroed memory, returning its address.
+/// Without `MAP_FIXED`, `address` is a hint, and the memory is mapped in the lowest free area at
+/// or above it.
+pub fn syscall_map_mem(
+    address: usize,
+    len: usize,
+    protection: usize,
+    map_flags: usize,
+) -> Result<usize, SyscallError> {
+    let flags = segment_flags(protection)?;
+    let (address, fixed) = map_address(address, map_flags)?;
+    let len = user_range_len(address, len)?;
+    let segment = Segment {
+        start: address,
+        len,
+        flags,
+        backing: None,
+    };
+    let process = current_process();
+    let mut task = {
+        let mut memory = process.memory.lock();
+        let ProcessMemory {
+            segments,
+            pages_used,
+        } = &mut *memory;
+        let result = match fixed {
+            true => unsafe { segments.start_try_map_at(pages_used, segment) },
+            false => unsafe { segments.start_find_map(pages_used, segment) },
+        };
+        result.map_err(map_error)?
+    };
+    let start = task.start_address();
+    let pages_allocated = process
+        .run_task(|segments, should_suspend| task.run(segments, should_suspend))
+        .map_err(|MapMemError::OutOfMemory| SyscallError::OUT_OF_MEMORY)?;
+    process.memory.lock().pages_used += pages_allocated;
+    Ok(start)
+}
+
+/// Handler for the map shared memory syscall. Maps the whole of the shared memory object `handle`,
+/// returning its address. `address` and `map_flags` are as for the map memory syscall.
+pub fn syscall_map_shared(
+    handle: usize,
+    address: usize,
+    protection: usize,
+    map_flags: usize,
+) -> Result<usize, SyscallError> {
+    let object = shared_memory::get(SharedMemoryHandle(handle))?;
+    map_shared(object, address, protection, map_flags)
+}
+
+/// Maps `object` into the current process with `protection`, returning the address. `address` and
+/// `map_flags` are as for the map memory syscall.
+pub fn map_shared(
+    object: Arc<SharedMemory>,
+    address: usize,
+    protection: usize,
+    map_flags: usize,
+) -> Result<usize, SyscallError> {
+    let flags = segment_flags(protection)?;
+    let (address, fixed) = map_address(address, map_flags)?;
+    user_range_len(address, object.size())?;
+    let process = current_process();
+    let mut task = {
+        let mut memory = process.memory.lock();
+        let ProcessMemory {
+            segments,
+            pages_used,
+        } = &mut *memory;
+        let result = match fixed {
+            true => unsafe { segments.start_try_map_shared_at(pages_used, address, object, flags) },
+            false => unsafe { segments.start_find_map_shared(pages_used, address, object, flags) },
+        };
+        result.map_err(map_error)?
+    };
+    let start = task.start_address();
+    let pages_allocated = process
+        .run_task(|segments, should_suspend| task.run(segments, should_suspend))
+        .map_err(|MapMemError::OutOfMemory| SyscallError::OUT_OF_MEMORY)?;
+    process.memory.lock().pages_used += pages_allocated;
+    Ok(start)
+}
+
+#[derive(UserArgs)]
+struct MapFileArgs {
+    #[user(read, len = path_len, max_len = MAX_PATH_LEN)]
+    path_ptr: *const u8,
+    path_len: usize,
+}
+
+/// Handler for the map file syscall. Maps the file at `path` in the initrd from `offset`, which
+/// must be page aligned, to its end, returning the address. Pages are read from the file as
+/// they're touched. `address` and `map_flags` are as for the map memory syscall.
+pub fn syscall_map_file(
+    path_ptr: *const u8,
+    path_len: usize,
+    offset: usize,
+    address: usize,
+    protection: usize,
+    map_flags: usize,
+) -> Result<usize, SyscallError> {
+    let args = MapFileArgs { path_ptr, path_len };
+    args.validate()?;
+    let path = user_memory::read_string(args.path_ptr, args.path_len, MAX_PATH_LEN)?;
+    let flags = segment_flags(protection)?;
+    let (address, fixed) = map_address(address, map_flags)?;
+    let initrd = cpio::INITRD.lock().ok_or(SyscallError::INVALID_ARGUMENT)?;
+    let file = cpio::find_file(initrd, path.as_bytes()).ok_or(SyscallError::INVALID_ARGUMENT)?;
+    if !offset.is_multiple_of(PAGE_SIZE) || offset >= file.len() {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let len = user_range_len(address, file.len() - offset)?;
+    let segment = Segment {
+        start: address,
+        len,
+        flags,
+        backing: Some(SegmentBacking { file, offset }),
+    };
+    let process = current_process();
+    let mut memory = process.memory.lock();
+    let ProcessMemory {
+        segments,
+        pages_used,
+    } = &mut *memory;
+    let result = match fixed {
+        true => unsafe { segments.start_try_map_at(pages_used, segment) },
+        false => unsafe { segments.start_find_map(pages_used, segment) },
+    };
+    let mut task = result.map_err(map_error)?;
+    // Nothing is mapped up front, so this completes straight away
+    let Poll::Ready(Ok(_)) = task.run(segments, || false) else {
+        unreachable!();
+    };
+    Ok(task.start_address())
+}
+
+/// Handler for the unmap memory syscall. Unmaps the whole segment containing `address`.
+pub fn syscall_unmap_mem(address: usize) -> Result<usize, SyscallError> {
+    if !arch::process::is_user_address_valid(address) {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let process = current_process();
+    let mut task = process
+        .memory
+        .lock()
+        .segments
+        .start_unmap(address)
+        .map_err(|_| SyscallError::INVALID_ARGUMENT)?;
+    let pages_freed =
+        process.run_task(|segments, should_suspend| task.run(segments, should_suspend));
+    let mut memory = process.memory.lock();
+    memory.pages_used = memory.pages_used.saturating_sub(pages_freed);
+    // Reloaded to flush translations of the freed pages
+    unsafe { memory.segments.address_space().load() };
+    Ok(0)
+}
+
+/// Handler for the protect memory syscall. Changes the protection of the segment at `address`,
+/// which must be `len` bytes long.
+pub fn syscall_protect_mem(
+    address: usize,
+    len: usize,
+    protection: usize,
+) -> Result<usize, SyscallError> {
+    let flags = segment_flags(protection)?;
+    // Pages stay accessible from user mode once mapped, so read access can't be taken away
+    if !flags.read {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let len = user_range_len(address, len)?;
+    let process = current_process();
+    let mut memory = process.memory.lock();
+    memory
+        .segments
+        .protect(address, len, flags)
+        .map_err(|_| SyscallError::INVALID_ARGUMENT)?;
+    // Reloaded to flush translations with the old flags
+    unsafe { memory.segments.address_space().load() };
+    Ok(0)
+}
+
+/// Handler for the resize memory syscall. Grows or shrinks the segment starting at `address` in
+/// place to `new_len` bytes. Growing fails if the segment isn't followed by enough free space.
+pub fn syscall_resize_mem(address: usize, new_len: usize) -> Result<usize, SyscallError> {
+    let new_len = user_range_len(address, new_len)?;
+    let process = current_process();
+    let mut task = {
+        let mut memory = process.memory.lock();
+        let ProcessMemory {
+            segments,
+            pages_used,
+        } = &mut *memory;
+        segments
+            .start_resize_segment(pages_used, address, new_len)
+            .map_err(|err| match err {
+                VMAResizeError::NoSpace | VMAResizeError::OutOfMemory => {
+                    SyscallError::OUT_OF_MEMORY
+                }
+                _ => SyscallError::INVALID_ARGUMENT,
+            })?
+    };
+    let (pages_allocated, pages_freed) = process
+        .run_task(|segments, should_suspend| task.run(segments, should_suspend))
+        .map_err(|MapMemError::OutOfMemory| SyscallError::OUT_OF_MEMORY)?;
+    let mut memory = process.memory.lock();
+    memory.pages_used = (memory.pages_used + pages_allocated).saturating_sub(pages_freed);
+    if pages_freed != 0 {
+        // Reloaded to flush translations of the freed pages
+        unsafe { memory.segments.address_space().load() };
+    }
+    Ok(0)
+}
+
+/// Handler for the exit syscall. Exits the current process with `status`, which its parent
+/// collects with the wait syscall.
+pub fn syscall_exit(status: usize) -> Result<usize, SyscallError> {
+    // Statuses are truncated so they can't be confused with `EXIT_STATUS_KILLED`
+    current_process().exit(status & u32::MAX as usize);
+    Ok(0)
+}
+
+/// Handler for the wait syscall. Blocks until the child process `pid` exits, or any child if
+/// `pid` is 0, returning its ID. The exit status is written to `status_ptr` unless it's null.
+pub fn syscall_wait(pid: usize, status_ptr: usize) -> Result<usize, SyscallError> {
+    let child = match pid {
+        0 => None,
+        pid => Some(ProcessId(pid as u64)),
+    };
+    let parent = current_process().id;
+    let (id, status) = lifecycle::wait(parent, child)
+        .map_err(|lifecycle::WaitError::NoChildren| SyscallError::NO_CHILDREN)?;
+    if status_ptr != 0 {
+        user_memory::copy_to_user(status_ptr, &status.to_ne_bytes())?;
+    }
+    Ok(id.0 as usize)
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+    use alloc::format;
+
+    #[kernel_test]
+    fn init_id_is_kept_for_init() -> TestResult {
+        let process = Process::new().map_err(|err| format!("{err}"))?;
+        ktest_assert!(process.id != INIT_PROCESS);
+        let init = Process::new_init().map_err(|err| format!("{err}"))?;
+        ktest_assert_eq!(init.id, INIT_PROCESS);
+        ktest_assert_eq!(
+            Process::new_init().map(|_| ()),
+            Err(ProcessCreateError::InitExists)
+        );
+        // Never run, so reaped as soon as it's dropped
+        drop(init);
+        ktest_assert!(Process::new_init().is_ok());
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn orphans_are_reaped_by_init() -> TestResult {
+        // Stand-ins for processes, as only the table is involved
+        let parent = lifecycle::register(None);
+        let first = lifecycle::register(Some(parent));
+        let second = lifecycle::register(Some(parent));
+        let grandchild = lifecycle::register(Some(second));
+        lifecycle::mark_exited(first, 3);
+        ktest_assert_eq!(lifecycle::wait(parent, None), Ok((first, 3)));
+        ktest_assert_eq!(
+            lifecycle::wait(parent, Some(first)),
+            Err(lifecycle::WaitError::NoChildren),
+        );
+        lifecycle::mark_exited(second, 4);
+        lifecycle::mark_exited(parent, 0);
+        // Children of exited processes are adopted by init, or reaped once they exit if it isn't
+        // running
+        lifecycle::mark_exited(grandchild, 5);
+        ktest_assert_eq!(
+            lifecycle::wait(parent, None),
+            Err(lifecycle::WaitError::NoChildren),
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,169 @@
+//! Sampling profiler, for finding the kernel's hot paths.
+//!
+//! While it runs, each CPU is interrupted `rate_hz` times a second and records the address of the
+//! interrupted instruction in a ring buffer in its thread local storage, keeping the most recent
+//! `RING_LEN` samples. How CPUs are interrupted is up to the architecture, see `arch::profiler`.
+//! Samples may be recorded from NMIs, so recording is lock free. `write_report` tallies the
+//! samples by the kernel function containing them, for the kshell `prof` command.
+
+use crate::arch;
+use crate::debugging::symbols;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub use arch::profiler::Source;
+
+/// Samples kept by each CPU.
+pub const RING_LEN: usize = 4096;
+/// Recorded in place of the address for samples of user code.
+pub const USER_SAMPLE: usize = 0;
+
+/// The most recent samples taken on a CPU.
+pub struct SampleRing {
+    addresses: Box<[AtomicUsize]>,
+    /// Samples recorded since the ring was cleared, including those since overwritten.
+    count: AtomicUsize,
+}
+
+impl SampleRing {
+    pub fn new() -> Self {
+        Self {
+            addresses: (0..RING_LEN).map(|_| AtomicUsize::new(0)).collect(),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records a sample, overwriting the oldest if the ring is full. Must only be called on the
+    /// ring's CPU.
+    pub fn record(&self, address: usize) {
+        let count = self.count.load(Ordering::Relaxed);
+        self.addresses[count % RING_LEN].store(address, Ordering::Relaxed);
+        self.count.store(count + 1, Ordering::Release);
+    }
+
+    pub fn clear(&self) {
+        self.count.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the number of samples recorded since the ring was cleared.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Returns the samples held by the ring, in no particular order.
+    pub fn samples(&self) -> impl Iterator<Item = usize> + '_ {
+        self.addresses[..self.count().min(RING_LEN)]
+            .iter()
+            .map(|address| address.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for SampleRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a sample was taken in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Location {
+    User,
+    /// The kernel function starting at the given address.
+    Function(usize),
+    /// Kernel code outside of any known function.
+    Unknown,
+}
+
+/// Counts `samples` by where they were taken, using `function_start` to find the start of the
+/// kernel function containing an address. Returns the counts from most to least samples.
+fn tally(
+    samples: impl Iterator<Item = usize>,
+    function_start: impl Fn(usize) -> Option<usize>,
+) -> Vec<(Location, usize)> {
+    let mut counts = BTreeMap::new();
+    for address in samples {
+        let location = match address {
+            USER_SAMPLE => Location::User,
+            _ => function_start(address).map_or(Location::Unknown, Location::Function),
+        };
+        *counts.entry(location).or_insert(0) += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+    counts
+}
+
+/// Starts profiling the current CPU at `rate_hz`, discarding its previous samples. Returns how
+/// the CPU is being interrupted.
+pub fn start(rate_hz: u32) -> Source {
+    arch::profiler::start(rate_hz)
+}
+
+pub fn stop() {
+    arch::profiler::stop();
+}
+
+/// Writes the `max_entries` locations with the most samples on the current CPU.
+pub fn write_report(out: &mut (impl fmt::Write + ?Sized), max_entries: usize) -> fmt::Result {
+    let Some(ring) = arch::profiler::samples() else {
+        return writeln!(out, "Profiler hasn't been started");
+    };
+    let total = ring.count().min(RING_LEN);
+    writeln!(out, "{} samples, showing the last {total}", ring.count())?;
+    let function_start = |address| symbols::resolve(address).map(|(_, offset)| address - offset);
+    for (location, count) in tally(ring.samples(), function_start)
+        .into_iter()
+        .take(max_entries)
+    {
+        let per_mille = count * 1000 / total;
+        let (percent, tenths) = (per_mille / 10, per_mille % 10);
+        write!(out, "{percent:>3}.{tenths}% {count:>5}  ")?;
+        match location {
+            Location::User => writeln!(out, "<user>")?,
+            Location::Unknown => writeln!(out, "<unknown>")?,
+            Location::Function(address) => match symbols::resolve(address) {
+                Some((symbol, _)) => writeln!(out, "{}", symbol.demangled())?,
+                None => writeln!(out, "{address:#x}")?,
+            },
+        }
+    }
+    Ok(())
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn tallies_samples_by_function() -> TestResult {
+        let ring = SampleRing::new();
+        // Functions start every 0x100 bytes, with nothing known below 0x1000
+        for address in [0x1010, 0x1020, USER_SAMPLE, 0x20F0, 0x10, 0x1080] {
+            ring.record(address);
+        }
+        let function_start = |address: usize| (address >= 0x1000).then_some(address & !0xFF);
+        ktest_assert_eq!(
+            tally(ring.samples(), function_start),
+            [
+                (Location::Function(0x1000), 3),
+                (Location::User, 1),
+                (Location::Function(0x2000), 1),
+                (Location::Unknown, 1),
+            ]
+        );
+        // Older samples are overwritten once the ring is full
+        for _ in 0..RING_LEN {
+            ring.record(0x2010);
+        }
+        ktest_assert_eq!(ring.count(), RING_LEN + 6);
+        ktest_assert_eq!(
+            tally(ring.samples(), function_start),
+            [(Location::Function(0x2000), RING_LEN)]
+        );
+        Ok(())
+    }
+}
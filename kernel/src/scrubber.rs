@@ -0,0 +1,124 @@
+//! Background testing of free physical memory, enabled with `memscrub`.
+//!
+//! Most machines this kernel runs on have no ECC, so bad RAM only shows up as corrupted data. The
+//! scrubber thread walks every free page in turn, writing test patterns and reading them back, and
+//! reports any mismatch. Pages that fail are kept reserved, so they're never handed out.
+//!
+//! Threads aren't prioritised, so scrubbing only happens while the system looks idle, with nothing
+//! else waiting to run. It's spread out over time, to keep the cost low even then.
+
+use crate::arch::page_allocation;
+use crate::arch::paging::PAGE_SIZE;
+use crate::kthread;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Pages scrubbed each time the scrubber runs.
+const PAGES_PER_BATCH: usize = 16;
+const BATCH_INTERVAL_MS: u64 = 10;
+
+static PAGES_SCRUBBED: AtomicUsize = AtomicUsize::new(0);
+static PASSES: AtomicUsize = AtomicUsize::new(0);
+static BAD_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScrubStats {
+    pub pages_scrubbed: usize,
+    /// Completed passes over all of physical memory.
+    pub passes: usize,
+    pub bad_pages: usize,
+}
+
+impl fmt::Display for ScrubStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Scrubber: {} pages scrubbed over {} full passes, {} bad pages",
+            self.pages_scrubbed, self.passes, self.bad_pages,
+        )
+    }
+}
+
+pub fn stats() -> ScrubStats {
+    ScrubStats {
+        pages_scrubbed: PAGES_SCRUBBED.load(Ordering::Relaxed),
+        passes: PASSES.load(Ordering::Relaxed),
+        bad_pages: BAD_PAGES.load(Ordering::Relaxed),
+    }
+}
+
+/// Starts the scrubber thread.
+pub fn start() {
+    match kthread::spawn("scrubber", scrubber_thread, 0) {
+        Ok(_) => log::info!("Scrubbing free memory in the background"),
+        Err(err) => log::warn!("Failed to start memory scrubber - {err}"),
+    }
+}
+
+fn is_idle() -> bool {
+    kthread::stats().is_some_and(|stats| stats.run_queue_len == 0)
+}
+
+fn scrubber_thread(_: usize) -> usize {
+    let total_pages = page_allocation::total_pages();
+    let mut page_index = 0;
+    loop {
+        kthread::sleep_ms(BATCH_INTERVAL_MS);
+        if !is_idle() {
+            continue;
+        }
+        let mut pages_scrubbed = 0;
+        while pages_scrubbed < PAGES_PER_BATCH {
+            if page_index == total_pages {
+                page_index = 0;
+                PASSES.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+            let address = page_index * PAGE_SIZE;
+            page_index += 1;
+            let Some(page) = page_allocation::reserve_page_at(address) else {
+                continue;
+            };
+            if scrub(address) {
+                PAGES_SCRUBBED.fetch_add(1, Ordering::Relaxed);
+            } else {
+                BAD_PAGES.fetch_add(1, Ordering::Relaxed);
+                // Never freed, so the page is never used again
+                core::mem::forget(page);
+            }
+            pages_scrubbed += 1;
+        }
+    }
+}
+
+/// Writes and verifies test patterns across the reserved page at `address`. Returns `false` and
+/// logs the first mismatch if the page is bad.
+fn scrub(address: usize) -> bool {
+    let words = address as *mut u64;
+    let num_words = PAGE_SIZE / size_of::<u64>();
+    // Alternating bits both ways, then each word's own address to catch address line faults
+    let patterns: [fn(usize) -> u64; 3] = [
+        |_| 0x5555_5555_5555_5555,
+        |_| 0xAAAA_AAAA_AAAA_AAAA,
+        |word_address| !(word_address as u64),
+    ];
+    for pattern in patterns {
+        for index in 0..num_words {
+            let word = unsafe { words.add(index) };
+            unsafe { word.write_volatile(pattern(word as usize)) };
+        }
+        for index in 0..num_words {
+            let word = unsafe { words.add(index) };
+            let expected = pattern(word as usize);
+            let actual = unsafe { word.read_volatile() };
+            if actual != expected {
+                log::error!(
+                    "Memory error at {:#x}: expected {expected:#018x}, read {actual:#018x}",
+                    word as usize,
+                );
+                return false;
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,239 @@
+//! Shared memory objects, sets of physical pages which can be mapped into several address spaces
+//! at once.
+//!
+//! Objects are created with `create`, optionally with a name that `open` can find them by, and are
+//! referred to by handles. Each handle and each mapping holds a reference to its object, and each
+//! mapped page holds a reference to its physical page, so pages are only freed once the object
+//! and every mapping of it are gone. Mappings of the same object can have different protections.
+
+use crate::arch::page_allocation::{self, PageFlags};
+use crate::arch::paging::PAGE_SIZE;
+use crate::arch::syscall::SyscallError;
+use crate::user_memory::{self, UserArgs};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const MAX_NAME_LEN: usize = 64;
+
+/// Open handles, indexed by handle.
+static HANDLES: Mutex<Vec<Option<Arc<SharedMemory>>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SharedMemoryError {
+    #[error("invalid length")]
+    InvalidLength,
+    #[error("name too long")]
+    NameTooLong,
+    #[error("name already in use")]
+    NameInUse,
+    #[error("no such shared memory object")]
+    NotFound,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+impl From<SharedMemoryError> for SyscallError {
+    fn from(err: SharedMemoryError) -> Self {
+        match err {
+            SharedMemoryError::OutOfMemory => SyscallError::OUT_OF_MEMORY,
+            _ => SyscallError::INVALID_ARGUMENT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SharedMemoryHandle(pub usize);
+
+#[derive(Debug)]
+pub struct SharedMemory {
+    name: Option<String>,
+    /// Physical addresses of the object's pages.
+    frames: Arc<[usize]>,
+}
+
+impl SharedMemory {
+    /// Allocates `len` bytes of zeroed pages.
+    fn new(name: Option<String>, len: usize) -> Result<Self, SharedMemoryError> {
+        let num_pages = len / PAGE_SIZE;
+        let mut frames = Vec::new();
+        frames
+            .try_reserve_exact(num_pages)
+            .map_err(|_| SharedMemoryError::OutOfMemory)?;
+        for _ in 0..num_pages {
+            match page_allocation::find_and_reserve_page() {
+                Ok(page) => {
+                    let frame = page.into_raw() as usize;
+                    page_allocation::set_page_flags(frame, PageFlags::SHARED);
+                    frames.push(frame);
+                }
+                Err(_) => {
+                    for frame in frames {
+                        page_allocation::free_page(frame);
+                    }
+                    return Err(SharedMemoryError::OutOfMemory);
+                }
+            }
+        }
+        Ok(Self {
+            name,
+            frames: frames.into(),
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the size of the object in bytes.
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    pub fn frames(&self) -> &Arc<[usize]> {
+        &self.frames
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        // Pages still mapped somewhere are freed once they're unmapped
+        for frame in self.frames.iter() {
+            page_allocation::put_page(*frame);
+        }
+    }
+}
+
+fn insert_handle(handles: &mut Vec<Option<Arc<SharedMemory>>>, object: Arc<SharedMemory>) -> usize {
+    match handles.iter().position(Option::is_none) {
+        Some(index) => {
+            handles[index] = Some(object);
+            index
+        }
+        None => {
+            handles.push(Some(object));
+            handles.len() - 1
+        }
+    }
+}
+
+/// Creates an object of `len` bytes of zeroed memory, returning a handle to it. Named objects can
+/// be opened by name until every handle to them is closed.
+pub fn create(name: Option<&str>, len: usize) -> Result<SharedMemoryHandle, SharedMemoryError> {
+    if len == 0 || !len.is_multiple_of(PAGE_SIZE) {
+        return Err(SharedMemoryError::InvalidLength);
+    }
+    if name.is_some_and(|name| name.len() > MAX_NAME_LEN) {
+        return Err(SharedMemoryError::NameTooLong);
+    }
+    let mut handles = HANDLES.lock();
+    if let Some(name) = name
+        && handles
+            .iter()
+            .flatten()
+            .any(|object| object.name() == Some(name))
+    {
+        return Err(SharedMemoryError::NameInUse);
+    }
+    let object = SharedMemory::new(name.map(String::from), len)?;
+    Ok(SharedMemoryHandle(insert_handle(
+        &mut handles,
+        Arc::new(object),
+    )))
+}
+
+/// Allocates an anonymous object of `len` bytes of zeroed memory without a handle, for objects
+/// the kernel shares with a process itself.
+pub fn allocate(len: usize) -> Result<Arc<SharedMemory>, SharedMemoryError> {
+    if len == 0 || !len.is_multiple_of(PAGE_SIZE) {
+        return Err(SharedMemoryError::InvalidLength);
+    }
+    Ok(Arc::new(SharedMemory::new(None, len)?))
+}
+
+/// Returns a new handle to the object named `name`.
+pub fn open(name: &str) -> Result<SharedMemoryHandle, SharedMemoryError> {
+    let mut handles = HANDLES.lock();
+    let object = handles
+        .iter()
+        .flatten()
+        .find(|object| object.name() == Some(name))
+        .cloned()
+        .ok_or(SharedMemoryError::NotFound)?;
+    Ok(SharedMemoryHandle(insert_handle(&mut handles, object)))
+}
+
+/// Returns a new handle to `object`, for objects passed between processes by the kernel.
+pub fn add_handle(object: Arc<SharedMemory>) -> SharedMemoryHandle {
+    SharedMemoryHandle(insert_handle(&mut HANDLES.lock(), object))
+}
+
+/// Returns the object `handle` refers to.
+pub fn get(handle: SharedMemoryHandle) -> Result<Arc<SharedMemory>, SharedMemoryError> {
+    HANDLES
+        .lock()
+        .get(handle.0)
+        .cloned()
+        .flatten()
+        .ok_or(SharedMemoryError::NotFound)
+}
+
+/// Closes `handle`. The object is freed once it's also unmapped everywhere.
+pub fn close(handle: SharedMemoryHandle) -> Result<(), SharedMemoryError> {
+    let object = HANDLES
+        .lock()
+        .get_mut(handle.0)
+        .and_then(Option::take)
+        .ok_or(SharedMemoryError::NotFound)?;
+    // Dropped outside the lock, as the pages may be freed
+    drop(object);
+    Ok(())
+}
+
+#[derive(UserArgs)]
+struct NameArgs {
+    #[user(read, len = name_len, max_len = MAX_NAME_LEN, optional)]
+    name_ptr: *const u8,
+    name_len: usize,
+}
+
+impl NameArgs {
+    /// Returns the name, or `None` if the pointer is null.
+    fn name(&self) -> Result<Option<String>, SyscallError> {
+        self.validate()?;
+        if self.name_ptr.is_null() {
+            return Ok(None);
+        }
+        user_memory::read_string(self.name_ptr, self.name_len, MAX_NAME_LEN).map(Some)
+    }
+}
+
+/// Handler for the create shared memory syscall. `name` is a UTF-8 string in user memory, or null
+/// for an anonymous object. Returns the new handle.
+pub fn syscall_create(
+    name_ptr: *const u8,
+    name_len: usize,
+    len: usize,
+) -> Result<usize, SyscallError> {
+    let name = NameArgs { name_ptr, name_len }.name()?;
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(SyscallError::INVALID_ARGUMENT)?;
+    Ok(create(name.as_deref(), len)?.0)
+}
+
+/// Handler for the open shared memory syscall. `name` is a UTF-8 string in user memory. Returns
+/// the new handle.
+pub fn syscall_open(name_ptr: *const u8, name_len: usize) -> Result<usize, SyscallError> {
+    let name = NameArgs { name_ptr, name_len }
+        .name()?
+        .ok_or(SyscallError::INVALID_ARGUMENT)?;
+    Ok(open(&name)?.0)
+}
+
+/// Handler for the close shared memory syscall.
+pub fn syscall_close(handle: usize) -> Result<usize, SyscallError> {
+    close(SharedMemoryHandle(handle))?;
+    Ok(0)
+}
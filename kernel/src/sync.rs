@@ -0,0 +1,438 @@
+//! Synchronisation primitives.
+//!
+//! The `Irq` variants of the `spin` locks disable interrupts while held, so that an interrupt
+//! handler taking the same lock (e.g. by logging) can't deadlock against the code it interrupted.
+//!
+//! `RawIrqSpinlock` is the same without a guard, for C interfaces which lock and unlock in
+//! separate calls, and `Semaphore` blocks threads rather than spinning.
+//!
+//! `OnceLock` and `OnceFlag` replace `static mut`s which are written once during boot and read
+//! afterwards, publishing the write to every CPU without needing a lock to read.
+
+use crate::arch::interrupts::{self, SavedInterruptState};
+use crate::wait_queue::WaitQueue;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+pub struct IrqMutex<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupt_state: SavedInterruptState,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupt_state = interrupts::disable_and_save();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupt_state,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupt_state = interrupts::disable_and_save();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupt_state,
+            }),
+            None => {
+                unsafe { interrupts::restore(interrupt_state) };
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Interrupts must only be restored after the lock has been released
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            interrupts::restore(self.interrupt_state);
+        }
+    }
+}
+
+pub struct IrqRwLock<T: ?Sized> {
+    inner: spin::RwLock<T>,
+}
+
+pub struct IrqRwLockReadGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<spin::RwLockReadGuard<'a, T>>,
+    interrupt_state: SavedInterruptState,
+}
+
+pub struct IrqRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<spin::RwLockWriteGuard<'a, T>>,
+    interrupt_state: SavedInterruptState,
+}
+
+impl<T> IrqRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqRwLock<T> {
+    pub fn read(&self) -> IrqRwLockReadGuard<'_, T> {
+        let interrupt_state = interrupts::disable_and_save();
+        IrqRwLockReadGuard {
+            guard: ManuallyDrop::new(self.inner.read()),
+            interrupt_state,
+        }
+    }
+
+    pub fn write(&self) -> IrqRwLockWriteGuard<'_, T> {
+        let interrupt_state = interrupts::disable_and_save();
+        IrqRwLockWriteGuard {
+            guard: ManuallyDrop::new(self.inner.write()),
+            interrupt_state,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<IrqRwLockReadGuard<'_, T>> {
+        let interrupt_state = interrupts::disable_and_save();
+        match self.inner.try_read() {
+            Some(guard) => Some(IrqRwLockReadGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupt_state,
+            }),
+            None => {
+                unsafe { interrupts::restore(interrupt_state) };
+                None
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<IrqRwLockWriteGuard<'_, T>> {
+        let interrupt_state = interrupts::disable_and_save();
+        match self.inner.try_write() {
+            Some(guard) => Some(IrqRwLockWriteGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupt_state,
+            }),
+            None => {
+                unsafe { interrupts::restore(interrupt_state) };
+                None
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Deref for IrqRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            interrupts::restore(self.interrupt_state);
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            interrupts::restore(self.interrupt_state);
+        }
+    }
+}
+
+const ONCE_UNINITIALISED: u8 = 0;
+const ONCE_INITIALISING: u8 = 1;
+const ONCE_INITIALISED: u8 = 2;
+
+/// A value which can be set at most once, then read from any CPU without locking.
+pub struct OnceLock<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_UNINITIALISED),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, or `None` if it hasn't been set yet.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            ONCE_INITIALISED => Some(unsafe { (*self.value.get()).assume_init_ref() }),
+            _ => None,
+        }
+    }
+
+    /// Sets the value. If it has already been set, or another CPU is setting it, `value` is
+    /// given back.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(
+                ONCE_UNINITIALISED,
+                ONCE_INITIALISING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return Err(value);
+        }
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(ONCE_INITIALISED, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnceLock").field(&self.get()).finish()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == ONCE_INITIALISED {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A flag which can be set but never cleared.
+#[derive(Debug, Default)]
+pub struct OnceFlag(AtomicBool);
+
+impl OnceFlag {
+    pub const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    /// Sets the flag, returning whether this call was the one to set it.
+    #[inline]
+    pub fn set(&self) -> bool {
+        !self.0.swap(true, Ordering::AcqRel)
+    }
+
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// A spinlock which disables interrupts while held, locked and unlocked by separate calls.
+pub struct RawIrqSpinlock {
+    locked: AtomicBool,
+    /// Written by the holder.
+    interrupt_state: UnsafeCell<Option<SavedInterruptState>>,
+}
+
+unsafe impl Sync for RawIrqSpinlock {}
+unsafe impl Send for RawIrqSpinlock {}
+
+impl RawIrqSpinlock {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            interrupt_state: UnsafeCell::new(None),
+        }
+    }
+
+    pub fn lock(&self) {
+        let interrupt_state = interrupts::disable_and_save();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        unsafe { *self.interrupt_state.get() = Some(interrupt_state) };
+    }
+
+    /// Releases the lock, then restores interrupts to how they were when it was taken.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the caller.
+    pub unsafe fn unlock(&self) {
+        let interrupt_state = unsafe { (*self.interrupt_state.get()).take() };
+        self.locked.store(false, Ordering::Release);
+        if let Some(interrupt_state) = interrupt_state {
+            unsafe { interrupts::restore(interrupt_state) };
+        }
+    }
+}
+
+impl Default for RawIrqSpinlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A counting semaphore. Threads taking units block until enough are available.
+pub struct Semaphore {
+    units: IrqMutex<u32>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(units: u32) -> Self {
+        Self {
+            units: IrqMutex::new(units),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes `num_units` units if they're available, without blocking.
+    pub fn try_acquire(&self, num_units: u32) -> bool {
+        let mut units = self.units.lock();
+        match *units >= num_units {
+            true => {
+                *units -= num_units;
+                true
+            }
+            false => false,
+        }
+    }
+
+    /// Blocks until `num_units` units can be taken.
+    pub fn acquire(&self, num_units: u32) {
+        self.waiters.wait_until(|| self.try_acquire(num_units));
+    }
+
+    /// Blocks until `num_units` units can be taken, or the clock counter reaches `deadline_ns`.
+    /// Returns whether they were taken.
+    pub fn acquire_until(&self, num_units: u32, deadline_ns: u64) -> bool {
+        self.waiters
+            .wait_until_deadline(deadline_ns, || self.try_acquire(num_units))
+    }
+
+    /// Returns units, waking any threads waiting for them. Safe to call from interrupt handlers.
+    pub fn release(&self, num_units: u32) {
+        {
+            let mut units = self.units.lock();
+            *units = units.saturating_add(num_units);
+        }
+        // Waiters may want different numbers of units, so all of them check again
+        self.waiters.wake_all();
+    }
+
+    pub fn available(&self) -> u32 {
+        *self.units.lock()
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::arch::clock;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    #[kernel_test]
+    fn semaphore_counts_units() -> TestResult {
+        let semaphore = Semaphore::new(2);
+        ktest_assert!(semaphore.try_acquire(2));
+        ktest_assert!(!semaphore.try_acquire(1));
+        semaphore.release(1);
+        ktest_assert_eq!(semaphore.available(), 1);
+        semaphore.acquire(1);
+        // Nothing releases it, so this times out
+        ktest_assert!(!semaphore.acquire_until(1, clock::now_ns() + 1_000_000));
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! The last few failed system calls of each process, for finding out why early user code is
+//! misbehaving without a debugger.
+//!
+//! Every system call returning an error is recorded against the calling process with its
+//! arguments and the time, and can be listed with the `syserr` shell command.
+
+use crate::arch::clock;
+use crate::arch::syscall::{SyscallError, SystemCall};
+use core::fmt;
+
+/// Failures kept for each process, the oldest being dropped first.
+pub const SYSCALL_ERRORS_KEPT: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct SyscallFailure {
+    pub number: usize,
+    pub args: [usize; 6],
+    pub error: SyscallError,
+    /// Time since boot, or `None` if the clock wasn't running.
+    pub time_ns: Option<u64>,
+}
+
+impl fmt::Display for SyscallFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.time_ns {
+            Some(time_ns) => write!(
+                f,
+                "[{:5}.{:06}] ",
+                time_ns / 1_000_000_000,
+                time_ns % 1_000_000_000 / 1000,
+            )?,
+            None => write!(f, "[    -.------] ")?,
+        }
+        match SystemCall::from_number(self.number) {
+            Some(system_call) => write!(f, "{system_call:?}(")?,
+            None => write!(f, "syscall {}(", self.number)?,
+        }
+        for (index, arg) in self.args.iter().enumerate() {
+            if index != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{arg:#x}")?;
+        }
+        write!(f, ") = {}", self.error.name())
+    }
+}
+
+/// Ring buffer of a process's most recent failures.
+#[derive(Clone)]
+pub struct SyscallErrorLog {
+    failures: [Option<SyscallFailure>; SYSCALL_ERRORS_KEPT],
+    /// Index the next failure is written at.
+    next: usize,
+}
+
+impl SyscallErrorLog {
+    pub const fn new() -> Self {
+        Self {
+            failures: [None; SYSCALL_ERRORS_KEPT],
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, number: usize, args: [usize; 6], error: SyscallError) {
+        self.failures[self.next] = Some(SyscallFailure {
+            number,
+            args,
+            error,
+            time_ns: clock::try_now_ns(),
+        });
+        self.next = (self.next + 1) % SYSCALL_ERRORS_KEPT;
+    }
+
+    /// Returns the recorded failures, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &SyscallFailure> {
+        let (newer, older) = self.failures.split_at(self.next);
+        older.iter().chain(newer).flatten()
+    }
+}
+
+impl Default for SyscallErrorLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,9 +1,21 @@
-use crate::core_graphics::FRAMEBUFFER;
+use crate::arch::clock;
+use crate::arch::syscall::SyscallError;
+use crate::core_graphics::{FRAMEBUFFERS, Framebuffer, Framebuffers};
+use crate::cpio;
+use crate::input;
+use crate::kthread;
+use crate::memory_tag::MemoryTag;
+use crate::sync::IrqMutex;
+use crate::tunables;
+use crate::user_memory::{self, UserArgs};
+use crate::work_queue::{self, Work};
 use alloc::collections::TryReserveError;
+use alloc::vec;
 use alloc::vec::Vec;
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
 
 pub mod psf {
+    use alloc::vec::Vec;
     use core::mem::size_of;
 
     pub const MAGIC: u32 = 0x864AB572;
@@ -30,6 +42,12 @@ pub mod psf {
     }
 
     impl Header {
+        /// Returns the number of bytes used by each row of a glyph's bitmap.
+        #[inline]
+        pub fn bytes_per_row(&self) -> u32 {
+            self.width.div_ceil(8)
+        }
+
         pub fn from_bytes(bytes: [u8; size_of::<Header>()]) -> Result<Self, HeaderParseError> {
             let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
             let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
@@ -52,40 +70,101 @@ pub mod psf {
         }
     }
 
-    #[derive(Clone, Copy)]
+    /// Set in `Header::flags` if the font has a Unicode translation table after the glyphs.
+    pub const HAS_UNICODE_TABLE: u32 = 0x1;
+    /// Starts a multi-character sequence in a glyph's Unicode table entry.
+    const UNICODE_SEQUENCE_START: u8 = 0xFE;
+    /// Ends a glyph's Unicode table entry.
+    const UNICODE_ENTRY_END: u8 = 0xFF;
+
+    #[derive(Clone)]
     pub struct Font<'a> {
         pub header: Header,
         pub font_data: &'a [u8],
+        /// Characters with glyphs, sorted by character. Empty if the font has no Unicode table,
+        /// in which case characters are used as glyph indices directly.
+        unicode_map: Vec<(char, u32)>,
     }
 
     impl<'a> Font<'a> {
         pub fn new(file: &'a [u8]) -> Result<Self, &'a str> {
-            let header_slice = &file[0..size_of::<Header>()];
-            let header = Header::from_bytes(header_slice.try_into().map_err(|_| "file too small")?)
+            let header_slice = file.get(0..size_of::<Header>()).ok_or("file too small")?;
+            let header = Header::from_bytes(header_slice.try_into().unwrap())
                 .map_err(|_| "invalid magic")?;
-            if header.bytes_per_glyph != 16 {
+            if header.width == 0 || header.height == 0 {
+                return Err("invalid glyph size");
+            }
+            if header.bytes_per_glyph != header.height * header.bytes_per_row() {
                 return Err("invalid bytes per glyph");
             }
+            let glyphs_len = header.num_glyphs as usize * header.bytes_per_glyph as usize;
+            let font_data = file
+                .get(header.header_size as usize..)
+                .filter(|data| data.len() >= glyphs_len)
+                .ok_or("file too small")?;
+            let unicode_map = match header.flags & HAS_UNICODE_TABLE {
+                0 => Vec::new(),
+                _ => parse_unicode_table(&font_data[glyphs_len..], header.num_glyphs)?,
+            };
             Ok(Self {
                 header,
-                font_data: &file[32..],
+                font_data: &font_data[..glyphs_len],
+                unicode_map,
             })
         }
 
+        /// Returns the index of the glyph for `character`, if the font has one.
+        fn glyph_index(&self, character: char) -> Option<u32> {
+            if self.unicode_map.is_empty() {
+                return ((character as u32) < self.header.num_glyphs).then_some(character as u32);
+            }
+            self.unicode_map
+                .binary_search_by_key(&character, |(mapped, _)| *mapped)
+                .ok()
+                .map(|i| self.unicode_map[i].1)
+        }
+
+        /// Returns the bitmap of the glyph for `character`. Characters without a glyph are
+        /// drawn as the replacement character or '?' if the font has them, otherwise as the
+        /// first glyph.
         #[inline]
         pub fn get_character(&self, character: char) -> &[u8] {
-            let mut character_usize = character as usize;
-            // Replace unknown characters with '?' if exists
-            if character_usize >= self.header.num_glyphs as usize {
-                character_usize = '?' as usize;
-            }
-            let start_pos = self.header.bytes_per_glyph as usize * character_usize;
+            let glyph_index = self
+                .glyph_index(character)
+                .or_else(|| self.glyph_index(char::REPLACEMENT_CHARACTER))
+                .or_else(|| self.glyph_index('?'))
+                .unwrap_or(0) as usize;
+            let start_pos = self.header.bytes_per_glyph as usize * glyph_index;
             let end_pos = start_pos + self.header.bytes_per_glyph as usize;
-            if end_pos >= self.font_data.len() {
-                return &[];
-            }
-            &self.font_data[start_pos..end_pos]
+            self.font_data.get(start_pos..end_pos).unwrap_or(&[])
+        }
+    }
+
+    /// Parses a PSF2 Unicode table into a sorted map of characters to glyph indices. Each glyph
+    /// has an entry of UTF-8 characters, optionally followed by multi-character sequences which
+    /// are ignored, ended by `UNICODE_ENTRY_END`.
+    fn parse_unicode_table(
+        table: &[u8],
+        num_glyphs: u32,
+    ) -> Result<Vec<(char, u32)>, &'static str> {
+        let mut unicode_map = Vec::new();
+        let mut entries = table.split(|byte| *byte == UNICODE_ENTRY_END);
+        for glyph_index in 0..num_glyphs {
+            let entry = entries.next().ok_or("unicode table too small")?;
+            let characters = entry
+                .split(|byte| *byte == UNICODE_SEQUENCE_START)
+                .next()
+                .unwrap_or(&[]);
+            let characters = str::from_utf8(characters).map_err(|_| "invalid unicode table")?;
+            unicode_map
+                .try_reserve(characters.len())
+                .map_err(|_| "out of memory")?;
+            unicode_map.extend(characters.chars().map(|character| (character, glyph_index)));
         }
+        // Stable sort, so the first glyph listed for a character is kept
+        unicode_map.sort_by_key(|(character, _)| *character);
+        unicode_map.dedup_by_key(|(character, _)| *character);
+        Ok(unicode_map)
     }
 }
 
@@ -104,6 +183,15 @@ struct ScreenChar {
     pub background_color: u32,
 }
 
+impl ScreenChar {
+    /// What a cleared framebuffer looks like.
+    const BLANK: Self = Self {
+        character: ' ',
+        foreground_color: 0,
+        background_color: 0,
+    };
+}
+
 impl Default for ScreenChar {
     fn default() -> Self {
         Self {
@@ -149,7 +237,340 @@ enum TerminalMode {
     FifthArgument([u32; 5]),
 }
 
-pub static TERMINAL: Mutex<Option<Terminal<'static>>> = Mutex::new(None);
+/// Number of virtual terminals, switched between with Alt+F1 onwards.
+pub const NUM_VTS: usize = 4;
+/// Virtual terminal the kernel log is written to.
+pub const LOG_VT: usize = 0;
+
+/// Virtual terminals sharing the displays. Only the active one is drawn, the others keep writing
+/// to their own buffers until switched to.
+pub static VTS: IrqMutex<Vts<'static>> = IrqMutex::new(Vts::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VtError {
+    #[error("no such virtual terminal")]
+    NoSuchVt,
+    #[error("no terminal active")]
+    NoTerminal,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+impl From<TryReserveError> for VtError {
+    fn from(_: TryReserveError) -> Self {
+        VtError::OutOfMemory
+    }
+}
+
+pub struct Vts<'a> {
+    terminals: [Option<Terminal<'a>>; NUM_VTS],
+    active: usize,
+}
+
+impl<'a> Vts<'a> {
+    const fn new() -> Self {
+        Self {
+            terminals: [const { None }; NUM_VTS],
+            active: 0,
+        }
+    }
+
+    /// Sets up VT 0 with `terminal` and shows it. Other VTs are created with the same font and
+    /// output when first used.
+    pub fn init(&mut self, mut terminal: Terminal<'a>) {
+        terminal.show();
+        self.terminals = [const { None }; NUM_VTS];
+        self.terminals[0] = Some(terminal);
+        self.active = 0;
+    }
+
+    pub fn get_mut(&mut self, vt: usize) -> Option<&mut Terminal<'a>> {
+        self.terminals.get_mut(vt)?.as_mut()
+    }
+
+    /// Returns VT `vt`, creating it if this is its first use.
+    pub fn get_or_create(&mut self, vt: usize) -> Result<&mut Terminal<'a>, VtError> {
+        let slot = self.terminals.get(vt).ok_or(VtError::NoSuchVt)?;
+        if slot.is_none() {
+            let first = self.iter_mut().next().ok_or(VtError::NoTerminal)?;
+            let terminal = Terminal::new(first.font.clone(), first.output)?;
+            self.terminals[vt] = Some(terminal);
+        }
+        Ok(self.terminals[vt].as_mut().unwrap())
+    }
+
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    pub fn active_mut(&mut self) -> Option<&mut Terminal<'a>> {
+        self.terminals[self.active].as_mut()
+    }
+
+    /// Shows VT `vt` in place of the active one.
+    pub fn switch(&mut self, vt: usize) -> Result<(), VtError> {
+        self.get_or_create(vt)?;
+        if vt == self.active {
+            return Ok(());
+        }
+        if let Some(terminal) = self.active_mut() {
+            terminal.hide();
+        }
+        self.active = vt;
+        if let Some(terminal) = self.active_mut() {
+            terminal.show();
+        }
+        Ok(())
+    }
+
+    /// Iterates over the VTs which have been created.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Terminal<'a>> {
+        self.terminals.iter_mut().flatten()
+    }
+}
+
+/// Which displays the terminal is drawn to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    Display(usize),
+    /// Every display, with the character grid sized to fit the smallest.
+    Mirror,
+}
+
+impl Output {
+    pub fn includes(self, display: usize) -> bool {
+        match self {
+            Output::Display(output_display) => output_display == display,
+            Output::Mirror => true,
+        }
+    }
+
+    /// Returns the size in pixels the terminal can use, or `None` if there are no displays to
+    /// draw to.
+    fn size(self, framebuffers: &Framebuffers) -> Option<(u32, u32)> {
+        match self {
+            Output::Display(display) => framebuffers
+                .get(display)
+                .map(|framebuffer| (framebuffer.width, framebuffer.height)),
+            Output::Mirror => framebuffers
+                .iter()
+                .map(|(_, framebuffer)| (framebuffer.width, framebuffer.height))
+                .reduce(|(a_w, a_h), (b_w, b_h)| (a_w.min(b_w), a_h.min(b_h))),
+        }
+    }
+}
+/// Mirrors whether the terminal is blanked, so `wake_screen` doesn't need the terminal lock.
+static SCREEN_BLANKED: AtomicBool = AtomicBool::new(false);
+static UNBLANK_WORK: Work = Work::new(unblank_work, 0);
+/// Pages to scroll the view by from `scroll_view_pages` calls, not yet applied.
+static PENDING_SCROLL_PAGES: AtomicIsize = AtomicIsize::new(0);
+static SCROLL_WORK: Work = Work::new(scroll_work, 0);
+/// VT to switch to from the last `switch_vt` call, not yet applied.
+static PENDING_VT: AtomicUsize = AtomicUsize::new(0);
+static SWITCH_WORK: Work = Work::new(switch_work, 0);
+
+/// Shown on the bottom line while viewing scrollback.
+const SCROLLBACK_MARKER: &str = " SCROLLBACK - Shift+PageDown to return ";
+
+/// Longest font path accepted from userspace.
+const MAX_FONT_PATH_LEN: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FontError {
+    #[error("no initrd available")]
+    NoInitrd,
+    #[error("font file not found")]
+    NotFound,
+    #[error("invalid font file: {0}")]
+    InvalidFont(&'static str),
+    #[error("glyphs larger than the framebuffer")]
+    TooLarge,
+    #[error("no terminal active")]
+    NoTerminal,
+    #[error("no such display")]
+    NoDisplay,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+impl From<TryReserveError> for FontError {
+    fn from(_: TryReserveError) -> Self {
+        FontError::OutOfMemory
+    }
+}
+
+impl From<FontError> for SyscallError {
+    fn from(err: FontError) -> Self {
+        match err {
+            FontError::OutOfMemory => SyscallError::OUT_OF_MEMORY,
+            _ => SyscallError::INVALID_ARGUMENT,
+        }
+    }
+}
+
+/// Loads the PSF font at `path` in the initrd, and switches every VT to it.
+pub fn load_font(path: &[u8]) -> Result<(), FontError> {
+    let initrd = cpio::INITRD.lock().ok_or(FontError::NoInitrd)?;
+    let file = cpio::find_file(initrd, path).ok_or(FontError::NotFound)?;
+    let font = psf::Font::new(file).map_err(FontError::InvalidFont)?;
+    let mut vts = VTS.lock();
+    if vts.active_mut().is_none() {
+        return Err(FontError::NoTerminal);
+    }
+    for terminal in vts.iter_mut() {
+        terminal.set_font(font.clone())?;
+    }
+    Ok(())
+}
+
+/// Moves every VT to a different display, or mirrors them to all of them.
+pub fn set_output(output: Output) -> Result<(), FontError> {
+    let mut vts = VTS.lock();
+    if vts.active_mut().is_none() {
+        return Err(FontError::NoTerminal);
+    }
+    for terminal in vts.iter_mut() {
+        terminal.set_output(output)?;
+    }
+    Ok(())
+}
+
+#[derive(UserArgs)]
+struct SetFontArgs {
+    #[user(read, len = path_len, max_len = MAX_FONT_PATH_LEN)]
+    path_ptr: *const u8,
+    path_len: usize,
+}
+
+/// Handler for the set font syscall. `path` is the font's path in the initrd, in user memory.
+pub fn syscall_set_font(path_ptr: *const u8, path_len: usize) -> Result<usize, SyscallError> {
+    let args = SetFontArgs { path_ptr, path_len };
+    args.validate()?;
+    let mut path = vec![0; args.path_len];
+    user_memory::copy_from_user(&mut path, args.path_ptr as usize)?;
+    load_font(&path)?;
+    Ok(0)
+}
+
+/// Starts the thread which blinks the cursor, and blanks the screen after no input for
+/// `screen_blank_secs`.
+pub fn start_blink_thread() {
+    if let Err(err) = kthread::spawn("tty_blink", blink_thread, 0) {
+        log::warn!("Failed to start cursor blink thread - {err}");
+    }
+}
+
+/// Restores the screen if it's blanked. Safe to call from interrupt handlers.
+pub fn wake_screen() {
+    if SCREEN_BLANKED.load(Ordering::Relaxed) {
+        work_queue::schedule(&UNBLANK_WORK);
+    }
+}
+
+fn unblank_work(_: usize) {
+    if let Some(terminal) = VTS.lock().active_mut() {
+        terminal.unblank();
+    }
+}
+
+/// Scrolls the terminal's view back through its scrollback by `pages` screens, or forward if
+/// negative. Safe to call from interrupt handlers.
+pub fn scroll_view_pages(pages: isize) {
+    PENDING_SCROLL_PAGES.fetch_add(pages, Ordering::Relaxed);
+    work_queue::schedule(&SCROLL_WORK);
+}
+
+fn scroll_work(_: usize) {
+    let pages = PENDING_SCROLL_PAGES.swap(0, Ordering::Relaxed);
+    if let Some(terminal) = VTS.lock().active_mut() {
+        // Overlap by a line, so there's some context after paging
+        let page_lines = (terminal.height as isize - 1).max(1);
+        terminal.scroll_view(pages * page_lines);
+    }
+}
+
+/// Shows VT `vt` in place of the active one. Safe to call from interrupt handlers.
+pub fn switch_vt(vt: usize) {
+    PENDING_VT.store(vt, Ordering::Relaxed);
+    work_queue::schedule(&SWITCH_WORK);
+}
+
+fn switch_work(_: usize) {
+    let vt = PENDING_VT.load(Ordering::Relaxed);
+    let result = VTS.lock().switch(vt);
+    if let Err(err) = result {
+        log::warn!("Failed to switch to VT {vt} - {err}");
+    }
+}
+
+fn blink_thread(_: usize) -> usize {
+    loop {
+        kthread::sleep_ms(tunables::CURSOR_BLINK_MS.get());
+        let blank_timeout_ns = tunables::SCREEN_BLANK_SECS.get() * 1_000_000_000;
+        let idle_ns = clock::now_ns().saturating_sub(input::last_activity_ns());
+        let mut vts = VTS.lock();
+        let Some(terminal) = vts.active_mut() else {
+            continue;
+        };
+        if blank_timeout_ns != 0 && idle_ns >= blank_timeout_ns {
+            terminal.blank();
+        } else if !terminal.is_blanked() {
+            terminal.toggle_cursor();
+        }
+    }
+}
+
+/// Lines which have scrolled off the top of the terminal, in a ring buffer.
+struct Scrollback {
+    /// `capacity` lines of `width` characters.
+    lines: Vec<ScreenChar>,
+    width: usize,
+    capacity: usize,
+    /// Index of the oldest line.
+    start: usize,
+    len: usize,
+}
+
+impl Scrollback {
+    fn new(width: u16, capacity: usize) -> Result<Self, TryReserveError> {
+        let width = width as usize;
+        let mut lines = Vec::new();
+        lines.try_reserve_exact(width * capacity)?;
+        lines.resize(width * capacity, ScreenChar::default());
+        Ok(Self {
+            lines,
+            width,
+            capacity,
+            start: 0,
+            len: 0,
+        })
+    }
+
+    /// Adds a line, discarding the oldest line if full.
+    fn push(&mut self, line: &[ScreenChar]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let index = (self.start + self.len) % self.capacity;
+        self.lines[index * self.width..(index + 1) * self.width].copy_from_slice(line);
+        if self.len == self.capacity {
+            self.start = (self.start + 1) % self.capacity;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Returns line `i`, counting from the oldest.
+    fn line(&self, i: usize) -> &[ScreenChar] {
+        let index = (self.start + i) % self.capacity;
+        &self.lines[index * self.width..(index + 1) * self.width]
+    }
+
+    fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
 
 pub struct Terminal<'a> {
     pub font: psf::Font<'a>,
@@ -158,14 +579,27 @@ pub struct Terminal<'a> {
     front_buffer: Vec<ScreenChar>,
     back_buffer: Vec<ScreenChar>,
     current_state: TerminalState,
+    output: Output,
+    scrollback: Scrollback,
+    /// Number of lines the view is scrolled back into the scrollback, 0 when showing the screen.
+    view_offset: usize,
+    /// Whether this is the active VT, and so drawn to the displays.
+    shown: bool,
+    /// Blink phase of the cursor, drawn as the character under it with its colors swapped.
+    cursor_shown: bool,
+    /// Whether the screen is cleared for inactivity. Text is still written to the front buffer,
+    /// and drawn once unblanked.
+    blanked: bool,
 }
 
 impl<'a> Terminal<'a> {
-    pub fn new(font: psf::Font<'a>) -> Result<Self, TryReserveError> {
-        let framebuffer_lock = FRAMEBUFFER.lock();
-        let framebuffer = framebuffer_lock.as_ref().unwrap();
-        let width = (framebuffer.width / font.header.width) as u16;
-        let height = (framebuffer.height / font.header.height) as u16;
+    /// Creates a terminal for `output`, which must have at least one display. It isn't drawn
+    /// until shown as the active VT.
+    pub fn new(font: psf::Font<'a>, output: Output) -> Result<Self, TryReserveError> {
+        let _tag = MemoryTag::Terminal.enter();
+        let (pixel_width, pixel_height) = output.size(&FRAMEBUFFERS.lock()).unwrap();
+        let width = (pixel_width / font.header.width) as u16;
+        let height = (pixel_height / font.header.height) as u16;
         let buffer_len = width as usize * height as usize;
         let mut front_buffer = Vec::new();
         let mut back_buffer = Vec::new();
@@ -175,6 +609,7 @@ impl<'a> Terminal<'a> {
             front_buffer.push(ScreenChar::default());
             back_buffer.push(ScreenChar::default());
         }
+        let scrollback = Scrollback::new(width, tunables::SCROLLBACK_LINES.get() as usize)?;
         Ok(Self {
             font,
             width,
@@ -182,56 +617,222 @@ impl<'a> Terminal<'a> {
             front_buffer,
             back_buffer,
             current_state: TerminalState::default(),
+            output,
+            scrollback,
+            view_offset: 0,
+            shown: false,
+            cursor_shown: false,
+            blanked: false,
         })
     }
 
     pub fn render(&mut self) {
-        let mut framebuffer_lock = FRAMEBUFFER.lock();
-        let framebuffer = framebuffer_lock.as_mut().unwrap();
-        for (i, screen_char) in self.front_buffer.iter().enumerate() {
-            let old_screen_char = self.back_buffer[i];
-            if *screen_char != old_screen_char {
+        if self.blanked || !self.shown {
+            return;
+        }
+        let cursor_index = (self.cursor_shown
+            && self.current_state.cursor_x < self.width
+            && self.current_state.cursor_y < self.height)
+            .then(|| {
+                self.current_state.cursor_y as usize * self.width as usize
+                    + self.current_state.cursor_x as usize
+            });
+        let mut framebuffers = FRAMEBUFFERS.lock();
+        for i in 0..self.front_buffer.len() {
+            let screen_char = self.visible_char(i, cursor_index);
+            if screen_char != self.back_buffer[i] {
                 let y_pos = (i / self.width as usize) as u32;
                 let x_pos = (i % self.width as usize) as u32;
-                if screen_char.character == ' ' {
-                    framebuffer.fill_box(
-                        (
-                            x_pos * self.font.header.width,
-                            y_pos * self.font.header.height,
-                        ),
-                        (self.font.header.width, self.font.header.height),
-                        screen_char.background_color,
-                    );
-                } else {
-                    let char_bitmap = self.font.get_character(screen_char.character);
-                    for line_i in 0..self.font.header.height {
-                        let mut line = char_bitmap[line_i as usize];
-                        for bit in 0..8 {
-                            line >>= 1;
-                            // Branchless code to calculate whether to use the background or
-                            // foreground color
-                            let mask = (line & 1) as u32;
-                            let foreground = mask * screen_char.foreground_color;
-                            let background = (1 - mask) * screen_char.background_color;
-                            let color = foreground | background;
-                            framebuffer.set(
-                                (
-                                    x_pos * self.font.header.width + (7 - bit),
-                                    y_pos * self.font.header.height + line_i,
-                                ),
-                                color,
-                            );
-                        }
+                for (display, framebuffer) in framebuffers.iter_mut() {
+                    if self.output.includes(display) {
+                        draw_char(&self.font, framebuffer, (x_pos, y_pos), screen_char);
                     }
                 }
-                self.back_buffer[i] = *screen_char;
+                self.back_buffer[i] = screen_char;
+            }
+        }
+        for (display, framebuffer) in framebuffers.iter_mut() {
+            if self.output.includes(display) {
+                framebuffer.flush();
+            }
+        }
+    }
+
+    /// Returns the character shown at index `i` of the screen, with the cursor at `cursor_index`
+    /// and the view scrolled back by `view_offset` lines.
+    fn visible_char(&self, i: usize, cursor_index: Option<usize>) -> ScreenChar {
+        let width = self.width as usize;
+        let (row, column) = (i / width, i % width);
+        if self.view_offset == 0 {
+            let screen_char = self.front_buffer[i];
+            return match Some(i) == cursor_index {
+                true => ScreenChar {
+                    foreground_color: screen_char.background_color,
+                    background_color: screen_char.foreground_color,
+                    ..screen_char
+                },
+                false => screen_char,
+            };
+        }
+        if row == self.height as usize - 1 {
+            let marker_start = width.saturating_sub(SCROLLBACK_MARKER.len()) / 2;
+            let character = column
+                .checked_sub(marker_start)
+                .and_then(|marker_i| SCROLLBACK_MARKER.as_bytes().get(marker_i))
+                .map_or('-', |byte| *byte as char);
+            return ScreenChar {
+                character,
+                foreground_color: VGA_COLORS[0],
+                background_color: VGA_BRIGHT_COLORS[7],
+            };
+        }
+        // Lines are numbered from the oldest scrollback line, with the screen's lines after
+        let line = self.scrollback.len + row - self.view_offset;
+        match line.checked_sub(self.scrollback.len) {
+            None => self.scrollback.line(line)[column],
+            Some(screen_row) => self.front_buffer[screen_row * width + column],
+        }
+    }
+
+    /// Moves the view `lines` lines back into the scrollback, or forward if negative.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let max_offset = self.scrollback.len as isize;
+        self.view_offset = (self.view_offset as isize + lines).clamp(0, max_offset) as usize;
+        self.render();
+    }
+
+    /// Switches to a different font, resizing the character grid to fit the framebuffer. As much
+    /// of the existing text as fits is kept, anchored so the cursor's line stays visible.
+    pub fn set_font(&mut self, font: psf::Font<'a>) -> Result<(), FontError> {
+        self.relayout(Some(font), self.output)
+    }
+
+    pub fn output(&self) -> Output {
+        self.output
+    }
+
+    /// Switches to drawing on different displays, resizing the character grid to fit in the same
+    /// way as `set_font`.
+    pub fn set_output(&mut self, output: Output) -> Result<(), FontError> {
+        self.relayout(None, output)
+    }
+
+    /// Resizes the character grid for a new font, or the current font if `None`, and output.
+    fn relayout(&mut self, font: Option<psf::Font<'a>>, output: Output) -> Result<(), FontError> {
+        let _tag = MemoryTag::Terminal.enter();
+        let (pixel_width, pixel_height) = output
+            .size(&FRAMEBUFFERS.lock())
+            .ok_or(FontError::NoDisplay)?;
+        let header = font.as_ref().unwrap_or(&self.font).header;
+        let width = (pixel_width / header.width) as u16;
+        let height = (pixel_height / header.height) as u16;
+        if width == 0 || height == 0 {
+            return Err(FontError::TooLarge);
+        }
+        let buffer_len = width as usize * height as usize;
+        let mut front_buffer = Vec::new();
+        let mut back_buffer = Vec::new();
+        front_buffer.try_reserve_exact(buffer_len)?;
+        back_buffer.try_reserve_exact(buffer_len)?;
+        front_buffer.resize(buffer_len, ScreenChar::default());
+        back_buffer.resize(buffer_len, ScreenChar::default());
+        // Copy across the lines up to and including the cursor's, cropping on the right
+        let kept_lines = (self.current_state.cursor_y + 1).min(height);
+        let first_line = self.current_state.cursor_y + 1 - kept_lines;
+        let kept_columns = self.width.min(width) as usize;
+        for line in 0..kept_lines {
+            let old_start = (first_line + line) as usize * self.width as usize;
+            let new_start = line as usize * width as usize;
+            front_buffer[new_start..new_start + kept_columns]
+                .copy_from_slice(&self.front_buffer[old_start..old_start + kept_columns]);
+        }
+        // Scrollback lines are the old width, so are discarded
+        let scrollback = Scrollback::new(width, tunables::SCROLLBACK_LINES.get() as usize)?;
+        self.current_state.cursor_y = kept_lines - 1;
+        self.current_state.cursor_x = self.current_state.cursor_x.min(width - 1);
+        // Clear the displays being left as well as the new ones
+        self.clear_output();
+        if let Some(font) = font {
+            self.font = font;
+        }
+        self.output = output;
+        self.width = width;
+        self.height = height;
+        self.front_buffer = front_buffer;
+        self.scrollback = scrollback;
+        self.view_offset = 0;
+        // Back buffer matches the cleared framebuffer, so only text gets redrawn
+        self.back_buffer = back_buffer;
+        self.clear_output();
+        self.render();
+        Ok(())
+    }
+
+    /// Flips the cursor between shown and hidden, for blinking.
+    pub fn toggle_cursor(&mut self) {
+        self.cursor_shown = !self.cursor_shown;
+        self.render();
+    }
+
+    pub fn is_blanked(&self) -> bool {
+        self.blanked
+    }
+
+    /// Clears the screen until `unblank` is called.
+    pub fn blank(&mut self) {
+        if self.blanked {
+            return;
+        }
+        self.clear_output();
+        self.back_buffer.as_mut_slice().fill(ScreenChar::BLANK);
+        self.blanked = true;
+        SCREEN_BLANKED.store(true, Ordering::Relaxed);
+    }
+
+    /// Redraws the screen after `blank`.
+    pub fn unblank(&mut self) {
+        if !self.blanked {
+            return;
+        }
+        self.blanked = false;
+        SCREEN_BLANKED.store(false, Ordering::Relaxed);
+        self.render();
+    }
+
+    /// Starts drawing the terminal, replacing whatever was on its displays.
+    fn show(&mut self) {
+        self.shown = true;
+        self.blanked = false;
+        SCREEN_BLANKED.store(false, Ordering::Relaxed);
+        self.clear_output();
+        self.back_buffer.as_mut_slice().fill(ScreenChar::BLANK);
+        self.render();
+    }
+
+    /// Stops drawing the terminal, leaving its displays to another VT.
+    fn hide(&mut self) {
+        self.shown = false;
+        self.blanked = false;
+    }
+
+    /// Clears every display the terminal is drawn to, if it's being drawn.
+    fn clear_output(&self) {
+        if !self.shown {
+            return;
+        }
+        for (display, framebuffer) in FRAMEBUFFERS.lock().iter_mut() {
+            if self.output.includes(display) {
+                framebuffer.clear();
+                framebuffer.flush();
             }
         }
     }
 
     pub fn reset(&mut self) {
-        FRAMEBUFFER.lock().as_mut().unwrap().clear();
+        self.clear_output();
         self.current_state = Default::default();
+        self.scrollback.clear();
+        self.view_offset = 0;
         self.front_buffer.as_mut_slice().fill(Default::default());
         self.back_buffer.as_mut_slice().fill(Default::default());
     }
@@ -248,6 +849,8 @@ impl<'a> Terminal<'a> {
         if *cursor_y >= self.height {
             let buffer_size = self.front_buffer.len();
             *cursor_y = self.height - 1;
+            self.scrollback
+                .push(&self.front_buffer[..self.width as usize]);
             // Scroll display
             self.front_buffer
                 .copy_within(self.width as usize..buffer_size, 0);
@@ -259,6 +862,11 @@ impl<'a> Terminal<'a> {
     }
 
     pub fn write(&mut self, text: &str) {
+        // Return to the screen when there's new output
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.render();
+        }
         for character in text.chars() {
             match self.current_state.mode {
                 TerminalMode::Text => match character {
@@ -268,6 +876,9 @@ impl<'a> Terminal<'a> {
                         self.current_state.cursor_x = 0;
                     }
                     '\r' => self.current_state.cursor_x = 0,
+                    '\x08' => {
+                        self.current_state.cursor_x = self.current_state.cursor_x.saturating_sub(1)
+                    }
                     '\t' => self.current_state.cursor_x = (self.current_state.cursor_x % 8 + 1) * 8,
                     character => {
                         let i =
@@ -464,6 +1075,33 @@ impl<'a> Terminal<'a> {
     }
 }
 
+/// Draws a character cell at grid position `pos`.
+fn draw_char(
+    font: &psf::Font,
+    framebuffer: &mut Framebuffer,
+    pos: (u32, u32),
+    screen_char: ScreenChar,
+) {
+    let x_start = pos.0 * font.header.width;
+    let y_start = pos.1 * font.header.height;
+    if screen_char.character == ' ' {
+        framebuffer.fill_box(
+            (x_start, y_start),
+            (font.header.width, font.header.height),
+            screen_char.background_color,
+        );
+        return;
+    }
+    framebuffer.draw_glyph(
+        (x_start, y_start),
+        (font.header.width, font.header.height),
+        font.get_character(screen_char.character),
+        font.header.bytes_per_row() as usize,
+        screen_char.foreground_color,
+        screen_char.background_color,
+    );
+}
+
 impl<'a> core::fmt::Write for Terminal<'a> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.write(s);
@@ -0,0 +1,61 @@
+//! Time namespaces, shifting the clocks a process sees, for checkpoint/restore and test harnesses.
+//!
+//! Each process has an offset added to the monotonic clock it reads through the get clock syscall,
+//! so a restored process can carry on from the time it was checkpointed at, and a test can run as
+//! though the machine had been up for a long time. Offsets should be set before the process first
+//! reads the clock, as changing one later can make the clock jump backwards.
+
+use crate::arch::clock;
+use crate::arch::syscall::SyscallError;
+use crate::kthread;
+use core::sync::atomic::{AtomicI64, Ordering};
+
+/// Clock ID of the monotonic clock, counting from boot. There's no wall clock yet.
+pub const CLOCK_MONOTONIC: usize = 0;
+
+#[derive(Debug, Default)]
+pub struct TimeNamespace {
+    monotonic_offset_ns: AtomicI64,
+}
+
+impl TimeNamespace {
+    pub const fn new() -> Self {
+        Self {
+            monotonic_offset_ns: AtomicI64::new(0),
+        }
+    }
+
+    pub fn monotonic_offset_ns(&self) -> i64 {
+        self.monotonic_offset_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn set_monotonic_offset_ns(&self, offset_ns: i64) {
+        self.monotonic_offset_ns.store(offset_ns, Ordering::Relaxed);
+    }
+
+    /// Returns the monotonic clock as seen in this namespace, which never reads below zero.
+    pub fn monotonic_ns(&self) -> u64 {
+        clock::now_ns().saturating_add_signed(self.monotonic_offset_ns())
+    }
+}
+
+/// Handler for the get clock syscall. Returns the nanoseconds read from clock `clock_id`, shifted
+/// by the current process's offset.
+pub fn syscall_get_clock(clock_id: usize) -> Result<usize, SyscallError> {
+    let process = kthread::current_process().ok_or(SyscallError::INVALID_ARGUMENT)?;
+    match clock_id {
+        CLOCK_MONOTONIC => Ok(process.time.monotonic_ns() as usize),
+        _ => Err(SyscallError::INVALID_ARGUMENT),
+    }
+}
+
+/// Handler for the set clock offset syscall. Sets the current process's offset for clock
+/// `clock_id` to `offset_ns`, a signed number of nanoseconds.
+pub fn syscall_set_clock_offset(clock_id: usize, offset_ns: usize) -> Result<usize, SyscallError> {
+    let process = kthread::current_process().ok_or(SyscallError::INVALID_ARGUMENT)?;
+    match clock_id {
+        CLOCK_MONOTONIC => process.time.set_monotonic_offset_ns(offset_ns as i64),
+        _ => return Err(SyscallError::INVALID_ARGUMENT),
+    }
+    Ok(0)
+}
@@ -0,0 +1,88 @@
+//! Timers, for calling functions once the clock counter reaches a deadline.
+//!
+//! Pending timers are kept ordered by deadline in nanoseconds. There's no periodic tick, so expired
+//! timers are run whenever the scheduler runs, and the scheduler starts a countdown for the
+//! earliest deadline before waiting for an interrupt. Sleeping threads are woken by timers.
+//!
+//! Callbacks run in whichever thread is rescheduling, with no locks held, so they must not block.
+//! They may run late, but never before their deadline.
+
+use crate::arch::clock;
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub type TimerCallback = fn(usize);
+
+/// Identifies a pending timer. Timers with the same deadline run in the order they were added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId {
+    deadline_ns: u64,
+    sequence: u64,
+}
+
+impl TimerId {
+    pub fn deadline_ns(self) -> u64 {
+        self.deadline_ns
+    }
+}
+
+static TIMERS: IrqMutex<BTreeMap<TimerId, (TimerCallback, usize)>> = IrqMutex::new(BTreeMap::new());
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Calls `callback` with `arg` once the clock counter reaches `deadline_ns`. Allocates, so can't
+/// be called from interrupt handlers.
+pub fn add_timer(deadline_ns: u64, callback: TimerCallback, arg: usize) -> TimerId {
+    let id = TimerId {
+        deadline_ns,
+        sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+    };
+    TIMERS.lock().insert(id, (callback, arg));
+    id
+}
+
+/// Calls `callback` with `arg` after at least `num_ns` nanoseconds.
+pub fn add_timer_after(num_ns: u64, callback: TimerCallback, arg: usize) -> TimerId {
+    add_timer(clock::now_ns().saturating_add(num_ns), callback, arg)
+}
+
+/// Cancels the timer `id`. Returns `false` if it has already run or been cancelled.
+pub fn cancel_timer(id: TimerId) -> bool {
+    TIMERS.lock().remove(&id).is_some()
+}
+
+/// Returns the earliest pending deadline, if there are any timers.
+pub fn next_deadline_ns() -> Option<u64> {
+    TIMERS
+        .lock()
+        .first_key_value()
+        .map(|(id, _)| id.deadline_ns)
+}
+
+/// Returns the number of pending timers.
+pub fn num_pending() -> usize {
+    TIMERS.lock().len()
+}
+
+/// Runs every timer whose deadline has passed, returning how many were run.
+pub fn run_expired() -> usize {
+    let Some(now) = clock::try_now_ns() else {
+        return 0;
+    };
+    let mut num_run = 0;
+    loop {
+        // Each callback runs without the lock held, so it can add or cancel timers
+        let expired = {
+            let mut timers = TIMERS.lock();
+            match timers.first_entry() {
+                Some(entry) if entry.key().deadline_ns <= now => Some(entry.remove()),
+                _ => None,
+            }
+        };
+        let Some((callback, arg)) = expired else {
+            return num_run;
+        };
+        callback(arg);
+        num_run += 1;
+    }
+}
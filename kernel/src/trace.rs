@@ -0,0 +1,293 @@
+//! Event tracing, for seeing what the kernel did and when in more detail than logging gives.
+//!
+//! Tracepoints are macros placed where events happen, such as `trace_sched_switch!`. While tracing
+//! is on, each records its event and arguments with a timestamp from the monotonic clock in a ring
+//! buffer in its CPU's thread local storage, which keeps the most recent `RING_LEN` events. While
+//! it's off, a tracepoint costs an atomic load, and its arguments aren't evaluated. Recording is
+//! lock free, so tracepoints can be placed in interrupt handlers. An event is only read back once
+//! it's completely written, so one interrupted by another tracepoint on the same CPU is skipped.
+//!
+//! `dump` writes the recorded events to the serial port in the format of the `trace-format` crate,
+//! whose `trace-to-json` tool converts a capture of the serial output to Chrome trace JSON.
+//! Booting with `trace` starts tracing once the clock is calibrated.
+
+use crate::arch::interrupts::apic;
+use crate::arch::serial::{self, SerialWriter};
+use crate::arch::{clock, tls};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence};
+use trace_format::{Base64Writer, Record};
+
+pub use trace_format::Event;
+
+/// Events kept by each CPU.
+pub const RING_LEN: usize = 8192;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Records that the CPU switched from kernel thread `from` to `to`, given as `ThreadId`s.
+#[macro_export]
+macro_rules! trace_sched_switch {
+    ($from:expr, $to:expr $(,)?) => {
+        $crate::trace_event!(SchedSwitch, $from.as_u64(), $to.as_u64(), 0)
+    };
+}
+
+/// Records that the blocked kernel thread `thread`, given as a `ThreadId`, was made ready to run.
+#[macro_export]
+macro_rules! trace_sched_wakeup {
+    ($thread:expr $(,)?) => {
+        $crate::trace_event!(SchedWakeup, $thread.as_u64(), 0, 0)
+    };
+}
+
+/// Records that a user thread faulted on the page containing `address`, at the instruction at
+/// `instruction_address`, with the exception's `error_code`.
+#[macro_export]
+macro_rules! trace_page_fault {
+    ($address:expr, $instruction_address:expr, $error_code:expr $(,)?) => {
+        $crate::trace_event!(PageFault, $address, $instruction_address, $error_code)
+    };
+}
+
+/// Records that the dynamic interrupt handler for `vector` ran.
+#[macro_export]
+macro_rules! trace_irq {
+    ($vector:expr $(,)?) => {
+        $crate::trace_event!(Irq, $vector, 0, 0)
+    };
+}
+
+/// Records `event` with three arguments, if tracing is on. Used by the tracepoint macros.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! trace_event {
+    ($event:ident, $arg0:expr, $arg1:expr, $arg2:expr) => {
+        if $crate::trace::is_enabled() {
+            $crate::trace::record(
+                $crate::trace::Event::$event,
+                [$arg0 as u64, $arg1 as u64, $arg2 as u64],
+            );
+        }
+    };
+}
+
+/// An event in a ring, written with plain atomics so that it can be read while being overwritten.
+struct Slot {
+    /// The event's index in the ring plus 1, or 0 while it's being written.
+    sequence: AtomicUsize,
+    timestamp_ns: AtomicU64,
+    cpu: AtomicU32,
+    event: AtomicU32,
+    args: [AtomicU64; 3],
+}
+
+/// The most recent events recorded on a CPU.
+pub struct TraceRing {
+    slots: Box<[Slot]>,
+    /// Index of the next event to be recorded, counting every event ever recorded.
+    next: AtomicUsize,
+    /// Index of the first event since the ring was cleared.
+    start: AtomicUsize,
+}
+
+impl TraceRing {
+    pub fn new() -> Self {
+        let slots = (0..RING_LEN)
+            .map(|_| Slot {
+                sequence: AtomicUsize::new(0),
+                timestamp_ns: AtomicU64::new(0),
+                cpu: AtomicU32::new(0),
+                event: AtomicU32::new(0),
+                args: [const { AtomicU64::new(0) }; 3],
+            })
+            .collect();
+        Self {
+            slots,
+            next: AtomicUsize::new(0),
+            start: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records an event, overwriting the oldest if the ring is full. Must only be called on the
+    /// ring's CPU.
+    fn record(&self, record: &Record) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[index % RING_LEN];
+        slot.sequence.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.timestamp_ns
+            .store(record.timestamp_ns, Ordering::Relaxed);
+        slot.cpu.store(record.cpu, Ordering::Relaxed);
+        slot.event.store(record.event as u32, Ordering::Relaxed);
+        for (slot_arg, arg) in slot.args.iter().zip(record.args) {
+            slot_arg.store(arg, Ordering::Relaxed);
+        }
+        slot.sequence.store(index + 1, Ordering::Release);
+    }
+
+    /// Forgets every event recorded so far.
+    fn clear(&self) {
+        self.start
+            .store(self.next.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Appends the events held by the ring to `records`, oldest first.
+    fn read(&self, records: &mut Vec<Record>) {
+        self.for_each_recent(RING_LEN, |record| records.push(record));
+    }
+
+    /// Calls `f` with up to the `max_records` most recent events held by the ring, oldest first.
+    fn for_each_recent(&self, max_records: usize, mut f: impl FnMut(Record)) {
+        let end = self.next.load(Ordering::Acquire);
+        let start = self
+            .start
+            .load(Ordering::Relaxed)
+            .max(end.saturating_sub(max_records.min(RING_LEN)));
+        for index in start..end {
+            let slot = &self.slots[index % RING_LEN];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence != index + 1 {
+                continue;
+            }
+            let event = Event::from_u32(slot.event.load(Ordering::Relaxed));
+            let record = event.map(|event| Record {
+                timestamp_ns: slot.timestamp_ns.load(Ordering::Relaxed),
+                cpu: slot.cpu.load(Ordering::Relaxed),
+                event,
+                args: slot.args.each_ref().map(|arg| arg.load(Ordering::Relaxed)),
+            });
+            // Skip the event if it was overwritten while being read
+            fence(Ordering::Acquire);
+            if let Some(record) = record
+                && slot.sequence.load(Ordering::Relaxed) == sequence
+            {
+                f(record);
+            }
+        }
+    }
+}
+
+impl Default for TraceRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the current CPU's ring, if tracing has been started on it.
+fn current_ring() -> Option<&'static TraceRing> {
+    unsafe { (*tls::get()).trace_events.get().map(|ring| &**ring) }
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records an event on the current CPU. Use the tracepoint macros rather than calling this.
+#[doc(hidden)]
+pub fn record(event: Event, args: [u64; 3]) {
+    let (Some(ring), Some(timestamp_ns)) = (current_ring(), clock::try_now_ns()) else {
+        return;
+    };
+    ring.record(&Record {
+        timestamp_ns,
+        cpu: apic::current_local_apic_id() as u32,
+        event,
+        args,
+    });
+}
+
+/// Starts tracing, discarding the current CPU's previous events.
+pub fn start() {
+    let trace_events = unsafe { &(*tls::get()).trace_events };
+    match trace_events.get() {
+        Some(ring) => ring.clear(),
+        None => _ = trace_events.set(Box::new(TraceRing::new())),
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stops tracing, keeping the events recorded so far.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Writes the current CPU's events to the serial port as base64 between the `trace-format`
+/// markers, returning the number of events written, or `None` if there's no serial port.
+pub fn dump() -> Option<Result<usize, fmt::Error>> {
+    if !serial::is_present() && !unsafe { serial::init() } {
+        return None;
+    }
+    let mut records = Vec::new();
+    if let Some(ring) = current_ring() {
+        ring.read(&mut records);
+    }
+    let mut out = SerialWriter;
+    let result = (|| {
+        writeln!(out, "{}", trace_format::BEGIN_MARKER)?;
+        let mut writer = Base64Writer::new(&mut out);
+        writer.write(&trace_format::header(records.len() as u32))?;
+        for record in records.iter() {
+            writer.write(&record.to_bytes())?;
+        }
+        writer.finish()?;
+        writeln!(out, "{}", trace_format::END_MARKER)
+    })();
+    Some(result.map(|()| records.len()))
+}
+
+/// Writes up to the `max_records` most recent events on the current CPU to `out` as text, one per
+/// line. Doesn't allocate or lock, for state dumps from NMI handlers.
+pub fn write_recent(out: &mut (impl Write + ?Sized), max_records: usize) -> fmt::Result {
+    let mut result = Ok(());
+    if let Some(ring) = current_ring() {
+        ring.for_each_recent(max_records, |record| {
+            if result.is_ok() {
+                result = writeln!(out, "{record}");
+            }
+        });
+    }
+    result
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    fn irq(timestamp_ns: u64, vector: u64) -> Record {
+        Record {
+            timestamp_ns,
+            cpu: 0,
+            event: Event::Irq,
+            args: [vector, 0, 0],
+        }
+    }
+
+    fn read(ring: &TraceRing) -> Vec<Record> {
+        let mut records = Vec::new();
+        ring.read(&mut records);
+        records
+    }
+
+    #[kernel_test]
+    fn keeps_most_recent_events() -> TestResult {
+        let ring = TraceRing::new();
+        ring.record(&irq(1, 130));
+        ring.record(&irq(2, 131));
+        ktest_assert_eq!(read(&ring), [irq(1, 130), irq(2, 131)]);
+        ring.clear();
+        ktest_assert_eq!(read(&ring), []);
+        for index in 0..RING_LEN as u64 + 2 {
+            ring.record(&irq(index, 130));
+        }
+        let records = read(&ring);
+        ktest_assert_eq!(records.len(), RING_LEN);
+        ktest_assert_eq!(records[0], irq(2, 130));
+        ktest_assert_eq!(records[RING_LEN - 1], irq(RING_LEN as u64 + 1, 130));
+        Ok(())
+    }
+}
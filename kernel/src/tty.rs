@@ -0,0 +1,272 @@
+//! The console TTY, a character device joining a VT's terminal to the keyboard.
+//!
+//! Characters typed while the console's VT is shown (Alt+F3) are queued by `push_input`, and the
+//! line discipline is applied as they're read, so nothing heavy runs in interrupt handlers. In
+//! canonical mode input is edited a line at a time, with backspace deleting the last character,
+//! and reads return once a line is finished with enter. Ctrl+D finishes a line without a newline,
+//! which reads as the end of input if the line is empty. Otherwise reads return whatever has been
+//! typed. Typed characters are echoed to the terminal if echo is on.
+
+use crate::sync::IrqMutex;
+use crate::terminal;
+use crate::wait_queue::WaitQueue;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// VT the console is shown on.
+pub const CONSOLE_VT: usize = 2;
+const MAX_PENDING_INPUT: usize = 64;
+/// Longest line kept in canonical mode, further characters are dropped.
+const MAX_LINE_LEN: usize = 256;
+
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7F';
+const END_OF_TRANSMISSION: char = '\x04';
+
+pub static CONSOLE: Tty = Tty::new(CONSOLE_VT);
+
+/// Line discipline settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TtyMode {
+    pub canonical: bool,
+    pub echo: bool,
+}
+
+impl TtyMode {
+    pub const DEFAULT: Self = Self {
+        canonical: true,
+        echo: true,
+    };
+}
+
+/// Characters typed but not yet seen by the line discipline.
+struct RawInput {
+    chars: [char; MAX_PENDING_INPUT],
+    start: usize,
+    len: usize,
+}
+
+impl RawInput {
+    /// Adds a character, dropping it if the buffer is full.
+    fn push(&mut self, character: char) {
+        if self.len < MAX_PENDING_INPUT {
+            self.chars[(self.start + self.len) % MAX_PENDING_INPUT] = character;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        if self.len == 0 {
+            return None;
+        }
+        let character = self.chars[self.start];
+        self.start = (self.start + 1) % MAX_PENDING_INPUT;
+        self.len -= 1;
+        Some(character)
+    }
+}
+
+struct LineDiscipline {
+    mode: TtyMode,
+    /// Line being edited in canonical mode.
+    line: Vec<char>,
+    /// Input ready to be read.
+    ready: VecDeque<u8>,
+    /// Set by Ctrl+D on an empty line, making the next read return nothing.
+    end_of_input: bool,
+}
+
+pub struct Tty {
+    vt: usize,
+    raw_input: IrqMutex<RawInput>,
+    discipline: Mutex<LineDiscipline>,
+    /// Woken when a character is typed.
+    input_waiters: WaitQueue,
+}
+
+impl Tty {
+    pub const fn new(vt: usize) -> Self {
+        Self {
+            vt,
+            raw_input: IrqMutex::new(RawInput {
+                chars: ['\0'; MAX_PENDING_INPUT],
+                start: 0,
+                len: 0,
+            }),
+            discipline: Mutex::new(LineDiscipline {
+                mode: TtyMode::DEFAULT,
+                line: Vec::new(),
+                ready: VecDeque::new(),
+                end_of_input: false,
+            }),
+            input_waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Queues a typed character if the TTY's VT is shown. Safe to call from interrupt handlers.
+    pub fn push_input(&self, character: char) {
+        if terminal::VTS.lock().active() != self.vt {
+            return;
+        }
+        self.raw_input.lock().push(character);
+        self.input_waiters.wake_one();
+    }
+
+    pub fn mode(&self) -> TtyMode {
+        self.discipline.lock().mode
+    }
+
+    /// Changes the line discipline settings. A partly edited line is made ready to read when
+    /// leaving canonical mode.
+    pub fn set_mode(&self, mode: TtyMode) {
+        let mut discipline = self.discipline.lock();
+        if !mode.canonical {
+            let LineDiscipline { line, ready, .. } = &mut *discipline;
+            push_chars(ready, line.drain(..));
+        }
+        discipline.mode = mode;
+    }
+
+    /// Blocks until there's input, then reads up to `buffer.len()` bytes of it. Returns 0 at the
+    /// end of input.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        loop {
+            {
+                let mut discipline = self.discipline.lock();
+                self.process_input(&mut discipline);
+                if !discipline.ready.is_empty() {
+                    let len = buffer.len().min(discipline.ready.len());
+                    for (byte, ready) in buffer.iter_mut().zip(discipline.ready.drain(..len)) {
+                        *byte = ready;
+                    }
+                    return len;
+                }
+                if core::mem::take(&mut discipline.end_of_input) {
+                    return 0;
+                }
+            }
+            self.input_waiters
+                .wait_until(|| self.raw_input.lock().len != 0);
+        }
+    }
+
+    /// Writes `data` to the terminal, with invalid UTF-8 shown as replacement characters.
+    pub fn write(&self, data: &[u8]) {
+        let mut vts = terminal::VTS.lock();
+        let Ok(terminal) = vts.get_or_create(self.vt) else {
+            return;
+        };
+        for chunk in data.utf8_chunks() {
+            terminal.write(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                terminal.write("\u{FFFD}");
+            }
+        }
+        // Terminals only render on new lines, but prompts should show straight away
+        terminal.render();
+    }
+
+    /// Runs typed characters through the line discipline.
+    fn process_input(&self, discipline: &mut LineDiscipline) {
+        let mut echo = String::new();
+        while let Some(character) = self.raw_input.lock().pop() {
+            let LineDiscipline {
+                mode,
+                line,
+                ready,
+                end_of_input,
+            } = discipline;
+            if !mode.canonical {
+                push_chars(ready, [character]);
+                echo.push(character);
+                continue;
+            }
+            match character {
+                BACKSPACE | DELETE => {
+                    if line.pop().is_some() {
+                        echo.push_str("\x08 \x08");
+                    }
+                }
+                '\r' | '\n' => {
+                    push_chars(ready, line.drain(..).chain(['\n']));
+                    echo.push('\n');
+                }
+                END_OF_TRANSMISSION if line.is_empty() => *end_of_input = true,
+                END_OF_TRANSMISSION => push_chars(ready, line.drain(..)),
+                character if line.len() < MAX_LINE_LEN => {
+                    line.push(character);
+                    echo.push(character);
+                }
+                _ => {}
+            }
+        }
+        if discipline.mode.echo && !echo.is_empty() {
+            self.write(echo.as_bytes());
+        }
+    }
+}
+
+fn push_chars(ready: &mut VecDeque<u8>, chars: impl IntoIterator<Item = char>) {
+    for character in chars {
+        let mut bytes = [0; 4];
+        ready.extend(character.encode_utf8(&mut bytes).bytes());
+    }
+}
+
+/// Passes a character typed on the keyboard to the console. Safe to call from interrupt
+/// handlers.
+pub fn push_input(character: char) {
+    CONSOLE.push_input(character);
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    /// Runs `input` through the line discipline of a TTY without a VT, returning what's ready.
+    fn discipline(mode: TtyMode, input: &str) -> (Vec<u8>, bool) {
+        let tty = Tty::new(usize::MAX);
+        let mut discipline = tty.discipline.lock();
+        discipline.mode = TtyMode {
+            echo: false,
+            ..mode
+        };
+        for character in input.chars() {
+            tty.raw_input.lock().push(character);
+        }
+        tty.process_input(&mut discipline);
+        (
+            discipline.ready.iter().copied().collect(),
+            discipline.end_of_input,
+        )
+    }
+
+    #[kernel_test]
+    fn edits_lines_in_canonical_mode() -> TestResult {
+        let (ready, _) = discipline(TtyMode::DEFAULT, "lx\x08s\r");
+        ktest_assert_eq!(ready, b"ls\n");
+        // Unfinished lines aren't ready
+        let (ready, _) = discipline(TtyMode::DEFAULT, "ls");
+        ktest_assert!(ready.is_empty());
+        let (ready, end_of_input) = discipline(TtyMode::DEFAULT, "ls\x04");
+        ktest_assert_eq!(ready, b"ls");
+        ktest_assert!(!end_of_input);
+        let (ready, end_of_input) = discipline(TtyMode::DEFAULT, "\x04");
+        ktest_assert!(ready.is_empty() && end_of_input);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn passes_input_through_in_raw_mode() -> TestResult {
+        let raw = TtyMode {
+            canonical: false,
+            echo: false,
+        };
+        let (ready, _) = discipline(raw, "a\x08é\r");
+        ktest_assert_eq!(ready, "a\x08é\r".as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! Kernel tunables, named parameters which can be adjusted at runtime.
+//!
+//! Tunables are declared here rather than in the subsystems using them, so that every adjustable
+//! parameter can be found in one place. Values are stored as `u64`s and validated against the
+//! tunable's kind when set by name.
+//!
+//! Tunables can be set on the command line as `name=value`, or in the kernel shell with `tun`.
+//! Any process can read them with the get tunable syscall, but only the init process can set
+//! them, as they affect the whole system.
+
+use crate::arch::syscall::SyscallError;
+use crate::kthread;
+use crate::process::INIT_PROCESS;
+use crate::user_memory::{self, UserArgs};
+use alloc::string::String;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::LevelFilter;
+
+/// Longest name accepted by the tunable syscalls. Names are short, so anything longer can't be
+/// one.
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Integer { min: u64, max: u64 },
+    Bool,
+    LogLevel,
+}
+
+#[derive(Debug)]
+pub struct Tunable {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub kind: Kind,
+    default: u64,
+    value: AtomicU64,
+    /// Called with the new value after it's been set.
+    on_set: Option<fn(u64)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TunableError {
+    #[error("unknown tunable")]
+    UnknownTunable,
+    #[error("invalid value")]
+    InvalidValue,
+    #[error("value out of range")]
+    OutOfRange,
+}
+
+impl From<TunableError> for SyscallError {
+    fn from(_: TunableError) -> Self {
+        SyscallError::INVALID_ARGUMENT
+    }
+}
+
+impl Tunable {
+    const fn new(name: &'static str, description: &'static str, kind: Kind, default: u64) -> Self {
+        Self {
+            name,
+            description,
+            kind,
+            default,
+            value: AtomicU64::new(default),
+            on_set: None,
+        }
+    }
+
+    const fn with_on_set(mut self, on_set: fn(u64)) -> Self {
+        self.on_set = Some(on_set);
+        self
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn default_value(&self) -> u64 {
+        self.default
+    }
+
+    /// Sets the raw value, checking it's valid for the tunable's kind.
+    pub fn set(&self, value: u64) -> Result<(), TunableError> {
+        let valid = match self.kind {
+            Kind::Integer { min, max } => (min..=max).contains(&value),
+            Kind::Bool => value <= 1,
+            Kind::LogLevel => value <= LevelFilter::Trace as u64,
+        };
+        if !valid {
+            return Err(TunableError::OutOfRange);
+        }
+        self.value.store(value, Ordering::Relaxed);
+        if let Some(on_set) = self.on_set {
+            on_set(value);
+        }
+        Ok(())
+    }
+
+    /// Parses and sets the value from a string, as given on the command line or in the shell.
+    pub fn set_from_str(&self, value: &str) -> Result<(), TunableError> {
+        let value = match self.kind {
+            Kind::Integer { .. } => value.parse().map_err(|_| TunableError::InvalidValue)?,
+            Kind::Bool => match value {
+                "1" | "true" | "on" => 1,
+                "0" | "false" | "off" => 0,
+                _ => return Err(TunableError::InvalidValue),
+            },
+            Kind::LogLevel => match value.parse::<LevelFilter>() {
+                Ok(level) => level as u64,
+                Err(_) => value.parse().map_err(|_| TunableError::InvalidValue)?,
+            },
+        };
+        self.set(value)
+    }
+}
+
+pub static LOG_LEVEL: Tunable = Tunable::new(
+    "log_level",
+    "Most verbose log level that is output",
+    Kind::LogLevel,
+    LevelFilter::Trace as u64,
+)
+.with_on_set(|value| {
+    let level = LevelFilter::iter()
+        .nth(value as usize)
+        .unwrap_or(LevelFilter::Trace);
+    log::set_max_level(level);
+});
+
+pub static SCHED_QUANTUM_MS: Tunable = Tunable::new(
+    "sched_quantum_ms",
+    "Sleep wakeup times are rounded up to a multiple of this when scheduling deterministically",
+    Kind::Integer { min: 1, max: 1000 },
+    10,
+);
+
+pub static WATCHDOG_TIMEOUT_SECS: Tunable = Tunable::new(
+    "watchdog_timeout_secs",
+    "Time with interrupts disabled before the NMI watchdog reports a hard lockup",
+    Kind::Integer { min: 1, max: 3600 },
+    10,
+);
+
+pub static THERMAL_POLL_INTERVAL_MS: Tunable = Tunable::new(
+    "thermal_poll_interval_ms",
+    "Time between thermal zone temperature readings",
+    Kind::Integer {
+        min: 100,
+        max: 600_000,
+    },
+    5000,
+);
+
+pub static EC_TIMEOUT_MS: Tunable = Tunable::new(
+    "ec_timeout_ms",
+    "Time to wait for the embedded controller to respond",
+    Kind::Integer {
+        min: 1,
+        max: 10_000,
+    },
+    500,
+);
+
+pub static IRQ_AFFINITY: Tunable = Tunable::new(
+    "irq_affinity",
+    "Local APIC ID to send all device interrupts to, or 256 to balance them across CPUs",
+    Kind::Integer { min: 0, max: 256 },
+    crate::arch::interrupts::affinity::AUTOMATIC,
+)
+.with_on_set(|_| crate::arch::interrupts::affinity::rebalance());
+
+pub static IRQ_BALANCE_INTERVAL_MS: Tunable = Tunable::new(
+    "irq_balance_interval_ms",
+    "Time between rebalancing device interrupts across CPUs",
+    Kind::Integer {
+        min: 100,
+        max: 600_000,
+    },
+    10_000,
+);
+
+pub static CURSOR_BLINK_MS: Tunable = Tunable::new(
+    "cursor_blink_ms",
+    "Time between terminal cursor blinks",
+    Kind::Integer {
+        min: 100,
+        max: 10_000,
+    },
+    500,
+);
+
+pub static SCREEN_BLANK_SECS: Tunable = Tunable::new(
+    "screen_blank_secs",
+    "Time without input before the screen is blanked, or 0 to never blank it",
+    Kind::Integer {
+        min: 0,
+        max: 86_400,
+    },
+    600,
+);
+
+pub static SCROLLBACK_LINES: Tunable = Tunable::new(
+    "scrollback_lines",
+    "Lines of terminal history kept for scrolling back, applied when the terminal is resized",
+    Kind::Integer {
+        min: 0,
+        max: 100_000,
+    },
+    1000,
+);
+
+pub static ALL: &[&Tunable] = &[
+    &LOG_LEVEL,
+    &SCHED_QUANTUM_MS,
+    &WATCHDOG_TIMEOUT_SECS,
+    &THERMAL_POLL_INTERVAL_MS,
+    &EC_TIMEOUT_MS,
+    &IRQ_AFFINITY,
+    &IRQ_BALANCE_INTERVAL_MS,
+    &CURSOR_BLINK_MS,
+    &SCREEN_BLANK_SECS,
+    &SCROLLBACK_LINES,
+];
+
+pub fn find(name: &str) -> Option<&'static Tunable> {
+    ALL.iter().copied().find(|tunable| tunable.name == name)
+}
+
+pub fn set(name: &str, value: &str) -> Result<(), TunableError> {
+    find(name)
+        .ok_or(TunableError::UnknownTunable)?
+        .set_from_str(value)
+}
+
+/// Applies every `name=value` pair whose name is a tunable, logging invalid values. Other pairs
+/// are ignored, so this can be given the whole kernel command line.
+pub fn apply_arguments<'a>(arguments: impl Iterator<Item = &'a str>) {
+    for argument in arguments {
+        let Some((name, value)) = argument.split_once('=') else {
+            continue;
+        };
+        match set(name, value) {
+            Ok(()) => log::debug!("Tunable {name} set to {value}"),
+            Err(TunableError::UnknownTunable) => {}
+            Err(err) => log::warn!("Failed to set tunable {name} to {value:?} - {err}"),
+        }
+    }
+}
+
+#[derive(UserArgs)]
+struct NameArgs {
+    #[user(read, len = name_len, max_len = MAX_NAME_LEN)]
+    name_ptr: *const u8,
+    name_len: usize,
+}
+
+impl NameArgs {
+    fn name(&self) -> Result<String, SyscallError> {
+        self.validate()?;
+        user_memory::read_string(self.name_ptr, self.name_len, MAX_NAME_LEN)
+    }
+}
+
+/// Handler for the get tunable syscall. `name` is a UTF-8 string in user memory.
+pub fn syscall_get(name_ptr: *const u8, name_len: usize) -> Result<usize, SyscallError> {
+    let name = NameArgs { name_ptr, name_len }.name()?;
+    let tunable = find(&name).ok_or(TunableError::UnknownTunable)?;
+    Ok(tunable.get() as usize)
+}
+
+/// Handler for the set tunable syscall. `name` is a UTF-8 string in user memory. Only allowed for
+/// the init process.
+pub fn syscall_set(
+    name_ptr: *const u8,
+    name_len: usize,
+    value: usize,
+) -> Result<usize, SyscallError> {
+    if kthread::current_process().is_none_or(|process| process.id != INIT_PROCESS) {
+        return Err(SyscallError::PERMISSION_DENIED);
+    }
+    let name = NameArgs { name_ptr, name_len }.name()?;
+    let tunable = find(&name).ok_or(TunableError::UnknownTunable)?;
+    tunable.set(value as u64)?;
+    Ok(0)
+}
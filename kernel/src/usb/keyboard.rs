@@ -0,0 +1,162 @@
+//! USB HID keyboards in the boot protocol, which report the held modifier keys and up to six
+//! other held keys in a fixed 8 byte report.
+//!
+//! Each report is compared with the previous one, and keys which weren't held before are passed
+//! on to `input` as presses, with a US layout. Held keys don't repeat, and lock keys are ignored.
+
+use crate::input::{self, Key, Modifiers};
+
+pub const REPORT_LEN: usize = 8;
+
+const MODIFIER_LEFT_CTRL: u8 = 1 << 0;
+const MODIFIER_LEFT_SHIFT: u8 = 1 << 1;
+const MODIFIER_LEFT_ALT: u8 = 1 << 2;
+const MODIFIER_RIGHT_CTRL: u8 = 1 << 4;
+const MODIFIER_RIGHT_SHIFT: u8 = 1 << 5;
+const MODIFIER_RIGHT_ALT: u8 = 1 << 6;
+
+/// Sent in every key slot when too many keys are held to report.
+const USAGE_ERROR_ROLLOVER: u8 = 0x01;
+/// Usages below this are errors, or no key.
+const FIRST_KEY_USAGE: u8 = 0x04;
+
+pub struct BootKeyboard {
+    previous: [u8; REPORT_LEN],
+}
+
+impl BootKeyboard {
+    pub const fn new() -> Self {
+        Self {
+            previous: [0; REPORT_LEN],
+        }
+    }
+
+    /// Reports keys pressed since the last report.
+    pub fn handle_report(&mut self, report: &[u8]) {
+        let Some(report) = report.first_chunk::<REPORT_LEN>() else {
+            return;
+        };
+        if report[2..]
+            .iter()
+            .all(|usage| *usage == USAGE_ERROR_ROLLOVER)
+        {
+            return;
+        }
+        let modifiers = report[0];
+        let modifiers = Modifiers {
+            shift: modifiers & (MODIFIER_LEFT_SHIFT | MODIFIER_RIGHT_SHIFT) != 0,
+            ctrl: modifiers & (MODIFIER_LEFT_CTRL | MODIFIER_RIGHT_CTRL) != 0,
+            alt: modifiers & (MODIFIER_LEFT_ALT | MODIFIER_RIGHT_ALT) != 0,
+        };
+        for usage in new_presses(&self.previous, report) {
+            press(usage, modifiers);
+        }
+        if modifiers != Modifiers::default() {
+            input::report_activity();
+        }
+        self.previous = *report;
+    }
+}
+
+impl Default for BootKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the usages of keys held in `report` but not in `previous`.
+fn new_presses<'a>(
+    previous: &'a [u8; REPORT_LEN],
+    report: &'a [u8; REPORT_LEN],
+) -> impl Iterator<Item = u8> + 'a {
+    report[2..]
+        .iter()
+        .copied()
+        .filter(move |usage| *usage >= FIRST_KEY_USAGE && !previous[2..].contains(usage))
+}
+
+fn press(usage: u8, modifiers: Modifiers) {
+    if let Some(key) = usage_to_key(usage) {
+        input::report_key(key, modifiers);
+        return;
+    }
+    match usage_to_char(usage, modifiers.shift) {
+        // Control characters, such as Ctrl+D for the end of input
+        Some(character) if modifiers.ctrl && character.is_ascii_alphabetic() => {
+            input::report_char((character.to_ascii_lowercase() as u8 & 0x1F) as char)
+        }
+        Some(character) => input::report_char(character),
+        None => input::report_activity(),
+    }
+}
+
+/// Returns the key with a meaning to the kernel for a usage.
+fn usage_to_key(usage: u8) -> Option<Key> {
+    match usage {
+        0x3A..=0x45 => Some(Key::Function(usage - 0x39)),
+        0x4B => Some(Key::PageUp),
+        0x4E => Some(Key::PageDown),
+        _ => None,
+    }
+}
+
+/// Returns the character a usage types on a US layout, if any.
+fn usage_to_char(usage: u8, shift: bool) -> Option<char> {
+    let (plain, shifted) = match usage {
+        0x04..=0x1D => {
+            let letter = (b'a' + usage - 0x04) as char;
+            (letter, letter.to_ascii_uppercase())
+        }
+        0x1E..=0x27 => {
+            let index = (usage - 0x1E) as usize;
+            (b"1234567890"[index] as char, b"!@#$%^&*()"[index] as char)
+        }
+        0x28 => ('\n', '\n'),
+        0x29 => ('\x1B', '\x1B'),
+        0x2A => ('\x08', '\x08'),
+        0x2B => ('\t', '\t'),
+        0x2C => (' ', ' '),
+        0x2D..=0x38 => {
+            let index = (usage - 0x2D) as usize;
+            (
+                b"-=[]\\#;'`,./"[index] as char,
+                b"_+{}|~:\"~<>?"[index] as char,
+            )
+        }
+        0x4C => ('\x7F', '\x7F'),
+        _ => return None,
+    };
+    Some(if shift { shifted } else { plain })
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+    use alloc::vec::Vec;
+
+    #[kernel_test]
+    fn translates_usages() -> TestResult {
+        ktest_assert_eq!(usage_to_char(0x04, false), Some('a'));
+        ktest_assert_eq!(usage_to_char(0x1D, true), Some('Z'));
+        ktest_assert_eq!(usage_to_char(0x1E, false), Some('1'));
+        ktest_assert_eq!(usage_to_char(0x27, true), Some(')'));
+        ktest_assert_eq!(usage_to_char(0x38, true), Some('?'));
+        ktest_assert_eq!(usage_to_char(0x3A, false), None);
+        ktest_assert_eq!(usage_to_key(0x3A), Some(Key::Function(1)));
+        ktest_assert_eq!(usage_to_key(0x45), Some(Key::Function(12)));
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn finds_new_presses() -> TestResult {
+        let previous = [0, 0, 0x04, 0x05, 0, 0, 0, 0];
+        let report = [0, 0, 0x05, 0x06, 0x04, 0, 0, 0];
+        let presses: Vec<_> = new_presses(&previous, &report).collect();
+        ktest_assert_eq!(presses, [0x06]);
+        // Released keys aren't presses
+        let presses: Vec<_> = new_presses(&report, &[0; REPORT_LEN]).collect();
+        ktest_assert_eq!(presses, []);
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+//! USB, through XHCI host controllers, with a class driver for keyboards.
+//!
+//! Only what's needed to find boot protocol keyboards and read their reports is supported. Host
+//! controller drivers enumerate the devices on their root hub ports, read each one's
+//! configuration descriptor, and set up any keyboard interface found in it.
+
+pub mod keyboard;
+pub mod xhci;
+
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+pub const CLASS_HID: u8 = 3;
+pub const SUBCLASS_BOOT: u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+
+const REQUEST_GET_DESCRIPTOR: u8 = 6;
+const REQUEST_SET_CONFIGURATION: u8 = 9;
+const REQUEST_HID_SET_IDLE: u8 = 0x0A;
+const REQUEST_HID_SET_PROTOCOL: u8 = 0x0B;
+
+const REQUEST_TYPE_DEVICE_TO_HOST: u8 = 1 << 7;
+const REQUEST_TYPE_CLASS: u8 = 1 << 5;
+const REQUEST_TYPE_INTERFACE: u8 = 1;
+
+const ENDPOINT_DIRECTION_IN: u8 = 1 << 7;
+const ENDPOINT_TRANSFER_TYPE: u8 = 0x3;
+const ENDPOINT_INTERRUPT: u8 = 3;
+
+/// HID protocol which gives fixed format reports.
+const HID_PROTOCOL_BOOT: u16 = 0;
+
+/// The request sent in the setup stage of a control transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn get_descriptor(descriptor_type: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_DEVICE_TO_HOST,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (descriptor_type as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(configuration: u8) -> Self {
+        Self {
+            request_type: 0,
+            request: REQUEST_SET_CONFIGURATION,
+            value: configuration as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Switches a HID interface to the boot protocol.
+    pub fn set_boot_protocol(interface: u8) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_CLASS | REQUEST_TYPE_INTERFACE,
+            request: REQUEST_HID_SET_PROTOCOL,
+            value: HID_PROTOCOL_BOOT,
+            index: interface as u16,
+            length: 0,
+        }
+    }
+
+    /// Asks a HID interface to only send reports when they change.
+    pub fn set_idle(interface: u8) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_CLASS | REQUEST_TYPE_INTERFACE,
+            request: REQUEST_HID_SET_IDLE,
+            value: 0,
+            index: interface as u16,
+            length: 0,
+        }
+    }
+
+    pub fn is_device_to_host(self) -> bool {
+        self.request_type & REQUEST_TYPE_DEVICE_TO_HOST != 0
+    }
+
+    /// Returns the packet as it's laid out on the wire, in a little endian `u64`.
+    pub fn to_u64(self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+/// A boot protocol keyboard interface, and the interrupt endpoint its reports arrive on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyboardInterface {
+    pub configuration: u8,
+    pub interface: u8,
+    /// Endpoint number, without the direction bit.
+    pub endpoint: u8,
+    pub max_packet_size: u16,
+    /// `bInterval` of the endpoint, in the device's speed dependent units.
+    pub interval: u8,
+}
+
+/// Searches a full configuration descriptor, with the interface and endpoint descriptors after
+/// it, for a boot protocol keyboard.
+pub fn find_keyboard(configuration: &[u8]) -> Option<KeyboardInterface> {
+    let configuration_value = *configuration.get(5)?;
+    let mut keyboard_interface = None;
+    let mut offset = 0;
+    while let &[len, descriptor_type, ..] = &configuration[offset..] {
+        let len = len as usize;
+        let descriptor = configuration.get(offset..offset + len)?;
+        if len < 2 {
+            return None;
+        }
+        match descriptor_type {
+            DESCRIPTOR_INTERFACE if len >= 9 => {
+                let is_keyboard = descriptor[5] == CLASS_HID
+                    && descriptor[6] == SUBCLASS_BOOT
+                    && descriptor[7] == PROTOCOL_KEYBOARD;
+                keyboard_interface = is_keyboard.then_some(descriptor[2]);
+            }
+            DESCRIPTOR_ENDPOINT if len >= 7 => {
+                if let Some(interface) = keyboard_interface
+                    && descriptor[2] & ENDPOINT_DIRECTION_IN != 0
+                    && descriptor[3] & ENDPOINT_TRANSFER_TYPE == ENDPOINT_INTERRUPT
+                {
+                    return Some(KeyboardInterface {
+                        configuration: configuration_value,
+                        interface,
+                        endpoint: descriptor[2] & 0xF,
+                        max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]]) & 0x7FF,
+                        interval: descriptor[6],
+                    });
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    None
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::{ktest_assert, ktest_assert_eq};
+
+    #[kernel_test]
+    fn finds_keyboard_interfaces() -> TestResult {
+        #[rustfmt::skip]
+        let configuration = [
+            // Configuration 1
+            9, DESCRIPTOR_CONFIGURATION, 59, 0, 2, 1, 0, 0xA0, 50,
+            // Interface 0, a boot mouse
+            9, DESCRIPTOR_INTERFACE, 0, 0, 1, CLASS_HID, SUBCLASS_BOOT, 2, 0,
+            9, 0x21, 0x11, 0x01, 0, 1, 0x22, 52, 0,
+            7, DESCRIPTOR_ENDPOINT, 0x81, 3, 4, 0, 10,
+            // Interface 1, a boot keyboard
+            9, DESCRIPTOR_INTERFACE, 1, 0, 1, CLASS_HID, SUBCLASS_BOOT, PROTOCOL_KEYBOARD, 0,
+            9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+            7, DESCRIPTOR_ENDPOINT, 0x82, 3, 8, 0, 10,
+        ];
+        ktest_assert_eq!(
+            find_keyboard(&configuration),
+            Some(KeyboardInterface {
+                configuration: 1,
+                interface: 1,
+                endpoint: 2,
+                max_packet_size: 8,
+                interval: 10,
+            })
+        );
+        // Only the mouse
+        ktest_assert_eq!(find_keyboard(&configuration[..34]), None);
+        // Truncated descriptors
+        ktest_assert_eq!(find_keyboard(&configuration[..57]), None);
+        Ok(())
+    }
+
+    #[kernel_test]
+    fn lays_out_setup_packets() -> TestResult {
+        let packet = SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 18);
+        ktest_assert_eq!(packet.to_u64(), 0x0012_0000_0100_0680);
+        ktest_assert!(packet.is_device_to_host());
+        Ok(())
+    }
+}
@@ -0,0 +1,987 @@
+//! Driver for XHCI USB host controllers on PCI.
+//!
+//! Each controller gets a command ring, a single event ring on interrupter 0, and a transfer ring
+//! for every endpoint used, all one page of TRBs. The devices on the root hub ports are
+//! enumerated once at boot, polling the event ring for each command and control transfer to
+//! complete. Keyboards then have a few Normal TRBs queued on their interrupt endpoint, and from
+//! then on the interrupt schedules work to handle events, passing reports to the keyboard driver
+//! and queueing the TRB again. Hubs, hotplug and every other device class are unsupported.
+
+use super::keyboard::{self, BootKeyboard};
+use super::{DESCRIPTOR_CONFIGURATION, DESCRIPTOR_DEVICE, KeyboardInterface, SetupPacket};
+use crate::arch::clock;
+use crate::arch::interrupts::{apic, routing};
+use crate::arch::msi::{self, AllocatedVector, MsiError};
+use crate::arch::page_allocation;
+use crate::arch::paging::{PAGE_SIZE, PageTableEntry};
+use crate::arch::pci::{self, PciAddress};
+use crate::kthread;
+use crate::work_queue::{self, Work};
+use alloc::vec::Vec;
+use core::sync::atomic::{Ordering, fence};
+use spin::Mutex;
+
+/// Serial bus controller, USB, XHCI.
+const CLASS_XHCI: u32 = 0x0C_03_30;
+
+const MAX_CONTROLLERS: usize = 4;
+/// Device slots enabled on each controller.
+const MAX_SLOTS: u32 = 16;
+/// Mapped from the start of BAR0, covering every register used.
+const REGISTERS_SIZE: usize = 64 * 1024;
+const TIMEOUT_NS: u64 = 1_000_000_000;
+/// Time for a device to recover from a port reset before it must respond.
+const RESET_RECOVERY_MS: u64 = 10;
+/// TRBs in each ring, filling a page.
+const RING_LEN: usize = PAGE_SIZE / size_of::<Trb>();
+/// Normal TRBs kept queued on each keyboard's interrupt endpoint.
+const QUEUED_REPORTS: usize = 4;
+/// Configuration descriptors longer than this are cut short.
+const MAX_CONFIGURATION_LEN: usize = 2048;
+/// Space for each report in the device's buffer page, after the control transfer buffer.
+const REPORT_BUFFER_SIZE: usize = 64;
+
+const _: () = assert!(MAX_CONFIGURATION_LEN + QUEUED_REPORTS * REPORT_BUFFER_SIZE <= PAGE_SIZE);
+
+mod capability {
+    pub const LENGTH: usize = 0x00;
+    pub const STRUCTURAL_PARAMS_1: usize = 0x04;
+    pub const STRUCTURAL_PARAMS_2: usize = 0x08;
+    pub const CAPABILITY_PARAMS_1: usize = 0x10;
+    pub const DOORBELL_OFFSET: usize = 0x14;
+    pub const RUNTIME_OFFSET: usize = 0x18;
+
+    pub const PARAMS_64_BYTE_CONTEXTS: u32 = 1 << 2;
+
+    pub const EXTENDED_LEGACY_SUPPORT: u32 = 1;
+    pub const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+    pub const LEGACY_OS_OWNED: u32 = 1 << 24;
+    /// SMI event bits in the legacy control register, which are write 1 to clear.
+    pub const LEGACY_SMI_EVENTS: u32 = 0xE000_0000;
+}
+
+mod operational {
+    pub const COMMAND: usize = 0x00;
+    pub const STATUS: usize = 0x04;
+    pub const COMMAND_RING_CONTROL: usize = 0x18;
+    pub const CONTEXT_BASE_ARRAY: usize = 0x30;
+    pub const CONFIGURE: usize = 0x38;
+    pub const PORT_STATUS_BASE: usize = 0x400;
+    pub const PORT_STATUS_STRIDE: usize = 0x10;
+
+    pub const COMMAND_RUN: u32 = 1 << 0;
+    pub const COMMAND_RESET: u32 = 1 << 1;
+    pub const COMMAND_INTERRUPTER_ENABLE: u32 = 1 << 2;
+
+    pub const STATUS_HALTED: u32 = 1 << 0;
+    pub const STATUS_EVENT_INTERRUPT: u32 = 1 << 3;
+    pub const STATUS_NOT_READY: u32 = 1 << 11;
+
+    pub const PORT_CONNECTED: u32 = 1 << 0;
+    pub const PORT_ENABLED: u32 = 1 << 1;
+    pub const PORT_RESET: u32 = 1 << 4;
+    pub const PORT_SPEED_SHIFT: u32 = 10;
+    pub const PORT_RESET_CHANGE: u32 = 1 << 21;
+    /// Every change bit, which are write 1 to clear.
+    pub const PORT_CHANGES: u32 = 0x7F << 17;
+    /// Bits which keep their value when written back. Writing any other bit as it was read may
+    /// disable the port or clear a change.
+    pub const PORT_PRESERVE: u32 = 0x0E00_C200;
+}
+
+/// Registers of interrupter 0, in the runtime registers.
+mod interrupter {
+    pub const OFFSET: usize = 0x20;
+
+    pub const MANAGEMENT: usize = 0x00;
+    pub const MODERATION: usize = 0x04;
+    pub const TABLE_SIZE: usize = 0x08;
+    pub const TABLE_BASE: usize = 0x10;
+    pub const DEQUEUE: usize = 0x18;
+
+    pub const MANAGEMENT_PENDING: u32 = 1 << 0;
+    pub const MANAGEMENT_ENABLE: u32 = 1 << 1;
+    /// 1 ms, in 250 ns units.
+    pub const MODERATION_INTERVAL: u32 = 4000;
+    pub const DEQUEUE_HANDLER_BUSY: u64 = 1 << 3;
+}
+
+mod trb_type {
+    pub const NORMAL: u32 = 1;
+    pub const SETUP: u32 = 2;
+    pub const DATA: u32 = 3;
+    pub const STATUS: u32 = 4;
+    pub const LINK: u32 = 6;
+    pub const ENABLE_SLOT: u32 = 9;
+    pub const ADDRESS_DEVICE: u32 = 11;
+    pub const CONFIGURE_ENDPOINT: u32 = 12;
+    pub const EVALUATE_CONTEXT: u32 = 13;
+    pub const TRANSFER_EVENT: u32 = 32;
+    pub const COMMAND_COMPLETION: u32 = 33;
+    pub const PORT_STATUS_CHANGE: u32 = 34;
+
+    pub const SHIFT: u32 = 10;
+}
+
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+const TRB_IMMEDIATE_DATA: u32 = 1 << 6;
+const TRB_DIRECTION_IN: u32 = 1 << 16;
+const TRB_TRANSFER_TYPE_SHIFT: u32 = 16;
+const TRB_TRANSFER_TYPE_OUT: u32 = 2;
+const TRB_TRANSFER_TYPE_IN: u32 = 3;
+const TRB_SLOT_SHIFT: u32 = 24;
+
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+const SPEED_FULL: u8 = 1;
+const SPEED_LOW: u8 = 2;
+const SPEED_HIGH: u8 = 3;
+
+const ENDPOINT_TYPE_CONTROL: u32 = 4;
+const ENDPOINT_TYPE_INTERRUPT_IN: u32 = 7;
+const ENDPOINT_ERROR_COUNT: u32 = 3;
+/// Doorbell target and device context index of the default control endpoint.
+const CONTROL_ENDPOINT: u8 = 1;
+
+static CONTROLLERS: Mutex<Vec<Controller>> = Mutex::new(Vec::new());
+/// Event handling work for each controller, by index in `CONTROLLERS`.
+static EVENT_WORKS: [Work; MAX_CONTROLLERS] = {
+    let mut works = [const { Work::new(event_work, 0) }; MAX_CONTROLLERS];
+    let mut i = 0;
+    while i < MAX_CONTROLLERS {
+        works[i] = Work::new(event_work, i);
+        i += 1;
+    }
+    works
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum XhciError {
+    #[error("registers aren't in a memory BAR")]
+    NoMemoryBar,
+    #[error("timed out waiting for the controller")]
+    Timeout,
+    #[error("command failed with completion code {0}")]
+    CommandFailed(u8),
+    #[error("transfer failed with completion code {0}")]
+    TransferFailed(u8),
+    #[error("invalid descriptor")]
+    InvalidDescriptor,
+    #[error("no interrupt route")]
+    NoInterruptRoute,
+    #[error("out of memory")]
+    OutOfMemory,
+    #[error("too many controllers")]
+    TooManyControllers,
+    #[error("failed to set up interrupts - {0}")]
+    Msi(#[from] MsiError),
+}
+
+/// Transfer request block, the entry type of every ring.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn trb_type(&self) -> u32 {
+        (self.control >> trb_type::SHIFT) & 0x3F
+    }
+
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> TRB_SLOT_SHIFT) as u8
+    }
+
+    /// Device context index of the endpoint a transfer event is for.
+    fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1F) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Registers {
+    base: usize,
+}
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    /// Writes a 64 bit register as two halves, for controllers which don't take 64 bit accesses.
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// A command or transfer ring in an identity mapped page, which the driver produces TRBs on. The
+/// last TRB links back to the start.
+struct Ring {
+    trbs: *mut Trb,
+    enqueue: usize,
+    cycle: bool,
+}
+
+// The ring's memory is only reachable through this
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /// Creates a ring in the cleared page at `address`.
+    unsafe fn new(address: usize) -> Self {
+        let trbs = address as *mut Trb;
+        unsafe {
+            trbs.add(RING_LEN - 1).write_volatile(Trb {
+                parameter: address as u64,
+                status: 0,
+                control: trb_type::LINK << trb_type::SHIFT | TRB_TOGGLE_CYCLE,
+            });
+        }
+        Self {
+            trbs,
+            enqueue: 0,
+            cycle: true,
+        }
+    }
+
+    fn address(&self) -> u64 {
+        self.trbs as u64
+    }
+
+    /// Hands a TRB to the controller, returning its address.
+    fn push(&mut self, trb: Trb) -> u64 {
+        let address = self.write(self.enqueue, trb);
+        self.enqueue += 1;
+        if self.enqueue == RING_LEN - 1 {
+            let link = unsafe { self.trbs.add(RING_LEN - 1).read_volatile() };
+            self.write(RING_LEN - 1, link);
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        address
+    }
+
+    /// Writes a TRB with the current cycle bit, which is written last so the controller never
+    /// sees it half written.
+    fn write(&mut self, index: usize, trb: Trb) -> u64 {
+        let control = (trb.control & !TRB_CYCLE) | self.cycle as u32;
+        unsafe {
+            let entry = self.trbs.add(index);
+            (&raw mut (*entry).parameter).write_volatile(trb.parameter);
+            (&raw mut (*entry).status).write_volatile(trb.status);
+            fence(Ordering::SeqCst);
+            (&raw mut (*entry).control).write_volatile(control);
+            entry as u64
+        }
+    }
+}
+
+/// The event ring in an identity mapped page, which the controller produces TRBs on.
+struct EventRing {
+    trbs: *mut Trb,
+    dequeue: usize,
+    cycle: bool,
+}
+
+// The ring's memory is only reachable through this
+unsafe impl Send for EventRing {}
+
+impl EventRing {
+    /// Takes the next event, telling the controller it's been handled.
+    fn pop(&mut self, interrupter: Registers) -> Option<Trb> {
+        let entry = unsafe { self.trbs.add(self.dequeue) };
+        let control = unsafe { (&raw const (*entry).control).read_volatile() };
+        if (control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let event = unsafe { entry.read_volatile() };
+        self.dequeue += 1;
+        if self.dequeue == RING_LEN {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        let dequeue = unsafe { self.trbs.add(self.dequeue) } as u64;
+        interrupter.write_u64(
+            interrupter::DEQUEUE,
+            dequeue | interrupter::DEQUEUE_HANDLER_BUSY,
+        );
+        Some(event)
+    }
+}
+
+/// A configured keyboard, with its interrupt endpoint's transfer ring.
+struct Keyboard {
+    slot: u8,
+    /// Device context index of the interrupt endpoint.
+    endpoint: u8,
+    ring: Ring,
+    report_len: u32,
+    keyboard: BootKeyboard,
+}
+
+struct Controller {
+    index: usize,
+    operational: Registers,
+    interrupter: Registers,
+    doorbells: Registers,
+    /// Size of each context in a device or input context, 32 or 64 bytes.
+    context_size: usize,
+    num_ports: u8,
+    /// Device context base address array, indexed by slot.
+    context_base_array: *mut u64,
+    command_ring: Ring,
+    event_ring: EventRing,
+    keyboards: Vec<Keyboard>,
+    _vector: AllocatedVector,
+}
+
+// The controller's memory is only reachable through this
+unsafe impl Send for Controller {}
+
+/// Returns the interval of an interrupt endpoint's context, as a power of 2 of 125 µs, from the
+/// `bInterval` in its descriptor.
+fn endpoint_interval(speed: u8, interval: u8) -> u32 {
+    match speed {
+        // In 1 ms frames
+        SPEED_FULL | SPEED_LOW => (interval.max(1) as u32 * 8).ilog2().clamp(3, 10),
+        // Already a power of 2 of 125 µs, plus one
+        _ => interval.clamp(1, 16) as u32 - 1,
+    }
+}
+
+/// Returns the maximum packet size of the default control endpoint before the device
+/// descriptor is read, which full speed devices may change.
+fn default_max_packet_size(speed: u8) -> u32 {
+    match speed {
+        SPEED_FULL | SPEED_LOW => 8,
+        SPEED_HIGH => 64,
+        _ => 512,
+    }
+}
+
+/// Polls `condition` until it's true, or the timeout passes.
+fn wait_until(mut condition: impl FnMut() -> bool) -> Result<(), XhciError> {
+    let deadline = clock::now_ns() + TIMEOUT_NS;
+    while !condition() {
+        if clock::now_ns() >= deadline {
+            return Err(XhciError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+impl Controller {
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        fence(Ordering::SeqCst);
+        self.doorbells.write(slot as usize * 4, target as u32);
+    }
+
+    fn port_status_offset(port: u8) -> usize {
+        operational::PORT_STATUS_BASE + (port as usize - 1) * operational::PORT_STATUS_STRIDE
+    }
+
+    /// Clears every change bit set on a port.
+    fn clear_port_changes(&self, port: u8) {
+        use operational::*;
+        let offset = Self::port_status_offset(port);
+        let status = self.operational.read(offset);
+        self.operational
+            .write(offset, (status & PORT_PRESERVE) | (status & PORT_CHANGES));
+    }
+
+    /// Returns a pointer to context `index` of the input or device context at `base`.
+    fn context(&self, base: usize, index: usize) -> *mut u32 {
+        (base + index * self.context_size) as *mut u32
+    }
+
+    /// Polls the event ring until an event matching `predicate` arrives, handling any others.
+    fn wait_for_event(&mut self, predicate: impl Fn(&Trb) -> bool) -> Result<Trb, XhciError> {
+        let deadline = clock::now_ns() + TIMEOUT_NS;
+        loop {
+            match self.event_ring.pop(self.interrupter) {
+                Some(event) if predicate(&event) => return Ok(event),
+                Some(event) => self.handle_event(event),
+                None if clock::now_ns() >= deadline => return Err(XhciError::Timeout),
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Runs a command, returning its completion event.
+    fn command(&mut self, trb: Trb) -> Result<Trb, XhciError> {
+        let address = self.command_ring.push(trb);
+        self.ring_doorbell(0, 0);
+        let event = self.wait_for_event(|event| {
+            event.trb_type() == trb_type::COMMAND_COMPLETION && event.parameter == address
+        })?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS => Ok(event),
+            code => Err(XhciError::CommandFailed(code)),
+        }
+    }
+
+    /// Runs a control transfer on a device's default control endpoint, with any data stage
+    /// using `buffer`.
+    fn control_transfer(
+        &mut self,
+        slot: u8,
+        ring: &mut Ring,
+        setup: SetupPacket,
+        buffer: usize,
+    ) -> Result<(), XhciError> {
+        let has_data = setup.length != 0;
+        let device_to_host = setup.is_device_to_host();
+        let transfer_type = match (has_data, device_to_host) {
+            (false, _) => 0,
+            (true, false) => TRB_TRANSFER_TYPE_OUT,
+            (true, true) => TRB_TRANSFER_TYPE_IN,
+        };
+        ring.push(Trb {
+            parameter: setup.to_u64(),
+            status: 8,
+            control: trb_type::SETUP << trb_type::SHIFT
+                | TRB_IMMEDIATE_DATA
+                | transfer_type << TRB_TRANSFER_TYPE_SHIFT,
+        });
+        if has_data {
+            ring.push(Trb {
+                parameter: buffer as u64,
+                status: setup.length as u32,
+                control: trb_type::DATA << trb_type::SHIFT
+                    | if device_to_host { TRB_DIRECTION_IN } else { 0 },
+            });
+        }
+        // The status stage goes the opposite way to the data
+        let status_in = !has_data || !device_to_host;
+        let status = ring.push(Trb {
+            parameter: 0,
+            status: 0,
+            control: trb_type::STATUS << trb_type::SHIFT
+                | TRB_INTERRUPT_ON_COMPLETION
+                | if status_in { TRB_DIRECTION_IN } else { 0 },
+        });
+        self.ring_doorbell(slot, CONTROL_ENDPOINT);
+        // Errors are reported on the stage they happen in
+        let event = self.wait_for_event(|event| {
+            event.trb_type() == trb_type::TRANSFER_EVENT
+                && event.slot() == slot
+                && event.endpoint() == CONTROL_ENDPOINT
+                && (event.parameter == status || event.completion_code() != COMPLETION_SUCCESS)
+        })?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS => Ok(()),
+            code => Err(XhciError::TransferFailed(code)),
+        }
+    }
+
+    /// Resets a port if needed, and enumerates any device connected to it.
+    fn init_port(&mut self, port: u8) -> Result<(), XhciError> {
+        use operational::*;
+        let offset = Self::port_status_offset(port);
+        let status = self.operational.read(offset);
+        if status & PORT_CONNECTED == 0 {
+            return Ok(());
+        }
+        // USB 3 ports enable themselves once the link is up, USB 2 ports need a reset
+        if status & PORT_ENABLED == 0 {
+            self.operational
+                .write(offset, (status & PORT_PRESERVE) | PORT_RESET);
+            let operational = self.operational;
+            wait_until(|| operational.read(offset) & PORT_RESET_CHANGE != 0)?;
+            kthread::sleep_ms(RESET_RECOVERY_MS);
+        }
+        self.clear_port_changes(port);
+        let status = self.operational.read(offset);
+        if status & PORT_ENABLED == 0 {
+            return Ok(());
+        }
+        let speed = ((status >> PORT_SPEED_SHIFT) & 0xF) as u8;
+        self.enumerate(port, speed)
+    }
+
+    /// Addresses the device on a root hub port, and configures it if it's a keyboard.
+    fn enumerate(&mut self, port: u8, speed: u8) -> Result<(), XhciError> {
+        let enable_slot = Trb {
+            control: trb_type::ENABLE_SLOT << trb_type::SHIFT,
+            ..Default::default()
+        };
+        let slot = self.command(enable_slot)?.slot();
+
+        // Input context, output device context, control endpoint ring, interrupt endpoint ring,
+        // then buffers. The slot stays enabled, so none of it is freed.
+        let memory =
+            page_allocation::find_and_reserve_contiguous(5).map_err(|_| XhciError::OutOfMemory)?;
+        let input_context = memory;
+        let device_context = memory + PAGE_SIZE;
+        let mut control_ring = unsafe { Ring::new(memory + 2 * PAGE_SIZE) };
+        let interrupt_ring = unsafe { Ring::new(memory + 3 * PAGE_SIZE) };
+        let buffer = memory + 4 * PAGE_SIZE;
+
+        let mut max_packet_size = default_max_packet_size(speed);
+        unsafe {
+            let control = self.context(input_context, 0);
+            control.add(1).write_volatile(1 << 0 | 1 << 1);
+            let slot_context = self.context(input_context, 1);
+            slot_context.write_volatile((speed as u32) << 20 | 1 << 27);
+            slot_context.add(1).write_volatile((port as u32) << 16);
+            let endpoint = self.context(input_context, 2);
+            endpoint.add(1).write_volatile(
+                ENDPOINT_ERROR_COUNT << 1 | ENDPOINT_TYPE_CONTROL << 3 | max_packet_size << 16,
+            );
+            endpoint
+                .add(2)
+                .write_volatile(control_ring.address() as u32 | 1);
+            endpoint
+                .add(3)
+                .write_volatile((control_ring.address() >> 32) as u32);
+            endpoint.add(4).write_volatile(8);
+            self.context_base_array
+                .add(slot as usize)
+                .write_volatile(device_context as u64);
+        }
+        self.command(Trb {
+            parameter: input_context as u64,
+            status: 0,
+            control: trb_type::ADDRESS_DEVICE << trb_type::SHIFT | (slot as u32) << TRB_SLOT_SHIFT,
+        })?;
+
+        // Full speed devices may use any of several packet sizes, given in the first 8 bytes of
+        // the device descriptor
+        if speed == SPEED_FULL {
+            let setup = SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 8);
+            self.control_transfer(slot, &mut control_ring, setup, buffer)?;
+            let device_max_packet_size = unsafe { (buffer as *const u8).add(7).read_volatile() };
+            if device_max_packet_size as u32 != max_packet_size && device_max_packet_size != 0 {
+                max_packet_size = device_max_packet_size as u32;
+                unsafe {
+                    let control = self.context(input_context, 0);
+                    control.add(1).write_volatile(1 << 1);
+                    let endpoint = self.context(input_context, 2);
+                    endpoint.add(1).write_volatile(
+                        ENDPOINT_ERROR_COUNT << 1
+                            | ENDPOINT_TYPE_CONTROL << 3
+                            | max_packet_size << 16,
+                    );
+                }
+                self.command(Trb {
+                    parameter: input_context as u64,
+                    status: 0,
+                    control: trb_type::EVALUATE_CONTEXT << trb_type::SHIFT
+                        | (slot as u32) << TRB_SLOT_SHIFT,
+                })?;
+            }
+        }
+
+        let setup = SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 18);
+        self.control_transfer(slot, &mut control_ring, setup, buffer)?;
+        let descriptor = unsafe { core::slice::from_raw_parts(buffer as *const u8, 18) };
+        let vendor_id = u16::from_le_bytes([descriptor[8], descriptor[9]]);
+        let product_id = u16::from_le_bytes([descriptor[10], descriptor[11]]);
+        log::info!(
+            "xhci{}: port {port}: device {vendor_id:04x}:{product_id:04x}",
+            self.index
+        );
+
+        // The start of the configuration descriptor gives the length of it with every interface
+        // and endpoint descriptor after it
+        let setup = SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, 9);
+        self.control_transfer(slot, &mut control_ring, setup, buffer)?;
+        let total_len = unsafe { (buffer as *const u16).add(1).read_unaligned() } as usize;
+        if total_len < 9 {
+            return Err(XhciError::InvalidDescriptor);
+        }
+        let total_len = total_len.min(MAX_CONFIGURATION_LEN);
+        let setup = SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, total_len as u16);
+        self.control_transfer(slot, &mut control_ring, setup, buffer)?;
+        let configuration = unsafe { core::slice::from_raw_parts(buffer as *const u8, total_len) };
+        let Some(interface) = super::find_keyboard(configuration) else {
+            return Ok(());
+        };
+
+        let setup = SetupPacket::set_configuration(interface.configuration);
+        self.control_transfer(slot, &mut control_ring, setup, buffer)?;
+        let setup = SetupPacket::set_boot_protocol(interface.interface);
+        self.control_transfer(slot, &mut control_ring, setup, buffer)?;
+        // Optional, and may stall, so it's done last
+        let setup = SetupPacket::set_idle(interface.interface);
+        if let Err(err) = self.control_transfer(slot, &mut control_ring, setup, buffer) {
+            log::debug!("xhci{}: port {port}: SET_IDLE failed - {err}", self.index);
+        }
+
+        let endpoint = self.configure_keyboard_endpoint(
+            slot,
+            speed,
+            input_context,
+            &interface,
+            interrupt_ring.address(),
+        )?;
+        let mut keyboard = Keyboard {
+            slot,
+            endpoint,
+            ring: interrupt_ring,
+            report_len: (interface.max_packet_size as u32).min(REPORT_BUFFER_SIZE as u32),
+            keyboard: BootKeyboard::new(),
+        };
+        for i in 0..QUEUED_REPORTS {
+            let report_buffer = buffer + MAX_CONFIGURATION_LEN + i * REPORT_BUFFER_SIZE;
+            keyboard.queue_report(report_buffer as u64);
+        }
+        self.ring_doorbell(slot, endpoint);
+        self.keyboards.push(keyboard);
+        log::info!("xhci{}: port {port}: boot protocol keyboard", self.index);
+        Ok(())
+    }
+
+    /// Adds a keyboard's interrupt endpoint to its slot, returning the endpoint's device context
+    /// index.
+    fn configure_keyboard_endpoint(
+        &mut self,
+        slot: u8,
+        speed: u8,
+        input_context: usize,
+        interface: &KeyboardInterface,
+        ring_address: u64,
+    ) -> Result<u8, XhciError> {
+        if interface.endpoint == 0 {
+            return Err(XhciError::InvalidDescriptor);
+        }
+        let endpoint = 2 * interface.endpoint + 1;
+        let max_packet_size = interface.max_packet_size as u32;
+        unsafe {
+            let control = self.context(input_context, 0);
+            control.write_volatile(0);
+            control.add(1).write_volatile(1 << 0 | 1 << endpoint);
+            let slot_context = self.context(input_context, 1);
+            slot_context.write_volatile((speed as u32) << 20 | (endpoint as u32) << 27);
+            let context = self.context(input_context, endpoint as usize + 1);
+            context.write_volatile(endpoint_interval(speed, interface.interval) << 16);
+            context.add(1).write_volatile(
+                ENDPOINT_ERROR_COUNT << 1 | ENDPOINT_TYPE_INTERRUPT_IN << 3 | max_packet_size << 16,
+            );
+            context.add(2).write_volatile(ring_address as u32 | 1);
+            context.add(3).write_volatile((ring_address >> 32) as u32);
+            context
+                .add(4)
+                .write_volatile(keyboard::REPORT_LEN as u32 | max_packet_size << 16);
+        }
+        self.command(Trb {
+            parameter: input_context as u64,
+            status: 0,
+            control: trb_type::CONFIGURE_ENDPOINT << trb_type::SHIFT
+                | (slot as u32) << TRB_SLOT_SHIFT,
+        })?;
+        Ok(endpoint)
+    }
+
+    fn handle_event(&mut self, event: Trb) {
+        match event.trb_type() {
+            trb_type::TRANSFER_EVENT => {
+                let keyboard = self.keyboards.iter_mut().find(|keyboard| {
+                    keyboard.slot == event.slot() && keyboard.endpoint == event.endpoint()
+                });
+                let Some(keyboard) = keyboard else {
+                    return;
+                };
+                let code = event.completion_code();
+                if code != COMPLETION_SUCCESS && code != COMPLETION_SHORT_PACKET {
+                    // The endpoint is halted, and stays that way
+                    log::warn!(
+                        "xhci{}: keyboard transfer failed with completion code {code}",
+                        self.index
+                    );
+                    return;
+                }
+                fence(Ordering::SeqCst);
+                // The event points at the Normal TRB, which points at the report
+                let buffer = unsafe { (event.parameter as *const Trb).read_volatile() }.parameter;
+                let residue = event.status & 0xFF_FFFF;
+                let len = keyboard.report_len.saturating_sub(residue) as usize;
+                let report = unsafe { core::slice::from_raw_parts(buffer as *const u8, len) };
+                keyboard.keyboard.handle_report(report);
+                keyboard.queue_report(buffer);
+                let (slot, endpoint) = (keyboard.slot, keyboard.endpoint);
+                self.ring_doorbell(slot, endpoint);
+            }
+            trb_type::PORT_STATUS_CHANGE => {
+                let port = (event.parameter >> 24) as u8;
+                let status = self.operational.read(Self::port_status_offset(port));
+                log::debug!(
+                    "xhci{}: port {port} status changed to {status:#x}",
+                    self.index
+                );
+                self.clear_port_changes(port);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Keyboard {
+    fn queue_report(&mut self, buffer: u64) {
+        self.ring.push(Trb {
+            parameter: buffer,
+            status: self.report_len,
+            control: trb_type::NORMAL << trb_type::SHIFT | TRB_INTERRUPT_ON_COMPLETION,
+        });
+    }
+}
+
+fn event_work(index: usize) {
+    let mut controllers = CONTROLLERS.lock();
+    let Some(controller) = controllers.get_mut(index) else {
+        return;
+    };
+    while let Some(event) = controller.event_ring.pop(controller.interrupter) {
+        controller.handle_event(event);
+    }
+}
+
+/// Routes interrupter 0 to a new vector, through MSI-X or MSI if either is supported or INTx.
+unsafe fn setup_interrupt(
+    address: PciAddress,
+    operational_registers: Registers,
+    interrupter_registers: Registers,
+    index: usize,
+) -> Result<AllocatedVector, XhciError> {
+    use interrupter::*;
+    let vector = msi::allocate_vector(move || {
+        let management = interrupter_registers.read(MANAGEMENT);
+        if management & MANAGEMENT_PENDING != 0 {
+            // Both are write 1 to clear, which also deasserts INTx
+            interrupter_registers.write(MANAGEMENT, MANAGEMENT_ENABLE | MANAGEMENT_PENDING);
+            operational_registers.write(operational::STATUS, operational::STATUS_EVENT_INTERRUPT);
+            work_queue::schedule(&EVENT_WORKS[index]);
+        }
+    })?;
+    match unsafe { msi::enable_msix(address, 0, &vector) } {
+        Ok(()) => return Ok(vector),
+        Err(MsiError::NoMsiXCapability) => {}
+        Err(err) => return Err(err.into()),
+    }
+    match unsafe { msi::enable_msi(address, &vector) } {
+        Ok(()) => return Ok(vector),
+        Err(MsiError::NoMsiCapability) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let pin = pci::read_byte(address, pci::REGISTER_INTERRUPT_PIN);
+    let route = pin
+        .checked_sub(1)
+        .and_then(|pin| routing::pci_interrupt_route(address.bus, address.device, pin))
+        .ok_or(XhciError::NoInterruptRoute)?;
+    unsafe {
+        routing::route_gsi(route, vector.vector(), apic::current_local_apic_id())
+            .map_err(|_| XhciError::NoInterruptRoute)?;
+    }
+    Ok(vector)
+}
+
+/// Takes the controller from the firmware, if it's using it to emulate a PS/2 keyboard.
+fn take_ownership(base: usize, capability_params: u32) {
+    use capability::*;
+    let registers = Registers { base };
+    let mut offset = ((capability_params >> 16) as usize) * 4;
+    while offset != 0 {
+        let header = registers.read(offset);
+        if header & 0xFF == EXTENDED_LEGACY_SUPPORT {
+            registers.write(offset, header | LEGACY_OS_OWNED);
+            if wait_until(|| registers.read(offset) & LEGACY_BIOS_OWNED == 0).is_err() {
+                log::warn!("Firmware didn't release XHCI controller, taking it anyway");
+                registers.write(offset, (header | LEGACY_OS_OWNED) & !LEGACY_BIOS_OWNED);
+            }
+            let control = registers.read(offset + 4);
+            registers.write(offset + 4, control & LEGACY_SMI_EVENTS);
+            return;
+        }
+        match (header >> 8) & 0xFF {
+            0 => return,
+            next => offset += next as usize * 4,
+        }
+    }
+}
+
+/// Resets and starts the controller at `address`, which will be at `index` in `CONTROLLERS`.
+unsafe fn init_controller(address: PciAddress, index: usize) -> Result<Controller, XhciError> {
+    use operational::*;
+    if index >= MAX_CONTROLLERS {
+        return Err(XhciError::TooManyControllers);
+    }
+    let base = pci::memory_bar_address(address, 0).ok_or(XhciError::NoMemoryBar)? as usize;
+    for page in (base..base + REGISTERS_SIZE).step_by(PAGE_SIZE) {
+        unsafe {
+            if !page_allocation::is_address_identity_mapped(page) {
+                page_allocation::map_page_translation(page, page, PageTableEntry::READ_WRITE)
+                    .map_err(|_| XhciError::OutOfMemory)?;
+            }
+        }
+    }
+    let command = pci::read_word(address, pci::REGISTER_COMMAND);
+    unsafe {
+        pci::write_word(
+            address,
+            pci::REGISTER_COMMAND,
+            command | pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER,
+        );
+    }
+    let capabilities = Registers { base };
+    let capability_params = capabilities.read(capability::CAPABILITY_PARAMS_1);
+    take_ownership(base, capability_params);
+    let operational_registers = Registers {
+        base: base + (capabilities.read(capability::LENGTH) & 0xFF) as usize,
+    };
+    let runtime = base + (capabilities.read(capability::RUNTIME_OFFSET) & !0x1F) as usize;
+    let interrupter_registers = Registers {
+        base: runtime + interrupter::OFFSET,
+    };
+    let doorbells = Registers {
+        base: base + (capabilities.read(capability::DOORBELL_OFFSET) & !0x3) as usize,
+    };
+    let structural_params_1 = capabilities.read(capability::STRUCTURAL_PARAMS_1);
+    let max_slots = (structural_params_1 & 0xFF).min(MAX_SLOTS);
+    let num_ports = (structural_params_1 >> 24) as u8;
+    let structural_params_2 = capabilities.read(capability::STRUCTURAL_PARAMS_2);
+    let num_scratchpads =
+        ((structural_params_2 >> 21) & 0x1F) << 5 | (structural_params_2 >> 27) & 0x1F;
+
+    let operational = operational_registers;
+    operational.write(COMMAND, operational.read(COMMAND) & !COMMAND_RUN);
+    wait_until(|| operational.read(STATUS) & STATUS_HALTED != 0)?;
+    operational.write(COMMAND, COMMAND_RESET);
+    wait_until(|| {
+        operational.read(COMMAND) & COMMAND_RESET == 0
+            && operational.read(STATUS) & STATUS_NOT_READY == 0
+    })?;
+    operational.write(CONFIGURE, max_slots);
+
+    // Context base array, command ring, event ring, then the event ring segment table. The
+    // controller is never removed, so neither is its memory.
+    let memory =
+        page_allocation::find_and_reserve_contiguous(4).map_err(|_| XhciError::OutOfMemory)?;
+    let context_base_array = memory as *mut u64;
+    let command_ring = unsafe { Ring::new(memory + PAGE_SIZE) };
+    let event_ring = EventRing {
+        trbs: (memory + 2 * PAGE_SIZE) as *mut Trb,
+        dequeue: 0,
+        cycle: true,
+    };
+    let segment_table = memory + 3 * PAGE_SIZE;
+    unsafe {
+        (segment_table as *mut u64).write_volatile((memory + 2 * PAGE_SIZE) as u64);
+        (segment_table as *mut u32)
+            .add(2)
+            .write_volatile(RING_LEN as u32);
+    }
+    if num_scratchpads != 0 {
+        let scratchpads =
+            page_allocation::find_and_reserve_contiguous(1 + num_scratchpads as usize)
+                .map_err(|_| XhciError::OutOfMemory)?;
+        for i in 0..num_scratchpads as usize {
+            unsafe {
+                (scratchpads as *mut u64)
+                    .add(i)
+                    .write_volatile((scratchpads + (1 + i) * PAGE_SIZE) as u64);
+            }
+        }
+        unsafe { context_base_array.write_volatile(scratchpads as u64) };
+    }
+    operational.write_u64(CONTEXT_BASE_ARRAY, memory as u64);
+    operational.write_u64(COMMAND_RING_CONTROL, command_ring.address() | 1);
+
+    {
+        use interrupter::*;
+        let interrupter = interrupter_registers;
+        interrupter.write(MODERATION, MODERATION_INTERVAL);
+        interrupter.write(TABLE_SIZE, 1);
+        interrupter.write_u64(DEQUEUE, event_ring.trbs as u64);
+        interrupter.write_u64(TABLE_BASE, segment_table as u64);
+        interrupter.write(MANAGEMENT, MANAGEMENT_ENABLE | MANAGEMENT_PENDING);
+    }
+    let vector =
+        unsafe { setup_interrupt(address, operational_registers, interrupter_registers, index)? };
+    operational.write(COMMAND, COMMAND_RUN | COMMAND_INTERRUPTER_ENABLE);
+    wait_until(|| operational.read(STATUS) & STATUS_HALTED == 0)?;
+
+    let context_size = match capability_params & capability::PARAMS_64_BYTE_CONTEXTS != 0 {
+        true => 64,
+        false => 32,
+    };
+    Ok(Controller {
+        index,
+        operational: operational_registers,
+        interrupter: interrupter_registers,
+        doorbells,
+        context_size,
+        num_ports,
+        context_base_array,
+        command_ring,
+        event_ring,
+        keyboards: Vec::new(),
+        _vector: vector,
+    })
+}
+
+/// Sets up every XHCI controller on PCI, and any keyboards connected directly to them. Must be
+/// called from a kernel thread, after interrupts and the work queue are set up.
+pub fn init() {
+    let mut addresses = Vec::new();
+    pci::for_each_function(|address| {
+        if pci::read_dword(address, pci::REGISTER_CLASS) >> 8 == CLASS_XHCI {
+            addresses.push(address);
+        }
+    });
+    for address in addresses {
+        let index = CONTROLLERS.lock().len();
+        let mut controller = match unsafe { init_controller(address, index) } {
+            Ok(controller) => controller,
+            Err(err) => {
+                log::warn!("Failed to set up XHCI controller at {address} - {err}");
+                continue;
+            }
+        };
+        // Until the controller is in `CONTROLLERS`, event work ignores it, so each port's
+        // events are polled for instead
+        for port in 1..=controller.num_ports {
+            if let Err(err) = controller.init_port(port) {
+                log::warn!("xhci{index}: failed to set up port {port} - {err}");
+            }
+        }
+        CONTROLLERS.lock().push(controller);
+        // Events which arrived since the last poll
+        work_queue::schedule(&EVENT_WORKS[index]);
+    }
+}
+
+mod kernel_tests {
+    use super::*;
+    use crate::ktest::{TestResult, kernel_test};
+    use crate::ktest_assert_eq;
+
+    #[kernel_test]
+    fn converts_endpoint_intervals() -> TestResult {
+        // Full and low speed intervals are in 1 ms frames, rounded down to a power of 2
+        ktest_assert_eq!(endpoint_interval(SPEED_FULL, 1), 3);
+        ktest_assert_eq!(endpoint_interval(SPEED_FULL, 10), 6);
+        ktest_assert_eq!(endpoint_interval(SPEED_LOW, 255), 10);
+        ktest_assert_eq!(endpoint_interval(SPEED_FULL, 0), 3);
+        // High speed intervals are already exponents
+        ktest_assert_eq!(endpoint_interval(SPEED_HIGH, 4), 3);
+        ktest_assert_eq!(endpoint_interval(SPEED_HIGH, 0), 0);
+        ktest_assert_eq!(endpoint_interval(SPEED_HIGH, 20), 15);
+        Ok(())
+    }
+}
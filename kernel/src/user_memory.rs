@@ -0,0 +1,142 @@
+//! Checking pointers passed in by user code, and copying the memory behind them.
+//!
+//! System call handlers taking pointers gather their arguments into a struct deriving
+//! `UserArgs`, which checks every pointer field against the current process's segments. Pointer
+//! fields can't be left unmarked, so a new argument can't skip its check by accident.
+//!
+//! User memory is only touched by the copy functions here, which check the range themselves and
+//! allow the access with `arch::protection::UserAccess`, as it's otherwise blocked by SMAP.
+//! Checking a range reads in any file backed pages it covers, as the kernel can't handle page
+//! faults on user memory. Threads aren't preempted, so a checked range can't be unmapped before
+//! it's copied.
+
+use crate::arch;
+use crate::arch::paging::{self, PAGE_SIZE, align_to_page};
+use crate::arch::protection::UserAccess;
+use crate::arch::syscall::SyscallError;
+use crate::kthread;
+use crate::process::ProcessMemory;
+use crate::vma::VMAFaultError;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub use syscall_args::UserArgs;
+
+/// System call arguments with user pointers, usually implemented with `#[derive(UserArgs)]`.
+pub trait UserArgs {
+    /// Checks that every pointer can be accessed as marked.
+    fn validate(&self) -> Result<(), SyscallError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Checks that `len` bytes at `address` are in segments of the current process, accessible from
+/// user mode with `access`, and maps any pages in the range that haven't been read in yet. Empty
+/// ranges are always accepted, apart from at the null page.
+pub fn check_user_range(address: usize, len: usize, access: Access) -> Result<(), SyscallError> {
+    if address < PAGE_SIZE {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    if len == 0 {
+        return Ok(());
+    }
+    let end = address
+        .checked_add(len - 1)
+        .filter(|end| arch::process::is_user_address_valid(*end))
+        .ok_or(SyscallError::INVALID_ARGUMENT)?;
+    let process = kthread::current_process().ok_or(SyscallError::INVALID_ARGUMENT)?;
+    let page_table_address = process.page_table_address();
+    let mut memory = process.memory.lock();
+    let ProcessMemory {
+        segments,
+        pages_used,
+    } = &mut *memory;
+    let mut segment_address = address;
+    loop {
+        let (flags, segment_end) = segments
+            .segment_containing(segment_address)
+            .ok_or(SyscallError::INVALID_ARGUMENT)?;
+        if !flags.read || (access == Access::Write && !flags.write) {
+            return Err(SyscallError::INVALID_ARGUMENT);
+        }
+        if segment_end >= end {
+            break;
+        }
+        segment_address = segment_end + 1;
+    }
+    for page in (align_to_page(address)..=end).step_by(PAGE_SIZE) {
+        if unsafe { paging::find_entry(page_table_address, page) }.is_none() {
+            match segments.handle_page_fault(pages_used, page, access == Access::Write) {
+                Ok(()) | Err(VMAFaultError::PageAlreadyMapped) => {}
+                Err(VMAFaultError::OutOfMemory) => return Err(SyscallError::OUT_OF_MEMORY),
+                Err(_) => return Err(SyscallError::INVALID_ARGUMENT),
+            }
+        }
+        let entry = unsafe { paging::find_entry(page_table_address, page) }
+            .ok_or(SyscallError::INVALID_ARGUMENT)?;
+        if !entry.user_accessable() || (access == Access::Write && !entry.writable()) {
+            return Err(SyscallError::INVALID_ARGUMENT);
+        }
+    }
+    Ok(())
+}
+
+/// Copies `dst.len()` bytes from user memory at `src` into `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), SyscallError> {
+    check_user_range(src, dst.len(), Access::Read)?;
+    let _access = unsafe { UserAccess::enter() };
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+    Ok(())
+}
+
+/// Copies `src` into user memory at `dst`.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), SyscallError> {
+    check_user_range(dst, src.len(), Access::Write)?;
+    let _access = unsafe { UserAccess::enter() };
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+    Ok(())
+}
+
+/// Copies a NUL terminated string from user memory at `src` into `dst`, stopping at the NUL or
+/// once `dst` is full. Returns the string's length, not counting the NUL. Only the pages the
+/// string is read from need to be mapped.
+pub fn strncpy_from_user(dst: &mut [u8], src: usize) -> Result<usize, SyscallError> {
+    let mut copied = 0;
+    while copied < dst.len() {
+        let address = src
+            .checked_add(copied)
+            .ok_or(SyscallError::INVALID_ARGUMENT)?;
+        // Up to the end of the page, so a string ending just before an unmapped page is fine
+        let chunk_len = (PAGE_SIZE - address % PAGE_SIZE).min(dst.len() - copied);
+        check_user_range(address, chunk_len, Access::Read)?;
+        let _access = unsafe { UserAccess::enter() };
+        for index in 0..chunk_len {
+            let byte = unsafe { (address as *const u8).add(index).read() };
+            if byte == 0 {
+                return Ok(copied);
+            }
+            dst[copied] = byte;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Copies the UTF-8 string of `len` bytes at `ptr` out of user memory. Fails without allocating
+/// if `len` is more than `max_len`, as it's chosen by user code.
+pub fn read_string(ptr: *const u8, len: usize, max_len: usize) -> Result<String, SyscallError> {
+    if len > max_len {
+        return Err(SyscallError::INVALID_ARGUMENT);
+    }
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(len)
+        .map_err(|_| SyscallError::OUT_OF_MEMORY)?;
+    bytes.resize(len, 0);
+    copy_from_user(&mut bytes, ptr as usize)?;
+    String::from_utf8(bytes).map_err(|_| SyscallError::INVALID_ARGUMENT)
+}
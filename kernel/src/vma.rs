@@ -1,18 +1,49 @@
 use crate::arch;
+use crate::arch::address_space::{
+    self, AddressSpaceError, MapMemError, MapMemTask, UnmapMemTask, UserAddressSpace,
+};
 use crate::arch::paging::PAGE_SIZE;
-use crate::arch::user_page_mapping::{UnmapMemTask, MapMemTask, UserPageMapper, MapMemError};
-use crate::physical_block_allocator::{PageBox, PhysicalBlockAllocator};
-use core::alloc::AllocError;
-use core::mem::{size_of, offset_of};
+use crate::physical_block_allocator::PhysicalBlockAllocator;
+use crate::shared_memory::SharedMemory;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::{AllocError, Allocator, Layout};
+use core::fmt;
 use core::ptr::NonNull;
 use core::task::Poll;
 use spin::Mutex;
+use vma_tree::{LeafInfo, LeafNode, NodeFlags, NodePtr, PageStorage};
+
+/// Segment tree with its nodes stored in physical pages.
+type VMATree = vma_tree::VMATree<PhysicalBlockAllocator>;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Segment {
     pub start: usize,
     pub len: usize,
     pub flags: SegmentFlags,
+    /// File the segment's pages are read from when first touched, instead of being mapped up
+    /// front. Pages are zero filled otherwise.
+    pub backing: Option<SegmentBacking>,
+}
+
+/// A file backing a segment, with the segment's first page at `offset`. Parts of pages past the
+/// end of the file are zero filled.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SegmentBacking {
+    /// File contents, from the initrd.
+    pub file: &'static [u8],
+    pub offset: usize,
+}
+
+impl fmt::Debug for SegmentBacking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SegmentBacking")
+            .field("file", &self.file.as_ptr_range())
+            .field("offset", &self.offset)
+            .finish()
+    }
 }
 
 // TODO: Replace this with a bitfield structure, to be taken straight from syscall
@@ -34,6 +65,68 @@ pub enum VMAMapError {
     OutOfAddressSpace,
 }
 
+impl From<AllocError> for VMAMapError {
+    fn from(_: AllocError) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VMACheckpointError {
+    #[error("not a segment checkpoint")]
+    BadMagic,
+    #[error("the checkpoint is truncated")]
+    Truncated,
+    #[error("the checkpoint has data past its last segment")]
+    TrailingData,
+    #[error("segment {0} is invalid")]
+    InvalidSegment(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VMAResizeError {
+    #[error("no segment starts at the address")]
+    SegmentNotMapped,
+    #[error("the segment is currently locked")]
+    SegmentLocked,
+    #[error("the new length is invalid")]
+    InvalidLength,
+    #[error("not enough free space after the segment")]
+    NoSpace,
+    #[error("shared segments can't be resized")]
+    SharedSegment,
+    #[error("file backed segments can't be resized")]
+    BackedSegment,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VMAProtectError {
+    #[error("the segment is not mapped")]
+    SegmentNotMapped,
+    #[error("the segment is currently locked")]
+    SegmentLocked,
+    #[error("the range does not cover exactly one segment")]
+    PartialSegment,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VMAFaultError {
+    #[error("the address is not in a segment")]
+    SegmentNotMapped,
+    #[error("the segment is not file backed")]
+    NotBacked,
+    #[error("the segment doesn't allow the access")]
+    AccessDenied,
+    #[error("the page is already mapped")]
+    PageAlreadyMapped,
+    #[error("the segment is currently locked")]
+    SegmentLocked,
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
 pub enum VMAUnmapError {
     #[error("the segment is already unmapped")]
@@ -43,18 +136,164 @@ pub enum VMAUnmapError {
 }
 
 pub struct VMAAllocator {
-    page_mapper: UserPageMapper,
+    address_space: UserAddressSpace,
     tree: Mutex<VMATree>,
+    /// Objects mapped by shared segments, keyed by segment start address.
+    shared: BTreeMap<usize, Arc<SharedMemory>>,
+    /// Backings of file backed segments, keyed by segment start address.
+    backings: BTreeMap<usize, SegmentBacking>,
 }
 
 impl VMAAllocator {
-    pub fn new(page_mapper: UserPageMapper, pages_used: &mut usize) -> Result<Self, AllocError> {
+    pub fn new(
+        address_space: UserAddressSpace,
+        pages_used: &mut usize,
+    ) -> Result<Self, AllocError> {
         Ok(Self {
-            page_mapper,
-            tree: Mutex::new(VMATree::new(pages_used)?),
+            address_space,
+            tree: Mutex::new(VMATree::new(
+                pages_used,
+                arch::process::HIGHEST_USER_ADDRESS,
+            )?),
+            shared: BTreeMap::new(),
+            backings: BTreeMap::new(),
         })
     }
 
+    pub fn address_space(&self) -> &UserAddressSpace {
+        &self.address_space
+    }
+
+    /// Grows or shrinks the segment starting at `start` in place, to `new_len` bytes.
+    ///
+    /// Growing takes space from the gap after the segment, which must be left with at least a
+    /// page, and the new pages are mapped by the returned task. Shrinking unmaps the pages past
+    /// the new end when the task is run, then gives them back to the gap after the segment. The
+    /// segment is locked until the task completes.
+    pub fn start_resize_segment(
+        &mut self,
+        pages_used: &mut usize,
+        start: usize,
+        new_len: usize,
+    ) -> Result<ResizeTask, VMAResizeError> {
+        if new_len == 0 || !new_len.is_multiple_of(PAGE_SIZE) {
+            return Err(VMAResizeError::InvalidLength);
+        }
+        let new_end = start
+            .checked_add(new_len - 1)
+            .filter(|end| *end <= arch::process::HIGHEST_USER_ADDRESS)
+            .ok_or(VMAResizeError::InvalidLength)?;
+        let mut tree = self.tree.lock();
+        let LeafInfo {
+            leaf,
+            start: segment_start,
+            end,
+            ..
+        } = tree.get_leaf_containing(start);
+        unsafe {
+            let LeafNode::Used { flags } = leaf.unwrap_leaf().read() else {
+                return Err(VMAResizeError::SegmentNotMapped);
+            };
+            if segment_start != start {
+                return Err(VMAResizeError::SegmentNotMapped);
+            }
+            if flags.locked() {
+                return Err(VMAResizeError::SegmentLocked);
+            }
+            if flags.shared() {
+                return Err(VMAResizeError::SharedSegment);
+            }
+            if self.backings.contains_key(&start) {
+                return Err(VMAResizeError::BackedSegment);
+            }
+            if new_end == end {
+                return Ok(ResizeTask {
+                    start,
+                    state: ResizeState::Unchanged,
+                });
+            }
+            let next_is_gap = end < arch::process::HIGHEST_USER_ADDRESS
+                && tree.get_leaf_containing(end + 1).leaf.is_empty_leaf();
+            let state = if new_end > end {
+                if !next_is_gap || new_end >= tree.get_leaf_containing(end + 1).end {
+                    return Err(VMAResizeError::NoSpace);
+                }
+                tree.move_boundary(start, end, new_end);
+                ResizeState::Growing {
+                    old_end: end,
+                    map_mem_task: MapMemTask::new(
+                        end + 1,
+                        (new_end - end) / PAGE_SIZE,
+                        flags.into(),
+                    ),
+                }
+            } else {
+                // Nodes for the new gap are reserved now, so that finishing can't fail
+                let spare_nodes = match next_is_gap {
+                    true => None,
+                    false => Some(
+                        tree.reserve_node_pair(pages_used)
+                            .map_err(|_| VMAResizeError::OutOfMemory)?,
+                    ),
+                };
+                ResizeState::Shrinking {
+                    new_end,
+                    spare_nodes,
+                    unmap_mem_task: UnmapMemTask::new(new_end + 1, (end - new_end) / PAGE_SIZE),
+                }
+            };
+            tree.set_segment_locked(start, true);
+            Ok(ResizeTask { start, state })
+        }
+    }
+
+    /// Returns every segment, in address order.
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        self.tree.lock().for_each_leaf(|leaf, start, end| {
+            if let LeafNode::Used { flags } = leaf {
+                segments.push(Segment {
+                    start,
+                    len: end + 1 - start,
+                    flags: flags.into(),
+                    backing: self.backings.get(&start).copied(),
+                });
+            }
+        });
+        segments
+    }
+
+    /// Serialises the segments and their flags, in the format read by `deserialise_segments`.
+    pub fn serialise(&self) -> Vec<u8> {
+        serialise_segments(&self.segments())
+    }
+
+    /// Adds `segments` to the tree without mapping any memory, for when the address space has
+    /// been filled in some other way, e.g. copied from another process. Segments added before an
+    /// error are left in the tree.
+    ///
+    /// # Safety
+    ///
+    /// The segments must meet the requirements of `start_try_map_at`.
+    pub unsafe fn insert_segments(
+        &mut self,
+        pages_used: &mut usize,
+        segments: &[Segment],
+    ) -> Result<(), VMAMapError> {
+        let mut tree = self.tree.lock();
+        for segment in segments {
+            let LeafInfo { leaf, end, .. } = tree.get_leaf_containing(segment.start);
+            if end < segment.start + segment.len - 1 || unsafe { !leaf.is_empty_leaf() } {
+                return Err(VMAMapError::SegmentAlreadyExists);
+            }
+            tree.insert(pages_used, segment.start, segment.len, segment.flags.into())?;
+            if let Some(backing) = segment.backing {
+                self.backings.insert(segment.start, backing);
+            }
+        }
+        Ok(())
+    }
+
     /// Unmaps the segment containing `segment_address`.
     /// Returns `VMAUnmapError::SegmentAlreadyUnmapped` if `segment_address` does not belong to a
     /// segment, or `VMAUnmapError::SegmentLocked` if the segment is currently locked for mapping
@@ -76,9 +315,13 @@ impl VMAAllocator {
                         return Err(VMAUnmapError::SegmentLocked);
                     }
                     flags.set_locked(true);
+                    let num_pages = (end + 1 - start) / PAGE_SIZE;
                     Ok(UnmapTask {
                         start_address: start,
-                        unmap_mem_task: UnmapMemTask::new(start, (end + 1 - start) / PAGE_SIZE),
+                        unmap_mem_task: match flags.shared() {
+                            true => UnmapMemTask::new_shared(start, num_pages),
+                            false => UnmapMemTask::new(start, num_pages),
+                        },
                     })
                 }
             }
@@ -93,6 +336,61 @@ impl VMAAllocator {
         &mut self,
         pages_used: &mut usize,
         new_segment: Segment,
+    ) -> Result<MapTask, VMAMapError> {
+        unsafe { self.start_map_at(pages_used, new_segment, None) }
+    }
+
+    /// Maps the pages of `object` at `start`, like `start_try_map_at`. Other mappings of the
+    /// object can have different flags, and its pages are left to it when the segment is unmapped.
+    ///
+    /// # Safety
+    ///
+    /// `start` must be page aligned, and the segment must end at or below
+    /// `arch::process::HIGHEST_USER_ADDRESS`.
+    pub unsafe fn start_try_map_shared_at(
+        &mut self,
+        pages_used: &mut usize,
+        start: usize,
+        object: Arc<SharedMemory>,
+        flags: SegmentFlags,
+    ) -> Result<MapTask, VMAMapError> {
+        let new_segment = Segment {
+            start,
+            len: object.size(),
+            flags,
+            backing: None,
+        };
+        unsafe { self.start_map_at(pages_used, new_segment, Some(object)) }
+    }
+
+    /// Maps the pages of `object` in the lowest gap large enough for them at or above `start`,
+    /// like `start_find_map`.
+    ///
+    /// # Safety
+    ///
+    /// `start` must be page aligned.
+    pub unsafe fn start_find_map_shared(
+        &mut self,
+        pages_used: &mut usize,
+        start: usize,
+        object: Arc<SharedMemory>,
+        flags: SegmentFlags,
+    ) -> Result<MapTask, VMAMapError> {
+        let start = self
+            .tree
+            .lock()
+            .find_gap(start, object.size())
+            .ok_or(VMAMapError::OutOfAddressSpace)?;
+        unsafe { self.start_try_map_shared_at(pages_used, start, object, flags) }
+    }
+
+    /// Maps `new_segment` with new pages, or with the pages of `object` if it's given. File backed
+    /// segments are only added to the tree, as their pages are mapped by `handle_page_fault`.
+    unsafe fn start_map_at(
+        &mut self,
+        pages_used: &mut usize,
+        new_segment: Segment,
+        object: Option<Arc<SharedMemory>>,
     ) -> Result<MapTask, VMAMapError> {
         unsafe {
             debug_assert_eq!(new_segment.start % PAGE_SIZE, 0);
@@ -116,12 +414,30 @@ impl VMAAllocator {
                     .unwrap_leaf();
                     let flags_ptr = new_leaf.unwrap_used_flags_ptr();
                     (*flags_ptr.as_ptr()).set_locked(true);
-                    Ok(MapTask {
-                        map_mem_task: MapMemTask::new(
+                    let map_mem_task = match (object, new_segment.backing) {
+                        (Some(object), _) => {
+                            (*flags_ptr.as_ptr()).set_shared(true);
+                            let frames = object.frames().clone();
+                            self.shared.insert(new_segment.start, object);
+                            Some(MapMemTask::new_shared(
+                                new_segment.start,
+                                frames,
+                                new_segment.flags,
+                            ))
+                        }
+                        (None, Some(backing)) => {
+                            self.backings.insert(new_segment.start, backing);
+                            None
+                        }
+                        (None, None) => Some(MapMemTask::new(
                             new_segment.start,
                             new_segment.len / PAGE_SIZE,
                             new_segment.flags,
-                        ),
+                        )),
+                    };
+                    Ok(MapTask {
+                        start_address: new_segment.start,
+                        map_mem_task,
                     })
                 }
                 LeafNode::Used { .. } => Err(VMAMapError::SegmentAlreadyExists),
@@ -129,303 +445,208 @@ impl VMAAllocator {
         }
     }
 
-    // /// # Safety
-    // ///
-    // /// The start of `new_segment` is intepreted as a hint of where to put the mapping.
-    // /// Panics if the start and length of `new_segment` are not page aligned, or if the end address
-    // /// is not less than or equal to `arch::process::HIGHEST_USER_ADDRESS`.
-    // pub fn start_find_map(
-    //     &mut self,
-    //     pages_used: &mut usize,
-    //     new_segment: Segment,
-    // ) -> Result<MapTask, AllocError> {
-    //     // 1. Get information about segment start.
-    //     // 2. If we're in a gap large enough to map the segment, just create a task starting at
-    //     //    PageMapping.
-    //     // 3. If we're in a gap, but not one large enough to map the segment, just create a search
-    //     //    task starting at `right_node`.1
-    //     // 4. If we're in a segment, do the same as above just using the mapping we're already
-    //     //    inside.
-    //     debug_assert_eq!(new_segment.start % PAGE_SIZE, 0);
-    //     debug_assert_eq!(new_segment.len % PAGE_SIZE, 0);
-    //     let new_segment_end = new_segment.start + new_segment.len - 1;
-    //     debug_assert!(new_segment_end <= arch::process::HIGHEST_USER_ADDRESS);
-    //     Ok(match self.tree.get_area_info(new_segment.start) {
-    //         AddressInfo::Space {
-    //             start,
-    //             length,
-    //             // left_node: _,
-    //             right_node,
-    //         } => {
-    //             MapTask {
-    //                 state: if start + length - 1 >= new_segment_end {
-    //                     let mut node = self
-    //                         .node_storage
-    //                         .find_and_reserve_node(pages_used)
-    //                         .unwrap();
-    //                     node.set_start(new_segment.start);
-    //                     node.len = new_segment.len;
-    //                     node.flags = new_segment.flags.into();
-    //                     let node_ptr = self.tree.insert(node).as_ptr();
-    //                     MapState::PageMapping {
-    //                         current_address: new_segment.start,
-    //                         new_mapping: node_ptr,
-    //                     }
-    //                 } else {
-    //                     MapState::GapSearch {
-    //                         current_mapping: match right_node {
-    //                             Some(ptr) => ptr,
-    //                             None => return Err(AllocError),
-    //                         },
-    //                     }
-    //                 },
-    //                 new_segment,
-    //             }
-    //         }
-    //         AddressInfo::Segment(node_ptr) => MapTask {
-    //             state: MapState::GapSearch {
-    //                 current_mapping: node_ptr,
-    //             },
-    //             new_segment,
-    //         },
-    //     })
-    // }
-}
-
-struct NodeStorageList {
-    head: NonNull<NodeStoragePage>,
-    /// Used in node deletion operations.
-    temp_node: Node,
-}
-
-impl NodeStorageList {
-    pub fn new() -> Result<Self, AllocError> {
-        let head_page = PageBox::try_new_in(
-            NodeStoragePage::new_with_prev_page(None),
-            PhysicalBlockAllocator,
-        )?;
-        Ok(Self {
-            head: unsafe { NonNull::new_unchecked(PageBox::into_raw_with_allocator(head_page).0) },
-            temp_node: Node::placeholder(),
-        })
-    }
-
-    /// Searches storage pages for a node space.
-    /// If no space is found, this will attempt to allocate a new storage page, which may fail.
-    fn find_and_reserve_node(&mut self, pages_used: &mut usize) -> Result<NodePtr, VMAMapError> {
-        unsafe {
-            let mut current_page_ptr = self.head;
-            let mut current_page = current_page_ptr.as_mut();
-            loop {
-                if current_page.free_entries > 0 {
-                    return Ok(current_page.find_and_reserve_node().unwrap());
-                } else {
-                    match current_page.next_page {
-                        Some(next_page_ptr) => current_page_ptr = next_page_ptr,
-                        None => break,
-                    }
-                    current_page = current_page_ptr.as_mut();
-                }
-            }
-            // No space found, allocate new page
-            *pages_used += 1;
-            let Ok(mut new_page) = PageBox::try_new_in(
-                NodeStoragePage::new_with_prev_page(Some(current_page_ptr)),
-                PhysicalBlockAllocator,
-            ) else {
-                return Err(VMAMapError::OutOfMemory);
-            };
-            let node = new_page.find_and_reserve_node().unwrap();
-            current_page.next_page = Some(NonNull::new_unchecked(
-                PageBox::into_raw_with_allocator(new_page).0,
-            ));
-            Ok(node)
-        }
-    }
-
-    pub fn new_empty_leaf(
-        &mut self,
-        pages_used: &mut usize,
-        size: usize,
-    ) -> Result<NodePtr, VMAMapError> {
-        unsafe {
-            let node_ptr = self.find_and_reserve_node(pages_used)?;
-            node_ptr.write(Node::Leaf(LeafNode::Empty { size }));
-            Ok(node_ptr)
-        }
-    }
-
-    pub fn new_used_leaf(
-        &mut self,
-        pages_used: &mut usize,
-        flags: NodeFlags,
-    ) -> Result<NodePtr, VMAMapError> {
-        unsafe {
-            let node_ptr = self.find_and_reserve_node(pages_used)?;
-            node_ptr.write(Node::Leaf(LeafNode::Used { flags }));
-            Ok(node_ptr)
-        }
-    }
-
-    pub fn new_branch(
+    /// Maps `new_segment` in the lowest gap large enough for it at or above its start address,
+    /// which is only used as a hint. Returns `VMAMapError::OutOfAddressSpace` if there is no
+    /// such gap.
+    ///
+    /// # Safety
+    ///
+    /// The start and length of `new_segment` must be page aligned.
+    pub unsafe fn start_find_map(
         &mut self,
         pages_used: &mut usize,
-        pivot: usize,
-        parent: Option<BranchNodePtr>,
-        left: NodePtr,
-        right: NodePtr,
-    ) -> Result<NodePtr, VMAMapError> {
-        unsafe {
-            let node_ptr = self.find_and_reserve_node(pages_used)?;
-            node_ptr.write(Node::Branch(BranchNode::new(
-                pivot,
-                false,
-                NodeColor::Black,
-                parent,
-                left,
-                right,
-            )));
-            Ok(node_ptr)
-        }
-    }
-
-    pub fn get_temp_node(&mut self) -> NodePtr {
-        NodePtr(NonNull::from(&mut self.temp_node))
-    }
-}
-
-#[repr(C)]
-struct NodeStoragePage {
-    pub entries: [Node; Self::MAX_NODES],
-    pub next_page: Option<NonNull<NodeStoragePage>>,
-    pub prev_page: Option<NonNull<NodeStoragePage>>,
-    pub free_entries: usize,
-    pub usage_bitmap: [u8; Self::BITMAP_LEN],
-}
-
-impl NodeStoragePage {
-    const MAX_NODES: usize = {
-        // Iteratively reduce array length until both the array and bitmap can fit
-        let max_array_and_bitmap_space: usize = PAGE_SIZE - (3 * size_of::<usize>());
-        let mut current_num_entries = max_array_and_bitmap_space / size_of::<Node>();
-        loop {
-            let extra_bitmap_len = !current_num_entries.is_multiple_of(8) as usize;
-            let bitmap_byte_size = (current_num_entries / 8) + extra_bitmap_len;
-            let entries_byte_size = current_num_entries * size_of::<Node>();
-            if entries_byte_size + bitmap_byte_size <= max_array_and_bitmap_space {
-                break;
-            }
-            current_num_entries -= 1;
-        }
-        current_num_entries
-    };
-    const BITMAP_LEN: usize = (Self::MAX_NODES / 8) + (Self::MAX_NODES % 8 > 0) as usize;
-    const INITIAL_USAGE_BITMAP: [u8; Self::BITMAP_LEN] = match Self::MAX_NODES % 8 {
-        0 => [0; Self::BITMAP_LEN],
-        last_byte_entries => {
-            // If there are not enough entries to fill the last byte, fill the
-            // least significant bits past the end of the entries bitmap to
-            // indicate that they are not free.
-            // This technically probably isn't required, as the `num_entries_free`
-            // field already tracks the number of free entries, and should mean
-            // that bits past the end of the usable bitmap are never used anyway,
-            // but this is just to be on the safe side.
-            let mut bitmap = [0; Self::BITMAP_LEN];
-            bitmap[Self::BITMAP_LEN - 1] = !((0x80 >> (last_byte_entries - 1)) - 1);
-            bitmap
-        }
-    };
-
-    pub fn new_with_prev_page(prev_page: Option<NonNull<NodeStoragePage>>) -> Self {
-        Self {
-            entries: core::array::from_fn(|_| Node::placeholder()),
-            next_page: None,
-            prev_page,
-            free_entries: Self::MAX_NODES,
-            usage_bitmap: Self::INITIAL_USAGE_BITMAP,
+        new_segment: Segment,
+    ) -> Result<MapTask, VMAMapError> {
+        debug_assert_eq!(new_segment.start % PAGE_SIZE, 0);
+        debug_assert_eq!(new_segment.len % PAGE_SIZE, 0);
+        let start = self
+            .tree
+            .lock()
+            .find_gap(new_segment.start, new_segment.len)
+            .ok_or(VMAMapError::OutOfAddressSpace)?;
+        unsafe {
+            self.start_try_map_at(
+                pages_used,
+                Segment {
+                    start,
+                    ..new_segment
+                },
+            )
         }
     }
 
-    #[inline]
-    pub fn find_and_reserve_node(&mut self) -> Option<NodePtr> {
-        if self.free_entries == 0 {
+    /// Returns the flags of the segment containing `address` and the last address in it, or `None`
+    /// if `address` isn't in a segment, or the segment is locked.
+    pub fn segment_containing(&self, address: usize) -> Option<(SegmentFlags, usize)> {
+        if !arch::process::is_user_address_valid(address) {
             return None;
         }
-        for (byte_index, byte) in self.usage_bitmap.iter_mut().enumerate() {
-            if *byte != 0xFF {
-                let bit_index = (!*byte).leading_zeros() as usize;
-                *byte |= 0x80 >> bit_index;
-                self.free_entries -= 1;
-                let entry_index = (byte_index * 8) + bit_index;
-                debug_assert!(entry_index < Self::MAX_NODES);
-                return Some(NodePtr(NonNull::from(&mut self.entries[entry_index])));
-            }
-        }
-        unreachable!();
-    }
-
-    /// Mark the node at the given index as no longer reserved.
-    /// If this returns `true`, then this page is now empty, and has unlinked itself.
-    /// This means it should be now be freed.
-    #[must_use]
-    pub unsafe fn unreserve_node(&mut self, i: usize) -> bool {
-        debug_assert!(i < Self::MAX_NODES);
-        // Check if the page is now completely empty, and that we're not the head page.
-        if self.free_entries + 1 == Self::MAX_NODES
-            && let Some(mut prev_page) = self.prev_page
-        {
-            // If this is empty, unlink it and report that this page should be freed.
-            unsafe {
-                if let Some(mut next_page) = self.next_page {
-                    next_page.as_mut().prev_page = self.prev_page;
-                }
-                prev_page.as_mut().next_page = self.next_page;
-                true
-            }
-        } else {
-            // If the page still isn't empty, just mark the node as no longer reserved.
-            let byte_index = i / 8;
-            let bit_index = i % 8;
-            self.usage_bitmap[byte_index] &= !(0x80 >> bit_index);
-            self.free_entries += 1;
-            false
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum NodeColor {
-    Red = 0,
-    Black = 1,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Side {
-    Left = 0,
-    Right = 1,
-}
-
-impl core::ops::Not for Side {
-    type Output = Self;
-
-    fn not(self) -> Self::Output {
-        match self {
-            Side::Left => Side::Right,
-            Side::Right => Side::Left,
+        let LeafInfo { leaf, end, .. } = self.tree.lock().get_leaf_containing(address);
+        match unsafe { leaf.unwrap_leaf().read() } {
+            LeafNode::Used { flags } if !flags.locked() => Some((flags.into(), end)),
+            _ => None,
         }
     }
-}
 
-bitfield::bitfield! {
-    #[derive(Clone, Copy, PartialEq, Eq)]
-    #[repr(transparent)]
-    pub struct NodeFlags(u32);
-    impl Debug;
-    pub readable, set_readable: 0;
-    pub writable, set_writable: 1;
-    pub executable, set_executable: 2;
-    pub locked, set_locked: 31;
-}
+    /// Maps the page containing `address` in a file backed segment, reading it from the file.
+    /// Called on page faults from user mode, with `write` set for write accesses.
+    pub fn handle_page_fault(
+        &mut self,
+        pages_used: &mut usize,
+        address: usize,
+        write: bool,
+    ) -> Result<(), VMAFaultError> {
+        if !arch::process::is_user_address_valid(address) {
+            return Err(VMAFaultError::SegmentNotMapped);
+        }
+        let LeafInfo {
+            leaf,
+            start: segment_start,
+            ..
+        } = self.tree.lock().get_leaf_containing(address);
+        let LeafNode::Used { flags } = (unsafe { leaf.unwrap_leaf().read() }) else {
+            return Err(VMAFaultError::SegmentNotMapped);
+        };
+        let backing = self
+            .backings
+            .get(&segment_start)
+            .ok_or(VMAFaultError::NotBacked)?;
+        if flags.locked() {
+            return Err(VMAFaultError::SegmentLocked);
+        }
+        if write && !flags.writable() {
+            return Err(VMAFaultError::AccessDenied);
+        }
+        let page_address = arch::paging::align_to_page(address);
+        let file_offset = backing.offset + (page_address - segment_start);
+        let data = backing.file.get(file_offset..).unwrap_or_default();
+        let data = &data[..data.len().min(PAGE_SIZE)];
+        let page_flags = address_space::segment_page_flags(flags.into());
+        self.address_space
+            .map_page_from_buffer(page_address, page_flags, data, pages_used)
+            .map_err(|err| match err {
+                AddressSpaceError::PageAlreadyExists => VMAFaultError::PageAlreadyMapped,
+                AddressSpaceError::OutOfMemory => VMAFaultError::OutOfMemory,
+            })
+    }
+
+    /// Changes the flags of the segment starting at `start`, which must be `len` bytes long.
+    pub fn protect(
+        &mut self,
+        start: usize,
+        len: usize,
+        flags: SegmentFlags,
+    ) -> Result<(), VMAProtectError> {
+        let tree = self.tree.lock();
+        let LeafInfo {
+            leaf,
+            start: segment_start,
+            end: segment_end,
+            ..
+        } = tree.get_leaf_containing(start);
+        unsafe {
+            let LeafNode::Used { flags: node_flags } = &mut *leaf.unwrap_leaf().raw() else {
+                return Err(VMAProtectError::SegmentNotMapped);
+            };
+            if node_flags.locked() {
+                return Err(VMAProtectError::SegmentLocked);
+            }
+            if segment_start != start || segment_end + 1 - segment_start != len {
+                return Err(VMAProtectError::PartialSegment);
+            }
+            *node_flags = flags.into();
+        }
+        drop(tree);
+        self.address_space
+            .change_flags(start, len, address_space::segment_page_flags(flags));
+        Ok(())
+    }
+}
+
+const CHECKPOINT_MAGIC: [u8; 4] = *b"VMA1";
+const CHECKPOINT_HEADER_LEN: usize = 8;
+const CHECKPOINT_SEGMENT_LEN: usize = 20;
+
+/// Serialises `segments` into a compact form, for checkpoints, core dumps and comparing trees.
+///
+/// The format is the magic `VMA1` and a 32 bit segment count, followed by each segment's 64 bit
+/// start address, 64 bit length and 32 bit flags (read, write and execute from the lowest bit),
+/// all little endian. Segment backings aren't recorded.
+pub fn serialise_segments(segments: &[Segment]) -> Vec<u8> {
+    let mut bytes =
+        Vec::with_capacity(CHECKPOINT_HEADER_LEN + segments.len() * CHECKPOINT_SEGMENT_LEN);
+    bytes.extend_from_slice(&CHECKPOINT_MAGIC);
+    bytes.extend_from_slice(&(segments.len() as u32).to_le_bytes());
+    for segment in segments {
+        let flags = NodeFlags::from(segment.flags).0;
+        bytes.extend_from_slice(&(segment.start as u64).to_le_bytes());
+        bytes.extend_from_slice(&(segment.len as u64).to_le_bytes());
+        bytes.extend_from_slice(&flags.to_le_bytes());
+    }
+    bytes
+}
+
+/// Reads segments serialised by `serialise_segments`, checking that they're page aligned, in
+/// order, non-overlapping and within user memory.
+pub fn deserialise_segments(bytes: &[u8]) -> Result<Vec<Segment>, VMACheckpointError> {
+    let (header, mut rest) = bytes
+        .split_first_chunk::<CHECKPOINT_HEADER_LEN>()
+        .ok_or(VMACheckpointError::Truncated)?;
+    if header[..4] != CHECKPOINT_MAGIC {
+        return Err(VMACheckpointError::BadMagic);
+    }
+    let count = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    let mut segments = Vec::with_capacity(count.min(rest.len() / CHECKPOINT_SEGMENT_LEN));
+    let mut next_free_address = 0;
+    for index in 0..count {
+        let (entry, remaining) = rest
+            .split_first_chunk::<CHECKPOINT_SEGMENT_LEN>()
+            .ok_or(VMACheckpointError::Truncated)?;
+        rest = remaining;
+        let start = u64::from_le_bytes(entry[0..8].try_into().unwrap()) as usize;
+        let len = u64::from_le_bytes(entry[8..16].try_into().unwrap()) as usize;
+        let flags = NodeFlags(u32::from_le_bytes(entry[16..].try_into().unwrap()));
+        let valid = start >= next_free_address
+            && start.is_multiple_of(PAGE_SIZE)
+            && len != 0
+            && len.is_multiple_of(PAGE_SIZE)
+            && start
+                .checked_add(len - 1)
+                .is_some_and(|end| end <= arch::process::HIGHEST_USER_ADDRESS)
+            && flags.0 & !0b111 == 0;
+        if !valid {
+            return Err(VMACheckpointError::InvalidSegment(index));
+        }
+        next_free_address = start + len;
+        segments.push(Segment {
+            start,
+            len,
+            flags: flags.into(),
+            backing: None,
+        });
+    }
+    if !rest.is_empty() {
+        return Err(VMACheckpointError::TrailingData);
+    }
+    Ok(segments)
+}
+
+const _: () = assert!(vma_tree::PAGE_SIZE == PAGE_SIZE);
+
+unsafe impl PageStorage for PhysicalBlockAllocator {
+    fn allocate_page() -> Result<NonNull<u8>, AllocError> {
+        Ok(PhysicalBlockAllocator.allocate(PAGE_LAYOUT)?.cast())
+    }
+
+    unsafe fn free_page(page: NonNull<u8>) {
+        unsafe { PhysicalBlockAllocator.deallocate(page, PAGE_LAYOUT) };
+    }
+}
+
+const PAGE_LAYOUT: Layout = match Layout::from_size_align(PAGE_SIZE, PAGE_SIZE) {
+    Ok(layout) => layout,
+    Err(_) => panic!(),
+};
 
 impl From<SegmentFlags> for NodeFlags {
     fn from(flags: SegmentFlags) -> Self {
@@ -437,1034 +658,209 @@ impl From<SegmentFlags> for NodeFlags {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum Node {
-    Branch(BranchNode),
-    Leaf(LeafNode),
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum LeafNode {
-    Empty { size: usize },
-    Used { flags: NodeFlags },
-}
-
-#[derive(Debug, PartialEq, Eq)]
-struct BranchNode {
-    /// 0: Node color
-    /// 1: Is temp null?
-    /// 2-(usize::BITS-1): pivot (masked, not shifted)
-    packed_fields: usize,
-    max_empty_area_size: usize,
-    parent: Option<BranchNodePtr>,
-    left: NodePtr,
-    right: NodePtr,
-}
-
-unsafe impl Sync for BranchNode {}
-
-impl core::ops::Index<Side> for BranchNode {
-    type Output = NodePtr;
-
-    fn index(&self, side: Side) -> &Self::Output {
-        match side {
-            Side::Left => &self.left,
-            Side::Right => &self.right,
-        }
-    }
-}
-
-impl core::ops::IndexMut<Side> for BranchNode {
-    fn index_mut(&mut self, side: Side) -> &mut Self::Output {
-        match side {
-            Side::Left => &mut self.left,
-            Side::Right => &mut self.right,
+impl From<NodeFlags> for SegmentFlags {
+    fn from(flags: NodeFlags) -> Self {
+        Self {
+            read: flags.readable(),
+            write: flags.writable(),
+            execute: flags.executable(),
         }
     }
 }
 
-#[repr(transparent)]
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
-struct NodePtr(pub NonNull<Node>);
-
-#[repr(transparent)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct BranchNodePtr(pub NonNull<BranchNode>);
-
-#[repr(transparent)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct LeafNodePtr(pub NonNull<LeafNode>);
-
-impl Node {
-    pub const fn placeholder() -> Self {
-        Self::Leaf(LeafNode::Empty { size: 0 })
-    }
+impl Drop for VMAAllocator {
+    fn drop(&mut self) {
+        // Dropping the address space frees every page still mapped, so shared pages are unmapped
+        // first to leave them to their objects
+        for (start, object) in &self.shared {
+            let mut task = UnmapMemTask::new_shared(*start, object.size() / PAGE_SIZE);
+            let _ = task.run(&mut self.address_space, || false);
+        }
+    }
+}
+
+/// Times inserting `num_segments` single page segments into an empty tree, then deleting them.
+/// Returns the average nanoseconds per insert and per delete.
+#[cfg(feature = "bench")]
+pub fn bench_insert_delete(num_segments: usize) -> Result<(u64, u64), VMAMapError> {
+    use crate::arch::clock;
+    let mut pages_used = 0;
+    let mut tree = VMATree::new(&mut pages_used, arch::process::HIGHEST_USER_ADDRESS)?;
+    let flags = NodeFlags::from(SegmentFlags {
+        read: true,
+        write: true,
+        execute: false,
+    });
+    // Spaced out so that every insert splits a gap
+    let segment_address = |i: usize| (1 + i * 2) * PAGE_SIZE;
+    let start = clock::now_ns();
+    for i in 0..num_segments {
+        tree.insert(&mut pages_used, segment_address(i), PAGE_SIZE, flags)?;
+    }
+    let inserted = clock::now_ns();
+    for i in 0..num_segments {
+        tree.delete(segment_address(i));
+    }
+    let deleted = clock::now_ns();
+    let num_segments = num_segments as u64;
+    Ok((
+        (inserted - start) / num_segments,
+        (deleted - inserted) / num_segments,
+    ))
+}
+
+/// Times finding gaps in a tree fragmented by `num_segments` single page segments, separated by
+/// gaps of 1 to 4 pages. Returns the average nanoseconds per search.
+#[cfg(feature = "bench")]
+pub fn bench_find_gap(num_segments: usize) -> Result<u64, VMAMapError> {
+    use crate::arch::clock;
+    let mut pages_used = 0;
+    let mut tree = VMATree::new(&mut pages_used, arch::process::HIGHEST_USER_ADDRESS)?;
+    let flags = NodeFlags::from(SegmentFlags {
+        read: true,
+        write: true,
+        execute: false,
+    });
+    let mut address = PAGE_SIZE;
+    for i in 0..num_segments {
+        tree.insert(&mut pages_used, address, PAGE_SIZE, flags)?;
+        address += PAGE_SIZE * (2 + i % 4);
+    }
+    let fragmented_end = address;
+    let mut num_searches = 0;
+    let mut elapsed_ns = 0;
+    for len in (1..=5).map(|num_pages| num_pages * PAGE_SIZE) {
+        for hint in (0..fragmented_end).step_by(PAGE_SIZE * 7) {
+            let start = clock::now_ns();
+            core::hint::black_box(tree.find_gap(hint, len));
+            elapsed_ns += clock::now_ns() - start;
+            num_searches += 1;
+        }
+    }
+    Ok(elapsed_ns / num_searches)
 }
 
-impl BranchNode {
-    pub fn new(
-        pivot: usize,
-        is_temp_null: bool,
-        color: NodeColor,
-        parent: Option<BranchNodePtr>,
-        left: NodePtr,
-        right: NodePtr,
-    ) -> Self {
-        Self {
-            packed_fields: (pivot & !0b11) | ((is_temp_null as usize) << 1) | (color as usize),
-            max_empty_area_size: 0,
-            parent,
-            left,
-            right,
-        }
-    }
-
-    pub fn pivot(&self) -> usize {
-        self.packed_fields & !0b11
-    }
-
-    pub fn color(&self) -> NodeColor {
-        match self.packed_fields & 0b01 {
-            0 => NodeColor::Red,
-            1 => NodeColor::Black,
-            _ => unreachable!(),
-        }
-    }
+#[derive(Debug)]
+pub struct MapTask {
+    start_address: usize,
+    /// `None` for file backed segments, which are mapped as they're used.
+    map_mem_task: Option<MapMemTask>,
 }
 
-impl NodePtr {
-    pub unsafe fn read(self) -> Node {
-        unsafe { self.0.read() }
-    }
-
-    pub unsafe fn write(self, value: Node) {
-        unsafe {
-            self.0.write(value);
-        }
-    }
-
-    pub fn raw(self) -> *mut Node {
-        self.0.as_ptr()
-    }
-
-    pub unsafe fn free(self) {
-        unsafe {
-            self.write(Node::placeholder());
-            // Get NodeStoragePage containing self
-            let page_address = (self.raw() as usize) & !(PAGE_SIZE - 1);
-            let node_storage_page = page_address as *mut NodeStoragePage;
-            // Calculate index of self
-            let address_in_page = (self.raw() as usize) & (PAGE_SIZE - 1);
-            const ARRAY_OFFSET: usize = offset_of!(NodeStoragePage, entries);
-            let entry_index = (address_in_page - ARRAY_OFFSET) / size_of::<Node>();
-            let storage_page_needs_freeing = (*node_storage_page).unreserve_node(entry_index);
-            // If the unreserve operation returned true, then it's already unlinked itself from the
-            // storage page list, and we need to free it.
-            if storage_page_needs_freeing {
-                drop(PageBox::from_raw_in(node_storage_page, PhysicalBlockAllocator));
-            }
-        }
-    }
-
-    pub unsafe fn color(self) -> NodeColor {
-        unsafe {
-            match self.0.read() {
-                Node::Branch(branch) => branch.color(),
-                Node::Leaf(_) => NodeColor::Black,
-            }
-        }
+impl MapTask {
+    pub fn start_address(&self) -> usize {
+        self.start_address
     }
 
-    pub unsafe fn is_branch(self) -> bool {
-        unsafe {
-            match self.0.read() {
-                Node::Branch(_) => true,
-                Node::Leaf(_) => false,
+    /// If this completes successfully, returns the total number of pages allocated. If it fails,
+    /// the segment is removed again.
+    pub fn run<F>(&mut self, allocator: &mut VMAAllocator, mut should_suspend: F) -> Poll<Result<usize, MapMemError>>
+    where
+        F: FnMut() -> bool,
+    {
+        let result = match &mut self.map_mem_task {
+            Some(map_mem_task) => {
+                match map_mem_task.run(&mut allocator.address_space, &mut should_suspend) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => result,
+                }
             }
-        }
-    }
-
-    pub unsafe fn is_leaf(self) -> bool {
-        unsafe {
-            match self.0.read() {
-                Node::Branch(_) => false,
-                Node::Leaf(_) => true,
+            None => Ok(0),
+        };
+        let mut tree = allocator.tree.lock();
+        let start_address = self.start_address;
+        match result {
+            Ok(_) => {
+                let LeafInfo { leaf, .. } = tree.get_leaf_containing(start_address);
+                unsafe {
+                    let flags = leaf.unwrap_leaf().unwrap_used_flags_ptr().as_ptr();
+                    debug_assert!((*flags).locked());
+                    (&mut *flags).set_locked(false);
+                }
             }
-        }
-    }
-
-    pub unsafe fn is_empty_leaf(self) -> bool {
-        unsafe {
-            match self.0.read() {
-                Node::Branch(_) => false,
-                Node::Leaf(LeafNode::Empty { .. }) => true,
-                Node::Leaf(LeafNode::Used { .. }) => false,
+            // The pages are already unmapped, so only the segment is left to remove
+            Err(_) => {
+                tree.delete(start_address);
+                allocator.shared.remove(&start_address);
             }
         }
+        Poll::Ready(result)
     }
+}
 
-    pub unsafe fn is_used_leaf(self) -> bool {
-        unsafe {
-            match self.0.read() {
-                Node::Branch(_) => false,
-                Node::Leaf(LeafNode::Empty { .. }) => false,
-                Node::Leaf(LeafNode::Used { .. }) => true,
-            }
-        }
-    }
-
-    pub unsafe fn branch(self) -> Option<BranchNodePtr> {
-        unsafe {
-            if matches!(self.0.read(), Node::Branch(_)) {
-                Some(self.unwrap_branch())
-            } else {
-                None
-            }
-        }
-    }
-
-    pub unsafe fn unwrap_branch(self) -> BranchNodePtr {
-        unsafe {
-            debug_assert!(matches!(self.0.read(), Node::Branch(_)));
-            BranchNodePtr(
-                self.0
-                    .byte_add(core::mem::offset_of!(Node, Branch.0))
-                    .cast::<BranchNode>(),
-            )
-        }
-    }
-
-    pub unsafe fn unwrap_leaf(self) -> LeafNodePtr {
-        unsafe {
-            debug_assert!(matches!(self.0.read(), Node::Leaf(_)));
-            LeafNodePtr(
-                self.0
-                    .byte_add(core::mem::offset_of!(Node, Leaf.0))
-                    .cast::<LeafNode>(),
-            )
-        }
-    }
-}
-
-impl BranchNodePtr {
-    pub unsafe fn read(self) -> BranchNode {
-        unsafe { self.0.read() }
-    }
-
-    pub fn raw(self) -> *mut BranchNode {
-        self.0.as_ptr()
-    }
-
-    pub unsafe fn node_ptr(self) -> NodePtr {
-        unsafe {
-            NodePtr(
-                self.0
-                    .byte_sub(core::mem::offset_of!(Node, Branch.0))
-                    .cast::<Node>(),
-            )
-        }
-    }
-
-    pub unsafe fn pivot(self) -> usize {
-        unsafe { (*self.raw()).packed_fields & !0b11 }
-    }
-
-    pub unsafe fn color(self) -> NodeColor {
-        unsafe {
-            match (*self.raw()).packed_fields & 0b01 {
-                0 => NodeColor::Red,
-                1 => NodeColor::Black,
-                _ => unreachable!(),
-            }
-        }
-    }
-
-    pub unsafe fn is_temp_null(self) -> bool {
-        unsafe { (*self.raw()).packed_fields & 0b10 != 0 }
-    }
-
-    pub unsafe fn set_pivot(self, pivot: usize) {
-        unsafe {
-            let ptr = self.raw();
-            (*ptr).packed_fields &= 0b11;
-            (*ptr).packed_fields |= pivot & !0b11;
-        }
-    }
-
-    pub unsafe fn set_color(self, color: NodeColor) {
-        unsafe {
-            let ptr = self.raw();
-            (*ptr).packed_fields &= !0b01;
-            (*ptr).packed_fields |= color as usize;
-        }
-    }
-
-    pub unsafe fn set_max_empty_area_size(self, new_size: usize) {
-        unsafe {
-            let ptr = self.raw();
-            (*ptr).max_empty_area_size = new_size;
-        }
-    }
-
-    pub unsafe fn is_left_side(self) -> Option<bool> {
-        unsafe { Some(self.node_ptr() == (*(*self.raw()).parent?.raw()).left) }
-    }
-
-    pub unsafe fn is_right_side(self) -> Option<bool> {
-        unsafe { Some(self.node_ptr() == (*(*self.raw()).parent?.raw()).right) }
-    }
-
-    pub unsafe fn get_parent(self) -> Self {
-        unsafe { (*self.raw()).parent.unwrap() }
-    }
-
-    pub unsafe fn get_grandparent(self) -> Self {
-        unsafe { self.get_parent().get_parent() }
-    }
-
-    pub unsafe fn get_sibling(self) -> NodePtr {
-        unsafe {
-            let self_node = self.node_ptr();
-            let parent = self.get_parent();
-            if self_node == (*parent.raw()).left {
-                (*parent.raw()).right
-            } else if self_node == (*parent.raw()).right {
-                (*parent.raw()).left
-            } else {
-                unreachable!();
-            }
-        }
-    }
-
-    pub unsafe fn recalculate_max_empty_area_size(self) {
-        unsafe {
-            let self_branch = self.0.read();
-            let left_max_size = match self_branch.left.read() {
-                Node::Branch(child_branch) => child_branch.max_empty_area_size,
-                Node::Leaf(LeafNode::Used { .. }) => 0,
-                Node::Leaf(LeafNode::Empty { size }) => size,
-            };
-            let right_max_size = match self_branch.right.read() {
-                Node::Branch(child_branch) => child_branch.max_empty_area_size,
-                Node::Leaf(LeafNode::Used { .. }) => 0,
-                Node::Leaf(LeafNode::Empty { size }) => size,
-            };
-            (*self.raw()).max_empty_area_size = usize::max(left_max_size, right_max_size);
-        }
-    }
-}
-
-impl LeafNodePtr {
-    pub unsafe fn read(self) -> LeafNode {
-        unsafe { self.0.read() }
-    }
-
-    pub fn raw(self) -> *mut LeafNode {
-        self.0.as_ptr()
-    }
-
-    pub unsafe fn is_empty(self) -> bool {
-        unsafe {
-            match self.0.read() {
-                LeafNode::Empty { .. } => true,
-                LeafNode::Used { .. } => false,
-            }
-        }
-    }
-
-    pub unsafe fn unwrap_flags(self) -> NodeFlags {
-        unsafe {
-            match self.0.read() {
-                LeafNode::Used { flags } => flags,
-                LeafNode::Empty { .. } => panic!(),
-            }
-        }
-    }
-
-    pub unsafe fn unwrap_empty_size_ptr(self) -> NonNull<usize> {
-        unsafe {
-            debug_assert!(matches!(self.0.read(), LeafNode::Empty { .. }));
-            self.0
-                .byte_add(core::mem::offset_of!(LeafNode, Empty.size))
-                .cast::<usize>()
-        }
-    }
-
-    pub unsafe fn unwrap_empty_set_size(self, new_size: usize) {
-        unsafe {
-            self.unwrap_empty_size_ptr().write(new_size);
-        }
-    }
-
-    pub unsafe fn unwrap_used_flags_ptr(self) -> NonNull<NodeFlags> {
-        unsafe {
-            debug_assert!(matches!(self.0.read(), LeafNode::Used { .. }));
-            self.0
-                .byte_add(core::mem::offset_of!(LeafNode, Used.flags))
-                .cast::<NodeFlags>()
-        }
-    }
-}
-
-struct VMATree {
-    root: NodePtr,
-    node_storage: NodeStorageList,
-}
-
-struct LeafInfo {
-    pub leaf: NodePtr,
-    pub parent_and_side: Option<(BranchNodePtr, Side)>,
-    pub start: usize,
-    pub end: usize,
-}
-
-impl VMATree {
-    pub fn new(pages_used: &mut usize) -> Result<Self, AllocError> {
-        let mut node_storage = NodeStorageList::new()?;
-        let root = node_storage
-            .new_empty_leaf(pages_used, arch::process::HIGHEST_USER_ADDRESS)
-            .unwrap();
-        Ok(Self { root, node_storage })
-    }
-
-    /// Returns a pointer to the newly inserted leaf node.
-    pub fn insert(
-        &mut self,
-        pages_used: &mut usize,
-        start: usize,
-        len: usize,
-        flags: NodeFlags,
-    ) -> Result<NodePtr, VMAMapError> {
-        unsafe {
-            let end = start + len - 1;
-            let LeafInfo {
-                leaf: gap_node,
-                parent_and_side,
-                start: gap_start,
-                end: gap_end,
-            } = self.get_leaf_containing(start);
-            assert!(gap_node.is_empty_leaf());
-            assert!(gap_start <= start);
-            assert!(end <= gap_end);
-            if gap_start == start && end == gap_end {
-                gap_node.write(Node::Leaf(LeafNode::Used { flags }));
-                if let Some((parent, _side)) = parent_and_side {
-                    self.update_max_empty_area_data(parent);
-                }
-                Ok(gap_node)
-            } else if gap_start < start && end == gap_end {
-                let new_used_leaf = self.node_storage.new_used_leaf(pages_used, flags)?;
-                let new_branch = self
-                    .node_storage
-                    .new_branch(
-                        pages_used,
-                        start,
-                        parent_and_side.map(|(parent, _)| parent),
-                        gap_node,
-                        new_used_leaf,
-                    )
-                    .inspect_err(|_| new_used_leaf.free())?;
-                gap_node
-                    .unwrap_leaf()
-                    .unwrap_empty_set_size(start - gap_start);
-                self.link_in_branch(new_branch, parent_and_side);
-                Ok(new_used_leaf)
-            } else if gap_start == start && end < gap_end {
-                let new_used_leaf = self.node_storage.new_used_leaf(pages_used, flags)?;
-                let new_branch = self
-                    .node_storage
-                    .new_branch(
-                        pages_used,
-                        end + 1,
-                        parent_and_side.map(|(parent, _)| parent),
-                        new_used_leaf,
-                        gap_node,
-                    )
-                    .inspect_err(|_| new_used_leaf.free())?;
-                gap_node.unwrap_leaf().unwrap_empty_set_size(gap_end - end);
-                self.link_in_branch(new_branch, parent_and_side);
-                Ok(new_used_leaf)
-            } else if gap_start < start && end < gap_end {
-                let new_used_leaf = self.node_storage.new_used_leaf(pages_used, flags)?;
-                let new_empty_leaf = self
-                    .node_storage
-                    .new_empty_leaf(pages_used, gap_end - end)
-                    .inspect_err(|_| new_used_leaf.free())?;
-                let new_end_branch = self
-                    .node_storage
-                    .new_branch(
-                        pages_used,
-                        end + 1,
-                        parent_and_side.map(|(parent, _)| parent),
-                        new_used_leaf,
-                        new_empty_leaf,
-                    )
-                    .inspect_err(|_| {
-                        new_empty_leaf.free();
-                        new_used_leaf.free();
-                    })?;
-                // We allocate all nodes before linking them into the tree, so that we don't have
-                // to deal with unlinking them in case of error.
-                // In the case of this start branch though, that means we have to delay writing the
-                // actual branch data until we've done the first bit of linking.
-                let new_start_branch = self
-                    .node_storage
-                    .find_and_reserve_node(pages_used)
-                    .inspect_err(|_| {
-                        new_end_branch.free();
-                        new_empty_leaf.free();
-                        new_used_leaf.free();
-                    })?;
-                self.link_in_branch(new_end_branch, parent_and_side);
-                let LeafInfo {
-                    leaf: used_node,
-                    parent_and_side,
-                    start: _,
-                    end: gap_end,
-                } = self.get_leaf_containing(start);
-                debug_assert_eq!(gap_end, end);
-                // Now we actually write the start branch data.
-                new_start_branch.write(Node::Branch(BranchNode::new(
-                    start,
-                    false,
-                    NodeColor::Black,
-                    parent_and_side.map(|(parent, _)| parent),
-                    gap_node,
-                    used_node,
-                )));
-                gap_node
-                    .unwrap_leaf()
-                    .unwrap_empty_set_size(start - gap_start);
-                self.link_in_branch(new_start_branch, parent_and_side);
-                Ok(used_node)
-            } else {
-                unreachable!();
-            }
-        }
-    }
-
-    pub fn get_leaf_containing(&self, addr: usize) -> LeafInfo {
-        unsafe {
-            debug_assert!(addr <= arch::process::HIGHEST_USER_ADDRESS);
-            let mut current_parent_and_side: Option<(BranchNodePtr, Side)> = None;
-            let mut current_node: NodePtr = self.root;
-            let mut current_start: usize = 0;
-            let mut current_end: usize = arch::process::HIGHEST_USER_ADDRESS.saturating_add(1);
-            while let Node::Branch(branch) = current_node.read() {
-                if addr < branch.pivot() {
-                    debug_assert!(branch.pivot() <= current_end);
-                    current_end = branch.pivot();
-                    current_parent_and_side = Some((current_node.unwrap_branch(), Side::Left));
-                    current_node = branch.left;
-                } else {
-                    debug_assert!(branch.pivot() >= current_start);
-                    current_start = branch.pivot();
-                    current_parent_and_side = Some((current_node.unwrap_branch(), Side::Right));
-                    current_node = branch.right;
-                }
-            }
-            LeafInfo {
-                leaf: current_node,
-                parent_and_side: current_parent_and_side,
-                start: current_start,
-                end: current_end - 1,
-            }
-        }
-    }
-
-    unsafe fn link_in_branch(
-        &mut self,
-        new_branch: NodePtr,
-        new_parent_and_side: Option<(BranchNodePtr, Side)>,
-    ) {
-        unsafe {
-            match new_parent_and_side {
-                Some((new_parent, side)) => {
-                    (&mut *new_parent.raw())[side] = new_branch;
-                    new_branch.unwrap_branch().set_color(NodeColor::Red);
-                    // Fix the tree if the properties are violated
-                    if (*new_parent.raw()).parent.is_some() {
-                        self.fix_insert(new_branch);
-                    }
-                }
-                None => self.root = new_branch,
-            }
-            self.update_max_empty_area_data(new_branch.unwrap_branch());
-        }
-    }
-
-    unsafe fn fix_insert(&mut self, node: NodePtr) {
-        unsafe {
-            let mut k = if node.is_branch() {
-                node.unwrap_branch()
-            } else {
-                panic!();
-            };
-            while k.get_parent().color() == NodeColor::Red {
-                if k.get_parent().is_right_side().unwrap() {
-                    let u = (*k.get_grandparent().raw()).left;
-                    match u.color() {
-                        NodeColor::Red => {
-                            u.unwrap_branch().set_color(NodeColor::Black);
-                            k.get_parent().set_color(NodeColor::Black);
-                            k.get_grandparent().set_color(NodeColor::Red);
-                            k = k.get_grandparent();
-                        }
-                        NodeColor::Black => {
-                            if k.is_left_side().unwrap() {
-                                k = k.get_parent();
-                                self.right_rotate(k);
-                            }
-                            k.get_parent().set_color(NodeColor::Black);
-                            k.get_grandparent().set_color(NodeColor::Red);
-                            self.left_rotate(k.get_grandparent());
-                        }
-                    }
-                } else {
-                    let u = (*k.get_grandparent().raw()).right;
-                    match u.color() {
-                        NodeColor::Red => {
-                            u.unwrap_branch().set_color(NodeColor::Black);
-                            k.get_parent().set_color(NodeColor::Black);
-                            k.get_grandparent().set_color(NodeColor::Red);
-                            k = k.get_grandparent();
-                        }
-                        NodeColor::Black => {
-                            if k.is_right_side().unwrap() {
-                                k = k.get_parent();
-                                self.left_rotate(k);
-                            }
-                            k.get_parent().set_color(NodeColor::Black);
-                            k.get_grandparent().set_color(NodeColor::Red);
-                            self.right_rotate(k.get_grandparent());
-                        }
-                    }
-                }
-                if k.node_ptr() == self.root {
-                    break;
-                }
-            }
-            self.root.unwrap_branch().set_color(NodeColor::Black);
-        }
-    }
-
-    /// Panics if `addr` does not belong to a used leaf node.
-    pub fn delete(&mut self, addr: usize) {
-        unsafe {
-            let LeafInfo {
-                leaf,
-                parent_and_side,
-                start: leaf_start,
-                end: leaf_end,
-            } = self.get_leaf_containing(addr);
-            assert!(
-                matches!(leaf.read(), Node::Leaf(LeafNode::Used { .. })),
-                "{:?}",
-                leaf.read()
-            );
-            (*leaf.raw()) = Node::Leaf(LeafNode::Empty {
-                size: leaf_end + 1 - leaf_start,
-            });
-            if let Some((parent, side)) = parent_and_side {
-                let sibling = parent.read()[!side];
-                if sibling.is_empty_leaf() {
-                    // If sibling is also an empty leaf, then combine their sizes and delete the
-                    // parent.
-                    let leaf_size = leaf.unwrap_leaf().unwrap_empty_size_ptr();
-                    let sibling_size = sibling.unwrap_leaf().unwrap_empty_size_ptr();
-                    let combined_size = leaf_size.read() + sibling_size.read();
-                    leaf_size.write(combined_size);
-                    sibling_size.write(combined_size);
-                    self.delete_branch(parent);
-                }
-                'gap_join_loop: loop {
-                    let LeafInfo {
-                        leaf: _,
-                        parent_and_side,
-                        start: _,
-                        end: _,
-                    } = self.get_leaf_containing(addr);
-                    // Update area sizes up to root
-                    if let Some((parent, _side)) = parent_and_side {
-                        self.update_max_empty_area_data(parent);
-                    }
-                    // Traverse up the tree from the new segment, delete useless pivots
-                    let mut current_branch = parent_and_side.map(|(p, _)| p);
-                    while let Some(branch) = current_branch {
-                        let left_max = self.max_leaf((*branch.raw()).left);
-                        let right_min = self.min_leaf((*branch.raw()).right);
-                        if left_max.is_empty() && right_min.is_empty() {
-                            // Combine sizes
-                            let left_max_size = left_max.unwrap_empty_size_ptr();
-                            let right_min_size = right_min.unwrap_empty_size_ptr();
-                            let combined_size = left_max_size.read() + right_min_size.read();
-                            left_max_size.write(combined_size);
-                            right_min_size.write(combined_size);
-                            // Delete splitting pivot
-                            self.delete_branch(branch);
-                            continue 'gap_join_loop;
-                        } else {
-                            current_branch = (*branch.raw()).parent;
-                        }
-                    }
-                    break;
-                }
-            }
-        }
-    }
-
-    unsafe fn delete_branch(&mut self, delete_branch: BranchNodePtr) {
-        unsafe {
-            let delete_node = delete_branch.node_ptr();
-            let moved_up_node: NodePtr;
-            let moved_up_node_parent: Option<BranchNodePtr>;
-            let delete_node_color: NodeColor;
-            if (*delete_branch.raw()).left.is_leaf() || (*delete_branch.raw()).right.is_leaf() {
-                (moved_up_node, moved_up_node_parent) =
-                    self.delete_node_with_zero_or_one_child(delete_branch);
-                delete_node_color = delete_branch.color();
-                delete_node.free();
-            } else {
-                // Node has two children
-                let successor = self.find_min((*delete_branch.raw()).right.unwrap_branch());
-                delete_branch.set_pivot(successor.pivot());
-                delete_branch.set_max_empty_area_size((*successor.raw()).max_empty_area_size);
-                (moved_up_node, moved_up_node_parent) =
-                    self.delete_node_with_zero_or_one_child(successor);
-                delete_node_color = successor.color();
-                successor.node_ptr().free();
-            }
-            if delete_node_color == NodeColor::Black {
-                let moved_up_branch = moved_up_node.unwrap_branch();
-                self.fix_delete(moved_up_branch);
-                if moved_up_branch.is_temp_null() {
-                    let left_child = (*moved_up_branch.raw()).left;
-                    let right_child = (*moved_up_branch.raw()).right;
-                    if left_child.is_used_leaf() {
-                        debug_assert!(
-                            !right_child.is_used_leaf(),
-                            "{:?}",
-                            right_child.unwrap_leaf().unwrap_flags(),
-                        );
-                        (*moved_up_node.raw()) = (*moved_up_branch.raw()).left.read();
-                        left_child.free();
-                        right_child.free();
-                    } else {
-                        (*moved_up_node.raw()) = (*moved_up_branch.raw()).right.read();
-                        left_child.free();
-                        right_child.free();
-                    }
-                }
-            }
-            // Update adjacent subtree area sizes
-            if let Some(parent) = moved_up_node_parent {
-                self.update_max_empty_area_data(parent);
-            }
-        }
-    }
-
-    unsafe fn delete_node_with_zero_or_one_child(
-        &mut self,
-        node: BranchNodePtr,
-    ) -> (NodePtr, Option<BranchNodePtr>) {
-        unsafe {
-            let parent = (*node.raw()).parent;
-            if (*node.raw()).left.is_branch() {
-                self.replace_node(node, (*node.raw()).left);
-                debug_assert!(
-                    !(*node.raw()).right.is_used_leaf(),
-                    "{:?}",
-                    (*node.raw()).right.unwrap_leaf().unwrap_flags(),
-                );
-                (*node.raw()).right.free();
-                ((*node.raw()).left, parent)
-            } else if (*node.raw()).right.is_branch() {
-                self.replace_node(node, (*node.raw()).right);
-                debug_assert!(
-                    !(*node.raw()).left.is_used_leaf(),
-                    "{:?}",
-                    (*node.raw()).left.unwrap_leaf().unwrap_flags(),
-                );
-                (*node.raw()).left.free();
-                ((*node.raw()).right, parent)
-            } else {
-                let new_child = match node.color() {
-                    NodeColor::Black => {
-                        let temp_node_ptr = self.node_storage.get_temp_node();
-                        temp_node_ptr.write(Node::Branch(BranchNode::new(
-                            0,
-                            true,
-                            NodeColor::Black,
-                            None,
-                            (*node.raw()).left,
-                            (*node.raw()).right,
-                        )));
-                        temp_node_ptr
-                    }
-                    NodeColor::Red => {
-                        if (*node.raw()).left.is_used_leaf() {
-                            debug_assert!(
-                                !(*node.raw()).right.is_used_leaf(),
-                                "{:?}",
-                                (*node.raw()).right.unwrap_leaf().unwrap_flags(),
-                            );
-                            (*node.raw()).right.free();
-                            (*node.raw()).left
-                        } else {
-                            debug_assert!(
-                                !(*node.raw()).left.is_used_leaf(),
-                                "{:?}",
-                                (*node.raw()).left.unwrap_leaf().unwrap_flags(),
-                            );
-                            (*node.raw()).left.free();
-                            (*node.raw()).right
-                        }
-                    }
-                };
-                self.replace_node(node, new_child);
-                (new_child, parent)
-            }
-        }
-    }
-
-    unsafe fn fix_delete(&mut self, mut node: BranchNodePtr) {
-        unsafe {
-            while node.node_ptr() != self.root {
-                let mut sibling = node.get_sibling().unwrap_branch();
-                if sibling.color() == NodeColor::Red {
-                    self.handle_red_sibling(node, sibling);
-                    sibling = node.get_sibling().unwrap_branch();
-                }
-                if (*sibling.raw()).left.color() == NodeColor::Black
-                    && (*sibling.raw()).right.color() == NodeColor::Black
-                {
-                    sibling.set_color(NodeColor::Red);
-                    if node.get_parent().color() == NodeColor::Red {
-                        node.get_parent().set_color(NodeColor::Black);
-                    } else {
-                        node = node.get_parent();
-                        continue;
-                    }
-                } else {
-                    self.handle_black_sibling_at_least_one_red_child(node, sibling);
-                }
-                break;
-            }
-            if node.node_ptr() == self.root {
-                node.set_color(NodeColor::Black);
-            }
-        }
-    }
-
-    unsafe fn handle_red_sibling(&mut self, node: BranchNodePtr, sibling: BranchNodePtr) {
-        unsafe {
-            sibling.set_color(NodeColor::Black);
-            node.get_parent().set_color(NodeColor::Red);
-            if node.is_left_side().unwrap() {
-                self.left_rotate(node.get_parent());
-            } else if node.is_right_side().unwrap() {
-                self.right_rotate(node.get_parent());
-            } else {
-                unreachable!();
-            }
-        }
-    }
-
-    unsafe fn handle_black_sibling_at_least_one_red_child(
-        &mut self,
-        node: BranchNodePtr,
-        mut sibling: BranchNodePtr,
-    ) {
-        unsafe {
-            let is_left = node.is_left_side().unwrap();
-            if is_left && (*sibling.raw()).right.color() == NodeColor::Black {
-                (*sibling.raw())
-                    .left
-                    .unwrap_branch()
-                    .set_color(NodeColor::Black);
-                sibling.set_color(NodeColor::Red);
-                self.right_rotate(sibling);
-                sibling = (*node.get_parent().raw()).right.unwrap_branch();
-            } else if !is_left && (*sibling.raw()).left.color() == NodeColor::Black {
-                (*sibling.raw())
-                    .right
-                    .unwrap_branch()
-                    .set_color(NodeColor::Black);
-                sibling.set_color(NodeColor::Red);
-                self.left_rotate(sibling);
-                sibling = (*node.get_parent().raw()).left.unwrap_branch();
-            }
-            sibling.set_color(node.get_parent().color());
-            node.get_parent().set_color(NodeColor::Black);
-            if is_left {
-                (*sibling.raw())
-                    .right
-                    .unwrap_branch()
-                    .set_color(NodeColor::Black);
-                self.left_rotate(node.get_parent());
-            } else {
-                (*sibling.raw())
-                    .left
-                    .unwrap_branch()
-                    .set_color(NodeColor::Black);
-                self.right_rotate(node.get_parent());
-            }
-        }
-    }
-
-    fn find_min(&self, mut node: BranchNodePtr) -> BranchNodePtr {
-        unsafe {
-            while (*node.raw()).left.is_branch() {
-                node = (*node.raw()).left.unwrap_branch();
-            }
-            node
-        }
-    }
-
-    fn min_leaf(&self, mut node: NodePtr) -> LeafNodePtr {
-        unsafe {
-            while let Some(branch) = node.branch() {
-                node = (*branch.raw()).left;
-            }
-            node.unwrap_leaf()
-        }
-    }
-
-    fn max_leaf(&self, mut node: NodePtr) -> LeafNodePtr {
-        unsafe {
-            while let Some(branch) = node.branch() {
-                node = (*branch.raw()).right;
-            }
-            node.unwrap_leaf()
-        }
-    }
-
-    fn update_max_empty_area_data(&mut self, lowest_branch: BranchNodePtr) {
-        unsafe {
-            let mut current_branch_ptr = Some(lowest_branch);
-            while let Some(branch_ptr) = current_branch_ptr {
-                let branch = branch_ptr.read();
-                let mut current_max = 0;
-                for child in [branch.left, branch.right] {
-                    let child_max_empty_area_size = match child.read() {
-                        Node::Branch(child_branch) => child_branch.max_empty_area_size,
-                        Node::Leaf(LeafNode::Used { .. }) => 0,
-                        Node::Leaf(LeafNode::Empty { size }) => size,
-                    };
-                    current_max = usize::max(current_max, child_max_empty_area_size);
-                }
-                branch_ptr.set_max_empty_area_size(current_max);
-                current_branch_ptr = branch.parent;
-            }
-        }
-    }
-
-    unsafe fn replace_node(&mut self, old_branch: BranchNodePtr, new_node: NodePtr) {
-        unsafe {
-            match old_branch.is_left_side() {
-                Some(true) => (*old_branch.get_parent().raw()).left = new_node,
-                Some(false) => (*old_branch.get_parent().raw()).right = new_node,
-                None => self.root = new_node,
-            }
-            if let Some(branch) = new_node.branch() {
-                (*branch.raw()).parent = (*old_branch.raw()).parent;
-            }
-        }
-    }
-
-    unsafe fn left_rotate(&mut self, node: BranchNodePtr) {
-        unsafe {
-            let right_node = (*node.raw()).right;
-            let right = right_node.unwrap_branch();
-            (*node.raw()).right = (*right.raw()).left;
-            if let Node::Branch(branch) = &mut *(*right.raw()).left.raw() {
-                branch.parent = Some(node);
-            }
-            (*right.raw()).parent = (*node.raw()).parent;
-            match node.is_left_side() {
-                Some(true) => (*node.get_parent().raw()).left = right_node,
-                Some(false) => (*node.get_parent().raw()).right = right_node,
-                None => self.root = right_node,
-            }
-            (*right.raw()).left = node.node_ptr();
-            (*node.raw()).parent = Some(right);
-            node.recalculate_max_empty_area_size();
-            right.recalculate_max_empty_area_size();
-        }
-    }
-
-    unsafe fn right_rotate(&mut self, node: BranchNodePtr) {
-        unsafe {
-            let left_node = (*node.raw()).left;
-            let left = left_node.unwrap_branch();
-            (*node.raw()).left = (*left.raw()).right;
-            if let Node::Branch(branch) = &mut *(*left.raw()).right.raw() {
-                branch.parent = Some(node);
-            }
-            (*left.raw()).parent = (*node.raw()).parent;
-            match node.is_left_side() {
-                Some(true) => (*node.get_parent().raw()).left = left_node,
-                Some(false) => (*node.get_parent().raw()).right = left_node,
-                None => self.root = left_node,
-            }
-            (*left.raw()).right = node.node_ptr();
-            (*node.raw()).parent = Some(left);
-            node.recalculate_max_empty_area_size();
-            left.recalculate_max_empty_area_size();
-        }
-    }
-}
-
-impl Drop for VMATree {
-    fn drop(&mut self) {
-        // Drop the tree recursively.
-        // As this is a roughly-balanced binary tree, the maximum depth should be pretty low, so
-        // we shouldn't have any issues around overflowing the kernel stack.
-        unsafe fn drop_subtree(node: NodePtr) {
-            unsafe {
-                if let Node::Branch(branch) = &*node.raw() {
-                    drop_subtree(branch.left);
-                    drop_subtree(branch.right);
-                }
-                node.free();
-            }
-        }
-        unsafe {
-            drop_subtree(self.root);
-        }
-    }
-}
+#[derive(Debug)]
+pub struct ResizeTask {
+    start: usize,
+    state: ResizeState,
+}
 
 #[derive(Debug)]
-pub struct MapTask {
-    map_mem_task: MapMemTask,
-}
-
-impl MapTask {
-    /// If this completes, returns the total number of pages freed.
-    pub fn run<F>(&mut self, allocator: &mut VMAAllocator, mut should_suspend: F) -> Poll<Result<usize, MapMemError>>
+enum ResizeState {
+    Unchanged,
+    Growing {
+        old_end: usize,
+        map_mem_task: MapMemTask,
+    },
+    Shrinking {
+        new_end: usize,
+        /// Nodes for the new gap, if the segment isn't followed by one already.
+        spare_nodes: Option<(NodePtr, NodePtr)>,
+        unmap_mem_task: UnmapMemTask,
+    },
+}
+
+impl ResizeTask {
+    /// If this completes successfully, returns the number of pages allocated and the number of
+    /// pages freed. If growing fails, the segment is shrunk back to its old length.
+    pub fn run<F>(
+        &mut self,
+        allocator: &mut VMAAllocator,
+        mut should_suspend: F,
+    ) -> Poll<Result<(usize, usize), MapMemError>>
     where
         F: FnMut() -> bool,
     {
-        match self
-            .map_mem_task
-            .run(&mut allocator.page_mapper, &mut should_suspend)
-        {
-            Poll::Pending => Poll::Pending,
-            err @ Poll::Ready(Err(_)) => err,
-            Poll::Ready(Ok(pages_allocated)) => {
+        let result = match &mut self.state {
+            ResizeState::Unchanged => return Poll::Ready(Ok((0, 0))),
+            ResizeState::Growing {
+                old_end,
+                map_mem_task,
+            } => {
+                let Poll::Ready(result) =
+                    map_mem_task.run(&mut allocator.address_space, &mut should_suspend)
+                else {
+                    return Poll::Pending;
+                };
+                if result.is_err() {
+                    let mut tree = allocator.tree.lock();
+                    let end = tree.get_leaf_containing(self.start).end;
+                    unsafe { tree.move_boundary(self.start, end, *old_end) };
+                }
+                result.map(|pages_allocated| (pages_allocated, 0))
+            }
+            ResizeState::Shrinking {
+                new_end,
+                spare_nodes,
+                unmap_mem_task,
+            } => {
+                let Poll::Ready(pages_freed) =
+                    unmap_mem_task.run(&mut allocator.address_space, &mut should_suspend)
+                else {
+                    return Poll::Pending;
+                };
                 let mut tree = allocator.tree.lock();
-                let start_address = self.map_mem_task.start_address();
-                let LeafInfo { leaf, .. } = tree.get_leaf_containing(start_address);
+                let end = tree.get_leaf_containing(self.start).end;
                 unsafe {
-                    let flags = leaf.unwrap_leaf().unwrap_used_flags_ptr().as_ptr();
-                    debug_assert!((*flags).locked());
-                    (&mut *flags).set_locked(false);
+                    match spare_nodes.take() {
+                        Some(nodes) => tree.split_tail(self.start, end, *new_end, nodes),
+                        None => tree.move_boundary(self.start, end, *new_end),
+                    }
                 }
-                tree.delete(start_address);
-                Poll::Ready(Ok(pages_allocated))
+                Ok((0, pages_freed))
             }
-        }
+        };
+        unsafe { allocator.tree.lock().set_segment_locked(self.start, false) };
+        Poll::Ready(result)
     }
 }
 
@@ -1482,12 +878,14 @@ impl UnmapTask {
     {
         match self
             .unmap_mem_task
-            .run(&mut allocator.page_mapper, &mut should_suspend)
+            .run(&mut allocator.address_space, &mut should_suspend)
         {
             Poll::Pending => Poll::Pending,
             Poll::Ready(pages_freed) => {
                 let mut tree = allocator.tree.lock();
                 tree.delete(self.start_address);
+                allocator.shared.remove(&self.start_address);
+                allocator.backings.remove(&self.start_address);
                 Poll::Ready(pages_freed)
             }
         }
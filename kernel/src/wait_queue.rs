@@ -0,0 +1,112 @@
+//! Wait queues, for blocking threads until a condition holds.
+
+use crate::arch;
+use crate::arch::clock;
+use crate::kthread::{self, ThreadId};
+use crate::sync::IrqMutex;
+use alloc::collections::VecDeque;
+
+pub struct WaitQueue {
+    waiters: IrqMutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns `true`. The condition is checked
+    /// before blocking and after every wakeup, so it should be made true before waking the queue.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        let Some(id) = kthread::current() else {
+            // Threads aren't running yet, so only an interrupt can change the condition
+            while !condition() {
+                arch::kthread::wait_for_interrupt();
+            }
+            return;
+        };
+        loop {
+            kthread::prepare_to_block();
+            self.waiters.lock().push_back(id);
+            if condition() {
+                kthread::cancel_block();
+                self.remove(id);
+                return;
+            }
+            kthread::block();
+            // Woken threads are removed by the waker, unless this was a spurious wakeup
+            self.remove(id);
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns `true`, as `wait_until` does, or the
+    /// clock counter reaches `deadline_ns`. Returns whether the condition became true.
+    pub fn wait_until_deadline<F: FnMut() -> bool>(
+        &self,
+        deadline_ns: u64,
+        mut condition: F,
+    ) -> bool {
+        let expired = || clock::try_now_ns().is_none_or(|now_ns| now_ns >= deadline_ns);
+        let Some(id) = kthread::current() else {
+            while !condition() {
+                if expired() {
+                    return false;
+                }
+                core::hint::spin_loop();
+            }
+            return true;
+        };
+        loop {
+            kthread::prepare_to_block();
+            self.waiters.lock().push_back(id);
+            let done = match condition() {
+                true => Some(true),
+                false if expired() => Some(false),
+                false => None,
+            };
+            if let Some(done) = done {
+                kthread::cancel_block();
+                self.remove(id);
+                return done;
+            }
+            kthread::block_until(deadline_ns);
+            self.remove(id);
+        }
+    }
+
+    /// Wakes the thread that has been waiting the longest. Returns `false` if there were no
+    /// waiting threads.
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop_front();
+        match waiter {
+            Some(id) => {
+                kthread::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all waiting threads, returning how many there were.
+    pub fn wake_all(&self) -> usize {
+        // Popped one at a time rather than taking the queue, so that waking from an interrupt
+        // handler doesn't free memory, and `kthread::wake` never allocates
+        let mut num_waiters = 0;
+        while self.wake_one() {
+            num_waiters += 1;
+        }
+        num_waiters
+    }
+
+    fn remove(&self, id: ThreadId) {
+        self.waiters.lock().retain(|waiter| *waiter != id);
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
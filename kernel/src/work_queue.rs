@@ -0,0 +1,156 @@
+//! Deferred work, for running the bulk of an interrupt handler outside of interrupt context.
+//!
+//! Work items are statics owned by whoever schedules them, and are linked into the queue
+//! in place, so scheduling never allocates and is safe from interrupt handlers. Queued work is
+//! run in order by the `kworker` thread. Subsystems whose work can block for a long time, or
+//! must not wait behind other work, can have their own `WorkQueue` and worker thread.
+
+use crate::init_state::{self, Subsystem};
+use crate::kthread;
+use crate::sync::IrqMutex;
+use crate::wait_queue::WaitQueue;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+static KERNEL_QUEUE: WorkQueue = WorkQueue::new("kworker");
+
+/// A function to be run later by the worker thread.
+#[derive(Debug)]
+pub struct Work {
+    function: fn(usize),
+    arg: usize,
+    pending: AtomicBool,
+    /// Next item in the queue, only accessed with the queue locked.
+    next: AtomicPtr<Work>,
+}
+
+impl Work {
+    pub const fn new(function: fn(usize), arg: usize) -> Self {
+        Self {
+            function,
+            arg,
+            pending: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Whether the work is queued and hasn't started running yet.
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+struct Queue {
+    head: *const Work,
+    tail: *const Work,
+}
+
+// Only contains pointers to `'static` works
+unsafe impl Send for Queue {}
+
+impl Queue {
+    fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    fn push(&mut self, work: &'static Work) {
+        work.next.store(ptr::null_mut(), Ordering::Relaxed);
+        match unsafe { self.tail.as_ref() } {
+            Some(tail) => tail
+                .next
+                .store(ptr::from_ref(work).cast_mut(), Ordering::Relaxed),
+            None => self.head = work,
+        }
+        self.tail = work;
+    }
+
+    fn pop(&mut self) -> Option<&'static Work> {
+        let work = unsafe { self.head.as_ref()? };
+        self.head = work.next.load(Ordering::Relaxed);
+        if self.head.is_null() {
+            self.tail = ptr::null();
+        }
+        Some(work)
+    }
+}
+
+/// Queue of work run in order by its own worker thread.
+pub struct WorkQueue {
+    name: &'static str,
+    queue: IrqMutex<Queue>,
+    wait_queue: WaitQueue,
+}
+
+impl WorkQueue {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            queue: IrqMutex::new(Queue {
+                head: ptr::null(),
+                tail: ptr::null(),
+            }),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    /// Queues `work` to be run by the worker thread. Returns `false` if it was already queued,
+    /// in which case it will still only run once. Safe to call from interrupt handlers.
+    pub fn schedule(&self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.queue.lock().push(work);
+        self.wait_queue.wake_one();
+        true
+    }
+
+    /// Runs all queued work on the current thread, including any queued while running.
+    pub fn run_pending(&self) {
+        loop {
+            let Some(work) = self.queue.lock().pop() else {
+                return;
+            };
+            // Cleared first, so that the work can be rescheduled while it runs
+            work.pending.store(false, Ordering::Release);
+            (work.function)(work.arg);
+        }
+    }
+
+    /// Starts the worker thread, named after the queue. Work scheduled before this is run once
+    /// the thread starts.
+    pub fn start(&'static self) -> Result<(), kthread::SpawnError> {
+        kthread::spawn(self.name, worker_thread, ptr::from_ref(self) as usize)?;
+        Ok(())
+    }
+}
+
+/// Queues `work` on the kernel's work queue. Returns `false` if it was already queued, in which
+/// case it will still only run once. Safe to call from interrupt handlers.
+pub fn schedule(work: &'static Work) -> bool {
+    KERNEL_QUEUE.schedule(work)
+}
+
+/// Runs all work queued on the kernel's work queue on the current thread, including any queued
+/// while running.
+pub fn run_pending() {
+    KERNEL_QUEUE.run_pending();
+}
+
+/// Starts the kernel's worker thread. Work scheduled before this is run once the thread starts.
+pub fn init() {
+    init_state::begin(Subsystem::WorkQueue);
+    if let Err(err) = KERNEL_QUEUE.start() {
+        panic!("failed to start worker thread - {err}");
+    }
+    init_state::finish(Subsystem::WorkQueue);
+}
+
+fn worker_thread(work_queue: usize) -> usize {
+    let work_queue = unsafe { &*(work_queue as *const WorkQueue) };
+    loop {
+        work_queue
+            .wait_queue
+            .wait_until(|| !work_queue.queue.lock().is_empty());
+        work_queue.run_pending();
+    }
+}